@@ -0,0 +1,27 @@
+use std::fmt::{Display, Formatter};
+
+pub type Result<T> = std::result::Result<T, ThetaError>;
+#[derive(Debug)]
+pub struct ThetaError {
+    pub message: String,
+}
+
+impl Display for ThetaError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ThetaError {
+    pub fn new<Str: ToString>(message: Str) -> ThetaError {
+        return ThetaError {
+            message: message.to_string(),
+        };
+    }
+}
+
+impl From<std::io::Error> for ThetaError {
+    fn from(err: std::io::Error) -> Self {
+        return ThetaError::new(err);
+    }
+}
@@ -0,0 +1,445 @@
+/*
+ * Copyright 2022 Cube Dev, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::error::{Result, ThetaError};
+use crate::murmur3;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeSet;
+use std::io::Cursor;
+
+/// Seed used by DataSketches unless configured otherwise. Sketches can only be combined if they
+/// were built with the same seed.
+const DEFAULT_UPDATE_SEED: u64 = 9001;
+/// Theta of a sketch in exact mode, i.e. all hashes are kept.
+const MAX_THETA: u64 = i64::MAX as u64;
+
+pub const DEFAULT_LG_K: u8 = 12;
+const MIN_LG_K: u8 = 4;
+const MAX_LG_K: u8 = 26;
+
+const SERIAL_VERSION: u8 = 3;
+const COMPACT_FAMILY_ID: u8 = 3;
+
+const FLAG_BIG_ENDIAN: u8 = 1;
+const FLAG_READ_ONLY: u8 = 2;
+const FLAG_EMPTY: u8 = 4;
+const FLAG_COMPACT: u8 = 8;
+const FLAG_ORDERED: u8 = 16;
+const FLAG_SINGLE_ITEM: u8 = 32;
+
+/// Theta sketch estimates a size of a set and, unlike HyperLogLog, can also estimate sizes of
+/// intersections and differences of the sets.
+///
+/// The sketch keeps the `k` smallest hashes of the elements along with `theta`, the fraction of
+/// the hash space covered by the kept hashes. Serialization uses the compact sketch format of
+/// Apache DataSketches and elements are hashed with the default update seed, so sketches can be
+/// exchanged with the Java and C++ implementations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThetaSketch {
+    lg_k: u8,
+    theta: u64,
+    empty: bool,
+    /// All hashes are non-zero and less than `theta`.
+    entries: BTreeSet<u64>,
+}
+
+impl ThetaSketch {
+    /// Create a sketch for an empty set, keeping up to 4096 hashes.
+    pub fn new() -> ThetaSketch {
+        return ThetaSketch {
+            lg_k: DEFAULT_LG_K,
+            theta: MAX_THETA,
+            empty: true,
+            entries: BTreeSet::new(),
+        };
+    }
+
+    /// Create a sketch for an empty set, keeping up to `2^lg_k` hashes.
+    pub fn with_lg_k(lg_k: u8) -> Result<ThetaSketch> {
+        if lg_k < MIN_LG_K || MAX_LG_K < lg_k {
+            return Err(ThetaError::new(format!(
+                "lg_k must be between {} and {}, got {}",
+                MIN_LG_K, MAX_LG_K, lg_k
+            )));
+        }
+        let mut s = ThetaSketch::new();
+        s.lg_k = lg_k;
+        return Ok(s);
+    }
+
+    /// Read a compact sketch serialized by DataSketches (serial version 3).
+    /// Sketches are read with the default number of kept hashes, which only matters for unions.
+    pub fn read(data: &[u8]) -> Result<ThetaSketch> {
+        if data.len() < 8 {
+            return Err(ThetaError::new("theta sketch is too short"));
+        }
+        let pre_longs = data[0] & 0x3F;
+        let serial_version = data[1];
+        let family = data[2];
+        let flags = data[5];
+        let seed_hash = u16::from_le_bytes([data[6], data[7]]);
+        if serial_version != SERIAL_VERSION {
+            return Err(ThetaError::new(format!(
+                "unsupported theta sketch serial version {}",
+                serial_version
+            )));
+        }
+        if family != COMPACT_FAMILY_ID {
+            return Err(ThetaError::new(format!(
+                "expected compact theta sketch, got family id {}",
+                family
+            )));
+        }
+        if flags & FLAG_BIG_ENDIAN != 0 {
+            return Err(ThetaError::new(
+                "big-endian theta sketches are not supported",
+            ));
+        }
+
+        let mut s = ThetaSketch::new();
+        if flags & FLAG_EMPTY != 0 {
+            return Ok(s);
+        }
+        if seed_hash != default_seed_hash() {
+            return Err(ThetaError::new(
+                "theta sketch was built with a different seed",
+            ));
+        }
+        s.empty = false;
+
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(8);
+        let num_entries = match pre_longs {
+            1 => 1,
+            2 | 3 => {
+                let n = cursor.read_i32::<LittleEndian>()?;
+                if n < 0 {
+                    return Err(ThetaError::new("negative number of theta sketch entries"));
+                }
+                n as usize
+            }
+            _ => {
+                return Err(ThetaError::new(format!(
+                    "unexpected number of theta sketch preamble longs {}",
+                    pre_longs
+                )))
+            }
+        };
+        if pre_longs == 3 {
+            cursor.set_position(16);
+            s.theta = cursor.read_u64::<LittleEndian>()?;
+            if s.theta == 0 || MAX_THETA < s.theta {
+                return Err(ThetaError::new(format!("invalid theta {}", s.theta)));
+            }
+        }
+        cursor.set_position(8 * pre_longs as u64);
+        if data.len() < 8 * (pre_longs as usize + num_entries) {
+            return Err(ThetaError::new("theta sketch is too short"));
+        }
+        for _ in 0..num_entries {
+            let h = cursor.read_u64::<LittleEndian>()?;
+            if h == 0 || s.theta <= h {
+                return Err(ThetaError::new(format!("invalid theta sketch hash {}", h)));
+            }
+            s.entries.insert(h);
+        }
+        return Ok(s);
+    }
+
+    /// Write as an ordered compact sketch, the same way DataSketches serializes them.
+    pub fn write(&self) -> Vec<u8> {
+        let seed_hash = default_seed_hash().to_le_bytes();
+        let mut flags = FLAG_READ_ONLY | FLAG_COMPACT | FLAG_ORDERED;
+        if self.empty {
+            flags |= FLAG_EMPTY;
+            return vec![
+                1,
+                SERIAL_VERSION,
+                COMPACT_FAMILY_ID,
+                0,
+                0,
+                flags,
+                seed_hash[0],
+                seed_hash[1],
+            ];
+        }
+
+        let estimating = self.theta < MAX_THETA;
+        let pre_longs: u8 = if estimating {
+            3
+        } else if self.entries.len() == 1 {
+            flags |= FLAG_SINGLE_ITEM;
+            1
+        } else {
+            2
+        };
+        let mut r = Vec::with_capacity(8 * (pre_longs as usize + self.entries.len()));
+        r.extend_from_slice(&[pre_longs, SERIAL_VERSION, COMPACT_FAMILY_ID, 0, 0, flags]);
+        r.extend_from_slice(&seed_hash);
+        // Writes into a vector do not fail.
+        if 2 <= pre_longs {
+            r.write_i32::<LittleEndian>(self.entries.len() as i32)
+                .unwrap();
+            // Sampling probability, always 1 for sketches built here.
+            r.write_f32::<LittleEndian>(1.0).unwrap();
+        }
+        if 3 <= pre_longs {
+            r.write_u64::<LittleEndian>(self.theta).unwrap();
+        }
+        for h in &self.entries {
+            r.write_u64::<LittleEndian>(*h).unwrap();
+        }
+        return r;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.empty;
+    }
+
+    /// Whether the sketch dropped some of the hashes, i.e. the result is an estimate.
+    pub fn is_estimation_mode(&self) -> bool {
+        return self.theta < MAX_THETA;
+    }
+
+    /// Fraction of the hash space covered by the sketch, between 0 and 1.
+    pub fn theta(&self) -> f64 {
+        return self.theta as f64 / MAX_THETA as f64;
+    }
+
+    /// The number of hashes kept in the sketch.
+    pub fn num_retained(&self) -> usize {
+        return self.entries.len();
+    }
+
+    /// Produces an estimate of the current set size.
+    pub fn estimate(&self) -> f64 {
+        return self.entries.len() as f64 / self.theta();
+    }
+
+    /// Same as `estimate()`, rounded to the nearest integer.
+    pub fn cardinality(&self) -> u64 {
+        return self.estimate().round() as u64;
+    }
+
+    /// Adds an element to the set. Same as `UpdateSketch.update(byte[])` in DataSketches, empty
+    /// inputs are ignored. Strings are added as their UTF-8 bytes.
+    pub fn update(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.update_hash(murmur3::hash64(data, DEFAULT_UPDATE_SEED) >> 1);
+    }
+
+    /// Adds an integer element to the set. Same as `UpdateSketch.update(long)` in DataSketches.
+    pub fn update_i64(&mut self, v: i64) {
+        self.update_hash(murmur3::hash64(&v.to_le_bytes(), DEFAULT_UPDATE_SEED) >> 1);
+    }
+
+    fn update_hash(&mut self, hash: u64) {
+        self.empty = false;
+        if hash == 0 || self.theta <= hash {
+            return;
+        }
+        self.entries.insert(hash);
+        self.trim();
+    }
+
+    /// Merges elements from `o` into the current sketch.
+    /// Afterwards the current sketch estimates the size of the union.
+    pub fn merge_with(&mut self, o: &ThetaSketch) {
+        if o.empty {
+            return;
+        }
+        self.empty = false;
+        self.set_theta(self.theta.min(o.theta));
+        let theta = self.theta;
+        self.entries
+            .extend(o.entries.iter().take_while(|h| **h < theta).cloned());
+        self.trim();
+    }
+
+    /// Keeps only the elements present in both sketches.
+    /// Afterwards the current sketch estimates the size of the intersection.
+    pub fn intersect_with(&mut self, o: &ThetaSketch) {
+        if self.empty || o.empty {
+            *self = ThetaSketch {
+                lg_k: self.lg_k,
+                ..ThetaSketch::new()
+            };
+            return;
+        }
+        self.set_theta(self.theta.min(o.theta));
+        self.entries.retain(|h| o.entries.contains(h));
+        self.reset_if_exact_and_empty();
+    }
+
+    /// Removes elements present in `o` from the current sketch.
+    /// Afterwards the current sketch estimates the size of the difference, i.e. `self AND NOT o`.
+    pub fn subtract(&mut self, o: &ThetaSketch) {
+        if self.empty || o.empty {
+            return;
+        }
+        self.set_theta(self.theta.min(o.theta));
+        self.entries.retain(|h| !o.entries.contains(h));
+        self.reset_if_exact_and_empty();
+    }
+
+    fn set_theta(&mut self, theta: u64) {
+        self.theta = theta;
+        // Drops the hashes not less than `theta`.
+        self.entries.split_off(&theta);
+    }
+
+    /// Drops the largest hashes until the sketch keeps at most `k` of them.
+    fn trim(&mut self) {
+        let k = 1usize << self.lg_k;
+        while k < self.entries.len() {
+            let max = *self.entries.iter().next_back().unwrap();
+            self.entries.remove(&max);
+            self.theta = max;
+        }
+    }
+
+    /// DataSketches reports an exact result without elements as an empty set.
+    fn reset_if_exact_and_empty(&mut self) {
+        if self.entries.is_empty() && self.theta == MAX_THETA {
+            self.empty = true;
+        }
+    }
+}
+
+/// Stored in serialized sketches to check they were built with the same seed.
+fn default_seed_hash() -> u16 {
+    return murmur3::hash64(&DEFAULT_UPDATE_SEED.to_le_bytes(), 0) as u16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch(range: std::ops::Range<i64>) -> ThetaSketch {
+        let mut s = ThetaSketch::new();
+        for i in range {
+            s.update_i64(i);
+        }
+        return s;
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= 0.05 * expected,
+            "estimate {} is too far off from {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_seed_hash() {
+        assert_eq!(default_seed_hash(), 0x93CC);
+    }
+
+    #[test]
+    fn test_empty() {
+        let s = ThetaSketch::new();
+        assert_eq!(hex::encode(s.write()), "01030300001ecc93");
+        let read = ThetaSketch::read(&s.write()).unwrap();
+        assert!(read.is_empty());
+        assert_eq!(read.estimate(), 0.);
+    }
+
+    #[test]
+    fn test_exact() {
+        let mut s = sketch(0..100);
+        s.update(b"value");
+        s.update(b"");
+        s.update_i64(5);
+        assert!(!s.is_estimation_mode());
+        assert_eq!(s.cardinality(), 101);
+
+        let read = ThetaSketch::read(&s.write()).unwrap();
+        assert_eq!(read, s);
+
+        let single = sketch(0..1);
+        let data = single.write();
+        assert_eq!(data.len(), 16);
+        assert_eq!(data[5], 0x3A);
+        assert_eq!(ThetaSketch::read(&data).unwrap(), single);
+    }
+
+    #[test]
+    fn test_estimation() {
+        let s = sketch(0..100000);
+        assert!(s.is_estimation_mode());
+        assert_eq!(s.num_retained(), 4096);
+        assert_close(s.estimate(), 100000.);
+
+        let read = ThetaSketch::read(&s.write()).unwrap();
+        assert_eq!(read.estimate(), s.estimate());
+        assert_eq!(read.write(), s.write());
+
+        let small = ThetaSketch::with_lg_k(6).unwrap();
+        assert_eq!(small.write(), ThetaSketch::new().write());
+        assert!(ThetaSketch::with_lg_k(27).is_err());
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a = sketch(0..60000);
+        let b = sketch(40000..100000);
+
+        let mut union = a.clone();
+        union.merge_with(&b);
+        assert_eq!(union.num_retained(), 4096);
+        assert_close(union.estimate(), 100000.);
+
+        let mut intersection = a.clone();
+        intersection.intersect_with(&b);
+        assert_close(intersection.estimate(), 20000.);
+
+        let mut a_not_b = a.clone();
+        a_not_b.subtract(&b);
+        assert_close(a_not_b.estimate(), 40000.);
+
+        let mut exact = sketch(0..10);
+        exact.intersect_with(&sketch(5..15));
+        assert_eq!(exact.cardinality(), 5);
+        exact.subtract(&sketch(0..100));
+        assert!(exact.is_empty());
+
+        let mut empty = sketch(0..10);
+        empty.intersect_with(&ThetaSketch::new());
+        assert!(empty.is_empty());
+
+        let mut not_empty = sketch(0..10);
+        not_empty.subtract(&ThetaSketch::new());
+        not_empty.merge_with(&ThetaSketch::new());
+        assert_eq!(not_empty.cardinality(), 10);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert!(ThetaSketch::read(&[]).is_err());
+        let mut data = sketch(0..10).write();
+        data[6] = 0;
+        assert!(ThetaSketch::read(&data).is_err());
+        let mut data = sketch(0..10).write();
+        data[2] = 2;
+        assert!(ThetaSketch::read(&data).is_err());
+        let data = sketch(0..10).write();
+        assert!(ThetaSketch::read(&data[..data.len() - 1]).is_err());
+    }
+}
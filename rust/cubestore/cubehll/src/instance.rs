@@ -17,7 +17,7 @@ use crate::error::Result;
 use crate::instance::HllInstance::{Dense, Sparse};
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use itertools::Itertools;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::collections::HashSet;
 use std::convert::TryInto;
@@ -67,6 +67,14 @@ impl HllInstance {
         };
     }
 
+    pub fn insert_hash(&mut self, hash: u64) {
+        match self {
+            Sparse(s) => s.insert_hash(hash),
+            Dense(d) => d.insert_hash(hash),
+        }
+        self.make_dense_if_necessary();
+    }
+
     /// Returns true iff `self.make_dense_if_necessary` has to be run.
     /// See comments inside the function for explanation on why we need this.
     fn merge_with_prepare(&mut self, o: &HllInstance) -> bool {
@@ -112,10 +120,6 @@ impl HllInstance {
                 v
             )));
         }
-        const ENC_EMPTY: u8 = 1;
-        const ENC_EXPLICIT: u8 = 2;
-        const ENC_SPARSE: u8 = 3;
-        const ENC_FULL: u8 = 4;
         let encoding = match data[0] & 0x0F {
             0 => {
                 return Err(HllError::new(
@@ -151,7 +155,7 @@ impl HllInstance {
         // Read the HLL body.
         let data = &data[3..];
         match encoding {
-            ENC_EMPTY => {
+            STORAGE_SPEC_ENC_EMPTY => {
                 if !data.is_empty() {
                     return Err(HllError::new(format!(
                         "HLL with encdoing EMPTY has data {} bytes",
@@ -160,7 +164,7 @@ impl HllInstance {
                 }
                 return HllInstance::new(num_buckets);
             }
-            ENC_EXPLICIT => {
+            STORAGE_SPEC_ENC_EXPLICIT => {
                 if data.len() % 8 != 0 {
                     return Err(HllError::new(format!(
                         "Size of EXPLICIT encoding is not a multiple of 8: {}",
@@ -190,7 +194,7 @@ impl HllInstance {
                     &values,
                 )?));
             }
-            ENC_SPARSE => {
+            STORAGE_SPEC_ENC_SPARSE => {
                 let mut cursor = BitCursor::new(data);
                 let entry_len = (log_num_buckets + reg_width) as usize;
                 let mut indices = Vec::new();
@@ -205,7 +209,7 @@ impl HllInstance {
                     &values,
                 )?));
             }
-            ENC_FULL => {
+            STORAGE_SPEC_ENC_FULL => {
                 let expected_bits = num_buckets * reg_width as u32;
                 let expected_len = expected_bits / 8 + (expected_bits % 8 != 0) as u32;
                 if data.len() != expected_len as usize {
@@ -230,6 +234,59 @@ impl HllInstance {
         }
     }
 
+    /// Writes v1 of https://github.com/aggregateknowledge/hll-storage-spec, i.e. the format of the
+    /// `hll` type in Postgres. Registers are 5 bits wide as in the default Postgres `hll` type, so
+    /// larger bucket values are truncated the same way Postgres does it.
+    pub fn write_hll_storage_spec(&self) -> Result<Vec<u8>> {
+        const REG_WIDTH: u8 = 5;
+        const MAX_VALUE: u8 = (1 << REG_WIDTH) - 1;
+        // Sparse representation is enabled, explicit cutoff is chosen automatically.
+        const CUTOFF: u8 = 0x7F;
+
+        let log_num_buckets = self.index_bit_len();
+        if log_num_buckets < 4 || 16 < log_num_buckets {
+            return Err(HllError::new(format!(
+                "Log2m must be between 4 and 16, got {}",
+                log_num_buckets
+            )));
+        }
+        let num_buckets = number_of_buckets(log_num_buckets) as usize;
+        let buckets = self.non_empty_buckets();
+
+        let encoding;
+        let mut body = BitWriter::new();
+        let sparse_entry_len = (log_num_buckets + REG_WIDTH) as usize;
+        if buckets.is_empty() {
+            encoding = STORAGE_SPEC_ENC_EMPTY;
+        } else if matches!(self, Sparse(_))
+            && buckets.len() * sparse_entry_len < num_buckets * REG_WIDTH as usize
+        {
+            // Only for sparse sketches. Dense ones would turn into sparse sketches on read and
+            // that changes the estimate.
+            encoding = STORAGE_SPEC_ENC_SPARSE;
+            for (bucket, value) in buckets {
+                let value = min(value, MAX_VALUE) as u64;
+                body.write_bits((bucket as u64) << REG_WIDTH | value, sparse_entry_len);
+            }
+        } else {
+            encoding = STORAGE_SPEC_ENC_FULL;
+            let mut values = vec![0; num_buckets];
+            for (bucket, value) in buckets {
+                values[bucket as usize] = min(value, MAX_VALUE);
+            }
+            for v in values {
+                body.write_bits(v as u64, REG_WIDTH as usize);
+            }
+        }
+
+        let mut r = Vec::with_capacity(3 + body.len());
+        r.push(1 << 4 | encoding);
+        r.push((REG_WIDTH - 1) << 5 | log_num_buckets);
+        r.push(CUTOFF);
+        r.extend_from_slice(&body.finish());
+        return Ok(r);
+    }
+
     pub fn read_snowflake(s: &str) -> Result<HllInstance> {
         #[derive(Deserialize)]
         struct SerializedHll {
@@ -270,6 +327,43 @@ impl HllInstance {
         }
     }
 
+    /// Writes the Snowflake JSON format, i.e. the input of HLL_IMPORT serialized to string.
+    pub fn write_snowflake(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct SerializedHll {
+            precision: u8,
+            version: u8,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            sparse: Option<SparseEntries>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            dense: Option<Vec<u8>>,
+        }
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct SparseEntries {
+            indices: Vec<u32>,
+            maxLzCounts: Vec<u8>,
+        }
+
+        let (sparse, dense) = match self {
+            Sparse(_) => {
+                let (indices, max_lz_counts) = self.non_empty_buckets().into_iter().unzip();
+                let sparse = SparseEntries {
+                    indices,
+                    maxLzCounts: max_lz_counts,
+                };
+                (Some(sparse), None)
+            }
+            Dense(_) => (None, Some(self.bucket_values())),
+        };
+        return Ok(serde_json::to_string(&SerializedHll {
+            precision: self.index_bit_len(),
+            version: 4,
+            sparse,
+            dense,
+        })?);
+    }
+
     pub fn read(data: &[u8]) -> Result<HllInstance> {
         if data.is_empty() {
             return Err(HllError::new("hll input data is empty"));
@@ -289,6 +383,43 @@ impl HllInstance {
         };
     }
 
+    /// Creates a dense instance from values of all buckets, e.g. computed by other HyperLogLog
+    /// implementations. The number of values must be a power of two.
+    pub fn from_bucket_values(values: Vec<u8>) -> Result<HllInstance> {
+        let index_bit_len = index_bit_length(values.len() as u32)?;
+        return Ok(Dense(DenseHll::new_from_entries(index_bit_len, values)?));
+    }
+
+    /// Values of all buckets, the result has `num_buckets()` elements.
+    pub fn bucket_values(&self) -> Vec<u8> {
+        let mut values = vec![0; self.num_buckets() as usize];
+        for (bucket, value) in self.non_empty_buckets() {
+            values[bucket as usize] = value;
+        }
+        return values;
+    }
+
+    /// Values of all non-empty buckets, ordered by bucket index.
+    fn non_empty_buckets(&self) -> Vec<(u32, u8)> {
+        match self {
+            Sparse(s) => {
+                let mut r: Vec<(u32, u8)> = Vec::with_capacity(s.entries.len());
+                // Several sparse entries can map into the same bucket.
+                s.each_bucket(|bucket, value| match r.last_mut() {
+                    Some((b, v)) if *b == bucket => *v = max(*v, value),
+                    _ => r.push((bucket, value)),
+                });
+                r
+            }
+            Dense(d) => (0..number_of_buckets(d.index_bit_len))
+                .filter_map(|bucket| match d.get_value(bucket) {
+                    0 => None,
+                    v => Some((bucket, v as u8)),
+                })
+                .collect(),
+        }
+    }
+
     fn ensure_dense(&mut self) -> &mut DenseHll {
         if let Dense(d) = self {
             return d;
@@ -356,15 +487,19 @@ impl SparseHll {
         }
 
         // Turn indices into the entries array inplace.
+        // TODO: validate range of index values.
         let mut entries = indices;
+        let mut num_entries = 0;
         for i in 0..entries.len() {
-            // TODO: validate range of index values.
-            // High bits are bucket index, followed by zeros and the actual value.
-            // Airlift stores actual bits of the hash in the available bits, but inputs of this
-            // function do not have this information, so we set those bits to 0.
-            let bucket = entries[i];
-            entries[i] = (bucket << (32 - index_bit_len)) | (values[i] as u32);
+            // Zero means the bucket is empty, same as in the dense representation.
+            if values[i] == 0 {
+                continue;
+            }
+            entries[num_entries] =
+                SparseHll::encode_bucket_value(index_bit_len, entries[i], values[i]);
+            num_entries += 1;
         }
+        entries.truncate(num_entries);
 
         // Sort by bucket index.
         entries
@@ -423,6 +558,26 @@ impl SparseHll {
         self.entries = self.merge_entries(o);
     }
 
+    pub fn insert_hash(&mut self, hash: u64) {
+        let bucket = compute_index(hash, SparseHll::EXTENDED_PREFIX_BITS);
+        let value = number_of_leading_zeros(hash, SparseHll::EXTENDED_PREFIX_BITS);
+
+        // Entries are sorted by bucket index, keep it that way.
+        match self
+            .entries
+            .binary_search_by_key(&bucket, |e| SparseHll::decode_bucket_index(*e))
+        {
+            Ok(pos) => {
+                if SparseHll::decode_bucket_value(self.entries[pos]) < value {
+                    self.entries[pos] = SparseHll::encode_entry(bucket, value);
+                }
+            }
+            Err(pos) => self
+                .entries
+                .insert(pos, SparseHll::encode_entry(bucket, value)),
+        }
+    }
+
     pub fn to_dense(&self) -> DenseHll {
         // TODO: this can panic if Sparse HLL had too much precision.
         let mut d = DenseHll::new(self.index_bit_len);
@@ -506,6 +661,22 @@ impl SparseHll {
         return result;
     }
 
+    /// Produces an entry that `each_bucket` will report as `value` for `bucket`.
+    /// Airlift stores actual bits of the hash after the bucket index, but callers of this function
+    /// do not have this information, so we choose the bits that encode the same value.
+    fn encode_bucket_value(index_bit_len: u8, bucket: u32, value: u8) -> u32 {
+        debug_assert!(0 < value);
+        let entry = bucket << (32 - index_bit_len);
+        let bits = SparseHll::EXTENDED_PREFIX_BITS - index_bit_len;
+        if value <= bits {
+            // The first set bit after the bucket index.
+            return entry | 1 << (32 - index_bit_len - value);
+        } else {
+            // All available bits are zero, the rest is stored in the value bits.
+            return entry | (value - bits - 1) as u32;
+        }
+    }
+
     fn encode_entry(bucket_index: u32, value: u8) -> u32 {
         return (bucket_index << SparseHll::VALUE_BITS) | value as u32;
     }
@@ -827,8 +998,7 @@ impl DenseHll {
         }
     }
 
-    #[allow(dead_code)]
-    fn insert_hash(&mut self, hash: u64) {
+    pub fn insert_hash(&mut self, hash: u64) {
         let index = compute_index(hash, self.index_bit_len);
         let value = compute_value(hash, self.index_bit_len);
 
@@ -1127,7 +1297,6 @@ fn index_bit_length(n: u32) -> Result<u8> {
     }
 }
 
-#[allow(dead_code)]
 fn compute_index(hash: u64, index_bit_len: u8) -> u32 {
     return (hash >> (64 - index_bit_len)) as u32;
 }
@@ -1136,7 +1305,6 @@ fn compute_value(hash: u64, index_bit_len: u8) -> u8 {
     return number_of_leading_zeros(hash, index_bit_len) + 1;
 }
 
-#[allow(dead_code)]
 fn number_of_leading_zeros(hash: u64, index_bit_len: u8) -> u8 {
     // place a 1 in the LSB to preserve the original number of leading zeros if the hash happens to be 0.
     let value = (hash << index_bit_len) | (1 << (index_bit_len - 1));
@@ -1166,6 +1334,52 @@ const TAG_DENSE_V1: u8 = 1;
 const TAG_SPARSE_V2: u8 = 2;
 const TAG_DENSE_V2: u8 = 3;
 
+// Encodings of the HLL storage spec, kept in the lower bits of the first byte.
+const STORAGE_SPEC_ENC_EMPTY: u8 = 1;
+const STORAGE_SPEC_ENC_EXPLICIT: u8 = 2;
+const STORAGE_SPEC_ENC_SPARSE: u8 = 3;
+const STORAGE_SPEC_ENC_FULL: u8 = 4;
+
+struct BitWriter {
+    output: Vec<u8>,
+    bit_pos: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            output: Vec::new(),
+            bit_pos: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.output.len()
+    }
+
+    /// Writes the lower [num_bits] of [v], starting from the most significant one. This is the
+    /// opposite of [BitCursor::read_bits].
+    pub fn write_bits(&mut self, v: u64, mut num_bits: usize) {
+        debug_assert!(num_bits <= 64);
+        while num_bits != 0 {
+            if self.bit_pos == 0 {
+                self.output.push(0);
+            }
+            let write_bits = min(num_bits, 8 - self.bit_pos);
+            let b = (v >> (num_bits - write_bits)) & ((1u64 << write_bits) - 1);
+            *self.output.last_mut().unwrap() |= (b << (8 - self.bit_pos - write_bits)) as u8;
+            num_bits -= write_bits;
+
+            self.bit_pos = (self.bit_pos + write_bits) % 8;
+        }
+    }
+
+    /// The last byte is padded with zeros.
+    pub fn finish(self) -> Vec<u8> {
+        self.output
+    }
+}
+
 struct BitCursor<'a> {
     input: &'a [u8],
     pos: usize,
@@ -1213,7 +1427,7 @@ mod tests {
     use std::cmp::max;
 
     mod serialization {
-        use crate::instance::HllInstance;
+        use crate::instance::{number_of_buckets, HllInstance};
 
         #[test]
         fn test_snowflake() {
@@ -1234,9 +1448,9 @@ mod tests {
             assert_eq!(
                 &sparse.entries,
                 &[
-                    233832449, 771751938, 1023410177, 1091567620, 1317011458, 1638924290,
-                    1898971139, 2335178753, 2440036353, 2552233986, 2647654404, 2785017858,
-                    3089104897, 3118465025, 3414163458, 3926917123, 3954180098, 4263510017
+                    234356736, 772014080, 1023934464, 1091633152, 1317273600, 1639186432,
+                    1899102208, 2335703040, 2440560640, 2552496128, 2647719936, 2785280000,
+                    3089629184, 3118989312, 3414425600, 3927048192, 3954442240, 4264034304
                 ]
             );
             assert_eq!(sparse.to_dense().cardinality(), 18);
@@ -1327,6 +1541,53 @@ mod tests {
             let h = read("148b7f21083288a4320a12086719c65108c1088422884511063388232904418c8520484184862886528c65198832106328c83114e6214831108518d03208851948511884188441908119083388661842818c43190c320ce4210a50948221083084a421c8328c632104221c4120d01284e20902318ca5214641942319101294641906228483184e128c43188e308882204a538c8328903288642102220c64094631086330c832106320c46118443886329062118a230c63108a320c23204a11852419c6528c85210a318c6308c41088842086308ce7110a418864190650884210ca631064108642a1022186518c8509862109020a0a4318671144150842400e5090631a0811848320c821888120c81114a220880290622906310d0220c83090a118c433106128c221902210cc23106029044114841104409862190c43188111063104c310c6728c8618c62290441102310c23214440882438ca2110a32908548c432110329462188a43946328842114640944320884190c928c442084228863318a2190a318c6618ca3114651886618c44190c5108e2110612144319062284641908428882314862106419883310421988619ca420cc511442104633888218c4428465288651910730c81118821088218c6418c45108452106519ce410d841904218863308622086211483198c710c83104a328c620906218864118623086418c8711423094632186420c4620c41104620a441108e40882628c6311c212046428c8319021104672888428ca320c431984418c4209043084451886510c641108310c4c20c66188472146310ca71084820c621946218c8228822190e2410861904411c27288621144328c6440c6311063190813086228ca710c2218c4718865188c2114850888608864404a3194e22882310ce53088619ca31904519503188e1118c4214cb2948110c6119c2818c843108520c43188c5204821186528c871908311086214c630c4218c8418cc3298a31888210c63110a121042198622886531082098c419c4210c6210c8338c25294610944518c442104610884104424206310c8311462288873102308c2440c451082228824310440982220c4240c622084310c642850118c641148430d0128c8228c2120c221884428863208c21a0a4190a4404c21186548865204633906308ca32086211c8319ce22146520c6120803318a518c840084519461208c21908538cc428c2110844384e40906320c44014a3204e62042408c8328c632146318c812004310c41318e3208a5308a511827104a4188c51048421446090a7088631102231484104473084318c41210860906919083190652906129c4628c45310652848221443114420084500865184a618c81198c32906418c63190e320c231882728484184671888309465188a320c83208632144318c6331c642988108c61218812144328d022844021022184a31908328c6218c2328c4528cc541428190641046418c84108443146230c6419483214232184411863290a210824318c220868194631106618c43188821048230c4128c6310c0330462094241106330c42188c321043118863046438823110a041464108e3190e4209a11902439c43188631104321008090441106218c6419064294a229463594622244320cc71184510902924421908218c62308641044328ca328882111012884120ca52882428c62184442086718c4221c8211082208a321023115270086218c4218c6528ce400482310a520c43104a520c44210811884118c4310864198263942331822").unwrap();
             assert_eq!(h.cardinality(), 9722);
         }
+
+        fn assert_same_buckets(l: &HllInstance, r: &HllInstance) {
+            assert_eq!(l.index_bit_len(), r.index_bit_len());
+            assert_eq!(l.non_empty_buckets(), r.non_empty_buckets());
+            assert_eq!(l.cardinality(), r.cardinality());
+        }
+
+        #[test]
+        fn test_write_hll_storage_spec() {
+            let read = |s: &str| HllInstance::read_hll_storage_spec(&hex::decode(s).unwrap());
+            for input in &["118b7f", "138b7f04a10642078507c308e309230a420ac10c2510a2114511611363138116811848188218a119411a821ae11f0122e223a125a126632685276327a328e2296129e52b812fe23081320132c133e335a53641368236a23721374237e1382138e13a813c243e6140e341854304434148a24a034f8150c1520152e254e155a1564157e158e35ac25b265b615c615fc1620166a368226a416a626c016c816d677163728275817a637a817ac37b617c247c427d677f6180e18101826382e1846184e18541858287e1880189218a418b818bc38e018ea290a19244938295e4988198c299e29b239b419c419ce49da1a1e1a321a381a4c1aa61acc2ae01b0a1b101b142b161b443b801bd02bd61bf61c263c4a3c501c7a1caa1cb03cd03cf03cf42d123d4c3d662d744d901dd01df81e001e0a2e641e7e3edc1f0a2f1c1f203f484f5c4f763fc84fdc1fe02fea1", "148b7f21083288a4320a12086719c65108c1088422884511063388232904418c8520484184862886528c65198832106328c83114e6214831108518d03208851948511884188441908119083388661842818c43190c320ce4210a50948221083084a421c8328c632104221c4120d01284e20902318ca5214641942319101294641906228483184e128c43188e308882204a538c8328903288642102220c64094631086330c832106320c46118443886329062118a230c63108a320c23204a11852419c6528c85210a318c6308c41088842086308ce7110a418864190650884210ca631064108642a1022186518c8509862109020a0a4318671144150842400e5090631a0811848320c821888120c81114a220880290622906310d0220c83090a118c433106128c221902210cc23106029044114841104409862190c43188111063104c310c6728c8618c62290441102310c23214440882438ca2110a32908548c432110329462188a43946328842114640944320884190c928c442084228863318a2190a318c6618ca3114651886618c44190c5108e2110612144319062284641908428882314862106419883310421988619ca420cc511442104633888218c4428465288651910730c81118821088218c6418c45108452106519ce410d841904218863308622086211483198c710c83104a328c620906218864118623086418c8711423094632186420c4620c41104620a441108e40882628c6311c212046428c8319021104672888428ca320c431984418c4209043084451886510c641108310c4c20c66188472146310ca71084820c621946218c8228822190e2410861904411c27288621144328c6440c6311063190813086228ca710c2218c4718865188c2114850888608864404a3194e22882310ce53088619ca31904519503188e1118c4214cb2948110c6119c2818c843108520c43188c5204821186528c871908311086214c630c4218c8418cc3298a31888210c63110a121042198622886531082098c419c4210c6210c8338c25294610944518c442104610884104424206310c8311462288873102308c2440c451082228824310440982220c4240c622084310c642850118c641148430d0128c8228c2120c221884428863208c21a0a4190a4404c21186548865204633906308ca32086211c8319ce22146520c6120803318a518c840084519461208c21908538cc428c2110844384e40906320c44014a3204e62042408c8328c632146318c812004310c41318e3208a5308a511827104a4188c51048421446090a7088631102231484104473084318c41210860906919083190652906129c4628c45310652848221443114420084500865184a618c81198c32906418c63190e320c231882728484184671888309465188a320c83208632144318c6331c642988108c61218812144328d022844021022184a31908328c6218c2328c4528cc541428190641046418c84108443146230c6419483214232184411863290a210824318c220868194631106618c43188821048230c4128c6310c0330462094241106330c42188c321043118863046438823110a041464108e3190e4209a11902439c43188631104321008090441106218c6419064294a229463594622244320cc71184510902924421908218c62308641044328ca328882111012884120ca52882428c62184442086718c4221c8211082208a321023115270086218c4218c6528ce400482310a520c43104a520c44210811884118c4310864198263942331822"] {
+                let h = read(input).unwrap();
+                // Inputs are written the same way Postgres does it, so we should get the same bytes.
+                assert_eq!(hex::encode(h.write_hll_storage_spec().unwrap()), *input);
+                assert_same_buckets(&h, &read(input).unwrap());
+            }
+
+            // Values larger than the register width are truncated.
+            let mut h = HllInstance::new(number_of_buckets(11)).unwrap();
+            h.insert_hash(1 << 17);
+            assert_eq!(h.non_empty_buckets(), vec![(0, 36)]);
+            let written =
+                HllInstance::read_hll_storage_spec(&h.write_hll_storage_spec().unwrap()).unwrap();
+            assert_eq!(written.non_empty_buckets(), vec![(0, 31)]);
+
+            let h = HllInstance::new(number_of_buckets(2)).unwrap();
+            assert!(h.write_hll_storage_spec().is_err());
+        }
+
+        #[test]
+        fn test_write_snowflake() {
+            let inputs = [
+                r#"{"precision":12,"version":4,"sparse":{"indices":[223,736,976,1041,1256,1563,1811,2227,2327,2434,2525,2656,2946,2974,3256,3745,3771,4066],"maxLzCounts":[1,2,1,4,2,2,3,1,1,2,4,2,1,1,2,3,2,1]}}"#,
+                r#"{"precision":4,"version":4,"dense":[0,0,3,1,0,1,2,2,0,0,3,1,2,0,2,1]}"#,
+            ];
+            for input in &inputs {
+                let h = HllInstance::read_snowflake(input).unwrap();
+                assert_eq!(h.write_snowflake().unwrap(), *input);
+            }
+
+            let mut h = HllInstance::new(number_of_buckets(12)).unwrap();
+            for i in 0..100 {
+                h.insert_hash(crate::murmur3::hash64_i64(i));
+            }
+            let written = HllInstance::read_snowflake(&h.write_snowflake().unwrap()).unwrap();
+            assert_same_buckets(&h, &written);
+        }
     }
 
     mod dense {
@@ -1559,7 +1820,71 @@ mod tests {
             assert_eq!(hll.cardinality(), 655);
         }
     }
-    // TODO: port tests for Sparse HLLs and HLLInstance.
+
+    mod instance {
+        use crate::instance::tests::TestingHll;
+        use crate::instance::{number_of_buckets, DenseHll, HllInstance};
+        use std::hash::Hasher;
+        use twox_hash::XxHash64;
+
+        #[test]
+        fn test_insert_hash() {
+            for prefix_bit_len in 4..17 {
+                let mut testing_hll = TestingHll::new(prefix_bit_len);
+                let mut hll = HllInstance::new(number_of_buckets(prefix_bit_len)).unwrap();
+                for i in 0..10_000 {
+                    let mut hasher = XxHash64::default();
+                    hasher.write_i32(i);
+                    let h = hasher.finish();
+
+                    testing_hll.insert_hash(h);
+                    hll.insert_hash(h);
+                    // Inserting the same hash twice must not change anything.
+                    hll.insert_hash(h);
+                }
+                let dense = hll.ensure_dense();
+                for i in 0..testing_hll.buckets().len() {
+                    assert_eq!(dense.get_value(i as u32), testing_hll.buckets()[i]);
+                }
+            }
+        }
+
+        #[test]
+        fn test_bucket_values() {
+            let mut sparse = HllInstance::new(number_of_buckets(12)).unwrap();
+            let mut dense = HllInstance::Dense(DenseHll::new(12));
+            for i in 0..100 {
+                let h = crate::murmur3::hash64_i64(i);
+                sparse.insert_hash(h);
+                dense.insert_hash(h);
+            }
+            assert!(matches!(sparse, HllInstance::Sparse(_)));
+            assert_eq!(sparse.bucket_values(), dense.bucket_values());
+
+            let values = dense.bucket_values();
+            let restored = HllInstance::from_bucket_values(values.clone()).unwrap();
+            assert_eq!(restored.bucket_values(), values);
+            assert_eq!(restored.cardinality(), dense.cardinality());
+
+            assert!(HllInstance::from_bucket_values(vec![0; 100]).is_err());
+        }
+
+        #[test]
+        fn test_sparse_insert_roundtrip() {
+            let mut hll = HllInstance::new(2048).unwrap();
+            for i in 0..100 {
+                let mut hasher = XxHash64::default();
+                hasher.write_i32(i);
+                hll.insert_hash(hasher.finish());
+            }
+            assert!(matches!(hll, HllInstance::Sparse(_)));
+            assert_eq!(hll.cardinality(), 100);
+
+            let read = HllInstance::read(&hll.write()).unwrap();
+            assert_eq!(read.cardinality(), 100);
+        }
+    }
+    // TODO: port the rest of tests for Sparse HLLs and HLLInstance.
 
     struct TestingHll {
         index_bit_length: u8,
@@ -14,14 +14,15 @@
 
 use crate::error::Result;
 use crate::instance::HllInstance;
+use crate::murmur3;
 
 /// HyperLogLog sketch estimates a size of a set (i.e. the number of unique elements in it) without
 /// storing all the elements in the set.
 ///
 /// Port of the HyperLogLog from Airlift.
 /// You can deserialize sketches produced by Airlift by using `read()`.
-/// In fact, the code to add new elements has not been ported yet, so reading existing sketches is
-/// the only way to produce non-empty sets at this point.
+/// Elements are hashed with the same Murmur3 hash as in Airlift, so sketches built with `insert()`
+/// can be merged with the ones produced by Presto's `approx_set`.
 #[derive(Debug, Clone)]
 pub struct HllSketch {
     instance: HllInstance,
@@ -64,6 +65,31 @@ impl HllSketch {
         });
     }
 
+    /// Create a sketch from the values of all buckets, e.g. registers of another HyperLogLog
+    /// implementation that uses the same bucket index and value definitions.
+    pub fn from_bucket_values(values: Vec<u8>) -> Result<HllSketch> {
+        return Ok(HllSketch {
+            instance: HllInstance::from_bucket_values(values)?,
+        });
+    }
+
+    /// Values of all buckets, i.e. the number of leading zeros in the hash bits after the bucket
+    /// index plus one, or zero for empty buckets.
+    pub fn bucket_values(&self) -> Vec<u8> {
+        return self.instance.bucket_values();
+    }
+
+    /// Write in the format of the `hll` type in Postgres, see
+    /// https://github.com/aggregateknowledge/hll-storage-spec.
+    pub fn write_hll_storage_spec(&self) -> Result<Vec<u8>> {
+        return self.instance.write_hll_storage_spec();
+    }
+
+    /// Write in the snowflake JSON format, i.e. the input of HLL_IMPORT serialized to string.
+    pub fn write_snowflake(&self) -> Result<String> {
+        return self.instance.write_snowflake();
+    }
+
     pub fn write(&self) -> Vec<u8> {
         return self.instance.write();
     }
@@ -73,6 +99,22 @@ impl HllSketch {
         return self.instance.cardinality();
     }
 
+    /// Adds an element to the set. Same as `HyperLogLog.add(Slice)` in Airlift.
+    pub fn insert(&mut self, data: &[u8]) {
+        self.insert_hash(murmur3::hash64(data));
+    }
+
+    /// Adds an integer element to the set. Same as `HyperLogLog.add(long)` in Airlift.
+    pub fn insert_i64(&mut self, v: i64) {
+        self.insert_hash(murmur3::hash64_i64(v));
+    }
+
+    /// Adds an element by its precomputed hash. Only sketches built with the same hash function
+    /// can be merged.
+    pub fn insert_hash(&mut self, hash: u64) {
+        self.instance.insert_hash(hash);
+    }
+
     /// Merges elements from `o` into the current sketch.
     /// Afterwards the current sketch estimates the size of the union.
     ///
@@ -81,3 +123,37 @@ impl HllSketch {
         self.instance.merge_with(&o.instance);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut s = HllSketch::new(2048).unwrap();
+        for i in 0..1000 {
+            s.insert_i64(i);
+            s.insert(format!("value_{}", i).as_bytes());
+        }
+        // Duplicates must not change the estimate.
+        let c = s.cardinality();
+        s.insert_i64(5);
+        s.insert(b"value_5");
+        assert_eq!(s.cardinality(), c);
+        assert!(1900 <= c && c <= 2100, "estimate {} is too far off", c);
+
+        let mut other = HllSketch::new(2048).unwrap();
+        for i in 500..1500 {
+            other.insert_i64(i);
+        }
+        let mut read = HllSketch::read(&s.write()).unwrap();
+        assert_eq!(read.cardinality(), c);
+        read.merge_with(&other);
+        let merged = read.cardinality();
+        assert!(
+            2350 <= merged && merged <= 2650,
+            "estimate {} is too far off",
+            merged
+        );
+    }
+}
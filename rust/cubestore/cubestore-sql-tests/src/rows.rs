@@ -27,6 +27,7 @@ impl_to_row!(T);
 impl_to_row!(T1, T2);
 impl_to_row!(T1, T2, T3);
 impl_to_row!(T1, T2, T3, T4);
+impl_to_row!(T1, T2, T3, T4, T5);
 
 pub trait ToValue {
     fn to_val(&self) -> TableValue;
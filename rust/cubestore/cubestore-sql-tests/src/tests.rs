@@ -124,6 +124,10 @@ pub fn sql_tests() -> Vec<(&'static str, TestFn)> {
         t("hyperloglog_inplace_group_by", hyperloglog_inplace_group_by),
         t("hyperloglog_postgres", hyperloglog_postgres),
         t("hyperloglog_snowflake", hyperloglog_snowflake),
+        t("hyperloglog_init", hyperloglog_init),
+        t("hyperloglog_export", hyperloglog_export),
+        t("hyperloglog_convert", hyperloglog_convert),
+        t("theta_sketches", theta_sketches),
         t("planning_inplace_aggregate", planning_inplace_aggregate),
         t("planning_hints", planning_hints),
         t("planning_inplace_aggregate2", planning_inplace_aggregate2),
@@ -143,9 +147,11 @@ pub fn sql_tests() -> Vec<(&'static str, TestFn)> {
         ),
         t("topk_query", topk_query),
         t("topk_having", topk_having),
+        t("topk_avg_count", topk_avg_count),
         t("topk_decimals", topk_decimals),
         t("planning_topk_having", planning_topk_having),
         t("planning_topk_hll", planning_topk_hll),
+        t("planning_topk_narrowing_cast", planning_topk_narrowing_cast),
         t("topk_hll", topk_hll),
         t("topk_hll_with_nulls", topk_hll_with_nulls),
         t("offset", offset),
@@ -218,6 +224,10 @@ pub fn sql_tests() -> Vec<(&'static str, TestFn)> {
             aggregate_index_with_hll_bytes,
         ),
         t("aggregate_index_errors", aggregate_index_errors),
+        t("materialized_view", materialized_view),
+        t("materialized_view_errors", materialized_view_errors),
+        t("window_functions", window_functions),
+        t("grouping_sets", grouping_sets),
         t("inline_tables", inline_tables),
         t("inline_tables_2x", inline_tables_2x),
         t("build_range_end", build_range_end),
@@ -2553,6 +2563,285 @@ async fn hyperloglog_snowflake(service: Box<dyn SqlClient>) {
         .unwrap_err();
 }
 
+async fn hyperloglog_init(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA hll").await.unwrap();
+    service
+        .exec_query("CREATE TABLE hll.data (key int, id int, s text)")
+        .await
+        .unwrap();
+    service
+        .exec_query(
+            "INSERT INTO hll.data (key, id, s) VALUES \
+             (1, 1, 'a'), (1, 2, 'b'), (1, 3, 'c'), (1, 3, 'c'), \
+             (2, 3, 'c'), (2, 4, 'd'), (2, 5, 'e'), (2, 6, 'f'), (2, 7, 'g'), (2, NULL, NULL)",
+        )
+        .await
+        .unwrap();
+
+    let r = service
+        .exec_query(
+            "SELECT key, cardinality(hll_init_agg(id)), cardinality(hll_init_agg(s)) \
+             FROM hll.data GROUP BY 1 ORDER BY 1",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[(1, 3, 3), (2, 5, 5)]));
+
+    // Sketches built per group are mergeable.
+    for flavour in &["hyperloglog", "hyperloglogpp"] {
+        let r = service
+            .exec_query(&format!(
+                "SELECT cardinality(merge(h)) \
+                 FROM (SELECT key, hll_init_agg(id, '{}') h FROM hll.data GROUP BY 1) x",
+                flavour
+            ))
+            .await
+            .unwrap();
+        assert_eq!(to_rows(&r), rows(&[7]), "flavour {}", flavour);
+
+        let r = service
+            .exec_query(&format!(
+                "SELECT cardinality(merge(hll_init(s, '{}'))) FROM hll.data",
+                flavour
+            ))
+            .await
+            .unwrap();
+        assert_eq!(to_rows(&r), rows(&[7]), "flavour {}", flavour);
+    }
+
+    let r = service
+        .exec_query("SELECT id, cardinality(hll_init(id)) FROM hll.data WHERE key = 2 ORDER BY 1")
+        .await
+        .unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[
+            (Some(3), Some(1)),
+            (Some(4), Some(1)),
+            (Some(5), Some(1)),
+            (Some(6), Some(1)),
+            (Some(7), Some(1)),
+            (None, None),
+        ])
+    );
+
+    service
+        .exec_query("SELECT hll_init_agg(id, 'unknown') FROM hll.data")
+        .await
+        .unwrap_err();
+}
+
+async fn hyperloglog_export(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA hll").await.unwrap();
+    service
+        .exec_query("CREATE TABLE hll.data (key int, id int)")
+        .await
+        .unwrap();
+    let values = (0..1000)
+        .map(|i| format!("({}, {})", if i < 10 { 1 } else { 2 }, i))
+        .join(", ");
+    service
+        .exec_query(&format!("INSERT INTO hll.data (key, id) VALUES {}", values))
+        .await
+        .unwrap();
+    service
+        .exec_query("CREATE TABLE hll.pg (key int, hll HLL_POSTGRES)")
+        .await
+        .unwrap();
+    service
+        .exec_query("CREATE TABLE hll.sf (key int, hll HLL_SNOWFLAKE)")
+        .await
+        .unwrap();
+
+    // Exported sketches are imported back with the same estimates.
+    let r = service
+        .exec_query(
+            "SELECT key, cardinality(h), hll_export_postgres(h), hll_export_snowflake(h) \
+             FROM (SELECT key, hll_init_agg(id) h FROM hll.data GROUP BY 1) x ORDER BY 1",
+        )
+        .await
+        .unwrap();
+    let mut expected = Vec::new();
+    for row in r.get_rows() {
+        let (key, cardinality, pg, sf) = match row.values().as_slice() {
+            [TableValue::Int(k), TableValue::Int(c), TableValue::Bytes(pg), TableValue::String(sf)] => {
+                (*k, *c, pg.clone(), sf.clone())
+            }
+            v => panic!("unexpected row: {:?}", v),
+        };
+        let pg = pg.iter().map(|b| format!("{:02x}", b)).join("");
+        service
+            .exec_query(&format!(
+                "INSERT INTO hll.pg (key, hll) VALUES ({}, X'{}')",
+                key, pg
+            ))
+            .await
+            .unwrap();
+        service
+            .exec_query(&format!(
+                "INSERT INTO hll.sf (key, hll) VALUES ({}, '{}')",
+                key, sf
+            ))
+            .await
+            .unwrap();
+        expected.push((key, cardinality));
+    }
+    assert_eq!(expected.len(), 2);
+    for table in &["hll.pg", "hll.sf"] {
+        let r = service
+            .exec_query(&format!(
+                "SELECT key, cardinality(hll) FROM {} ORDER BY 1",
+                table
+            ))
+            .await
+            .unwrap();
+        assert_eq!(to_rows(&r), rows(&expected), "table {}", table);
+    }
+
+    // ZetaSketch has a different structure and can not be exported.
+    service
+        .exec_query("SELECT hll_export_postgres(hll_init_agg(id, 'hyperloglogpp')) FROM hll.data")
+        .await
+        .unwrap_err();
+    service
+        .exec_query("SELECT hll_export_snowflake(hll_init_agg(id, 'hyperloglogpp')) FROM hll.data")
+        .await
+        .unwrap_err();
+}
+
+async fn hyperloglog_convert(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA hll").await.unwrap();
+    service
+        .exec_query("CREATE TABLE hll.data (id int)")
+        .await
+        .unwrap();
+    service
+        .exec_query("INSERT INTO hll.data (id) VALUES (1), (2), (3), (3), (3), (4), (5), (6), (7)")
+        .await
+        .unwrap();
+
+    let r = service
+        .exec_query(
+            "SELECT cardinality(hll_convert(hll_init_agg(id), 'zetasketch')), \
+                    cardinality(hll_convert(hll_init_agg(id, 'hyperloglogpp'), 'airlift')) \
+             FROM hll.data",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[(7, 7)]));
+
+    // Converted sketches can be merged with the sketches of the target flavour.
+    let r = service
+        .exec_query(
+            "SELECT cardinality(merge(h)) FROM (SELECT hll_init_agg(id) h FROM hll.data \
+             UNION ALL SELECT hll_convert(hll_init_agg(id, 'hyperloglogpp'), 'airlift') h FROM hll.data) x",
+        )
+        .await
+        .unwrap();
+    // Values are hashed differently in Airlift and ZetaSketch, so they are counted twice.
+    assert_eq!(to_rows(&r), rows(&[14]));
+
+    // MERGE converts sketches of different flavours implicitly.
+    let r = service
+        .exec_query(
+            "SELECT cardinality(merge(h)) FROM (SELECT hll_init_agg(id) h FROM hll.data \
+             UNION ALL SELECT hll_init_agg(id, 'hyperloglogpp') h FROM hll.data) x",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[14]));
+
+    service
+        .exec_query("SELECT hll_convert(hll_init_agg(id), 'unknown') FROM hll.data")
+        .await
+        .unwrap_err();
+}
+
+async fn theta_sketches(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA s").await.unwrap();
+    service
+        .exec_query("CREATE TABLE s.events (day int, user_id int)")
+        .await
+        .unwrap();
+    service
+        .exec_query(
+            "INSERT INTO s.events (day, user_id) VALUES (1, 1), (1, 2), (1, 3), (1, 3), \
+                                                        (2, 3), (2, 4), (2, 5), (2, 6)",
+        )
+        .await
+        .unwrap();
+
+    let r = service
+        .exec_query(
+            "SELECT day, theta_estimate(theta_init_agg(user_id)) FROM s.events \
+             GROUP BY 1 ORDER BY 1",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[(1, 3), (2, 4)]));
+
+    let r = service
+        .exec_query(
+            "SELECT theta_estimate(theta_union(u)), theta_estimate(theta_intersect(u)) \
+             FROM (SELECT day, theta_init_agg(user_id) u FROM s.events GROUP BY 1) x",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[(6, 1)]));
+
+    // Users that were active on the first day, but did not come back on the second one.
+    let r = service
+        .exec_query(
+            "SELECT theta_estimate(theta_a_not_b(\
+                        theta_init_agg(CASE WHEN day = 1 THEN user_id END), \
+                        theta_init_agg(CASE WHEN day = 2 THEN user_id END))) \
+             FROM s.events",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[2]));
+
+    // Compact sketches of {1, 2, 3} and {3, 4, 5}.
+    let a = "X'02030300001ACC93030000000000803F15F97DCBBD86A105C397FC1281709D1EBA40B3C1DA06695D'";
+    let b = "X'02030300001ACC93030000000000803F40DE2EE1C9DB3D08BD3273724691CC14BA40B3C1DA06695D'";
+    service
+        .exec_query(
+            "CREATE TABLE s.sketches (day int, users theta_sketch) \
+             AGGREGATIONS(merge(users)) \
+             AGGREGATE INDEX agg_index (day)",
+        )
+        .await
+        .unwrap();
+    service
+        .exec_query(&format!(
+            "INSERT INTO s.sketches (day, users) VALUES (1, {a}), (1, {b}), (2, {b})",
+            a = a,
+            b = b
+        ))
+        .await
+        .unwrap();
+
+    let r = service
+        .exec_query(
+            "SELECT day, theta_estimate(theta_union(users)) FROM s.sketches \
+             GROUP BY 1 ORDER BY 1",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[(1, 5), (2, 3)]));
+
+    let r = service
+        .exec_query("SELECT theta_estimate(theta_intersect(users)) FROM s.sketches")
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[1]));
+
+    service
+        .exec_query("INSERT INTO s.sketches (day, users) VALUES (3, X'0102')")
+        .await
+        .unwrap_err();
+}
+
 async fn planning_inplace_aggregate(service: Box<dyn SqlClient>) {
     service.exec_query("CREATE SCHEMA s").await.unwrap();
     service
@@ -3544,6 +3833,102 @@ async fn topk_query(service: Box<dyn SqlClient>) {
     assert_eq!(to_rows(&r), rows(&[("a", 1), ("e", 35), ("d", 40)]));
 }
 
+async fn topk_avg_count(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA s").await.unwrap();
+    service
+        .exec_query("CREATE TABLE s.Data1(url text, hits int)")
+        .await
+        .unwrap();
+    service
+            .exec_query("INSERT INTO s.Data1(url, hits) VALUES ('a', 1), ('b', 2), ('c', 3), ('d', 4), ('e', 5), ('z', 100)")
+            .await
+            .unwrap();
+    service
+        .exec_query("CREATE TABLE s.Data2(url text, hits int)")
+        .await
+        .unwrap();
+    service
+            .exec_query("INSERT INTO s.Data2(url, hits) VALUES ('b', 50), ('c', 45), ('d', 40), ('e', 35), ('y', 80)")
+            .await
+            .unwrap();
+
+    // Avg is not monotone, but still can be computed with top-k.
+    let r = service
+        .exec_query(
+            "SELECT `url` `url`, AVG(`hits`) `hits` \
+                         FROM (SELECT * FROM s.Data1 \
+                               UNION ALL \
+                               SELECT * FROM s.Data2) AS `Data` \
+                         GROUP BY 1 \
+                         ORDER BY 2 DESC \
+                         LIMIT 3",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[("z", 100.), ("y", 80.), ("b", 26.)]));
+
+    let r = service
+        .exec_query(
+            "SELECT `url` `url`, AVG(`hits`) `hits` \
+                         FROM (SELECT * FROM s.Data1 \
+                               UNION ALL \
+                               SELECT * FROM s.Data2) AS `Data` \
+                         GROUP BY 1 \
+                         ORDER BY 2 ASC \
+                         LIMIT 3",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[("a", 1.), ("e", 20.), ("d", 22.)]));
+
+    // Count with a secondary sort column.
+    let r = service
+        .exec_query(
+            "SELECT `url` `url`, COUNT(`hits`) `c`, SUM(`hits`) `hits` \
+                         FROM (SELECT * FROM s.Data1 \
+                               UNION ALL \
+                               SELECT * FROM s.Data2) AS `Data` \
+                         GROUP BY 1 \
+                         ORDER BY 2 DESC, 3 DESC \
+                         LIMIT 3",
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[("b", 2, 52), ("c", 2, 48), ("d", 2, 44)])
+    );
+
+    // Monotone expressions over aggregates.
+    let r = service
+        .exec_query(
+            "SELECT `url` `url`, SUM(`hits`) * -1 `hits` \
+                         FROM (SELECT * FROM s.Data1 \
+                               UNION ALL \
+                               SELECT * FROM s.Data2) AS `Data` \
+                         GROUP BY 1 \
+                         ORDER BY 2 ASC \
+                         LIMIT 2",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[("z", -100), ("y", -80)]));
+
+    let r = service
+        .exec_query(
+            "SELECT `url` `url`, MAX(`hits`) `hits` \
+                         FROM (SELECT * FROM s.Data1 \
+                               UNION ALL \
+                               SELECT * FROM s.Data2) AS `Data` \
+                         GROUP BY 1 \
+                         ORDER BY -MAX(`hits`) DESC \
+                         LIMIT 2",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[("a", 1), ("e", 35)]));
+}
+
 async fn topk_having(service: Box<dyn SqlClient>) {
     service.exec_query("CREATE SCHEMA s").await.unwrap();
     service
@@ -3739,6 +4124,42 @@ async fn planning_topk_having(service: Box<dyn SqlClient>) {
         \n                  Empty"
         );
 }
+async fn planning_topk_narrowing_cast(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA s").await.unwrap();
+    service
+        .exec_query("CREATE TABLE s.Data1(url text, hits int)")
+        .await
+        .unwrap();
+    service
+        .exec_query("CREATE TABLE s.Data2(url text, hits int)")
+        .await
+        .unwrap();
+    let topk_query = |hits: &str| {
+        format!(
+            "SELECT `url` `url`, {} `hits` \
+             FROM (SELECT * FROM s.Data1 \
+                   UNION ALL \
+                   SELECT * FROM s.Data2) AS `Data` \
+             GROUP BY 1 \
+             ORDER BY 2 DESC \
+             LIMIT 3",
+            hits
+        )
+    };
+    let p = service
+        .plan_query(&topk_query("CAST(MAX(`hits`) AS BIGINT) * 2"))
+        .await
+        .unwrap();
+    assert!(pp_phys_plan(p.worker.as_ref()).contains("AggregateTopK"));
+
+    // Narrowing casts change the order of values, e.g. on overflow.
+    let p = service
+        .plan_query(&topk_query("CAST(MAX(`hits`) AS SMALLINT)"))
+        .await
+        .unwrap();
+    assert!(!pp_phys_plan(p.worker.as_ref()).contains("AggregateTopK"));
+}
+
 async fn planning_topk_hll(service: Box<dyn SqlClient>) {
     service.exec_query("CREATE SCHEMA s").await.unwrap();
     service
@@ -5890,6 +6311,241 @@ async fn aggregate_index_errors(service: Box<dyn SqlClient>) {
         .expect_err("Aggregate function MERGE not allowed for column type integer");
 }
 
+async fn materialized_view(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA s").await.unwrap();
+    service
+        .exec_query("CREATE TABLE s.Orders(platform text, age int, amount int)")
+        .await
+        .unwrap();
+    service
+        .exec_query(
+            "CREATE MATERIALIZED VIEW s.ByPlatform AS \
+             SELECT platform, SUM(amount) total, COUNT(*) cnt, COUNT(age) with_age, MAX(age) max_age \
+             FROM s.Orders \
+             GROUP BY platform",
+        )
+        .await
+        .unwrap();
+
+    service
+        .exec_query(
+            "INSERT INTO s.Orders(platform, age, amount) VALUES \
+             ('ios', 20, 10), ('android', 30, 5), ('ios', 40, 1)",
+        )
+        .await
+        .unwrap();
+    service
+        .exec_query(
+            "INSERT INTO s.Orders(platform, age, amount) VALUES \
+             ('android', 25, 7), ('web', NULL, 3)",
+        )
+        .await
+        .unwrap();
+
+    // Rows from different inserts are merged on read, before compaction.
+    let r = service
+        .exec_query("SELECT * FROM s.ByPlatform ORDER BY 1")
+        .await
+        .unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[
+            ("android", 12, 2, 2, Some(30)),
+            ("ios", 11, 2, 2, Some(40)),
+            ("web", 3, 1, 0, None),
+        ])
+    );
+
+    let r = service
+        .exec_query("SELECT SUM(total), SUM(cnt) FROM s.ByPlatform")
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[(26, 5)]));
+
+    let r = service
+        .exec_query("SELECT COUNT(*) FROM s.ByPlatform")
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[(3)]));
+
+    let r = service
+        .exec_query(
+            "SELECT table_name, source_table, status, lag_seconds FROM system.materialized_views",
+        )
+        .await
+        .unwrap();
+    assert_eq!(to_rows(&r), rows(&[("ByPlatform", "s.Orders", "ready", 0)]));
+}
+
+async fn materialized_view_errors(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA s").await.unwrap();
+    service
+        .exec_query("CREATE TABLE s.Orders(platform text, age int, amount int)")
+        .await
+        .unwrap();
+    service
+        .exec_query("CREATE MATERIALIZED VIEW s.V AS SELECT platform, SUM(amount) FROM s.Orders GROUP BY platform")
+        .await
+        .expect_err("Aggregate function in materialized view must have an alias");
+    service
+        .exec_query("CREATE MATERIALIZED VIEW s.V AS SELECT platform, SUM(amount) total FROM s.Orders WHERE age > 1 GROUP BY platform")
+        .await
+        .expect_err("DISTINCT, WHERE and HAVING are not supported in materialized views");
+    service
+        .exec_query("CREATE MATERIALIZED VIEW s.V AS SELECT platform, age, SUM(amount) total FROM s.Orders GROUP BY platform")
+        .await
+        .expect_err("GROUP BY in materialized view must list exactly the selected columns");
+    service
+        .exec_query("CREATE MATERIALIZED VIEW s.V AS SELECT platform, SUM(platform) total FROM s.Orders GROUP BY 1")
+        .await
+        .expect_err("Aggregate function SUM not allowed for column type text");
+
+    service
+        .exec_query("CREATE MATERIALIZED VIEW s.V AS SELECT platform, SUM(amount) total FROM s.Orders GROUP BY 1")
+        .await
+        .unwrap();
+    service
+        .exec_query("INSERT INTO s.V(platform, total) VALUES ('ios', 1)")
+        .await
+        .expect_err("Can't insert into materialized view s.V");
+    service
+        .exec_query("DROP TABLE s.Orders")
+        .await
+        .expect_err("Can't drop table 'Orders' because materialized view 'V' depends on it");
+
+    service
+        .exec_query("INSERT INTO s.Orders(platform, age, amount) VALUES ('ios', 20, 10)")
+        .await
+        .unwrap();
+    service
+        .exec_query("CREATE MATERIALIZED VIEW s.V2 AS SELECT platform, SUM(amount) total FROM s.Orders GROUP BY 1")
+        .await
+        .expect_err("Can't create materialized view over table 'Orders' because it already has data");
+}
+
+async fn window_functions(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA s").await.unwrap();
+    service
+        .exec_query("CREATE TABLE s.Data(category text, value int)")
+        .await
+        .unwrap();
+    service
+        .exec_query(
+            "INSERT INTO s.Data(category, value) VALUES \
+             ('a', 1), ('a', 2), ('b', 3), ('b', 4), ('b', 5)",
+        )
+        .await
+        .unwrap();
+
+    // Window partitions span multiple sort key values, computed on the router.
+    let q = "SELECT category, value, SUM(value) OVER (PARTITION BY category) \
+             FROM s.Data \
+             ORDER BY 1, 2";
+    let r = service.exec_query(q).await.unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[
+            ("a", 1, 3),
+            ("a", 2, 3),
+            ("b", 3, 12),
+            ("b", 4, 12),
+            ("b", 5, 12),
+        ])
+    );
+    let p = service.plan_query(q).await.unwrap();
+    assert!(pp_phys_plan(p.router.as_ref()).contains("WindowAggExec"));
+    assert!(!pp_phys_plan(p.worker.as_ref()).contains("WindowAggExec"));
+
+    // Window partitioned by the whole sort key, computed on workers.
+    let q = "SELECT category, value, COUNT(*) OVER (PARTITION BY category, value) \
+             FROM s.Data \
+             ORDER BY 1, 2";
+    let r = service.exec_query(q).await.unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[
+            ("a", 1, 1),
+            ("a", 2, 1),
+            ("b", 3, 1),
+            ("b", 4, 1),
+            ("b", 5, 1)
+        ])
+    );
+    let p = service.plan_query(q).await.unwrap();
+    assert!(!pp_phys_plan(p.router.as_ref()).contains("WindowAggExec"));
+    assert!(pp_phys_plan(p.worker.as_ref()).contains("WindowAggExec"));
+}
+
+async fn grouping_sets(service: Box<dyn SqlClient>) {
+    service.exec_query("CREATE SCHEMA s").await.unwrap();
+    service
+        .exec_query("CREATE TABLE s.Data(category text, kind text, value int)")
+        .await
+        .unwrap();
+    service
+        .exec_query(
+            "INSERT INTO s.Data(category, kind, value) VALUES \
+             ('a', 'x', 1), ('a', 'y', 2), ('b', 'x', 4), ('b', 'y', 8)",
+        )
+        .await
+        .unwrap();
+
+    let r = service
+        .exec_query("SELECT category, SUM(value) FROM s.Data GROUP BY ROLLUP(1) ORDER BY 2")
+        .await
+        .unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[(Some("a"), 3), (Some("b"), 12), (None, 15)])
+    );
+
+    let r = service
+        .exec_query(
+            "SELECT category, kind, SUM(value) FROM s.Data \
+             GROUP BY CUBE(category, kind) \
+             ORDER BY 3",
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[
+            (Some("a"), Some("x"), 1),
+            (Some("a"), Some("y"), 2),
+            (Some("a"), None, 3),
+            (Some("b"), Some("x"), 4),
+            (None, Some("x"), 5),
+            (Some("b"), Some("y"), 8),
+            (None, Some("y"), 10),
+            (Some("b"), None, 12),
+            (None, None, 15),
+        ])
+    );
+
+    let r = service
+        .exec_query(
+            "SELECT category, GROUPING(category), COUNT(*) FROM s.Data \
+             GROUP BY GROUPING SETS ((category), ()) \
+             ORDER BY 2, 1",
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        to_rows(&r),
+        rows(&[(Some("a"), 0, 2), (Some("b"), 0, 2), (None, 1, 4)])
+    );
+
+    // Every branch of the expansion is aggregated on workers.
+    let p = service
+        .plan_query("SELECT category, SUM(value) FROM s.Data GROUP BY ROLLUP(category)")
+        .await
+        .unwrap();
+    assert_eq!(
+        pp_phys_plan(p.worker.as_ref()).matches("Partial").count(),
+        2
+    );
+}
+
 async fn inline_tables(service: Box<dyn SqlClient>) {
     service.exec_query("CREATE SCHEMA Foo").await.unwrap();
     service
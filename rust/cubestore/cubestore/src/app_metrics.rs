@@ -2,7 +2,7 @@
 //! The convention is to prefix all metrics with `cs.` (short for CubeStore).
 
 use crate::util::metrics;
-use crate::util::metrics::{Counter, Histogram};
+use crate::util::metrics::{Counter, Gauge, Histogram};
 
 /// The number of process startups.
 pub static STARTUPS: Counter = metrics::counter("cs.startup");
@@ -13,3 +13,35 @@ pub static DATA_QUERY_TIME_MS: Histogram = metrics::histogram("cs.sql.query.data
 /// Incoming SQL queries that only read metadata or do trivial computations.
 pub static META_QUERIES: Counter = metrics::counter("cs.sql.query.meta");
 pub static META_QUERY_TIME_MS: Histogram = metrics::histogram("cs.sql.query.meta.ms");
+/// Time data queries spend waiting in the queue of their workload class, tagged with `class`.
+pub static QUERY_QUEUE_TIME_MS: Histogram = metrics::histogram("cs.sql.query.queue.ms");
+/// Data queries rejected because the queue of their workload class is full, tagged with `class`.
+pub static QUERIES_REJECTED: Counter = metrics::counter("cs.sql.query.rejected");
+
+/// Files transferred by the remote filesystem queue. Failed transfers are counted in
+/// `cs.remote_fs.errors`, tagged with `operation`.
+pub static REMOTE_FS_UPLOADS: Counter = metrics::counter("cs.remote_fs.upload");
+pub static REMOTE_FS_UPLOAD_BYTES: Counter = metrics::counter("cs.remote_fs.upload.bytes");
+pub static REMOTE_FS_UPLOAD_TIME_MS: Histogram = metrics::histogram("cs.remote_fs.upload.ms");
+pub static REMOTE_FS_DOWNLOADS: Counter = metrics::counter("cs.remote_fs.download");
+pub static REMOTE_FS_DOWNLOAD_BYTES: Counter = metrics::counter("cs.remote_fs.download.bytes");
+pub static REMOTE_FS_DOWNLOAD_TIME_MS: Histogram = metrics::histogram("cs.remote_fs.download.ms");
+pub static REMOTE_FS_ERRORS: Counter = metrics::counter("cs.remote_fs.errors");
+
+/// Scans of partition and chunk files served from the decoded batch cache of select workers, the
+/// hit rate is `hits / (hits + misses)`. Forwarded to the main process with the `worker` tag.
+pub static WORKER_BATCH_CACHE_HITS: Counter = metrics::counter("cs.worker.batch_cache.hits");
+pub static WORKER_BATCH_CACHE_MISSES: Counter = metrics::counter("cs.worker.batch_cache.misses");
+pub static WORKER_BATCH_CACHE_BYTES: Gauge = metrics::gauge("cs.worker.batch_cache.bytes");
+
+/// State of the node, updated when the status server is scraped. Metastore and queue stats are
+/// only reported by the router.
+pub static METASTORE_TABLES: Gauge = metrics::gauge("cs.metastore.tables");
+pub static METASTORE_ACTIVE_PARTITIONS: Gauge = metrics::gauge("cs.metastore.partitions");
+/// Tagged with `in_memory`.
+pub static METASTORE_ACTIVE_CHUNKS: Gauge = metrics::gauge("cs.metastore.chunks");
+/// Jobs not yet finished, tagged with `type` and `status`. Scheduled compactions are the
+/// compaction backlog.
+pub static JOBS: Gauge = metrics::gauge("cs.jobs");
+/// Tagged with `status`.
+pub static QUEUE_ITEMS: Gauge = metrics::gauge("cs.queue.items");
@@ -9,6 +9,7 @@ use datafusion::cube_ext;
 use log::debug;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::Path;
 use std::time::Duration;
 use tokio::runtime::Builder;
 
@@ -61,6 +62,28 @@ fn main() {
     #[cfg(not(target_os = "windows"))]
     cubestore::util::respawn::init();
 
+    let args = std::env::args().collect::<Vec<_>>();
+    let command = match args.get(1).map(|a| a.as_str()) {
+        Some("restore") => match args.get(2) {
+            Some(prefix) => Command::Restore(prefix.to_string()),
+            None => {
+                eprintln!("Usage: cubestored restore <remote prefix>");
+                std::process::exit(1);
+            }
+        },
+        Some("replay") => match args.get(2) {
+            Some(bundle) => Command::Replay(bundle.to_string()),
+            None => {
+                eprintln!("Usage: cubestored replay <bundle dir>");
+                std::process::exit(1);
+            }
+        },
+        Some("check") => Command::Check {
+            repair: args.get(2).map(|a| a.as_str()) == Some("repair"),
+        },
+        _ => Command::Serve,
+    };
+
     let mut tokio_builder = Builder::new_multi_thread();
     tokio_builder.enable_all();
     tokio_builder.thread_name("cubestore-main");
@@ -73,6 +96,62 @@ fn main() {
 
         validate_config(config.config_obj().as_ref()).report_and_abort_on_errors();
 
+        match command {
+            Command::Serve => {}
+            // Restores the backup before the router starts.
+            Command::Restore(prefix) => {
+                if let Err(e) = config.restore_backup(&prefix).await {
+                    log::error!("Restore from '{}' failed: {}", prefix, e);
+                    std::process::exit(1);
+                }
+            }
+            // Runs its own single-node instance, so doesn't touch the configured storage.
+            Command::Replay(bundle) => {
+                match cubestore::sql::replay::replay_bundle(Path::new(&bundle)).await {
+                    Ok(result) => {
+                        let columns = result
+                            .get_columns()
+                            .iter()
+                            .map(|c| c.get_name().as_str())
+                            .collect::<Vec<_>>();
+                        println!("{}", columns.join("\t"));
+                        for row in result.get_rows().iter() {
+                            let values = row
+                                .values()
+                                .iter()
+                                .map(|v| format!("{:?}", v))
+                                .collect::<Vec<_>>();
+                            println!("{}", values.join("\t"));
+                        }
+                        std::process::exit(0);
+                    }
+                    Err(e) => {
+                        log::error!("Replay of '{}' failed: {}", bundle, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Command::Check { repair } => match config.check_consistency(repair).await {
+                Ok(issues) => {
+                    for i in issues.iter() {
+                        println!(
+                            "{}\t{}\t{}{}",
+                            i.kind,
+                            i.object,
+                            i.details,
+                            if i.repaired { "\trepaired" } else { "" }
+                        );
+                    }
+                    let unresolved = issues.iter().any(|i| !i.repaired);
+                    std::process::exit(if unresolved { 2 } else { 0 });
+                }
+                Err(e) => {
+                    log::error!("Consistency check failed: {}", e);
+                    std::process::exit(1);
+                }
+            },
+        }
+
         config.configure_injector().await;
 
         serve_status_probes(&config);
@@ -88,6 +167,13 @@ fn main() {
     });
 }
 
+enum Command {
+    Serve,
+    Restore(String),
+    Replay(String),
+    Check { repair: bool },
+}
+
 async fn stop_on_ctrl_c(s: &CubeServices) {
     let s = s.clone();
     cube_ext::spawn(async move {
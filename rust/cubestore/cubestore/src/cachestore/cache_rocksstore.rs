@@ -8,7 +8,10 @@ use crate::cachestore::queue_item::{
 use crate::cachestore::queue_result::{
     QueueResultIndexKey, QueueResultRocksIndex, QueueResultRocksTable,
 };
-use crate::cachestore::{compaction, QueueResult};
+use crate::cachestore::result_cache_item::{
+    ResultCacheItemIndexKey, ResultCacheItemRocksIndex, ResultCacheItemRocksTable,
+};
+use crate::cachestore::{compaction, QueueResult, ResultCacheItem};
 use crate::config::injection::DIService;
 use crate::config::{Config, ConfigObj};
 use std::collections::HashMap;
@@ -85,6 +88,7 @@ impl RocksStoreDetails for RocksCacheStoreDetails {
     fn migrate(&self, table_ref: DbTableRef) -> Result<(), CubeError> {
         CacheItemRocksTable::new(table_ref.clone()).migrate()?;
         QueueItemRocksTable::new(table_ref.clone()).migrate()?;
+        ResultCacheItemRocksTable::new(table_ref.clone()).migrate()?;
 
         table_ref
             .db
@@ -283,6 +287,13 @@ pub trait CacheStore: DIService + Send + Sync {
     async fn cache_keys(&self, prefix: String) -> Result<Vec<IdRow<CacheItem>>, CubeError>;
     async fn cache_incr(&self, key: String) -> Result<IdRow<CacheItem>, CubeError>;
 
+    // query results shared by routers
+    async fn result_cache_get(
+        &self,
+        path: String,
+    ) -> Result<Option<IdRow<ResultCacheItem>>, CubeError>;
+    async fn result_cache_set(&self, item: ResultCacheItem) -> Result<(), CubeError>;
+
     // queue
     async fn queue_all(&self) -> Result<Vec<IdRow<QueueItem>>, CubeError>;
     async fn queue_add(&self, item: QueueItem) -> Result<bool, CubeError>;
@@ -447,6 +458,39 @@ impl CacheStore for RocksCacheStore {
             .await
     }
 
+    async fn result_cache_get(
+        &self,
+        path: String,
+    ) -> Result<Option<IdRow<ResultCacheItem>>, CubeError> {
+        self.store
+            .read_operation(move |db_ref| {
+                let result_schema = ResultCacheItemRocksTable::new(db_ref);
+                let index_key = ResultCacheItemIndexKey::ByPath(path);
+                Ok(result_schema
+                    .get_single_opt_row_by_index(&index_key, &ResultCacheItemRocksIndex::ByPath)?)
+            })
+            .await
+    }
+
+    async fn result_cache_set(&self, item: ResultCacheItem) -> Result<(), CubeError> {
+        self.store
+            .write_operation(move |db_ref, batch_pipe| {
+                let result_schema = ResultCacheItemRocksTable::new(db_ref);
+                let index_key = ResultCacheItemIndexKey::ByPath(item.get_path().clone());
+                let id_row_opt = result_schema
+                    .get_single_opt_row_by_index(&index_key, &ResultCacheItemRocksIndex::ByPath)?;
+
+                if let Some(id_row) = id_row_opt {
+                    result_schema.update(id_row.id, item, &id_row.row, batch_pipe)?;
+                } else {
+                    result_schema.insert(item, batch_pipe)?;
+                }
+
+                Ok(())
+            })
+            .await
+    }
+
     async fn queue_all(&self) -> Result<Vec<IdRow<QueueItem>>, CubeError> {
         self.store
             .read_operation(move |db_ref| Ok(QueueItemRocksTable::new(db_ref).all_rows()?))
@@ -831,6 +875,17 @@ impl CacheStore for ClusterCacheStoreClient {
         panic!("CacheStore cannot be used on the worker node! cache_incr was used.")
     }
 
+    async fn result_cache_get(
+        &self,
+        _path: String,
+    ) -> Result<Option<IdRow<ResultCacheItem>>, CubeError> {
+        panic!("CacheStore cannot be used on the worker node! result_cache_get was used.")
+    }
+
+    async fn result_cache_set(&self, _item: ResultCacheItem) -> Result<(), CubeError> {
+        panic!("CacheStore cannot be used on the worker node! result_cache_set was used.")
+    }
+
     async fn queue_all(&self) -> Result<Vec<IdRow<QueueItem>>, CubeError> {
         panic!("CacheStore cannot be used on the worker node! queue_all was used.")
     }
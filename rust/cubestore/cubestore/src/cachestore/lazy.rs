@@ -1,5 +1,6 @@
 use crate::cachestore::{
-    CacheItem, CacheStore, QueueItem, QueueItemStatus, QueueResultResponse, RocksCacheStore,
+    CacheItem, CacheStore, QueueItem, QueueItemStatus, QueueResultResponse, ResultCacheItem,
+    RocksCacheStore,
 };
 use crate::config::ConfigObj;
 use crate::metastore::{IdRow, MetaStoreEvent, MetaStoreFs};
@@ -199,6 +200,17 @@ impl CacheStore for LazyRocksCacheStore {
         self.init().await?.cache_incr(path).await
     }
 
+    async fn result_cache_get(
+        &self,
+        path: String,
+    ) -> Result<Option<IdRow<ResultCacheItem>>, CubeError> {
+        self.init().await?.result_cache_get(path).await
+    }
+
+    async fn result_cache_set(&self, item: ResultCacheItem) -> Result<(), CubeError> {
+        self.init().await?.result_cache_set(item).await
+    }
+
     async fn queue_all(&self) -> Result<Vec<IdRow<QueueItem>>, CubeError> {
         self.init().await?.queue_all().await
     }
@@ -5,6 +5,7 @@ mod lazy;
 mod listener;
 mod queue_item;
 mod queue_result;
+mod result_cache_item;
 
 pub use cache_item::CacheItem;
 pub use cache_rocksstore::{
@@ -13,3 +14,4 @@ pub use cache_rocksstore::{
 pub use lazy::LazyRocksCacheStore;
 pub use queue_item::{QueueItem, QueueItemStatus, QueueResultAckEvent};
 pub use queue_result::QueueResult;
+pub use result_cache_item::ResultCacheItem;
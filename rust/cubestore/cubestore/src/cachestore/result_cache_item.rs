@@ -0,0 +1,92 @@
+use crate::metastore::{IndexId, RocksSecondaryIndex, TableId};
+use crate::{base_rocks_secondary_index, rocks_table_impl};
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Query result shared by routers. Stored apart from [crate::cachestore::CacheItem], so results
+/// are not visible to users of the cache and don't compete with their entries.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ResultCacheItem {
+    path: String,
+    pub(crate) value: String,
+    #[serde(with = "ts_seconds")]
+    pub(crate) expire: DateTime<Utc>,
+}
+
+impl ResultCacheItem {
+    pub fn new(path: String, ttl_secs: u32, value: String) -> Self {
+        ResultCacheItem {
+            path,
+            value,
+            expire: Utc::now() + Duration::seconds(ttl_secs as i64),
+        }
+    }
+
+    pub fn get_path(&self) -> &String {
+        &self.path
+    }
+
+    pub fn get_value(&self) -> &String {
+        &self.value
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ResultCacheItemRocksIndex {
+    ByPath = 1,
+}
+
+rocks_table_impl!(
+    ResultCacheItem,
+    ResultCacheItemRocksTable,
+    TableId::ResultCacheItems,
+    { vec![Box::new(ResultCacheItemRocksIndex::ByPath)] }
+);
+
+#[derive(Hash, Clone, Debug)]
+pub enum ResultCacheItemIndexKey {
+    ByPath(String),
+}
+
+base_rocks_secondary_index!(ResultCacheItem, ResultCacheItemRocksIndex);
+
+impl RocksSecondaryIndex<ResultCacheItem, ResultCacheItemIndexKey> for ResultCacheItemRocksIndex {
+    fn typed_key_by(&self, row: &ResultCacheItem) -> ResultCacheItemIndexKey {
+        match self {
+            ResultCacheItemRocksIndex::ByPath => {
+                ResultCacheItemIndexKey::ByPath(row.get_path().clone())
+            }
+        }
+    }
+
+    fn key_to_bytes(&self, key: &ResultCacheItemIndexKey) -> Vec<u8> {
+        match key {
+            ResultCacheItemIndexKey::ByPath(s) => s.as_bytes().to_vec(),
+        }
+    }
+
+    fn is_unique(&self) -> bool {
+        match self {
+            ResultCacheItemRocksIndex::ByPath => true,
+        }
+    }
+
+    fn version(&self) -> u32 {
+        match self {
+            ResultCacheItemRocksIndex::ByPath => 1,
+        }
+    }
+
+    fn is_ttl(&self) -> bool {
+        true
+    }
+
+    fn get_expire(&self, row: &ResultCacheItem) -> Option<DateTime<Utc>> {
+        Some(row.expire.clone())
+    }
+
+    fn get_id(&self) -> IndexId {
+        *self as IndexId
+    }
+}
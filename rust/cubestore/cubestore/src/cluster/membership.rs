@@ -0,0 +1,312 @@
+use crate::config::ConfigObj;
+use crate::metastore::node::{Node, NodeState};
+use crate::metastore::{IdRow, MetaStore};
+use crate::CubeError;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Select workers that partitions are assigned to. Assignment uses rendezvous hashing, so adding
+/// or removing a worker only moves partitions to or from that worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerSet {
+    /// Does all the work when there are no workers.
+    server_name: String,
+    workers: Vec<String>,
+    /// Routing version in the metastore the set was built from, see [Node::routing_version].
+    version: u64,
+}
+
+impl WorkerSet {
+    pub fn new(server_name: String, workers: impl IntoIterator<Item = String>) -> WorkerSet {
+        let mut workers = workers.into_iter().collect::<Vec<_>>();
+        workers.sort();
+        workers.dedup();
+        WorkerSet {
+            server_name,
+            workers,
+            version: 0,
+        }
+    }
+
+    pub fn with_version(self, version: u64) -> WorkerSet {
+        WorkerSet { version, ..self }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Only the workers from `CUBESTORE_WORKERS`.
+    pub fn from_config(config: &dyn ConfigObj) -> WorkerSet {
+        WorkerSet::new(
+            config.server_name().to_string(),
+            config.select_workers().iter().cloned(),
+        )
+    }
+
+    pub fn workers(&self) -> &[String] {
+        &self.workers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    pub fn contains(&self, worker: &str) -> bool {
+        self.workers.iter().any(|w| w == worker)
+    }
+
+    pub fn with_worker(&self, worker: &str) -> WorkerSet {
+        WorkerSet::new(
+            self.server_name.clone(),
+            self.workers
+                .iter()
+                .cloned()
+                .chain(std::iter::once(worker.to_string())),
+        )
+        .with_version(self.version)
+    }
+
+    /// Worker with the highest weight for `key`, the current node if there are no workers.
+    pub fn pick(&self, key: u64) -> &str {
+        self.replicas(key, 1)[0]
+    }
+
+    /// Up to `count` workers with the highest weights for `key`, starting with the one returned
+    /// by [WorkerSet::pick]. Never empty.
+    pub fn replicas(&self, key: u64, count: usize) -> Vec<&str> {
+        if self.workers.is_empty() {
+            return vec![self.server_name.as_str()];
+        }
+        let mut weighted = self
+            .workers
+            .iter()
+            .map(|w| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                w.hash(&mut hasher);
+                (hasher.finish(), w.as_str())
+            })
+            .collect::<Vec<_>>();
+        weighted.sort_unstable_by(|l, r| r.cmp(l));
+        weighted
+            .into_iter()
+            .take(count.max(1))
+            .map(|(_, w)| w)
+            .collect()
+    }
+
+    /// Same as [WorkerSet::replicas], but consecutive `position`s start with different workers.
+    pub fn replicas_by_position(&self, position: u64, count: usize) -> Vec<&str> {
+        if self.workers.is_empty() {
+            return vec![self.server_name.as_str()];
+        }
+        let n = self.workers.len();
+        (0..count.max(1).min(n))
+            .map(|i| self.workers[(position as usize + i) % n].as_str())
+            .collect()
+    }
+}
+
+/// Workers that failed to respond recently. Queries try them last, after the other replicas.
+pub struct NodeHealth {
+    failed_at: Mutex<HashMap<String, Instant>>,
+    retry_after: Duration,
+}
+
+impl NodeHealth {
+    pub fn new(retry_after: Duration) -> NodeHealth {
+        NodeHealth {
+            failed_at: Mutex::new(HashMap::new()),
+            retry_after,
+        }
+    }
+
+    pub fn report_failure(&self, node: &str) {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .insert(node.to_string(), Instant::now());
+    }
+
+    pub fn report_success(&self, node: &str) {
+        self.failed_at.lock().unwrap().remove(node);
+    }
+
+    pub fn is_healthy(&self, node: &str) -> bool {
+        match self.failed_at.lock().unwrap().get(node) {
+            None => true,
+            Some(t) => self.retry_after <= t.elapsed(),
+        }
+    }
+
+    /// Healthy nodes go first, otherwise keeps the order of `nodes`.
+    pub fn order<'a>(&self, nodes: &'a [String]) -> Vec<&'a str> {
+        let (healthy, failed): (Vec<&str>, Vec<&str>) = nodes
+            .iter()
+            .map(|n| n.as_str())
+            .partition(|n| self.is_healthy(n));
+        healthy.into_iter().chain(failed).collect()
+    }
+}
+
+/// Keeps the set of workers partitions are routed to: the static `CUBESTORE_WORKERS` along with
+/// active workers registered in the metastore. Every node refreshes it periodically and whenever
+/// another node sends a newer routing version, see [ClusterMembership::ensure_version]. The router
+/// also moves registered workers between [NodeState]s, see
+/// [ClusterImpl::update_membership](crate::cluster::ClusterImpl::update_membership).
+pub struct ClusterMembership {
+    config: Arc<dyn ConfigObj>,
+    meta_store: Arc<dyn MetaStore>,
+    workers: RwLock<Arc<WorkerSet>>,
+}
+
+crate::di_service!(ClusterMembership, []);
+
+impl ClusterMembership {
+    pub fn new(config: Arc<dyn ConfigObj>, meta_store: Arc<dyn MetaStore>) -> Arc<Self> {
+        let workers = RwLock::new(Arc::new(WorkerSet::from_config(config.as_ref())));
+        Arc::new(ClusterMembership {
+            config,
+            meta_store,
+            workers,
+        })
+    }
+
+    pub fn workers(&self) -> Arc<WorkerSet> {
+        self.workers.read().unwrap().clone()
+    }
+
+    pub async fn refresh(&self) -> Result<Arc<WorkerSet>, CubeError> {
+        let nodes = self.meta_store.get_nodes().await?;
+        let workers = Arc::new(Self::worker_set(self.config.as_ref(), &nodes));
+        let mut current = self.workers.write().unwrap();
+        // Concurrent refreshes can finish out of order, never go back to an older version.
+        if current.version() <= workers.version() && **current != *workers {
+            log::info!(
+                "Select workers changed to version {}: {:?}",
+                workers.version(),
+                workers.workers()
+            );
+            *current = workers.clone();
+        }
+        Ok(current.clone())
+    }
+
+    /// Refreshes the workers if `version` seen by another node is newer than the local one.
+    pub async fn ensure_version(&self, version: u64) -> Result<Arc<WorkerSet>, CubeError> {
+        let workers = self.workers();
+        if version <= workers.version() {
+            return Ok(workers);
+        }
+        self.refresh().await
+    }
+
+    fn worker_set(config: &dyn ConfigObj, nodes: &[IdRow<Node>]) -> WorkerSet {
+        WorkerSet::new(
+            config.server_name().to_string(),
+            config.select_workers().iter().cloned().chain(
+                nodes
+                    .iter()
+                    .filter(|n| n.get_row().state() == NodeState::Active)
+                    .map(|n| n.get_row().name().to_string()),
+            ),
+        )
+        .with_version(
+            nodes
+                .iter()
+                .map(|n| n.get_row().routing_version())
+                .max()
+                .unwrap_or(0),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workers(names: &[&str]) -> WorkerSet {
+        WorkerSet::new("router".to_string(), names.iter().map(|n| n.to_string()))
+    }
+
+    #[test]
+    fn rendezvous_hashing() {
+        assert_eq!(workers(&[]).pick(1), "router");
+        assert_eq!(workers(&["w1", "w2"]), workers(&["w2", "w1", "w2"]));
+
+        let before = workers(&["w1", "w2", "w3"]);
+        let after = before.with_worker("w4");
+        let mut moved = 0;
+        for key in 0..1000 {
+            let (old, new) = (before.pick(key), after.pick(key));
+            if old != new {
+                // Keys only move to the new worker.
+                assert_eq!(new, "w4");
+                moved += 1;
+            }
+        }
+        assert!(100 < moved && moved < 400, "moved {} keys", moved);
+
+        let mut per_worker = std::collections::HashMap::new();
+        for key in 0..1000 {
+            *per_worker.entry(after.pick(key)).or_insert(0) += 1;
+        }
+        assert_eq!(per_worker.len(), 4);
+        assert!(per_worker.values().all(|c| *c > 150), "{:?}", per_worker);
+    }
+
+    #[test]
+    fn replicas() {
+        assert_eq!(workers(&[]).replicas(1, 2), vec!["router"]);
+        let all = workers(&["w1", "w2", "w3"]);
+        for key in 0..100 {
+            let r = all.replicas(key, 2);
+            assert_eq!(r.len(), 2);
+            assert_eq!(r[0], all.pick(key));
+            assert_ne!(r[0], r[1]);
+            assert_eq!(all.replicas(key, 5).len(), 3);
+            // The next replica takes over once the first one is removed.
+            let rest = workers(
+                &["w1", "w2", "w3"]
+                    .iter()
+                    .filter(|w| **w != r[0])
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            );
+            assert_eq!(rest.pick(key), r[1]);
+        }
+
+        assert_eq!(workers(&[]).replicas_by_position(3, 2), vec!["router"]);
+        assert_eq!(all.replicas_by_position(0, 2), vec!["w1", "w2"]);
+        assert_eq!(all.replicas_by_position(2, 2), vec!["w3", "w1"]);
+        assert_eq!(all.replicas_by_position(4, 5), vec!["w2", "w3", "w1"]);
+    }
+
+    #[test]
+    fn versions() {
+        let v1 = workers(&["w1"]).with_version(1);
+        assert_eq!(v1.with_worker("w2").version(), 1);
+        // The same workers with a newer version is a different routing table.
+        assert_ne!(v1, workers(&["w1"]).with_version(2));
+    }
+
+    #[test]
+    fn node_health() {
+        let health = NodeHealth::new(Duration::from_secs(60));
+        let nodes = vec!["w1".to_string(), "w2".to_string(), "w3".to_string()];
+        assert_eq!(health.order(&nodes), vec!["w1", "w2", "w3"]);
+        health.report_failure("w1");
+        assert!(!health.is_healthy("w1"));
+        assert_eq!(health.order(&nodes), vec!["w2", "w3", "w1"]);
+        health.report_success("w1");
+        assert_eq!(health.order(&nodes), vec!["w1", "w2", "w3"]);
+
+        let health = NodeHealth::new(Duration::from_secs(0));
+        health.report_failure("w1");
+        assert!(health.is_healthy("w1"));
+    }
+}
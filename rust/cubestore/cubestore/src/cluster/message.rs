@@ -1,17 +1,28 @@
 use crate::metastore::{MetaStoreRpcMethodCall, MetaStoreRpcMethodResult};
-use crate::queryplanner::query_executor::SerializedRecordBatchStream;
+use crate::queryplanner::explain::ExplainNode;
+use crate::queryplanner::query_executor::{SerializedRecordBatchStream, WorkerTiming};
 use crate::queryplanner::serialized_plan::SerializedPlan;
 use crate::CubeError;
 use arrow::datatypes::SchemaRef;
 use serde::{Deserialize, Serialize};
 use std::io::ErrorKind;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum NetworkMessage {
     /// Route subqueries to other nodes and collect results.
     RouterSelect(SerializedPlan),
+    /// Response to [RouterSelect], with the time each worker took.
+    RouterSelectResult(
+        Result<
+            (
+                SchemaRef,
+                Vec<SerializedRecordBatchStream>,
+                Vec<WorkerTiming>,
+            ),
+            CubeError,
+        >,
+    ),
 
     /// Partial select on the worker.
     Select(SerializedPlan),
@@ -21,6 +32,10 @@ pub enum NetworkMessage {
     ExplainAnalyze(SerializedPlan),
     ExplainAnalyzeResult(Result<String, CubeError>),
 
+    /// Explain the worker query part for `EXPLAIN (FORMAT JSON)`, running it with `analyze`.
+    ExplainJson(SerializedPlan, /*analyze*/ bool),
+    ExplainJsonResult(Result<ExplainNode, CubeError>),
+
     /// Select that sends results in batches. The immediate response is [SelectResultSchema],
     /// followed by a stream of [SelectResultBatch].
     SelectStart(SerializedPlan),
@@ -35,6 +50,8 @@ pub enum NetworkMessage {
     AddMemoryChunk {
         chunk_id: u64,
         data: SerializedRecordBatchStream,
+        /// Version of the select workers the sender picked the owner with.
+        routing_version: u64,
     },
     AddMemoryChunkResult(Result<(), CubeError>),
 
@@ -52,7 +69,7 @@ pub enum NetworkMessage {
 
 const MAGIC: u32 = 94107;
 
-const NETWORK_MESSAGE_VERSION: u32 = 1;
+const NETWORK_MESSAGE_VERSION: u32 = 4;
 
 impl NetworkMessage {
     pub fn is_streaming_request(&self) -> bool {
@@ -63,7 +80,10 @@ impl NetworkMessage {
     }
 
     /// Returns true iff the client accepted the message.
-    pub async fn maybe_send(&self, socket: &mut TcpStream) -> Result<bool, CubeError> {
+    pub async fn maybe_send<S: AsyncWrite + Unpin + Send>(
+        &self,
+        socket: &mut S,
+    ) -> Result<bool, CubeError> {
         match self.send_impl(socket).await {
             Ok(()) => Ok(true),
             Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => Ok(false),
@@ -71,11 +91,17 @@ impl NetworkMessage {
         }
     }
 
-    pub async fn send(&self, socket: &mut TcpStream) -> Result<(), CubeError> {
+    pub async fn send<S: AsyncWrite + Unpin + Send>(
+        &self,
+        socket: &mut S,
+    ) -> Result<(), CubeError> {
         Ok(self.send_impl(socket).await?)
     }
 
-    async fn send_impl(&self, socket: &mut TcpStream) -> Result<(), std::io::Error> {
+    async fn send_impl<S: AsyncWrite + Unpin + Send>(
+        &self,
+        socket: &mut S,
+    ) -> Result<(), std::io::Error> {
         let mut ser = flexbuffers::FlexbufferSerializer::new();
         self.serialize(&mut ser).unwrap();
         let message_buffer = ser.take_buffer();
@@ -91,10 +117,12 @@ impl NetworkMessage {
         socket.write_u32(NETWORK_MESSAGE_VERSION).await?;
         socket.write_u64(len).await?;
         socket.write_all(message_buffer.as_slice()).await?;
+        // TLS streams buffer the written data.
+        socket.flush().await?;
         Ok(())
     }
 
-    pub async fn receive(socket: &mut TcpStream) -> Result<Self, CubeError> {
+    pub async fn receive<S: AsyncRead + Unpin + Send>(socket: &mut S) -> Result<Self, CubeError> {
         match Self::maybe_receive(socket).await? {
             Some(m) => Ok(m),
             None => Err(CubeError::user("Connection closed unexpectedly. Please check your worker and meta connection environment variables.".to_string())),
@@ -102,7 +130,9 @@ impl NetworkMessage {
     }
 
     /// Either receives a message or waits for the connection to close.
-    pub async fn maybe_receive(socket: &mut TcpStream) -> Result<Option<Self>, CubeError> {
+    pub async fn maybe_receive<S: AsyncRead + Unpin + Send>(
+        socket: &mut S,
+    ) -> Result<Option<Self>, CubeError> {
         let magic = socket.read_u32().await;
         if let Err(e) = &magic {
             // TODO: corner case with `0 < n < 8` read bytes.
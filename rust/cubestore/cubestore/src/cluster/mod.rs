@@ -1,5 +1,7 @@
 pub mod message;
 
+pub mod membership;
+pub mod tls;
 pub mod transport;
 #[cfg(not(target_os = "windows"))]
 pub mod worker_pool;
@@ -8,7 +10,9 @@ pub mod worker_pool;
 use crate::cluster::worker_pool::{worker_main, MessageProcessor, WorkerPool};
 
 use crate::ack_error;
+use crate::cluster::membership::{ClusterMembership, NodeHealth, WorkerSet};
 use crate::cluster::message::NetworkMessage;
+use crate::cluster::tls::{accept_stream, ClusterStream, ClusterTls};
 use crate::cluster::transport::{ClusterTransport, MetaStoreTransport, WorkerConnection};
 use crate::config::injection::{DIService, Injector};
 use crate::config::{is_router, WorkerServices};
@@ -17,6 +21,7 @@ use crate::config::{Config, ConfigObj};
 use crate::import::ImportService;
 use crate::metastore::chunks::chunk_file_name;
 use crate::metastore::job::{Job, JobStatus, JobType};
+use crate::metastore::node::{Node, NodeState};
 use crate::metastore::table::Table;
 use crate::metastore::{
     deactivate_table_on_corrupt_data, Chunk, IdRow, MetaStore, MetaStoreEvent, Partition, RowKey,
@@ -26,7 +31,10 @@ use crate::metastore::{
     MetaStoreRpcClientTransport, MetaStoreRpcMethodCall, MetaStoreRpcMethodResult,
     MetaStoreRpcServer,
 };
-use crate::queryplanner::query_executor::{QueryExecutor, SerializedRecordBatchStream};
+use crate::queryplanner::explain::ExplainNode;
+use crate::queryplanner::query_executor::{
+    QueryExecutor, SerializedRecordBatchStream, WorkerTiming,
+};
 use crate::queryplanner::serialized_plan::SerializedPlan;
 use crate::remotefs::RemoteFs;
 use crate::store::compaction::CompactionService;
@@ -44,7 +52,7 @@ use datafusion::physical_plan::{RecordBatchStream, SendableRecordBatchStream};
 use flatbuffers::bitflags::_core::pin::Pin;
 use futures::future::join_all;
 use futures::task::{Context, Poll};
-use futures::{Future, Stream};
+use futures::{Future, Stream, StreamExt};
 use futures_timer::Delay;
 use itertools::Itertools;
 use log::{debug, error, info, warn};
@@ -52,7 +60,7 @@ use mockall::automock;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::sync::Weak;
 use std::sync::{Arc, Mutex};
@@ -78,7 +86,14 @@ pub trait Cluster: DIService + Send + Sync {
         &self,
         node_name: &str,
         plan: SerializedPlan,
-    ) -> Result<(SchemaRef, Vec<SerializedRecordBatchStream>), CubeError>;
+    ) -> Result<
+        (
+            SchemaRef,
+            Vec<SerializedRecordBatchStream>,
+            Vec<WorkerTiming>,
+        ),
+        CubeError,
+    >;
 
     /// Runs select on a single worker node to get partial results from that worker.
     async fn run_select(
@@ -95,6 +110,15 @@ pub trait Cluster: DIService + Send + Sync {
         plan: SerializedPlan,
     ) -> Result<String, CubeError>;
 
+    /// Explains the worker part of the plan on a single node for `EXPLAIN (FORMAT JSON)`.
+    /// With `analyze`, the node runs the plan to collect stats of each operator.
+    async fn run_explain_json(
+        &self,
+        node_name: &str,
+        plan: SerializedPlan,
+        analyze: bool,
+    ) -> Result<ExplainNode, CubeError>;
+
     /// Like [run_select], but streams results as they are requested.
     /// This allows to send only a limited number of results, if the caller does not need all.
     async fn run_select_stream(
@@ -103,6 +127,21 @@ pub trait Cluster: DIService + Send + Sync {
         plan: SerializedPlan,
     ) -> Result<SendableRecordBatchStream, CubeError>;
 
+    /// Like [run_select], but tries the next node from `replicas` when a worker can't be reached.
+    async fn run_select_on_replicas(
+        &self,
+        replicas: &[String],
+        plan: SerializedPlan,
+    ) -> Result<Vec<RecordBatch>, CubeError>;
+
+    /// Like [run_select_stream], but tries the next node from `replicas` when a worker can't be
+    /// reached. Errors after the stream started are not retried.
+    async fn run_select_stream_on_replicas(
+        &self,
+        replicas: &[String],
+        plan: SerializedPlan,
+    ) -> Result<SendableRecordBatchStream, CubeError>;
+
     async fn available_nodes(&self) -> Result<Vec<String>, CubeError>;
 
     fn server_name(&self) -> &str;
@@ -120,9 +159,12 @@ pub trait Cluster: DIService + Send + Sync {
         chunks: Vec<IdRow<Chunk>>,
     ) -> Result<(), CubeError>;
 
+    /// Sends the chunk to the owner of `partition`, the owner rejects chunks routed with an
+    /// outdated set of workers, so they can't end up on a node that already handed off the
+    /// partition.
     async fn add_memory_chunk(
         &self,
-        node_name: &str,
+        partition: &IdRow<Partition>,
         chunk_id: u64,
         batch: RecordBatch,
     ) -> Result<(), CubeError>;
@@ -131,6 +173,9 @@ pub trait Cluster: DIService + Send + Sync {
 
     fn job_result_listener(&self) -> JobResultListener;
 
+    /// Workers partitions are routed to at the moment.
+    fn worker_set(&self) -> Arc<WorkerSet>;
+
     fn node_name_by_partition(&self, p: &IdRow<Partition>) -> String;
 
     async fn node_name_for_chunk_repartition(
@@ -160,6 +205,9 @@ pub enum JobEvent {
     Error(RowKey, JobType, String),
 }
 
+/// Number of partitions downloaded at once when a joining worker is warmed up.
+const JOINING_WORKER_WARMUP_CONCURRENCY: usize = 8;
+
 pub struct ClusterImpl {
     this: Weak<ClusterImpl>,
     // Used in order to avoid cycle dependencies.
@@ -170,6 +218,10 @@ pub struct ClusterImpl {
     remote_fs: Arc<dyn RemoteFs>,
     meta_store: Arc<dyn MetaStore>,
     cluster_transport: Arc<dyn ClusterTransport>,
+    membership: Arc<ClusterMembership>,
+    /// Joining workers that are being warmed up, see [ClusterImpl::update_membership].
+    activating_workers: Mutex<HashSet<String>>,
+    node_health: NodeHealth,
     connect_timeout: Duration,
     server_name: String,
     server_addresses: Vec<String>,
@@ -290,6 +342,7 @@ struct JobRunner {
     chunk_store: Arc<dyn ChunkDataStore>,
     compaction_service: Arc<dyn CompactionService>,
     import_service: Arc<dyn ImportService>,
+    membership: Arc<ClusterMembership>,
     server_name: String,
     notify: Arc<Notify>,
     stop_token: CancellationToken,
@@ -323,12 +376,19 @@ impl Cluster for ClusterImpl {
         &self,
         node_name: &str,
         plan: SerializedPlan,
-    ) -> Result<(SchemaRef, Vec<SerializedRecordBatchStream>), CubeError> {
+    ) -> Result<
+        (
+            SchemaRef,
+            Vec<SerializedRecordBatchStream>,
+            Vec<WorkerTiming>,
+        ),
+        CubeError,
+    > {
         let response = self
             .send_or_process_locally(&node_name, NetworkMessage::RouterSelect(plan))
             .await?;
         match response {
-            NetworkMessage::SelectResult(r) => r,
+            NetworkMessage::RouterSelectResult(r) => r,
             _ => panic!("unexpected response for route select"),
         }
     }
@@ -364,6 +424,21 @@ impl Cluster for ClusterImpl {
         }
     }
 
+    async fn run_explain_json(
+        &self,
+        node_name: &str,
+        plan: SerializedPlan,
+        analyze: bool,
+    ) -> Result<ExplainNode, CubeError> {
+        let response = self
+            .send_or_process_locally(node_name, NetworkMessage::ExplainJson(plan, analyze))
+            .await?;
+        match response {
+            NetworkMessage::ExplainJsonResult(r) => r,
+            _ => panic!("unexpected result for explain json"),
+        }
+    }
+
     async fn run_select_stream(
         &self,
         node_name: &str,
@@ -376,6 +451,78 @@ impl Cluster for ClusterImpl {
             .await
     }
 
+    async fn run_select_on_replicas(
+        &self,
+        replicas: &[String],
+        plan: SerializedPlan,
+    ) -> Result<Vec<RecordBatch>, CubeError> {
+        let attempts = async move {
+            let mut last_error = None;
+            let ordered = self.node_health.order(replicas);
+            for (i, node_name) in ordered.iter().enumerate() {
+                let node_name = *node_name;
+                let m = NetworkMessage::Select(plan.clone());
+                let attempt = async move {
+                    if self.server_name == node_name || is_self_reference(node_name) {
+                        Ok(self.process_message_on_worker(m).await)
+                    } else {
+                        self.send_to_worker(node_name, m).await
+                    }
+                };
+                let response = self
+                    .with_replica_timeout(node_name, i + 1 == ordered.len(), attempt)
+                    .await;
+                match response {
+                    Ok(NetworkMessage::SelectResult(r)) => {
+                        self.node_health.report_success(node_name);
+                        return r.and_then(|(_, batches)| {
+                            batches.into_iter().map(|b| b.read()).collect()
+                        });
+                    }
+                    Ok(_) => panic!("unexpected response for select"),
+                    Err(e) => {
+                        self.on_replica_failure(node_name, &e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+            Err(last_error.unwrap_or_else(no_replicas_error))
+        };
+        timeout(
+            Duration::from_secs(self.config_obj.query_timeout()),
+            attempts,
+        )
+        .await?
+    }
+
+    async fn run_select_stream_on_replicas(
+        &self,
+        replicas: &[String],
+        plan: SerializedPlan,
+    ) -> Result<SendableRecordBatchStream, CubeError> {
+        let this = self.this.upgrade().unwrap();
+        let mut last_error = None;
+        let ordered = self.node_health.order(replicas);
+        for (i, node_name) in ordered.iter().enumerate() {
+            let node_name = *node_name;
+            let attempt = this.start_select_stream(node_name, plan.clone());
+            let started = self
+                .with_replica_timeout(node_name, i + 1 == ordered.len(), attempt)
+                .await;
+            match started {
+                Ok(r) => {
+                    self.node_health.report_success(node_name);
+                    return r;
+                }
+                Err(e) => {
+                    self.on_replica_failure(node_name, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(no_replicas_error))
+    }
+
     async fn available_nodes(&self) -> Result<Vec<String>, CubeError> {
         Ok(vec![self.server_name.to_string()])
     }
@@ -405,23 +552,38 @@ impl Cluster for ClusterImpl {
 
     async fn add_memory_chunk(
         &self,
-        node_name: &str,
+        partition: &IdRow<Partition>,
         chunk_id: u64,
         batch: RecordBatch,
     ) -> Result<(), CubeError> {
-        let record_batch = SerializedRecordBatchStream::write(&batch.schema(), vec![batch])?;
-        let response = self
-            .send_or_process_locally(
-                node_name,
-                NetworkMessage::AddMemoryChunk {
-                    chunk_id,
-                    data: record_batch.into_iter().next().unwrap(),
-                },
-            )
-            .await?;
-        match response {
-            NetworkMessage::AddMemoryChunkResult(r) => r,
-            x => panic!("Unexpected result for add chunk: {:?}", x),
+        let mut workers = self.membership.workers();
+        loop {
+            let node_name = node_name_by_partition_in(&workers, partition);
+            let record_batch =
+                SerializedRecordBatchStream::write(&batch.schema(), vec![batch.clone()])?;
+            let response = self
+                .send_or_process_locally(
+                    &node_name,
+                    NetworkMessage::AddMemoryChunk {
+                        chunk_id,
+                        data: record_batch.into_iter().next().unwrap(),
+                        routing_version: workers.version(),
+                    },
+                )
+                .await?;
+            let res = match response {
+                NetworkMessage::AddMemoryChunkResult(r) => r,
+                x => panic!("Unexpected result for add chunk: {:?}", x),
+            };
+            if res.is_ok() {
+                return res;
+            }
+            // Pick the owner again if the rejection was caused by a routing change.
+            let latest = self.membership.refresh().await?;
+            if latest.version() == workers.version() {
+                return res;
+            }
+            workers = latest;
         }
     }
 
@@ -441,12 +603,12 @@ impl Cluster for ClusterImpl {
         }
     }
 
+    fn worker_set(&self) -> Arc<WorkerSet> {
+        self.membership.workers()
+    }
+
     fn node_name_by_partition(&self, p: &IdRow<Partition>) -> String {
-        if let Some(id) = p.get_row().multi_partition_id() {
-            pick_worker_by_ids(self.config_obj.as_ref(), [id]).to_string()
-        } else {
-            pick_worker_by_partitions(self.config_obj.as_ref(), [p]).to_string()
-        }
+        node_name_by_partition_in(&self.membership.workers(), p)
     }
 
     async fn node_name_for_chunk_repartition(
@@ -461,7 +623,7 @@ impl Cluster for ClusterImpl {
                     .await?,
             ))
         } else {
-            Ok(pick_worker_by_ids(self.config_obj.as_ref(), [chunk.get_id()]).to_string())
+            Ok(pick_worker_by_ids(&self.membership.workers(), [chunk.get_id()]).to_string())
         }
     }
 
@@ -470,14 +632,10 @@ impl Cluster for ClusterImpl {
         table_id: u64,
         location: &str,
     ) -> Result<String, CubeError> {
-        let workers = self.config_obj.select_workers();
-        if workers.is_empty() {
-            return Ok(self.server_name.to_string());
-        }
         let mut hasher = DefaultHasher::new();
         table_id.hash(&mut hasher);
         location.hash(&mut hasher);
-        Ok(workers[(hasher.finish() % workers.len() as u64) as usize].to_string())
+        Ok(self.membership.workers().pick(hasher.finish()).to_string())
     }
 
     async fn warmup_partition(
@@ -485,23 +643,19 @@ impl Cluster for ClusterImpl {
         partition: IdRow<Partition>,
         chunks: Vec<IdRow<Chunk>>,
     ) -> Result<(), CubeError> {
-        let node_name = self.node_name_by_partition(&partition);
-        let mut futures = Vec::new();
-        if let Some(name) = partition.get_row().get_full_name(partition.get_id()) {
-            futures.push(self.warmup_download(&node_name, name, partition.get_row().file_size()));
-        }
-        for chunk in chunks.iter() {
-            let name = chunk.get_row().get_full_name(chunk.get_id());
-            futures.push(self.warmup_download(&node_name, name, chunk.get_row().file_size()));
-        }
-        let res = join_all(futures)
+        let replicas = replicas_by_partition_in(
+            &self.membership.workers(),
+            &partition,
+            self.config_obj.select_worker_replicas(),
+        );
+        let futures = replicas
+            .iter()
+            .map(|node_name| self.warmup_partition_on(node_name, partition.clone(), chunks.clone()))
+            .collect::<Vec<_>>();
+        join_all(futures)
             .await
             .into_iter()
-            .collect::<Result<Vec<_>, _>>();
-
-        deactivate_table_on_corrupt_data(self.meta_store.clone(), &res, &partition).await;
-
-        res?;
+            .collect::<Result<Vec<_>, _>>()?;
         Ok(())
     }
 
@@ -513,11 +667,11 @@ impl Cluster for ClusterImpl {
                     .query_executor
                     .execute_router_plan(plan, self.this.upgrade().unwrap())
                     .await
-                    .and_then(|(schema, records)| {
+                    .and_then(|(schema, records, worker_timings)| {
                         let records = SerializedRecordBatchStream::write(&schema, records)?;
-                        Ok((schema, records))
+                        Ok((schema, records, worker_timings))
                     });
-                NetworkMessage::SelectResult(res)
+                NetworkMessage::RouterSelectResult(res)
             }
             NetworkMessage::Select(plan) => {
                 let res = self.run_local_select_worker(plan).await;
@@ -527,6 +681,10 @@ impl Cluster for ClusterImpl {
                 let res = self.run_local_explain_analyze_worker(plan).await;
                 NetworkMessage::ExplainAnalyzeResult(res)
             }
+            NetworkMessage::ExplainJson(plan, analyze) => {
+                let res = self.run_local_explain_json_worker(plan, analyze).await;
+                NetworkMessage::ExplainJsonResult(res)
+            }
             NetworkMessage::WarmupDownload(remote_path, expected_file_size) => {
                 let res = self
                     .remote_fs
@@ -535,23 +693,37 @@ impl Cluster for ClusterImpl {
                 NetworkMessage::WarmupDownloadResult(res.map(|_| ()))
             }
             NetworkMessage::SelectResult(_)
+            | NetworkMessage::RouterSelectResult(_)
             | NetworkMessage::WarmupDownloadResult(_)
-            | NetworkMessage::ExplainAnalyzeResult(_) => {
+            | NetworkMessage::ExplainAnalyzeResult(_)
+            | NetworkMessage::ExplainJsonResult(_) => {
                 panic!("result sent to worker");
             }
-            NetworkMessage::AddMemoryChunk { chunk_id, data } => {
-                let res = match data.read() {
-                    Ok(batch) => {
-                        let chunk_store = self
-                            .injector
-                            .upgrade()
-                            .unwrap()
-                            .get_service_typed::<dyn ChunkDataStore>()
-                            .await;
-                        chunk_store.add_memory_chunk(chunk_id, batch).await
+            NetworkMessage::AddMemoryChunk {
+                chunk_id,
+                data,
+                routing_version,
+            } => {
+                let res = async {
+                    let workers = self.membership.ensure_version(routing_version).await?;
+                    if routing_version < workers.version() {
+                        return Err(CubeError::internal(format!(
+                            "In memory chunk {} was routed with select workers version {}, current version is {}",
+                            chunk_id,
+                            routing_version,
+                            workers.version()
+                        )));
                     }
-                    Err(e) => Err(e),
-                };
+                    let batch = data.read()?;
+                    let chunk_store = self
+                        .injector
+                        .upgrade()
+                        .unwrap()
+                        .get_service_typed::<dyn ChunkDataStore>()
+                        .await;
+                    chunk_store.add_memory_chunk(chunk_id, batch).await
+                }
+                .await;
                 NetworkMessage::AddMemoryChunkResult(res)
             }
             NetworkMessage::AddMemoryChunkResult(_) => {
@@ -883,8 +1055,12 @@ impl JobRunner {
             JobType::InMemoryChunksCompaction => {
                 if let RowKey::Table(TableId::Partitions, partition_id) = job.row_reference() {
                     let compaction_service = self.compaction_service.clone();
+                    let membership = self.membership.clone();
                     let partition_id = *partition_id;
                     Ok(cube_ext::spawn(async move {
+                        // Only the partition owner can tell lost chunks apart, make sure it
+                        // knows it owns the partition after a routing change.
+                        membership.refresh().await?;
                         compaction_service
                             .compact_in_memory_chunks(partition_id)
                             .await
@@ -893,6 +1069,23 @@ impl JobRunner {
                     Self::fail_job_row_key(job)
                 }
             }
+            JobType::InMemoryChunksFlush => {
+                if let RowKey::Table(TableId::Partitions, partition_id) = job.row_reference() {
+                    let compaction_service = self.compaction_service.clone();
+                    let membership = self.membership.clone();
+                    let partition_id = *partition_id;
+                    Ok(cube_ext::spawn(async move {
+                        // Chunks routed with the previous set of workers are rejected after the
+                        // refresh, so none arrive once the flush has listed the held ones.
+                        membership.refresh().await?;
+                        compaction_service
+                            .flush_in_memory_chunks(partition_id)
+                            .await
+                    }))
+                } else {
+                    Self::fail_job_row_key(job)
+                }
+            }
             JobType::MultiPartitionSplit => {
                 if let RowKey::Table(TableId::MultiPartitions, id) = job.row_reference() {
                     let compaction_service = self.compaction_service.clone();
@@ -983,6 +1176,7 @@ impl ClusterImpl {
         query_executor: Arc<dyn QueryExecutor>,
         meta_store_sender: Sender<MetaStoreEvent>,
         cluster_transport: Arc<dyn ClusterTransport>,
+        membership: Arc<ClusterMembership>,
         tracing_helper: Arc<dyn TracingHelper>,
     ) -> Arc<ClusterImpl> {
         let (close_worker_socket_tx, close_worker_socket_rx) = watch::channel(false);
@@ -995,6 +1189,11 @@ impl ClusterImpl {
             connect_timeout,
             meta_store,
             cluster_transport,
+            membership,
+            activating_workers: Mutex::new(HashSet::new()),
+            node_health: NodeHealth::new(Duration::from_secs(
+                config_obj.worker_heartbeat_timeout(),
+            )),
             job_notify: Arc::new(Notify::new()),
             long_running_job_notify: Arc::new(Notify::new()),
             meta_store_sender,
@@ -1054,6 +1253,7 @@ impl ClusterImpl {
                 chunk_store: self.injector.upgrade().unwrap().get_service_typed().await,
                 compaction_service: self.injector.upgrade().unwrap().get_service_typed().await,
                 import_service: self.injector.upgrade().unwrap().get_service_typed().await,
+                membership: self.membership.clone(),
                 server_name: self.server_name.clone(),
                 notify: if is_long_running {
                     self.long_running_job_notify.clone()
@@ -1196,12 +1396,22 @@ impl ClusterImpl {
         address: &str,
         cluster: Arc<ClusterImpl>,
         on_socket_bound: oneshot::Sender<()>,
-        process_fn: impl Fn(Arc<ClusterImpl>, TcpStream) -> F + Send + Sync + Clone + 'static,
+        process_fn: impl Fn(Arc<ClusterImpl>, Box<dyn ClusterStream>) -> F
+            + Send
+            + Sync
+            + Clone
+            + 'static,
     ) -> Result<(), CubeError> {
+        let tls = ClusterTls::from_config(cluster.config_obj.as_ref())?;
         let listener = TcpListener::bind(address.clone()).await?;
         let _ = on_socket_bound.send(());
 
-        info!("{} port open on {}", name, address);
+        info!(
+            "{} port open on {}{}",
+            name,
+            address,
+            if tls.is_some() { " with TLS" } else { "" }
+        );
 
         loop {
             let mut stop_receiver = cluster.close_worker_socket_rx.write().await;
@@ -1225,8 +1435,17 @@ impl ClusterImpl {
             };
             let cluster_to_move = cluster.clone();
             let process_fn_to_move = process_fn.clone();
+            let tls_to_move = tls.clone();
 
             cube_ext::spawn(async move {
+                // Handshake is done here to avoid blocking other connections.
+                let socket = match accept_stream(tls_to_move.as_deref(), socket).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Network error: {}", e);
+                        return;
+                    }
+                };
                 process_fn_to_move(cluster_to_move, socket).await;
             });
         }
@@ -1280,39 +1499,7 @@ impl ClusterImpl {
             warn!("Warmup download for select ({:?})", warmup);
         }
 
-        let chunk_store = self
-            .injector
-            .upgrade()
-            .unwrap()
-            .get_service_typed::<dyn ChunkDataStore>()
-            .await;
-
-        let in_memory_chunks_to_load = plan_node.in_memory_chunks_to_load();
-        let in_memory_chunks_futures = in_memory_chunks_to_load
-            .iter()
-            .map(|(c, p, i)| {
-                chunk_store
-                    .get_chunk_columns_with_preloaded_meta(c.clone(), p.clone(), i.clone())
-                    .instrument(tracing::span!(
-                        tracing::Level::TRACE,
-                        "get in memory chunk columns",
-                        row_count = c.get_row().get_row_count()
-                    ))
-            })
-            .collect::<Vec<_>>();
-
-        let chunk_id_to_record_batches = in_memory_chunks_to_load
-            .clone()
-            .into_iter()
-            .map(|(c, _, _)| c.get_id())
-            .zip(
-                join_all(in_memory_chunks_futures)
-                    .await
-                    .into_iter()
-                    .collect::<Result<Vec<_>, _>>()?
-                    .into_iter(),
-            )
-            .collect::<HashMap<_, _>>();
+        let chunk_id_to_record_batches = self.load_in_memory_chunks(&plan_node).await?;
 
         let mut res = None;
         #[cfg(not(target_os = "windows"))]
@@ -1366,6 +1553,70 @@ impl ClusterImpl {
         res.unwrap()
     }
 
+    async fn load_in_memory_chunks(
+        &self,
+        plan_node: &SerializedPlan,
+    ) -> Result<HashMap<u64, Vec<RecordBatch>>, CubeError> {
+        let chunk_store = self
+            .injector
+            .upgrade()
+            .unwrap()
+            .get_service_typed::<dyn ChunkDataStore>()
+            .await;
+
+        let in_memory_chunks_to_load = plan_node.in_memory_chunks_to_load();
+        let in_memory_chunks_futures = in_memory_chunks_to_load
+            .iter()
+            .map(|(c, p, i)| {
+                chunk_store
+                    .get_chunk_columns_with_preloaded_meta(c.clone(), p.clone(), i.clone())
+                    .instrument(tracing::span!(
+                        tracing::Level::TRACE,
+                        "get in memory chunk columns",
+                        row_count = c.get_row().get_row_count()
+                    ))
+            })
+            .collect::<Vec<_>>();
+
+        Ok(in_memory_chunks_to_load
+            .clone()
+            .into_iter()
+            .map(|(c, _, _)| c.get_id())
+            .zip(
+                join_all(in_memory_chunks_futures)
+                    .await
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter(),
+            )
+            .collect::<HashMap<_, _>>())
+    }
+
+    async fn run_local_explain_json_worker(
+        &self,
+        plan_node: SerializedPlan,
+        analyze: bool,
+    ) -> Result<ExplainNode, CubeError> {
+        let remote_to_local_names = self.warmup_select_worker_files(&plan_node).await?;
+        let chunk_id_to_record_batches = if analyze {
+            self.load_in_memory_chunks(&plan_node).await?
+        } else {
+            plan_node
+                .in_memory_chunks_to_load()
+                .into_iter()
+                .map(|(c, _, _)| (c.get_id(), Vec::new()))
+                .collect()
+        };
+        self.query_executor
+            .explain_worker_plan(
+                plan_node,
+                remote_to_local_names,
+                chunk_id_to_record_batches,
+                analyze,
+            )
+            .await
+    }
+
     async fn run_local_explain_analyze_worker(
         &self,
         plan_node: SerializedPlan,
@@ -1498,18 +1749,31 @@ impl ClusterImpl {
         node_name: &str,
         plan: SerializedPlan,
     ) -> Result<SendableRecordBatchStream, CubeError> {
+        self.start_select_stream(node_name, plan).await?
+    }
+
+    /// The outer error means the worker can't be reached, the inner one comes from the worker.
+    async fn start_select_stream(
+        self: &Arc<Self>,
+        node_name: &str,
+        plan: SerializedPlan,
+    ) -> Result<Result<SendableRecordBatchStream, CubeError>, CubeError> {
         let init_message = NetworkMessage::SelectStart(plan);
         let mut c = self.call_streaming(node_name, init_message).await?;
         let schema = match c.receive().await? {
             NetworkMessage::SelectResultSchema(s) => s,
             _ => panic!("unexpected response to select stream"),
-        }?;
-        return Ok(Box::pin(SelectStream {
+        };
+        let schema = match schema {
+            Ok(schema) => schema,
+            Err(e) => return Ok(Err(e)),
+        };
+        return Ok(Ok(Box::pin(SelectStream {
             schema,
             connection: Some(c),
             pending: Mutex::new(None),
             finished: false,
-        }));
+        })));
 
         type ConnPtr = Box<dyn WorkerConnection>;
         struct SelectStream {
@@ -1597,20 +1861,42 @@ impl ClusterImpl {
         }
     }
 
+    /// Attempts on all replicas but the last one are limited by
+    /// [ConfigObj::select_replica_timeout], so a hanging worker doesn't use up the query timeout
+    /// before the other replicas are tried.
+    async fn with_replica_timeout<T>(
+        &self,
+        node_name: &str,
+        is_last: bool,
+        attempt: impl Future<Output = Result<T, CubeError>>,
+    ) -> Result<T, CubeError> {
+        if is_last {
+            return attempt.await;
+        }
+        let limit = Duration::from_secs(self.config_obj.select_replica_timeout());
+        match timeout(limit, attempt).await {
+            Ok(r) => r,
+            Err(_) => Err(CubeError::internal(format!(
+                "Select on {} didn't respond in {:?}",
+                node_name, limit
+            ))),
+        }
+    }
+
+    fn on_replica_failure(&self, node_name: &str, e: &CubeError) {
+        warn!(
+            "Can't run select on {}, trying other replicas: {}",
+            node_name, e
+        );
+        self.node_health.report_failure(node_name);
+    }
+
     /// Downloads missing data files for the current partition. Will do the downloads sequentially
     /// to avoid monopolizing the queue of selects that might follow.
     ///
     /// Can take awhile, use the passed cancellation token to stop the worker before it finishes.
     /// Designed to run in the background.
     pub async fn warmup_select_worker(&self) {
-        if self.config_obj.select_workers().len() == 0 {
-            log::error!("No select workers specified");
-            return;
-        }
-        if !self.config_obj.select_workers().contains(&self.server_name) {
-            log::error!("Current node is not a select worker");
-            return;
-        }
         if !self.config_obj.enable_startup_warmup() {
             log::info!("Startup warmup disabled");
             return;
@@ -1626,8 +1912,15 @@ impl ClusterImpl {
         };
         log::debug!("Got {} partitions, running the warmup", partitions.len());
 
+        // Registered workers get partitions only after they become active, warm up the ones we
+        // are going to own at that point.
+        if let Err(e) = self.membership.refresh().await {
+            log::error!("Failed to get select workers for startup warmup: {}", e);
+        }
+        let workers = self.membership.workers().with_worker(&self.server_name);
+        let replicas = self.config_obj.select_worker_replicas();
         for (p, chunks) in partitions {
-            if self.node_name_by_partition(&p) != self.server_name {
+            if !replicas_by_partition_in(&workers, &p, replicas).contains(&self.server_name) {
                 continue;
             }
             if let Some(file) = p.get_row().get_full_name(p.get_id()) {
@@ -1665,6 +1958,312 @@ impl ClusterImpl {
         log::debug!("Startup warmup finished");
         return;
     }
+
+    async fn warmup_partition_on(
+        &self,
+        node_name: &str,
+        partition: IdRow<Partition>,
+        chunks: Vec<IdRow<Chunk>>,
+    ) -> Result<(), CubeError> {
+        let mut futures = Vec::new();
+        if let Some(name) = partition.get_row().get_full_name(partition.get_id()) {
+            futures.push(self.warmup_download(node_name, name, partition.get_row().file_size()));
+        }
+        for chunk in chunks.iter() {
+            let name = chunk.get_row().get_full_name(chunk.get_id());
+            futures.push(self.warmup_download(node_name, name, chunk.get_row().file_size()));
+        }
+        let res = join_all(futures)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>();
+
+        deactivate_table_on_corrupt_data(self.meta_store.clone(), &res, &partition).await;
+
+        res?;
+        Ok(())
+    }
+
+    /// Runs on workers: registers the worker with the metastore and keeps its heartbeat and the
+    /// set of select workers up to date.
+    pub async fn worker_heartbeat_loop(&self) {
+        let interval = Duration::from_secs(self.config_obj.worker_heartbeat_interval());
+        loop {
+            if let Err(e) = self
+                .meta_store
+                .node_heartbeat(self.server_name.clone())
+                .await
+            {
+                log::error!("Failed to send worker heartbeat: {}", e);
+            }
+            if let Err(e) = self.membership.refresh().await {
+                log::error!("Failed to refresh select workers: {}", e);
+            }
+            tokio::select! {
+                _ = self.stop_token.cancelled() => {
+                    return;
+                }
+                _ = Delay::new(interval) => {}
+            }
+        }
+    }
+
+    /// Runs on the router, see [ClusterImpl::update_membership].
+    pub async fn membership_loop(&self) {
+        let interval = Duration::from_secs(self.config_obj.worker_heartbeat_interval());
+        loop {
+            if let Err(e) = self.update_membership().await {
+                log::error!("Failed to update cluster membership: {}", e);
+            }
+            tokio::select! {
+                _ = self.stop_token.cancelled() => {
+                    return;
+                }
+                _ = Delay::new(interval) => {}
+            }
+        }
+    }
+
+    /// Starts routing to registered workers once partitions that move to them are warmed up and
+    /// stops routing to the ones that missed heartbeats.
+    pub async fn update_membership(&self) -> Result<(), CubeError> {
+        let timeout = chrono::Duration::seconds(self.config_obj.worker_heartbeat_timeout() as i64);
+        for node in self.meta_store.get_nodes().await? {
+            match (node.get_row().state(), node.get_row().is_alive(timeout)) {
+                (NodeState::Joining, true) => self.start_worker_activation(node),
+                (NodeState::Joining, false) | (NodeState::Active, false) => {
+                    // Failure of one node must not keep the others from being marked unavailable.
+                    if let Err(e) = self.mark_worker_unavailable(&node).await {
+                        log::error!(
+                            "Failed to mark worker {} as unavailable: {}",
+                            node.get_row().name(),
+                            e
+                        );
+                    }
+                }
+                (NodeState::Active, true)
+                | (NodeState::Unavailable, true)
+                | (NodeState::Unavailable, false) => {}
+            }
+        }
+        self.membership.refresh().await?;
+        Ok(())
+    }
+
+    /// Warming up a joining worker can take a while, so it runs in the background and doesn't
+    /// delay detection of failed workers. At most one activation per worker runs at a time.
+    fn start_worker_activation(&self, node: IdRow<Node>) {
+        let name = node.get_row().name().to_string();
+        if !self.activating_workers.lock().unwrap().insert(name.clone()) {
+            return;
+        }
+        let cluster = self.this.upgrade().unwrap();
+        cube_ext::spawn(async move {
+            if let Err(e) = cluster.activate_worker(&node).await {
+                log::error!("Failed to activate worker {}: {}", name, e);
+            }
+            cluster.activating_workers.lock().unwrap().remove(&name);
+        });
+    }
+
+    async fn activate_worker(&self, node: &IdRow<Node>) -> Result<(), CubeError> {
+        let name = node.get_row().name();
+        self.warmup_joining_worker(name).await?;
+        // The worker might have missed heartbeats while we were warming it up.
+        let timeout = chrono::Duration::seconds(self.config_obj.worker_heartbeat_timeout() as i64);
+        let still_joining = self.meta_store.get_nodes().await?.into_iter().any(|n| {
+            n.get_id() == node.get_id()
+                && n.get_row().state() == NodeState::Joining
+                && n.get_row().is_alive(timeout)
+        });
+        if !still_joining {
+            log::warn!("Worker {} is no longer joining, not activating it", name);
+            return Ok(());
+        }
+        let current = self.membership.workers();
+        let next = current.with_worker(name);
+        // In memory chunks exist only on the owner of their partition. They are persisted before
+        // the routing changes and once more after that for chunks that were added while the
+        // first flush was running.
+        self.flush_moved_in_memory_chunks(&current, &next).await?;
+        self.meta_store
+            .update_node_state(node.get_id(), NodeState::Active)
+            .await?;
+        self.membership.refresh().await?;
+        if let Err(e) = self.flush_moved_in_memory_chunks(&current, &next).await {
+            log::error!(
+                "Failed to flush in memory chunks of partitions moved to {}: {}",
+                name,
+                e
+            );
+        }
+        log::info!("Worker {} is active", name);
+        Ok(())
+    }
+
+    async fn mark_worker_unavailable(&self, node: &IdRow<Node>) -> Result<(), CubeError> {
+        let name = node.get_row().name();
+        let previous = self.membership.workers();
+        self.meta_store
+            .update_node_state(node.get_id(), NodeState::Unavailable)
+            .await?;
+        let current = self.membership.refresh().await?;
+        log::warn!(
+            "Worker {} missed heartbeats, its partitions are routed to other workers",
+            name
+        );
+        self.schedule_lost_in_memory_chunks_compaction(&previous, &current, name)
+            .await
+    }
+
+    /// Partitions with active in memory chunks along with their owner in `current`, if `next`
+    /// routes them to another node.
+    async fn partitions_with_moved_in_memory_chunks(
+        &self,
+        current: &WorkerSet,
+        next: &WorkerSet,
+    ) -> Result<Vec<(IdRow<Partition>, String)>, CubeError> {
+        let mut moved = Vec::new();
+        for (partition, chunks) in self.meta_store.get_warmup_partitions().await? {
+            let has_in_memory = chunks.iter().any(|c| {
+                c.get_row().in_memory() && c.get_row().get_partition_id() == partition.get_id()
+            });
+            if !has_in_memory {
+                continue;
+            }
+            let owner = node_name_by_partition_in(current, &partition);
+            if owner != node_name_by_partition_in(next, &partition) {
+                moved.push((partition, owner));
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Persists in memory chunks on the nodes that stop owning their partitions and waits for
+    /// that to finish, see [CompactionService::flush_in_memory_chunks].
+    async fn flush_moved_in_memory_chunks(
+        &self,
+        current: &WorkerSet,
+        next: &WorkerSet,
+    ) -> Result<(), CubeError> {
+        let moved = self
+            .partitions_with_moved_in_memory_chunks(current, next)
+            .await?;
+        if moved.is_empty() {
+            return Ok(());
+        }
+        let listener = self.job_result_listener();
+        let mut wait_for = Vec::new();
+        for (partition, owner) in moved {
+            let key = RowKey::Table(TableId::Partitions, partition.get_id());
+            let job = self
+                .meta_store
+                .add_job(Job::new(
+                    key.clone(),
+                    JobType::InMemoryChunksFlush,
+                    owner.to_string(),
+                ))
+                .await?;
+            if job.is_some() {
+                self.notify_job_runner(owner).await?;
+            }
+            wait_for.push((key, JobType::InMemoryChunksFlush));
+        }
+        let results = timeout(
+            Duration::from_secs(self.config_obj.import_job_timeout()),
+            listener.wait_for_job_results(wait_for),
+        )
+        .await
+        .map_err(|_| CubeError::internal("Timed out flushing in memory chunks".to_string()))??;
+        for r in results {
+            if let JobEvent::Error(key, _, e) = r {
+                return Err(CubeError::internal(format!(
+                    "Failed to flush in memory chunks of {:?}: {}",
+                    key, e
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// In memory chunks held by a failed worker are lost. The new owners mark them for replay
+    /// during compaction, which is scheduled right away so queries don't keep failing on them.
+    async fn schedule_lost_in_memory_chunks_compaction(
+        &self,
+        previous: &WorkerSet,
+        current: &WorkerSet,
+        worker: &str,
+    ) -> Result<(), CubeError> {
+        for (partition, owner) in self
+            .partitions_with_moved_in_memory_chunks(previous, current)
+            .await?
+        {
+            if owner != worker {
+                continue;
+            }
+            let node = node_name_by_partition_in(current, &partition);
+            let job = self
+                .meta_store
+                .add_job(Job::new(
+                    RowKey::Table(TableId::Partitions, partition.get_id()),
+                    JobType::InMemoryChunksCompaction,
+                    node.to_string(),
+                ))
+                .await?;
+            if job.is_some() {
+                self.notify_job_runner(node).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Downloads files of the partitions that move to `worker` once it's added to the select
+    /// workers, so queries don't wait for downloads after the routing switches.
+    async fn warmup_joining_worker(&self, worker: &str) -> Result<(), CubeError> {
+        let current = self.membership.workers();
+        let next = current.with_worker(worker);
+        if *current == next {
+            return Ok(());
+        }
+        let replicas = self.config_obj.select_worker_replicas();
+        let is_replica = |workers: &WorkerSet, p: &IdRow<Partition>| {
+            replicas_by_partition_in(workers, p, replicas)
+                .iter()
+                .any(|r| r == worker)
+        };
+        let partitions = self
+            .meta_store
+            .get_warmup_partitions()
+            .await?
+            .into_iter()
+            .filter(|(p, _)| is_replica(&next, p) && !is_replica(&current, p))
+            .collect_vec();
+        let warmed_up = partitions.len();
+        futures::stream::iter(partitions)
+            .for_each_concurrent(
+                JOINING_WORKER_WARMUP_CONCURRENCY,
+                |(partition, chunks)| async move {
+                    let partition_id = partition.get_id();
+                    // Compaction might remove files in the meantime, that's not a reason to keep
+                    // the worker out of the cluster.
+                    if let Err(e) = self.warmup_partition_on(worker, partition, chunks).await {
+                        log::warn!(
+                            "Failed to warm up partition {} on {}: {}",
+                            partition_id,
+                            worker,
+                            e
+                        );
+                    }
+                },
+            )
+            .await;
+        log::info!(
+            "Warmed up {} partitions on joining worker {}",
+            warmed_up,
+            worker
+        );
+        Ok(())
+    }
 }
 
 struct LoopbackConnection {
@@ -1752,38 +2351,86 @@ fn is_self_reference(name: &str) -> bool {
 /// Picks a worker by opaque id for any distributing work in a cluster.
 /// Ids usually come from multi-partitions of the metastore.
 pub fn pick_worker_by_ids<'a>(
-    config: &'a dyn ConfigObj,
+    workers: &'a WorkerSet,
     ids: impl IntoIterator<Item = u64>,
 ) -> &'a str {
-    let workers = config.select_workers();
-    if workers.is_empty() {
-        return config.server_name().as_str();
-    }
+    workers.pick(ids_hash(ids))
+}
 
+/// Same as [pick_worker_by_ids], but returns `count` replicas, starting with the same worker.
+pub fn pick_replicas_by_ids<'a>(
+    workers: &'a WorkerSet,
+    ids: impl IntoIterator<Item = u64>,
+    count: usize,
+) -> Vec<&'a str> {
+    workers.replicas(ids_hash(ids), count)
+}
+
+fn ids_hash(ids: impl IntoIterator<Item = u64>) -> u64 {
     let mut hasher = DefaultHasher::new();
     for p in ids {
         p.hash(&mut hasher);
     }
-    workers[(hasher.finish() % workers.len() as u64) as usize].as_str()
+    hasher.finish()
 }
 
 /// Same as [pick_worker_by_ids], but uses ranges of partitions. This is a hack
 /// to keep the same node for partitions produced by compaction that merged
 /// chunks into the main table of a single partition.
-pub fn pick_worker_by_partitions<'a>(
-    config: &'a dyn ConfigObj,
-    partitions: impl IntoIterator<Item = &'a IdRow<Partition>>,
+pub fn pick_worker_by_partitions<'a, 'p>(
+    workers: &'a WorkerSet,
+    partitions: impl IntoIterator<Item = &'p IdRow<Partition>>,
 ) -> &'a str {
-    let workers = config.select_workers();
-    if workers.is_empty() {
-        return config.server_name().as_str();
-    }
+    workers.pick(partitions_hash(partitions))
+}
+
+/// Same as [pick_worker_by_partitions], but returns `count` replicas, starting with the same
+/// worker.
+pub fn pick_replicas_by_partitions<'a, 'p>(
+    workers: &'a WorkerSet,
+    partitions: impl IntoIterator<Item = &'p IdRow<Partition>>,
+    count: usize,
+) -> Vec<&'a str> {
+    workers.replicas(partitions_hash(partitions), count)
+}
 
+fn partitions_hash<'p>(partitions: impl IntoIterator<Item = &'p IdRow<Partition>>) -> u64 {
     let mut hasher = DefaultHasher::new();
     for partition in partitions {
         partition.get_row().get_min_val().hash(&mut hasher);
         partition.get_row().get_max_val().hash(&mut hasher);
         partition.get_row().get_index_id().hash(&mut hasher);
+        // Root partitions of time buckets only differ by the bucket. Partitions without a bucket
+        // keep their hash.
+        if let Some(bucket) = partition.get_row().time_bucket() {
+            bucket.hash(&mut hasher);
+        }
     }
-    workers[(hasher.finish() % workers.len() as u64) as usize].as_str()
+    hasher.finish()
+}
+
+fn node_name_by_partition_in(workers: &WorkerSet, p: &IdRow<Partition>) -> String {
+    if let Some(id) = p.get_row().multi_partition_id() {
+        pick_worker_by_ids(workers, [id]).to_string()
+    } else {
+        pick_worker_by_partitions(workers, [p]).to_string()
+    }
+}
+
+/// Workers that keep the files of the partition warm, see [ConfigObj::select_worker_replicas].
+fn replicas_by_partition_in(
+    workers: &WorkerSet,
+    p: &IdRow<Partition>,
+    count: usize,
+) -> Vec<String> {
+    let replicas = if let Some(id) = p.get_row().multi_partition_id() {
+        pick_replicas_by_ids(workers, [id], count)
+    } else {
+        pick_replicas_by_partitions(workers, [p], count)
+    };
+    replicas.into_iter().map(|r| r.to_string()).collect()
+}
+
+fn no_replicas_error() -> CubeError {
+    CubeError::internal("No workers to run select on".to_string())
 }
@@ -0,0 +1,379 @@
+use crate::config::{ClusterTlsConfig, ConfigObj};
+use crate::CubeError;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Connection between the cluster nodes, either plain TCP or TLS.
+pub trait ClusterStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClusterStream for T {}
+
+/// TLS with mutual authentication for the connections between the router, workers and the
+/// metastore. Every node uses the same certificate as a server and as a client, the peer must
+/// present a certificate signed by the configured CA on both sides of the connection.
+pub struct ClusterTls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    /// Overrides the name checked in the server certificates, host names from the addresses of
+    /// workers and the metastore are used otherwise.
+    server_name: Option<ServerName>,
+    /// Peers that stop responding during the handshake must not hold the connection forever.
+    handshake_timeout: Duration,
+}
+
+impl ClusterTls {
+    /// Returns [None] when TLS is not configured, i.e. plain TCP must be used.
+    pub fn from_config(config: &dyn ConfigObj) -> Result<Option<Arc<ClusterTls>>, CubeError> {
+        match config.cluster_tls() {
+            None => Ok(None),
+            Some(c) => Ok(Some(Arc::new(ClusterTls::new(
+                c,
+                Duration::from_secs(config.connection_timeout()),
+            )?))),
+        }
+    }
+
+    pub fn new(
+        config: &ClusterTlsConfig,
+        handshake_timeout: Duration,
+    ) -> Result<ClusterTls, CubeError> {
+        let certs = read_certs(&config.cert_path)?;
+        let key = read_private_key(&config.key_path)?;
+        let mut roots = RootCertStore::empty();
+        for ca in read_certs(&config.ca_path)? {
+            roots.add(&ca).map_err(|e| {
+                CubeError::user(format!(
+                    "Invalid CA certificate in {}: {}",
+                    config.ca_path.display(),
+                    e
+                ))
+            })?;
+        }
+
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()))
+            .with_single_cert(certs.clone(), key.clone())
+            .map_err(|e| CubeError::user(format!("Invalid cluster TLS certificate: {}", e)))?;
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_single_cert(certs, key)
+            .map_err(|e| CubeError::user(format!("Invalid cluster TLS certificate: {}", e)))?;
+        let server_name = match &config.server_name {
+            None => None,
+            Some(n) => Some(parse_server_name(n)?),
+        };
+        Ok(ClusterTls {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name,
+            handshake_timeout,
+        })
+    }
+
+    /// Server side of the handshake, fails if the client did not present a valid certificate.
+    pub async fn accept(&self, stream: TcpStream) -> Result<Box<dyn ClusterStream>, CubeError> {
+        let stream = tokio::time::timeout(self.handshake_timeout, self.acceptor.accept(stream))
+            .await
+            .map_err(|_| {
+                CubeError::internal("TLS handshake with cluster peer timed out".to_string())
+            })?
+            .map_err(|e| {
+                CubeError::internal(format!("TLS handshake with cluster peer failed: {}", e))
+            })?;
+        Ok(Box::new(stream))
+    }
+
+    /// Client side of the handshake, `address` is the `host:port` we connected to.
+    pub async fn connect(
+        &self,
+        address: &str,
+        stream: TcpStream,
+    ) -> Result<Box<dyn ClusterStream>, CubeError> {
+        let server_name = match &self.server_name {
+            Some(n) => n.clone(),
+            None => parse_server_name(host_of(address))?,
+        };
+        let stream = tokio::time::timeout(
+            self.handshake_timeout,
+            self.connector.connect(server_name, stream),
+        )
+        .await
+        .map_err(|_| CubeError::internal(format!("TLS handshake with {} timed out", address)))?
+        .map_err(|e| {
+            CubeError::internal(format!("TLS handshake with {} failed: {}", address, e))
+        })?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Wraps an accepted connection into TLS, if it is configured.
+pub async fn accept_stream(
+    tls: Option<&ClusterTls>,
+    stream: TcpStream,
+) -> Result<Box<dyn ClusterStream>, CubeError> {
+    match tls {
+        None => Ok(Box::new(stream)),
+        Some(tls) => tls.accept(stream).await,
+    }
+}
+
+/// Wraps an outgoing connection to `address` into TLS, if it is configured.
+pub async fn connect_stream(
+    tls: Option<&ClusterTls>,
+    address: &str,
+    stream: TcpStream,
+) -> Result<Box<dyn ClusterStream>, CubeError> {
+    match tls {
+        None => Ok(Box::new(stream)),
+        Some(tls) => tls.connect(address, stream).await,
+    }
+}
+
+fn host_of(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn parse_server_name(name: &str) -> Result<ServerName, CubeError> {
+    ServerName::try_from(name)
+        .map_err(|_| CubeError::user(format!("Invalid TLS server name: '{}'", name)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, CubeError> {
+    let mut reader = BufReader::new(open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).map_err(|e| {
+        CubeError::user(format!(
+            "Can't read certificates from {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    if certs.is_empty() {
+        return Err(CubeError::user(format!(
+            "No certificates found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey, CubeError> {
+    let mut reader = BufReader::new(open(path)?);
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader).map_err(|e| {
+            CubeError::user(format!(
+                "Can't read private key from {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        match item {
+            Some(rustls_pemfile::Item::RSAKey(k))
+            | Some(rustls_pemfile::Item::PKCS8Key(k))
+            | Some(rustls_pemfile::Item::ECKey(k)) => return Ok(PrivateKey(k)),
+            Some(_) => continue,
+            None => {
+                return Err(CubeError::user(format!(
+                    "No private key found in {}",
+                    path.display()
+                )))
+            }
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File, CubeError> {
+    File::open(path).map_err(|e| CubeError::user(format!("Can't open {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate as GenCertificate, CertificateParams, IsCa};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn generate_ca() -> GenCertificate {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        GenCertificate::from_params(params).unwrap()
+    }
+
+    /// Writes a certificate for `localhost` signed by `ca` along with the CA certificate itself.
+    fn write_node_config(dir: &TempDir, name: &str, ca: &GenCertificate) -> ClusterTlsConfig {
+        let node =
+            GenCertificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                .unwrap();
+        let write = |file: &str, content: String| -> PathBuf {
+            let path = dir.path().join(format!("{}-{}", name, file));
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        ClusterTlsConfig {
+            cert_path: write("cert.pem", node.serialize_pem_with_signer(ca).unwrap()),
+            key_path: write("key.pem", node.serialize_private_key_pem()),
+            ca_path: write("ca.pem", ca.serialize_pem().unwrap()),
+            server_name: None,
+        }
+    }
+
+    /// Accepts a single connection and echoes a number sent by the client.
+    async fn echo_server(
+        tls: ClusterTls,
+    ) -> (String, tokio::task::JoinHandle<Result<(), CubeError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            let mut stream = tls.accept(socket).await?;
+            let v = stream.read_u32().await?;
+            stream.write_u32(v).await?;
+            stream.flush().await?;
+            Ok(())
+        });
+        (address, server)
+    }
+
+    async fn echo(tls: &ClusterTls, address: &str) -> Result<u32, CubeError> {
+        let socket = TcpStream::connect(address).await?;
+        let mut stream = tls.connect(address, socket).await?;
+        stream.write_u32(42).await?;
+        stream.flush().await?;
+        Ok(stream.read_u32().await?)
+    }
+
+    #[tokio::test]
+    async fn mutual_authentication() {
+        let dir = TempDir::new().unwrap();
+        let ca = generate_ca();
+        let server_config = write_node_config(&dir, "server", &ca);
+        let client_config = write_node_config(&dir, "client", &ca);
+
+        let (address, server) =
+            echo_server(ClusterTls::new(&server_config, TIMEOUT).unwrap()).await;
+        let client = ClusterTls::new(&client_config, TIMEOUT).unwrap();
+        assert_eq!(echo(&client, &address).await.unwrap(), 42);
+        server.await.unwrap().unwrap();
+
+        // Certificates are checked against the host name unless it is overridden.
+        let (address, server) =
+            echo_server(ClusterTls::new(&server_config, TIMEOUT).unwrap()).await;
+        let ip_address = address.replace("localhost", "127.0.0.1");
+        assert!(echo(&client, &ip_address).await.is_err());
+        server.await.unwrap().unwrap_err();
+
+        let (address, server) =
+            echo_server(ClusterTls::new(&server_config, TIMEOUT).unwrap()).await;
+        let ip_address = address.replace("localhost", "127.0.0.1");
+        let client = ClusterTls::new(
+            &ClusterTlsConfig {
+                server_name: Some("localhost".to_string()),
+                ..client_config
+            },
+            TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(echo(&client, &ip_address).await.unwrap(), 42);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_peers() {
+        let dir = TempDir::new().unwrap();
+        let ca = generate_ca();
+        let server_config = write_node_config(&dir, "server", &ca);
+
+        // Client trusts the server, but its own certificate is signed by another CA.
+        let other_ca = generate_ca();
+        let rogue_config = ClusterTlsConfig {
+            ca_path: server_config.ca_path.clone(),
+            ..write_node_config(&dir, "rogue", &other_ca)
+        };
+        let (address, server) =
+            echo_server(ClusterTls::new(&server_config, TIMEOUT).unwrap()).await;
+        assert!(
+            echo(&ClusterTls::new(&rogue_config, TIMEOUT).unwrap(), &address)
+                .await
+                .is_err()
+        );
+        server.await.unwrap().unwrap_err();
+
+        // Plain TCP clients are rejected as well.
+        let (address, server) =
+            echo_server(ClusterTls::new(&server_config, TIMEOUT).unwrap()).await;
+        let mut socket = TcpStream::connect(&address).await.unwrap();
+        socket.write_u32(42).await.unwrap();
+        drop(socket);
+        server.await.unwrap().unwrap_err();
+
+        // Client does not trust the server signed by an unknown CA.
+        let other_config = write_node_config(&dir, "other", &other_ca);
+        let (address, server) =
+            echo_server(ClusterTls::new(&server_config, TIMEOUT).unwrap()).await;
+        assert!(
+            echo(&ClusterTls::new(&other_config, TIMEOUT).unwrap(), &address)
+                .await
+                .is_err()
+        );
+        server.await.unwrap().unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let dir = TempDir::new().unwrap();
+        let config = write_node_config(&dir, "node", &generate_ca());
+        let timeout = Duration::from_millis(100);
+
+        // Client connects, but never starts the handshake.
+        let (address, server) = echo_server(ClusterTls::new(&config, timeout).unwrap()).await;
+        let socket = TcpStream::connect(&address).await.unwrap();
+        let err = server.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        drop(socket);
+
+        // Server accepts the connection, but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let err = echo(&ClusterTls::new(&config, timeout).unwrap(), &address)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        drop(listener);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let dir = TempDir::new().unwrap();
+        let mut config = write_node_config(&dir, "node", &generate_ca());
+        config.key_path = config.cert_path.clone();
+        assert!(ClusterTls::new(&config, TIMEOUT).is_err());
+        config.key_path = dir.path().join("missing.pem");
+        assert!(ClusterTls::new(&config, TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn host_names() {
+        assert_eq!(host_of("localhost:10001"), "localhost");
+        assert_eq!(host_of("[::1]:10001"), "::1");
+        assert_eq!(host_of("worker-1"), "worker-1");
+    }
+}
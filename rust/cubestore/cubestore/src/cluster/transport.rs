@@ -1,4 +1,5 @@
 use crate::cluster::message::NetworkMessage;
+use crate::cluster::tls::{connect_stream, ClusterStream, ClusterTls};
 use crate::config::injection::DIService;
 use crate::config::ConfigObj;
 use crate::CubeError;
@@ -59,18 +60,26 @@ impl dyn ClusterTransport {
 
 pub struct ClusterTransportImpl {
     config: Arc<dyn ConfigObj>,
+    tls: Option<Arc<ClusterTls>>,
 }
 
 crate::di_service!(ClusterTransportImpl, [ClusterTransport]);
 
 impl ClusterTransportImpl {
     pub fn new(config: Arc<dyn ConfigObj>) -> Arc<Self> {
-        Arc::new(Self { config })
+        let tls = cluster_tls(config.as_ref());
+        Arc::new(Self { config, tls })
     }
 }
 
+/// Certificates are loaded once on startup, so invalid TLS settings are reported immediately.
+fn cluster_tls(config: &dyn ConfigObj) -> Option<Arc<ClusterTls>> {
+    ClusterTls::from_config(config)
+        .unwrap_or_else(|e| panic!("Invalid cluster TLS configuration: {}", e))
+}
+
 struct Connection {
-    stream: TcpStream,
+    stream: Box<dyn ClusterStream>,
 }
 
 #[async_trait]
@@ -97,6 +106,7 @@ impl ClusterTransport for ClusterTransportImpl {
         .await
         .map_err(|_| CubeError::internal(format!("Connection timeout to {}. Please check your worker connection env variables (CUBESTORE_WORKERS, CUBESTORE_WORKER_PORT, etc.).", worker_node)))?
         .map_err(|e| CubeError::internal(format!("Can't connect to {}: {}", worker_node, e)))?;
+        let stream = connect_stream(self.tls.as_deref(), &worker_node, stream).await?;
         Ok(Box::new(Connection { stream }))
     }
 }
@@ -108,13 +118,15 @@ pub trait MetaStoreTransport: DIService {
 
 pub struct MetaStoreTransportImpl {
     config: Arc<dyn ConfigObj>,
+    tls: Option<Arc<ClusterTls>>,
 }
 
 crate::di_service!(MetaStoreTransportImpl, [MetaStoreTransport]);
 
 impl MetaStoreTransportImpl {
     pub fn new(config: Arc<dyn ConfigObj>) -> Arc<Self> {
-        Arc::new(Self { config })
+        let tls = cluster_tls(config.as_ref());
+        Arc::new(Self { config, tls })
     }
 }
 
@@ -127,7 +139,7 @@ impl MetaStoreTransport for MetaStoreTransportImpl {
             .as_ref()
             .expect("Meta store remote addr is not defined")
             .to_string();
-        let mut stream = tokio::time::timeout(
+        let stream = tokio::time::timeout(
             Duration::from_secs(self.config.connection_timeout()),
             TcpStream::connect(&meta_remote_addr),
         )
@@ -141,6 +153,7 @@ impl MetaStoreTransport for MetaStoreTransportImpl {
         .map_err(|e| {
             CubeError::internal(format!("Can't connect to {}: {}", meta_remote_addr, e))
         })?;
+        let mut stream = connect_stream(self.tls.as_deref(), &meta_remote_addr, stream).await?;
         m.send(&mut stream).await?;
         let message = NetworkMessage::receive(&mut stream).await?;
         Ok(message)
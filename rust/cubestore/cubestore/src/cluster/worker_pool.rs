@@ -20,6 +20,8 @@ use tracing::{instrument, Instrument};
 use tracing_futures::WithSubscriber;
 
 use crate::config::{Config, WorkerServices};
+use crate::util::metrics;
+use crate::util::metrics::MetricUpdate;
 use crate::util::respawn::respawn;
 use crate::CubeError;
 use datafusion::cube_ext;
@@ -277,16 +279,18 @@ impl<
         &self,
         message: T,
         args_tx: IpcSender<T>,
-        res_rx: IpcReceiver<Result<R, CubeError>>,
-    ) -> Result<(R, IpcSender<T>, IpcReceiver<Result<R, CubeError>>), CubeError> {
+        res_rx: IpcReceiver<WorkerResult<R>>,
+    ) -> Result<(R, IpcSender<T>, IpcReceiver<WorkerResult<R>>), CubeError> {
         args_tx.send(message)?;
         let (res, res_rx) = cube_ext::spawn_blocking(move || (res_rx.recv(), res_rx)).await?;
-        Ok((res??, args_tx, res_rx))
+        let (res, metric_updates) = res?;
+        metrics::apply_updates(metric_updates, &[("worker", &self.name)]);
+        Ok((res?, args_tx, res_rx))
     }
 
     fn spawn_process(
         &self,
-    ) -> Result<(IpcSender<T>, IpcReceiver<Result<R, CubeError>>, Child), CubeError> {
+    ) -> Result<(IpcSender<T>, IpcReceiver<WorkerResult<R>>, Child), CubeError> {
         let (args_tx, args_rx) = ipc::channel()?;
         let (res_tx, res_rx) = ipc::channel()?;
 
@@ -311,10 +315,13 @@ impl<
     }
 }
 
+/// Metric updates of the worker process are sent with each result, see [metrics::take_updates].
+type WorkerResult<R> = (Result<R, CubeError>, Vec<MetricUpdate>);
+
 #[derive(Serialize, Deserialize)]
 pub struct WorkerProcessArgs<T, R, P: ?Sized> {
     args: IpcReceiver<T>,
-    results: IpcSender<Result<R, CubeError>>,
+    results: IpcSender<WorkerResult<R>>,
     processor: PhantomData<P>,
 }
 
@@ -347,7 +354,7 @@ where
                             Ok(result) => result,
                             Err(panic) => Err(CubeError::from(panic)),
                         };
-                    let send_res = tx.send(result);
+                    let send_res = tx.send((result, metrics::take_updates()));
                     if let Err(e) = send_res {
                         error!("Worker message send error: {:?}", e);
                         return 0;
@@ -3,6 +3,7 @@ pub mod injection;
 pub mod processing_loop;
 
 use crate::cachestore::{CacheStore, ClusterCacheStoreClient, LazyRocksCacheStore};
+use crate::cluster::membership::ClusterMembership;
 use crate::cluster::transport::{
     ClusterTransport, ClusterTransportImpl, MetaStoreTransport, MetaStoreTransportImpl,
 };
@@ -12,8 +13,12 @@ use crate::config::processing_loop::ProcessingLoop;
 use crate::http::HttpServer;
 use crate::import::limits::ConcurrencyLimits;
 use crate::import::{ImportService, ImportServiceImpl};
+use crate::metastore::backup::{self, BackupManifest};
+use crate::metastore::check::{self, CheckIssue};
 use crate::metastore::{BaseRocksStoreFs, MetaStore, MetaStoreRpcClient, RocksMetaStore};
+use crate::mysql::auth::SqlAuthConfigImpl;
 use crate::mysql::{MySqlServer, SqlAuthDefaultImpl, SqlAuthService};
+use crate::queryplanner::batch_cache::PartitionBatchCache;
 use crate::queryplanner::query_executor::{QueryExecutor, QueryExecutorImpl};
 use crate::queryplanner::{QueryPlanner, QueryPlannerImpl};
 use crate::remotefs::gcs::GCSRemoteFs;
@@ -22,6 +27,8 @@ use crate::remotefs::queue::QueueRemoteFs;
 use crate::remotefs::s3::S3RemoteFs;
 use crate::remotefs::{LocalDirRemoteFs, RemoteFs};
 use crate::scheduler::SchedulerImpl;
+use crate::sql::admission::WorkloadClassConfig;
+use crate::sql::query_log::QueryLog;
 use crate::sql::{SqlService, SqlServiceImpl};
 use crate::store::compaction::{CompactionService, CompactionServiceImpl};
 use crate::store::{ChunkDataStore, ChunkStore, WALDataStore, WALStore};
@@ -124,6 +131,12 @@ impl CubeServices {
             let scheduler = self.scheduler.clone();
             futures.extend(SchedulerImpl::spawn_processing_loops(scheduler));
 
+            let cluster = self.cluster.clone();
+            futures.push(cube_ext::spawn(async move {
+                cluster.membership_loop().await;
+                Ok(())
+            }));
+
             if self.injector.has_service_typed::<MySqlServer>().await {
                 let mysql_server = self.injector.get_service_typed::<MySqlServer>().await;
                 futures.push(cube_ext::spawn(async move {
@@ -148,6 +161,12 @@ impl CubeServices {
             futures.push(cube_ext::spawn(async move {
                 cluster.warmup_select_worker().await;
                 Ok(())
+            }));
+
+            let cluster = self.cluster.clone();
+            futures.push(cube_ext::spawn(async move {
+                cluster.worker_heartbeat_loop().await;
+                Ok(())
             }))
         }
         futures.push(cube_ext::spawn(async move {
@@ -231,7 +250,7 @@ pub fn validate_config(c: &dyn ConfigObj) -> ValidationMessages {
         );
     }
     if !is_router(c) && !c.select_workers().contains(c.server_name()) {
-        warnings.push(format!("Current worker '{}' is missing in CUBESTORE_WORKERS. Please check CUBESTORE_SERVER_NAME and CUBESTORE_WORKERS variables", c.server_name()));
+        warnings.push(format!("Current worker '{}' is missing in CUBESTORE_WORKERS, it will register with the metastore and receive partitions once it's warmed up. Please check CUBESTORE_SERVER_NAME and CUBESTORE_WORKERS variables if this is not intended", c.server_name()));
     }
 
     let mut router_vars = vec![
@@ -290,6 +309,20 @@ pub enum FileStoreProvider {
     },
 }
 
+/// TLS with client certificates for the worker and metastore ports, see
+/// [ClusterTls](crate::cluster::tls::ClusterTls).
+#[derive(Debug, Clone)]
+pub struct ClusterTlsConfig {
+    /// Certificate chain of this node in PEM, used both as a server and as a client.
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA certificates in PEM, peers must present certificates signed by one of them.
+    pub ca_path: PathBuf,
+    /// Name to check in the server certificates instead of the host names of worker and
+    /// metastore addresses.
+    pub server_name: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct Config {
     config_obj: Arc<ConfigObjImpl>,
@@ -352,12 +385,42 @@ pub trait ConfigObj: DIService {
 
     fn select_workers(&self) -> &Vec<String>;
 
+    fn worker_heartbeat_interval(&self) -> u64;
+
+    fn worker_heartbeat_timeout(&self) -> u64;
+
+    fn select_worker_replicas(&self) -> usize;
+
+    fn select_replica_timeout(&self) -> u64;
+
+    fn join_broadcast_rows(&self) -> u64;
+
+    fn query_memory_limit(&self) -> usize;
+
+    fn worker_memory_limit(&self) -> usize;
+
+    fn workload_classes(&self) -> &Vec<WorkloadClassConfig>;
+
+    fn max_concurrent_queries(&self) -> usize;
+
+    fn query_log_max_entries(&self) -> usize;
+
+    fn query_log_file(&self) -> &Option<PathBuf>;
+
+    fn slow_query_threshold_ms(&self) -> u64;
+
     fn worker_bind_address(&self) -> &Option<String>;
 
     fn metastore_bind_address(&self) -> &Option<String>;
 
     fn metastore_remote_address(&self) -> &Option<String>;
 
+    fn cluster_tls(&self) -> &Option<ClusterTlsConfig>;
+
+    fn auth_users_file(&self) -> &Option<PathBuf>;
+
+    fn auth_jwks_file(&self) -> &Option<PathBuf>;
+
     fn download_concurrency(&self) -> u64;
 
     fn upload_concurrency(&self) -> u64;
@@ -380,10 +443,16 @@ pub trait ConfigObj: DIService {
 
     fn max_cached_queries(&self) -> usize;
 
+    fn persistent_result_cache_ttl_secs(&self) -> u32;
+
+    fn persistent_result_cache_max_entry_bytes(&self) -> usize;
+
     fn metadata_cache_max_capacity_bytes(&self) -> u64;
 
     fn metadata_cache_time_to_idle_secs(&self) -> u64;
 
+    fn batch_cache_max_capacity_bytes(&self) -> u64;
+
     fn stream_replay_check_interval_secs(&self) -> u64;
 
     fn check_ws_orphaned_messages_interval_secs(&self) -> u64;
@@ -434,9 +503,48 @@ pub struct ConfigObjImpl {
     pub gc_loop_interval: u64,
     pub stale_stream_timeout: u64,
     pub select_workers: Vec<String>,
+    /// Workers missing in `select_workers` register with the metastore and send heartbeats
+    /// with this interval.
+    pub worker_heartbeat_interval: u64,
+    /// Partitions are not routed to registered workers that didn't send heartbeats for this long.
+    pub worker_heartbeat_timeout: u64,
+    /// Number of workers each partition is warmed up on. Queries fall back to the other replicas
+    /// when a worker can't be reached.
+    pub select_worker_replicas: usize,
+    /// Seconds a replica has to answer a select before the next replica is tried. The last
+    /// replica is only limited by `query_timeout`.
+    pub select_replica_timeout: u64,
+    /// Joins without an index sorted by the join key send the smaller side to all workers when
+    /// it has at most this many rows. Larger sides are split by the hash of the join key.
+    pub join_broadcast_rows: u64,
+    /// Bytes a single query can use on a select worker before sorts and aggregations spill to
+    /// disk. Zero means no limit.
+    pub query_memory_limit: usize,
+    /// Bytes all queries running on a select worker can use together. Zero means no limit.
+    pub worker_memory_limit: usize,
+    /// Data queries on the router wait in the queue of their workload class, selected with
+    /// `SET workload_class` or the `workloadClass` field of the trace object. Queries without a
+    /// class use `default`, which has no limits unless listed here.
+    pub workload_classes: Vec<WorkloadClassConfig>,
+    /// Data queries the router runs at once across all workload classes. Zero means no limit.
+    pub max_concurrent_queries: usize,
+    /// Number of recent queries kept in memory for `system.queries`.
+    pub query_log_max_entries: usize,
+    /// Queries are also appended to this file as JSON lines when set.
+    pub query_log_file: Option<PathBuf>,
+    /// Data queries running longer are logged with their physical plans. Zero disables the log.
+    pub slow_query_threshold_ms: u64,
     pub worker_bind_address: Option<String>,
     pub metastore_bind_address: Option<String>,
     pub metastore_remote_address: Option<String>,
+    /// Plain TCP is used between the nodes when not set.
+    pub cluster_tls: Option<ClusterTlsConfig>,
+    /// JSON with passwords and roles of MySQL and HTTP users, see
+    /// [SqlAuthConfigImpl](crate::mysql::auth::SqlAuthConfigImpl). Any user is accepted when
+    /// neither this nor `auth_jwks_file` is set.
+    pub auth_users_file: Option<PathBuf>,
+    /// Keys to verify bearer tokens of HTTP clients.
+    pub auth_jwks_file: Option<PathBuf>,
     pub upload_concurrency: u64,
     pub download_concurrency: u64,
     pub connection_timeout: u64,
@@ -447,8 +555,14 @@ pub struct ConfigObjImpl {
     pub enable_startup_warmup: bool,
     pub malloc_trim_every_secs: u64,
     pub max_cached_queries: usize,
+    /// Zero disables the persistent result cache.
+    pub persistent_result_cache_ttl_secs: u32,
+    pub persistent_result_cache_max_entry_bytes: usize,
     pub metadata_cache_max_capacity_bytes: u64,
     pub metadata_cache_time_to_idle_secs: u64,
+    /// Decoded partition files kept in memory on the node, zero disables it. Select worker
+    /// processes have separate caches and split the budget equally.
+    pub batch_cache_max_capacity_bytes: u64,
     pub stream_replay_check_interval_secs: u64,
     pub check_ws_orphaned_messages_interval_secs: u64,
     pub drop_ws_processing_messages_after_secs: u64,
@@ -570,6 +684,54 @@ impl ConfigObj for ConfigObjImpl {
         &self.select_workers
     }
 
+    fn worker_heartbeat_interval(&self) -> u64 {
+        self.worker_heartbeat_interval
+    }
+
+    fn worker_heartbeat_timeout(&self) -> u64 {
+        self.worker_heartbeat_timeout
+    }
+
+    fn select_worker_replicas(&self) -> usize {
+        self.select_worker_replicas
+    }
+
+    fn select_replica_timeout(&self) -> u64 {
+        self.select_replica_timeout
+    }
+
+    fn join_broadcast_rows(&self) -> u64 {
+        self.join_broadcast_rows
+    }
+
+    fn query_memory_limit(&self) -> usize {
+        self.query_memory_limit
+    }
+
+    fn worker_memory_limit(&self) -> usize {
+        self.worker_memory_limit
+    }
+
+    fn workload_classes(&self) -> &Vec<WorkloadClassConfig> {
+        &self.workload_classes
+    }
+
+    fn max_concurrent_queries(&self) -> usize {
+        self.max_concurrent_queries
+    }
+
+    fn query_log_max_entries(&self) -> usize {
+        self.query_log_max_entries
+    }
+
+    fn query_log_file(&self) -> &Option<PathBuf> {
+        &self.query_log_file
+    }
+
+    fn slow_query_threshold_ms(&self) -> u64 {
+        self.slow_query_threshold_ms
+    }
+
     fn worker_bind_address(&self) -> &Option<String> {
         &self.worker_bind_address
     }
@@ -582,6 +744,18 @@ impl ConfigObj for ConfigObjImpl {
         &self.metastore_remote_address
     }
 
+    fn cluster_tls(&self) -> &Option<ClusterTlsConfig> {
+        &self.cluster_tls
+    }
+
+    fn auth_users_file(&self) -> &Option<PathBuf> {
+        &self.auth_users_file
+    }
+
+    fn auth_jwks_file(&self) -> &Option<PathBuf> {
+        &self.auth_jwks_file
+    }
+
     fn download_concurrency(&self) -> u64 {
         self.download_concurrency
     }
@@ -623,12 +797,21 @@ impl ConfigObj for ConfigObjImpl {
     fn max_cached_queries(&self) -> usize {
         self.max_cached_queries
     }
+    fn persistent_result_cache_ttl_secs(&self) -> u32 {
+        self.persistent_result_cache_ttl_secs
+    }
+    fn persistent_result_cache_max_entry_bytes(&self) -> usize {
+        self.persistent_result_cache_max_entry_bytes
+    }
     fn metadata_cache_max_capacity_bytes(&self) -> u64 {
         self.metadata_cache_max_capacity_bytes
     }
     fn metadata_cache_time_to_idle_secs(&self) -> u64 {
         self.metadata_cache_time_to_idle_secs
     }
+    fn batch_cache_max_capacity_bytes(&self) -> u64 {
+        self.batch_cache_max_capacity_bytes
+    }
     fn stream_replay_check_interval_secs(&self) -> u64 {
         self.stream_replay_check_interval_secs
     }
@@ -818,6 +1001,29 @@ impl Config {
                     .ok()
                     .map(|v| v.split(",").map(|s| s.to_string()).collect())
                     .unwrap_or(Vec::new()),
+                worker_heartbeat_interval: env_parse("CUBESTORE_WORKER_HEARTBEAT_INTERVAL", 10),
+                worker_heartbeat_timeout: env_parse("CUBESTORE_WORKER_HEARTBEAT_TIMEOUT", 60),
+                select_worker_replicas: env_parse("CUBESTORE_SELECT_WORKER_REPLICAS", 1),
+                select_replica_timeout: env_parse("CUBESTORE_SELECT_REPLICA_TIMEOUT", 30),
+                join_broadcast_rows: env_parse("CUBESTORE_JOIN_BROADCAST_ROWS", 1_000_000),
+                query_memory_limit: env_parse("CUBESTORE_QUERY_MEMORY_LIMIT", 0),
+                worker_memory_limit: env_parse("CUBESTORE_WORKER_MEMORY_LIMIT", 0),
+                workload_classes: env::var("CUBESTORE_WORKLOAD_CLASSES")
+                    .ok()
+                    .map(|v| {
+                        v.split(",")
+                            .filter(|s| !s.trim().is_empty())
+                            .map(|s| match s.parse() {
+                                Ok(c) => c,
+                                Err(e) => panic!("Invalid CUBESTORE_WORKLOAD_CLASSES: {}", e),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or(Vec::new()),
+                max_concurrent_queries: env_parse("CUBESTORE_MAX_CONCURRENT_QUERIES", 0),
+                query_log_max_entries: env_parse("CUBESTORE_QUERY_LOG_MAX_ENTRIES", 1000),
+                query_log_file: env::var("CUBESTORE_QUERY_LOG_FILE").ok().map(PathBuf::from),
+                slow_query_threshold_ms: env_parse("CUBESTORE_SLOW_QUERY_THRESHOLD_MS", 200),
                 worker_bind_address: env::var("CUBESTORE_WORKER_BIND_ADDR").ok().or_else(|| {
                     env_optparse::<u16>("CUBESTORE_WORKER_PORT").map(|v| format!("0.0.0.0:{}", v))
                 }),
@@ -825,6 +1031,22 @@ impl Config {
                     env_optparse::<u16>("CUBESTORE_META_PORT").map(|v| format!("0.0.0.0:{}", v))
                 }),
                 metastore_remote_address: env::var("CUBESTORE_META_ADDR").ok(),
+                cluster_tls: env::var("CUBESTORE_CLUSTER_TLS_CERT").ok().map(|cert_path| {
+                    ClusterTlsConfig {
+                        cert_path: PathBuf::from(cert_path),
+                        key_path: PathBuf::from(env::var("CUBESTORE_CLUSTER_TLS_KEY").expect(
+                            "CUBESTORE_CLUSTER_TLS_KEY required when CUBESTORE_CLUSTER_TLS_CERT is set",
+                        )),
+                        ca_path: PathBuf::from(env::var("CUBESTORE_CLUSTER_TLS_CA").expect(
+                            "CUBESTORE_CLUSTER_TLS_CA required when CUBESTORE_CLUSTER_TLS_CERT is set",
+                        )),
+                        server_name: env::var("CUBESTORE_CLUSTER_TLS_SERVER_NAME").ok(),
+                    }
+                }),
+                auth_users_file: env::var("CUBESTORE_AUTH_USERS_FILE")
+                    .ok()
+                    .map(PathBuf::from),
+                auth_jwks_file: env::var("CUBESTORE_AUTH_JWKS_FILE").ok().map(PathBuf::from),
                 upload_concurrency: env_parse("CUBESTORE_MAX_ACTIVE_UPLOADS", 4),
                 download_concurrency: env_parse("CUBESTORE_MAX_ACTIVE_DOWNLOADS", 8),
                 max_ingestion_data_frames: env_parse("CUBESTORE_MAX_DATA_FRAMES", 4),
@@ -840,6 +1062,14 @@ impl Config {
                 enable_startup_warmup: env_bool("CUBESTORE_STARTUP_WARMUP", true),
                 malloc_trim_every_secs: env_parse("CUBESTORE_MALLOC_TRIM_EVERY_SECS", 30),
                 max_cached_queries: env_parse("CUBESTORE_MAX_CACHED_QUERIES", 10_000),
+                persistent_result_cache_ttl_secs: env_parse(
+                    "CUBESTORE_PERSISTENT_RESULT_CACHE_TTL",
+                    0,
+                ),
+                persistent_result_cache_max_entry_bytes: env_parse(
+                    "CUBESTORE_PERSISTENT_RESULT_CACHE_MAX_ENTRY_SIZE",
+                    1024 * 1024,
+                ),
                 metadata_cache_max_capacity_bytes: env_parse(
                     "CUBESTORE_METADATA_CACHE_MAX_CAPACITY_BYTES",
                     0,
@@ -848,6 +1078,10 @@ impl Config {
                     "CUBESTORE_METADATA_CACHE_TIME_TO_IDLE_SECS",
                     0,
                 ),
+                batch_cache_max_capacity_bytes: env_parse(
+                    "CUBESTORE_BATCH_CACHE_MAX_CAPACITY_BYTES",
+                    0,
+                ),
                 stream_replay_check_interval_secs: env_parse(
                     "CUBESTORE_STREAM_REPLAY_CHECK_INTERVAL",
                     0,
@@ -916,9 +1150,24 @@ impl Config {
                 import_job_timeout: 600,
                 stale_stream_timeout: 60,
                 select_workers: Vec::new(),
+                worker_heartbeat_interval: 1,
+                worker_heartbeat_timeout: 10,
+                select_worker_replicas: 1,
+                select_replica_timeout: 30,
+                join_broadcast_rows: 1_000_000,
+                query_memory_limit: 0,
+                worker_memory_limit: 0,
+                workload_classes: Vec::new(),
+                max_concurrent_queries: 0,
+                query_log_max_entries: 1000,
+                query_log_file: None,
+                slow_query_threshold_ms: 200,
                 worker_bind_address: None,
                 metastore_bind_address: None,
                 metastore_remote_address: None,
+                cluster_tls: None,
+                auth_users_file: None,
+                auth_jwks_file: None,
                 upload_concurrency: 4,
                 download_concurrency: 8,
                 max_ingestion_data_frames: 4,
@@ -930,8 +1179,11 @@ impl Config {
                 enable_startup_warmup: true,
                 malloc_trim_every_secs: 0,
                 max_cached_queries: 10_000,
+                persistent_result_cache_ttl_secs: 0,
+                persistent_result_cache_max_entry_bytes: 1024 * 1024,
                 metadata_cache_max_capacity_bytes: 0,
                 metadata_cache_time_to_idle_secs: 1_000,
+                batch_cache_max_capacity_bytes: 0,
                 meta_store_log_upload_interval: 30,
                 meta_store_snapshot_interval: 300,
                 gc_loop_interval: 60,
@@ -1159,6 +1411,41 @@ impl Config {
         Ok(self.injector.get_service("original_remote_fs").await)
     }
 
+    /// Copies the backup created by `SYS BACKUP TO '<prefix>'` in the remote storage into place,
+    /// so the router loads it on start.
+    pub async fn restore_backup(&self, prefix: &str) -> Result<BackupManifest, CubeError> {
+        backup::restore_backup(
+            self.remote_fs().await?,
+            self.config_obj(),
+            &self.meta_store_path(),
+            prefix,
+        )
+        .await
+    }
+
+    /// Runs the check of `SYS CHECK` on the metastore of the router without starting it.
+    /// Repairs are uploaded as a new metastore snapshot.
+    pub async fn check_consistency(&self, repair: bool) -> Result<Vec<CheckIssue>, CubeError> {
+        self.configure_injector().await;
+        if uses_remote_metastore(&self.injector).await {
+            return Err(CubeError::user(
+                "Consistency check should run on the router".to_string(),
+            ));
+        }
+        let meta_store = self.injector.get_service_typed::<RocksMetaStore>().await;
+        let issues = check::check_consistency(
+            meta_store.as_ref(),
+            self.remote_fs().await?.as_ref(),
+            chrono::Duration::seconds(self.config_obj.import_job_timeout() as i64),
+            repair,
+        )
+        .await?;
+        if repair {
+            meta_store.upload_check_point().await?;
+        }
+        Ok(issues)
+    }
+
     pub fn injector(&self) -> Arc<Injector> {
         self.injector.clone()
     }
@@ -1392,19 +1679,47 @@ impl Config {
             })
             .await;
 
+        self.injector
+            .register_typed::<ClusterMembership, _, _, _>(async move |i| {
+                ClusterMembership::new(i.get_service_typed().await, i.get_service_typed().await)
+            })
+            .await;
+
+        self.injector
+            .register_typed::<QueryLog, _, _, _>(async move |i| {
+                QueryLog::new(i.get_service_typed().await)
+            })
+            .await;
+
         self.injector
             .register_typed::<dyn QueryPlanner, _, _, _>(async move |i| {
                 QueryPlannerImpl::new(
                     i.get_service_typed().await,
                     i.get_service_typed().await,
                     i.get_service_typed().await,
+                    i.get_service_typed().await,
+                    i.get_service_typed().await,
                 )
             })
             .await;
 
+        self.injector
+            .register_typed::<PartitionBatchCache, _, _, _>(async move |i| {
+                let c = i.get_service_typed::<dyn ConfigObj>().await;
+                // Every select worker process creates its own cache.
+                let max_bytes =
+                    c.batch_cache_max_capacity_bytes() / c.select_worker_pool_size().max(1) as u64;
+                PartitionBatchCache::new(max_bytes as usize, 4096)
+            })
+            .await;
+
         self.injector
             .register_typed_with_default::<dyn QueryExecutor, _, _, _>(async move |i| {
-                QueryExecutorImpl::new(i.get_service_typed().await)
+                QueryExecutorImpl::new(
+                    i.get_service_typed().await,
+                    i.get_service_typed().await,
+                    i.get_service_typed().await,
+                )
             })
             .await;
 
@@ -1433,6 +1748,7 @@ impl Config {
                     cluster_meta_store_sender,
                     i.get_service_typed().await,
                     i.get_service_typed().await,
+                    i.get_service_typed().await,
                 )
             })
             .await;
@@ -1455,6 +1771,7 @@ impl Config {
                     Duration::from_secs(c.query_timeout()),
                     Duration::from_secs(c.import_job_timeout() * 2),
                     c.max_cached_queries(),
+                    i.get_service_typed().await,
                 )
             })
             .await;
@@ -1473,11 +1790,25 @@ impl Config {
             .await;
 
         if self.config_obj.bind_address().is_some() {
-            self.injector
-                .register_typed::<dyn SqlAuthService, _, _, _>(async move |_| {
-                    Arc::new(SqlAuthDefaultImpl)
-                })
-                .await;
+            if self.config_obj.auth_users_file().is_some()
+                || self.config_obj.auth_jwks_file().is_some()
+            {
+                self.injector
+                    .register_typed::<dyn SqlAuthService, _, _, _>(async move |i| {
+                        let config = i.get_service_typed::<dyn ConfigObj>().await;
+                        Arc::new(
+                            SqlAuthConfigImpl::from_config(config.as_ref())
+                                .unwrap_or_else(|e| panic!("Invalid auth config: {}", e)),
+                        )
+                    })
+                    .await;
+            } else {
+                self.injector
+                    .register_typed::<dyn SqlAuthService, _, _, _>(async move |_| {
+                        Arc::new(SqlAuthDefaultImpl)
+                    })
+                    .await;
+            }
 
             self.injector
                 .register_typed::<MySqlServer, _, _, _>(async move |i| {
@@ -11,6 +11,7 @@ use crate::codegen::http_message_generated::{
 };
 use crate::metastore::{Column, ColumnType, ImportFormat};
 use crate::mysql::SqlAuthService;
+use crate::sql::permissions::Role;
 use crate::sql::{InlineTable, InlineTables, SqlQueryContext, SqlService};
 use crate::store::DataFrame;
 use crate::table::{Row, TableValue};
@@ -108,10 +109,13 @@ impl HttpServer {
                 async move {
                     let res = HttpServer::authorize(auth_service, auth_header).await;
                     match res {
-                        Ok(user) => Ok(SqlQueryContext {
+                        Ok((user, role)) => Ok(SqlQueryContext {
                             user,
+                            role,
                             inline_tables: InlineTables::new(),
                             trace_obj: None,
+                            workload_class: None,
+                            session: None,
                         }),
                         Err(_) => Err(warp::reject::custom(CubeRejection::NotAuthorized)),
                     }
@@ -523,25 +527,23 @@ impl HttpServer {
     pub async fn authorize(
         auth: Arc<dyn SqlAuthService>,
         auth_header: Option<String>,
-    ) -> Result<Option<String>, CubeError> {
+    ) -> Result<(Option<String>, Option<Role>), CubeError> {
+        if let Some(token) = auth_header.as_ref().and_then(|h| h.strip_prefix("Bearer ")) {
+            let (user, role) = auth.authenticate_token(token.trim().to_string()).await?;
+            return Ok((Some(user), Some(role)));
+        }
         let credentials = auth_header
             .map(|auth_header| Credentials::from_header(auth_header))
             .transpose()
             .map_err(|e| CubeError::from_error(e))?;
-        if let Some(password) = auth
-            .authenticate(credentials.as_ref().map(|c| c.user_id.to_string()))
-            .await?
-        {
-            if Some(password) != credentials.as_ref().map(|c| c.password.to_string()) {
-                Err(CubeError::user(
-                    "User or password doesn't match".to_string(),
-                ))
-            } else {
-                Ok(credentials.as_ref().map(|c| c.user_id.to_string()))
-            }
-        } else {
-            Ok(credentials.as_ref().map(|c| c.user_id.to_string()))
-        }
+        let user = credentials.as_ref().map(|c| c.user_id.to_string());
+        auth.check_password(
+            user.clone(),
+            credentials.as_ref().map(|c| c.password.to_string()),
+        )
+        .await?;
+        let role = auth.role(user.clone()).await?;
+        Ok((user, role))
     }
 
     pub async fn stop_processing(&self) {
@@ -1028,7 +1030,8 @@ mod tests {
             message_counter: AtomicU64::new(0),
         };
         let mut auth = MockSqlAuthService::new();
-        auth.expect_authenticate().return_const(Ok(None));
+        auth.expect_check_password().return_const(Ok(()));
+        auth.expect_role().return_const(Ok(None));
         let http_server = Arc::new(HttpServer::new(
             "127.0.0.1:53031".to_string(),
             Arc::new(auth),
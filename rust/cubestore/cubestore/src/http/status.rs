@@ -1,37 +1,67 @@
+use crate::app_metrics;
+use crate::cachestore::{CacheStore, QueueItemStatus};
 use crate::config::injection::Injector;
 use crate::config::{is_router, uses_remote_metastore, Config};
-use crate::metastore::MetaStore;
+use crate::metastore::job::{JobStatus, JobType};
+use crate::metastore::{MetaStore, MetaStoreTable};
 use crate::sql::SqlService;
+use crate::util::metrics;
 use crate::CubeError;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
 use warp::http::StatusCode;
 use warp::Filter;
 
+/// Serves `/metrics` on every node. Liveness and readiness probes are served only by the router.
 pub fn serve_status_probes(c: &Config) {
     let addr = match c.config_obj().status_bind_address() {
         Some(a) => a.clone(),
         None => return,
     };
 
-    let p = match RouterProbes::try_new(c) {
-        Some(p) => p,
-        None => return,
-    };
+    let p = RouterProbes::try_new(c);
 
     let pc = p.clone();
     let l = warp::path!("livez").and_then(move || {
         let pc = pc.clone();
-        async move { status_probe_reply("liveness", pc.is_live().await) }
+        async move {
+            match pc {
+                Some(pc) => status_probe_reply("liveness", pc.is_live().await),
+                None => Ok(StatusCode::NOT_FOUND),
+            }
+        }
     });
     let r = warp::path!("readyz").and_then(move || {
         let p = p.clone();
-        async move { status_probe_reply("readiness", p.is_ready().await) }
+        async move {
+            match p {
+                Some(p) => status_probe_reply("readiness", p.is_ready().await),
+                None => Ok(StatusCode::NOT_FOUND),
+            }
+        }
+    });
+    let services = c.injector();
+    let router = is_router(c.config_obj().as_ref());
+    let last_refresh = Arc::new(Mutex::new(None));
+    let m = warp::path!("metrics").and_then(move || {
+        let services = services.clone();
+        let last_refresh = last_refresh.clone();
+        async move {
+            refresh_node_metrics(&services, router, &last_refresh).await;
+            Ok::<_, Infallible>(warp::reply::with_header(
+                metrics::prometheus_text(),
+                "content-type",
+                metrics::PROMETHEUS_CONTENT_TYPE,
+            ))
+        }
     });
 
     let addr: SocketAddr = addr.parse().expect("cannot parse status probe address");
-    match warp::serve(l.or(r)).try_bind_ephemeral(addr) {
+    match warp::serve(l.or(r).or(m)).try_bind_ephemeral(addr) {
         Ok((addr, f)) => {
             log::info!("Serving status probes at {}", addr);
             tokio::spawn(f);
@@ -94,3 +124,125 @@ impl RouterProbes {
         Ok(())
     }
 }
+
+/// Node metrics scan whole metastore tables, so they are not collected more often than this.
+/// Scrapes in between return the last reported values.
+const NODE_METRICS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+async fn refresh_node_metrics(
+    services: &Injector,
+    router: bool,
+    last_refresh: &Mutex<Option<Instant>>,
+) {
+    // Holding the lock makes concurrent scrapes wait for a single refresh.
+    let mut last_refresh = last_refresh.lock().await;
+    if let Some(t) = *last_refresh {
+        if t.elapsed() < NODE_METRICS_MIN_REFRESH_INTERVAL {
+            return;
+        }
+    }
+    // Failures are not retried before the interval passes either.
+    *last_refresh = Some(Instant::now());
+    if let Err(e) = report_node_metrics(services, router).await {
+        log::warn!(
+            "Failed to collect node metrics: {}",
+            e.display_with_backtrace()
+        );
+    }
+}
+
+/// Updates gauges that describe the state of the node before metrics are scraped.
+async fn report_node_metrics(services: &Injector, router: bool) -> Result<(), CubeError> {
+    if !router {
+        return Ok(());
+    }
+    if !uses_remote_metastore(services).await {
+        if let Some(m) = services.try_get_service_typed::<dyn MetaStore>().await {
+            report_metastore_metrics(m.as_ref()).await?;
+        }
+    }
+    if let Some(c) = services.try_get_service_typed::<dyn CacheStore>().await {
+        let items = c.queue_all().await?;
+        for (status, name) in [
+            (QueueItemStatus::Pending, "pending"),
+            (QueueItemStatus::Active, "active"),
+            (QueueItemStatus::Finished, "finished"),
+        ] {
+            let count = items
+                .iter()
+                .filter(|i| i.get_row().get_status() == &status)
+                .count();
+            app_metrics::QUEUE_ITEMS.report_with_tags(count as i64, &[("status", name)]);
+        }
+    }
+    Ok(())
+}
+
+async fn report_metastore_metrics(m: &dyn MetaStore) -> Result<(), CubeError> {
+    app_metrics::METASTORE_TABLES.report(m.get_tables().await?.len() as i64);
+    let partitions = m.partition_table().all_rows().await?;
+    app_metrics::METASTORE_ACTIVE_PARTITIONS.report(
+        partitions
+            .iter()
+            .filter(|p| p.get_row().is_active())
+            .count() as i64,
+    );
+    let chunks = m.chunks_table().all_rows().await?;
+    for in_memory in [false, true] {
+        let count = chunks
+            .iter()
+            .filter(|c| c.get_row().active() && c.get_row().in_memory() == in_memory)
+            .count();
+        app_metrics::METASTORE_ACTIVE_CHUNKS
+            .report_with_tags(count as i64, &[("in_memory", &in_memory.to_string())]);
+    }
+
+    let mut jobs = HashMap::new();
+    for j in m.all_jobs().await? {
+        let status = match j.get_row().status() {
+            JobStatus::Scheduled(_) => "scheduled",
+            JobStatus::ProcessingBy(_) => "processing",
+            JobStatus::Completed => continue,
+            JobStatus::Timeout | JobStatus::Error(_) => "failed",
+        };
+        *jobs
+            .entry((job_type_name(j.get_row().job_type()), status))
+            .or_insert(0) += 1;
+    }
+    // Report zeros too, otherwise the last non-zero value would stay forever.
+    for job_type in JOB_TYPE_NAMES {
+        for status in ["scheduled", "processing", "failed"] {
+            let count = jobs.get(&(job_type, status)).cloned().unwrap_or(0);
+            app_metrics::JOBS.report_with_tags(count, &[("type", job_type), ("status", status)]);
+        }
+    }
+    Ok(())
+}
+
+const JOB_TYPE_NAMES: [&str; 10] = [
+    "wal_partitioning",
+    "partition_compaction",
+    "table_import",
+    "repartition",
+    "table_import_csv",
+    "multi_partition_split",
+    "finish_multi_split",
+    "repartition_chunk",
+    "in_memory_chunks_compaction",
+    "in_memory_chunks_flush",
+];
+
+fn job_type_name(t: &JobType) -> &'static str {
+    match t {
+        JobType::WalPartitioning => "wal_partitioning",
+        JobType::PartitionCompaction => "partition_compaction",
+        JobType::TableImport => "table_import",
+        JobType::Repartition => "repartition",
+        JobType::TableImportCSV(_) => "table_import_csv",
+        JobType::MultiPartitionSplit => "multi_partition_split",
+        JobType::FinishMultiSplit => "finish_multi_split",
+        JobType::RepartitionChunk => "repartition_chunk",
+        JobType::InMemoryChunksCompaction => "in_memory_chunks_compaction",
+        JobType::InMemoryChunksFlush => "in_memory_chunks_flush",
+    }
+}
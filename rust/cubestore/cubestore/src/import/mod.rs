@@ -23,6 +23,7 @@ use tokio::fs::File;
 use tokio::io::{AsyncBufRead, AsyncSeekExt, AsyncWriteExt, BufReader};
 use tokio::task::JoinHandle;
 
+use cubedatasketches::ThetaSketch;
 use cubehll::HllSketch;
 
 use crate::config::injection::DIService;
@@ -183,6 +184,11 @@ impl ImportFormat {
                 is_valid_plain_binary_hll(&data, *f)?;
                 TableValue::Bytes(data)
             }
+            ColumnType::ThetaSketch => {
+                let data = parse_binary_data(value)?;
+                ThetaSketch::read(&data)?;
+                TableValue::Bytes(data)
+            }
             ColumnType::Timestamp => TableValue::Timestamp(timestamp_from_string(value)?),
             ColumnType::Float => TableValue::Float(OrdF64(value.parse::<f64>()?)),
             ColumnType::Boolean => TableValue::Boolean(value.to_lowercase() == "true"),
@@ -19,6 +19,7 @@ extern crate core;
 use crate::metastore::TableId;
 use crate::remotefs::queue::RemoteFsOpResult;
 use arrow::error::ArrowError;
+use cubedatasketches::ThetaError;
 use cubehll::HllError;
 use cubezetasketch::ZetaError;
 use datafusion::cube_ext::catch_unwind::PanicError;
@@ -432,6 +433,12 @@ impl From<ZetaError> for CubeError {
     }
 }
 
+impl From<ThetaError> for CubeError {
+    fn from(v: ThetaError) -> Self {
+        return CubeError::from_error(v);
+    }
+}
+
 impl From<cloud_storage::Error> for CubeError {
     fn from(v: cloud_storage::Error) -> Self {
         return CubeError::from_error(v);
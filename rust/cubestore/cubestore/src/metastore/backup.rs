@@ -0,0 +1,246 @@
+//! Offline backups created by `SYS BACKUP TO '<prefix>'` and restored by `cubestored restore`.
+//!
+//! A backup is a copy of a metastore checkpoint in `<prefix>/metastore-<millis>/` plus every
+//! partition and chunk file it references, stored under the same names as in the remote root.
+//! `<prefix>/backup.json` lists all copied files with their sizes and is written last, so a
+//! backup without it is incomplete.
+use crate::config::ConfigObj;
+use crate::metastore::rocks_fs::{BaseRocksStoreFs, MetaStoreFs};
+use crate::metastore::MetaStore;
+use crate::remotefs::RemoteFs;
+use crate::CubeError;
+use futures::{StreamExt, TryStreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs;
+
+pub const BACKUP_MANIFEST: &str = "backup.json";
+
+/// Each copy downloads the whole file to the local dir before uploading it, so only a few of them
+/// run at once.
+const COPY_CONCURRENCY: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupManifest {
+    /// Name of the metastore snapshot, e.g. `metastore-1670000000000`.
+    pub snapshot: String,
+    /// Paths are relative to the backup prefix.
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupFile {
+    pub path: String,
+    pub size: u64,
+}
+
+pub async fn create_backup(
+    meta_store: &dyn MetaStore,
+    remote_fs: Arc<dyn RemoteFs>,
+    prefix: &str,
+) -> Result<BackupManifest, CubeError> {
+    let prefix = normalize_prefix(prefix)?;
+    if !remote_fs
+        .list(&format!("{}/{}", prefix, BACKUP_MANIFEST))
+        .await?
+        .is_empty()
+    {
+        return Err(CubeError::user(format!(
+            "Backup already exists in '{}'",
+            prefix
+        )));
+    }
+
+    // Checkpoint files end up in their temp upload paths.
+    let checkpoint_dir = remote_fs.temp_upload_path(&prefix).await?;
+    fs::create_dir_all(&checkpoint_dir).await?;
+    let (snapshot, data_files) = meta_store.backup_checkpoint(checkpoint_dir.clone()).await?;
+    info!(
+        "Backing up {} with {} data files to '{}'",
+        snapshot,
+        data_files.len(),
+        prefix
+    );
+
+    let mut files = Vec::new();
+    let checkpoint_path = Path::new(&checkpoint_dir).join(&snapshot);
+    let mut dir = fs::read_dir(&checkpoint_path).await?;
+    while let Some(file) = dir.next_entry().await? {
+        let path = format!("{}/{}", snapshot, file.file_name().to_string_lossy());
+        let remote_path = format!("{}/{}", prefix, path);
+        let size = remote_fs
+            .upload_file(&file.path().to_string_lossy(), &remote_path)
+            .await?;
+        files.push(BackupFile { path, size });
+    }
+    fs::remove_dir_all(&checkpoint_path).await?;
+
+    let copied = futures::stream::iter(data_files.into_iter().map(|(path, size)| {
+        let remote_fs = remote_fs.clone();
+        let to = format!("{}/{}", prefix, path);
+        async move {
+            let size = copy_file(remote_fs.as_ref(), &path, &to, size).await?;
+            Ok::<_, CubeError>(BackupFile { path, size })
+        }
+    }))
+    .buffer_unordered(COPY_CONCURRENCY)
+    .try_collect::<Vec<_>>()
+    .await?;
+    files.extend(copied);
+
+    let manifest = BackupManifest { snapshot, files };
+    let manifest_path = format!("{}/{}", prefix, BACKUP_MANIFEST);
+    let temp_path = remote_fs.temp_upload_path(&manifest_path).await?;
+    fs::write(&temp_path, serde_json::to_vec_pretty(&manifest)?).await?;
+    remote_fs.upload_file(&temp_path, &manifest_path).await?;
+
+    info!("Backup to '{}' completed", prefix);
+    Ok(manifest)
+}
+
+/// Copies the backup in `prefix` to the remote root and makes its snapshot current, so the next
+/// start of the router loads it. Refuses to overwrite an existing metastore.
+pub async fn restore_backup(
+    remote_fs: Arc<dyn RemoteFs>,
+    config: Arc<dyn ConfigObj>,
+    meta_store_path: &Path,
+    prefix: &str,
+) -> Result<BackupManifest, CubeError> {
+    let prefix = normalize_prefix(prefix)?;
+    let metastore_fs = BaseRocksStoreFs::new(remote_fs.clone(), "metastore", config);
+    if metastore_fs.is_remote_metadata_exists().await?
+        || fs::metadata(meta_store_path).await.is_ok()
+    {
+        return Err(CubeError::user(format!(
+            "Can't restore from '{}': metastore already exists. Restore requires empty remote and local storage.",
+            prefix
+        )));
+    }
+
+    let (manifest_path, downloaded) = download_file(
+        remote_fs.as_ref(),
+        &format!("{}/{}", prefix, BACKUP_MANIFEST),
+        None,
+    )
+    .await?;
+    let manifest: BackupManifest = serde_json::from_slice(&fs::read(&manifest_path).await?)?;
+    if downloaded {
+        fs::remove_file(&manifest_path).await?;
+    }
+
+    // Validate the whole backup before writing anything.
+    let remote_sizes = remote_fs
+        .list_with_metadata(&format!("{}/", prefix))
+        .await?
+        .into_iter()
+        .map(|f| (f.remote_path, f.file_size))
+        .collect::<HashMap<_, _>>();
+    for f in manifest.files.iter() {
+        match remote_sizes.get(&format!("{}/{}", prefix, f.path)) {
+            Some(size) if *size == f.size => {}
+            Some(size) => {
+                return Err(CubeError::user(format!(
+                    "Backup file {} has size {} but {} is expected",
+                    f.path, size, f.size
+                )))
+            }
+            None => {
+                return Err(CubeError::user(format!(
+                    "Backup file {} is missing",
+                    f.path
+                )))
+            }
+        }
+    }
+
+    info!(
+        "Restoring {} with {} files from '{}'",
+        manifest.snapshot,
+        manifest.files.len(),
+        prefix
+    );
+    futures::stream::iter(manifest.files.iter().map(|f| {
+        let remote_fs = remote_fs.clone();
+        let from = format!("{}/{}", prefix, f.path);
+        async move { copy_file(remote_fs.as_ref(), &from, &f.path, Some(f.size)).await }
+    }))
+    .buffer_unordered(COPY_CONCURRENCY)
+    .try_collect::<Vec<_>>()
+    .await?;
+
+    metastore_fs
+        .write_metastore_current(&manifest.snapshot)
+        .await?;
+    info!("Restore from '{}' completed", prefix);
+    Ok(manifest)
+}
+
+fn normalize_prefix(prefix: &str) -> Result<String, CubeError> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() || prefix.split('/').any(|p| p == "." || p == "..") {
+        return Err(CubeError::user(format!(
+            "Invalid backup prefix '{}'",
+            prefix
+        )));
+    }
+    Ok(prefix.to_string())
+}
+
+/// Also tells whether the file wasn't in the local dir before. Local dir might be the remote
+/// storage itself, so we must only remove files we've downloaded.
+async fn download_file(
+    remote_fs: &dyn RemoteFs,
+    remote_path: &str,
+    expected_size: Option<u64>,
+) -> Result<(String, bool), CubeError> {
+    let had_local_copy = fs::metadata(remote_fs.local_file(remote_path).await?)
+        .await
+        .is_ok();
+    let local = remote_fs.download_file(remote_path, expected_size).await?;
+    Ok((local, !had_local_copy))
+}
+
+/// Returns the size of the copy. Checks it against `expected_size` when it's known.
+async fn copy_file(
+    remote_fs: &dyn RemoteFs,
+    from: &str,
+    to: &str,
+    expected_size: Option<u64>,
+) -> Result<u64, CubeError> {
+    let (local, downloaded) = download_file(remote_fs, from, expected_size).await?;
+    let temp_path = remote_fs.temp_upload_path(to).await?;
+    if let Some(parent) = Path::new(&temp_path).parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::copy(&local, &temp_path).await?;
+    if downloaded {
+        fs::remove_file(&local).await?;
+    }
+
+    let size = remote_fs.upload_file(&temp_path, to).await?;
+    if let Some(expected_size) = expected_size {
+        if size != expected_size {
+            return Err(CubeError::internal(format!(
+                "File {} has size {} but {} is expected",
+                from, size, expected_size
+            )));
+        }
+    }
+    remote_fs.check_upload_file(to, size).await?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix() {
+        assert_eq!(normalize_prefix("/backups/b1/").unwrap(), "backups/b1");
+        assert!(normalize_prefix("/").is_err());
+        assert!(normalize_prefix("backups/../b1").is_err());
+    }
+}
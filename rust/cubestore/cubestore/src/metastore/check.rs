@@ -0,0 +1,466 @@
+//! Consistency check of the metastore against the remote storage, run by `SYS CHECK [REPAIR]`
+//! and `cubestored check [repair]`.
+use crate::metastore::chunks::chunk_file_name;
+use crate::metastore::partition::partition_file_name;
+use crate::metastore::table::Table;
+use crate::metastore::{
+    Chunk, Column, ColumnType, IdRow, Index, MetaStore, Partition, Schema, WAL,
+};
+use crate::remotefs::{RemoteFile, RemoteFs};
+use crate::store::{DataFrame, WALStore};
+use crate::table::{Row, TableValue};
+use crate::CubeError;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Rows the check walks, read from the same metastore snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetaStoreRows {
+    pub schemas: Vec<IdRow<Schema>>,
+    pub tables: Vec<IdRow<Table>>,
+    pub indexes: Vec<IdRow<Index>>,
+    pub partitions: Vec<IdRow<Partition>>,
+    pub chunks: Vec<IdRow<Chunk>>,
+    pub wals: Vec<IdRow<WAL>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckIssueKind {
+    /// Metastore row points at a row that doesn't exist.
+    DanglingReference,
+    /// Metastore row points at a file that doesn't exist in the remote storage.
+    MissingFile,
+    SizeMismatch,
+    /// Data file in the remote storage no metastore row points at.
+    UnreferencedFile,
+}
+
+impl fmt::Display for CheckIssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckIssueKind::DanglingReference => "dangling_reference",
+            CheckIssueKind::MissingFile => "missing_file",
+            CheckIssueKind::SizeMismatch => "size_mismatch",
+            CheckIssueKind::UnreferencedFile => "unreferenced_file",
+        })
+    }
+}
+
+/// Fixes applied in the repair mode. Issues that can't be fixed without losing track of data,
+/// e.g. partitions with missing files, are only reported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckRepair {
+    /// Garbage collection removes the chunk afterwards.
+    DeactivateChunk(u64),
+    DeleteWAL(u64),
+    DeleteFile(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckIssue {
+    pub kind: CheckIssueKind,
+    /// E.g. `partition 12` or `file 12.parquet`.
+    pub object: String,
+    pub details: String,
+    pub repair: Option<CheckRepair>,
+    pub repaired: bool,
+    /// Set for [CheckIssueKind::MissingFile], the file is looked up again before the repair.
+    pub missing_file: Option<String>,
+}
+
+pub async fn check_consistency(
+    meta_store: &dyn MetaStore,
+    remote_fs: &dyn RemoteFs,
+    // Files younger than this can belong to jobs that haven't updated the metastore yet.
+    unreferenced_file_min_age: chrono::Duration,
+    repair: bool,
+) -> Result<Vec<CheckIssue>, CubeError> {
+    // List files first, so files of rows created after the metastore read aren't reported.
+    let remote_files = remote_fs.list_with_metadata("").await?;
+    let rows = meta_store.get_all_metadata_rows().await?;
+    let mut issues = find_issues(&rows, &remote_files, Utc::now() - unreferenced_file_min_age);
+    info!("Consistency check found {} issues", issues.len());
+    if !repair {
+        return Ok(issues);
+    }
+
+    for issue in issues.iter_mut() {
+        if issue.repair.is_none() {
+            continue;
+        }
+        if let Some(file) = &issue.missing_file {
+            // The row could be created after the listing and its file uploaded since then.
+            match remote_fs.list(file).await {
+                Ok(files) if files.iter().any(|f| f == file) => {
+                    info!("Not repairing {}: {} exists now", issue.object, file);
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Can't repair {} {}: {}", issue.kind, issue.object, e);
+                    continue;
+                }
+            }
+        }
+        let r = match &issue.repair {
+            None => continue,
+            Some(CheckRepair::DeactivateChunk(id)) => meta_store.deactivate_chunk(*id).await,
+            Some(CheckRepair::DeleteWAL(id)) => meta_store.delete_wal(*id).await,
+            Some(CheckRepair::DeleteFile(f)) => remote_fs.delete_file(f).await,
+        };
+        match r {
+            Ok(()) => issue.repaired = true,
+            Err(e) => warn!("Can't repair {} {}: {}", issue.kind, issue.object, e),
+        }
+    }
+    Ok(issues)
+}
+
+pub fn find_issues(
+    rows: &MetaStoreRows,
+    remote_files: &[RemoteFile],
+    unreferenced_created_before: DateTime<Utc>,
+) -> Vec<CheckIssue> {
+    let mut issues = Vec::new();
+
+    let schemas = ids(&rows.schemas);
+    let tables = ids(&rows.tables);
+    let indexes = ids(&rows.indexes);
+    let partitions = ids(&rows.partitions);
+    let remote_sizes = remote_files
+        .iter()
+        .map(|f| (f.remote_path.as_str(), f.file_size))
+        .collect::<HashMap<_, _>>();
+    // Rows in every state keep their files until garbage collection removes both.
+    let mut referenced = HashSet::new();
+
+    for t in rows.tables.iter() {
+        if !schemas.contains(&t.get_row().get_schema_id()) {
+            push_issue(
+                &mut issues,
+                CheckIssueKind::DanglingReference,
+                format!("table {}", t.get_id()),
+                format!("schema {} doesn't exist", t.get_row().get_schema_id()),
+                None,
+            );
+        }
+    }
+    for i in rows.indexes.iter() {
+        if !tables.contains(&i.get_row().table_id()) {
+            push_issue(
+                &mut issues,
+                CheckIssueKind::DanglingReference,
+                format!("index {}", i.get_id()),
+                format!("table {} doesn't exist", i.get_row().table_id()),
+                None,
+            );
+        }
+    }
+    for p in rows.partitions.iter() {
+        let object = format!("partition {}", p.get_id());
+        let row = p.get_row();
+        if !indexes.contains(&row.get_index_id()) {
+            push_issue(
+                &mut issues,
+                CheckIssueKind::DanglingReference,
+                object.clone(),
+                format!("index {} doesn't exist", row.get_index_id()),
+                None,
+            );
+        }
+        referenced.insert(partition_file_name(p.get_id(), row.suffix()));
+        if let Some(file) = row.get_full_name(p.get_id()) {
+            check_file(
+                &mut issues,
+                &remote_sizes,
+                object,
+                file,
+                row.file_size(),
+                None,
+            );
+        }
+    }
+    for c in rows.chunks.iter() {
+        let object = format!("chunk {}", c.get_id());
+        let row = c.get_row();
+        referenced.insert(chunk_file_name(c.get_id(), row.suffix()));
+        if !row.active() {
+            continue;
+        }
+        if !partitions.contains(&row.get_partition_id()) {
+            push_issue(
+                &mut issues,
+                CheckIssueKind::DanglingReference,
+                object.clone(),
+                format!("partition {} doesn't exist", row.get_partition_id()),
+                Some(CheckRepair::DeactivateChunk(c.get_id())),
+            );
+        } else if row.uploaded() && !row.in_memory() {
+            check_file(
+                &mut issues,
+                &remote_sizes,
+                object,
+                row.get_full_name(c.get_id()),
+                row.file_size(),
+                Some(CheckRepair::DeactivateChunk(c.get_id())),
+            );
+        }
+    }
+    for w in rows.wals.iter() {
+        let object = format!("wal {}", w.get_id());
+        let file = WALStore::wal_remote_path(w.get_id());
+        referenced.insert(file.clone());
+        if !tables.contains(&w.get_row().table_id()) {
+            push_issue(
+                &mut issues,
+                CheckIssueKind::DanglingReference,
+                object.clone(),
+                format!("table {} doesn't exist", w.get_row().table_id()),
+                Some(CheckRepair::DeleteWAL(w.get_id())),
+            );
+        } else if w.get_row().uploaded() {
+            check_file(
+                &mut issues,
+                &remote_sizes,
+                object,
+                file,
+                None,
+                Some(CheckRepair::DeleteWAL(w.get_id())),
+            );
+        }
+    }
+
+    for f in remote_files {
+        let is_data_file = !f.remote_path.contains('/')
+            && (f.remote_path.ends_with(".parquet") || f.remote_path.ends_with(".wal"));
+        if is_data_file
+            && !referenced.contains(&f.remote_path)
+            && f.updated < unreferenced_created_before
+        {
+            push_issue(
+                &mut issues,
+                CheckIssueKind::UnreferencedFile,
+                format!("file {}", f.remote_path),
+                format!("{} bytes, updated at {}", f.file_size, f.updated),
+                Some(CheckRepair::DeleteFile(f.remote_path.clone())),
+            );
+        }
+    }
+
+    issues
+}
+
+pub fn check_issues_data_frame(issues: Vec<CheckIssue>) -> DataFrame {
+    let columns = vec![
+        Column::new("kind".to_string(), ColumnType::String, 0),
+        Column::new("object".to_string(), ColumnType::String, 1),
+        Column::new("details".to_string(), ColumnType::String, 2),
+        Column::new("repaired".to_string(), ColumnType::Boolean, 3),
+    ];
+    let rows = issues
+        .into_iter()
+        .map(|i| {
+            Row::new(vec![
+                TableValue::String(i.kind.to_string()),
+                TableValue::String(i.object),
+                TableValue::String(i.details),
+                TableValue::Boolean(i.repaired),
+            ])
+        })
+        .collect();
+    DataFrame::new(columns, rows)
+}
+
+fn push_issue(
+    issues: &mut Vec<CheckIssue>,
+    kind: CheckIssueKind,
+    object: String,
+    details: String,
+    repair: Option<CheckRepair>,
+) {
+    issues.push(CheckIssue {
+        kind,
+        object,
+        details,
+        repair,
+        repaired: false,
+        missing_file: None,
+    })
+}
+
+fn check_file(
+    issues: &mut Vec<CheckIssue>,
+    remote_sizes: &HashMap<&str, u64>,
+    object: String,
+    file: String,
+    size: Option<u64>,
+    on_missing: Option<CheckRepair>,
+) {
+    match (remote_sizes.get(file.as_str()), size) {
+        (None, _) => issues.push(CheckIssue {
+            kind: CheckIssueKind::MissingFile,
+            object,
+            details: format!("{} doesn't exist", file),
+            repair: on_missing,
+            repaired: false,
+            missing_file: Some(file),
+        }),
+        (Some(remote_size), Some(size)) if *remote_size != size => push_issue(
+            issues,
+            CheckIssueKind::SizeMismatch,
+            object,
+            format!("{} has size {} but {} is expected", file, remote_size, size),
+            None,
+        ),
+        _ => {}
+    }
+}
+
+fn ids<T: Clone>(rows: &[IdRow<T>]) -> HashSet<u64> {
+    rows.iter().map(|r| r.get_id()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metastore::IndexType;
+
+    fn file(path: String, size: u64) -> RemoteFile {
+        RemoteFile {
+            remote_path: path,
+            updated: Utc::now() - chrono::Duration::hours(2),
+            file_size: size,
+        }
+    }
+
+    #[test]
+    fn issues() {
+        let columns = vec![Column::new("a".to_string(), ColumnType::Int, 0)];
+        let table = Table::new(
+            "t".to_string(),
+            1,
+            columns.clone(),
+            None,
+            None,
+            true,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Vec::new(),
+            None,
+            None,
+            None,
+        );
+        let index = Index::try_new(
+            "default".to_string(),
+            1,
+            columns,
+            1,
+            None,
+            None,
+            IndexType::Regular,
+        )
+        .unwrap();
+        let partition = |id, index_id| {
+            let p = Partition::new(index_id, None, None, None)
+                .update_row_count(10)
+                .set_file_size(100)
+                .unwrap();
+            IdRow::new(id, p)
+        };
+        let chunk = |id, partition_id| {
+            let c = Chunk::new(partition_id, 1, false)
+                .set_uploaded(true)
+                .set_file_size(50)
+                .unwrap();
+            IdRow::new(id, c)
+        };
+        let rows = MetaStoreRows {
+            schemas: vec![IdRow::new(1, Schema::new("s".to_string()))],
+            tables: vec![IdRow::new(1, table)],
+            indexes: vec![IdRow::new(1, index)],
+            partitions: vec![partition(1, 1), partition(2, 1), partition(3, 2)],
+            chunks: vec![chunk(1, 1), chunk(2, 1), chunk(3, 4)],
+            wals: vec![IdRow::new(1, WAL::new(2, 10))],
+        };
+        let partition_file = |i: usize| {
+            let p = &rows.partitions[i];
+            p.get_row().get_full_name(p.get_id()).unwrap()
+        };
+        let chunk_file = |i: usize| {
+            let c = &rows.chunks[i];
+            c.get_row().get_full_name(c.get_id())
+        };
+        let remote_files = vec![
+            file(partition_file(0), 100),
+            file(partition_file(1), 99),
+            file(partition_file(2), 100),
+            file(chunk_file(0), 50),
+            file(chunk_file(2), 50),
+            file("5.chunk.parquet".to_string(), 50),
+            RemoteFile {
+                updated: Utc::now(),
+                ..file("6.chunk.parquet".to_string(), 50)
+            },
+            file("metastore-current".to_string(), 10),
+            file("backups/1.parquet".to_string(), 10),
+        ];
+
+        let issues = find_issues(
+            &rows,
+            &remote_files,
+            Utc::now() - chrono::Duration::hours(1),
+        );
+        assert_eq!(
+            issues
+                .iter()
+                .filter_map(|i| i.missing_file.clone())
+                .collect::<Vec<_>>(),
+            vec![chunk_file(1)]
+        );
+        let issues = issues
+            .into_iter()
+            .map(|i| (i.kind, i.object, i.repair))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            vec![
+                (
+                    CheckIssueKind::SizeMismatch,
+                    "partition 2".to_string(),
+                    None
+                ),
+                (
+                    CheckIssueKind::DanglingReference,
+                    "partition 3".to_string(),
+                    None
+                ),
+                (
+                    CheckIssueKind::MissingFile,
+                    "chunk 2".to_string(),
+                    Some(CheckRepair::DeactivateChunk(2))
+                ),
+                (
+                    CheckIssueKind::DanglingReference,
+                    "chunk 3".to_string(),
+                    Some(CheckRepair::DeactivateChunk(3))
+                ),
+                (
+                    CheckIssueKind::DanglingReference,
+                    "wal 1".to_string(),
+                    Some(CheckRepair::DeleteWAL(1))
+                ),
+                (
+                    CheckIssueKind::UnreferencedFile,
+                    "file 5.chunk.parquet".to_string(),
+                    Some(CheckRepair::DeleteFile("5.chunk.parquet".to_string()))
+                ),
+            ]
+        );
+    }
+}
@@ -20,6 +20,7 @@ pub enum JobType {
     FinishMultiSplit,
     RepartitionChunk,
     InMemoryChunksCompaction,
+    InMemoryChunksFlush,
 }
 
 fn get_job_type_index(j: &JobType) -> u32 {
@@ -33,6 +34,7 @@ fn get_job_type_index(j: &JobType) -> u32 {
         JobType::FinishMultiSplit => 7,
         JobType::RepartitionChunk => 8,
         JobType::InMemoryChunksCompaction => 9,
+        JobType::InMemoryChunksFlush => 10,
     }
 }
 
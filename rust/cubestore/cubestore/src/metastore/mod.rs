@@ -1,8 +1,11 @@
+pub mod backup;
+pub mod check;
 pub mod chunks;
 pub mod index;
 pub mod job;
 pub mod listener;
 pub mod multi_index;
+pub mod node;
 pub mod partition;
 pub mod replay_handle;
 mod rocks_fs;
@@ -27,6 +30,7 @@ use std::{env, io::Cursor, sync::Arc};
 
 use crate::config::injection::DIService;
 use crate::config::{Config, ConfigObj};
+use crate::metastore::check::MetaStoreRows;
 use crate::metastore::chunks::{ChunkIndexKey, ChunkRocksIndex};
 use crate::metastore::index::IndexIndexKey;
 use crate::metastore::job::{Job, JobIndexKey, JobRocksIndex, JobRocksTable, JobStatus, JobType};
@@ -34,6 +38,7 @@ use crate::metastore::multi_index::{
     MultiIndexIndexKey, MultiPartition, MultiPartitionIndexKey, MultiPartitionRocksIndex,
     MultiPartitionRocksTable,
 };
+use crate::metastore::node::{Node, NodeIndexKey, NodeRocksIndex, NodeRocksTable, NodeState};
 use crate::metastore::partition::PartitionIndexKey;
 use crate::metastore::replay_handle::{
     ReplayHandle, ReplayHandleIndexKey, ReplayHandleRocksIndex, ReplayHandleRocksTable, SeqPointer,
@@ -41,7 +46,9 @@ use crate::metastore::replay_handle::{
 use crate::metastore::source::{
     Source, SourceCredentials, SourceIndexKey, SourceRocksIndex, SourceRocksTable,
 };
-use crate::metastore::table::{AggregateColumnIndex, StreamOffset, TableIndexKey, TablePath};
+use crate::metastore::table::{
+    AggregateColumnIndex, MaterializedView, StreamOffset, TableIndexKey, TablePath, TimePartition,
+};
 use crate::metastore::wal::{WALIndexKey, WALRocksIndex};
 
 use crate::table::{Row, TableValue};
@@ -67,6 +74,7 @@ use parquet::{basic::Type, schema::types};
 use partition::{PartitionRocksIndex, PartitionRocksTable};
 use regex::Regex;
 use rocksdb::backup::BackupEngineOptions;
+use rocksdb::checkpoint::Checkpoint;
 
 use schema::{SchemaRocksIndex, SchemaRocksTable};
 use smallvec::alloc::fmt::Formatter;
@@ -81,7 +89,7 @@ use std::str::FromStr;
 use crate::cachestore::{CacheItem, QueueItem, QueueItemStatus, QueueResult, QueueResultAckEvent};
 use crate::remotefs::LocalDirRemoteFs;
 use snapshot_info::SnapshotInfo;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use table::Table;
 use table::{TableRocksIndex, TableRocksTable};
 use tokio::sync::broadcast::Sender;
@@ -346,6 +354,7 @@ pub enum ColumnType {
     Int,
     Bytes,
     HyperLogLog(HllFlavour), // HLL Sketches, compatible with presto.
+    ThetaSketch,             // Theta Sketches, compatible with Apache DataSketches.
     Timestamp,
     Decimal { scale: i32, precision: i32 },
     Float,
@@ -363,6 +372,7 @@ impl Display for ColumnType {
             ColumnType::HyperLogLog(HllFlavour::ZetaSketch) => "hyperloglogpp",
             ColumnType::HyperLogLog(HllFlavour::Postgres) => "hll_postgres",
             ColumnType::HyperLogLog(HllFlavour::Snowflake) => "hll_snowflake",
+            ColumnType::ThetaSketch => "theta_sketch",
             ColumnType::Timestamp => "timestamp",
             ColumnType::Float => "float",
             ColumnType::Boolean => "boolean",
@@ -396,6 +406,7 @@ impl ColumnType {
                 "hyperloglogpp" => Ok(ColumnType::HyperLogLog(HllFlavour::ZetaSketch)),
                 "hll_postgres" => Ok(ColumnType::HyperLogLog(HllFlavour::Postgres)),
                 "hll_snowflake" => Ok(ColumnType::HyperLogLog(HllFlavour::Snowflake)),
+                "theta_sketch" => Ok(ColumnType::ThetaSketch),
                 "timestamp" => Ok(ColumnType::Timestamp),
                 "float" => Ok(ColumnType::Float),
                 "boolean" => Ok(ColumnType::Boolean),
@@ -447,7 +458,7 @@ impl From<&Column> for parquet::schema::types::Type {
                     .build()
                     .unwrap()
             }
-            ColumnType::Bytes | ColumnType::HyperLogLog(_) => {
+            ColumnType::Bytes | ColumnType::HyperLogLog(_) | ColumnType::ThetaSketch => {
                 types::Type::primitive_type_builder(&column.get_name(), Type::BYTE_ARRAY)
                     .with_converted_type(ConvertedType::NONE)
                     .with_repetition(Repetition::OPTIONAL)
@@ -505,6 +516,7 @@ impl<'a> Into<Field> for &'a Column {
                 }
                 ColumnType::Bytes => DataType::Binary,
                 ColumnType::HyperLogLog(_) => DataType::Binary,
+                ColumnType::ThetaSketch => DataType::Binary,
                 ColumnType::Float => DataType::Float64,
             },
             true,
@@ -527,6 +539,7 @@ impl fmt::Display for Column {
             ColumnType::HyperLogLog(HllFlavour::ZetaSketch) => "HYPERLOGLOGPP".to_string(),
             ColumnType::HyperLogLog(HllFlavour::Postgres) => "HLL_POSTGRES".to_string(),
             ColumnType::HyperLogLog(HllFlavour::Snowflake) => "HLL_SNOWFLAKE".to_string(),
+            ColumnType::ThetaSketch => "THETA_SKETCH".to_string(),
             ColumnType::Float => "FLOAT".to_string(),
         };
         f.write_fmt(format_args!("{} {}", self.name, column_type))
@@ -574,6 +587,10 @@ pub enum AggregateFunction {
     MAX = 2,
     MIN = 3,
     MERGE = 4,
+    COUNT = 5,
+    ANY_VALUE = 6,
+    LAST_VALUE = 7,
+    BIT_OR = 8,
 }
 
 impl FromStr for AggregateFunction {
@@ -585,6 +602,10 @@ impl FromStr for AggregateFunction {
             "MAX" => Ok(AggregateFunction::MAX),
             "MIN" => Ok(AggregateFunction::MIN),
             "MERGE" => Ok(AggregateFunction::MERGE),
+            "COUNT" => Ok(AggregateFunction::COUNT),
+            "ANY_VALUE" => Ok(AggregateFunction::ANY_VALUE),
+            "LAST_VALUE" => Ok(AggregateFunction::LAST_VALUE),
+            "BIT_OR" => Ok(AggregateFunction::BIT_OR),
             _ => Err(CubeError::user(format!(
                 "Function {} can't be used in aggregate index",
                 s
@@ -600,6 +621,10 @@ impl fmt::Display for AggregateFunction {
             Self::MAX => "MAX",
             Self::MIN => "MIN",
             Self::MERGE => "MERGE",
+            Self::COUNT => "COUNT",
+            Self::ANY_VALUE => "ANY_VALUE",
+            Self::LAST_VALUE => "LAST_VALUE",
+            Self::BIT_OR => "BIT_OR",
         };
 
         f.write_fmt(format_args!("{}", res))
@@ -610,7 +635,7 @@ impl AggregateFunction {
     pub fn allowed_for_type(&self, col_type: &ColumnType) -> bool {
         match self {
             Self::MAX | Self::MIN => match col_type {
-                ColumnType::HyperLogLog(_) => false,
+                ColumnType::HyperLogLog(_) | ColumnType::ThetaSketch => false,
                 _ => true,
             },
             Self::SUM => match col_type {
@@ -619,9 +644,19 @@ impl AggregateFunction {
             },
             Self::MERGE => match col_type {
                 ColumnType::HyperLogLog(_) => true,
+                ColumnType::ThetaSketch => true,
                 ColumnType::Bytes => true,
                 _ => false,
             },
+            // Counts are stored in the column itself, so it must be able to hold them.
+            Self::COUNT | Self::BIT_OR => match col_type {
+                ColumnType::Int => true,
+                _ => false,
+            },
+            Self::ANY_VALUE | Self::LAST_VALUE => match col_type {
+                ColumnType::HyperLogLog(_) | ColumnType::ThetaSketch => false,
+                _ => true,
+            },
         }
     }
 }
@@ -634,6 +669,13 @@ pub struct IndexDef {
     pub index_type: IndexType,
 }
 
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
+pub struct TimePartitionDef {
+    pub column: String,
+    pub interval_secs: i64,
+    pub retention_secs: Option<i64>,
+}
+
 data_frame_from! {
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct Partition {
@@ -653,7 +695,22 @@ pub struct Partition {
     #[serde(default)]
     suffix: Option<String>,
     #[serde(default)]
-    file_size: Option<u64>
+    file_size: Option<u64>,
+    /// Number of distinct values of each sort key prefix in the main table file, gathered at
+    /// compaction. `key_distinct[i]` is for the first `i + 1` columns.
+    #[serde(default)]
+    key_distinct: Option<Vec<u64>>,
+    /// Minimum and maximum of each column in the main table file, gathered at compaction.
+    /// NULLs are ignored, columns without values and binary columns have NULL in both rows.
+    #[serde(default)]
+    column_min: Option<Row>,
+    #[serde(default)]
+    column_max: Option<Row>,
+    /// Start of the time bucket, in unix seconds, that all rows of the partition belong to. Set for
+    /// tables with a [TimePartition], where each bucket has its own tree of partitions. Rows
+    /// without a timestamp go to partitions without a bucket.
+    #[serde(default)]
+    time_bucket: Option<i64>
 }
 }
 
@@ -749,6 +806,7 @@ pub trait MetaStore: DIService + Send + Sync {
         unique_key_column_names: Option<Vec<String>>,
         aggregates: Option<Vec<(String, String)>>,
         partition_split_threshold: Option<u64>,
+        time_partition: Option<TimePartitionDef>,
     ) -> Result<IdRow<Table>, CubeError>;
     async fn table_ready(&self, id: u64, is_ready: bool) -> Result<IdRow<Table>, CubeError>;
     async fn seal_table(&self, id: u64) -> Result<IdRow<Table>, CubeError>;
@@ -774,6 +832,21 @@ pub trait MetaStore: DIService + Send + Sync {
         created_seconds_ago: i64,
     ) -> Result<Vec<IdRow<Table>>, CubeError>;
     async fn drop_table(&self, table_id: u64) -> Result<IdRow<Table>, CubeError>;
+    /// Creates a materialized view over `view.source_table_id()`. The view's default index is an
+    /// aggregate index over `dimensions`.
+    async fn create_materialized_view(
+        &self,
+        schema_name: String,
+        view_name: String,
+        columns: Vec<Column>,
+        dimensions: Vec<String>,
+        aggregates: Vec<(String, String)>,
+        view: MaterializedView,
+    ) -> Result<IdRow<Table>, CubeError>;
+    async fn get_materialized_views(
+        &self,
+        source_table_id: u64,
+    ) -> Result<Vec<IdRow<Table>>, CubeError>;
 
     fn partition_table(&self) -> PartitionMetaStoreTable;
     async fn create_partition(&self, partition: Partition) -> Result<IdRow<Partition>, CubeError>;
@@ -838,6 +911,21 @@ pub trait MetaStore: DIService + Send + Sync {
         &self,
         index_id: u64,
     ) -> Result<Vec<IdRow<Partition>>, CubeError>;
+    /// Creates root partitions for `time_buckets` of a time partitioned index that have no active
+    /// partitions yet.
+    async fn create_time_bucket_partitions(
+        &self,
+        index_id: u64,
+        time_buckets: Vec<i64>,
+    ) -> Result<Vec<IdRow<Partition>>, CubeError>;
+    /// Deactivates partitions and chunks of time buckets that start before `time_bucket` in all
+    /// indexes of the table, so that garbage collection removes them. Returns all partitions of
+    /// those buckets, including the ones deactivated earlier.
+    async fn deactivate_time_buckets_before(
+        &self,
+        table_id: u64,
+        time_bucket: i64,
+    ) -> Result<Vec<IdRow<Partition>>, CubeError>;
     async fn get_index(&self, index_id: u64) -> Result<IdRow<Index>, CubeError>;
 
     async fn create_partitioned_index(
@@ -975,6 +1063,11 @@ pub trait MetaStore: DIService + Send + Sync {
     async fn update_heart_beat(&self, job_id: u64) -> Result<IdRow<Job>, CubeError>;
     async fn delete_all_jobs(&self) -> Result<Vec<IdRow<Job>>, CubeError>;
 
+    /// Registers a select worker on the first call, updates its heartbeat after that.
+    async fn node_heartbeat(&self, name: String) -> Result<IdRow<Node>, CubeError>;
+    async fn update_node_state(&self, id: u64, state: NodeState) -> Result<IdRow<Node>, CubeError>;
+    async fn get_nodes(&self) -> Result<Vec<IdRow<Node>>, CubeError>;
+
     async fn create_or_update_source(
         &self,
         name: String,
@@ -1025,6 +1118,14 @@ pub trait MetaStore: DIService + Send + Sync {
     ) -> Result<Vec<(IdRow<Schema>, IdRow<Table>, Vec<IdRow<Index>>)>, CubeError>;
 
     async fn debug_dump(&self, out_path: String) -> Result<(), CubeError>;
+    /// Creates a checkpoint in `out_dir/<snapshot name>` and returns the snapshot name together
+    /// with remote paths and known sizes of all partition and chunk files the checkpoint references.
+    async fn backup_checkpoint(
+        &self,
+        out_dir: String,
+    ) -> Result<(String, Vec<(String, Option<u64>)>), CubeError>;
+    /// Rows of all tables checked by `SYS CHECK`, read from the same snapshot.
+    async fn get_all_metadata_rows(&self) -> Result<MetaStoreRows, CubeError>;
     // Force compaction for the whole RocksDB
     async fn compaction(&self) -> Result<(), CubeError>;
 
@@ -1050,6 +1151,7 @@ pub enum MetaStoreEvent {
     UpdateWAL(IdRow<WAL>, IdRow<WAL>),
     UpdateSource(IdRow<Source>, IdRow<Source>),
     UpdateReplayHandle(IdRow<ReplayHandle>, IdRow<ReplayHandle>),
+    UpdateNode(IdRow<Node>, IdRow<Node>),
 
     DeleteChunk(IdRow<Chunk>),
     DeleteIndex(IdRow<Index>),
@@ -1060,6 +1162,7 @@ pub enum MetaStoreEvent {
     DeleteWAL(IdRow<WAL>),
     DeleteSource(IdRow<Source>),
     DeleteReplayHandle(IdRow<ReplayHandle>),
+    DeleteNode(IdRow<Node>),
 
     UpdateMultiIndex(IdRow<MultiIndex>, IdRow<MultiIndex>),
     DeleteMultiIndex(IdRow<MultiIndex>),
@@ -1119,6 +1222,7 @@ impl RocksStoreDetails for RocksMetaStoreDetails {
         SourceRocksTable::new(table_ref.clone()).migrate()?;
         MultiIndexRocksTable::new(table_ref.clone()).migrate()?;
         MultiPartitionRocksTable::new(table_ref.clone()).migrate()?;
+        NodeRocksTable::new(table_ref.clone()).migrate()?;
 
         Ok(())
     }
@@ -1283,6 +1387,105 @@ impl RocksMetaStore {
 }
 
 impl RocksMetaStore {
+    fn resolve_aggregate_columns(
+        columns: &[Column],
+        aggregates: &[(String, String)],
+        unique_key_column_indices: &Option<Vec<u64>>,
+    ) -> Result<Vec<AggregateColumnIndex>, CubeError> {
+        aggregates
+            .iter()
+            .map(|aggr| {
+                let aggr_column = &aggr.1;
+                let column = columns
+                    .iter()
+                    .find(|c| &c.name == aggr_column)
+                    .ok_or_else(|| {
+                        CubeError::user(format!(
+                            "Aggregate column {} not found among column definitions {:?}",
+                            aggr_column, columns
+                        ))
+                    })?;
+
+                let index = column.column_index as u64;
+                if let Some(unique_indices) = unique_key_column_indices {
+                    if unique_indices.iter().find(|i| i == &&index).is_some() {
+                        return Err(CubeError::user(format!(
+                            "Aggregate column {} is in unique key. A column can't be in an unique key and an aggregation at the same time",
+                            aggr_column
+                        )));
+                    }
+                }
+                let function = aggr.0.parse::<AggregateFunction>()?;
+
+                if !function.allowed_for_type(&column.column_type) {
+                    return Err(CubeError::user(format!(
+                        "Aggregate function {} not allowed for column type {}",
+                        function, &column.column_type
+                    )));
+                }
+                Ok(AggregateColumnIndex::new(index, function))
+            })
+            .collect()
+    }
+
+    /// Tables without a unique key have no seq column filled on ingestion, so `LAST_VALUE` needs
+    /// one declared explicitly.
+    fn resolve_last_value_seq_column(columns: &[Column]) -> Result<u64, CubeError> {
+        let column = columns
+            .iter()
+            .find(|c| c.name == Table::LAST_VALUE_SEQ_COLUMN)
+            .ok_or_else(|| {
+                CubeError::user(format!(
+                    "LAST_VALUE requires a unique key or an int column {} that orders the values",
+                    Table::LAST_VALUE_SEQ_COLUMN
+                ))
+            })?;
+        if column.column_type != ColumnType::Int {
+            return Err(CubeError::user(format!(
+                "Column {} must be an int, got {}",
+                column.name, column.column_type
+            )));
+        }
+        Ok(column.column_index as u64)
+    }
+
+    fn resolve_time_partition(
+        columns: &[Column],
+        time_partition: TimePartitionDef,
+        unique_key_column_indices: &Option<Vec<u64>>,
+    ) -> Result<TimePartition, CubeError> {
+        let column = columns
+            .iter()
+            .find(|c| c.name == time_partition.column)
+            .ok_or_else(|| {
+                CubeError::user(format!(
+                    "Time partition column {} not found among column definitions {:?}",
+                    time_partition.column, columns
+                ))
+            })?;
+        if column.column_type != ColumnType::Timestamp {
+            return Err(CubeError::user(format!(
+                "Time partition column {} must be a timestamp, got {}",
+                column.name, column.column_type
+            )));
+        }
+        let index = column.column_index as u64;
+        if let Some(unique_indices) = unique_key_column_indices {
+            // Rows with the same key must end up in the same partition to be merged.
+            if !unique_indices.contains(&index) {
+                return Err(CubeError::user(format!(
+                    "Time partition column {} must be in the unique key",
+                    column.name
+                )));
+            }
+        }
+        Ok(TimePartition::new(
+            index,
+            time_partition.interval_secs,
+            time_partition.retention_secs,
+        ))
+    }
+
     fn add_index(
         batch_pipe: &mut BatchPipe,
         rocks_index: &IndexRocksTable,
@@ -1293,6 +1496,18 @@ impl RocksMetaStore {
         multi_partitions: &[IdRow<MultiPartition>],
         index_def: IndexDef,
     ) -> Result<IdRow<Index>, CubeError> {
+        if let Some(time_column) = table_id.get_row().time_partition_column() {
+            // Aggregate indexes contain only the listed columns.
+            if index_def.index_type == IndexType::Aggregate
+                && !index_def.columns.contains(time_column.get_name())
+            {
+                return Err(CubeError::user(format!(
+                    "Aggregate index '{}' must contain time partition column '{}'",
+                    index_def.name,
+                    time_column.get_name()
+                )));
+            }
+        }
         match index_def.index_type {
             IndexType::Regular => Self::add_regular_index(
                 batch_pipe,
@@ -1560,6 +1775,16 @@ impl RocksMetaStore {
         Ok(table)
     }
 
+    fn materialized_views_impl(
+        tables: &TableRocksTable,
+        source_table_id: u64,
+    ) -> Result<Vec<IdRow<Table>>, CubeError> {
+        tables.get_rows_by_index(
+            &TableIndexKey::ByMaterializedViewSource(Some(source_table_id)),
+            &TableRocksIndex::MaterializedViewSource,
+        )
+    }
+
     fn chunks_by_partition(
         partition_id: u64,
         table: &ChunkRocksTable,
@@ -1802,7 +2027,8 @@ impl MetaStore for RocksMetaStore {
             stream_offset,
             unique_key_column_names,
             aggregates,
-            partition_split_threshold
+            partition_split_threshold,
+            time_partition
         )
     )]
     async fn create_table(
@@ -1821,6 +2047,7 @@ impl MetaStore for RocksMetaStore {
         unique_key_column_names: Option<Vec<String>>,
         aggregates: Option<Vec<(String, String)>>,
         partition_split_threshold: Option<u64>,
+        time_partition: Option<TimePartitionDef>,
     ) -> Result<IdRow<Table>, CubeError> {
         self.write_operation(move |db_ref, batch_pipe| {
             batch_pipe.invalidate_tables_cache();
@@ -1860,47 +2087,43 @@ impl MetaStore for RocksMetaStore {
             } else {
                 None
             };
-            let aggregate_column_indices = if let Some(aggrs) = aggregates {
-                let res = aggrs.iter()
-                    .map(|aggr| {
-                        let aggr_column = &aggr.1;
-                        let column = columns
-                            .iter()
-                            .find(|c| &c.name == aggr_column)
-                            .ok_or_else(|| {
-                                    CubeError::user(format!(
-                                        "Aggregate column {} not found among column definitions {:?}",
-                                        aggr_column, columns
-                                    ))
-                            })?;
-
-                        let index = column.column_index as u64;
-                        if let Some(unique_indices) = &unique_key_column_indices {
-                            if unique_indices.iter().find(|i| i == &&index).is_some() {
-                                return Err(CubeError::user(format!(
-                                            "Aggregate column {} is in unique key. A column can't be in an unique key and an aggregation at the same time",
-                                            aggr_column
-                                            )));
-                            }
-                        }
-                        let function = aggr.0.parse::<AggregateFunction>()?;
-
-                        if !function.allowed_for_type(&column.column_type) {
-                            return Err(CubeError::user(
-                                    format!(
-                                        "Aggregate function {} not allowed for column type {}",
-                                        function, &column.column_type
-                                        )
-                            ))
-                        }
-                        Ok(AggregateColumnIndex::new(index, function))
-                    })
-                .collect::<Result<Vec<_>,_>>()?;
-
-                res
+            let mut aggregate_column_indices = if let Some(aggrs) = aggregates {
+                RocksMetaStore::resolve_aggregate_columns(
+                    &columns,
+                    &aggrs,
+                    &unique_key_column_indices,
+                )?
             } else {
                 vec![]
             };
+            // LAST_VALUE picks values by the seq column. Aggregate indices keep its maximum.
+            if aggregate_column_indices
+                .iter()
+                .any(|a| a.function() == &AggregateFunction::LAST_VALUE)
+            {
+                let seq_column_index = match seq_column_index {
+                    Some(i) => i,
+                    None => RocksMetaStore::resolve_last_value_seq_column(&columns)?,
+                };
+                aggregate_column_indices.push(AggregateColumnIndex::new(
+                    seq_column_index,
+                    AggregateFunction::MAX,
+                ));
+            }
+            let time_partition = if let Some(time_partition) = time_partition {
+                if indexes.iter().any(|i| i.multi_index.is_some()) {
+                    return Err(CubeError::user(
+                        "PARTITION BY can't be used with a partitioned index".to_string(),
+                    ));
+                }
+                Some(RocksMetaStore::resolve_time_partition(
+                    &columns,
+                    time_partition,
+                    &unique_key_column_indices,
+                )?)
+            } else {
+                None
+            };
             let table = Table::new(
                 table_name,
                 schema_id.get_id(),
@@ -1916,6 +2139,7 @@ impl MetaStore for RocksMetaStore {
                 aggregate_column_indices,
                 seq_column_index,
                 partition_split_threshold,
+                time_partition,
             );
             let table_id = rocks_table.insert(table, batch_pipe)?;
             for index_def in indexes.into_iter() {
@@ -1959,6 +2183,7 @@ impl MetaStore for RocksMetaStore {
                 .filter_map(|c| match c.get_column_type() {
                     ColumnType::Bytes => None,
                     ColumnType::HyperLogLog(_) => None,
+                    ColumnType::ThetaSketch => None,
                     _ => {
                         if seq_column_index.is_none()
                             || seq_column_index.is_some()
@@ -1983,7 +2208,7 @@ impl MetaStore for RocksMetaStore {
                     name: "default".to_string(),
                     multi_index: None,
                     columns: def_index_columns,
-                    index_type: IndexType::Regular
+                    index_type: IndexType::Regular,
                 },
             )?;
 
@@ -2150,6 +2375,17 @@ impl MetaStore for RocksMetaStore {
             let tables_table = TableRocksTable::new(db_ref.clone());
             let indexes_table = IndexRocksTable::new(db_ref.clone());
             let replay_handles_table = ReplayHandleRocksTable::new(db_ref.clone());
+            if let Some(view) = Self::materialized_views_impl(&tables_table, table_id)?
+                .into_iter()
+                .next()
+            {
+                let table = tables_table.get_row_or_not_found(table_id)?;
+                return Err(CubeError::user(format!(
+                    "Can't drop table '{}' because materialized view '{}' depends on it",
+                    table.get_row().get_table_name(),
+                    view.get_row().get_table_name()
+                )));
+            }
             let indexes = indexes_table.get_row_ids_by_index(
                 &IndexIndexKey::TableId(table_id),
                 &IndexRocksIndex::TableID,
@@ -2169,6 +2405,115 @@ impl MetaStore for RocksMetaStore {
         .await
     }
 
+    #[tracing::instrument(level = "trace", skip(self, columns))]
+    async fn create_materialized_view(
+        &self,
+        schema_name: String,
+        view_name: String,
+        columns: Vec<Column>,
+        dimensions: Vec<String>,
+        aggregates: Vec<(String, String)>,
+        view: MaterializedView,
+    ) -> Result<IdRow<Table>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            batch_pipe.invalidate_tables_cache();
+            let rocks_table = TableRocksTable::new(db_ref.clone());
+            let rocks_index = IndexRocksTable::new(db_ref.clone());
+            let rocks_schema = SchemaRocksTable::new(db_ref.clone());
+            let rocks_partition = PartitionRocksTable::new(db_ref.clone());
+
+            let source = rocks_table.get_row_or_not_found(view.source_table_id())?;
+            let source_name = source.get_row().get_table_name();
+            if source.get_row().is_materialized_view() {
+                return Err(CubeError::user(format!(
+                    "Can't create materialized view over materialized view '{}'",
+                    source_name
+                )));
+            }
+            if source.get_row().unique_key_columns().is_some() {
+                return Err(CubeError::user(format!(
+                    "Can't create materialized view over table '{}' with unique key",
+                    source_name
+                )));
+            }
+            // Views only see rows inserted after they were created.
+            if *source.get_row().has_data() {
+                return Err(CubeError::user(format!(
+                    "Can't create materialized view over table '{}' because it already has data. Create the view before loading data into the table",
+                    source_name
+                )));
+            }
+            if dimensions.is_empty() {
+                return Err(CubeError::user(format!(
+                    "Materialized view '{}' must have at least one GROUP BY column",
+                    view_name
+                )));
+            }
+
+            let schema_id =
+                rocks_schema.get_single_row_by_index(&schema_name, &SchemaRocksIndex::Name)?;
+            let aggregate_column_indices =
+                RocksMetaStore::resolve_aggregate_columns(&columns, &aggregates, &None)?;
+            // Views have no seq column to order the values by.
+            if aggregate_column_indices
+                .iter()
+                .any(|a| a.function() == &AggregateFunction::LAST_VALUE)
+            {
+                return Err(CubeError::user(format!(
+                    "LAST_VALUE can't be used in materialized view '{}'",
+                    view_name
+                )));
+            }
+            let table = Table::new(
+                view_name,
+                schema_id.get_id(),
+                columns.clone(),
+                None,
+                None,
+                true,
+                None,
+                None,
+                None,
+                None,
+                None,
+                aggregate_column_indices,
+                None,
+                None,
+                None,
+            )
+            .update_materialized_view(view);
+            let table_id = rocks_table.insert(table, batch_pipe)?;
+            RocksMetaStore::add_index(
+                batch_pipe,
+                &rocks_index,
+                &rocks_partition,
+                &columns,
+                &table_id,
+                None,
+                &[],
+                IndexDef {
+                    name: "default".to_string(),
+                    multi_index: None,
+                    columns: dimensions,
+                    index_type: IndexType::Aggregate,
+                },
+            )?;
+            Ok(table_id)
+        })
+        .await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn get_materialized_views(
+        &self,
+        source_table_id: u64,
+    ) -> Result<Vec<IdRow<Table>>, CubeError> {
+        self.read_operation(move |db_ref| {
+            Self::materialized_views_impl(&TableRocksTable::new(db_ref), source_table_id)
+        })
+        .await
+    }
+
     fn partition_table(&self) -> PartitionMetaStoreTable {
         PartitionMetaStoreTable {
             rocks_meta_store: self.store.clone(),
@@ -2288,6 +2633,7 @@ impl MetaStore for RocksMetaStore {
             new_active.iter().map(|(p, _)| p.id).join(", ")
         );
         self.write_operation(move |db, pipe| {
+            let new_active_ref = &new_active;
             swap_active_partitions_impl(
                 db,
                 pipe,
@@ -2296,6 +2642,11 @@ impl MetaStore for RocksMetaStore {
                 move |i, p| {
                     let (rows, (min, max)) = take(&mut new_active_min_max[i]);
                     p.update_min_max_and_row_count(min, max, rows)
+                        .update_key_distinct(new_active_ref[i].0.get_row().key_distinct().clone())
+                        .update_column_min_max(
+                            new_active_ref[i].0.get_row().column_min().clone(),
+                            new_active_ref[i].0.get_row().column_max().clone(),
+                        )
                 },
                 |current_i| {
                     Err(CubeError::internal(format!(
@@ -2735,6 +3086,90 @@ impl MetaStore for RocksMetaStore {
         .await
     }
 
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn create_time_bucket_partitions(
+        &self,
+        index_id: u64,
+        time_buckets: Vec<i64>,
+    ) -> Result<Vec<IdRow<Partition>>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let rocks_partition = PartitionRocksTable::new(db_ref);
+            let existing = rocks_partition
+                .get_rows_by_index(
+                    &PartitionIndexKey::ByIndexId(index_id),
+                    &PartitionRocksIndex::IndexId,
+                )?
+                .into_iter()
+                .filter(|p| p.get_row().is_active())
+                .filter_map(|p| p.get_row().time_bucket())
+                .collect::<HashSet<_>>();
+            let mut created = Vec::new();
+            for bucket in time_buckets.into_iter().unique() {
+                if !existing.contains(&bucket) {
+                    created.push(
+                        rocks_partition
+                            .insert(Partition::new_time_bucket(index_id, bucket), batch_pipe)?,
+                    );
+                }
+            }
+            Ok(created)
+        })
+        .await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn deactivate_time_buckets_before(
+        &self,
+        table_id: u64,
+        time_bucket: i64,
+    ) -> Result<Vec<IdRow<Partition>>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let indexes = IndexRocksTable::new(db_ref.clone())
+                .get_rows_by_index(&IndexIndexKey::TableId(table_id), &IndexRocksIndex::TableID)?;
+            let partitions_table = PartitionRocksTable::new(db_ref.clone());
+            let chunks_table = ChunkRocksTable::new(db_ref.clone());
+            let mut expired = Vec::new();
+            for index in indexes {
+                let partitions = partitions_table.get_rows_by_index(
+                    &PartitionIndexKey::ByIndexId(index.get_id()),
+                    &PartitionRocksIndex::IndexId,
+                )?;
+                for partition in partitions {
+                    match partition.get_row().time_bucket() {
+                        Some(b) if b < time_bucket => {}
+                        _ => continue,
+                    }
+                    // Inactive partitions with active chunks would be repartitioned.
+                    let chunks = chunks_table.get_rows_by_index(
+                        &ChunkIndexKey::ByPartitionId(partition.get_id()),
+                        &ChunkRocksIndex::PartitionId,
+                    )?;
+                    for chunk in chunks {
+                        if chunk.get_row().active() {
+                            chunks_table.update_with_fn(
+                                chunk.get_id(),
+                                |row| row.deactivate(),
+                                batch_pipe,
+                            )?;
+                        }
+                    }
+                    if partition.get_row().is_active() {
+                        expired.push(partitions_table.update(
+                            partition.get_id(),
+                            partition.get_row().to_active(false),
+                            partition.get_row(),
+                            batch_pipe,
+                        )?);
+                    } else {
+                        expired.push(partition);
+                    }
+                }
+            }
+            Ok(expired)
+        })
+        .await
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     async fn get_index(&self, index_id: u64) -> Result<IdRow<Index>, CubeError> {
         self.read_operation(move |db_ref| {
@@ -3025,11 +3460,8 @@ impl MetaStore for RocksMetaStore {
             uploaded_chunk_ids.iter().map(|(id, _)| id).join(", ")
         );
         self.write_operation(move |db, pipe| {
-            TableRocksTable::new(db.clone()).update_with_fn(
-                table_id,
-                |t| t.update_has_data(true),
-                pipe,
-            )?;
+            let tables = TableRocksTable::new(db.clone());
+            tables.update_with_fn(table_id, |t| t.update_has_data(true), pipe)?;
             let (_, partition_rows) = Self::activate_chunks_impl(
                 db.clone(),
                 pipe,
@@ -3037,6 +3469,27 @@ impl MetaStore for RocksMetaStore {
                 replay_handle_id,
             )?;
             let partition = PartitionRocksTable::new(db.clone());
+            let views = Self::materialized_views_impl(&tables, table_id)?;
+            if !views.is_empty() {
+                let index = IndexRocksTable::new(db.clone());
+                let mut refreshed = HashSet::new();
+                for p in partition_rows.keys() {
+                    let index_id = partition.get_row_or_not_found(*p)?.row.get_index_id();
+                    refreshed.insert(index.get_row_or_not_found(index_id)?.row.table_id());
+                }
+                let now = Utc::now();
+                for v in views {
+                    let is_refreshed = refreshed.contains(&v.get_id());
+                    tables.update_with_fn(
+                        v.get_id(),
+                        |t| {
+                            let view = t.materialized_view().as_ref().unwrap();
+                            t.update_materialized_view(view.update_refreshed(now, is_refreshed))
+                        },
+                        pipe,
+                    )?;
+                }
+            }
             let mut mpartition_rows = HashMap::new();
             for (p, rows) in partition_rows {
                 if let Some(mp) = partition.get_row_or_not_found(p)?.row.multi_partition_id {
@@ -3397,6 +3850,48 @@ impl MetaStore for RocksMetaStore {
         .await
     }
 
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn node_heartbeat(&self, name: String) -> Result<IdRow<Node>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let table = NodeRocksTable::new(db_ref.clone());
+            let row = table.get_single_opt_row_by_index(
+                &NodeIndexKey::Name(name.to_string()),
+                &NodeRocksIndex::Name,
+            )?;
+            match row {
+                Some(row) => {
+                    Ok(table.update_with_fn(row.get_id(), |n| n.update_heartbeat(), batch_pipe)?)
+                }
+                None => Ok(table.insert(Node::new(name), batch_pipe)?),
+            }
+        })
+        .await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn update_node_state(&self, id: u64, state: NodeState) -> Result<IdRow<Node>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let table = NodeRocksTable::new(db_ref.clone());
+            // Every state change produces a new routing table, nodes use the version to tell
+            // whether their copy is stale.
+            let version = table
+                .all_rows()?
+                .iter()
+                .map(|n| n.get_row().routing_version())
+                .max()
+                .unwrap_or(0)
+                + 1;
+            Ok(table.update_with_fn(id, |n| n.update_state(state, version), batch_pipe)?)
+        })
+        .await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn get_nodes(&self) -> Result<Vec<IdRow<Node>>, CubeError> {
+        self.read_operation(move |db_ref| NodeRocksTable::new(db_ref).all_rows())
+            .await
+    }
+
     #[tracing::instrument(level = "trace", skip(self, credentials))]
     async fn create_or_update_source(
         &self,
@@ -3640,6 +4135,47 @@ impl MetaStore for RocksMetaStore {
         .await
     }
 
+    async fn backup_checkpoint(
+        &self,
+        out_dir: String,
+    ) -> Result<(String, Vec<(String, Option<u64>)>), CubeError> {
+        let snapshot = self.store.get_store_path(&SystemTime::now());
+        let checkpoint_path = Path::new(&out_dir).join(&snapshot);
+        // Writes are serialized with reads, so the checkpoint matches the rows we list.
+        self.read_operation(move |db_ref| {
+            Checkpoint::new(db_ref.db)?.create_checkpoint(&checkpoint_path)?;
+
+            let mut files = Vec::new();
+            for p in PartitionRocksTable::new(db_ref.clone()).all_rows()? {
+                if let Some(name) = p.get_row().get_full_name(p.get_id()) {
+                    files.push((name, p.get_row().file_size()));
+                }
+            }
+            for c in ChunkRocksTable::new(db_ref).all_rows()? {
+                let chunk = c.get_row();
+                if chunk.uploaded() && chunk.active() && !chunk.in_memory() {
+                    files.push((chunk.get_full_name(c.get_id()), chunk.file_size()));
+                }
+            }
+            Ok((snapshot, files))
+        })
+        .await
+    }
+
+    async fn get_all_metadata_rows(&self) -> Result<MetaStoreRows, CubeError> {
+        self.read_operation_out_of_queue(|db_ref| {
+            Ok(MetaStoreRows {
+                schemas: SchemaRocksTable::new(db_ref.clone()).all_rows()?,
+                tables: TableRocksTable::new(db_ref.clone()).all_rows()?,
+                indexes: IndexRocksTable::new(db_ref.clone()).all_rows()?,
+                partitions: PartitionRocksTable::new(db_ref.clone()).all_rows()?,
+                chunks: ChunkRocksTable::new(db_ref.clone()).all_rows()?,
+                wals: WALRocksTable::new(db_ref).all_rows()?,
+            })
+        })
+        .await
+    }
+
     async fn compaction(&self) -> Result<(), CubeError> {
         self.write_operation(move |db_ref, _batch_pipe| {
             let start: Option<&[u8]> = None;
@@ -4436,6 +4972,7 @@ mod tests {
                 None,
                 None,
                 None,
+                None,
             )
             .await
             .unwrap();
@@ -4456,6 +4993,7 @@ mod tests {
                 None,
                 None,
                 None,
+                None,
             )
             .await
             .unwrap();
@@ -4470,6 +5008,72 @@ mod tests {
         let _ = fs::remove_dir_all(remote_store_path.clone());
     }
 
+    #[tokio::test]
+    async fn node_heartbeat_test() {
+        let config = Config::test("node_heartbeat_test");
+        let store_path = env::current_dir()
+            .unwrap()
+            .join("test-local-node-heartbeat");
+        let remote_store_path = env::current_dir()
+            .unwrap()
+            .join("test-remote-node-heartbeat");
+        let _ = fs::remove_dir_all(store_path.clone());
+        let _ = fs::remove_dir_all(remote_store_path.clone());
+        let remote_fs = LocalDirRemoteFs::new(Some(remote_store_path.clone()), store_path.clone());
+
+        let meta_store = RocksMetaStore::new(
+            store_path.join("metastore").as_path(),
+            BaseRocksStoreFs::new(remote_fs.clone(), "metastore", config.config_obj()),
+            config.config_obj(),
+        )
+        .unwrap();
+
+        let node = meta_store
+            .node_heartbeat("worker-1".to_string())
+            .await
+            .unwrap();
+        assert_eq!(node.get_row().state(), NodeState::Joining);
+        assert_eq!(node.get_row().routing_version(), 0);
+        let unavailable = meta_store
+            .update_node_state(node.get_id(), NodeState::Unavailable)
+            .await
+            .unwrap();
+        assert_eq!(unavailable.get_row().routing_version(), 1);
+
+        // Heartbeats after a failure start the warmup again.
+        let updated = meta_store
+            .node_heartbeat("worker-1".to_string())
+            .await
+            .unwrap();
+        assert_eq!(updated.get_id(), node.get_id());
+        assert_eq!(updated.get_row().state(), NodeState::Joining);
+        assert!(updated.get_row().last_heartbeat() >= node.get_row().last_heartbeat());
+        assert!(updated.get_row().is_alive(chrono::Duration::seconds(10)));
+
+        meta_store
+            .node_heartbeat("worker-2".to_string())
+            .await
+            .unwrap();
+        let nodes = meta_store.get_nodes().await.unwrap();
+        assert_eq!(
+            nodes
+                .iter()
+                .map(|n| n.get_row().name().as_str())
+                .collect::<Vec<_>>(),
+            vec!["worker-1", "worker-2"]
+        );
+
+        // Versions grow across nodes, not per node.
+        let active = meta_store
+            .update_node_state(nodes[1].get_id(), NodeState::Active)
+            .await
+            .unwrap();
+        assert_eq!(active.get_row().routing_version(), 2);
+
+        let _ = fs::remove_dir_all(store_path.clone());
+        let _ = fs::remove_dir_all(remote_store_path.clone());
+    }
+
     #[tokio::test]
     async fn index_repair_test() {
         let config = Config::test("index_repair_test");
@@ -4574,6 +5178,7 @@ mod tests {
                     None,
                     None,
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -4596,6 +5201,7 @@ mod tests {
                     None,
                     None,
                     None,
+                    None,
                 )
                 .await
                 .is_err());
@@ -4682,6 +5288,7 @@ mod tests {
                     None,
                     None,
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -4769,6 +5376,7 @@ mod tests {
                         ("max".to_string(), "aggr_col1".to_string()),
                     ]),
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -4839,6 +5447,7 @@ mod tests {
                         ("max".to_string(), "col1".to_string()),
                     ]),
                     None,
+                    None,
                 )
                 .await
                 .is_err());
@@ -4859,6 +5468,7 @@ mod tests {
                     Some(vec!["col1".to_string()]),
                     None,
                     None,
+                    None,
                 )
                 .await
                 .is_err());
@@ -4882,6 +5492,7 @@ mod tests {
                         ("max".to_string(), "aggr_col1".to_string()),
                     ]),
                     None,
+                    None,
                 )
                 .await
                 .is_err());
@@ -5275,6 +5886,7 @@ mod tests {
                         None,
                         None,
                         None,
+                        None,
                     )
                     .await
                     .unwrap();
@@ -5495,6 +6107,7 @@ mod tests {
                     None,
                     None,
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -5633,6 +6246,7 @@ mod tests {
                     None,
                     None,
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -0,0 +1,137 @@
+use super::{IndexId, RocksSecondaryIndex, TableId};
+use crate::base_rocks_secondary_index;
+use crate::rocks_table_impl;
+use byteorder::{BigEndian, WriteBytesExt};
+use chrono::{DateTime, Duration, Utc};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use std::io::{Cursor, Write};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
+pub enum NodeState {
+    /// Registered, but partitions are not routed to the node until they are warmed up.
+    Joining,
+    Active,
+    /// Stopped sending heartbeats, goes back to [NodeState::Joining] once they resume.
+    Unavailable,
+}
+
+/// Select worker registered with the metastore.
+#[derive(Clone, Serialize, Deserialize, Debug, Hash)]
+pub struct Node {
+    name: String,
+    state: NodeState,
+    registered_at: DateTime<Utc>,
+    last_heartbeat: DateTime<Utc>,
+    /// Version of the routing table after the last state change of this node, the version of the
+    /// whole table is the maximum over all nodes.
+    #[serde(default)]
+    routing_version: u64,
+}
+
+impl Node {
+    pub fn new(name: String) -> Node {
+        let now = Utc::now();
+        Node {
+            name,
+            state: NodeState::Joining,
+            registered_at: now,
+            last_heartbeat: now,
+            routing_version: 0,
+        }
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn state(&self) -> NodeState {
+        self.state
+    }
+
+    pub fn registered_at(&self) -> &DateTime<Utc> {
+        &self.registered_at
+    }
+
+    pub fn last_heartbeat(&self) -> &DateTime<Utc> {
+        &self.last_heartbeat
+    }
+
+    pub fn routing_version(&self) -> u64 {
+        self.routing_version
+    }
+
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        Utc::now() - self.last_heartbeat < timeout
+    }
+
+    pub fn update_heartbeat(&self) -> Node {
+        Node {
+            state: match self.state {
+                NodeState::Unavailable => NodeState::Joining,
+                s => s,
+            },
+            last_heartbeat: Utc::now(),
+            ..self.clone()
+        }
+    }
+
+    pub fn update_state(&self, state: NodeState, routing_version: u64) -> Node {
+        Node {
+            state,
+            routing_version,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum NodeRocksIndex {
+    Name = 1,
+}
+
+base_rocks_secondary_index!(Node, NodeRocksIndex);
+
+rocks_table_impl!(Node, NodeRocksTable, TableId::Nodes, {
+    vec![Box::new(NodeRocksIndex::Name)]
+});
+
+#[derive(Hash, Clone, Debug)]
+pub enum NodeIndexKey {
+    Name(String),
+}
+
+impl RocksSecondaryIndex<Node, NodeIndexKey> for NodeRocksIndex {
+    fn typed_key_by(&self, row: &Node) -> NodeIndexKey {
+        match self {
+            NodeRocksIndex::Name => NodeIndexKey::Name(row.name.to_string()),
+        }
+    }
+
+    fn key_to_bytes(&self, key: &NodeIndexKey) -> Vec<u8> {
+        match key {
+            NodeIndexKey::Name(name) => {
+                let mut buf = Cursor::new(Vec::new());
+                buf.write_u32::<BigEndian>(name.len() as u32).unwrap();
+                buf.write_all(name.as_bytes()).unwrap();
+                buf.into_inner()
+            }
+        }
+    }
+
+    fn is_unique(&self) -> bool {
+        match self {
+            NodeRocksIndex::Name => true,
+        }
+    }
+
+    fn version(&self) -> u32 {
+        match self {
+            NodeRocksIndex::Name => 1,
+        }
+    }
+
+    fn get_id(&self) -> IndexId {
+        *self as IndexId
+    }
+}
@@ -33,6 +33,10 @@ impl Partition {
                     .to_lowercase(),
             ),
             file_size: None,
+            key_distinct: None,
+            column_min: None,
+            column_max: None,
+            time_bucket: None,
         }
     }
 
@@ -53,8 +57,20 @@ impl Partition {
                     .to_lowercase(),
             ),
             file_size: None,
+            key_distinct: None,
+            column_min: None,
+            column_max: None,
+            time_bucket: parent.get_row().time_bucket,
         }
     }
+
+    /// Root partition of `time_bucket` in a time partitioned index.
+    pub fn new_time_bucket(index_id: u64, time_bucket: i64) -> Partition {
+        let mut p = Partition::new(index_id, None, None, None);
+        p.time_bucket = Some(time_bucket);
+        p
+    }
+
     pub fn get_min_val(&self) -> &Option<Row> {
         &self.min_value
     }
@@ -120,10 +136,43 @@ impl Partition {
         Ok(p)
     }
 
+    pub fn key_distinct(&self) -> &Option<Vec<u64>> {
+        &self.key_distinct
+    }
+
+    pub fn update_key_distinct(&self, key_distinct: Option<Vec<u64>>) -> Partition {
+        let mut p = self.clone();
+        p.key_distinct = key_distinct;
+        p
+    }
+
+    pub fn column_min(&self) -> &Option<Row> {
+        &self.column_min
+    }
+
+    pub fn column_max(&self) -> &Option<Row> {
+        &self.column_max
+    }
+
+    pub fn update_column_min_max(
+        &self,
+        column_min: Option<Row>,
+        column_max: Option<Row>,
+    ) -> Partition {
+        let mut p = self.clone();
+        p.column_min = column_min;
+        p.column_max = column_max;
+        p
+    }
+
     pub fn get_index_id(&self) -> u64 {
         self.index_id
     }
 
+    pub fn time_bucket(&self) -> Option<i64> {
+        self.time_bucket
+    }
+
     pub fn multi_partition_id(&self) -> Option<u64> {
         self.multi_partition_id
     }
@@ -74,7 +74,9 @@ enum_from_primitive! {
         ReplayHandles = 0x0B00,
         CacheItems = 0x0C00,
         QueueItems = 0x0D00,
-        QueueResults = 0x0E00
+        QueueResults = 0x0E00,
+        Nodes = 0x0F00,
+        ResultCacheItems = 0x1000
     }
 }
 
@@ -95,6 +97,8 @@ impl TableId {
             TableId::CacheItems => true,
             TableId::QueueItems => true,
             TableId::QueueResults => true,
+            TableId::Nodes => false,
+            TableId::ResultCacheItems => true,
         }
     }
 }
@@ -826,7 +830,7 @@ impl RocksStore {
         *self.last_check_seq.read().await
     }
 
-    fn get_store_path(&self, checkpoint_time: &SystemTime) -> String {
+    pub fn get_store_path(&self, checkpoint_time: &SystemTime) -> String {
         format!(
             "{}-{}",
             self.details.get_name(),
@@ -6,12 +6,16 @@ use crate::metastore::{IdRow, ImportFormat, Schema};
 use crate::queryplanner::udfs::aggregate_udf_by_kind;
 use crate::queryplanner::udfs::CubeAggregateUDFKind;
 use crate::rocks_table_impl;
+use crate::table::TimestampValue;
 use crate::{base_rocks_secondary_index, CubeError};
+use arrow::datatypes::DataType;
 use arrow::datatypes::Schema as ArrowSchema;
 use byteorder::{BigEndian, WriteBytesExt};
 use chrono::DateTime;
 use chrono::Utc;
-use datafusion::physical_plan::expressions::{Column as FusionColumn, Max, Min, Sum};
+use datafusion::physical_plan::expressions::{
+    cast, is_not_null, Column as FusionColumn, Max, Min, Sum,
+};
 use datafusion::physical_plan::{udaf, AggregateExpr, PhysicalExpr};
 use itertools::Itertools;
 
@@ -51,11 +55,22 @@ impl DataFrameValue<String> for Vec<AggregateColumnIndex> {
 pub struct AggregateColumn {
     column: Column,
     function: AggregateFunction,
+    /// Orders the values of `LAST_VALUE`, see [Table::last_value_seq_column].
+    #[serde(default)]
+    seq_column: Option<Column>,
 }
 
 impl AggregateColumn {
     pub fn new(column: Column, function: AggregateFunction) -> Self {
-        Self { column, function }
+        Self {
+            column,
+            function,
+            seq_column: None,
+        }
+    }
+
+    pub fn with_seq_column(self, seq_column: Option<Column>) -> Self {
+        Self { seq_column, ..self }
     }
 
     pub fn column(&self) -> &Column {
@@ -66,6 +81,8 @@ impl AggregateColumn {
         &self.function
     }
 
+    /// Expression that merges rows of the aggregate index, i.e. rows that were already
+    /// aggregated with [Self::initial_aggregate_expr].
     pub fn aggregate_expr(
         &self,
         schema: &ArrowSchema,
@@ -75,7 +92,8 @@ impl AggregateColumn {
             &schema,
         )?);
         let res: Arc<dyn AggregateExpr> = match self.function {
-            AggregateFunction::SUM => {
+            // Counts are merged by summing them up.
+            AggregateFunction::SUM | AggregateFunction::COUNT => {
                 Arc::new(Sum::new(col.clone(), col.name(), col.data_type(schema)?))
             }
             AggregateFunction::MAX => {
@@ -85,12 +103,60 @@ impl AggregateColumn {
                 Arc::new(Min::new(col.clone(), col.name(), col.data_type(schema)?))
             }
             AggregateFunction::MERGE => {
-                let fun = aggregate_udf_by_kind(CubeAggregateUDFKind::MergeHll).descriptor();
+                let kind = match self.column.get_column_type() {
+                    ColumnType::ThetaSketch => CubeAggregateUDFKind::ThetaUnion,
+                    _ => CubeAggregateUDFKind::MergeHll,
+                };
+                let fun = aggregate_udf_by_kind(kind).descriptor();
+                udaf::create_aggregate_expr(&fun, &[col.clone()], schema, col.name())?
+            }
+            AggregateFunction::ANY_VALUE => {
+                let fun = aggregate_udf_by_kind(CubeAggregateUDFKind::AnyValue).descriptor();
+                udaf::create_aggregate_expr(&fun, &[col.clone()], schema, col.name())?
+            }
+            AggregateFunction::LAST_VALUE => {
+                // Values are picked by the seq column, which is aggregated with `MAX`.
+                let seq_column = self.seq_column.as_ref().ok_or_else(|| {
+                    CubeError::internal(format!(
+                        "Seq column is not defined for LAST_VALUE({})",
+                        self.column.get_name()
+                    ))
+                })?;
+                let seq: Arc<dyn PhysicalExpr> = Arc::new(FusionColumn::new_with_schema(
+                    seq_column.get_name().as_str(),
+                    &schema,
+                )?);
+                let fun = aggregate_udf_by_kind(CubeAggregateUDFKind::LastValue).descriptor();
+                udaf::create_aggregate_expr(&fun, &[col.clone(), seq], schema, col.name())?
+            }
+            AggregateFunction::BIT_OR => {
+                let fun = aggregate_udf_by_kind(CubeAggregateUDFKind::BitOr).descriptor();
                 udaf::create_aggregate_expr(&fun, &[col.clone()], schema, col.name())?
             }
         };
         Ok(res)
     }
+
+    /// Expression that aggregates raw table rows into rows of the aggregate index.
+    /// Differs from [Self::aggregate_expr] only for `COUNT`.
+    pub fn initial_aggregate_expr(
+        &self,
+        schema: &ArrowSchema,
+    ) -> Result<Arc<dyn AggregateExpr>, CubeError> {
+        match self.function {
+            AggregateFunction::COUNT => {
+                let col = Arc::new(FusionColumn::new_with_schema(
+                    self.column.get_name().as_str(),
+                    &schema,
+                )?);
+                // Count is stored in the same Int64 column, so we sum up `col IS NOT NULL`
+                // instead of using `COUNT` that produces UInt64.
+                let not_null = cast(is_not_null(col.clone())?, schema, DataType::Int64)?;
+                Ok(Arc::new(Sum::new(not_null, col.name(), DataType::Int64)))
+            }
+            _ => self.aggregate_expr(schema),
+        }
+    }
 }
 
 impl core::fmt::Display for AggregateColumn {
@@ -117,6 +183,179 @@ impl DataFrameValue<String> for Option<StreamOffset> {
     }
 }
 
+/// Definition of a materialized view. The view is stored as a regular table with an aggregate
+/// default index and receives new rows together with its source table.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
+pub struct MaterializedView {
+    source_table_id: u64,
+    /// Name of the source column for each column of the view.
+    source_columns: Vec<String>,
+    definition: String,
+    #[serde(default)]
+    last_refresh_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    source_last_insert_at: Option<DateTime<Utc>>,
+}
+
+impl MaterializedView {
+    pub fn new(source_table_id: u64, source_columns: Vec<String>, definition: String) -> Self {
+        Self {
+            source_table_id,
+            source_columns,
+            definition,
+            last_refresh_at: None,
+            source_last_insert_at: None,
+        }
+    }
+
+    pub fn source_table_id(&self) -> u64 {
+        self.source_table_id
+    }
+
+    pub fn source_columns(&self) -> &Vec<String> {
+        &self.source_columns
+    }
+
+    pub fn definition(&self) -> &String {
+        &self.definition
+    }
+
+    pub fn last_refresh_at(&self) -> &Option<DateTime<Utc>> {
+        &self.last_refresh_at
+    }
+
+    pub fn source_last_insert_at(&self) -> &Option<DateTime<Utc>> {
+        &self.source_last_insert_at
+    }
+
+    /// Time passed between the last insert into the source table and the last refresh of the view.
+    pub fn lag_seconds(&self) -> Option<i64> {
+        match (&self.source_last_insert_at, &self.last_refresh_at) {
+            (Some(insert), Some(refresh)) => Some((*insert - *refresh).num_seconds().max(0)),
+            (Some(insert), None) => Some((Utc::now() - *insert).num_seconds().max(0)),
+            (None, _) => None,
+        }
+    }
+
+    pub fn update_refreshed(&self, source_last_insert_at: DateTime<Utc>, refreshed: bool) -> Self {
+        let mut view = self.clone();
+        view.source_last_insert_at = Some(source_last_insert_at);
+        if refreshed {
+            view.last_refresh_at = Some(source_last_insert_at);
+        }
+        view
+    }
+}
+
+impl DataFrameValue<String> for Option<MaterializedView> {
+    fn value(v: &Self) -> String {
+        v.as_ref()
+            .map(|v| v.definition.clone())
+            .unwrap_or("NULL".to_string())
+    }
+}
+
+/// Forces partition boundaries of a table on time buckets of one of its timestamp columns, see
+/// [Partition::time_bucket](crate::metastore::Partition::time_bucket).
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
+pub struct TimePartition {
+    column_index: u64,
+    /// Buckets are aligned to the unix epoch.
+    interval_secs: i64,
+    /// Partitions of buckets that ended more than this ago are dropped.
+    retention_secs: Option<i64>,
+}
+
+impl TimePartition {
+    pub fn new(column_index: u64, interval_secs: i64, retention_secs: Option<i64>) -> Self {
+        Self {
+            column_index,
+            interval_secs,
+            retention_secs,
+        }
+    }
+
+    pub fn column_index(&self) -> u64 {
+        self.column_index
+    }
+
+    pub fn interval_secs(&self) -> i64 {
+        self.interval_secs
+    }
+
+    pub fn retention_secs(&self) -> Option<i64> {
+        self.retention_secs
+    }
+
+    /// Start of the bucket containing `unix_micros`, in seconds.
+    pub fn bucket_of(&self, unix_micros: i64) -> i64 {
+        self.bucket_of_secs(unix_micros.div_euclid(1_000_000))
+    }
+
+    fn bucket_of_secs(&self, unix_secs: i64) -> i64 {
+        unix_secs - unix_secs.rem_euclid(self.interval_secs)
+    }
+
+    /// First and last timestamps of `bucket`, both inclusive.
+    pub fn bucket_range(&self, bucket: i64) -> (TimestampValue, TimestampValue) {
+        let start = bucket.saturating_mul(1_000_000_000);
+        let end = bucket
+            .saturating_add(self.interval_secs)
+            .saturating_mul(1_000_000_000);
+        // Timestamps have microsecond precision.
+        (TimestampValue::new(start), TimestampValue::new(end - 1_000))
+    }
+
+    /// Buckets that start before the returned one are past retention. Retention that goes
+    /// beyond the supported time range keeps all buckets.
+    pub fn retained_since(&self, now: DateTime<Utc>) -> Option<i64> {
+        let since = now.timestamp().checked_sub(self.retention_secs?)?;
+        Some(self.bucket_of_secs(since))
+    }
+
+    /// Parses intervals like `'1 day'` or `'6 hours'`.
+    pub fn parse_interval(interval: &str) -> Result<i64, CubeError> {
+        let bad_interval = || {
+            CubeError::user(format!(
+                "Bad interval '{}', expected a number followed by second, minute, hour, day or week",
+                interval
+            ))
+        };
+        let mut parts = interval.split_whitespace();
+        let (count, unit) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(count), Some(unit), None) => (count, unit.to_lowercase()),
+            _ => return Err(bad_interval()),
+        };
+        let count = count.parse::<i64>().map_err(|_| bad_interval())?;
+        let unit_secs = match unit.trim_end_matches('s') {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            _ => return Err(bad_interval()),
+        };
+        match count.checked_mul(unit_secs) {
+            Some(secs) if 0 < secs => Ok(secs),
+            _ => Err(bad_interval()),
+        }
+    }
+}
+
+impl DataFrameValue<String> for Option<TimePartition> {
+    fn value(v: &Self) -> String {
+        v.as_ref()
+            .map(|v| match v.retention_secs {
+                Some(retention) => format!(
+                    "time_bucket({}, {}s) retention {}s",
+                    v.column_index, v.interval_secs, retention
+                ),
+                None => format!("time_bucket({}, {}s)", v.column_index, v.interval_secs),
+            })
+            .unwrap_or("NULL".to_string())
+    }
+}
+
 data_frame_from! {
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
 pub struct Table {
@@ -151,7 +390,11 @@ pub struct Table {
     #[serde(default)]
     location_download_sizes: Option<Vec<u64>>,
     #[serde(default)]
-    partition_split_threshold: Option<u64>
+    partition_split_threshold: Option<u64>,
+    #[serde(default)]
+    materialized_view: Option<MaterializedView>,
+    #[serde(default)]
+    time_partition: Option<TimePartition>
 }
 }
 
@@ -170,6 +413,8 @@ impl TablePath {
 }
 
 impl Table {
+    pub const LAST_VALUE_SEQ_COLUMN: &'static str = "__seq";
+
     pub fn new(
         table_name: String,
         schema_id: u64,
@@ -185,6 +430,7 @@ impl Table {
         aggregate_column_indices: Vec<AggregateColumnIndex>,
         seq_column_index: Option<u64>,
         partition_split_threshold: Option<u64>,
+        time_partition: Option<TimePartition>,
     ) -> Table {
         let location_download_sizes = locations.as_ref().map(|locations| vec![0; locations.len()]);
         Table {
@@ -206,6 +452,8 @@ impl Table {
             seq_column_index,
             location_download_sizes,
             partition_split_threshold,
+            materialized_view: None,
+            time_partition,
         }
     }
     pub fn get_columns(&self) -> &Vec<Column> {
@@ -320,7 +568,16 @@ impl Table {
         self.aggregate_column_indices
             .iter()
             .map(|v| {
-                AggregateColumn::new(self.columns[v.index as usize].clone(), v.function.clone())
+                let column = AggregateColumn::new(
+                    self.columns[v.index as usize].clone(),
+                    v.function.clone(),
+                );
+                match v.function {
+                    AggregateFunction::LAST_VALUE => {
+                        column.with_seq_column(self.last_value_seq_column().cloned())
+                    }
+                    _ => column,
+                }
             })
             .collect()
     }
@@ -335,6 +592,17 @@ impl Table {
             .map(|c| &self.columns[*c as usize])
     }
 
+    /// Column that orders the values of `LAST_VALUE` aggregations. Tables with a unique key use
+    /// their seq column, other tables have to declare [Table::LAST_VALUE_SEQ_COLUMN] explicitly and
+    /// fill it on insert.
+    pub fn last_value_seq_column(&self) -> Option<&Column> {
+        self.seq_column().or_else(|| {
+            self.columns
+                .iter()
+                .find(|c| c.get_name() == Self::LAST_VALUE_SEQ_COLUMN)
+        })
+    }
+
     pub fn in_memory_ingest(&self) -> bool {
         self.seq_column_index.is_some()
     }
@@ -379,6 +647,30 @@ impl Table {
     pub fn stream_offset(&self) -> &Option<StreamOffset> {
         &self.stream_offset
     }
+
+    pub fn materialized_view(&self) -> &Option<MaterializedView> {
+        &self.materialized_view
+    }
+
+    pub fn is_materialized_view(&self) -> bool {
+        self.materialized_view.is_some()
+    }
+
+    pub fn update_materialized_view(&self, materialized_view: MaterializedView) -> Self {
+        let mut table = self.clone();
+        table.materialized_view = Some(materialized_view);
+        table
+    }
+
+    pub fn time_partition(&self) -> &Option<TimePartition> {
+        &self.time_partition
+    }
+
+    pub fn time_partition_column(&self) -> Option<&Column> {
+        self.time_partition
+            .as_ref()
+            .map(|t| &self.columns[t.column_index as usize])
+    }
 }
 
 impl Column {
@@ -411,17 +703,23 @@ impl Column {
 }
 
 rocks_table_impl!(Table, TableRocksTable, TableId::Tables, {
-    vec![Box::new(TableRocksIndex::Name)]
+    vec![
+        Box::new(TableRocksIndex::Name),
+        Box::new(TableRocksIndex::MaterializedViewSource),
+    ]
 });
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum TableRocksIndex {
     Name = 1,
+    MaterializedViewSource = 2,
 }
 
 #[derive(Hash, Clone, Debug)]
 pub enum TableIndexKey {
     ByName(u64, String),
+    /// Id of the source table for materialized views, `None` for other tables.
+    ByMaterializedViewSource(Option<u64>),
 }
 
 base_rocks_secondary_index!(Table, TableRocksIndex);
@@ -432,6 +730,9 @@ impl RocksSecondaryIndex<Table, TableIndexKey> for TableRocksIndex {
             TableRocksIndex::Name => {
                 TableIndexKey::ByName(row.schema_id, row.table_name.to_string())
             }
+            TableRocksIndex::MaterializedViewSource => TableIndexKey::ByMaterializedViewSource(
+                row.materialized_view.as_ref().map(|v| v.source_table_id()),
+            ),
         }
     }
 
@@ -443,18 +744,30 @@ impl RocksSecondaryIndex<Table, TableIndexKey> for TableRocksIndex {
                 buf.write_all(table_name.as_bytes()).unwrap();
                 buf
             }
+            TableIndexKey::ByMaterializedViewSource(source_table_id) => {
+                let mut buf = Vec::with_capacity(9);
+                if let Some(source_table_id) = source_table_id {
+                    buf.write_u8(1).unwrap();
+                    buf.write_u64::<BigEndian>(*source_table_id).unwrap();
+                } else {
+                    buf.write_u8(0).unwrap();
+                }
+                buf
+            }
         }
     }
 
     fn is_unique(&self) -> bool {
         match self {
             TableRocksIndex::Name => true,
+            TableRocksIndex::MaterializedViewSource => false,
         }
     }
 
     fn version(&self) -> u32 {
         match self {
             TableRocksIndex::Name => 1,
+            TableRocksIndex::MaterializedViewSource => 1,
         }
     }
 
@@ -0,0 +1,526 @@
+use crate::config::ConfigObj;
+use crate::mysql::SqlAuthService;
+use crate::sql::permissions::Role;
+use crate::CubeError;
+use async_trait::async_trait;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Entry of the users file. The file is a JSON object keyed by user name, e.g.
+/// `{"reader": {"mysql_native_password": "*<hex>", "role": "read_only"}}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// Plain text password, the MySQL handshake can only check clients against it.
+    pub password: Option<String>,
+    /// `*` followed by hex encoded SHA1(SHA1(password)), the value MySQL keeps for its
+    /// `mysql_native_password` plugin. Checked by `check_native_password` and HTTP basic auth.
+    pub mysql_native_password: Option<String>,
+    /// Hex encoded SHA-256 of the password, only HTTP basic auth can be checked against it.
+    pub password_sha256: Option<String>,
+    pub role: Role,
+}
+
+/// JSON Web Key, only RSA and symmetric (`oct`) keys are supported.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    pub alg: Option<Algorithm>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+    pub k: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Claims {
+    sub: String,
+    role: Option<Role>,
+}
+
+/// Users and their roles configured with `CUBESTORE_AUTH_USERS_FILE`, bearer tokens of HTTP
+/// clients are verified with keys from `CUBESTORE_AUTH_JWKS_FILE`. Tokens must have `sub` and
+/// `exp` claims, the role is taken from the `role` claim or from the users file.
+pub struct SqlAuthConfigImpl {
+    users: HashMap<String, UserConfig>,
+    jwks: Option<JwkSet>,
+}
+
+crate::di_service!(SqlAuthConfigImpl, [SqlAuthService]);
+
+impl SqlAuthConfigImpl {
+    pub fn from_config(config: &dyn ConfigObj) -> Result<SqlAuthConfigImpl, CubeError> {
+        let users = match config.auth_users_file() {
+            Some(path) => read_json(path)?,
+            None => HashMap::new(),
+        };
+        let jwks = match config.auth_jwks_file() {
+            Some(path) => Some(read_json(path)?),
+            None => None,
+        };
+        SqlAuthConfigImpl::new(users, jwks)
+    }
+
+    pub fn new(
+        users: HashMap<String, UserConfig>,
+        jwks: Option<JwkSet>,
+    ) -> Result<SqlAuthConfigImpl, CubeError> {
+        for (name, user) in users.iter() {
+            if user.password.is_none()
+                && user.mysql_native_password.is_none()
+                && user.password_sha256.is_none()
+            {
+                return Err(CubeError::user(format!(
+                    "One of password, mysql_native_password or password_sha256 should be set for user '{}'",
+                    name
+                )));
+            }
+            if let Some(hash) = &user.mysql_native_password {
+                if decode_native_password(hash).is_none() {
+                    return Err(CubeError::user(format!(
+                        "Invalid mysql_native_password for user '{}': expected '*' and 40 hex digits",
+                        name
+                    )));
+                }
+            }
+            if let Some(hash) = &user.password_sha256 {
+                if hex::decode(hash).map(|h| h.len()).ok() != Some(32) {
+                    return Err(CubeError::user(format!(
+                        "Invalid password_sha256 for user '{}': expected 64 hex digits",
+                        name
+                    )));
+                }
+            }
+        }
+        if let Some(jwks) = &jwks {
+            for key in jwks.keys.iter() {
+                let valid = match key.kty.as_str() {
+                    "RSA" => key.n.is_some() && key.e.is_some(),
+                    "oct" => key.k.is_some(),
+                    _ => false,
+                };
+                if !valid {
+                    return Err(CubeError::user(format!(
+                        "Unsupported JSON Web Key{}: only RSA keys with 'n' and 'e' and oct keys with 'k' are supported",
+                        key.kid
+                            .as_ref()
+                            .map(|k| format!(" '{}'", k))
+                            .unwrap_or_default()
+                    )));
+                }
+            }
+        }
+        Ok(SqlAuthConfigImpl { users, jwks })
+    }
+
+    fn user(&self, user: &Option<String>) -> Result<&UserConfig, CubeError> {
+        let name = user
+            .as_ref()
+            .ok_or_else(|| CubeError::user("User is required".to_string()))?;
+        self.users
+            .get(name)
+            .ok_or_else(|| CubeError::user(format!("Unknown user '{}'", name)))
+    }
+
+    fn find_key(&self, kid: &Option<String>) -> Result<&Jwk, CubeError> {
+        let keys = match &self.jwks {
+            Some(jwks) => &jwks.keys,
+            None => {
+                return Err(CubeError::user(
+                    "Bearer tokens require CUBESTORE_AUTH_JWKS_FILE to be set".to_string(),
+                ))
+            }
+        };
+        match kid {
+            Some(kid) => keys.iter().find(|k| k.kid.as_ref() == Some(kid)),
+            None if keys.len() == 1 => keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| {
+            CubeError::user(format!(
+                "No JSON Web Key found for token with kid {:?}",
+                kid
+            ))
+        })
+    }
+}
+
+#[async_trait]
+impl SqlAuthService for SqlAuthConfigImpl {
+    async fn authenticate(&self, user: Option<String>) -> Result<Option<String>, CubeError> {
+        match &self.user(&user)?.password {
+            Some(password) => Ok(Some(password.to_string())),
+            None => Err(CubeError::user(format!(
+                "User '{}' has no plain text password and can't log in over MySQL protocol",
+                user.unwrap_or_default()
+            ))),
+        }
+    }
+
+    async fn check_password(
+        &self,
+        user: Option<String>,
+        password: Option<String>,
+    ) -> Result<(), CubeError> {
+        let config = self.user(&user)?;
+        let password = password.unwrap_or_default();
+        let (actual, expected) = match (
+            &config.password,
+            &config.mysql_native_password,
+            &config.password_sha256,
+        ) {
+            (_, _, Some(hash)) => (
+                Sha256::digest(password.as_bytes()).to_vec(),
+                hex::decode(hash)?,
+            ),
+            (_, Some(hash), None) => (
+                native_password_hash(password.as_bytes()),
+                decode_native_password(hash).unwrap_or_default(),
+            ),
+            (Some(expected), None, None) => (
+                Sha256::digest(password.as_bytes()).to_vec(),
+                Sha256::digest(expected.as_bytes()).to_vec(),
+            ),
+            (None, None, None) => (Vec::new(), vec![0]),
+        };
+        if constant_time_eq(actual.as_slice(), expected.as_slice()) {
+            Ok(())
+        } else {
+            Err(CubeError::user(
+                "User or password doesn't match".to_string(),
+            ))
+        }
+    }
+
+    async fn check_native_password(
+        &self,
+        user: Option<String>,
+        nonce: Vec<u8>,
+        auth_response: Vec<u8>,
+    ) -> Result<(), CubeError> {
+        let config = self.user(&user)?;
+        let expected = match (&config.mysql_native_password, &config.password) {
+            (Some(hash), _) => decode_native_password(hash).unwrap_or_default(),
+            (None, Some(password)) => native_password_hash(password.as_bytes()),
+            (None, None) => {
+                return Err(CubeError::user(format!(
+                    "User '{}' has only password_sha256 set and can't log in over MySQL protocol",
+                    user.unwrap_or_default()
+                )))
+            }
+        };
+        // The client sends SHA1(password) XOR SHA1(nonce + SHA1(SHA1(password))).
+        let mask = Sha1::new().chain(&nonce).chain(&expected).finalize();
+        let password_sha1 = auth_response
+            .iter()
+            .zip(mask.iter())
+            .map(|(r, m)| r ^ m)
+            .collect::<Vec<_>>();
+        if auth_response.len() == mask.len()
+            && constant_time_eq(Sha1::digest(&password_sha1).as_slice(), expected.as_slice())
+        {
+            Ok(())
+        } else {
+            Err(CubeError::user(
+                "User or password doesn't match".to_string(),
+            ))
+        }
+    }
+
+    async fn authenticate_token(&self, token: String) -> Result<(String, Role), CubeError> {
+        let header = decode_header(&token)
+            .map_err(|e| CubeError::user(format!("Invalid bearer token: {}", e)))?;
+        let key = self.find_key(&header.kid)?;
+        if matches!(key.alg, Some(alg) if alg != header.alg) {
+            return Err(CubeError::user(format!(
+                "Token algorithm {:?} doesn't match the key",
+                header.alg
+            )));
+        }
+        let secret;
+        let decoding_key = match (key.kty.as_str(), header.alg) {
+            (
+                "RSA",
+                Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512,
+            ) => DecodingKey::from_rsa_components(
+                key.n.as_deref().unwrap_or_default(),
+                key.e.as_deref().unwrap_or_default(),
+            ),
+            ("oct", Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) => {
+                secret = base64::decode_config(
+                    key.k.as_deref().unwrap_or_default(),
+                    base64::URL_SAFE_NO_PAD,
+                )?;
+                DecodingKey::from_secret(&secret)
+            }
+            (kty, alg) => {
+                return Err(CubeError::user(format!(
+                    "Token algorithm {:?} can't be used with {} key",
+                    alg, kty
+                )))
+            }
+        };
+        let claims = decode::<Claims>(&token, &decoding_key, &Validation::new(header.alg))
+            .map_err(|e| CubeError::user(format!("Invalid bearer token: {}", e)))?
+            .claims;
+        let role = match claims.role {
+            Some(role) => role,
+            None => match self.users.get(&claims.sub) {
+                Some(user) => user.role,
+                None => {
+                    return Err(CubeError::user(format!(
+                        "Token of '{}' has no role claim and the user is unknown",
+                        claims.sub
+                    )))
+                }
+            },
+        };
+        Ok((claims.sub, role))
+    }
+
+    async fn role(&self, user: Option<String>) -> Result<Option<Role>, CubeError> {
+        Ok(Some(self.user(&user)?.role))
+    }
+}
+
+/// Compares hashes without returning early, so response time doesn't tell how many leading bytes
+/// of the password hash matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// SHA1(SHA1(password)), the `mysql_native_password` hash.
+fn native_password_hash(password: &[u8]) -> Vec<u8> {
+    Sha1::digest(Sha1::digest(password).as_slice()).to_vec()
+}
+
+fn decode_native_password(hash: &str) -> Option<Vec<u8>> {
+    let hash = hex::decode(hash.strip_prefix('*')?).ok()?;
+    if hash.len() == 20 {
+        Some(hash)
+    } else {
+        None
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, CubeError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| CubeError::user(format!("Can't read {}: {}", path.display(), e)))?;
+    serde_json::from_str(&content)
+        .map_err(|e| CubeError::user(format!("Can't parse {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[derive(Serialize)]
+    struct TestClaims {
+        sub: String,
+        role: Option<Role>,
+        exp: u64,
+    }
+
+    fn auth() -> SqlAuthConfigImpl {
+        let users = serde_json::from_str(&format!(
+            r#"{{
+                "admin": {{ "password": "secret", "role": "admin" }},
+                "reader": {{ "password_sha256": "{}", "role": "read_only" }},
+                "writer": {{
+                    "mysql_native_password": "*14E65567ABDB5135D0CFD9A70B3032C179A49EE7",
+                    "role": "data_writer"
+                }}
+            }}"#,
+            hex::encode(Sha256::digest(b"reader-secret"))
+        ))
+        .unwrap();
+        let jwks = serde_json::from_str(&format!(
+            r#"{{ "keys": [{{ "kty": "oct", "kid": "key-1", "alg": "HS256", "k": "{}" }}] }}"#,
+            base64::encode_config(b"jwt-secret", base64::URL_SAFE_NO_PAD)
+        ))
+        .unwrap();
+        SqlAuthConfigImpl::new(users, Some(jwks)).unwrap()
+    }
+
+    fn token(kid: &str, secret: &[u8], sub: &str, role: Option<Role>, exp_offset: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(kid.to_string());
+        let claims = TestClaims {
+            sub: sub.to_string(),
+            role,
+            exp: (now + exp_offset) as u64,
+        };
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn passwords() {
+        let auth = auth();
+        let user = |u: &str| Some(u.to_string());
+
+        assert_eq!(
+            auth.authenticate(user("admin")).await.unwrap(),
+            Some("secret".to_string())
+        );
+        // MySQL protocol needs the password itself.
+        assert!(auth.authenticate(user("reader")).await.is_err());
+        assert!(auth.authenticate(user("writer")).await.is_err());
+        assert!(auth.authenticate(user("unknown")).await.is_err());
+        assert!(auth.authenticate(None).await.is_err());
+
+        auth.check_password(user("admin"), user("secret"))
+            .await
+            .unwrap();
+        auth.check_password(user("reader"), user("reader-secret"))
+            .await
+            .unwrap();
+        assert!(auth
+            .check_password(user("reader"), user("secret"))
+            .await
+            .is_err());
+        auth.check_password(user("writer"), user("secret"))
+            .await
+            .unwrap();
+        assert!(auth
+            .check_password(user("writer"), user("reader-secret"))
+            .await
+            .is_err());
+        assert!(auth.check_password(user("admin"), None).await.is_err());
+        assert!(auth.check_password(None, None).await.is_err());
+
+        assert_eq!(
+            auth.role(user("reader")).await.unwrap(),
+            Some(Role::ReadOnly)
+        );
+        assert!(auth.role(user("unknown")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn native_passwords() {
+        let auth = auth();
+        let user = |u: &str| Some(u.to_string());
+        let nonce = b"0123456789abcdefghij".to_vec();
+        // Scrambled the way MySQL clients do it.
+        let response = |password: &[u8]| {
+            let mask = Sha1::new()
+                .chain(&nonce)
+                .chain(native_password_hash(password))
+                .finalize();
+            Sha1::digest(password)
+                .iter()
+                .zip(mask.iter())
+                .map(|(p, m)| p ^ m)
+                .collect::<Vec<_>>()
+        };
+
+        for name in ["admin", "writer"] {
+            auth.check_native_password(user(name), nonce.clone(), response(b"secret"))
+                .await
+                .unwrap();
+            assert!(auth
+                .check_native_password(user(name), nonce.clone(), response(b"other"))
+                .await
+                .is_err());
+            assert!(auth
+                .check_native_password(user(name), b"other nonce".to_vec(), response(b"secret"))
+                .await
+                .is_err());
+            assert!(auth
+                .check_native_password(user(name), nonce.clone(), Vec::new())
+                .await
+                .is_err());
+        }
+        assert!(auth
+            .check_native_password(user("reader"), nonce.clone(), response(b"reader-secret"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn tokens() {
+        let auth = auth();
+
+        let t = token("key-1", b"jwt-secret", "app", Some(Role::CacheQueue), 60);
+        assert_eq!(
+            auth.authenticate_token(t).await.unwrap(),
+            ("app".to_string(), Role::CacheQueue)
+        );
+        // Role of known users can be omitted in the token.
+        let t = token("key-1", b"jwt-secret", "reader", None, 60);
+        assert_eq!(
+            auth.authenticate_token(t).await.unwrap(),
+            ("reader".to_string(), Role::ReadOnly)
+        );
+
+        for t in [
+            token("key-1", b"jwt-secret", "app", None, 60),
+            token("key-1", b"other-secret", "app", Some(Role::Admin), 60),
+            token("key-2", b"jwt-secret", "app", Some(Role::Admin), 60),
+            token("key-1", b"jwt-secret", "app", Some(Role::Admin), -3600),
+            "not a token".to_string(),
+        ] {
+            assert!(auth.authenticate_token(t).await.is_err());
+        }
+    }
+
+    #[test]
+    fn compare_hashes() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let user = |json: &str| -> HashMap<String, UserConfig> {
+            serde_json::from_str(&format!(r#"{{ "foo": {} }}"#, json)).unwrap()
+        };
+        assert!(SqlAuthConfigImpl::new(user(r#"{ "role": "admin" }"#), None).is_err());
+        assert!(SqlAuthConfigImpl::new(
+            user(r#"{ "password_sha256": "abc", "role": "admin" }"#),
+            None
+        )
+        .is_err());
+        assert!(SqlAuthConfigImpl::new(
+            user(r#"{ "mysql_native_password": "14E65567ABDB5135D0CFD9A70B3032C179A49EE7", "role": "admin" }"#),
+            None
+        )
+        .is_err());
+        assert!(serde_json::from_str::<HashMap<String, UserConfig>>(
+            r#"{ "foo": { "password": "a", "role": "superuser" } }"#
+        )
+        .is_err());
+
+        let jwks =
+            serde_json::from_str(r#"{ "keys": [{ "kty": "EC", "x": "a", "y": "b" }] }"#).unwrap();
+        assert!(SqlAuthConfigImpl::new(HashMap::new(), Some(jwks)).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::config::processing_loop::ProcessingLoop;
-use crate::sql::{InlineTables, SqlQueryContext, SqlService};
+use crate::sql::permissions::Role;
+use crate::sql::{InlineTables, SqlQueryContext, SqlService, SqlSession};
 use crate::table::TableValue;
 use crate::util::time_span::warn_long;
 use crate::{metastore, CubeError};
@@ -17,10 +18,14 @@ use std::time::SystemTime;
 use tokio::net::TcpListener;
 use tokio::sync::{watch, RwLock};
 
+pub mod auth;
+
 struct Backend {
     sql_service: Arc<dyn SqlService>,
     auth: Arc<dyn SqlAuthService>,
     user: Option<String>,
+    role: Option<Role>,
+    session: Arc<SqlSession>,
 }
 
 #[async_trait]
@@ -61,8 +66,11 @@ impl<W: io::Write + Send> AsyncMysqlShim<W> for Backend {
             .exec_query_with_context(
                 SqlQueryContext {
                     user: self.user.clone(),
+                    role: self.role,
                     inline_tables: InlineTables::new(),
                     trace_obj: None,
+                    workload_class: self.session.workload_class(),
+                    session: Some(self.session.clone()),
                 },
                 query,
             )
@@ -92,6 +100,7 @@ impl<W: io::Write + Send> AsyncMysqlShim<W> for Backend {
                     metastore::ColumnType::Boolean => ColumnType::MYSQL_TYPE_STRING,
                     metastore::ColumnType::Bytes => ColumnType::MYSQL_TYPE_STRING,
                     metastore::ColumnType::HyperLogLog(_) => ColumnType::MYSQL_TYPE_STRING,
+                    metastore::ColumnType::ThetaSketch => ColumnType::MYSQL_TYPE_STRING,
                     metastore::ColumnType::Float => ColumnType::MYSQL_TYPE_STRING,
                 },
                 colflags: ColumnFlags::empty(),
@@ -143,11 +152,18 @@ impl<W: io::Write + Send> AsyncMysqlShim<W> for Backend {
         } else {
             None
         };
-        self.auth
+        let password = self
+            .auth
             .authenticate(self.user.clone())
             .await
-            .map(|p| p.map(|p| p.as_bytes().to_vec()))
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        // Queries are accepted only after the password is checked against the returned one.
+        self.role = self
+            .auth
+            .role(self.user.clone())
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        Ok(password.map(|p| p.as_bytes().to_vec()))
     }
 }
 
@@ -197,6 +213,8 @@ impl ProcessingLoop for MySqlServer {
                         sql_service,
                         auth,
                         user: None,
+                        role: None,
+                        session: Arc::new(SqlSession::default()),
                     },
                     socket,
                 )
@@ -234,7 +252,42 @@ impl MySqlServer {
 #[automock]
 #[async_trait]
 pub trait SqlAuthService: Send + Sync {
+    /// Returns the password expected from `user` over MySQL protocol, any password is accepted
+    /// when [None] is returned.
     async fn authenticate(&self, user: Option<String>) -> Result<Option<String>, CubeError>;
+
+    /// Checks the password sent in clear text with HTTP basic auth.
+    async fn check_password(
+        &self,
+        _user: Option<String>,
+        _password: Option<String>,
+    ) -> Result<(), CubeError> {
+        Ok(())
+    }
+
+    /// Checks the `mysql_native_password` response of a client to the handshake `nonce`, for
+    /// users whose plain text password isn't known to the server.
+    async fn check_native_password(
+        &self,
+        _user: Option<String>,
+        _nonce: Vec<u8>,
+        _auth_response: Vec<u8>,
+    ) -> Result<(), CubeError> {
+        Ok(())
+    }
+
+    /// Verifies a bearer token and returns the user it was issued to along with its role.
+    async fn authenticate_token(&self, _token: String) -> Result<(String, Role), CubeError> {
+        Err(CubeError::user(
+            "Bearer tokens require CUBESTORE_AUTH_JWKS_FILE to be set".to_string(),
+        ))
+    }
+
+    /// Role of an authenticated user, statements aren't checked against permissions when
+    /// [None] is returned.
+    async fn role(&self, _user: Option<String>) -> Result<Option<Role>, CubeError> {
+        Ok(None)
+    }
 }
 
 pub struct SqlAuthDefaultImpl;
@@ -0,0 +1,296 @@
+use crate::app_metrics;
+use crate::queryplanner::memory::batch_memory_size;
+use crate::queryplanner::serialized_plan::SerializedPlan;
+use crate::CubeError;
+use arrow::record_batch::RecordBatch;
+use datafusion::cube_ext;
+use datafusion::physical_plan::parquet::{NoopParquetMetadataCache, ParquetMetadataCache};
+use futures::future::join_all;
+use itertools::Itertools;
+use lru::LruCache;
+use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Decoded contents of partition and chunk files read by queries on this node, so queries on hot
+/// partitions skip reading and decoding parquet. Files never change once uploaded, so entries
+/// are keyed by the remote file name. Files of inactive partitions are dropped by the next query
+/// on the same index, the least recently used files are dropped to stay within the byte budget.
+/// Select workers run in separate processes, so each of them gets its own cache with an equal
+/// share of `CUBESTORE_BATCH_CACHE_MAX_CAPACITY_BYTES`.
+pub struct PartitionBatchCache {
+    /// Zero disables the cache.
+    max_bytes: usize,
+    batch_size: usize,
+    state: Mutex<CacheState>,
+}
+
+crate::di_service!(PartitionBatchCache, []);
+
+struct CacheState {
+    files: LruCache<String, CachedFile>,
+    bytes: usize,
+    /// Files being decoded by some query, other queries read them from disk meanwhile.
+    loading: HashSet<String>,
+}
+
+struct CachedFile {
+    index_id: u64,
+    partition_id: u64,
+    batches: Arc<Vec<RecordBatch>>,
+    bytes: usize,
+}
+
+impl PartitionBatchCache {
+    pub fn new(max_bytes: usize, batch_size: usize) -> Arc<PartitionBatchCache> {
+        Arc::new(PartitionBatchCache {
+            max_bytes,
+            batch_size,
+            state: Mutex::new(CacheState {
+                files: LruCache::unbounded(),
+                bytes: 0,
+                loading: HashSet::new(),
+            }),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes != 0
+    }
+
+    pub fn get(&self, remote_path: &str) -> Option<Arc<Vec<RecordBatch>>> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .files
+            .get(&remote_path.to_string())
+            .map(|f| f.batches.clone())
+    }
+
+    /// Number of cached files and their size in bytes.
+    pub fn usage(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.files.len(), state.bytes)
+    }
+
+    /// Drops files of partitions and chunks that are no longer active according to `plan`, then
+    /// starts decoding the files this worker reads for `plan` that are not in the cache yet.
+    /// Decoding runs in the background, the query that triggered it reads the files from disk.
+    /// Errors are only logged, queries report them on their own.
+    pub fn load(
+        self: &Arc<Self>,
+        plan: &SerializedPlan,
+        remote_to_local_names: &HashMap<String, String>,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        for index in plan.index_snapshots() {
+            let active_partitions = index.active_partition_ids();
+            // Pruned partitions come without chunks, so only files of the listed ones are known.
+            let partition_files = index
+                .partitions()
+                .iter()
+                .map(|p| {
+                    let partition = p.partition();
+                    let files = partition
+                        .get_row()
+                        .get_full_name(partition.get_id())
+                        .into_iter()
+                        .chain(
+                            p.chunks()
+                                .iter()
+                                .map(|c| c.get_row().get_full_name(c.get_id())),
+                        )
+                        .collect::<HashSet<_>>();
+                    (partition.get_id(), files)
+                })
+                .collect::<HashMap<_, _>>();
+            self.evict_inactive(index.index().get_id(), |partition_id, file| {
+                active_partitions.binary_search(&partition_id).is_ok()
+                    && partition_files
+                        .get(&partition_id)
+                        .map_or(true, |files| files.contains(file))
+            });
+        }
+
+        let mut to_load = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            for (partition, remote_path, file_size) in plan.files_to_download() {
+                if state.files.get(&remote_path).is_some() {
+                    continue;
+                }
+                // Decoded data is almost always larger than the compressed file.
+                if file_size.map_or(false, |s| s as usize > self.max_bytes) {
+                    continue;
+                }
+                let local_path = match remote_to_local_names.get(&remote_path) {
+                    Some(p) => p.clone(),
+                    None => continue,
+                };
+                if state.loading.insert(remote_path.clone()) {
+                    to_load.push((partition, remote_path, local_path));
+                }
+            }
+        }
+
+        if to_load.is_empty() {
+            return;
+        }
+        let loads = to_load
+            .into_iter()
+            .map(|(partition, remote_path, local_path)| {
+                let cache = self.clone();
+                async move {
+                    let batch_size = cache.batch_size;
+                    let batches =
+                        cube_ext::spawn_blocking(move || read_file(&local_path, batch_size))
+                            .await
+                            .map_err(CubeError::from)
+                            .and_then(|r| r);
+                    match batches {
+                        Ok(batches) => cache.insert(
+                            remote_path,
+                            partition.get_row().get_index_id(),
+                            partition.get_id(),
+                            batches,
+                        ),
+                        Err(e) => {
+                            log::error!("Error caching batches of {}: {}", remote_path, e);
+                            cache.state.lock().unwrap().loading.remove(&remote_path);
+                        }
+                    }
+                }
+            })
+            .collect_vec();
+        cube_ext::spawn(async move {
+            join_all(loads).await;
+        });
+    }
+
+    /// Waits until files started by [PartitionBatchCache::load] are decoded.
+    #[cfg(test)]
+    pub async fn wait_for_loads(&self) {
+        loop {
+            let loading = self.state.lock().unwrap().loading.len();
+            if loading == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    fn insert(
+        &self,
+        remote_path: String,
+        index_id: u64,
+        partition_id: u64,
+        batches: Vec<RecordBatch>,
+    ) {
+        let bytes = batches.iter().map(batch_memory_size).sum();
+        let mut state = self.state.lock().unwrap();
+        state.loading.remove(&remote_path);
+        if self.max_bytes < bytes {
+            return;
+        }
+        if let Some(old) = state.files.pop(&remote_path) {
+            state.bytes -= old.bytes;
+        }
+        while self.max_bytes < state.bytes + bytes {
+            match state.files.pop_lru() {
+                Some((_, f)) => state.bytes -= f.bytes,
+                None => break,
+            }
+        }
+        state.bytes += bytes;
+        state.files.put(
+            remote_path,
+            CachedFile {
+                index_id,
+                partition_id,
+                batches: Arc::new(batches),
+                bytes,
+            },
+        );
+        app_metrics::WORKER_BATCH_CACHE_BYTES.report(state.bytes as i64);
+    }
+
+    /// Drops files of `index_id` unless `is_active(partition_id, remote_path)`.
+    fn evict_inactive(&self, index_id: u64, is_active: impl Fn(u64, &str) -> bool) {
+        let mut state = self.state.lock().unwrap();
+        let inactive = state
+            .files
+            .iter()
+            .filter(|(file, f)| f.index_id == index_id && !is_active(f.partition_id, file))
+            .map(|(file, _)| file.clone())
+            .collect_vec();
+        if inactive.is_empty() {
+            return;
+        }
+        log::trace!("Dropping cached batches of inactive files {:?}", inactive);
+        for file in inactive {
+            if let Some(f) = state.files.pop(&file) {
+                state.bytes -= f.bytes;
+            }
+        }
+        app_metrics::WORKER_BATCH_CACHE_BYTES.report(state.bytes as i64);
+    }
+}
+
+fn read_file(local_path: &str, batch_size: usize) -> Result<Vec<RecordBatch>, CubeError> {
+    let mut r = ParquetFileArrowReader::new(Arc::new(
+        NoopParquetMetadataCache::new().file_reader(local_path)?,
+    ));
+    let mut batches = Vec::new();
+    for b in r.get_record_reader(batch_size)? {
+        batches.push(b?)
+    }
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+
+    fn batch(rows: i64) -> Vec<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("n", DataType::Int64, false)]));
+        vec![RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int64Array::from((0..rows).collect::<Vec<_>>()))],
+        )
+        .unwrap()]
+    }
+
+    #[test]
+    fn budget_and_eviction() {
+        let size = batch(1000).iter().map(batch_memory_size).sum::<usize>();
+        let cache = PartitionBatchCache::new(3 * size, 4096);
+        cache.insert("1.parquet".to_string(), 1, 1, batch(1000));
+        cache.insert("2.parquet".to_string(), 1, 2, batch(1000));
+        cache.insert("3.parquet".to_string(), 2, 3, batch(1000));
+        assert_eq!(cache.usage(), (3, 3 * size));
+
+        // The least recently used file goes first.
+        assert!(cache.get("1.parquet").is_some());
+        cache.insert("4.parquet".to_string(), 2, 4, batch(1000));
+        assert_eq!(cache.usage(), (3, 3 * size));
+        assert!(cache.get("2.parquet").is_none());
+        assert!(cache.get("1.parquet").is_some());
+
+        // Files larger than the whole cache are not kept.
+        cache.insert("5.parquet".to_string(), 2, 5, batch(4000));
+        assert!(cache.get("5.parquet").is_none());
+        assert_eq!(cache.usage(), (3, 3 * size));
+
+        // Only files of the same index are checked.
+        cache.evict_inactive(2, |partition_id, _| partition_id == 4);
+        assert_eq!(cache.usage(), (2, 2 * size));
+        assert!(cache.get("1.parquet").is_some());
+        assert!(cache.get("3.parquet").is_none());
+        cache.evict_inactive(1, |_, file| file != "1.parquet");
+        assert_eq!(cache.usage(), (1, size));
+        assert!(cache.get("4.parquet").is_some());
+    }
+}
@@ -0,0 +1,238 @@
+//! Cost model used to choose among indexes that can serve the same table scan.
+//!
+//! The cost of an index is the estimated amount of data read from partitions and chunks left
+//! after partition pruning. Equality filters on a sort key prefix reduce reads from the main
+//! table files by the number of distinct values of that prefix, see [Partition::key_distinct].
+//! Main table files are not read at all when the filters can't match the minimum and maximum of
+//! some column in the file, see [Partition::column_min].
+//!
+//! Join order is only chosen for inner hash joins between two tables, the side with fewer rows is
+//! kept in memory, see `choose_join_order` in the planner. Merge joins and outer joins are planned
+//! in the order written in the query.
+use crate::metastore::{Column, IdRow, Index, Partition};
+use crate::queryplanner::partition_filter::PartitionFilter;
+use crate::queryplanner::serialized_plan::PartitionSnapshot;
+use crate::table::TableValue;
+use arrow::datatypes::Schema;
+use datafusion::logical_plan::Expr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Used for data without known file sizes, e.g. in-memory chunks.
+const ESTIMATED_BYTES_PER_VALUE: u64 = 8;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct IndexCost {
+    pub index: String,
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+impl IndexCost {
+    fn key(&self) -> (u64, u64) {
+        (self.bytes, self.rows)
+    }
+
+    /// True iff `self` is strictly cheaper than `other`.
+    pub fn is_cheaper(&self, other: &IndexCost) -> bool {
+        self.key() < other.key()
+    }
+}
+
+impl fmt::Display for IndexCost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (rows: {}, bytes: {})",
+            self.index, self.rows, self.bytes
+        )
+    }
+}
+
+/// Filter on a single column, checked against the column minimum and maximum in each file.
+#[derive(Debug)]
+pub struct ColumnFilter {
+    column: String,
+    filter: PartitionFilter,
+}
+
+/// Returns filters for `columns` that are constrained by `filters`.
+pub fn extract_column_filters(columns: &[Column], filters: &[Expr]) -> Vec<ColumnFilter> {
+    columns
+        .iter()
+        .filter_map(|c| {
+            let schema = Schema::new(vec![c.clone().into()]);
+            let filter = PartitionFilter::extract(&schema, filters);
+            if filter.is_unconstrained() {
+                return None;
+            }
+            Some(ColumnFilter {
+                column: c.get_name().clone(),
+                filter,
+            })
+        })
+        .collect()
+}
+
+/// `eq_columns` are columns compared with a single value by the filters.
+pub fn estimate_index_cost(
+    index: &IdRow<Index>,
+    partitions: &[PartitionSnapshot],
+    eq_columns: &HashSet<String>,
+    column_filters: &[ColumnFilter],
+) -> IndexCost {
+    let row_width = index.get_row().get_columns().len() as u64 * ESTIMATED_BYTES_PER_VALUE;
+    let eq_prefix = index
+        .get_row()
+        .get_columns()
+        .iter()
+        .take(index.get_row().sort_key_size() as usize)
+        .take_while(|c| eq_columns.contains(c.get_name()))
+        .count();
+
+    let mut rows = 0;
+    let mut bytes = 0;
+    for p in partitions {
+        let partition = p.partition().get_row();
+        if can_match_column_filters(partition, index.get_row().get_columns(), column_filters) {
+            let main_rows = partition.main_table_row_count();
+            let main_bytes = partition.file_size().unwrap_or(main_rows * row_width);
+            let distinct = eq_prefix_distinct(partition, eq_prefix);
+            rows += div_ceil(main_rows, distinct);
+            bytes += div_ceil(main_bytes, distinct);
+        }
+
+        for c in p.chunks() {
+            let chunk_rows = c.get_row().get_row_count();
+            rows += chunk_rows;
+            bytes += c.get_row().file_size().unwrap_or(chunk_rows * row_width);
+        }
+    }
+    IndexCost {
+        index: index.get_row().get_name().to_string(),
+        rows,
+        bytes,
+    }
+}
+
+/// False iff no row of the main table file can match some of the filters. `columns` are the
+/// columns of the file.
+fn can_match_column_filters(p: &Partition, columns: &[Column], filters: &[ColumnFilter]) -> bool {
+    let (min, max) = match (p.column_min(), p.column_max()) {
+        (Some(min), Some(max)) => (min.values(), max.values()),
+        _ => return true,
+    };
+    for f in filters {
+        let i = match columns.iter().position(|c| c.get_name() == &f.column) {
+            Some(i) if i < min.len() && i < max.len() => i,
+            _ => continue,
+        };
+        // Unknown for binary columns, no values to match in columns with only NULLs.
+        if min[i] == TableValue::Null || max[i] == TableValue::Null {
+            continue;
+        }
+        if !f
+            .filter
+            .can_match(Some(&min[i..i + 1]), Some(&max[i..i + 1]))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Distinct values of the first `prefix` key columns, 1 when unknown.
+fn eq_prefix_distinct(p: &Partition, prefix: usize) -> u64 {
+    if prefix == 0 {
+        return 1;
+    }
+    p.key_distinct()
+        .as_ref()
+        .and_then(|d| d.get(prefix - 1))
+        .map(|d| (*d).max(1))
+        .unwrap_or(1)
+}
+
+fn div_ceil(a: u64, b: u64) -> u64 {
+    (a + b - 1) / b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metastore::{Chunk, ColumnType};
+    use crate::table::Row;
+    use datafusion::logical_plan::{col, lit};
+    use itertools::Itertools;
+
+    #[test]
+    fn cost() {
+        let index = IdRow::new(
+            1,
+            Index::try_new(
+                "by_a".to_string(),
+                1,
+                vec![
+                    Column::new("a".to_string(), ColumnType::Int, 0),
+                    Column::new("b".to_string(), ColumnType::Int, 1),
+                ],
+                1,
+                None,
+                None,
+                Index::index_type_default(),
+            )
+            .unwrap(),
+        );
+        let partition = Partition::new(1, None, None, None)
+            .update_row_count(1000)
+            .set_file_size(8000)
+            .unwrap()
+            .update_key_distinct(Some(vec![10]));
+        let partitions = vec![PartitionSnapshot {
+            partition: IdRow::new(1, partition),
+            chunks: vec![IdRow::new(2, Chunk::new(1, 100, false))],
+        }];
+
+        let full = estimate_index_cost(&index, &partitions, &HashSet::new(), &[]);
+        assert_eq!(full.rows, 1100);
+        assert_eq!(full.bytes, 8000 + 100 * 16);
+
+        let eq_a = ["a".to_string()].into_iter().collect();
+        let filtered = estimate_index_cost(&index, &partitions, &eq_a, &[]);
+        assert_eq!(filtered.rows, 200);
+        assert_eq!(filtered.bytes, 800 + 100 * 16);
+        assert!(filtered.is_cheaper(&full));
+
+        // Filters on columns outside the sort key prefix don't help.
+        let eq_b = ["b".to_string()].into_iter().collect();
+        assert_eq!(estimate_index_cost(&index, &partitions, &eq_b, &[]), full);
+
+        // Only chunks are read when the filters are out of the column range in the main file.
+        let columns = index.get_row().get_columns().clone();
+        let partitions = partitions
+            .into_iter()
+            .map(|p| PartitionSnapshot {
+                partition: IdRow::new(
+                    p.partition.get_id(),
+                    p.partition.get_row().update_column_min_max(
+                        Some(Row::new(vec![TableValue::Int(0), TableValue::Int(0)])),
+                        Some(Row::new(vec![TableValue::Int(10), TableValue::Int(50)])),
+                    ),
+                ),
+                chunks: p.chunks,
+            })
+            .collect_vec();
+        let b_over_100 = extract_column_filters(&columns, &[col("b").gt(lit(100i64))]);
+        assert_eq!(b_over_100.len(), 1);
+        let out_of_range = estimate_index_cost(&index, &partitions, &HashSet::new(), &b_over_100);
+        assert_eq!(out_of_range.rows, 100);
+        assert_eq!(out_of_range.bytes, 100 * 16);
+
+        let b_over_10 = extract_column_filters(&columns, &[col("b").gt(lit(10i64))]);
+        assert_eq!(
+            estimate_index_cost(&index, &partitions, &HashSet::new(), &b_over_10),
+            full
+        );
+    }
+}
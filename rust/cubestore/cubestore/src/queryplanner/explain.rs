@@ -0,0 +1,311 @@
+//! Machine-readable plans returned by `EXPLAIN (FORMAT JSON)`.
+use crate::queryplanner::cost::IndexCost;
+use crate::queryplanner::pretty_printers::{pp_phys_operator, PPOptions};
+use crate::queryplanner::query_executor::ClusterSendExec;
+use crate::queryplanner::serialized_plan::{IndexSnapshot, SerializedPlan};
+use arrow::datatypes::SchemaRef;
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::error::DataFusionError;
+use datafusion::physical_plan::{
+    Distribution, ExecutionPlan, OptimizerHints, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream,
+};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExplainNode {
+    /// E.g. `Scan` or `FinalHashAggregate`.
+    pub operator: String,
+    /// Same as the line printed by `pp_phys_plan`.
+    pub description: String,
+    /// Indexes read by the workers of `ClusterSend`, empty for other operators.
+    pub indexes: Vec<ExplainIndex>,
+    /// Only with `ANALYZE`.
+    pub output_rows: Option<u64>,
+    /// Only with `ANALYZE`. Time from the start of execution until the last batch was produced.
+    pub elapsed_ms: Option<u64>,
+    pub children: Vec<ExplainNode>,
+    /// Plans that `ClusterSend` runs on each node.
+    pub workers: Vec<ExplainWorker>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExplainIndex {
+    pub table: String,
+    pub index: String,
+    pub partitions_scanned: u64,
+    pub partitions_pruned: u64,
+    pub chunks_scanned: u64,
+    /// Rows in the scanned partitions and chunks, before filtering.
+    pub rows: u64,
+    /// Estimated costs of the indexes the planner chose from, the chosen one goes first.
+    pub index_choice: Vec<IndexCost>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExplainWorker {
+    pub node: String,
+    pub plan: ExplainNode,
+}
+
+/// Plans to send to workers for each [ClusterSendExec] in `p`, in the order [explain_tree] visits
+/// them.
+pub fn cluster_send_worker_plans(p: &dyn ExecutionPlan) -> Vec<Vec<(String, SerializedPlan)>> {
+    fn collect(p: &dyn ExecutionPlan, out: &mut Vec<Vec<(String, SerializedPlan)>>) {
+        if let Some(cs) = p.as_any().downcast_ref::<ClusterSendExec>() {
+            out.push(cs.worker_plans());
+            return;
+        }
+        for c in p.children() {
+            collect(c.as_ref(), out);
+        }
+    }
+    let mut out = Vec::new();
+    collect(p, &mut out);
+    out
+}
+
+/// Converts the plan into a tree, picking up stats recorded by [instrument_plan].
+/// `workers` provides [ExplainNode::workers] for each [ClusterSendExec].
+pub fn explain_tree(
+    p: &dyn ExecutionPlan,
+    workers: &mut dyn Iterator<Item = Vec<ExplainWorker>>,
+) -> ExplainNode {
+    let (p, stats) = match p.as_any().downcast_ref::<AnalyzeExec>() {
+        Some(a) => (a.input.as_ref(), Some(a.stats.as_ref())),
+        None => (p, None),
+    };
+    let description = pp_phys_operator(
+        p,
+        &PPOptions {
+            show_filters: true,
+            show_sort_by: true,
+            ..PPOptions::default()
+        },
+    );
+    let mut node = ExplainNode {
+        operator: description.split(',').next().unwrap().to_string(),
+        description,
+        indexes: Vec::new(),
+        output_rows: stats.map(|s| s.output_rows.load(Ordering::Relaxed)),
+        elapsed_ms: stats.map(|s| s.elapsed_nanos.load(Ordering::Relaxed) / 1_000_000),
+        children: Vec::new(),
+        workers: Vec::new(),
+    };
+    if let Some(cs) = p.as_any().downcast_ref::<ClusterSendExec>() {
+        // Inputs of the cluster send are planned and executed by the workers.
+        node.indexes = cs
+            .serialized_plan
+            .index_snapshots()
+            .iter()
+            .map(explain_index)
+            .collect();
+        node.workers = workers.next().unwrap_or_default();
+    } else {
+        node.children = p
+            .children()
+            .iter()
+            .map(|c| explain_tree(c.as_ref(), workers))
+            .collect();
+    }
+    node
+}
+
+fn explain_index(i: &IndexSnapshot) -> ExplainIndex {
+    let mut chunks_scanned = 0;
+    let mut rows = 0;
+    for p in i.partitions() {
+        rows += p.partition().get_row().main_table_row_count();
+        for c in p.chunks() {
+            chunks_scanned += 1;
+            rows += c.get_row().get_row_count();
+        }
+    }
+    ExplainIndex {
+        table: i.table_name(),
+        index: i.index().get_row().get_name().to_string(),
+        partitions_scanned: i.partitions().len() as u64,
+        partitions_pruned: i.pruned_partitions(),
+        chunks_scanned,
+        rows,
+        index_choice: i.index_choice().clone(),
+    }
+}
+
+/// Wraps every operator to record the rows it produces and its execution time.
+/// Inputs of [ClusterSendExec] run on workers and are left as is.
+pub fn instrument_plan(
+    p: Arc<dyn ExecutionPlan>,
+) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+    let p = if p.as_any().is::<ClusterSendExec>() || p.children().is_empty() {
+        p
+    } else {
+        let children = p
+            .children()
+            .into_iter()
+            .map(instrument_plan)
+            .collect::<Result<Vec<_>, _>>()?;
+        p.with_new_children(children)?
+    };
+    Ok(Arc::new(AnalyzeExec {
+        input: p,
+        stats: Arc::new(OperatorStats::default()),
+    }))
+}
+
+#[derive(Debug, Default)]
+struct OperatorStats {
+    output_rows: AtomicU64,
+    /// Maximum over all partitions.
+    elapsed_nanos: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct AnalyzeExec {
+    input: Arc<dyn ExecutionPlan>,
+    stats: Arc<OperatorStats>,
+}
+
+#[async_trait]
+impl ExecutionPlan for AnalyzeExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn required_child_distribution(&self) -> Distribution {
+        self.input.required_child_distribution()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        &self,
+        mut children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        assert_eq!(children.len(), 1);
+        Ok(Arc::new(AnalyzeExec {
+            input: children.remove(0),
+            stats: self.stats.clone(),
+        }))
+    }
+
+    fn output_hints(&self) -> OptimizerHints {
+        self.input.output_hints()
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        let start = Instant::now();
+        let input = self.input.execute(partition).await?;
+        Ok(Box::pin(AnalyzeStream {
+            input,
+            stats: self.stats.clone(),
+            start,
+            finished: false,
+        }))
+    }
+}
+
+struct AnalyzeStream {
+    input: SendableRecordBatchStream,
+    stats: Arc<OperatorStats>,
+    start: Instant,
+    finished: bool,
+}
+
+impl AnalyzeStream {
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.stats
+                .elapsed_nanos
+                .fetch_max(self.start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Stream for AnalyzeStream {
+    type Item = Result<RecordBatch, ArrowError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let r = self.input.poll_next_unpin(cx);
+        match &r {
+            Poll::Ready(Some(Ok(b))) => {
+                self.stats
+                    .output_rows
+                    .fetch_add(b.num_rows() as u64, Ordering::Relaxed);
+            }
+            Poll::Ready(_) => self.finish(),
+            Poll::Pending => {}
+        }
+        r
+    }
+}
+
+impl RecordBatchStream for AnalyzeStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+impl Drop for AnalyzeStream {
+    fn drop(&mut self) {
+        // Consumers stop early on limits.
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::merge::MergeExec;
+
+    #[tokio::test]
+    async fn analyze() {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![1, 2, 3]))],
+        )
+        .unwrap();
+        let input =
+            MemoryExec::try_new(&vec![vec![batch.clone()], vec![batch]], schema, None).unwrap();
+        let plan = instrument_plan(Arc::new(MergeExec::new(Arc::new(input)))).unwrap();
+        let results = collect(plan.clone()).await.unwrap();
+        assert_eq!(results.iter().map(|b| b.num_rows()).sum::<usize>(), 6);
+
+        let tree = explain_tree(plan.as_ref(), &mut std::iter::empty());
+        assert_eq!(tree.operator, "Merge");
+        assert_eq!(tree.output_rows, Some(6));
+        assert_eq!(tree.children.len(), 1);
+        assert_eq!(tree.children[0].operator, "MemoryScan");
+        assert_eq!(tree.children[0].output_rows, Some(6));
+        assert!(tree.children[0].elapsed_ms.is_some());
+        assert!(tree.children[0].children.is_empty());
+    }
+}
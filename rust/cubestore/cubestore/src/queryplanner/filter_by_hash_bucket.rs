@@ -0,0 +1,178 @@
+use crate::table::TableValue;
+use arrow::array::{ArrayRef, BooleanArray};
+use arrow::compute::filter_record_batch;
+use arrow::datatypes::SchemaRef;
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::cube_ext::stream::StreamWithSchema;
+use datafusion::error::DataFusionError;
+use datafusion::physical_plan::{
+    Distribution, ExecutionPlan, OptimizerHints, Partitioning, SendableRecordBatchStream,
+};
+use futures::StreamExt;
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// Keeps rows with the hash of `columns` in one of the `buckets` out of `num_buckets`. Used to
+/// split both sides of a hash join between workers, see
+/// [crate::queryplanner::serialized_plan::JoinDistribution::Hash].
+#[derive(Debug)]
+pub struct FilterByHashBucketExec {
+    input: Arc<dyn ExecutionPlan>,
+    columns: Vec<usize>,
+    num_buckets: u64,
+    /// Sorted.
+    buckets: Arc<Vec<u64>>,
+}
+
+impl FilterByHashBucketExec {
+    pub fn issue_filter(
+        input: Arc<dyn ExecutionPlan>,
+        columns: Vec<usize>,
+        num_buckets: u64,
+        buckets: Vec<u64>,
+    ) -> Arc<dyn ExecutionPlan> {
+        if (0..num_buckets).all(|b| buckets.binary_search(&b).is_ok()) {
+            return input;
+        }
+        Arc::new(FilterByHashBucketExec {
+            input,
+            columns,
+            num_buckets,
+            buckets: Arc::new(buckets),
+        })
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for FilterByHashBucketExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn required_child_distribution(&self) -> Distribution {
+        self.input.required_child_distribution()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        &self,
+        mut children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        assert_eq!(children.len(), 1);
+        Ok(Arc::new(FilterByHashBucketExec {
+            input: children.remove(0),
+            columns: self.columns.clone(),
+            num_buckets: self.num_buckets,
+            buckets: self.buckets.clone(),
+        }))
+    }
+
+    fn output_hints(&self) -> OptimizerHints {
+        self.input.output_hints()
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        let i = self.input.execute(partition).await?;
+        let s = i.schema();
+        let columns = self.columns.clone();
+        let num_buckets = self.num_buckets;
+        let buckets = self.buckets.clone();
+        Ok(Box::pin(StreamWithSchema::wrap(
+            s,
+            i.map(move |b| apply_bucket_filter(b?, &columns, num_buckets, &buckets)),
+        )))
+    }
+}
+
+fn apply_bucket_filter(
+    b: RecordBatch,
+    columns: &[usize],
+    num_buckets: u64,
+    buckets: &[u64],
+) -> Result<RecordBatch, ArrowError> {
+    let cols = columns
+        .iter()
+        .map(|c| b.column(*c).clone())
+        .collect::<Vec<_>>();
+    let matches = (0..b.num_rows())
+        .map(|row| {
+            Some(
+                buckets
+                    .binary_search(&row_bucket(&cols, row, num_buckets))
+                    .is_ok(),
+            )
+        })
+        .collect::<BooleanArray>();
+    filter_record_batch(&b, &matches)
+}
+
+/// Must produce the same bucket for equal values on all nodes, so the hasher is not randomized.
+pub fn hash_bucket(values: &[TableValue], num_buckets: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    values.hash(&mut hasher);
+    hasher.finish() % num_buckets
+}
+
+fn row_bucket(cols: &[ArrayRef], row: usize, num_buckets: u64) -> u64 {
+    hash_bucket(&TableValue::from_columns(cols, row), num_buckets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Int64Array, StringArray};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use itertools::Itertools;
+
+    #[test]
+    fn buckets_split_rows() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]));
+        let ids = (0..100).collect_vec();
+        let b = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(ids.clone())),
+                Arc::new(StringArray::from(
+                    ids.iter().map(|i| Some(format!("n{}", i))).collect_vec(),
+                )),
+            ],
+        )
+        .unwrap();
+
+        let mut seen = Vec::new();
+        for bucket in 0..3 {
+            let r = apply_bucket_filter(b.clone(), &[0], 3, &[bucket]).unwrap();
+            let col = r.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+            for i in 0..r.num_rows() {
+                assert_eq!(hash_bucket(&[TableValue::Int(col.value(i))], 3), bucket);
+                seen.push(col.value(i));
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, ids);
+
+        let all = apply_bucket_filter(b.clone(), &[0, 1], 3, &[0, 1, 2]).unwrap();
+        assert_eq!(all.num_rows(), 100);
+    }
+}
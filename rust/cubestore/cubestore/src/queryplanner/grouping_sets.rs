@@ -0,0 +1,449 @@
+//! Support for `GROUP BY ROLLUP(...)`, `GROUP BY CUBE(...)` and `GROUP BY GROUPING SETS(...)`.
+//!
+//! DataFusion only plans plain aggregates, so we expand each grouping set into a separate
+//! `SELECT ... GROUP BY` and combine the results with `UNION ALL`. Every branch is an ordinary
+//! aggregate and gets distributed to the workers the same way as any other aggregation query.
+//!
+//! Projection items that are grouped in some sets, but not in others, are replaced with a typed
+//! NULL in branches where they are aggregated away. `GROUPING(expr)` is replaced with a constant
+//! `0` or `1` in each branch.
+use crate::CubeError;
+use datafusion::sql::parser::Statement as DFStatement;
+use sqlparser::ast::{
+    Expr, Function, FunctionArg, Ident, ObjectName, Query, Select, SelectItem, SetExpr,
+    SetOperator, Statement, TableFactor, Value,
+};
+use sqlparser::tokenizer::{Token, Whitespace};
+
+/// `GROUPING SETS` is not understood by the SQL parser, so the tokenizer output is rewritten into
+/// a function call form, e.g. `GROUPING SETS ((a, b), a, ())` becomes
+/// `GROUPING_SETS(GROUPING_SET(a, b), a, GROUPING_SET())`.
+pub const GROUPING_SETS_FUNCTION: &str = "GROUPING_SETS";
+pub const GROUPING_SET_FUNCTION: &str = "GROUPING_SET";
+
+/// Rewrites `GROUPING SETS` tokens so that the parser sees them as a function call.
+pub fn rewrite_grouping_sets_tokens(tokens: Vec<Token>) -> Vec<Token> {
+    let is_word = |t: &Token, s: &str| match t {
+        Token::Word(w) => w.quote_style.is_none() && w.value.eq_ignore_ascii_case(s),
+        _ => false,
+    };
+    let is_whitespace = |t: &Token| matches!(t, Token::Whitespace(_));
+
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        if !is_word(&tokens[i], "GROUPING") {
+            result.push(tokens[i].clone());
+            i += 1;
+            continue;
+        }
+        let mut j = i + 1;
+        while j < tokens.len() && is_whitespace(&tokens[j]) {
+            j += 1;
+        }
+        if j == tokens.len() || !is_word(&tokens[j], "SETS") {
+            result.push(tokens[i].clone());
+            i += 1;
+            continue;
+        }
+        result.push(Token::make_word(GROUPING_SETS_FUNCTION, None));
+        i = j + 1;
+        while i < tokens.len() && is_whitespace(&tokens[i]) {
+            result.push(tokens[i].clone());
+            i += 1;
+        }
+        if i == tokens.len() || tokens[i] != Token::LParen {
+            // Let the parser report the error.
+            continue;
+        }
+        // Prefix parenthesized elements of the list with a function name.
+        let mut depth = 0;
+        let mut element_start = false;
+        while i < tokens.len() {
+            let t = &tokens[i];
+            match t {
+                Token::LParen => {
+                    if depth == 1 && element_start {
+                        result.push(Token::make_word(GROUPING_SET_FUNCTION, None));
+                    }
+                    depth += 1;
+                    element_start = depth == 1;
+                }
+                Token::RParen => {
+                    depth -= 1;
+                    element_start = false;
+                }
+                Token::Comma => element_start = depth == 1,
+                Token::Whitespace(Whitespace::Space)
+                | Token::Whitespace(Whitespace::Newline)
+                | Token::Whitespace(Whitespace::Tab) => {}
+                _ => element_start = false,
+            }
+            result.push(t.clone());
+            i += 1;
+            if depth == 0 {
+                break;
+            }
+        }
+    }
+    result
+}
+
+/// Replaces queries with grouping sets by a `UNION ALL` of plain aggregations.
+pub fn rewrite_grouping_sets(statement: DFStatement) -> Result<DFStatement, CubeError> {
+    match statement {
+        DFStatement::Statement(Statement::Query(mut q)) => {
+            rewrite_query(&mut q)?;
+            Ok(DFStatement::Statement(Statement::Query(q)))
+        }
+        s => Ok(s),
+    }
+}
+
+fn rewrite_query(q: &mut Query) -> Result<(), CubeError> {
+    rewrite_set_expr(&mut q.body)
+}
+
+fn rewrite_set_expr(e: &mut SetExpr) -> Result<(), CubeError> {
+    match e {
+        SetExpr::Select(s) => {
+            for t in s.from.iter_mut() {
+                rewrite_table_factor(&mut t.relation)?;
+                for j in t.joins.iter_mut() {
+                    rewrite_table_factor(&mut j.relation)?;
+                }
+            }
+            if let Some(union) = expand_grouping_sets(s)? {
+                *e = union;
+            }
+            Ok(())
+        }
+        SetExpr::Query(q) => rewrite_query(q),
+        SetExpr::SetOperation { left, right, .. } => {
+            rewrite_set_expr(left)?;
+            rewrite_set_expr(right)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn rewrite_table_factor(t: &mut TableFactor) -> Result<(), CubeError> {
+    match t {
+        TableFactor::Derived { subquery, .. } => rewrite_query(subquery),
+        TableFactor::NestedJoin(j) => {
+            rewrite_table_factor(&mut j.relation)?;
+            for j in j.joins.iter_mut() {
+                rewrite_table_factor(&mut j.relation)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn function_name(e: &Expr) -> Option<String> {
+    match e {
+        Expr::Function(Function { name, .. }) if name.0.len() == 1 => {
+            Some(name.0[0].value.to_uppercase())
+        }
+        _ => None,
+    }
+}
+
+fn function_args(e: &Expr) -> Result<Vec<Expr>, CubeError> {
+    match e {
+        Expr::Function(Function { args, .. }) => args
+            .iter()
+            .map(|a| match a {
+                FunctionArg::Unnamed(e) => Ok(e.clone()),
+                _ => Err(CubeError::user(format!(
+                    "Named arguments are not allowed in '{}'",
+                    e
+                ))),
+            })
+            .collect(),
+        _ => Ok(vec![e.clone()]),
+    }
+}
+
+fn is_grouping_sets_expr(e: &Expr) -> bool {
+    match function_name(e) {
+        Some(n) => n == "ROLLUP" || n == "CUBE" || n == GROUPING_SETS_FUNCTION,
+        None => false,
+    }
+}
+
+/// Grouping sets for a single item of the `GROUP BY` clause.
+fn item_grouping_sets(e: &Expr) -> Result<Vec<Vec<Expr>>, CubeError> {
+    if !is_grouping_sets_expr(e) {
+        return Ok(vec![vec![e.clone()]]);
+    }
+    let name = function_name(e).unwrap();
+    let args = function_args(e)?;
+    for a in &args {
+        if is_grouping_sets_expr(a) {
+            return Err(CubeError::user(format!(
+                "Nested grouping sets are not supported: '{}'",
+                e
+            )));
+        }
+    }
+    match name.as_str() {
+        "ROLLUP" => Ok((0..=args.len()).rev().map(|n| args[..n].to_vec()).collect()),
+        "CUBE" => {
+            if args.len() > 12 {
+                return Err(CubeError::user(format!(
+                    "Too many expressions in CUBE: {}",
+                    args.len()
+                )));
+            }
+            let mut sets = Vec::with_capacity(1 << args.len());
+            // Enumerate subsets from the full set down to the empty one.
+            for mask in (0..(1u32 << args.len())).rev() {
+                sets.push(
+                    args.iter()
+                        .enumerate()
+                        .filter(|(i, _)| mask & (1 << (args.len() - 1 - i)) != 0)
+                        .map(|(_, e)| e.clone())
+                        .collect(),
+                );
+            }
+            Ok(sets)
+        }
+        _ => args
+            .iter()
+            .map(|a| match function_name(a) {
+                Some(n) if n == GROUPING_SET_FUNCTION => function_args(a),
+                _ => Ok(vec![a.clone()]),
+            })
+            .collect(),
+    }
+}
+
+/// Resolves positional references and aliases in the `GROUP BY` clause to projection expressions.
+fn resolve_group_expr<'a>(e: &'a Expr, projection: &'a [SelectItem]) -> &'a Expr {
+    match e {
+        Expr::Value(Value::Number(n, _)) => {
+            if let Ok(i) = n.parse::<usize>() {
+                if 1 <= i && i <= projection.len() {
+                    match &projection[i - 1] {
+                        SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. } => {
+                            return e
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            e
+        }
+        Expr::Identifier(id) => {
+            for p in projection {
+                match p {
+                    SelectItem::ExprWithAlias { expr, alias } if alias.value == id.value => {
+                        return expr
+                    }
+                    _ => {}
+                }
+            }
+            e
+        }
+        e => e,
+    }
+}
+
+fn expand_grouping_sets(s: &Select) -> Result<Option<SetExpr>, CubeError> {
+    if !s.group_by.iter().any(is_grouping_sets_expr) {
+        return Ok(None);
+    }
+
+    // Cartesian product of grouping sets of all items.
+    let mut sets: Vec<Vec<Expr>> = vec![vec![]];
+    for g in &s.group_by {
+        let item_sets = item_grouping_sets(g)?;
+        let mut next = Vec::with_capacity(sets.len() * item_sets.len());
+        for prefix in &sets {
+            for item_set in &item_sets {
+                let mut set = prefix.clone();
+                for e in item_set {
+                    if !set.contains(e) {
+                        set.push(e.clone())
+                    }
+                }
+                next.push(set);
+            }
+        }
+        sets = next;
+    }
+
+    let projection = &s.projection;
+    let resolved_sets = sets
+        .iter()
+        .map(|set| {
+            set.iter()
+                .map(|e| resolve_group_expr(e, projection).clone())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let all_grouped = resolved_sets.iter().flatten().collect::<Vec<_>>();
+
+    // Rewrite the projection for each of the sets.
+    let mut branches = Vec::with_capacity(sets.len());
+    for grouped in &resolved_sets {
+        let mut items = Vec::with_capacity(projection.len());
+        for p in projection {
+            let expr = match p {
+                SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. } => e,
+                _ => {
+                    items.push(None);
+                    continue;
+                }
+            };
+            let rewritten = if let Some("GROUPING") = function_name(expr).as_deref() {
+                let args = function_args(expr)?;
+                if args.len() != 1 {
+                    return Err(CubeError::user(format!(
+                        "GROUPING expects exactly one argument: '{}'",
+                        expr
+                    )));
+                }
+                let arg = resolve_group_expr(&args[0], projection);
+                if !all_grouped.contains(&arg) {
+                    return Err(CubeError::user(format!(
+                        "Argument of GROUPING must be a grouping expression: '{}'",
+                        expr
+                    )));
+                }
+                let is_aggregated = !grouped.contains(arg);
+                Some(Expr::Value(Value::Number(
+                    (is_aggregated as u8).to_string(),
+                    false,
+                )))
+            } else if all_grouped.contains(&expr) && !grouped.contains(expr) {
+                Some(typed_null(expr))
+            } else {
+                None
+            };
+            items.push(rewritten);
+        }
+        branches.push(items);
+    }
+
+    // Items that differ between branches get the same explicit alias in all of them, so that
+    // the schemas of the UNION ALL inputs match.
+    let mut result: Option<SetExpr> = None;
+    for (set, items) in sets.iter().zip(branches.iter()) {
+        let mut select = s.clone();
+        select.group_by = set.clone();
+        for (i, p) in projection.iter().enumerate() {
+            let varies = branches.iter().any(|b| b[i].is_some());
+            if !varies {
+                continue;
+            }
+            let (expr, alias) = match p {
+                SelectItem::UnnamedExpr(e) => (e, None),
+                SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias)),
+                _ => continue,
+            };
+            let alias = match alias {
+                Some(a) => a.clone(),
+                None => Ident::new(match expr {
+                    Expr::Identifier(id) => id.value.clone(),
+                    Expr::CompoundIdentifier(ids) => ids.last().unwrap().value.clone(),
+                    e => e.to_string(),
+                }),
+            };
+            let expr = match &items[i] {
+                Some(e) => e.clone(),
+                None => expr.clone(),
+            };
+            select.projection[i] = SelectItem::ExprWithAlias { expr, alias };
+        }
+        let select = SetExpr::Select(Box::new(select));
+        result = Some(match result {
+            None => select,
+            Some(left) => SetExpr::SetOperation {
+                op: SetOperator::Union,
+                all: true,
+                left: Box::new(left),
+                right: Box::new(select),
+            },
+        });
+    }
+    Ok(result)
+}
+
+/// NULL that has the same type as `e`. Note that `e` is wrapped into an aggregate function as it
+/// is not a part of the `GROUP BY` clause in the corresponding branch.
+fn typed_null(e: &Expr) -> Expr {
+    Expr::Case {
+        operand: None,
+        conditions: vec![Expr::Value(Value::Boolean(false))],
+        results: vec![Expr::Function(Function {
+            name: ObjectName(vec![Ident::new("MAX")]),
+            args: vec![FunctionArg::Unnamed(e.clone())],
+            over: None,
+            distinct: false,
+        })],
+        else_result: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::parser::{CubeStoreParser, Statement as CubeStoreStatement};
+
+    fn rewrite(sql: &str) -> String {
+        let s = match CubeStoreParser::new(sql)
+            .unwrap()
+            .parse_statement()
+            .unwrap()
+        {
+            CubeStoreStatement::Statement(s) => s,
+            s => panic!("unexpected statement: {:?}", s),
+        };
+        match rewrite_grouping_sets(DFStatement::Statement(s)).unwrap() {
+            DFStatement::Statement(s) => s.to_string(),
+            _ => panic!("unexpected statement"),
+        }
+    }
+
+    #[test]
+    fn rollup() {
+        assert_eq!(
+            rewrite("SELECT a, b, SUM(c) FROM t GROUP BY ROLLUP(a, b)"),
+            "SELECT a AS a, b AS b, SUM(c) FROM t GROUP BY a, b \
+             UNION ALL SELECT a AS a, CASE WHEN false THEN MAX(b) END AS b, SUM(c) FROM t GROUP BY a \
+             UNION ALL SELECT CASE WHEN false THEN MAX(a) END AS a, \
+             CASE WHEN false THEN MAX(b) END AS b, SUM(c) FROM t"
+        );
+    }
+
+    #[test]
+    fn cube_and_grouping() {
+        assert_eq!(
+            rewrite("SELECT a AS x, GROUPING(1) g, COUNT(*) FROM t GROUP BY CUBE(1)"),
+            "SELECT a AS x, 0 AS g, COUNT(*) FROM t GROUP BY 1 \
+             UNION ALL SELECT CASE WHEN false THEN MAX(a) END AS x, 1 AS g, COUNT(*) FROM t"
+        );
+    }
+
+    #[test]
+    fn grouping_sets() {
+        assert_eq!(
+            rewrite("SELECT a, b, COUNT(*) FROM t GROUP BY GROUPING SETS ((a, b), (a), ())"),
+            "SELECT a AS a, b AS b, COUNT(*) FROM t GROUP BY a, b \
+             UNION ALL SELECT a AS a, CASE WHEN false THEN MAX(b) END AS b, COUNT(*) FROM t GROUP BY a \
+             UNION ALL SELECT CASE WHEN false THEN MAX(a) END AS a, \
+             CASE WHEN false THEN MAX(b) END AS b, COUNT(*) FROM t"
+        );
+    }
+
+    #[test]
+    fn no_grouping_sets() {
+        assert_eq!(
+            rewrite("SELECT grouping, COUNT(*) FROM t GROUP BY 1"),
+            "SELECT grouping, COUNT(*) FROM t GROUP BY 1"
+        );
+    }
+}
@@ -1,14 +1,40 @@
+use crate::metastore::HllFlavour;
 use crate::CubeError;
 use cubehll::HllSketch;
 use cubezetasketch::HyperLogLogPlusPlus;
+use std::cmp::{max, min};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Hll {
     Airlift(HllSketch),              // Compatible with Athena, Presto, etc.
     ZetaSketch(HyperLogLogPlusPlus), // Compatible with BigQuery.
 }
 
 impl Hll {
+    /// Number of buckets in Airlift sketches we produce, same as in `approx_set` of Presto.
+    pub const AIRLIFT_NUM_BUCKETS: u32 = 4096;
+    /// Airlift supports at most 65536 buckets.
+    const AIRLIFT_MAX_PRECISION: u8 = 16;
+    /// ZetaSketch supports at least 1024 buckets.
+    const ZETA_MIN_PRECISION: u8 = 10;
+
+    /// Creates a sketch for an empty set. Snowflake and Postgres sketches are stored in the Airlift
+    /// format, so they get Airlift sketches. ZetaSketch uses the same precisions as BigQuery.
+    pub fn new(f: HllFlavour) -> Result<Hll, CubeError> {
+        match f {
+            HllFlavour::Airlift | HllFlavour::Snowflake | HllFlavour::Postgres => {
+                Ok(Hll::Airlift(HllSketch::new(Self::AIRLIFT_NUM_BUCKETS)?))
+            }
+            HllFlavour::ZetaSketch => {
+                let p = HyperLogLogPlusPlus::DEFAULT_NORMAL_PRECISION;
+                Ok(Hll::ZetaSketch(HyperLogLogPlusPlus::new(
+                    p,
+                    p + HyperLogLogPlusPlus::DEFAULT_SPARSE_PRECISION_DELTA,
+                )?))
+            }
+        }
+    }
+
     pub fn read(data: &[u8]) -> Result<Hll, CubeError> {
         if data.is_empty() {
             return Err(CubeError::internal(
@@ -32,6 +58,114 @@ impl Hll {
         }
     }
 
+    /// Exports into the format of the `hll` extension for Postgres. Only Airlift sketches have the
+    /// same structure and can be exported.
+    pub fn write_postgres(&self) -> Result<Vec<u8>, CubeError> {
+        match self {
+            Hll::Airlift(h) => Ok(h.write_hll_storage_spec()?),
+            Hll::ZetaSketch(_) => Err(CubeError::user(
+                "ZetaSketch HLL can not be exported to Postgres format".to_string(),
+            )),
+        }
+    }
+
+    /// Exports into the JSON format of Snowflake, i.e. the output of `HLL_EXPORT`.
+    pub fn write_snowflake(&self) -> Result<String, CubeError> {
+        match self {
+            Hll::Airlift(h) => Ok(h.write_snowflake()?),
+            Hll::ZetaSketch(_) => Err(CubeError::user(
+                "ZetaSketch HLL can not be exported to Snowflake format".to_string(),
+            )),
+        }
+    }
+
+    /// Airlift for Airlift, Snowflake and Postgres sketches, ZetaSketch otherwise.
+    pub fn flavour(&self) -> HllFlavour {
+        match self {
+            Hll::Airlift(_) => HllFlavour::Airlift,
+            Hll::ZetaSketch(_) => HllFlavour::ZetaSketch,
+        }
+    }
+
+    /// Log2 of the number of buckets, i.e. the normal precision for ZetaSketch.
+    pub fn precision(&self) -> u8 {
+        match self {
+            Hll::Airlift(h) => h.index_bit_len(),
+            Hll::ZetaSketch(h) => h.precision() as u8,
+        }
+    }
+
+    /// Converts the sketch into the format of `to`, keeping the precision if the target format
+    /// supports it. Both formats keep the same value in each bucket (the number of leading zeros
+    /// in the hash bits after the bucket index plus one), so the conversion is done on registers.
+    /// The conversion is lossy:
+    ///   - Airlift and ZetaSketch hash values differently, so the same value added to sketches of
+    ///     different flavours is counted twice after they are merged,
+    ///   - the extra precision of the ZetaSketch sparse representation is lost,
+    ///   - ZetaSketch sketches with more than 65536 buckets are downsampled to fit into Airlift.
+    /// Airlift sketches with less than 1024 buckets can not be converted into ZetaSketch.
+    pub fn convert(&self, to: HllFlavour) -> Result<Hll, CubeError> {
+        let precision = match to {
+            HllFlavour::ZetaSketch => self.precision(),
+            _ => min(self.precision(), Self::AIRLIFT_MAX_PRECISION),
+        };
+        return self.convert_with_precision(to, precision);
+    }
+
+    /// Same as `convert`, but also downsamples the registers to `2^precision` buckets.
+    /// Fails if `precision` is larger than the precision of `self`.
+    pub fn convert_with_precision(&self, to: HllFlavour, precision: u8) -> Result<Hll, CubeError> {
+        if self.precision() < precision {
+            return Err(CubeError::user(format!(
+                "cannot convert HLL sketch with precision {} to a larger precision {}",
+                self.precision(),
+                precision
+            )));
+        }
+        let same_flavour = match (self, to) {
+            (Hll::ZetaSketch(_), HllFlavour::ZetaSketch) => true,
+            (Hll::Airlift(_), HllFlavour::ZetaSketch) => false,
+            (Hll::Airlift(_), _) => true,
+            (Hll::ZetaSketch(_), _) => false,
+        };
+        if same_flavour && self.precision() == precision {
+            return Ok(self.clone());
+        }
+
+        let registers = match self {
+            Hll::Airlift(h) => h.bucket_values(),
+            Hll::ZetaSketch(h) => h.normal_data()?,
+        };
+        let registers = downsample_registers(&registers, self.precision(), precision);
+        match to {
+            HllFlavour::ZetaSketch => {
+                if precision < Self::ZETA_MIN_PRECISION {
+                    return Err(CubeError::user(format!(
+                        "cannot convert HLL sketch with {} buckets to ZetaSketch, at least {} buckets are required",
+                        1 << precision,
+                        1 << Self::ZETA_MIN_PRECISION
+                    )));
+                }
+                let p = precision as i32;
+                Ok(Hll::ZetaSketch(HyperLogLogPlusPlus::from_normal_data(
+                    p,
+                    p + HyperLogLogPlusPlus::DEFAULT_SPARSE_PRECISION_DELTA,
+                    registers,
+                )?))
+            }
+            _ => {
+                if Self::AIRLIFT_MAX_PRECISION < precision {
+                    return Err(CubeError::user(format!(
+                        "cannot convert HLL sketch with {} buckets to Airlift, at most {} buckets are supported",
+                        1 << precision,
+                        1 << Self::AIRLIFT_MAX_PRECISION
+                    )));
+                }
+                Ok(Hll::Airlift(HllSketch::from_bucket_values(registers)?))
+            }
+        }
+    }
+
     pub fn is_compatible(&self, other: &Hll) -> bool {
         match (self, other) {
             (Hll::Airlift(l), Hll::Airlift(r)) => l.index_bit_len() == r.index_bit_len(),
@@ -47,6 +181,25 @@ impl Hll {
         }
     }
 
+    /// Values are hashed the same way as in the original implementations, so the result can be
+    /// merged with sketches produced by Presto or BigQuery.
+    pub fn insert_i64(&mut self, v: i64) -> Result<(), CubeError> {
+        match self {
+            Hll::Airlift(h) => h.insert_i64(v),
+            Hll::ZetaSketch(h) => h.add_i64(v)?,
+        }
+        return Ok(());
+    }
+
+    /// Strings must be passed as UTF-8 bytes.
+    pub fn insert_bytes(&mut self, v: &[u8]) -> Result<(), CubeError> {
+        match self {
+            Hll::Airlift(h) => h.insert(v),
+            Hll::ZetaSketch(h) => h.add_bytes(v)?,
+        }
+        return Ok(());
+    }
+
     /// Clients are responsible for calling `is_compatible` before running this function.
     /// On error, `self` may end up in inconsistent state and must be discarded.
     pub fn merge_with(&mut self, other: &Hll) -> Result<(), CubeError> {
@@ -58,4 +211,205 @@ impl Hll {
         }
         return Ok(());
     }
+
+    /// Merges sketches that `merge_with` can not merge, i.e. sketches of different flavours or
+    /// precisions, see `convert` for the losses. The result only depends on the flavours and
+    /// precisions of the inputs, not on the order of merges:
+    ///   - sketches of the same flavour keep it, sketches of different flavours become Airlift,
+    ///   - the result is downsampled to the lowest precision of the two sketches.
+    pub fn merge_converted(&mut self, other: &Hll) -> Result<(), CubeError> {
+        let flavour = if self.flavour() == other.flavour() {
+            self.flavour()
+        } else {
+            HllFlavour::Airlift
+        };
+        let mut precision = min(self.precision(), other.precision());
+        if flavour != HllFlavour::ZetaSketch {
+            precision = min(precision, Self::AIRLIFT_MAX_PRECISION);
+        }
+        let mut l = self.convert_with_precision(flavour, precision)?;
+        let mut r = other.convert_with_precision(flavour, precision)?;
+        if !l.is_compatible(&r) {
+            // ZetaSketch sketches with different sparse precisions.
+            l = l.convert_to_normal()?;
+            r = r.convert_to_normal()?;
+        }
+        l.merge_with(&r)?;
+        *self = l;
+        return Ok(());
+    }
+
+    /// Drops the sparse representation of ZetaSketch sketches, so that all of them have the
+    /// default sparse precision.
+    fn convert_to_normal(&self) -> Result<Hll, CubeError> {
+        match self {
+            Hll::Airlift(_) => Ok(self.clone()),
+            Hll::ZetaSketch(h) => {
+                let p = h.precision();
+                Ok(Hll::ZetaSketch(HyperLogLogPlusPlus::from_normal_data(
+                    p,
+                    p + HyperLogLogPlusPlus::DEFAULT_SPARSE_PRECISION_DELTA,
+                    h.normal_data()?,
+                )?))
+            }
+        }
+    }
+}
+
+/// Folds registers of a sketch with `2^from` buckets into `2^to` buckets, `to <= from`. The low
+/// bits of the original bucket index become the leading bits of the hash after the new index.
+fn downsample_registers(registers: &[u8], from: u8, to: u8) -> Vec<u8> {
+    debug_assert!(to <= from);
+    debug_assert_eq!(registers.len(), 1 << from);
+    if from == to {
+        return registers.to_vec();
+    }
+    let shift = from - to;
+    let mut r = vec![0; 1 << to];
+    for (i, &v) in registers.iter().enumerate() {
+        if v == 0 {
+            continue;
+        }
+        let low = (i as u32) & ((1 << shift) - 1);
+        let v = if low != 0 {
+            // Leading zeros in the `shift` low bits of the index, plus one.
+            shift - (32 - low.leading_zeros()) as u8 + 1
+        } else {
+            shift + v
+        };
+        let bucket = i >> shift;
+        r[bucket] = max(r[bucket], v);
+    }
+    return r;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn airlift(values: std::ops::Range<i64>) -> Hll {
+        let mut h = Hll::new(HllFlavour::Airlift).unwrap();
+        for v in values {
+            h.insert_i64(v).unwrap();
+        }
+        h
+    }
+
+    fn zeta(values: std::ops::Range<i64>) -> Hll {
+        let mut h = Hll::new(HllFlavour::ZetaSketch).unwrap();
+        for v in values {
+            h.insert_i64(v).unwrap();
+        }
+        h
+    }
+
+    fn assert_close(actual: u64, expected: u64) {
+        let err = (actual as f64 - expected as f64).abs() / expected as f64;
+        assert!(
+            err < 0.05,
+            "estimate {} is too far from {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        let mut a = airlift(0..10000);
+        let mut z = a.convert(HllFlavour::ZetaSketch).unwrap();
+        assert_eq!(z.flavour(), HllFlavour::ZetaSketch);
+        assert_eq!(z.precision(), 12);
+        assert_close(z.cardinality(), a.cardinality());
+        let mut back = z.convert(HllFlavour::Airlift).unwrap();
+        assert_eq!(back.cardinality(), a.cardinality());
+        match (&a, &back) {
+            (Hll::Airlift(l), Hll::Airlift(r)) => assert_eq!(l.bucket_values(), r.bucket_values()),
+            _ => panic!("expected Airlift sketches"),
+        }
+
+        let mut z = zeta(0..10000);
+        let mut a = z.convert(HllFlavour::Airlift).unwrap();
+        assert_eq!(a.precision(), 15);
+        assert_close(a.cardinality(), z.cardinality());
+
+        // Postgres and Snowflake sketches are stored as Airlift.
+        let a = z.convert(HllFlavour::Postgres).unwrap();
+        assert_eq!(a.flavour(), HllFlavour::Airlift);
+
+        let mut small = Hll::Airlift(HllSketch::new(512).unwrap());
+        small.insert_i64(1).unwrap();
+        assert!(small.convert(HllFlavour::ZetaSketch).is_err());
+        assert!(small
+            .convert_with_precision(HllFlavour::Airlift, 10)
+            .is_err());
+    }
+
+    #[test]
+    fn test_downsample() {
+        let mut a = Hll::Airlift(HllSketch::new(1 << 14).unwrap());
+        let mut expected = Hll::Airlift(HllSketch::new(1 << 10).unwrap());
+        for i in 0..50000 {
+            a.insert_i64(i).unwrap();
+            expected.insert_i64(i).unwrap();
+        }
+        let mut downsampled = a.convert_with_precision(HllFlavour::Airlift, 10).unwrap();
+        assert_eq!(downsampled.precision(), 10);
+        // Registers are the same as if values were inserted at the lower precision.
+        assert_eq!(downsampled.cardinality(), expected.cardinality());
+    }
+
+    #[test]
+    fn test_merge_converted() {
+        let mut a = airlift(0..10000);
+        let z = zeta(10000..20000);
+        a.merge_converted(&z).unwrap();
+        assert_eq!(a.flavour(), HllFlavour::Airlift);
+        assert_eq!(a.precision(), 12);
+        assert_close(a.cardinality(), 20000);
+
+        // The result does not depend on the order of inputs.
+        let mut z = zeta(10000..20000);
+        z.merge_converted(&airlift(0..10000)).unwrap();
+        assert_eq!(z.flavour(), HllFlavour::Airlift);
+        assert_eq!(z.precision(), 12);
+        assert_eq!(z.write(), a.write());
+    }
+
+    #[test]
+    fn test_merge_different_precisions() {
+        let sketch = |num_buckets: u32, values: std::ops::Range<i64>| {
+            let mut h = Hll::Airlift(HllSketch::new(num_buckets).unwrap());
+            for v in values {
+                h.insert_i64(v).unwrap();
+            }
+            h
+        };
+        // Same flavour, different precisions are downsampled as for different flavours.
+        let mut l = sketch(1 << 14, 0..10000);
+        l.merge_converted(&sketch(1 << 12, 10000..20000)).unwrap();
+        let mut r = sketch(1 << 12, 10000..20000);
+        r.merge_converted(&sketch(1 << 14, 0..10000)).unwrap();
+        assert_eq!(l.flavour(), HllFlavour::Airlift);
+        assert_eq!(l.precision(), 12);
+        assert_eq!(l.write(), r.write());
+        assert_close(l.cardinality(), 20000);
+
+        let zeta_with_precision = |p: i32, values: std::ops::Range<i64>| {
+            let mut h = Hll::ZetaSketch(HyperLogLogPlusPlus::new(p, p + 5).unwrap());
+            for v in values {
+                h.insert_i64(v).unwrap();
+            }
+            h
+        };
+        let mut l = zeta_with_precision(15, 0..10000);
+        l.merge_converted(&zeta_with_precision(13, 10000..20000))
+            .unwrap();
+        let mut r = zeta_with_precision(13, 10000..20000);
+        r.merge_converted(&zeta_with_precision(15, 0..10000))
+            .unwrap();
+        assert_eq!(l.flavour(), HllFlavour::ZetaSketch);
+        assert_eq!(l.precision(), 13);
+        assert_eq!(l.write(), r.write());
+        assert_close(l.cardinality(), 20000);
+    }
 }
@@ -4,7 +4,10 @@ mod system_cache;
 mod system_chunks;
 mod system_indexes;
 mod system_jobs;
+mod system_materialized_views;
+mod system_nodes;
 mod system_partitions;
+mod system_queries;
 mod system_queue;
 mod system_replay_handles;
 mod system_snapshots;
@@ -16,7 +19,10 @@ pub use system_cache::*;
 pub use system_chunks::*;
 pub use system_indexes::*;
 pub use system_jobs::*;
+pub use system_materialized_views::*;
+pub use system_nodes::*;
 pub use system_partitions::*;
+pub use system_queries::*;
 pub use system_queue::*;
 pub use system_replay_handles::*;
 pub use system_snapshots::*;
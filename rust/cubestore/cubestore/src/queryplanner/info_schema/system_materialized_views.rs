@@ -0,0 +1,183 @@
+use crate::metastore::table::{MaterializedView, TablePath};
+use crate::queryplanner::{InfoSchemaTableDef, InfoSchemaTableDefContext};
+use crate::CubeError;
+use arrow::array::{ArrayRef, Int64Array, StringArray, TimestampNanosecondArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, TimeUnit};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+pub struct SystemMaterializedViewsTableDef;
+
+pub struct MaterializedViewRow {
+    view: TablePath,
+    source: Option<TablePath>,
+}
+
+#[async_trait]
+impl InfoSchemaTableDef for SystemMaterializedViewsTableDef {
+    type T = MaterializedViewRow;
+
+    async fn rows(&self, ctx: InfoSchemaTableDefContext) -> Result<Arc<Vec<Self::T>>, CubeError> {
+        let tables = ctx.meta_store.get_tables_with_path(true).await?;
+        let rows = tables
+            .iter()
+            .filter_map(|t| {
+                let source_id = t
+                    .table
+                    .get_row()
+                    .materialized_view()
+                    .as_ref()?
+                    .source_table_id();
+                Some(MaterializedViewRow {
+                    view: t.clone(),
+                    source: tables
+                        .iter()
+                        .find(|s| s.table.get_id() == source_id)
+                        .cloned(),
+                })
+            })
+            .collect();
+        Ok(Arc::new(rows))
+    }
+
+    fn columns(&self) -> Vec<(Field, Box<dyn Fn(Arc<Vec<Self::T>>) -> ArrayRef>)> {
+        vec![
+            (
+                Field::new("id", DataType::UInt64, false),
+                Box::new(|views| {
+                    Arc::new(UInt64Array::from(
+                        views
+                            .iter()
+                            .map(|row| row.view.table.get_id())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("table_schema", DataType::Utf8, false),
+                Box::new(|views| {
+                    Arc::new(StringArray::from(
+                        views
+                            .iter()
+                            .map(|row| row.view.schema.get_row().get_name().as_str())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("table_name", DataType::Utf8, false),
+                Box::new(|views| {
+                    Arc::new(StringArray::from(
+                        views
+                            .iter()
+                            .map(|row| row.view.table.get_row().get_table_name().as_str())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("source_table", DataType::Utf8, true),
+                Box::new(|views| {
+                    Arc::new(StringArray::from(
+                        views
+                            .iter()
+                            .map(|row| row.source.as_ref().map(|s| s.table_name()))
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("definition", DataType::Utf8, false),
+                Box::new(|views| {
+                    Arc::new(StringArray::from(
+                        views
+                            .iter()
+                            .map(|row| view_def(row).definition().as_str())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("status", DataType::Utf8, false),
+                Box::new(|views| {
+                    Arc::new(StringArray::from(
+                        views
+                            .iter()
+                            .map(|row| {
+                                if row.source.is_none() {
+                                    "source_missing"
+                                } else if view_def(row).lag_seconds().unwrap_or(0) > 0 {
+                                    "lagging"
+                                } else {
+                                    "ready"
+                                }
+                            })
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new(
+                    "last_refresh_at",
+                    DataType::Timestamp(TimeUnit::Nanosecond, None),
+                    true,
+                ),
+                Box::new(|views| {
+                    Arc::new(TimestampNanosecondArray::from(
+                        views
+                            .iter()
+                            .map(|row| {
+                                view_def(row)
+                                    .last_refresh_at()
+                                    .as_ref()
+                                    .map(|t| t.timestamp_nanos())
+                            })
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new(
+                    "source_last_insert_at",
+                    DataType::Timestamp(TimeUnit::Nanosecond, None),
+                    true,
+                ),
+                Box::new(|views| {
+                    Arc::new(TimestampNanosecondArray::from(
+                        views
+                            .iter()
+                            .map(|row| {
+                                view_def(row)
+                                    .source_last_insert_at()
+                                    .as_ref()
+                                    .map(|t| t.timestamp_nanos())
+                            })
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("lag_seconds", DataType::Int64, true),
+                Box::new(|views| {
+                    Arc::new(Int64Array::from(
+                        views
+                            .iter()
+                            .map(|row| view_def(row).lag_seconds())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+        ]
+    }
+}
+
+fn view_def(row: &MaterializedViewRow) -> &MaterializedView {
+    row.view
+        .table
+        .get_row()
+        .materialized_view()
+        .as_ref()
+        .unwrap()
+}
+
+crate::base_info_schema_table_def!(SystemMaterializedViewsTableDef);
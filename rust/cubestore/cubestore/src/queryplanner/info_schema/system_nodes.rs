@@ -0,0 +1,86 @@
+use crate::metastore::node::Node;
+use crate::metastore::IdRow;
+use crate::queryplanner::{InfoSchemaTableDef, InfoSchemaTableDefContext};
+use crate::CubeError;
+use arrow::array::{ArrayRef, StringArray, TimestampNanosecondArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, TimeUnit};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+pub struct SystemNodesTableDef;
+
+#[async_trait]
+impl InfoSchemaTableDef for SystemNodesTableDef {
+    type T = IdRow<Node>;
+
+    async fn rows(&self, ctx: InfoSchemaTableDefContext) -> Result<Arc<Vec<Self::T>>, CubeError> {
+        Ok(Arc::new(ctx.meta_store.get_nodes().await?))
+    }
+
+    fn columns(&self) -> Vec<(Field, Box<dyn Fn(Arc<Vec<Self::T>>) -> ArrayRef>)> {
+        vec![
+            (
+                Field::new("id", DataType::UInt64, false),
+                Box::new(|nodes| {
+                    Arc::new(UInt64Array::from(
+                        nodes.iter().map(|row| row.get_id()).collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("name", DataType::Utf8, false),
+                Box::new(|nodes| {
+                    Arc::new(StringArray::from(
+                        nodes
+                            .iter()
+                            .map(|row| row.get_row().name().as_str())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("state", DataType::Utf8, false),
+                Box::new(|nodes| {
+                    Arc::new(StringArray::from(
+                        nodes
+                            .iter()
+                            .map(|row| format!("{:?}", row.get_row().state()))
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new(
+                    "registered_at",
+                    DataType::Timestamp(TimeUnit::Nanosecond, None),
+                    false,
+                ),
+                Box::new(|nodes| {
+                    Arc::new(TimestampNanosecondArray::from(
+                        nodes
+                            .iter()
+                            .map(|row| row.get_row().registered_at().timestamp_nanos())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new(
+                    "last_heartbeat",
+                    DataType::Timestamp(TimeUnit::Nanosecond, None),
+                    false,
+                ),
+                Box::new(|nodes| {
+                    Arc::new(TimestampNanosecondArray::from(
+                        nodes
+                            .iter()
+                            .map(|row| row.get_row().last_heartbeat().timestamp_nanos())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+        ]
+    }
+}
+
+crate::base_info_schema_table_def!(SystemNodesTableDef);
@@ -2,8 +2,8 @@ use crate::metastore::partition::partition_file_name;
 use crate::metastore::{IdRow, MetaStoreTable, Partition};
 use crate::queryplanner::{InfoSchemaTableDef, InfoSchemaTableDefContext};
 use crate::CubeError;
-use arrow::array::{ArrayRef, BooleanArray, StringArray, UInt64Array};
-use arrow::datatypes::{DataType, Field};
+use arrow::array::{ArrayRef, BooleanArray, StringArray, TimestampMicrosecondArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, TimeUnit};
 use async_trait::async_trait;
 use std::sync::Arc;
 
@@ -158,6 +158,21 @@ impl InfoSchemaTableDef for SystemPartitionsTableDef {
                     ))
                 }),
             ),
+            (
+                Field::new(
+                    "time_bucket",
+                    DataType::Timestamp(TimeUnit::Microsecond, None),
+                    true,
+                ),
+                Box::new(|partitions| {
+                    Arc::new(TimestampMicrosecondArray::from(
+                        partitions
+                            .iter()
+                            .map(|row| row.get_row().time_bucket().map(|b| b * 1_000_000))
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
         ]
     }
 }
@@ -0,0 +1,128 @@
+use crate::queryplanner::{InfoSchemaTableDef, InfoSchemaTableDefContext};
+use crate::sql::query_log::QueryLogEntry;
+use crate::CubeError;
+use arrow::array::{ArrayRef, StringArray, TimestampNanosecondArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, TimeUnit};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+pub struct SystemQueriesTableDef;
+
+#[async_trait]
+impl InfoSchemaTableDef for SystemQueriesTableDef {
+    type T = QueryLogEntry;
+
+    async fn rows(&self, ctx: InfoSchemaTableDefContext) -> Result<Arc<Vec<Self::T>>, CubeError> {
+        Ok(Arc::new(ctx.query_log.entries()))
+    }
+
+    fn columns(&self) -> Vec<(Field, Box<dyn Fn(Arc<Vec<Self::T>>) -> ArrayRef>)> {
+        vec![
+            (
+                Field::new("id", DataType::UInt64, false),
+                Box::new(|queries| {
+                    Arc::new(UInt64Array::from(
+                        queries.iter().map(|row| row.id).collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new(
+                    "started_at",
+                    DataType::Timestamp(TimeUnit::Nanosecond, None),
+                    false,
+                ),
+                Box::new(|queries| {
+                    Arc::new(TimestampNanosecondArray::from(
+                        queries
+                            .iter()
+                            .map(|row| row.started_at.timestamp_nanos())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("query", DataType::Utf8, false),
+                Box::new(|queries| {
+                    Arc::new(StringArray::from(
+                        queries
+                            .iter()
+                            .map(|row| row.query.as_str())
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("trace_obj", DataType::Utf8, true),
+                Box::new(|queries| {
+                    Arc::new(StringArray::from_iter(
+                        queries.iter().map(|row| row.trace_obj.as_ref()),
+                    ))
+                }),
+            ),
+            (
+                Field::new("user", DataType::Utf8, true),
+                Box::new(|queries| {
+                    Arc::new(StringArray::from_iter(
+                        queries.iter().map(|row| row.user.as_ref()),
+                    ))
+                }),
+            ),
+            (
+                Field::new("duration_ms", DataType::UInt64, false),
+                Box::new(|queries| {
+                    Arc::new(UInt64Array::from(
+                        queries
+                            .iter()
+                            .map(|row| row.duration_ms)
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("partitions_scanned", DataType::UInt64, true),
+                Box::new(|queries| {
+                    Arc::new(UInt64Array::from(
+                        queries
+                            .iter()
+                            .map(|row| row.partitions_scanned)
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("rows_scanned", DataType::UInt64, true),
+                Box::new(|queries| {
+                    Arc::new(UInt64Array::from(
+                        queries
+                            .iter()
+                            .map(|row| row.rows_scanned)
+                            .collect::<Vec<_>>(),
+                    ))
+                }),
+            ),
+            (
+                Field::new("worker_timings", DataType::Utf8, true),
+                Box::new(|queries| {
+                    Arc::new(StringArray::from_iter(queries.iter().map(|row| {
+                        if row.worker_timings.is_empty() {
+                            None
+                        } else {
+                            serde_json::to_string(&row.worker_timings).ok()
+                        }
+                    })))
+                }),
+            ),
+            (
+                Field::new("error", DataType::Utf8, true),
+                Box::new(|queries| {
+                    Arc::new(StringArray::from_iter(
+                        queries.iter().map(|row| row.error.as_ref()),
+                    ))
+                }),
+            ),
+        ]
+    }
+}
+
+crate::base_info_schema_table_def!(SystemQueriesTableDef);
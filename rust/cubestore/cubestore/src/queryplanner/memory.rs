@@ -0,0 +1,205 @@
+use arrow::record_batch::RecordBatch;
+use datafusion::error::DataFusionError;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Memory available to all queries running in the current process. Select workers run in
+/// separate processes, so each of them gets its own pool.
+#[derive(Debug)]
+pub struct MemoryPool {
+    /// Zero means no limit.
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryPool {
+    pub fn new(limit: usize) -> Arc<MemoryPool> {
+        Arc::new(MemoryPool {
+            limit,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    fn try_grow(&self, bytes: usize) -> Result<(), DataFusionError> {
+        try_grow(&self.used, self.limit, bytes).map_err(|used| {
+            DataFusionError::Execution(format!(
+                "Query exceeded memory limit: queries on this worker use {} out of {} bytes",
+                used, self.limit
+            ))
+        })
+    }
+
+    fn shrink(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+/// Memory used by a single query. Allocations are checked against both the query limit and the
+/// limit of the [MemoryPool]. Operators that can spill to disk do so when they hit either of them.
+#[derive(Debug)]
+pub struct QueryMemory {
+    pool: Arc<MemoryPool>,
+    /// Zero means no limit.
+    limit: usize,
+    used: AtomicUsize,
+    spill_dir: PathBuf,
+}
+
+impl QueryMemory {
+    pub fn new(pool: Arc<MemoryPool>, limit: usize, spill_dir: PathBuf) -> Arc<QueryMemory> {
+        Arc::new(QueryMemory {
+            pool,
+            limit,
+            used: AtomicUsize::new(0),
+            spill_dir,
+        })
+    }
+
+    /// No limits, mostly for tests.
+    pub fn unlimited(spill_dir: PathBuf) -> Arc<QueryMemory> {
+        QueryMemory::new(MemoryPool::new(0), 0, spill_dir)
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn spill_dir(&self) -> &Path {
+        &self.spill_dir
+    }
+
+    pub fn reservation(self: &Arc<Self>) -> MemoryReservation {
+        MemoryReservation {
+            memory: self.clone(),
+            size: 0,
+        }
+    }
+
+    fn try_grow(&self, bytes: usize) -> Result<(), DataFusionError> {
+        try_grow(&self.used, self.limit, bytes).map_err(|_| {
+            DataFusionError::Execution(format!(
+                "Query exceeded memory limit of {} bytes",
+                self.limit
+            ))
+        })?;
+        if let Err(e) = self.pool.try_grow(bytes) {
+            self.used.fetch_sub(bytes, Ordering::AcqRel);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn shrink(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+        self.pool.shrink(bytes);
+    }
+}
+
+/// Memory held by a single operator, released on drop.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    memory: Arc<QueryMemory>,
+    size: usize,
+}
+
+impl MemoryReservation {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Fails with the "query exceeded memory limit" error, operators that can spill should do so
+    /// and retry.
+    pub fn try_grow(&mut self, bytes: usize) -> Result<(), DataFusionError> {
+        self.memory.try_grow(bytes)?;
+        self.size += bytes;
+        Ok(())
+    }
+
+    pub fn free(&mut self) {
+        self.memory.shrink(self.size);
+        self.size = 0;
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.free()
+    }
+}
+
+pub fn batch_memory_size(batch: &RecordBatch) -> usize {
+    batch
+        .columns()
+        .iter()
+        .map(|c| c.get_array_memory_size())
+        .sum()
+}
+
+/// Returns the current usage on failure.
+fn try_grow(used: &AtomicUsize, limit: usize, bytes: usize) -> Result<(), usize> {
+    let mut current = used.load(Ordering::Acquire);
+    loop {
+        if limit != 0 && limit < current + bytes {
+            return Err(current);
+        }
+        match used.compare_exchange_weak(
+            current,
+            current + bytes,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return Ok(()),
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let pool = MemoryPool::new(100);
+        let q1 = QueryMemory::new(pool.clone(), 60, PathBuf::new());
+        let q2 = QueryMemory::new(pool.clone(), 60, PathBuf::new());
+
+        let mut r1 = q1.reservation();
+        r1.try_grow(50).unwrap();
+        let err = r1.try_grow(20).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Query exceeded memory limit of 60 bytes"),
+            "{}",
+            err
+        );
+        assert_eq!(r1.size(), 50);
+
+        let mut r2 = q2.reservation();
+        r2.try_grow(40).unwrap();
+        let err = r2.try_grow(20).unwrap_err();
+        assert!(
+            err.to_string().contains("use 90 out of 100 bytes"),
+            "{}",
+            err
+        );
+        // Failed allocations are not counted.
+        assert_eq!(q2.used(), 40);
+        assert_eq!(pool.used(), 90);
+
+        drop(r1);
+        assert_eq!(q1.used(), 0);
+        r2.try_grow(20).unwrap();
+        assert_eq!(pool.used(), 60);
+        r2.free();
+        assert_eq!(pool.used(), 0);
+
+        let mut r = QueryMemory::unlimited(PathBuf::new()).reservation();
+        r.try_grow(usize::MAX / 2).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 pub mod hll;
+pub mod memory;
 mod optimizations;
 pub mod panic;
 mod partition_filter;
@@ -7,29 +8,39 @@ pub use planning::PlanningMeta;
 pub mod pretty_printers;
 pub mod query_executor;
 pub mod serialized_plan;
+mod spill;
 mod topk;
 pub use topk::MIN_TOPK_STREAM_ROWS;
+pub mod batch_cache;
 mod coalesce;
+pub mod cost;
+pub mod explain;
+mod filter_by_hash_bucket;
 mod filter_by_key_range;
 mod flatten_union;
+pub mod grouping_sets;
 pub mod info_schema;
 pub mod now;
 pub mod udfs;
 
 use crate::cachestore::CacheStore;
+use crate::cluster::membership::{ClusterMembership, WorkerSet};
 use crate::config::injection::DIService;
 use crate::config::ConfigObj;
 use crate::metastore::multi_index::MultiPartition;
 use crate::metastore::table::{Table, TablePath};
 use crate::metastore::{IdRow, MetaStore};
 use crate::queryplanner::flatten_union::FlattenUnion;
+use crate::queryplanner::grouping_sets::rewrite_grouping_sets;
 use crate::queryplanner::info_schema::{
     SchemataInfoSchemaTableDef, SystemCacheTableDef, SystemChunksTableDef, SystemIndexesTableDef,
-    SystemJobsTableDef, SystemPartitionsTableDef, SystemQueueTableDef, SystemReplayHandlesTableDef,
-    SystemSnapshotsTableDef, SystemTablesTableDef, TablesInfoSchemaTableDef,
+    SystemJobsTableDef, SystemMaterializedViewsTableDef, SystemNodesTableDef,
+    SystemPartitionsTableDef, SystemQueriesTableDef, SystemQueueTableDef,
+    SystemReplayHandlesTableDef, SystemSnapshotsTableDef, SystemTablesTableDef,
+    TablesInfoSchemaTableDef,
 };
 use crate::queryplanner::now::MaterializeNow;
-use crate::queryplanner::planning::{choose_index_ext, ClusterSendNode};
+use crate::queryplanner::planning::{choose_index_ext, ClusterSendNode, JoinOptions};
 use crate::queryplanner::query_executor::{
     batch_to_dataframe, ClusterSendExec, InlineTableProvider,
 };
@@ -38,6 +49,7 @@ use crate::queryplanner::topk::ClusterAggregateTopK;
 use crate::queryplanner::udfs::aggregate_udf_by_kind;
 use crate::queryplanner::udfs::{scalar_udf_by_kind, CubeAggregateUDFKind, CubeScalarUDFKind};
 
+use crate::sql::query_log::QueryLog;
 use crate::sql::InlineTables;
 use crate::store::DataFrame;
 use crate::{app_metrics, metastore, CubeError};
@@ -86,6 +98,8 @@ pub struct QueryPlannerImpl {
     meta_store: Arc<dyn MetaStore>,
     cache_store: Arc<dyn CacheStore>,
     config: Arc<dyn ConfigObj>,
+    membership: Arc<ClusterMembership>,
+    query_log: Arc<QueryLog>,
 }
 
 crate::di_service!(QueryPlannerImpl, [QueryPlanner]);
@@ -108,9 +122,11 @@ impl QueryPlanner for QueryPlannerImpl {
             self.meta_store.get_tables_with_path(false).await?,
             self.meta_store.clone(),
             self.cache_store.clone(),
+            self.query_log.clone(),
             inline_tables,
         );
 
+        let statement = rewrite_grouping_sets(statement)?;
         let query_planner = SqlToRel::new(&schema_provider);
         let mut logical_plan = query_planner.statement_to_plan(&statement)?;
 
@@ -118,14 +134,20 @@ impl QueryPlanner for QueryPlannerImpl {
         trace!("Logical Plan: {:#?}", &logical_plan);
 
         let plan = if SerializedPlan::is_data_select_query(&logical_plan) {
+            let select_workers = self.membership.workers();
+            let join_options = JoinOptions {
+                broadcast_rows: self.config.join_broadcast_rows(),
+                hash_buckets: select_workers.workers().len() as u64,
+            };
             let (logical_plan, meta) = choose_index_ext(
                 &logical_plan,
                 &self.meta_store.as_ref(),
                 self.config.enable_topk(),
+                join_options,
             )
             .await?;
             let workers = compute_workers(
-                self.config.as_ref(),
+                select_workers.as_ref(),
                 &logical_plan,
                 &meta.multi_part_subtree,
             )?;
@@ -161,11 +183,15 @@ impl QueryPlannerImpl {
         meta_store: Arc<dyn MetaStore>,
         cache_store: Arc<dyn CacheStore>,
         config: Arc<dyn ConfigObj>,
+        membership: Arc<ClusterMembership>,
+        query_log: Arc<QueryLog>,
     ) -> Arc<QueryPlannerImpl> {
         Arc::new(QueryPlannerImpl {
             meta_store,
             cache_store,
             config,
+            membership,
+            query_log,
         })
     }
 }
@@ -186,6 +212,7 @@ struct MetaStoreSchemaProvider {
     by_name: HashSet<TableKey>,
     meta_store: Arc<dyn MetaStore>,
     cache_store: Arc<dyn CacheStore>,
+    query_log: Arc<QueryLog>,
     inline_tables: InlineTables,
 }
 
@@ -221,6 +248,7 @@ impl MetaStoreSchemaProvider {
         tables: Arc<Vec<TablePath>>,
         meta_store: Arc<dyn MetaStore>,
         cache_store: Arc<dyn CacheStore>,
+        query_log: Arc<QueryLog>,
         inline_tables: &InlineTables,
     ) -> Self {
         let by_name = tables.iter().map(|t| TableKey(t)).collect();
@@ -229,6 +257,7 @@ impl MetaStoreSchemaProvider {
             by_name,
             meta_store,
             cache_store,
+            query_log,
             inline_tables: (*inline_tables).clone(),
         }
     }
@@ -271,6 +300,7 @@ impl ContextProvider for MetaStoreSchemaProvider {
                     Vec::new(),
                     None,
                     None,
+                    None,
                 ),
             ),
             schema: Arc::new(IdRow::new(0, metastore::Schema::new(schema.to_string()))),
@@ -299,58 +329,87 @@ impl ContextProvider for MetaStoreSchemaProvider {
             ("information_schema", "tables") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::Tables,
             ))),
             ("information_schema", "schemata") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::Schemata,
             ))),
             ("system", "cache") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemCache,
             ))),
             ("system", "tables") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemTables,
             ))),
             ("system", "indexes") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemIndexes,
             ))),
             ("system", "partitions") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemPartitions,
             ))),
             ("system", "chunks") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemChunks,
             ))),
             ("system", "queue") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemQueue,
             ))),
             ("system", "replay_handles") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemReplayHandles,
             ))),
             ("system", "jobs") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemJobs,
             ))),
+            ("system", "nodes") => Some(Arc::new(InfoSchemaTableProvider::new(
+                self.meta_store.clone(),
+                self.cache_store.clone(),
+                self.query_log.clone(),
+                InfoSchemaTable::SystemNodes,
+            ))),
             ("system", "snapshots") => Some(Arc::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
                 self.cache_store.clone(),
+                self.query_log.clone(),
                 InfoSchemaTable::SystemSnapshots,
             ))),
+            ("system", "materialized_views") => Some(Arc::new(InfoSchemaTableProvider::new(
+                self.meta_store.clone(),
+                self.cache_store.clone(),
+                self.query_log.clone(),
+                InfoSchemaTable::SystemMaterializedViews,
+            ))),
+            ("system", "queries") => Some(Arc::new(InfoSchemaTableProvider::new(
+                self.meta_store.clone(),
+                self.cache_store.clone(),
+                self.query_log.clone(),
+                InfoSchemaTable::SystemQueries,
+            ))),
             _ => None,
         })
     }
@@ -363,6 +422,14 @@ impl ContextProvider for MetaStoreSchemaProvider {
             "unix_timestamp" | "UNIX_TIMESTAMP" => CubeScalarUDFKind::UnixTimestamp,
             "date_add" | "DATE_ADD" => CubeScalarUDFKind::DateAdd,
             "date_sub" | "DATE_SUB" => CubeScalarUDFKind::DateSub,
+            "hll_init" | "HLL_INIT" => CubeScalarUDFKind::HllInit,
+            "hll_export_postgres" | "HLL_EXPORT_POSTGRES" => CubeScalarUDFKind::HllExportPostgres,
+            "hll_export_snowflake" | "HLL_EXPORT_SNOWFLAKE" => {
+                CubeScalarUDFKind::HllExportSnowflake
+            }
+            "hll_convert" | "HLL_CONVERT" => CubeScalarUDFKind::HllConvert,
+            "theta_estimate" | "THETA_ESTIMATE" => CubeScalarUDFKind::ThetaEstimate,
+            "theta_a_not_b" | "THETA_A_NOT_B" => CubeScalarUDFKind::ThetaANotB,
             _ => return None,
         };
         return Some(Arc::new(scalar_udf_by_kind(kind).descriptor()));
@@ -373,6 +440,14 @@ impl ContextProvider for MetaStoreSchemaProvider {
         // TODO: case-insensitive names.
         let kind = match name {
             "merge" | "MERGE" => CubeAggregateUDFKind::MergeHll,
+            "any_value" | "ANY_VALUE" => CubeAggregateUDFKind::AnyValue,
+            "last_value" | "LAST_VALUE" => CubeAggregateUDFKind::LastValue,
+            "bit_or" | "BIT_OR" => CubeAggregateUDFKind::BitOr,
+            "hll_init_agg" | "HLL_INIT_AGG" => CubeAggregateUDFKind::HllInitAgg,
+            // Theta sketches.
+            "theta_init_agg" | "THETA_INIT_AGG" => CubeAggregateUDFKind::ThetaInitAgg,
+            "theta_union" | "THETA_UNION" => CubeAggregateUDFKind::ThetaUnion,
+            "theta_intersect" | "THETA_INTERSECT" => CubeAggregateUDFKind::ThetaIntersect,
             _ => return None,
         };
         return Some(Arc::new(aggregate_udf_by_kind(kind).descriptor()));
@@ -392,11 +467,15 @@ pub enum InfoSchemaTable {
     SystemReplayHandles,
     SystemCache,
     SystemSnapshots,
+    SystemMaterializedViews,
+    SystemNodes,
+    SystemQueries,
 }
 
 pub struct InfoSchemaTableDefContext {
     meta_store: Arc<dyn MetaStore>,
     cache_store: Arc<dyn CacheStore>,
+    query_log: Arc<QueryLog>,
 }
 
 #[async_trait]
@@ -460,6 +539,9 @@ impl InfoSchemaTable {
             InfoSchemaTable::SystemJobs => Box::new(SystemJobsTableDef),
             InfoSchemaTable::SystemCache => Box::new(SystemCacheTableDef),
             InfoSchemaTable::SystemSnapshots => Box::new(SystemSnapshotsTableDef),
+            InfoSchemaTable::SystemMaterializedViews => Box::new(SystemMaterializedViewsTableDef),
+            InfoSchemaTable::SystemNodes => Box::new(SystemNodesTableDef),
+            InfoSchemaTable::SystemQueries => Box::new(SystemQueriesTableDef),
         }
     }
 
@@ -475,6 +557,7 @@ impl InfoSchemaTable {
 pub struct InfoSchemaTableProvider {
     meta_store: Arc<dyn MetaStore>,
     cache_store: Arc<dyn CacheStore>,
+    query_log: Arc<QueryLog>,
     table: InfoSchemaTable,
 }
 
@@ -482,11 +565,13 @@ impl InfoSchemaTableProvider {
     fn new(
         meta_store: Arc<dyn MetaStore>,
         cache_store: Arc<dyn CacheStore>,
+        query_log: Arc<QueryLog>,
         table: InfoSchemaTable,
     ) -> InfoSchemaTableProvider {
         InfoSchemaTableProvider {
             meta_store,
             cache_store,
+            query_log,
             table,
         }
     }
@@ -511,6 +596,7 @@ impl TableProvider for InfoSchemaTableProvider {
         let exec = InfoSchemaTableExec {
             meta_store: self.meta_store.clone(),
             cache_store: self.cache_store.clone(),
+            query_log: self.query_log.clone(),
             table: self.table.clone(),
             projection: projection.clone(),
             projected_schema: project_schema(&self.schema(), projection.as_deref()),
@@ -543,6 +629,7 @@ fn project_schema(s: &Schema, projection: Option<&[usize]>) -> SchemaRef {
 pub struct InfoSchemaTableExec {
     meta_store: Arc<dyn MetaStore>,
     cache_store: Arc<dyn CacheStore>,
+    query_log: Arc<QueryLog>,
     table: InfoSchemaTable,
     projected_schema: SchemaRef,
     projection: Option<Vec<usize>>,
@@ -588,6 +675,7 @@ impl ExecutionPlan for InfoSchemaTableExec {
             .scan(InfoSchemaTableDefContext {
                 meta_store: self.meta_store.clone(),
                 cache_store: self.cache_store.clone(),
+                query_log: self.query_log.clone(),
             })
             .await?;
         let mem_exec =
@@ -639,12 +727,12 @@ impl TableProvider for CubeTableLogical {
 }
 
 fn compute_workers(
-    config: &dyn ConfigObj,
+    select_workers: &WorkerSet,
     p: &LogicalPlan,
     tree: &HashMap<u64, MultiPartition>,
 ) -> Result<Vec<String>, CubeError> {
     struct Visitor<'a> {
-        config: &'a dyn ConfigObj,
+        select_workers: &'a WorkerSet,
         tree: &'a HashMap<u64, MultiPartition>,
         workers: Vec<String>,
     }
@@ -663,7 +751,7 @@ fn compute_workers(
                         return Ok(true);
                     }
                     let workers = ClusterSendExec::distribute_to_workers(
-                        self.config,
+                        self.select_workers,
                         snapshots.as_slice(),
                         self.tree,
                     )?;
@@ -676,7 +764,7 @@ fn compute_workers(
     }
 
     let mut v = Visitor {
-        config,
+        select_workers,
         tree,
         workers: Vec::new(),
     };
@@ -1,6 +1,8 @@
 use crate::cluster::Cluster;
+use crate::queryplanner::memory::QueryMemory;
 use crate::queryplanner::optimizations::distributed_partial_aggregate::push_aggregate_to_workers;
 use crate::queryplanner::optimizations::prefer_inplace_aggregates::try_switch_to_inplace_aggregates;
+use crate::queryplanner::optimizations::spill_to_disk::use_spilling_operators;
 use crate::queryplanner::planning::CubeExtensionPlanner;
 use crate::queryplanner::serialized_plan::SerializedPlan;
 use datafusion::error::DataFusionError;
@@ -14,27 +16,36 @@ use std::sync::Arc;
 mod distributed_partial_aggregate;
 mod prefer_inplace_aggregates;
 pub mod rewrite_plan;
+mod spill_to_disk;
 
 pub struct CubeQueryPlanner {
     cluster: Option<Arc<dyn Cluster>>,
     serialized_plan: Arc<SerializedPlan>,
+    /// Sorts and aggregations spill to disk when set.
+    memory: Option<Arc<QueryMemory>>,
 }
 
 impl CubeQueryPlanner {
     pub fn new_on_router(
         cluster: Arc<dyn Cluster>,
         serialized_plan: Arc<SerializedPlan>,
+        memory: Option<Arc<QueryMemory>>,
     ) -> CubeQueryPlanner {
         CubeQueryPlanner {
             cluster: Some(cluster),
             serialized_plan,
+            memory,
         }
     }
 
-    pub fn new_on_worker(serialized_plan: Arc<SerializedPlan>) -> CubeQueryPlanner {
+    pub fn new_on_worker(
+        serialized_plan: Arc<SerializedPlan>,
+        memory: Option<Arc<QueryMemory>>,
+    ) -> CubeQueryPlanner {
         CubeQueryPlanner {
             serialized_plan,
             cluster: None,
+            memory,
         }
     }
 }
@@ -52,13 +63,20 @@ impl QueryPlanner for CubeQueryPlanner {
             })])
             .create_physical_plan(logical_plan, ctx_state)?;
         // TODO: assert there is only a single ClusterSendExec in the plan.
-        finalize_physical_plan(p)
+        finalize_physical_plan(p, self.memory.as_ref())
     }
 }
 
 fn finalize_physical_plan(
     p: Arc<dyn ExecutionPlan>,
+    memory: Option<&Arc<QueryMemory>>,
 ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
     let p = rewrite_physical_plan(p.as_ref(), &mut |p| try_switch_to_inplace_aggregates(p))?;
-    rewrite_physical_plan(p.as_ref(), &mut |p| push_aggregate_to_workers(p))
+    let p = rewrite_physical_plan(p.as_ref(), &mut |p| push_aggregate_to_workers(p))?;
+    match memory {
+        Some(memory) => {
+            rewrite_physical_plan(p.as_ref(), &mut |p| use_spilling_operators(p, memory))
+        }
+        None => Ok(p),
+    }
 }
@@ -121,7 +121,16 @@ pub fn rewrite_plan<'a, R: PlanRewriter>(
                     .collect::<Result<Vec<_>, _>>()?,
             ),
         },
-        LogicalPlan::Window { .. } | LogicalPlan::CrossJoin { .. } => {
+        LogicalPlan::Window {
+            input,
+            window_expr,
+            schema,
+        } => LogicalPlan::Window {
+            input: Arc::new(rewrite_plan(input.as_ref(), ctx, f)?),
+            window_expr: window_expr.clone(),
+            schema: schema.clone(),
+        },
+        LogicalPlan::CrossJoin { .. } => {
             return Err(DataFusionError::Internal(
                 "unsupported operation".to_string(),
             ))
@@ -0,0 +1,46 @@
+use crate::queryplanner::memory::QueryMemory;
+use crate::queryplanner::spill::{SpillingAggregateExec, SpillingSortExec};
+use crate::queryplanner::topk::AggregateTopKExec;
+use datafusion::error::DataFusionError;
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::sort::SortExec;
+use datafusion::physical_plan::ExecutionPlan;
+use std::sync::Arc;
+
+/// Replaces sorts and hash aggregations with versions that write intermediate results to disk
+/// when they hit the memory limit of the query. Top-k aggregations switch to a spilling
+/// aggregation and sort once their groups exceed the limit.
+pub fn use_spilling_operators(
+    p: Arc<dyn ExecutionPlan>,
+    memory: &Arc<QueryMemory>,
+) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+    if let Some(agg) = p.as_any().downcast_ref::<HashAggregateExec>() {
+        return Ok(
+            match SpillingAggregateExec::try_wrap(agg, memory.clone())? {
+                Some(s) => Arc::new(s),
+                None => p,
+            },
+        );
+    }
+    if let Some(sort) = p.as_any().downcast_ref::<SortExec>() {
+        return Ok(Arc::new(SpillingSortExec {
+            expr: sort.expr().to_vec(),
+            input: sort.input().clone(),
+            memory: memory.clone(),
+        }));
+    }
+    if let Some(topk) = p.as_any().downcast_ref::<AggregateTopKExec>() {
+        return Ok(Arc::new(AggregateTopKExec {
+            limit: topk.limit,
+            key_len: topk.key_len,
+            agg_expr: topk.agg_expr.clone(),
+            agg_descr: topk.agg_descr.clone(),
+            order_by: topk.order_by.clone(),
+            having: topk.having.clone(),
+            cluster: topk.cluster.clone(),
+            schema: topk.schema.clone(),
+            memory: Some(memory.clone()),
+        }));
+    }
+    Ok(p)
+}
@@ -1,7 +1,9 @@
-use crate::table::{cmp_same_types, TableValue};
+use crate::sql::timestamp_from_string;
+use crate::table::{cmp_same_types, TableValue, TimestampValue};
 use crate::util::decimal::Decimal;
 use arrow::datatypes::{DataType, Schema};
 use datafusion::logical_plan::{Column, Expr, Operator};
+use datafusion::physical_plan::functions::BuiltinScalarFunction;
 use datafusion::scalar::ScalarValue;
 use std::cmp::Ordering;
 
@@ -27,6 +29,11 @@ impl PartitionFilter {
         PartitionFilter { min_max: r }
     }
 
+    /// True iff the filter matches everything.
+    pub fn is_unconstrained(&self) -> bool {
+        self.min_max.is_empty()
+    }
+
     /// Returns whether any rows between `min_row` and `max_row` could potentially match the filter.
     /// When this returns false, the corresponding rows can safely be ignored.
     pub fn can_match(
@@ -310,15 +317,27 @@ impl Builder<'_> {
         value: &Expr,
     ) -> Option<ColumnStat> {
         // TODO: fold constant expressions.
+        let field = self.schema.field_with_name(&col.name).ok()?;
+        let is_timestamp = matches!(field.data_type(), DataType::Timestamp(_, _));
+
         let scalar;
-        if let Expr::Literal(s) = value {
-            scalar = s;
-        } else {
-            return None;
+        match value {
+            Expr::Literal(s) => scalar = s,
+            // Timestamp literals are parsed from strings below.
+            Expr::Cast {
+                expr: box Expr::Literal(s),
+                ..
+            } if is_timestamp => scalar = s,
+            Expr::ScalarFunction {
+                fun: BuiltinScalarFunction::ToTimestamp,
+                args,
+            } if is_timestamp => match args.as_slice() {
+                [Expr::Literal(s)] => scalar = s,
+                _ => return None,
+            },
+            _ => return None,
         }
 
-        let field = self.schema.field_with_name(&col.name).ok()?;
-
         // TODO: all the other types. For now assume strings and numbers.
         let limit_val;
         if let Some(v) = Self::scalar_to_value(scalar, field.data_type()) {
@@ -405,11 +424,26 @@ impl Builder<'_> {
             DataType::Int64Decimal(scale) => Self::extract_decimal(v, *scale),
             DataType::Boolean => Self::extract_bool(v),
             DataType::Utf8 => Self::extract_string(v),
+            DataType::Timestamp(_, _) => Self::extract_timestamp(v),
             _ => None,
             // TODO: more data types
         }
     }
 
+    fn extract_timestamp(v: &ScalarValue) -> Option<TableValue> {
+        let nanos = match v {
+            ScalarValue::TimestampNanosecond(v) => (*v)?,
+            ScalarValue::TimestampMicrosecond(v) => (*v)?.checked_mul(1_000)?,
+            ScalarValue::TimestampMillisecond(v) => (*v)?.checked_mul(1_000_000)?,
+            ScalarValue::TimestampSecond(v) => (*v)?.checked_mul(1_000_000_000)?,
+            ScalarValue::Utf8(s) | ScalarValue::LargeUtf8(s) => {
+                timestamp_from_string(s.as_ref()?).ok()?.get_time_stamp()
+            }
+            _ => return None,
+        };
+        Some(TableValue::Timestamp(TimestampValue::new(nanos)))
+    }
+
     fn extract_bool(v: &ScalarValue) -> Option<TableValue> {
         match v {
             ScalarValue::Boolean(v) => v.as_ref().map(|v| TableValue::Boolean(*v)),
@@ -557,7 +591,7 @@ impl Builder<'_> {
 mod tests {
     use super::*;
     use crate::sql::parser::{CubeStoreParser, Statement as CubeStatement};
-    use arrow::datatypes::Field;
+    use arrow::datatypes::{Field, TimeUnit};
     use datafusion::catalog::TableReference;
     use datafusion::datasource::TableProvider;
     use datafusion::logical_plan::ToDFSchema;
@@ -687,6 +721,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_timestamps() {
+        let s = schema(&[("a", DataType::Timestamp(TimeUnit::Microsecond, None))]);
+        let extract = |sql| PartitionFilter::extract(&s, &[parse(sql, &s)]);
+        let ts = |s| TableValue::Timestamp(timestamp_from_string(s).unwrap());
+
+        assert_eq!(
+            extract(
+                "a >= to_timestamp('2022-01-01T00:00:00Z') AND a <= CAST('2022-01-02T00:00:00Z' AS TIMESTAMP)"
+            )
+            .min_max,
+            vec![MinMaxCondition {
+                min: vec![Some(ts("2022-01-01T00:00:00Z"))],
+                max: vec![Some(ts("2022-01-02T00:00:00Z"))],
+            }]
+        );
+        assert_eq!(extract("a >= 'yesterday'").min_max, vec![]);
+    }
+
     #[test]
     fn test_bools() {
         let s = schema(&[("a", DataType::Boolean)]);
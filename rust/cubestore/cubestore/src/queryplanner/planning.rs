@@ -17,14 +17,16 @@
 //!       At this point we also optimize the physical plan to ensure we do as much work as possible
 //!       on the workers, see [CubeQueryPlanner] for details.
 use std::collections::hash_map::RandomState;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
-use arrow::datatypes::{Field, SchemaRef};
+use arrow::datatypes::{DataType, Field, SchemaRef};
 use async_trait::async_trait;
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::ExecutionContextState;
-use datafusion::logical_plan::{DFSchemaRef, Expr, LogicalPlan, Operator, UserDefinedLogicalNode};
+use datafusion::logical_plan::{
+    DFField, DFSchema, DFSchemaRef, Expr, JoinType, LogicalPlan, Operator, UserDefinedLogicalNode,
+};
 use datafusion::physical_plan::aggregates::AggregateFunction as FusionAggregateFunction;
 use datafusion::physical_plan::empty::EmptyExec;
 use datafusion::physical_plan::planner::ExtensionPlanner;
@@ -41,15 +43,17 @@ use crate::metastore::table::{Table, TablePath};
 use crate::metastore::{
     AggregateFunction, Chunk, Column, IdRow, Index, IndexType, MetaStore, Partition, Schema,
 };
+use crate::queryplanner::cost::{estimate_index_cost, extract_column_filters};
 use crate::queryplanner::optimizations::rewrite_plan::{rewrite_plan, PlanRewriter};
 use crate::queryplanner::panic::{plan_panic_worker, PanicWorkerNode};
 use crate::queryplanner::partition_filter::PartitionFilter;
 use crate::queryplanner::query_executor::{ClusterSendExec, CubeTable, InlineTableProvider};
 use crate::queryplanner::serialized_plan::{
-    IndexSnapshot, InlineSnapshot, PartitionSnapshot, SerializedPlan,
+    IndexSnapshot, InlineSnapshot, JoinDistribution, PartitionSnapshot, SerializedPlan,
 };
 use crate::queryplanner::topk::{materialize_topk, plan_topk, ClusterAggregateTopK};
 use crate::queryplanner::CubeTableLogical;
+use crate::table::TableValue;
 use crate::CubeError;
 use datafusion::logical_plan;
 use datafusion::optimizer::utils::expr_to_columns;
@@ -64,7 +68,26 @@ pub async fn choose_index(
     p: &LogicalPlan,
     metastore: &dyn PlanIndexStore,
 ) -> Result<(LogicalPlan, PlanningMeta), DataFusionError> {
-    choose_index_ext(p, metastore, true).await
+    choose_index_ext(p, metastore, true, JoinOptions::default()).await
+}
+
+/// Controls how joins without an index sorted by the join key are distributed, see
+/// [JoinDistribution].
+#[derive(Clone, Copy, Debug)]
+pub struct JoinOptions {
+    /// Max rows of a side of the join sent to all workers.
+    pub broadcast_rows: u64,
+    /// Number of workers sides larger than `broadcast_rows` are split into.
+    pub hash_buckets: u64,
+}
+
+impl Default for JoinOptions {
+    fn default() -> Self {
+        JoinOptions {
+            broadcast_rows: 1_000_000,
+            hash_buckets: 1,
+        }
+    }
 }
 
 /// Information required to distribute the logical plan into multiple workers.
@@ -92,6 +115,7 @@ pub async fn choose_index_ext(
     p: &LogicalPlan,
     metastore: &dyn PlanIndexStore,
     enable_topk: bool,
+    join_options: JoinOptions,
 ) -> Result<(LogicalPlan, PlanningMeta), DataFusionError> {
     // Prepare information to choose the index.
     let mut collector = CollectConstraints::default();
@@ -120,32 +144,88 @@ pub async fn choose_index_ext(
     }
 
     // We pick partitioned index only when all tables request the same one.
-    let mut indices: Vec<_> = match all_have_same_partitioned_index(&candidates) {
-        true => candidates
-            .into_iter()
-            .map(|c| c.partitioned_index.unwrap())
-            .collect(),
-        // We sometimes propagate 'index for join not found' error here.
-        false => candidates
-            .into_iter()
-            .map(|c| c.ordinary_index)
-            .collect::<Result<_, DataFusionError>>()?,
-    };
+    let all_partitioned = all_have_same_partitioned_index(&candidates);
+    let mut indices = Vec::with_capacity(candidates.len());
+    let mut alternatives = Vec::with_capacity(candidates.len());
+    for c in candidates {
+        if all_partitioned {
+            indices.push(c.partitioned_index.unwrap());
+            alternatives.push(Vec::new());
+        } else {
+            indices.push(c.ordinary_index);
+            alternatives.push(c.alternatives);
+        }
+    }
 
+    // Partitions of the alternatives go after the ones of the picked indices.
+    let index_ids = indices
+        .iter()
+        .chain(alternatives.iter().flatten())
+        .map(|i| i.index.get_id())
+        .collect_vec();
     // TODO should be single snapshot read to ensure read consistency here
-    let partitions = metastore
-        .get_active_partitions_and_chunks_by_index_id_for_select(
-            indices.iter().map(|i| i.index.get_id()).collect_vec(),
-        )
+    let mut partitions = metastore
+        .get_active_partitions_and_chunks_by_index_id_for_select(index_ids.clone())
         .await?;
-    assert_eq!(partitions.len(), indices.len());
-    for ((i, c), ps) in indices
+    assert_eq!(partitions.len(), index_ids.len());
+    let mut alternative_partitions = partitions.split_off(indices.len()).into_iter();
+    for (((i, c), ps), alternatives) in indices
         .iter_mut()
         .zip(collector.constraints.iter())
         .zip(partitions)
+        .zip(alternatives)
     {
-        i.partitions = pick_partitions(i, c, ps)?
+        let (partitions, pruned_partitions) = pick_partitions(i, c, ps)?;
+        i.partitions = partitions;
+        i.pruned_partitions = pruned_partitions;
+        if alternatives.is_empty() {
+            continue;
+        }
+
+        // Switch to an alternative only when it is strictly cheaper, ties keep the index picked
+        // by the sort key match.
+        let eq_columns = single_value_filter_column_names(&c.filters);
+        let column_filters =
+            extract_column_filters(i.table_path.table.get_row().get_columns(), &c.filters);
+        let mut costs = vec![estimate_index_cost(
+            &i.index,
+            &i.partitions,
+            &eq_columns,
+            &column_filters,
+        )];
+        let mut cheapest: Option<(usize, IndexSnapshot)> = None;
+        for mut a in alternatives {
+            let (partitions, pruned_partitions) =
+                pick_partitions(&a, c, alternative_partitions.next().unwrap())?;
+            a.partitions = partitions;
+            a.pruned_partitions = pruned_partitions;
+            let cost = estimate_index_cost(&a.index, &a.partitions, &eq_columns, &column_filters);
+            let cheapest_cost = match &cheapest {
+                Some((j, _)) => &costs[*j],
+                None => &costs[0],
+            };
+            if cost.is_cheaper(cheapest_cost) {
+                cheapest = Some((costs.len(), a));
+            }
+            costs.push(cost);
+        }
+        if let Some((j, a)) = cheapest {
+            *i = a;
+            costs.swap(0, j);
+        }
+        i.index_choice = costs;
     }
+    choose_join_distribution(&mut indices, &collector.constraints, join_options)?;
+
+    let p = rewrite_plan(p, &(), &mut LastValueBySeq)?;
+
+    // Aggregate indices store counts, so `COUNT` over them has to be replaced with `SUM`.
+    let mut r = CountOverAggregateIndex {
+        chosen_indices: &indices,
+        next_index: 0,
+    };
+    let p = rewrite_plan(&p, &(), &mut r)?;
+    assert_eq!(r.next_index, indices.len());
 
     // We have enough information to finalize the logical plan.
     let mut r = ChooseIndex {
@@ -153,9 +233,25 @@ pub async fn choose_index_ext(
         next_index: 0,
         enable_topk,
     };
-    let plan = rewrite_plan(p, &(), &mut r)?;
+    let plan = rewrite_plan(&p, &(), &mut r)?;
     assert_eq!(r.next_index, indices.len());
 
+    // Done after the index choice, the rewrites above expect tables in the original order.
+    let swap = choose_join_order(&indices, &collector.constraints);
+    let plan = if swap.is_empty() {
+        plan
+    } else {
+        rewrite_plan(
+            &plan,
+            &(),
+            &mut SwapJoinInputs {
+                swap,
+                entered_joins: Vec::new(),
+                next_join_id: 0,
+            },
+        )?
+    };
+
     let mut multi_parts = Vec::new();
     for i in &indices {
         for p in &i.partitions {
@@ -176,6 +272,224 @@ pub async fn choose_index_ext(
     ))
 }
 
+/// Joins where one of the sides isn't sorted by the join key are executed with a hash join.
+/// Instead of pairing each partition of one side with each partition of the other, we send the
+/// smaller side to every worker or split both sides into buckets by the join key.
+/// Only joins that read tables directly on both sides are considered.
+///
+/// Both distributions run subqueries on workers that don't own the partitions they read. In memory
+/// chunks are only available on the partition owner, so tables with in memory chunks are never
+/// distributed this way.
+///
+/// Pairing partitions only works for joins sorted by the join key or when everything runs on a
+/// single worker, so planning fails when no distribution could be chosen for a multi-worker cluster.
+fn choose_join_distribution(
+    indices: &mut [IndexSnapshot],
+    constraints: &[IndexConstraints],
+    options: JoinOptions,
+) -> Result<(), DataFusionError> {
+    for (join_id, sides) in join_sides(constraints) {
+        let join_side = |i: usize| constraints[i].join_side.as_ref().unwrap();
+        let all = sides.iter().flatten().cloned().collect_vec();
+        // Both sides are sorted, this is a merge join.
+        if all.iter().all(|i| indices[*i].sort_on.is_some()) {
+            continue;
+        }
+        // Multi-partitions already define the distribution.
+        if all.iter().any(|i| {
+            indices[*i]
+                .partitions
+                .iter()
+                .any(|p| p.partition.get_row().multi_partition_id().is_some())
+        }) {
+            continue;
+        }
+
+        // Only tables can be distributed, not results of other joins.
+        if sides.iter().all(|s| !s.is_empty()) {
+            if let Some(distribution) =
+                join_distribution(indices, constraints, &sides, &all, join_id, options)
+            {
+                for (i, d) in distribution {
+                    indices[i].join_distribution = Some(d);
+                }
+                continue;
+            }
+        }
+
+        if options.hash_buckets > 1 {
+            let unsorted = *all.iter().find(|i| indices[**i].sort_on.is_none()).unwrap();
+            let on = &join_side(unsorted).on;
+            let i = &indices[unsorted];
+            let table_name = i.table_name();
+            return Err(DataFusionError::Plan(format!(
+                "Can't find index to join table {} on {}. Consider creating index: CREATE INDEX {}_{} ON {} ({})",
+                table_name,
+                on.join(", "),
+                i.table().get_row().get_table_name(),
+                on.join("_"),
+                table_name,
+                on.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Picks the distribution of each side of a join between two tables or returns `None` when
+/// partitions have to be paired.
+fn join_distribution(
+    indices: &[IndexSnapshot],
+    constraints: &[IndexConstraints],
+    sides: &[Vec<usize>; 2],
+    all: &[usize],
+    join_id: u64,
+    options: JoinOptions,
+) -> Option<Vec<(usize, JoinDistribution)>> {
+    let join_side = |i: usize| constraints[i].join_side.as_ref().unwrap();
+    let has_in_memory_chunks = |i: &usize| {
+        indices[*i]
+            .partitions
+            .iter()
+            .any(|p| p.chunks().iter().any(|c| c.get_row().in_memory()))
+    };
+
+    // Sending the preserved side of an outer join to all workers would duplicate rows.
+    let broadcast_sides = match join_side(all[0]).join_type {
+        JoinType::Inner => vec![0, 1],
+        JoinType::Left => vec![1],
+        JoinType::Right => vec![0],
+        _ => Vec::new(),
+    };
+    // Workers only know the partitions they read, not the side they read them for.
+    let same_index_on_both_sides = sides[0].iter().any(|l| {
+        sides[1]
+            .iter()
+            .any(|r| indices[*l].index.get_id() == indices[*r].index.get_id())
+    });
+    let broadcast: Option<(u64, usize)> = broadcast_sides
+        .into_iter()
+        .filter(|_| !same_index_on_both_sides)
+        .filter(|s| !sides[*s].iter().any(|i| has_in_memory_chunks(i)))
+        .map(|s| (sides[s].iter().map(|i| index_rows(&indices[*i])).sum(), s))
+        .filter(|(rows, _)| *rows <= options.broadcast_rows)
+        .min();
+    if let Some((_, s)) = broadcast {
+        return Some(
+            sides[s]
+                .iter()
+                .map(|i| (*i, JoinDistribution::Broadcast))
+                .collect(),
+        );
+    }
+
+    if options.hash_buckets <= 1
+        || all.iter().any(|i| has_in_memory_chunks(i))
+        || !can_split_by_join_key(indices, constraints, all)
+    {
+        return None;
+    }
+    Some(
+        all.iter()
+            .map(|i| {
+                (
+                    *i,
+                    JoinDistribution::Hash {
+                        join_id,
+                        on: join_side(*i).on.clone(),
+                        buckets: options.hash_buckets,
+                    },
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Returns ids of joins that should swap their inputs. Hash joins keep the left side in memory,
+/// so inner hash joins between two tables put the side with fewer rows on the left. Merge joins
+/// and outer joins keep the order written in the query.
+fn choose_join_order(indices: &[IndexSnapshot], constraints: &[IndexConstraints]) -> HashSet<u64> {
+    join_sides(constraints)
+        .into_iter()
+        .filter(|(_, sides)| {
+            // Only tables have row counts, not results of other joins.
+            if sides.iter().any(|s| s.is_empty()) {
+                return false;
+            }
+            let join_side = constraints[sides[0][0]].join_side.as_ref().unwrap();
+            if join_side.join_type != JoinType::Inner {
+                return false;
+            }
+            if sides
+                .iter()
+                .flatten()
+                .all(|i| indices[*i].sort_on.is_some())
+            {
+                return false;
+            }
+            let rows = |s: &Vec<usize>| s.iter().map(|i| index_rows(&indices[*i])).sum::<u64>();
+            rows(&sides[1]) < rows(&sides[0])
+        })
+        .map(|(join_id, _)| join_id)
+        .collect()
+}
+
+/// Indices of the tables read directly by the left and right sides of each join, by join id.
+fn join_sides(constraints: &[IndexConstraints]) -> BTreeMap<u64, [Vec<usize>; 2]> {
+    let mut joins = BTreeMap::<u64, [Vec<usize>; 2]>::new();
+    for (i, c) in constraints.iter().enumerate() {
+        if let Some(side) = &c.join_side {
+            joins.entry(side.join_id).or_default()[if side.left { 0 } else { 1 }].push(i);
+        }
+    }
+    joins
+}
+
+/// Equal join keys must produce the same hash on both sides, so the column types must match.
+/// Rows of tables with unique keys are deduplicated after the scan, they must not be split.
+fn can_split_by_join_key(
+    indices: &[IndexSnapshot],
+    constraints: &[IndexConstraints],
+    sides: &[usize],
+) -> bool {
+    let key_types = sides
+        .iter()
+        .map(|i| {
+            let table = indices[*i].table().get_row();
+            if table.unique_key_columns().is_some() {
+                return None;
+            }
+            let on = &constraints[*i].join_side.as_ref().unwrap().on;
+            on.iter()
+                .map(|name| {
+                    table
+                        .get_columns()
+                        .iter()
+                        .find(|c| c.get_name() == name)
+                        .map(|c| c.get_column_type().clone())
+                })
+                .collect::<Option<Vec<_>>>()
+        })
+        .collect::<Option<Vec<_>>>();
+    match key_types {
+        Some(types) => types.iter().all_equal(),
+        None => false,
+    }
+}
+
+fn index_rows(i: &IndexSnapshot) -> u64 {
+    i.partitions
+        .iter()
+        .map(|p| {
+            p.partition.get_row().main_table_row_count()
+                + p.chunks
+                    .iter()
+                    .map(|c| c.get_row().get_row_count())
+                    .sum::<u64>()
+        })
+        .sum()
+}
+
 fn all_have_same_partitioned_index(cs: &[IndexCandidate]) -> bool {
     if cs.is_empty() {
         return true;
@@ -249,17 +563,31 @@ struct IndexConstraints {
     projection: Option<Vec<usize>>,
     filters: Vec<Expr>,
     aggregates: Vec<Expr>,
+    join_side: Option<JoinSide>,
 }
 
 #[derive(Default)]
 struct CollectConstraints {
     constraints: Vec<IndexConstraints>,
+    next_join_id: u64,
+    /// Joins with the left side being visited, the right side goes next.
+    entered_joins: Vec<u64>,
+}
+
+/// The table is read directly by one of the sides of a join.
+#[derive(Clone, Debug)]
+struct JoinSide {
+    join_id: u64,
+    left: bool,
+    join_type: JoinType,
+    on: Vec<String>,
 }
 
 #[derive(Default, Clone)]
 struct ConstraintsContext {
     sort_on: Option<SortColumns>,
     aggregates: Vec<Expr>,
+    join_side: Option<JoinSide>,
 }
 
 impl ConstraintsContext {
@@ -267,6 +595,7 @@ impl ConstraintsContext {
         Self {
             sort_on,
             aggregates: self.aggregates.clone(),
+            join_side: self.join_side.clone(),
         }
     }
 }
@@ -293,6 +622,7 @@ impl PlanRewriter for CollectConstraints {
                         projection: projection.clone(),
                         filters: filters.clone(),
                         aggregates: c.aggregates.clone(),
+                        join_side: c.join_side.clone(),
                     })
                 };
             }
@@ -331,6 +661,7 @@ impl PlanRewriter for CollectConstraints {
                 Some(ConstraintsContext {
                     sort_on,
                     aggregates: aggr_expr.to_vec(),
+                    join_side: None,
                 })
             }
             LogicalPlan::Filter { predicate, .. } => {
@@ -366,18 +697,26 @@ impl PlanRewriter for CollectConstraints {
     }
 
     fn enter_join_left(&mut self, join: &LogicalPlan, _: &Self::Context) -> Option<Self::Context> {
-        let join_on;
-        if let LogicalPlan::Join { on, .. } = join {
-            join_on = on;
-        } else {
-            panic!("expected join node");
-        }
+        let (join_on, join_type) = match join {
+            LogicalPlan::Join { on, join_type, .. } => (on, *join_type),
+            _ => panic!("expected join node"),
+        };
+        let join_id = self.next_join_id;
+        self.next_join_id += 1;
+        self.entered_joins.push(join_id);
+        let on = join_on.iter().map(|(l, _)| l.name.clone()).collect_vec();
         Some(ConstraintsContext {
             sort_on: Some(SortColumns {
-                sort_on: join_on.iter().map(|(l, _)| l.name.clone()).collect(),
+                sort_on: on.clone(),
                 required: true,
             }),
             aggregates: Vec::new(),
+            join_side: Some(JoinSide {
+                join_id,
+                left: true,
+                join_type,
+                on,
+            }),
         })
     }
 
@@ -386,22 +725,41 @@ impl PlanRewriter for CollectConstraints {
         join: &LogicalPlan,
         _c: &Self::Context,
     ) -> Option<Self::Context> {
-        let join_on;
-        if let LogicalPlan::Join { on, .. } = join {
-            join_on = on;
-        } else {
-            panic!("expected join node");
-        }
+        let (join_on, join_type) = match join {
+            LogicalPlan::Join { on, join_type, .. } => (on, *join_type),
+            _ => panic!("expected join node"),
+        };
+        // Joins inside the left side have been visited completely at this point.
+        let join_id = self.entered_joins.pop().expect("join without left side");
+        let on = join_on.iter().map(|(_, r)| r.name.clone()).collect_vec();
         Some(ConstraintsContext {
             sort_on: Some(SortColumns {
-                sort_on: join_on.iter().map(|(_, r)| r.name.clone()).collect(),
+                sort_on: on.clone(),
                 required: true,
             }),
             aggregates: Vec::new(),
+            join_side: Some(JoinSide {
+                join_id,
+                left: false,
+                join_type,
+                on,
+            }),
         })
     }
 }
 
+/// Columns compared with a single value by any of the `filters`.
+fn single_value_filter_column_names(filters: &[Expr]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for f in filters {
+        let mut columns = Vec::new();
+        if single_value_filter_columns(f, &mut columns) {
+            names.extend(columns.into_iter().map(|c| c.name.clone()));
+        }
+    }
+    names
+}
+
 fn single_value_filter_columns<'a>(
     expr: &'a Expr,
     columns: &mut Vec<&'a logical_plan::Column>,
@@ -439,6 +797,395 @@ fn single_value_filter_columns<'a>(
     }
 }
 
+/// Replaces `COUNT(col)` with `SUM(col)` in aggregations that read from aggregate indices.
+/// Results are cast back to `UInt64`, so the rest of the plan is not affected.
+struct CountOverAggregateIndex<'a> {
+    next_index: usize,
+    chosen_indices: &'a [IndexSnapshot],
+}
+
+impl PlanRewriter for CountOverAggregateIndex<'_> {
+    type Context = ();
+
+    fn rewrite(
+        &mut self,
+        n: LogicalPlan,
+        _: &Self::Context,
+    ) -> Result<LogicalPlan, DataFusionError> {
+        match &n {
+            LogicalPlan::TableScan { source, .. } => {
+                if source.as_any().is::<CubeTableLogical>() {
+                    self.next_index += 1;
+                }
+                Ok(n)
+            }
+            LogicalPlan::Aggregate {
+                input,
+                group_expr,
+                aggr_expr,
+                schema,
+            } => {
+                // Only direct reads of the aggregate index are affected. Index for the table scan
+                // in the input is the one we saw last.
+                if !reads_single_table(input) {
+                    return Ok(n);
+                }
+                let snapshot = &self.chosen_indices[self.next_index - 1];
+                match snapshot.index.get_row().get_type() {
+                    IndexType::Aggregate => {}
+                    IndexType::Regular => return Ok(n),
+                }
+                // Rows of materialized views are merged on read, so counts are real row counts.
+                if snapshot.table_path.table.get_row().is_materialized_view() {
+                    return Ok(n);
+                }
+                let is_count = |e: &Expr| match e {
+                    Expr::AggregateFunction {
+                        fun: FusionAggregateFunction::Count,
+                        distinct: false,
+                        ..
+                    } => true,
+                    _ => false,
+                };
+                if !aggr_expr.iter().any(is_count) {
+                    return Ok(n);
+                }
+
+                let group_len = group_expr.len();
+                let mut new_aggr_expr = Vec::with_capacity(aggr_expr.len());
+                let mut new_fields = schema.fields()[0..group_len].to_vec();
+                for (i, e) in aggr_expr.iter().enumerate() {
+                    match e {
+                        Expr::AggregateFunction { args, .. } if is_count(e) => {
+                            let sum = Expr::AggregateFunction {
+                                fun: FusionAggregateFunction::Sum,
+                                args: args.clone(),
+                                distinct: false,
+                            };
+                            new_fields.push(DFField::new(
+                                None,
+                                &sum.name(input.schema())?,
+                                DataType::Int64,
+                                true,
+                            ));
+                            new_aggr_expr.push(sum);
+                        }
+                        e => {
+                            new_fields.push(schema.field(group_len + i).clone());
+                            new_aggr_expr.push(e.clone());
+                        }
+                    }
+                }
+                let new_schema = Arc::new(DFSchema::new(new_fields)?);
+
+                let mut project = Vec::with_capacity(schema.fields().len());
+                for i in 0..schema.fields().len() {
+                    let new_col = Expr::Column(new_schema.field(i).qualified_column());
+                    if i < group_len || !is_count(&aggr_expr[i - group_len]) {
+                        project.push(new_col);
+                    } else {
+                        project.push(Expr::Alias(
+                            Box::new(Expr::Cast {
+                                expr: Box::new(new_col),
+                                data_type: DataType::UInt64,
+                            }),
+                            schema.field(i).name().clone(),
+                        ));
+                    }
+                }
+
+                Ok(LogicalPlan::Projection {
+                    expr: project,
+                    input: Arc::new(LogicalPlan::Aggregate {
+                        input: input.clone(),
+                        group_expr: group_expr.clone(),
+                        aggr_expr: new_aggr_expr,
+                        schema: new_schema,
+                    }),
+                    schema: schema.clone(),
+                })
+            }
+            _ => Ok(n),
+        }
+    }
+}
+
+/// Checks the plan is a chain of projections and filters over a single [CubeTableLogical].
+fn reads_single_table(mut p: &LogicalPlan) -> bool {
+    loop {
+        match p {
+            LogicalPlan::Projection { input, .. } | LogicalPlan::Filter { input, .. } => {
+                p = input.as_ref()
+            }
+            LogicalPlan::TableScan { source, .. } => {
+                return source.as_any().is::<CubeTableLogical>()
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Passes the seq column of the table to `LAST_VALUE(col)`, so it picks values by the seq and
+/// not by the order rows are processed in, see [Table::last_value_seq_column]. Indices of tables
+/// with a seq column contain it.
+struct LastValueBySeq;
+
+impl PlanRewriter for LastValueBySeq {
+    type Context = ();
+
+    fn rewrite(
+        &mut self,
+        n: LogicalPlan,
+        _: &Self::Context,
+    ) -> Result<LogicalPlan, DataFusionError> {
+        let (input, group_expr, aggr_expr, schema) = match &n {
+            LogicalPlan::Aggregate {
+                input,
+                group_expr,
+                aggr_expr,
+                schema,
+            } => (input, group_expr, aggr_expr, schema),
+            _ => return Ok(n),
+        };
+        let is_last_value = |e: &Expr| match e {
+            Expr::AggregateUDF { fun, args } => {
+                args.len() == 1 && fun.name.to_uppercase() == "LAST_VALUE"
+            }
+            _ => false,
+        };
+        if !aggr_expr.iter().any(is_last_value) {
+            return Ok(n);
+        }
+        let (input, seq) = match expose_seq_column(input)? {
+            Some(r) => r,
+            None => return Ok(n),
+        };
+
+        let group_len = group_expr.len();
+        let mut new_aggr_expr = Vec::with_capacity(aggr_expr.len());
+        let mut new_fields = schema.fields()[0..group_len].to_vec();
+        for (i, e) in aggr_expr.iter().enumerate() {
+            let field = schema.field(group_len + i);
+            match e {
+                Expr::AggregateUDF { fun, args } if is_last_value(e) => {
+                    let last_value = Expr::AggregateUDF {
+                        fun: fun.clone(),
+                        args: vec![args[0].clone(), seq.clone()],
+                    };
+                    new_fields.push(DFField::new(
+                        None,
+                        &last_value.name(input.schema())?,
+                        field.data_type().clone(),
+                        field.is_nullable(),
+                    ));
+                    new_aggr_expr.push(last_value);
+                }
+                e => {
+                    new_fields.push(field.clone());
+                    new_aggr_expr.push(e.clone());
+                }
+            }
+        }
+        let new_schema = Arc::new(DFSchema::new(new_fields)?);
+
+        // Keep the original names for the rest of the plan.
+        let mut project = Vec::with_capacity(schema.fields().len());
+        for i in 0..schema.fields().len() {
+            let new_col = Expr::Column(new_schema.field(i).qualified_column());
+            if i < group_len || !is_last_value(&aggr_expr[i - group_len]) {
+                project.push(new_col);
+            } else {
+                project.push(Expr::Alias(
+                    Box::new(new_col),
+                    schema.field(i).name().clone(),
+                ));
+            }
+        }
+
+        Ok(LogicalPlan::Projection {
+            expr: project,
+            input: Arc::new(LogicalPlan::Aggregate {
+                input: Arc::new(input),
+                group_expr: group_expr.clone(),
+                aggr_expr: new_aggr_expr,
+                schema: new_schema,
+            }),
+            schema: schema.clone(),
+        })
+    }
+}
+
+/// Adds the seq column to the output of a chain of projections and filters over a single
+/// [CubeTableLogical]. Returns the new plan and the expression that reads the column, or `None`
+/// if the plan has a different shape or the table has no seq column.
+fn expose_seq_column(p: &LogicalPlan) -> Result<Option<(LogicalPlan, Expr)>, DataFusionError> {
+    match p {
+        LogicalPlan::Projection {
+            expr,
+            input,
+            schema,
+        } => {
+            let (input, seq) = match expose_seq_column(input)? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            let mut expr = expr.clone();
+            let mut fields = schema.fields().clone();
+            let seq_field = seq.to_field(input.schema())?;
+            if !fields
+                .iter()
+                .any(|f| f.qualified_column() == seq_field.qualified_column())
+            {
+                expr.push(seq.clone());
+                fields.push(seq_field);
+            }
+            Ok(Some((
+                LogicalPlan::Projection {
+                    expr,
+                    input: Arc::new(input),
+                    schema: Arc::new(DFSchema::new(fields)?),
+                },
+                seq,
+            )))
+        }
+        LogicalPlan::Filter { predicate, input } => {
+            let (input, seq) = match expose_seq_column(input)? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            Ok(Some((
+                LogicalPlan::Filter {
+                    predicate: predicate.clone(),
+                    input: Arc::new(input),
+                },
+                seq,
+            )))
+        }
+        LogicalPlan::TableScan {
+            table_name,
+            source,
+            projection,
+            projected_schema,
+            filters,
+            limit,
+        } => {
+            let table = match source.as_any().downcast_ref::<CubeTableLogical>() {
+                Some(t) => t,
+                None => return Ok(None),
+            };
+            let seq_index = match table.table.table.get_row().last_value_seq_column() {
+                Some(c) => c.get_index(),
+                None => return Ok(None),
+            };
+            let mut projection = projection.clone();
+            let mut projected_schema = projected_schema.clone();
+            if let Some(projection) = &mut projection {
+                if !projection.contains(&seq_index) {
+                    projection.push(seq_index);
+                    let f = source.schema().field(seq_index).clone();
+                    let mut fields = projected_schema.fields().clone();
+                    fields.push(DFField::new(
+                        Some(table_name.as_str()),
+                        f.name(),
+                        f.data_type().clone(),
+                        f.is_nullable(),
+                    ));
+                    projected_schema = Arc::new(DFSchema::new(fields)?);
+                }
+            }
+            let seq_name = source.schema().field(seq_index).name().clone();
+            let seq = Expr::Column(logical_plan::Column {
+                relation: Some(table_name.clone()),
+                name: seq_name,
+            });
+            Ok(Some((
+                LogicalPlan::TableScan {
+                    table_name: table_name.clone(),
+                    source: source.clone(),
+                    projection,
+                    projected_schema,
+                    filters: filters.clone(),
+                    limit: *limit,
+                },
+                seq,
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Swaps inputs of the joins chosen by [choose_join_order]. Joins are numbered in the same order
+/// as in [CollectConstraints].
+struct SwapJoinInputs {
+    swap: HashSet<u64>,
+    /// Joins with the inputs being visited.
+    entered_joins: Vec<u64>,
+    next_join_id: u64,
+}
+
+impl PlanRewriter for SwapJoinInputs {
+    type Context = ();
+
+    fn rewrite(&mut self, n: LogicalPlan, _: &()) -> Result<LogicalPlan, DataFusionError> {
+        match n {
+            LogicalPlan::Join {
+                left,
+                right,
+                on,
+                join_type,
+                join_constraint,
+                schema,
+            } => {
+                let join_id = self.entered_joins.pop().expect("join without left side");
+                if !self.swap.contains(&join_id) {
+                    return Ok(LogicalPlan::Join {
+                        left,
+                        right,
+                        on,
+                        join_type,
+                        join_constraint,
+                        schema,
+                    });
+                }
+                let swapped_schema = DFSchema::new(
+                    right
+                        .schema()
+                        .fields()
+                        .iter()
+                        .chain(left.schema().fields())
+                        .cloned()
+                        .collect(),
+                )?;
+                let join = LogicalPlan::Join {
+                    left: right,
+                    right: left,
+                    on: on.into_iter().map(|(l, r)| (r, l)).collect(),
+                    join_type,
+                    join_constraint,
+                    schema: Arc::new(swapped_schema),
+                };
+                // Parents expect the columns of the original left side first.
+                Ok(LogicalPlan::Projection {
+                    expr: schema
+                        .fields()
+                        .iter()
+                        .map(|f| Expr::Column(f.qualified_column()))
+                        .collect(),
+                    input: Arc::new(join),
+                    schema,
+                })
+            }
+            n => Ok(n),
+        }
+    }
+
+    fn enter_join_left(&mut self, _: &LogicalPlan, _: &()) -> Option<()> {
+        self.entered_joins.push(self.next_join_id);
+        self.next_join_id += 1;
+        None
+    }
+}
+
 struct ChooseIndex<'a> {
     next_index: usize,
     chosen_indices: &'a [IndexSnapshot],
@@ -524,9 +1271,10 @@ impl ChooseIndex<'_> {
 }
 
 struct IndexCandidate {
-    /// May contain for unmatched index.
-    pub ordinary_index: Result<IndexSnapshot, DataFusionError>,
+    pub ordinary_index: IndexSnapshot,
     pub partitioned_index: Option<IndexSnapshot>,
+    /// Other indices that can replace the ordinary one, chosen by [estimate_index_cost].
+    pub alternatives: Vec<IndexSnapshot>,
 }
 
 fn check_aggregates_expr(table: &IdRow<Table>, aggregates: &Vec<Expr>) -> bool {
@@ -534,7 +1282,11 @@ fn check_aggregates_expr(table: &IdRow<Table>, aggregates: &Vec<Expr>) -> bool {
 
     for aggr in aggregates.iter() {
         match aggr {
-            Expr::AggregateFunction { fun, args, .. } => {
+            Expr::AggregateFunction {
+                fun,
+                args,
+                distinct,
+            } => {
                 if args.len() != 1 {
                     return false;
                 }
@@ -543,6 +1295,7 @@ fn check_aggregates_expr(table: &IdRow<Table>, aggregates: &Vec<Expr>) -> bool {
                     FusionAggregateFunction::Sum => Some(AggregateFunction::SUM),
                     FusionAggregateFunction::Max => Some(AggregateFunction::MAX),
                     FusionAggregateFunction::Min => Some(AggregateFunction::MIN),
+                    FusionAggregateFunction::Count if !*distinct => Some(AggregateFunction::COUNT),
                     _ => None,
                 };
 
@@ -569,7 +1322,11 @@ fn check_aggregates_expr(table: &IdRow<Table>, aggregates: &Vec<Expr>) -> bool {
                 }
 
                 let aggr_fun = match fun.name.to_uppercase().as_str() {
-                    "MERGE" => Some(AggregateFunction::MERGE),
+                    // Aggregate indices merge theta sketches with a union.
+                    "MERGE" | "THETA_UNION" => Some(AggregateFunction::MERGE),
+                    "ANY_VALUE" => Some(AggregateFunction::ANY_VALUE),
+                    "LAST_VALUE" => Some(AggregateFunction::LAST_VALUE),
+                    "BIT_OR" => Some(AggregateFunction::BIT_OR),
                     _ => None,
                 };
 
@@ -610,6 +1367,8 @@ async fn pick_index(
     let aggr_index_allowed = check_aggregates_expr(&table, &c.aggregates);
 
     let default_index = indices.iter().next().expect("no default index");
+    // Indices and whether they match `sort_on`.
+    let mut alternatives: Vec<(&IdRow<Index>, bool)> = Vec::new();
     let (index, mut partitioned_index, sort_on) = if let Some(projection_column_indices) =
         &c.projection
     {
@@ -691,6 +1450,25 @@ async fn pick_index(
                 true
             }
         });
+        // Indices that don't match `sort_on` are alternatives only when it is not required, i.e.
+        // when it's not a join. Aggregate indices are only allowed under a matching aggregation.
+        let required = sort_on.map(|(_, required)| required).unwrap_or(false);
+        let aggr_alternatives_allowed = aggr_index_allowed && !c.aggregates.is_empty();
+        let unsorted_alternatives = if required {
+            Vec::new()
+        } else {
+            indexes_by_score(
+                indices.iter().skip(1).filter(|i| {
+                    aggr_alternatives_allowed || i.get_row().get_type() != IndexType::Aggregate
+                }),
+                &projection_columns,
+                &filter_columns,
+            )
+            .into_iter()
+            .chain(std::iter::once(default_index))
+            .map(|i| (i, false))
+            .collect_vec()
+        };
         let optimal_with_partitioned_index = optimal_index_by_score(
             filtered_by_sort_on
                 .clone()
@@ -698,42 +1476,34 @@ async fn pick_index(
             &projection_columns,
             &filter_columns,
         );
-        let optimal =
-            optimal_index_by_score(filtered_by_sort_on, &projection_columns, &filter_columns);
+        let sorted = indexes_by_score(filtered_by_sort_on, &projection_columns, &filter_columns);
+        let optimal = sorted.first().cloned();
         if let Some(index) = optimal_with_partitioned_index.or(optimal) {
+            if !required {
+                alternatives = sorted
+                    .into_iter()
+                    .map(|i| (i, true))
+                    .chain(unsorted_alternatives)
+                    .collect();
+            }
             (
-                Ok(index),
+                index,
                 index.get_row().multi_index_id().map(|_| index),
                 sort_on,
             )
         } else {
-            if let Some((join_on_columns, true)) = sort_on.as_ref() {
-                let table_name = c.table.table_name();
-                let err = Err(DataFusionError::Plan(format!(
-                    "Can't find index to join table {} on {}. Consider creating index: CREATE INDEX {}_{} ON {} ({})",
-                    table_name,
-                    join_on_columns.join(", "),
-                    table.get_row().get_table_name(),
-                    join_on_columns.join("_"),
-                    table_name,
-                    join_on_columns.join(", ")
-                )));
-                (err, None, sort_on)
-            } else {
-                let optimal = optimal_index_by_score(
-                    // Skipping default index
-                    indices.iter().skip(1),
-                    &projection_columns,
-                    &filter_columns,
-                );
+            // Joins on columns without a matching index are executed as hash joins, see
+            // [JoinDistribution].
+            let optimal = optimal_index_by_score(
+                // Skipping default index
+                indices.iter().skip(1),
+                &projection_columns,
+                &filter_columns,
+            );
 
-                let index = optimal.unwrap_or(default_index);
-                (
-                    Ok(index),
-                    index.get_row().multi_index_id().map(|_| index),
-                    None,
-                )
-            }
+            let index = optimal.unwrap_or(default_index);
+            alternatives = unsorted_alternatives;
+            (index, index.get_row().multi_index_id().map(|_| index), None)
         }
     } else {
         if let Some((join_on_columns, _)) = sort_on {
@@ -743,7 +1513,7 @@ async fn pick_index(
                 join_on_columns.join(", ")
             )));
         }
-        (Ok(default_index), None, None)
+        (default_index, None, None)
     };
 
     // Only use partitioned index for joins. Joins are indicated by the required flag.
@@ -756,7 +1526,7 @@ async fn pick_index(
     }
 
     let schema = Arc::new(schema);
-    let create_snapshot = |index: &IdRow<Index>| {
+    let create_snapshot = |index: &IdRow<Index>, sort_on: Option<(&Vec<String>, bool)>| {
         let index_sort_on = sort_on.map(|sc| {
             index
                 .get_row()
@@ -769,16 +1539,27 @@ async fn pick_index(
         IndexSnapshot {
             index: index.clone(),
             partitions: Vec::new(), // filled with results of `pick_partitions` later.
+            pruned_partitions: Vec::new(),
             table_path: TablePath {
                 table: table.clone(),
                 schema: schema.clone(),
             },
             sort_on: index_sort_on,
+            index_choice: Vec::new(),
+            join_distribution: None, // filled by `choose_join_distribution` later.
         }
     };
+    let chosen_id = index.get_id();
+    let mut seen = HashSet::new();
+    let alternatives = alternatives
+        .into_iter()
+        .filter(|(i, _)| i.get_id() != chosen_id && seen.insert(i.get_id()))
+        .map(|(i, sorted)| create_snapshot(i, if sorted { sort_on } else { None }))
+        .collect();
     Ok(IndexCandidate {
-        ordinary_index: index.map(create_snapshot),
-        partitioned_index: partitioned_index.map(create_snapshot),
+        ordinary_index: create_snapshot(index, sort_on),
+        partitioned_index: partitioned_index.map(|i| create_snapshot(i, sort_on)),
+        alternatives,
     })
 }
 
@@ -787,6 +1568,17 @@ fn optimal_index_by_score<'a, T: Iterator<Item = &'a IdRow<Index>>>(
     projection_columns: &Vec<Column>,
     filter_columns: &HashSet<logical_plan::Column>,
 ) -> Option<&'a IdRow<Index>> {
+    indexes_by_score(indexes, projection_columns, filter_columns)
+        .into_iter()
+        .next()
+}
+
+/// Indexes that have all projection and filter columns, best first.
+fn indexes_by_score<'a, T: Iterator<Item = &'a IdRow<Index>>>(
+    indexes: T,
+    projection_columns: &Vec<Column>,
+    filter_columns: &HashSet<logical_plan::Column>,
+) -> Vec<&'a IdRow<Index>> {
     #[derive(PartialEq, Eq, Clone)]
     struct Score {
         index_type: IndexType,
@@ -858,19 +1650,28 @@ fn optimal_index_by_score<'a, T: Iterator<Item = &'a IdRow<Index>>>(
             let res = Some(i).zip(index_score);
             res
         })
-        .min_by_key(|(_, score)| score.clone())
+        .sorted_by_key(|(_, score)| score.clone())
         .map(|(index, _)| index)
+        .collect()
 }
 
 fn pick_partitions(
     i: &IndexSnapshot,
     c: &IndexConstraints,
     partitions: Vec<(IdRow<Partition>, Vec<IdRow<Chunk>>)>,
-) -> Result<Vec<PartitionSnapshot>, DataFusionError> {
+) -> Result<(Vec<PartitionSnapshot>, Vec<u64>), DataFusionError> {
     let partition_filter = PartitionFilter::extract(&partition_filter_schema(&i.index), &c.filters);
     log::trace!("Extracted partition filter is {:?}", partition_filter);
+    let table = i.table_path.table.get_row();
+    let time_filter = match (table.time_partition(), table.time_partition_column()) {
+        (Some(p), Some(column)) => {
+            let schema = arrow::datatypes::Schema::new(vec![column.clone().into()]);
+            Some((p, PartitionFilter::extract(&schema, &c.filters)))
+        }
+        _ => None,
+    };
     let candidate_partitions = partitions.len();
-    let mut pruned_partitions = 0;
+    let mut pruned_partitions = Vec::new();
 
     let mut partition_snapshots = Vec::new();
     for (partition, chunks) in partitions.into_iter() {
@@ -886,19 +1687,30 @@ fn pick_partitions(
             .map(|r| r.values().as_slice());
 
         if !partition_filter.can_match(min_row, max_row) {
-            pruned_partitions += 1;
+            pruned_partitions.push(partition.get_id());
             continue;
         }
+        if let (Some((p, filter)), Some(bucket)) = (&time_filter, partition.get_row().time_bucket())
+        {
+            let (start, end) = p.bucket_range(bucket);
+            if !filter.can_match(
+                Some(&[TableValue::Timestamp(start)][..]),
+                Some(&[TableValue::Timestamp(end)][..]),
+            ) {
+                pruned_partitions.push(partition.get_id());
+                continue;
+            }
+        }
 
         partition_snapshots.push(PartitionSnapshot { chunks, partition });
     }
     log::trace!(
         "Pruned {} of {} partitions",
-        pruned_partitions,
+        pruned_partitions.len(),
         candidate_partitions
     );
 
-    Ok(partition_snapshots)
+    Ok((partition_snapshots, pruned_partitions))
 }
 
 fn partition_filter_schema(index: &IdRow<Index>) -> arrow::datatypes::Schema {
@@ -1052,7 +1864,25 @@ fn pull_up_cluster_send(mut p: LogicalPlan) -> Result<LogicalPlan, DataFusionErr
             *right = rsend.input.clone();
             return Ok(ClusterSendNode::new(Arc::new(p), snapshots).into_plan());
         }
-        LogicalPlan::Window { .. } | LogicalPlan::CrossJoin { .. } => {
+        LogicalPlan::Window {
+            input, window_expr, ..
+        } => {
+            // Window functions can run on workers only when every window partition is fully
+            // contained in a single table partition. Otherwise, compute them on the router.
+            let send;
+            if let Some(s) = try_extract_cluster_send(input) {
+                send = s;
+            } else {
+                return Ok(p);
+            }
+            if !window_partitioned_by_sort_key(window_expr, send) {
+                return Ok(p);
+            }
+            snapshots = send.snapshots.clone();
+            *input = send.input.clone();
+            return Ok(ClusterSendNode::new(Arc::new(p), snapshots).into_plan());
+        }
+        LogicalPlan::CrossJoin { .. } => {
             return Err(DataFusionError::Internal(
                 "unsupported operation".to_string(),
             ))
@@ -1060,6 +1890,82 @@ fn pull_up_cluster_send(mut p: LogicalPlan) -> Result<LogicalPlan, DataFusionErr
     }
 }
 
+/// Checks that `PARTITION BY` of each window expression includes all partition split columns of
+/// every index read by `send` and the time partition column of its table, if any. Rows of a single
+/// window partition are never split between table partitions in this case, so workers can compute
+/// window functions independently.
+fn window_partitioned_by_sort_key(window_expr: &[Expr], send: &ClusterSendNode) -> bool {
+    let mut partition_by = None;
+    for e in window_expr {
+        let e = match e {
+            Expr::Alias(e, _) => e.as_ref(),
+            e => e,
+        };
+        let columns = match e {
+            Expr::WindowFunction { partition_by, .. } => partition_by
+                .iter()
+                .map(|p| match p {
+                    Expr::Column(c) => Some(c.name.clone()),
+                    _ => None,
+                })
+                .collect::<Option<HashSet<_>>>(),
+            _ => None,
+        };
+        let columns = match columns {
+            Some(c) => c,
+            None => return false,
+        };
+        // Intersect, a column must be present in all windows to be used for distribution.
+        partition_by = Some(match partition_by {
+            None => columns,
+            Some(prev) => prev.intersection(&columns).cloned().collect::<HashSet<_>>(),
+        });
+    }
+    let partition_by = match partition_by {
+        Some(p) if !p.is_empty() => p,
+        _ => return false,
+    };
+    // Window input must read table columns directly, so names can be matched with the index.
+    if !passes_table_columns(&send.input) {
+        return false;
+    }
+    for s in send.snapshots.iter().flatten() {
+        let s = match s {
+            Snapshot::Index(s) => s,
+            Snapshot::Inline(_) => return false,
+        };
+        let index = s.index.get_row();
+        let key_size = index
+            .partition_split_key_size()
+            .unwrap_or(index.sort_key_size()) as usize;
+        if !index.get_columns()[..key_size]
+            .iter()
+            .all(|c| partition_by.contains(c.get_name()))
+        {
+            return false;
+        }
+        // Each time bucket has its own partitions covering the whole key range.
+        if let Some(c) = s.table().get_row().time_partition_column() {
+            if !partition_by.contains(c.get_name()) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Returns true if column names produced by `p` match the names of the table columns.
+fn passes_table_columns(p: &LogicalPlan) -> bool {
+    match p {
+        LogicalPlan::TableScan { .. } => true,
+        LogicalPlan::Filter { input, .. } => passes_table_columns(input),
+        LogicalPlan::Projection { expr, input, .. } => {
+            expr.iter().all(|e| matches!(e, Expr::Column(_))) && passes_table_columns(input)
+        }
+        _ => false,
+    }
+}
+
 pub struct CubeExtensionPlanner {
     pub cluster: Option<Arc<dyn Cluster>>,
     pub serialized_plan: Arc<SerializedPlan>,
@@ -1216,14 +2122,18 @@ pub mod tests {
     use itertools::Itertools;
     use pretty_assertions::assert_eq;
 
+    use crate::cluster::membership::WorkerSet;
     use crate::config::Config;
     use crate::metastore::multi_index::MultiPartition;
-    use crate::metastore::table::{Table, TablePath};
+    use crate::metastore::table::{Table, TablePath, TimePartition};
     use crate::metastore::{Chunk, Column, ColumnType, IdRow, Index, Partition, Schema};
-    use crate::queryplanner::planning::{choose_index, try_extract_cluster_send, PlanIndexStore};
+    use crate::queryplanner::planning::{
+        choose_index, choose_index_ext, try_extract_cluster_send, JoinOptions, PlanIndexStore,
+        PlanningMeta, Snapshot,
+    };
     use crate::queryplanner::pretty_printers::PPOptions;
     use crate::queryplanner::query_executor::ClusterSendExec;
-    use crate::queryplanner::serialized_plan::RowRange;
+    use crate::queryplanner::serialized_plan::{JoinDistribution, RowRange};
     use crate::queryplanner::{pretty_printers, CubeTableLogical};
     use crate::sql::parser::{CubeStoreParser, Statement};
     use crate::table::{Row, TableValue};
@@ -1370,6 +2280,46 @@ pub mod tests {
                                   \n      Scan c2, source: CubeTable(index: by_city:1:[]:sort_on[customer_city]), fields: [customer_name, customer_city]");
     }
 
+    #[tokio::test]
+    pub async fn test_choose_index_by_cost() {
+        let mut indices = default_indices();
+        let plan = initial_plan("SELECT order_id, order_amount FROM s.Orders", &indices);
+        // Without statistics, the index is picked by the sort key match.
+        let meta = choose_index(&plan, &indices).await.unwrap().1;
+        assert_eq!(meta.indices[0].index.get_row().get_name(), "by_customer");
+
+        for (index_id, file_size) in [(2, 50_000), (3, 100_000)] {
+            indices.partitions.push(
+                Partition::new(index_id, None, None, None)
+                    .update_row_count(1000)
+                    .set_file_size(file_size)
+                    .unwrap()
+                    .update_key_distinct(Some(vec![100, 1000])),
+            );
+        }
+        let meta = choose_index(&plan, &indices).await.unwrap().1;
+        let chosen = &meta.indices[0];
+        assert_eq!(chosen.index.get_row().get_name(), "default");
+        assert_eq!(
+            chosen
+                .index_choice
+                .iter()
+                .map(|c| (c.index.as_str(), c.rows, c.bytes))
+                .collect_vec(),
+            vec![("default", 1000, 50_000), ("by_customer", 1000, 100_000)]
+        );
+
+        // Equality filter on the sort key of `by_customer` reads a fraction of its file.
+        let plan = initial_plan(
+            "SELECT order_id, order_amount FROM s.Orders WHERE order_customer = 1",
+            &indices,
+        );
+        let meta = choose_index(&plan, &indices).await.unwrap().1;
+        let chosen = &meta.indices[0];
+        assert_eq!(chosen.index.get_row().get_name(), "by_customer");
+        assert_eq!(chosen.index_choice[0].bytes, 1000);
+    }
+
     #[tokio::test]
     pub async fn test_materialize_topk() {
         let indices = default_indices();
@@ -1593,17 +2543,68 @@ pub mod tests {
             c
         });
         let cs = &try_extract_cluster_send(&with_index).unwrap().snapshots;
-        let assigned = ClusterSendExec::distribute_to_workers(
-            c.config_obj().as_ref(),
-            &cs,
+        let workers = WorkerSet::from_config(c.config_obj().as_ref());
+        let assigned =
+            ClusterSendExec::distribute_to_workers(&workers, &cs, &meta.multi_part_subtree)
+                .unwrap();
+
+        // Replicas start with the same workers and are never repeated.
+        let replicated =
+            ClusterSendExec::distribute_to_replicas(&workers, 2, &cs, &meta.multi_part_subtree)
+                .unwrap();
+        for (replicas, _) in &replicated {
+            assert_eq!(replicas.len(), 2);
+            assert_ne!(replicas[0], replicas[1]);
+        }
+        assert_eq!(
+            replicated
+                .iter()
+                .map(|(r, (ps, _))| (r[0].as_str(), ps.len()))
+                .into_group_map()
+                .into_iter()
+                .map(|(w, lens)| (w, lens.into_iter().sum::<usize>()))
+                .sorted()
+                .collect_vec(),
+            assigned
+                .iter()
+                .map(|(w, (ps, _))| (w.as_str(), ps.len()))
+                .collect_vec()
+        );
+
+        // Only the owner holds in memory chunks, there's nothing to fail over to.
+        let mut with_in_memory = cs.clone();
+        let in_memory_partition = match &mut with_in_memory[0][0] {
+            Snapshot::Index(i) => {
+                let p = &mut i.partitions[0];
+                p.chunks
+                    .push(IdRow::new(100, Chunk::new(p.partition.get_id(), 1, true)));
+                p.partition.get_id()
+            }
+            Snapshot::Inline(_) => panic!("expected index snapshot"),
+        };
+        let replicated = ClusterSendExec::distribute_to_replicas(
+            &workers,
+            2,
+            &with_in_memory,
             &meta.multi_part_subtree,
         )
         .unwrap();
+        for (replicas, (ps, _)) in &replicated {
+            let reads_in_memory = ps.iter().any(|(id, _)| *id == in_memory_partition);
+            assert_eq!(replicas.len(), if reads_in_memory { 1 } else { 2 });
+        }
 
         let part = |id: u64, start: Option<i64>, end: Option<i64>| {
             let start = start.map(|i| Row::new(vec![TableValue::Int(i)]));
             let end = end.map(|i| Row::new(vec![TableValue::Int(i)]));
-            (id, RowRange { start, end })
+            (
+                id,
+                RowRange {
+                    start,
+                    end,
+                    hash_bucket: None,
+                },
+            )
         };
         assert_eq!(
             assigned,
@@ -1644,6 +2645,228 @@ pub mod tests {
         );
     }
 
+    #[tokio::test]
+    pub async fn test_hash_join_distribution() {
+        let mut indices = default_indices();
+        // No index of Orders is sorted by `order_product`.
+        let plan = initial_plan(
+            "SELECT order_id, product_name FROM s.Orders JOIN s.Products \
+               ON order_product = product_id",
+            &indices,
+        );
+        for index_id in 2..=3 {
+            for _ in 0..2 {
+                indices
+                    .partitions
+                    .push(Partition::new(index_id, None, None, None).update_row_count(1000));
+            }
+        }
+        indices
+            .partitions
+            .push(Partition::new(5, None, None, None).update_row_count(10));
+
+        let c = Config::test("hash_join_distribution").update_config(|mut c| {
+            c.server_name = "router".to_string();
+            c.select_workers = vec!["worker1".to_string(), "worker2".to_string()];
+            c
+        });
+        let workers = WorkerSet::from_config(c.config_obj().as_ref());
+        let distribute = |with_index: &LogicalPlan, meta: &PlanningMeta| {
+            let cs = &try_extract_cluster_send(with_index).unwrap().snapshots;
+            ClusterSendExec::distribute_to_workers(&workers, &cs, &meta.multi_part_subtree).unwrap()
+        };
+
+        // Products are small enough to be sent to every worker reading Orders.
+        let (with_index, meta) = choose_index(&plan, &indices).await.unwrap();
+        let (orders, products) = (&meta.indices[0], &meta.indices[1]);
+        assert_eq!(orders.sort_on(), None);
+        assert_eq!(orders.join_distribution(), None);
+        assert_eq!(
+            products.join_distribution(),
+            Some(&JoinDistribution::Broadcast)
+        );
+        assert!(pretty_printers::pp_plan(&with_index)
+            .contains("CubeTable(index: default:5:[4]:sort_on[product_id]:broadcast)"));
+        // The hash join keeps the smaller side in memory, i.e. Products go to the left.
+        let pp = pretty_printers::pp_plan(&with_index);
+        assert!(
+            pp.contains("Join on: [#s.Products.product_id = #s.Orders.order_product]"),
+            "{}",
+            pp
+        );
+        assert!(
+            pp.find("Scan s.Products").unwrap() < pp.find("Scan s.Orders").unwrap(),
+            "{}",
+            pp
+        );
+        let orders_parts = orders.partitions.iter().map(|p| p.partition.get_id());
+        let products_part = products.partitions[0].partition.get_id();
+        let mut jobs = distribute(&with_index, &meta)
+            .into_iter()
+            .flat_map(|(_, (ps, _))| ps)
+            .map(|(id, _)| id)
+            .collect_vec();
+        jobs.sort();
+        let mut expected = orders_parts.flat_map(|p| [p, products_part]).collect_vec();
+        expected.sort();
+        assert_eq!(jobs, expected);
+
+        // Both sides are split by the join key when Products are large.
+        let options = JoinOptions {
+            broadcast_rows: 5,
+            hash_buckets: 2,
+        };
+        let (with_index, meta) = choose_index_ext(&plan, &indices, true, options)
+            .await
+            .unwrap();
+        assert_eq!(
+            meta.indices
+                .iter()
+                .map(|i| i.join_distribution().cloned())
+                .collect_vec(),
+            vec![
+                Some(JoinDistribution::Hash {
+                    join_id: 0,
+                    on: vec!["order_product".to_string()],
+                    buckets: 2
+                }),
+                Some(JoinDistribution::Hash {
+                    join_id: 0,
+                    on: vec!["product_id".to_string()],
+                    buckets: 2
+                }),
+            ]
+        );
+        let all_parts = meta
+            .indices
+            .iter()
+            .flat_map(|i| i.partitions.iter().map(|p| p.partition.get_id()))
+            .collect_vec();
+        let assigned = distribute(&with_index, &meta);
+        assert_eq!(assigned.len(), 2);
+        for (bucket, (worker, (ps, _))) in assigned.into_iter().enumerate() {
+            assert_eq!(worker, format!("worker{}", bucket + 1));
+            assert_eq!(
+                ps,
+                all_parts
+                    .iter()
+                    .map(|id| (
+                        *id,
+                        RowRange {
+                            hash_bucket: Some(bucket as u64),
+                            ..RowRange::default()
+                        }
+                    ))
+                    .collect_vec()
+            );
+        }
+
+        // The preserved side of an outer join is never broadcast.
+        let plan = initial_plan(
+            "SELECT order_id, product_name FROM s.Products LEFT JOIN s.Orders \
+               ON order_product = product_id",
+            &indices,
+        );
+        let meta = choose_index(&plan, &indices).await.unwrap().1;
+        assert_eq!(meta.indices[0].join_distribution(), None);
+        assert_eq!(
+            meta.indices[1].join_distribution(),
+            Some(&JoinDistribution::Broadcast)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_join_without_distribution() {
+        let indices = default_indices();
+        // Products are joined with the result of another join and aren't sorted by `product_name`.
+        let plan = initial_plan(
+            "SELECT order_id, customer_name, product_name \
+             FROM s.Orders \
+             JOIN s.Customers ON order_customer = customer_id \
+             JOIN s.Products ON order_product = product_name",
+            &indices,
+        );
+
+        // Pairing partitions is fine when a single worker reads everything.
+        let meta = choose_index(&plan, &indices).await.unwrap().1;
+        assert!(meta.indices.iter().all(|i| i.join_distribution().is_none()));
+
+        let options = JoinOptions {
+            broadcast_rows: 5,
+            hash_buckets: 2,
+        };
+        let err = choose_index_ext(&plan, &indices, true, options)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Can't find index to join table s.Products on product_name"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_window_over_time_partitions() {
+        let mut indices = default_indices();
+        let events_cols = int_columns(&["event_ts", "event_id"]);
+        let events = indices.add_table(Table::new(
+            "Events".to_string(),
+            0,
+            events_cols.clone(),
+            None,
+            None,
+            true,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Vec::new(),
+            None,
+            None,
+            Some(TimePartition::new(0, 86400, None)),
+        ));
+        let by_id = indices.indices.len() as u64;
+        indices.indices.push(
+            Index::try_new(
+                "by_id".to_string(),
+                events,
+                put_first("event_id", &events_cols),
+                1,
+                None,
+                None,
+                Index::index_type_default(),
+            )
+            .unwrap(),
+        );
+        for index_id in [by_id - 1, by_id] {
+            for bucket in 0..2 {
+                indices
+                    .partitions
+                    .push(Partition::new_time_bucket(index_id, bucket));
+            }
+        }
+
+        async fn window_on_workers(indices: &TestIndices, partition_by: &str) -> bool {
+            let plan = initial_plan(
+                &format!(
+                    "SELECT event_id, COUNT(*) OVER (PARTITION BY {}) \
+                     FROM s.Events WHERE event_id = 1",
+                    partition_by
+                ),
+                indices,
+            );
+            let (with_index, meta) = choose_index(&plan, indices).await.unwrap();
+            assert_eq!(meta.indices[0].index.get_row().get_name(), "by_id");
+            let pp = pretty_printers::pp_plan(&with_index);
+            pp.find("ClusterSend").unwrap() < pp.find("Window").unwrap()
+        }
+        // Rows with the same `event_id` are in every time bucket.
+        assert!(!window_on_workers(&indices, "event_id").await);
+        assert!(window_on_workers(&indices, "event_id, event_ts").await);
+    }
+
     fn default_indices() -> TestIndices {
         make_test_indices(false)
     }
@@ -1705,6 +2928,7 @@ pub mod tests {
             Vec::new(),
             None,
             None,
+            None,
         ));
         i.indices.push(
             Index::try_new(
@@ -1755,6 +2979,7 @@ pub mod tests {
             Vec::new(),
             None,
             None,
+            None,
         ));
 
         i.indices.push(
@@ -1811,6 +3036,7 @@ pub mod tests {
             Vec::new(),
             None,
             None,
+            None,
         ));
 
         i
@@ -19,13 +19,15 @@ use datafusion::physical_plan::sort::SortExec;
 use datafusion::physical_plan::ExecutionPlan;
 use itertools::{repeat_n, Itertools};
 
+use crate::queryplanner::filter_by_hash_bucket::FilterByHashBucketExec;
 use crate::queryplanner::filter_by_key_range::FilterByKeyRangeExec;
 use crate::queryplanner::panic::{PanicWorkerExec, PanicWorkerNode};
 use crate::queryplanner::planning::{ClusterSendNode, Snapshot, WorkerExec};
 use crate::queryplanner::query_executor::{
     ClusterSendExec, CubeTable, CubeTableExec, InlineTableProvider,
 };
-use crate::queryplanner::serialized_plan::{IndexSnapshot, RowRange};
+use crate::queryplanner::serialized_plan::{IndexSnapshot, JoinDistribution, RowRange};
+use crate::queryplanner::spill::{SpillingAggregateExec, SpillingSortExec};
 use crate::queryplanner::topk::ClusterAggregateTopK;
 use crate::queryplanner::topk::{AggregateTopKExec, SortColumn};
 use crate::queryplanner::CubeTableLogical;
@@ -34,7 +36,7 @@ use datafusion::cube_ext::joinagg::CrossJoinAggExec;
 use datafusion::cube_ext::rolling::RollingWindowAggExec;
 use datafusion::cube_ext::rolling::RollingWindowAggregate;
 use datafusion::physical_plan::empty::EmptyExec;
-use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::expressions::{Column, PhysicalSortExpr};
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::merge::MergeExec;
 use datafusion::physical_plan::parquet::ParquetExec;
@@ -214,7 +216,13 @@ pub fn pp_plan_ext(p: &LogicalPlan, opts: &PPOptions) -> String {
                         log::error!("unknown extension node")
                     }
                 }
-                LogicalPlan::Window { .. } | LogicalPlan::CrossJoin { .. } => {
+                LogicalPlan::Window { window_expr, .. } => {
+                    self.output += "Window";
+                    if self.opts.show_aggregations {
+                        self.output += &format!(", exprs: {:?}", window_expr)
+                    }
+                }
+                LogicalPlan::CrossJoin { .. } => {
                     panic!("unsupported logical plan node")
                 }
             }
@@ -244,6 +252,13 @@ fn pp_index(index: &IndexSnapshot) -> String {
     if let Some(so) = &index.sort_on {
         r += &format!(":sort_on[{}]", so.join(", "))
     }
+    match &index.join_distribution {
+        Some(JoinDistribution::Broadcast) => r += ":broadcast",
+        Some(JoinDistribution::Hash { on, buckets, .. }) => {
+            r += &format!(":hash[{}]/{}", on.join(", "), buckets)
+        }
+        None => {}
+    }
     r
 }
 
@@ -277,8 +292,27 @@ fn pp_sort_columns(first_agg: usize, cs: &[SortColumn]) -> String {
     )
 }
 
+fn pp_sort_exprs(expr: &[PhysicalSortExpr]) -> String {
+    expr.iter()
+        .map(|e| {
+            let mut r = format!("{}", e.expr);
+            if e.options.descending {
+                r += " desc";
+            }
+            if !e.options.nulls_first {
+                r += " nulls last";
+            }
+            r
+        })
+        .join(", ")
+}
+
 fn pp_phys_plan_indented(p: &dyn ExecutionPlan, indent: usize, o: &PPOptions, out: &mut String) {
-    pp_instance(p, indent, o, out);
+    if indent != 0 {
+        *out += "\n";
+    }
+    out.extend(repeat_n(' ', indent));
+    pp_instance(p, o, out);
     if p.as_any().is::<ClusterSendExec>() {
         // Do not show children of ClusterSend. This is a hack to avoid rewriting all tests.
         return;
@@ -286,183 +320,188 @@ fn pp_phys_plan_indented(p: &dyn ExecutionPlan, indent: usize, o: &PPOptions, ou
     for c in p.children() {
         pp_phys_plan_indented(c.as_ref(), indent + 2, o, out);
     }
+}
 
-    fn pp_instance(p: &dyn ExecutionPlan, indent: usize, o: &PPOptions, out: &mut String) {
-        if indent != 0 {
-            *out += "\n";
-        }
-        out.extend(repeat_n(' ', indent));
+/// Single line description of the operator, without its children.
+pub fn pp_phys_operator(p: &dyn ExecutionPlan, o: &PPOptions) -> String {
+    let mut out = String::new();
+    pp_instance(p, o, &mut out);
+    out
+}
 
-        let a = p.as_any();
-        if let Some(t) = a.downcast_ref::<CubeTableExec>() {
-            *out += &format!("Scan, index: {}", pp_index(&t.index_snapshot));
-            if t.index_snapshot.index.get_row().columns().len() == t.schema().fields().len() {
-                *out += ", fields: *";
-            } else {
-                *out += &format!(
-                    ", fields: [{}]",
-                    t.schema().fields().iter().map(|f| f.name()).join(", ")
-                );
-            }
-            if o.show_filters && t.filter.is_some() {
-                *out += &format!(", predicate: {:?}", t.filter.as_ref().unwrap())
-            }
-        } else if let Some(_) = a.downcast_ref::<EmptyExec>() {
-            *out += "Empty";
-        } else if let Some(p) = a.downcast_ref::<ProjectionExec>() {
+fn pp_instance(p: &dyn ExecutionPlan, o: &PPOptions, out: &mut String) {
+    let a = p.as_any();
+    if let Some(t) = a.downcast_ref::<CubeTableExec>() {
+        *out += &format!("Scan, index: {}", pp_index(&t.index_snapshot));
+        if t.index_snapshot.index.get_row().columns().len() == t.schema().fields().len() {
+            *out += ", fields: *";
+        } else {
             *out += &format!(
-                "Projection, [{}]",
-                p.expr()
-                    .iter()
-                    .map(|(e, out_name)| {
-                        if let Some(c) = e.as_any().downcast_ref::<Column>() {
-                            if c.name() == out_name {
-                                return c.name().to_string();
-                            }
-                        }
-                        format!("{}:{}", e.to_string(), out_name)
-                    })
-                    .join(", ")
+                ", fields: [{}]",
+                t.schema().fields().iter().map(|f| f.name()).join(", ")
             );
-        } else if let Some(agg) = a.downcast_ref::<HashAggregateExec>() {
-            let strat = match agg.strategy() {
-                AggregateStrategy::Hash => "Hash",
-                AggregateStrategy::InplaceSorted => "Inplace",
-            };
-            let mode = match agg.mode() {
-                AggregateMode::Partial => "Partial",
-                AggregateMode::Final => "Final",
-                AggregateMode::FinalPartitioned => "FinalPartitioned",
-                AggregateMode::Full => "Full",
-            };
-            *out += &format!("{}{}Aggregate", mode, strat);
-            if o.show_aggregations {
-                *out += &format!(", aggs: {:?}", agg.aggr_expr())
-            }
-        } else if let Some(l) = a.downcast_ref::<LocalLimitExec>() {
-            *out += &format!("LocalLimit, n: {}", l.limit());
-        } else if let Some(l) = a.downcast_ref::<GlobalLimitExec>() {
-            *out += &format!("GlobalLimit, n: {}", l.limit());
-        } else if let Some(f) = a.downcast_ref::<FilterExec>() {
-            *out += "Filter";
-            if o.show_filters {
-                *out += &format!(", predicate: {}", f.predicate())
-            }
-        } else if let Some(s) = a.downcast_ref::<SortExec>() {
-            *out += "Sort";
-            if o.show_sort_by {
-                *out += &format!(
-                    ", by: [{}]",
-                    s.expr()
-                        .iter()
-                        .map(|e| {
-                            let mut r = format!("{}", e.expr);
-                            if e.options.descending {
-                                r += " desc";
-                            }
-                            if !e.options.nulls_first {
-                                r += " nulls last";
-                            }
-                            r
-                        })
-                        .join(", ")
-                );
-            }
-        } else if let Some(_) = a.downcast_ref::<HashJoinExec>() {
-            *out += "HashJoin";
-        } else if let Some(cs) = a.downcast_ref::<ClusterSendExec>() {
-            *out += &format!(
-                "ClusterSend, partitions: [{}]",
-                cs.partitions
-                    .iter()
-                    .map(|(_, (ps, inline))| {
-                        let ps = ps
-                            .iter()
-                            .map(|(id, range)| format!("{}{}", id, pp_row_range(range)))
-                            .join(", ");
-                        if !inline.is_empty() {
-                            format!("[{}, inline: {}]", ps, inline.iter().join(", "))
-                        } else {
-                            format!("[{}]", ps)
+        }
+        if o.show_filters && t.filter.is_some() {
+            *out += &format!(", predicate: {:?}", t.filter.as_ref().unwrap())
+        }
+    } else if let Some(_) = a.downcast_ref::<EmptyExec>() {
+        *out += "Empty";
+    } else if let Some(p) = a.downcast_ref::<ProjectionExec>() {
+        *out += &format!(
+            "Projection, [{}]",
+            p.expr()
+                .iter()
+                .map(|(e, out_name)| {
+                    if let Some(c) = e.as_any().downcast_ref::<Column>() {
+                        if c.name() == out_name {
+                            return c.name().to_string();
                         }
-                    })
-                    .join(", ")
-            );
-        } else if let Some(topk) = a.downcast_ref::<AggregateTopKExec>() {
-            *out += &format!("AggregateTopK, limit: {:?}", topk.limit);
-            if o.show_aggregations {
-                *out += &format!(", aggs: {:?}", topk.agg_expr);
-            }
-            if o.show_sort_by {
-                *out += &format!(
-                    ", sortBy: {}",
-                    pp_sort_columns(topk.key_len, &topk.order_by)
-                );
-            }
-            if o.show_filters {
-                if let Some(having) = &topk.having {
-                    *out += &format!(", having: {}", having);
-                }
-            }
-        } else if let Some(_) = a.downcast_ref::<PanicWorkerExec>() {
-            *out += "PanicWorker";
-        } else if let Some(_) = a.downcast_ref::<WorkerExec>() {
-            *out += "Worker";
-        } else if let Some(_) = a.downcast_ref::<MergeExec>() {
-            *out += "Merge";
-        } else if let Some(_) = a.downcast_ref::<MergeSortExec>() {
-            *out += "MergeSort";
-        } else if let Some(_) = a.downcast_ref::<MergeReSortExec>() {
-            *out += "MergeResort";
-        } else if let Some(j) = a.downcast_ref::<MergeJoinExec>() {
+                    }
+                    format!("{}:{}", e.to_string(), out_name)
+                })
+                .join(", ")
+        );
+    } else if let Some(agg) = a.downcast_ref::<HashAggregateExec>() {
+        let strat = match agg.strategy() {
+            AggregateStrategy::Hash => "Hash",
+            AggregateStrategy::InplaceSorted => "Inplace",
+        };
+        let mode = match agg.mode() {
+            AggregateMode::Partial => "Partial",
+            AggregateMode::Final => "Final",
+            AggregateMode::FinalPartitioned => "FinalPartitioned",
+            AggregateMode::Full => "Full",
+        };
+        *out += &format!("{}{}Aggregate", mode, strat);
+        if o.show_aggregations {
+            *out += &format!(", aggs: {:?}", agg.aggr_expr())
+        }
+    } else if let Some(l) = a.downcast_ref::<LocalLimitExec>() {
+        *out += &format!("LocalLimit, n: {}", l.limit());
+    } else if let Some(l) = a.downcast_ref::<GlobalLimitExec>() {
+        *out += &format!("GlobalLimit, n: {}", l.limit());
+    } else if let Some(f) = a.downcast_ref::<FilterExec>() {
+        *out += "Filter";
+        if o.show_filters {
+            *out += &format!(", predicate: {}", f.predicate())
+        }
+    } else if let Some(s) = a.downcast_ref::<SortExec>() {
+        *out += "Sort";
+        if o.show_sort_by {
+            *out += &format!(", by: [{}]", pp_sort_exprs(s.expr()));
+        }
+    } else if let Some(s) = a.downcast_ref::<SpillingSortExec>() {
+        *out += "SpillingSort";
+        if o.show_sort_by {
+            *out += &format!(", by: [{}]", pp_sort_exprs(&s.expr));
+        }
+    } else if let Some(agg) = a.downcast_ref::<SpillingAggregateExec>() {
+        let mode = match agg.mode() {
+            AggregateMode::Partial => "Partial",
+            AggregateMode::Final => "Final",
+            AggregateMode::FinalPartitioned => "FinalPartitioned",
+            AggregateMode::Full => "Full",
+        };
+        *out += &format!("{}SpillingAggregate", mode);
+        if o.show_aggregations {
+            *out += &format!(", aggs: {:?}", agg.aggregate().aggr_expr())
+        }
+    } else if let Some(_) = a.downcast_ref::<HashJoinExec>() {
+        *out += "HashJoin";
+    } else if let Some(cs) = a.downcast_ref::<ClusterSendExec>() {
+        *out += &format!(
+            "ClusterSend, partitions: [{}]",
+            cs.partitions
+                .iter()
+                .map(|(_, (ps, inline))| {
+                    let ps = ps
+                        .iter()
+                        .map(|(id, range)| format!("{}{}", id, pp_row_range(range)))
+                        .join(", ");
+                    if !inline.is_empty() {
+                        format!("[{}, inline: {}]", ps, inline.iter().join(", "))
+                    } else {
+                        format!("[{}]", ps)
+                    }
+                })
+                .join(", ")
+        );
+    } else if let Some(topk) = a.downcast_ref::<AggregateTopKExec>() {
+        *out += &format!("AggregateTopK, limit: {:?}", topk.limit);
+        if o.show_aggregations {
+            *out += &format!(", aggs: {:?}", topk.agg_expr);
+        }
+        if o.show_sort_by {
             *out += &format!(
-                "MergeJoin, on: [{}]",
-                j.join_on()
-                    .iter()
-                    .map(|(l, r)| format!("{} = {}", l, r))
-                    .join(", ")
+                ", sortBy: {}",
+                pp_sort_columns(topk.key_len, &topk.order_by)
             );
-        } else if let Some(j) = a.downcast_ref::<CrossJoinExec>() {
-            *out += &format!("CrossJoin, on: {}", j.on)
-        } else if let Some(j) = a.downcast_ref::<CrossJoinAggExec>() {
-            *out += &format!("CrossJoinAgg, on: {}", j.join.on);
-            if o.show_aggregations {
-                *out += &format!(", aggs: {:?}", j.agg_expr)
+        }
+        if o.show_filters {
+            if let Some(having) = &topk.having {
+                *out += &format!(", having: {}", having);
             }
-        } else if let Some(_) = a.downcast_ref::<UnionExec>() {
-            *out += "Union";
-        } else if let Some(_) = a.downcast_ref::<FilterByKeyRangeExec>() {
-            *out += "FilterByKeyRange";
-        } else if let Some(p) = a.downcast_ref::<ParquetExec>() {
-            *out += &format!(
-                "ParquetScan, files: {}",
-                p.partitions()
-                    .iter()
-                    .map(|p| p.filenames.iter())
-                    .flatten()
-                    .join(",")
-            );
-        } else if let Some(_) = a.downcast_ref::<SkipExec>() {
-            *out += "SkipRows";
-        } else if let Some(_) = a.downcast_ref::<RollingWindowAggExec>() {
-            *out += "RollingWindowAgg";
-        } else if let Some(_) = a.downcast_ref::<LastRowByUniqueKeyExec>() {
-            *out += "LastRowByUniqueKey";
-        } else if let Some(_) = a.downcast_ref::<MemoryExec>() {
-            *out += "MemoryScan";
-        } else {
-            let to_string = format!("{:?}", p);
-            *out += &to_string.split(" ").next().unwrap_or(&to_string);
         }
+    } else if let Some(_) = a.downcast_ref::<PanicWorkerExec>() {
+        *out += "PanicWorker";
+    } else if let Some(_) = a.downcast_ref::<WorkerExec>() {
+        *out += "Worker";
+    } else if let Some(_) = a.downcast_ref::<MergeExec>() {
+        *out += "Merge";
+    } else if let Some(_) = a.downcast_ref::<MergeSortExec>() {
+        *out += "MergeSort";
+    } else if let Some(_) = a.downcast_ref::<MergeReSortExec>() {
+        *out += "MergeResort";
+    } else if let Some(j) = a.downcast_ref::<MergeJoinExec>() {
+        *out += &format!(
+            "MergeJoin, on: [{}]",
+            j.join_on()
+                .iter()
+                .map(|(l, r)| format!("{} = {}", l, r))
+                .join(", ")
+        );
+    } else if let Some(j) = a.downcast_ref::<CrossJoinExec>() {
+        *out += &format!("CrossJoin, on: {}", j.on)
+    } else if let Some(j) = a.downcast_ref::<CrossJoinAggExec>() {
+        *out += &format!("CrossJoinAgg, on: {}", j.join.on);
+        if o.show_aggregations {
+            *out += &format!(", aggs: {:?}", j.agg_expr)
+        }
+    } else if let Some(_) = a.downcast_ref::<UnionExec>() {
+        *out += "Union";
+    } else if let Some(_) = a.downcast_ref::<FilterByKeyRangeExec>() {
+        *out += "FilterByKeyRange";
+    } else if let Some(_) = a.downcast_ref::<FilterByHashBucketExec>() {
+        *out += "FilterByHashBucket";
+    } else if let Some(p) = a.downcast_ref::<ParquetExec>() {
+        *out += &format!(
+            "ParquetScan, files: {}",
+            p.partitions()
+                .iter()
+                .map(|p| p.filenames.iter())
+                .flatten()
+                .join(",")
+        );
+    } else if let Some(_) = a.downcast_ref::<SkipExec>() {
+        *out += "SkipRows";
+    } else if let Some(_) = a.downcast_ref::<RollingWindowAggExec>() {
+        *out += "RollingWindowAgg";
+    } else if let Some(_) = a.downcast_ref::<LastRowByUniqueKeyExec>() {
+        *out += "LastRowByUniqueKey";
+    } else if let Some(_) = a.downcast_ref::<MemoryExec>() {
+        *out += "MemoryScan";
+    } else {
+        let to_string = format!("{:?}", p);
+        *out += &to_string.split(" ").next().unwrap_or(&to_string);
+    }
 
-        if o.show_output_hints {
-            let hints = p.output_hints();
-            if !hints.single_value_columns.is_empty() {
-                *out += &format!(", single_vals: {:?}", hints.single_value_columns);
-            }
-            if let Some(so) = hints.sort_order {
-                *out += &format!(", sort_order: {:?}", so);
-            }
+    if o.show_output_hints {
+        let hints = p.output_hints();
+        if !hints.single_value_columns.is_empty() {
+            *out += &format!(", single_vals: {:?}", hints.single_value_columns);
+        }
+        if let Some(so) = hints.sort_order {
+            *out += &format!(", sort_order: {:?}", so);
         }
     }
 }
@@ -479,5 +518,8 @@ fn pp_row_range(r: &RowRange) -> String {
         None => "∞".to_string(),
         Some(e) => format!("{:?}", e.values()),
     };
-    format!("[{},{})", s, e)
+    match r.hash_bucket {
+        Some(b) => format!("[{},{}):bucket{}", s, e, b),
+        None => format!("[{},{})", s, e),
+    }
 }
@@ -1,14 +1,24 @@
-use crate::cluster::{pick_worker_by_ids, pick_worker_by_partitions, Cluster};
+use crate::app_metrics;
+use crate::cluster::membership::WorkerSet;
+use crate::cluster::{pick_replicas_by_ids, pick_replicas_by_partitions, Cluster};
 use crate::config::injection::DIService;
 use crate::config::ConfigObj;
 use crate::metastore::multi_index::MultiPartition;
 use crate::metastore::table::Table;
 use crate::metastore::{Column, ColumnType, IdRow, Index, Partition};
+use crate::queryplanner::batch_cache::PartitionBatchCache;
+use crate::queryplanner::explain::{
+    cluster_send_worker_plans, explain_tree, instrument_plan, ExplainNode, ExplainWorker,
+};
+use crate::queryplanner::filter_by_hash_bucket::FilterByHashBucketExec;
 use crate::queryplanner::filter_by_key_range::FilterByKeyRangeExec;
+use crate::queryplanner::memory::{batch_memory_size, MemoryPool, QueryMemory};
 use crate::queryplanner::optimizations::CubeQueryPlanner;
 use crate::queryplanner::planning::{get_worker_plan, Snapshot, Snapshots};
 use crate::queryplanner::pretty_printers::{pp_phys_plan, pp_plan};
-use crate::queryplanner::serialized_plan::{IndexSnapshot, RowFilter, RowRange, SerializedPlan};
+use crate::queryplanner::serialized_plan::{
+    IndexSnapshot, JoinDistribution, RowFilter, RowRange, SerializedPlan,
+};
 use crate::store::DataFrame;
 use crate::table::data::rows_to_columns;
 use crate::table::parquet::CubestoreParquetMetadataCache;
@@ -26,6 +36,7 @@ use arrow::ipc::writer::MemStreamWriter;
 use arrow::record_batch::RecordBatch;
 use async_trait::async_trait;
 use core::fmt;
+use datafusion::cube_ext::stream::StreamWithSchema;
 use datafusion::datasource::datasource::{Statistics, TableProviderFilterPushDown};
 use datafusion::datasource::TableProvider;
 use datafusion::error::DataFusionError;
@@ -34,6 +45,9 @@ use datafusion::execution::context::{ExecutionConfig, ExecutionContext};
 use datafusion::logical_plan;
 use datafusion::logical_plan::{Expr, LogicalPlan};
 use datafusion::physical_plan::empty::EmptyExec;
+use datafusion::physical_plan::hash_aggregate::{
+    AggregateMode, AggregateStrategy, HashAggregateExec,
+};
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::merge::MergeExec;
 use datafusion::physical_plan::merge_sort::{LastRowByUniqueKeyExec, MergeSortExec};
@@ -44,13 +58,15 @@ use datafusion::physical_plan::projection::ProjectionExec;
 use datafusion::physical_plan::{
     collect, ExecutionPlan, OptimizerHints, Partitioning, PhysicalExpr, SendableRecordBatchStream,
 };
+use futures::future::join_all;
+use futures::StreamExt;
 use itertools::Itertools;
 use log::{debug, error, trace, warn};
 use mockall::automock;
 use serde_derive::{Deserialize, Serialize};
 use std::any::Any;
 use std::cmp::min;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::io::Cursor;
 use std::mem::take;
@@ -65,7 +81,7 @@ pub trait QueryExecutor: DIService + Send + Sync {
         &self,
         plan: SerializedPlan,
         cluster: Arc<dyn Cluster>,
-    ) -> Result<(SchemaRef, Vec<RecordBatch>), CubeError>;
+    ) -> Result<(SchemaRef, Vec<RecordBatch>, Vec<WorkerTiming>), CubeError>;
 
     async fn execute_worker_plan(
         &self,
@@ -93,12 +109,33 @@ pub trait QueryExecutor: DIService + Send + Sync {
         remote_to_local_names: HashMap<String, String>,
         chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
     ) -> Result<String, CubeError>;
+
+    /// With `analyze`, runs the query and reports stats of each operator. Worker parts of the
+    /// plan are explained by the workers.
+    async fn explain_router_plan(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+        analyze: bool,
+    ) -> Result<ExplainNode, CubeError>;
+
+    async fn explain_worker_plan(
+        &self,
+        plan: SerializedPlan,
+        remote_to_local_names: HashMap<String, String>,
+        chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
+        analyze: bool,
+    ) -> Result<ExplainNode, CubeError>;
 }
 
 crate::di_service!(MockQueryExecutor, [QueryExecutor]);
 
 pub struct QueryExecutorImpl {
     parquet_metadata_cache: Arc<dyn CubestoreParquetMetadataCache>,
+    batch_cache: Arc<PartitionBatchCache>,
+    /// Shared by all queries executed in this process.
+    memory_pool: Arc<MemoryPool>,
+    config: Arc<dyn ConfigObj>,
 }
 
 crate::di_service!(QueryExecutorImpl, [QueryExecutor]);
@@ -110,7 +147,7 @@ impl QueryExecutor for QueryExecutorImpl {
         &self,
         plan: SerializedPlan,
         cluster: Arc<dyn Cluster>,
-    ) -> Result<(SchemaRef, Vec<RecordBatch>), CubeError> {
+    ) -> Result<(SchemaRef, Vec<RecordBatch>, Vec<WorkerTiming>), CubeError> {
         let collect_span = tracing::span!(tracing::Level::TRACE, "collect_physical_plan");
         let (physical_plan, logical_plan) = self.router_plan(plan, cluster).await?;
         let split_plan = physical_plan;
@@ -126,15 +163,12 @@ impl QueryExecutor for QueryExecutorImpl {
         let execution_time = execution_time.elapsed()?;
         debug!("Query data processing time: {:?}", execution_time,);
         app_metrics::DATA_QUERY_TIME_MS.report(execution_time.as_millis() as i64);
-        if execution_time.as_millis() > 200 {
+        let slow_query_threshold = self.config.slow_query_threshold_ms();
+        if slow_query_threshold != 0 && execution_time.as_millis() > slow_query_threshold as u128 {
             warn!(
-                "Slow Query ({:?}):\n{}",
-                execution_time,
-                pp_plan(&logical_plan)
-            );
-            debug!(
-                "Slow Query Physical Plan ({:?}): {}",
+                "Slow Query ({:?}):\n{}\nPhysical Plan:\n{}",
                 execution_time,
+                pp_plan(&logical_plan),
                 pp_phys_plan(split_plan.as_ref())
             );
         }
@@ -150,7 +184,10 @@ impl QueryExecutor for QueryExecutorImpl {
                 pp_phys_plan(split_plan.as_ref())
             );
         }
-        Ok((split_plan.schema(), results?))
+        let results = results?;
+        let mut worker_timings = Vec::new();
+        collect_worker_timings(split_plan.as_ref(), &mut worker_timings);
+        Ok((split_plan.schema(), results, worker_timings))
     }
 
     #[instrument(level = "trace", skip(self, plan, remote_to_local_names))]
@@ -160,9 +197,14 @@ impl QueryExecutor for QueryExecutorImpl {
         remote_to_local_names: HashMap<String, String>,
         chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
     ) -> Result<(SchemaRef, Vec<RecordBatch>), CubeError> {
-        let (physical_plan, logical_plan) = self
-            .worker_plan(plan, remote_to_local_names, chunk_id_to_record_batches)
-            .await?;
+        self.batch_cache.load(&plan, &remote_to_local_names);
+        let memory = self.query_memory();
+        let (physical_plan, logical_plan) = self.worker_physical_plan(
+            plan,
+            remote_to_local_names,
+            chunk_id_to_record_batches,
+            memory.clone(),
+        )?;
 
         let worker_plan;
         let max_batch_rows;
@@ -216,8 +258,17 @@ impl QueryExecutor for QueryExecutorImpl {
                 pp_phys_plan(worker_plan.as_ref())
             );
         }
+        let results = results?;
+        // Results are kept in memory until they are sent to the router.
+        let mut reservation = memory.as_ref().map(|m| m.reservation());
+        if let Some(reservation) = &mut reservation {
+            let size = results.iter().map(batch_memory_size).sum();
+            reservation
+                .try_grow(size)
+                .map_err(|e| CubeError::user(e.to_string()))?;
+        }
         // TODO: stream results as they become available.
-        let results = regroup_batches(results?, max_batch_rows)?;
+        let results = regroup_batches(results, max_batch_rows)?;
         Ok((worker_plan.schema(), results))
     }
 
@@ -230,9 +281,14 @@ impl QueryExecutor for QueryExecutorImpl {
             HashMap::new(),
             HashMap::new(),
             NoopParquetMetadataCache::new(),
+            None,
         )?;
         let serialized_plan = Arc::new(plan);
-        let ctx = self.router_context(cluster.clone(), serialized_plan.clone())?;
+        let ctx = self.router_context(
+            cluster.clone(),
+            serialized_plan.clone(),
+            self.query_memory(),
+        )?;
         Ok((
             ctx.clone().create_physical_plan(&plan_to_move.clone())?,
             plan_to_move,
@@ -245,18 +301,12 @@ impl QueryExecutor for QueryExecutorImpl {
         remote_to_local_names: HashMap<String, String>,
         chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
     ) -> Result<(Arc<dyn ExecutionPlan>, LogicalPlan), CubeError> {
-        let plan_to_move = plan.logical_plan(
+        self.worker_physical_plan(
+            plan,
             remote_to_local_names,
             chunk_id_to_record_batches,
-            self.parquet_metadata_cache.cache().clone(),
-        )?;
-        let plan = Arc::new(plan);
-        let ctx = self.worker_context(plan.clone())?;
-        let plan_ctx = ctx.clone();
-        Ok((
-            plan_ctx.create_physical_plan(&plan_to_move.clone())?,
-            plan_to_move,
-        ))
+            self.query_memory(),
+        )
     }
 
     async fn pp_worker_plan(
@@ -281,19 +331,131 @@ impl QueryExecutor for QueryExecutorImpl {
 
         Ok(pp_phys_plan(worker_plan.as_ref()))
     }
+
+    async fn explain_router_plan(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+        analyze: bool,
+    ) -> Result<ExplainNode, CubeError> {
+        let (mut physical_plan, _) = self.router_plan(plan, cluster.clone()).await?;
+        if analyze {
+            physical_plan = instrument_plan(physical_plan)?;
+            collect(physical_plan.clone()).await?;
+        }
+
+        // Stats of the workers come from a separate run of their parts of the plan.
+        let worker_futures = cluster_send_worker_plans(physical_plan.as_ref())
+            .into_iter()
+            .map(|plans| {
+                let cluster = cluster.clone();
+                async move {
+                    let explains = plans.into_iter().map(|(node, plan)| {
+                        let cluster = cluster.clone();
+                        async move {
+                            let plan = cluster.run_explain_json(&node, plan, analyze).await?;
+                            Ok::<_, CubeError>(ExplainWorker { node, plan })
+                        }
+                    });
+                    join_all(explains)
+                        .await
+                        .into_iter()
+                        .collect::<Result<Vec<_>, CubeError>>()
+                }
+            });
+        let workers = join_all(worker_futures)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(explain_tree(
+            physical_plan.as_ref(),
+            &mut workers.into_iter(),
+        ))
+    }
+
+    async fn explain_worker_plan(
+        &self,
+        plan: SerializedPlan,
+        remote_to_local_names: HashMap<String, String>,
+        chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
+        analyze: bool,
+    ) -> Result<ExplainNode, CubeError> {
+        let (physical_plan, _) = self
+            .worker_plan(plan, remote_to_local_names, chunk_id_to_record_batches)
+            .await?;
+
+        let mut worker_plan;
+        if let Some((p, _)) = get_worker_plan(&physical_plan) {
+            worker_plan = p;
+        } else {
+            error!("No worker marker in physical plan: {:?}", physical_plan);
+            return Err(CubeError::internal(
+                "Invalid physical plan on worker".to_string(),
+            ));
+        }
+        if analyze {
+            worker_plan = instrument_plan(worker_plan)?;
+            collect(worker_plan.clone()).await?;
+        }
+
+        Ok(explain_tree(worker_plan.as_ref(), &mut std::iter::empty()))
+    }
 }
 
 impl QueryExecutorImpl {
-    pub fn new(parquet_metadata_cache: Arc<dyn CubestoreParquetMetadataCache>) -> Arc<Self> {
+    pub fn new(
+        parquet_metadata_cache: Arc<dyn CubestoreParquetMetadataCache>,
+        batch_cache: Arc<PartitionBatchCache>,
+        config: Arc<dyn ConfigObj>,
+    ) -> Arc<Self> {
         Arc::new(QueryExecutorImpl {
             parquet_metadata_cache,
+            batch_cache,
+            memory_pool: MemoryPool::new(config.worker_memory_limit()),
+            config,
         })
     }
 
+    /// Returns [None] when memory is not limited.
+    fn query_memory(&self) -> Option<Arc<QueryMemory>> {
+        if self.config.query_memory_limit() == 0 && self.config.worker_memory_limit() == 0 {
+            return None;
+        }
+        Some(QueryMemory::new(
+            self.memory_pool.clone(),
+            self.config.query_memory_limit(),
+            self.config.data_dir().join("spill"),
+        ))
+    }
+
+    fn worker_physical_plan(
+        &self,
+        plan: SerializedPlan,
+        remote_to_local_names: HashMap<String, String>,
+        chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
+        memory: Option<Arc<QueryMemory>>,
+    ) -> Result<(Arc<dyn ExecutionPlan>, LogicalPlan), CubeError> {
+        let plan_to_move = plan.logical_plan(
+            remote_to_local_names,
+            chunk_id_to_record_batches,
+            self.parquet_metadata_cache.cache().clone(),
+            Some(self.batch_cache.clone()).filter(|c| c.is_enabled()),
+        )?;
+        let plan = Arc::new(plan);
+        let ctx = self.worker_context(plan.clone(), memory)?;
+        let plan_ctx = ctx.clone();
+        Ok((
+            plan_ctx.create_physical_plan(&plan_to_move.clone())?,
+            plan_to_move,
+        ))
+    }
+
     fn router_context(
         &self,
         cluster: Arc<dyn Cluster>,
         serialized_plan: Arc<SerializedPlan>,
+        memory: Option<Arc<QueryMemory>>,
     ) -> Result<Arc<ExecutionContext>, CubeError> {
         Ok(Arc::new(ExecutionContext::with_config(
             ExecutionConfig::new()
@@ -302,6 +464,7 @@ impl QueryExecutorImpl {
                 .with_query_planner(Arc::new(CubeQueryPlanner::new_on_router(
                     cluster,
                     serialized_plan,
+                    memory,
                 ))),
         )))
     }
@@ -309,12 +472,16 @@ impl QueryExecutorImpl {
     fn worker_context(
         &self,
         serialized_plan: Arc<SerializedPlan>,
+        memory: Option<Arc<QueryMemory>>,
     ) -> Result<Arc<ExecutionContext>, CubeError> {
         Ok(Arc::new(ExecutionContext::with_config(
             ExecutionConfig::new()
                 .with_batch_size(4096)
                 .with_concurrency(1)
-                .with_query_planner(Arc::new(CubeQueryPlanner::new_on_worker(serialized_plan))),
+                .with_query_planner(Arc::new(CubeQueryPlanner::new_on_worker(
+                    serialized_plan,
+                    memory,
+                ))),
         )))
     }
 }
@@ -330,6 +497,8 @@ pub struct CubeTable {
     chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
     #[serde(skip, default = "NoopParquetMetadataCache::new")]
     parquet_metadata_cache: Arc<dyn ParquetMetadataCache>,
+    #[serde(skip, default)]
+    batch_cache: Option<Arc<PartitionBatchCache>>,
 }
 
 impl Debug for CubeTable {
@@ -369,6 +538,7 @@ impl CubeTable {
             worker_partition_ids,
             chunk_id_to_record_batches: HashMap::new(),
             parquet_metadata_cache,
+            batch_cache: None,
         })
     }
 
@@ -388,6 +558,7 @@ impl CubeTable {
         worker_partition_ids: Vec<(u64, RowFilter)>,
         chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
         parquet_metadata_cache: Arc<dyn ParquetMetadataCache>,
+        batch_cache: Option<Arc<PartitionBatchCache>>,
     ) -> CubeTable {
         debug_assert!(worker_partition_ids.iter().is_sorted_by_key(|(id, _)| id));
         let mut t = self.clone();
@@ -395,6 +566,7 @@ impl CubeTable {
         t.worker_partition_ids = worker_partition_ids;
         t.chunk_id_to_record_batches = chunk_id_to_record_batches;
         t.parquet_metadata_cache = parquet_metadata_cache;
+        t.batch_cache = batch_cache;
         t
     }
 
@@ -402,6 +574,44 @@ impl CubeTable {
         &self.index_snapshot
     }
 
+    /// Reads the file from [PartitionBatchCache] when it's there.
+    fn cached_file_scan(
+        &self,
+        remote_path: &str,
+        index_schema: &SchemaRef,
+        index_projection_schema: &SchemaRef,
+        index_projection: &Option<Vec<usize>>,
+    ) -> Result<Option<Arc<dyn ExecutionPlan>>, CubeError> {
+        let cache = match self.batch_cache.as_ref() {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+        let batches = match cache.get(remote_path) {
+            Some(batches) => batches,
+            None => {
+                app_metrics::WORKER_BATCH_CACHE_MISSES.increment();
+                return Ok(None);
+            }
+        };
+        if let Some(batch) = batches.first() {
+            if batch.schema().fields() != index_schema.fields() {
+                log::warn!(
+                    "Cached batches of {} do not match index schema {:?}",
+                    remote_path,
+                    index_schema
+                );
+                app_metrics::WORKER_BATCH_CACHE_MISSES.increment();
+                return Ok(None);
+            }
+        }
+        app_metrics::WORKER_BATCH_CACHE_HITS.increment();
+        Ok(Some(Arc::new(MemoryExec::try_new(
+            &[batches.as_ref().clone()],
+            index_projection_schema.clone(),
+            index_projection.clone(),
+        )?)))
+    }
+
     fn async_scan(
         &self,
         table_projection: &Option<Vec<usize>>,
@@ -423,9 +633,26 @@ impl CubeTable {
         // Prepare projection
         // If it's non last row query just return projection itself
         // If it's last row query re-project it as (key1, key2, __seq, col3, col4)
+        // If it's materialized view re-project it as (key1, key2, col3, col4)
         let table_projection_with_seq_column = {
             let table = self.index_snapshot.table_path.table.get_row();
-            if let Some(mut key_columns) = table.unique_key_columns() {
+            if table.is_materialized_view() {
+                let index = self.index_snapshot.index.get_row();
+                let mut with_keys = Vec::new();
+                for column in index.columns().iter().take(index.sort_key_size() as usize) {
+                    let (i, _) = table_cols
+                        .iter()
+                        .find_position(|c| c.get_name() == column.get_name())
+                        .unwrap();
+                    with_keys.push(i);
+                }
+                for original_projection_index in &table_projection {
+                    if !with_keys.iter().any(|s| *s == *original_projection_index) {
+                        with_keys.push(*original_projection_index);
+                    }
+                }
+                with_keys
+            } else if let Some(mut key_columns) = table.unique_key_columns() {
                 key_columns.push(table.seq_column().expect(&format!(
                     "Seq column is undefined for table: {}",
                     table.get_table_name()
@@ -499,35 +726,82 @@ impl CubeTable {
             None
         };
 
+        // Positions of the join key columns inside the scanned batches for hash joins.
+        let hash_join = match self.index_snapshot.join_distribution() {
+            Some(JoinDistribution::Hash { on, buckets, .. }) => {
+                let mut columns = Vec::with_capacity(on.len());
+                for name in on {
+                    let batch_col_i = index_cols
+                        .iter()
+                        .position(|c| c.get_name() == name)
+                        .and_then(|index_col_i| {
+                            index_projection.iter().position(|c| *c == index_col_i)
+                        })
+                        .ok_or_else(|| {
+                            CubeError::internal(format!(
+                                "Join column {} is not read from index {}",
+                                name,
+                                self.index_snapshot.index().get_row().get_name()
+                            ))
+                        })?;
+                    columns.push(batch_col_i);
+                }
+                Some((columns, *buckets))
+            }
+            _ => None,
+        };
+        let issue_bucket_filter = |node: Arc<dyn ExecutionPlan>, buckets: &Option<Vec<u64>>| match (
+            &hash_join, buckets,
+        ) {
+            (Some((columns, num_buckets)), Some(buckets)) => FilterByHashBucketExec::issue_filter(
+                node,
+                columns.clone(),
+                *num_buckets,
+                buckets.clone(),
+            ),
+            _ => node,
+        };
+
         let predicate = combine_filters(filters);
         for partition_snapshot in partition_snapshots {
             let partition = partition_snapshot.partition();
             let filter = self
                 .worker_partition_ids
                 .binary_search_by_key(&partition.get_id(), |(id, _)| *id);
-            let filter = match filter {
-                Ok(i) => Arc::new(self.worker_partition_ids[i].1.clone()),
+            let (filter, buckets) = match filter {
+                Ok(i) => self.worker_partition_ids[i].1.split_hash_buckets(),
                 Err(_) => continue,
             };
+            let filter = Arc::new(filter);
 
             let key_len = self.index_snapshot.index.get_row().sort_key_size() as usize;
 
             if let Some(remote_path) = partition.get_row().get_full_name(partition.get_id()) {
-                let local_path = self
-                    .remote_to_local_names
-                    .get(remote_path.as_str())
-                    .expect(format!("Missing remote path {}", remote_path).as_str());
-                let arc: Arc<dyn ExecutionPlan> = Arc::new(ParquetExec::try_from_path_with_cache(
-                    &local_path,
-                    index_projection_or_none_on_schema_match.clone(),
-                    predicate.clone(),
-                    batch_size,
-                    1,
-                    None, // TODO: propagate limit
-                    self.parquet_metadata_cache.clone(),
-                )?);
+                let arc = match self.cached_file_scan(
+                    &remote_path,
+                    &index_schema,
+                    &index_projection_schema,
+                    &index_projection_or_none_on_schema_match,
+                )? {
+                    Some(cached) => cached,
+                    None => {
+                        let local_path = self
+                            .remote_to_local_names
+                            .get(remote_path.as_str())
+                            .expect(format!("Missing remote path {}", remote_path).as_str());
+                        Arc::new(ParquetExec::try_from_path_with_cache(
+                            &local_path,
+                            index_projection_or_none_on_schema_match.clone(),
+                            predicate.clone(),
+                            batch_size,
+                            1,
+                            None, // TODO: propagate limit
+                            self.parquet_metadata_cache.clone(),
+                        )?)
+                    }
+                };
                 let arc = FilterByKeyRangeExec::issue_filters(arc, filter.clone(), key_len);
-                partition_execs.push(arc);
+                partition_execs.push(issue_bucket_filter(arc, &buckets));
             }
 
             let chunks = partition_snapshot.chunks();
@@ -554,6 +828,13 @@ impl CubeTable {
                         index_projection_schema.clone(),
                         index_projection_or_none_on_schema_match.clone(),
                     )?)
+                } else if let Some(cached) = self.cached_file_scan(
+                    &chunk.get_row().get_full_name(chunk.get_id()),
+                    &index_schema,
+                    &index_projection_schema,
+                    &index_projection_or_none_on_schema_match,
+                )? {
+                    cached
                 } else {
                     let remote_path = chunk.get_row().get_full_name(chunk.get_id());
                     let local_path = self
@@ -572,7 +853,7 @@ impl CubeTable {
                 };
 
                 let node = FilterByKeyRangeExec::issue_filters(node, filter.clone(), key_len);
-                partition_execs.push(node);
+                partition_execs.push(issue_bucket_filter(node, &buckets));
             }
         }
 
@@ -693,6 +974,71 @@ impl CubeTable {
                 })
                 .collect::<Result<Vec<_>, CubeError>>()?;
             Arc::new(ProjectionExec::try_new(proj_exprs, exec)?)
+        } else if self
+            .index_snapshot()
+            .table_path
+            .table
+            .get_row()
+            .is_materialized_view()
+        {
+            // Chunks of the view are merged only on compaction, so rows with the same key must
+            // be merged on read. Keys are split between partitions, so merging them on each
+            // worker separately is enough.
+            let table = self.index_snapshot().table_path.table.get_row();
+            let key_len = self.index_snapshot.index.get_row().sort_key_size() as usize;
+            let mut groups = Vec::with_capacity(key_len);
+            for c in self
+                .index_snapshot
+                .index
+                .get_row()
+                .columns()
+                .iter()
+                .take(key_len)
+            {
+                let col = datafusion::physical_plan::expressions::Column::new_with_schema(
+                    c.get_name(),
+                    &schema,
+                )?;
+                groups.push(col);
+            }
+            let merge = Arc::new(MergeSortExec::try_new(read_data, groups.clone())?);
+            let aggregates = table
+                .aggregate_columns()
+                .iter()
+                .filter(|a| schema.index_of(a.column().get_name()).is_ok())
+                .map(|a| a.aggregate_expr(&schema))
+                .collect::<Result<Vec<_>, _>>()?;
+            let groups = groups
+                .into_iter()
+                .map(|c| {
+                    let name = c.name().to_string();
+                    (Arc::new(c) as Arc<dyn PhysicalExpr>, name)
+                })
+                .collect_vec();
+            let exec = Arc::new(HashAggregateExec::try_new(
+                AggregateStrategy::InplaceSorted,
+                Some((0..key_len).collect()),
+                AggregateMode::Final,
+                groups,
+                aggregates,
+                merge,
+                schema.clone(),
+            )?);
+            // Re-project the merged data to what was actually queried.
+            let s = exec.schema();
+            let proj_exprs = table_projection
+                .iter()
+                .map(|c| {
+                    let name = table_cols[*c].get_name();
+                    let col = datafusion::physical_plan::expressions::Column::new(
+                        name,
+                        s.index_of(name)?,
+                    );
+                    let col: Arc<dyn PhysicalExpr> = Arc::new(col);
+                    Ok((col, name.clone()))
+                })
+                .collect::<Result<Vec<_>, CubeError>>()?;
+            Arc::new(ProjectionExec::try_new(proj_exprs, exec)?)
         } else if let Some(join_columns) = self.index_snapshot.sort_on() {
             assert!(join_columns.len() <= (self.index_snapshot().index.get_row().sort_key_size() as usize), "The number of columns to sort is greater than the number of sorted columns in the index");
             assert!(
@@ -888,11 +1234,34 @@ pub struct ClusterSendExec {
         /*node*/ String,
         (Vec<PartitionWithFilters>, Vec<InlineTableId>),
     )>,
+    /// Workers to run the corresponding entry of `partitions` on, in the order they are tried.
+    /// Starts with the node from `partitions`.
+    pub replicas: Vec<Vec<String>>,
     /// Never executed, only stored to allow consistent optimization on router and worker.
     pub input_for_optimizations: Arc<dyn ExecutionPlan>,
     pub cluster: Arc<dyn Cluster>,
     pub serialized_plan: Arc<SerializedPlan>,
     pub use_streaming: bool,
+    /// Filled during execution, shared with the copies made by the optimizations.
+    pub worker_timings: Arc<std::sync::Mutex<Vec<WorkerTiming>>>,
+}
+
+/// Time it took a worker to return the results of one [ClusterSendExec] partition.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkerTiming {
+    pub node: String,
+    pub duration_ms: u64,
+}
+
+fn collect_worker_timings(p: &dyn ExecutionPlan, out: &mut Vec<WorkerTiming>) {
+    if let Some(send) = p.as_any().downcast_ref::<ClusterSendExec>() {
+        // Inputs of the cluster send are never executed.
+        out.extend(send.worker_timings.lock().unwrap().iter().cloned());
+        return;
+    }
+    for c in p.children() {
+        collect_worker_timings(c.as_ref(), out);
+    }
 }
 
 pub type PartitionWithFilters = (u64, RowRange);
@@ -905,6 +1274,8 @@ pub enum InlineCompoundPartition {
     Partition(IdRow<Partition>),
     PartitionWithInlineTables(IdRow<Partition>, Vec<InlineTableId>),
     InlineTables(Vec<InlineTableId>),
+    /// Rows of the partitions with the join key in a single bucket, see [JoinDistribution::Hash].
+    HashBucket(Vec<IdRow<Partition>>, /*bucket*/ u64),
 }
 
 impl ClusterSendExec {
@@ -916,28 +1287,73 @@ impl ClusterSendExec {
         input_for_optimizations: Arc<dyn ExecutionPlan>,
         use_streaming: bool,
     ) -> Result<Self, CubeError> {
-        let partitions = Self::distribute_to_workers(
-            cluster.config().as_ref(),
+        let (partitions, replicas) = Self::distribute_to_replicas(
+            cluster.worker_set().as_ref(),
+            cluster.config().select_worker_replicas(),
             union_snapshots,
             &serialized_plan.planning_meta().multi_part_subtree,
-        )?;
+        )?
+        .into_iter()
+        .map(|(replicas, partitions)| ((replicas[0].clone(), partitions), replicas))
+        .unzip();
         Ok(Self {
             schema,
             partitions,
+            replicas,
             cluster,
             serialized_plan,
             input_for_optimizations,
             use_streaming,
+            worker_timings: Arc::new(std::sync::Mutex::new(Vec::new())),
         })
     }
 
     pub(crate) fn distribute_to_workers(
-        config: &dyn ConfigObj,
+        workers: &WorkerSet,
         snapshots: &[Snapshots],
         tree: &HashMap<u64, MultiPartition>,
     ) -> Result<Vec<(String, (Vec<PartitionWithFilters>, Vec<InlineTableId>))>, CubeError> {
+        Ok(Self::distribute_to_replicas(workers, 1, snapshots, tree)?
+            .into_iter()
+            .map(|(mut replicas, partitions)| (replicas.swap_remove(0), partitions))
+            .collect())
+    }
+
+    /// Same as [ClusterSendExec::distribute_to_workers], but assigns `replicas` workers to each
+    /// subquery. Subqueries reading in memory chunks only get the first one: chunks are held by
+    /// the partition owner alone.
+    pub(crate) fn distribute_to_replicas(
+        workers: &WorkerSet,
+        replicas: usize,
+        snapshots: &[Snapshots],
+        tree: &HashMap<u64, MultiPartition>,
+    ) -> Result<Vec<(Vec<String>, (Vec<PartitionWithFilters>, Vec<InlineTableId>))>, CubeError>
+    {
         let partitions = Self::logical_partitions(snapshots, tree)?;
-        Ok(Self::assign_nodes(config, partitions))
+        let in_memory = Self::partitions_with_in_memory_chunks(snapshots);
+        let mut assigned = Self::assign_nodes(workers, replicas, partitions);
+        for (replicas, (partitions, _)) in assigned.iter_mut() {
+            if partitions.iter().any(|(id, _)| in_memory.contains(id)) {
+                replicas.truncate(1);
+            }
+        }
+        Ok(assigned)
+    }
+
+    fn partitions_with_in_memory_chunks(snapshots: &[Snapshots]) -> HashSet<u64> {
+        let mut ids = HashSet::new();
+        for union in snapshots {
+            for index in union {
+                if let Snapshot::Index(index) = index {
+                    for p in &index.partitions {
+                        if p.chunks().iter().any(|c| c.get_row().in_memory()) {
+                            ids.insert(p.partition.get_id());
+                        }
+                    }
+                }
+            }
+        }
+        ids
     }
 
     fn logical_partitions(
@@ -947,12 +1363,18 @@ impl ClusterSendExec {
         let mut to_multiply = Vec::new();
         let mut multi_partitions = HashMap::<u64, Vec<_>>::new();
         let mut has_inline_tables = false;
+        // Sent along with each of the partitions of the other tables.
+        let mut broadcast = Vec::new();
+        // Partitions of both sides of hash joins, by join id.
+        let mut hash_joins = BTreeMap::<u64, (u64, Vec<IdRow<Partition>>)>::new();
         for union in snapshots.iter() {
             let mut ordinary_partitions = Vec::new();
             let mut inline_table_ids = Vec::new();
+            let mut join_distribution = None;
             for index in union {
                 match index {
                     Snapshot::Index(index) => {
+                        join_distribution = index.join_distribution();
                         for p in &index.partitions {
                             match p.partition.get_row().multi_partition_id() {
                                 Some(id) => multi_partitions
@@ -969,6 +1391,27 @@ impl ClusterSendExec {
                     }
                 }
             }
+            if inline_table_ids.is_empty() {
+                match join_distribution {
+                    Some(JoinDistribution::Broadcast) => {
+                        broadcast.extend(
+                            ordinary_partitions
+                                .into_iter()
+                                .map(|p| InlineCompoundPartition::Partition(p)),
+                        );
+                        continue;
+                    }
+                    Some(JoinDistribution::Hash {
+                        join_id, buckets, ..
+                    }) => {
+                        let join = hash_joins.entry(*join_id).or_default();
+                        join.0 = *buckets;
+                        join.1.extend(ordinary_partitions);
+                        continue;
+                    }
+                    None => {}
+                }
+            }
             let partitions_merged_with_inline_tables =
                 if !ordinary_partitions.is_empty() && !inline_table_ids.is_empty() {
                     let last_partition = ordinary_partitions.pop().unwrap();
@@ -995,6 +1438,19 @@ impl ClusterSendExec {
                 to_multiply.push(partitions_merged_with_inline_tables);
             }
         }
+        for (_, (buckets, partitions)) in hash_joins {
+            if partitions.is_empty() {
+                continue;
+            }
+            to_multiply.push(
+                (0..buckets)
+                    .map(|b| InlineCompoundPartition::HashBucket(partitions.clone(), b))
+                    .collect(),
+            );
+        }
+        if to_multiply.is_empty() && !broadcast.is_empty() {
+            to_multiply.push(take(&mut broadcast));
+        }
         assert!(to_multiply.is_empty() || multi_partitions.is_empty(),
                 "invalid state during partition selection. to_multiply: {:?}, multi_partitions: {:?}, snapshots: {:?}",
                 to_multiply, multi_partitions, snapshots);
@@ -1018,6 +1474,10 @@ impl ClusterSendExec {
         let partitions = to_multiply
             .into_iter()
             .multi_cartesian_product()
+            .map(|mut ps| {
+                ps.extend(broadcast.iter().cloned());
+                ps
+            })
             .collect::<Vec<Vec<_>>>();
         Ok(partitions)
     }
@@ -1077,6 +1537,7 @@ impl ClusterSendExec {
         let filter = RowRange {
             start: ps[0].get_row().get_min_val().clone(),
             end: ps[0].get_row().get_max_val().clone(),
+            hash_bucket: None,
         };
 
         let mut r = Vec::with_capacity(ps.len());
@@ -1092,9 +1553,10 @@ impl ClusterSendExec {
     }
 
     fn assign_nodes(
-        c: &dyn ConfigObj,
+        workers: &WorkerSet,
+        replicas: usize,
         logical: Vec<Vec<InlineCompoundPartition>>,
-    ) -> Vec<(String, (Vec<(u64, RowRange)>, Vec<InlineTableId>))> {
+    ) -> Vec<(Vec<String>, (Vec<(u64, RowRange)>, Vec<InlineTableId>))> {
         let mut m: HashMap<_, (Vec<(u64, RowRange)>, Vec<InlineTableId>)> = HashMap::new();
         for ps in &logical {
             let inline_table_ids = ps
@@ -1118,18 +1580,39 @@ impl ClusterSendExec {
                     _ => None,
                 })
                 .collect::<Vec<_>>();
-            let node = match partitions
-                .iter()
-                .next()
-                .and_then(|p| p.get_row().multi_partition_id())
-            {
-                Some(multi_id) => pick_worker_by_ids(c, [multi_id]),
-                None => pick_worker_by_partitions(c, partitions.iter()),
+            let buckets = ps.iter().filter_map(|p| match p {
+                InlineCompoundPartition::HashBucket(ps, b) => Some((ps, *b)),
+                _ => None,
+            });
+            let first_bucket = buckets.clone().next().map(|(_, b)| b);
+            let nodes = match (
+                first_bucket,
+                partitions
+                    .iter()
+                    .next()
+                    .and_then(|p| p.get_row().multi_partition_id()),
+            ) {
+                // Buckets of the same join go to different workers.
+                (Some(bucket), _) => workers.replicas_by_position(bucket, replicas),
+                (None, Some(multi_id)) => pick_replicas_by_ids(workers, [multi_id], replicas),
+                (None, None) => pick_replicas_by_partitions(workers, partitions.iter(), replicas),
             };
-            let node_entry = &mut m.entry(node.to_string()).or_default();
+            let nodes = nodes.into_iter().map(|n| n.to_string()).collect_vec();
+            let node_entry = &mut m.entry(nodes).or_default();
             node_entry
                 .0
                 .extend(Self::issue_filters(partitions.as_slice()));
+            for (ps, bucket) in buckets {
+                node_entry.0.extend(ps.iter().map(|p| {
+                    (
+                        p.get_id(),
+                        RowRange {
+                            hash_bucket: Some(bucket),
+                            ..RowRange::default()
+                        },
+                    )
+                }));
+            }
             node_entry.1.extend(inline_table_ids);
         }
 
@@ -1146,10 +1629,12 @@ impl ClusterSendExec {
         ClusterSendExec {
             schema,
             partitions: self.partitions.clone(),
+            replicas: self.replicas.clone(),
             cluster: self.cluster.clone(),
             serialized_plan: self.serialized_plan.clone(),
             input_for_optimizations,
             use_streaming: self.use_streaming,
+            worker_timings: self.worker_timings.clone(),
         }
     }
 
@@ -1210,10 +1695,12 @@ impl ExecutionPlan for ClusterSendExec {
         Ok(Arc::new(ClusterSendExec {
             schema: self.schema.clone(),
             partitions: self.partitions.clone(),
+            replicas: self.replicas.clone(),
             cluster: self.cluster.clone(),
             serialized_plan: self.serialized_plan.clone(),
             input_for_optimizations,
             use_streaming: self.use_streaming,
+            worker_timings: self.worker_timings.clone(),
         }))
     }
 
@@ -1226,14 +1713,35 @@ impl ExecutionPlan for ClusterSendExec {
         &self,
         partition: usize,
     ) -> Result<SendableRecordBatchStream, DataFusionError> {
-        let (node_name, partitions) = &self.partitions[partition];
+        let (node, partitions) = &self.partitions[partition];
+        let replicas = &self.replicas[partition];
 
         let plan = self.serialized_plan_for_partitions(partitions);
 
+        let start = SystemTime::now();
+        let timings = self.worker_timings.clone();
+        let node = node.clone();
+        let record_timing = move || {
+            timings.lock().unwrap().push(WorkerTiming {
+                node,
+                duration_ms: start.elapsed().map(|d| d.as_millis() as u64).unwrap_or(0),
+            })
+        };
         if self.use_streaming {
-            Ok(self.cluster.run_select_stream(node_name, plan).await?)
+            let stream = self
+                .cluster
+                .run_select_stream_on_replicas(replicas, plan)
+                .await?;
+            let schema = stream.schema();
+            let on_end = futures::stream::once(async move { record_timing() })
+                .filter_map(|()| futures::future::ready(None));
+            Ok(Box::pin(StreamWithSchema::wrap(
+                schema,
+                stream.chain(on_end),
+            )))
         } else {
-            let record_batches = self.cluster.run_select(node_name, plan).await?;
+            let record_batches = self.cluster.run_select_on_replicas(replicas, plan).await?;
+            record_timing();
             // TODO .to_schema_ref()
             let memory_exec = MemoryExec::try_new(&vec![record_batches], self.schema(), None)?;
             memory_exec.execute(0).await
@@ -1,5 +1,7 @@
 use crate::metastore::table::{Table, TablePath};
 use crate::metastore::{Chunk, IdRow, Index, Partition};
+use crate::queryplanner::batch_cache::PartitionBatchCache;
+use crate::queryplanner::cost::IndexCost;
 use crate::queryplanner::panic::PanicWorkerNode;
 use crate::queryplanner::planning::{ClusterSendNode, PlanningMeta, Snapshots};
 use crate::queryplanner::query_executor::{CubeTable, InlineTableId, InlineTableProvider};
@@ -17,18 +19,20 @@ use datafusion::cube_ext::alias::LogicalAlias;
 use datafusion::cube_ext::join::SkewedLeftCrossJoin;
 use datafusion::cube_ext::joinagg::CrossJoinAgg;
 use datafusion::cube_ext::rolling::RollingWindowAggregate;
-use datafusion::logical_plan::window_frames::WindowFrameBound;
+use datafusion::logical_plan::window_frames::{WindowFrame, WindowFrameBound, WindowFrameUnits};
 use datafusion::logical_plan::{
     Column, DFSchemaRef, Expr, JoinConstraint, JoinType, LogicalPlan, Operator, Partitioning,
     PlanVisitor,
 };
 use datafusion::physical_plan::parquet::ParquetMetadataCache;
+use datafusion::physical_plan::window_functions::{BuiltInWindowFunction, WindowFunction};
 use datafusion::physical_plan::{aggregates, functions};
 use datafusion::scalar::ScalarValue;
 use serde_derive::{Deserialize, Serialize};
 use sqlparser::ast::RollingOffset;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::str::FromStr;
 use std::sync::Arc;
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
@@ -37,11 +41,14 @@ pub struct RowRange {
     pub start: Option<Row>,
     /// Exclusive upper bound.
     pub end: Option<Row>,
+    /// Only rows with the join key in this bucket, see [JoinDistribution::Hash].
+    #[serde(default)]
+    pub hash_bucket: Option<u64>,
 }
 
 impl RowRange {
     pub fn matches_all_rows(&self) -> bool {
-        self.start.is_none() && self.end.is_none()
+        self.start.is_none() && self.end.is_none() && self.hash_bucket.is_none()
     }
 }
 
@@ -66,6 +73,30 @@ impl RowFilter {
     pub fn matches_all_rows(&self) -> bool {
         self.or_filters.len() == 1 && self.or_filters[0].matches_all_rows()
     }
+
+    /// Splits hash buckets from the key ranges. Buckets are `None` when some rows are read
+    /// regardless of the bucket.
+    pub fn split_hash_buckets(&self) -> (RowFilter, Option<Vec<u64>>) {
+        let mut ranges = RowFilter::default();
+        let mut buckets = Some(Vec::new());
+        for r in &self.or_filters {
+            match (r.hash_bucket, buckets.as_mut()) {
+                (Some(b), Some(bs)) => bs.push(b),
+                (None, _) => buckets = None,
+                (Some(_), None) => {}
+            }
+            ranges.append_or(RowRange {
+                start: r.start.clone(),
+                end: r.end.clone(),
+                hash_bucket: None,
+            });
+        }
+        if let Some(bs) = buckets.as_mut() {
+            bs.sort_unstable();
+            bs.dedup();
+        }
+        (ranges, buckets)
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -86,7 +117,31 @@ pub struct IndexSnapshot {
     pub table_path: TablePath,
     pub index: IdRow<Index>,
     pub partitions: Vec<PartitionSnapshot>,
+    /// Active partitions skipped by the planner because their key ranges or time buckets can't
+    /// match the filters.
+    pub pruned_partitions: Vec<u64>,
     pub sort_on: Option<Vec<String>>,
+    /// Estimated costs of indexes the planner chose from, the chosen one goes first.
+    /// Empty when there was no choice.
+    #[serde(default)]
+    pub index_choice: Vec<IndexCost>,
+    /// How partitions are sent to workers when the table is a side of a join, see
+    /// [JoinDistribution]. `None` pairs each partition with each partition of the other side.
+    #[serde(default)]
+    pub join_distribution: Option<JoinDistribution>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum JoinDistribution {
+    /// Every worker reading the other side of the join receives all partitions of the table.
+    Broadcast,
+    /// Rows are split into `buckets` by the hash of the `on` columns. Each worker reads a single
+    /// bucket from both sides of the join with the same `join_id`.
+    Hash {
+        join_id: u64,
+        on: Vec<String>,
+        buckets: u64,
+    },
 }
 
 impl IndexSnapshot {
@@ -109,6 +164,30 @@ impl IndexSnapshot {
     pub fn sort_on(&self) -> Option<&Vec<String>> {
         self.sort_on.as_ref()
     }
+
+    pub fn index_choice(&self) -> &Vec<IndexCost> {
+        &self.index_choice
+    }
+
+    pub fn join_distribution(&self) -> Option<&JoinDistribution> {
+        self.join_distribution.as_ref()
+    }
+
+    pub fn pruned_partitions(&self) -> u64 {
+        self.pruned_partitions.len() as u64
+    }
+
+    /// Ids of all active partitions of the index at planning time, sorted.
+    pub fn active_partition_ids(&self) -> Vec<u64> {
+        let mut ids = self
+            .partitions
+            .iter()
+            .map(|p| p.partition.get_id())
+            .chain(self.pruned_partitions.iter().cloned())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -238,6 +317,11 @@ pub enum SerializedLogicalPlan {
         group_by_dimension: Option<SerializedExpr>,
         aggs: Vec<SerializedExpr>,
     },
+    Window {
+        input: Arc<SerializedLogicalPlan>,
+        window_expr: Vec<SerializedExpr>,
+        schema: DFSchemaRef,
+    },
     Panic {},
 }
 
@@ -253,6 +337,7 @@ pub struct WorkerContext {
     inline_table_ids_to_execute: Vec<InlineTableId>,
     chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
     parquet_metadata_cache: Arc<dyn ParquetMetadataCache>,
+    batch_cache: Option<Arc<PartitionBatchCache>>,
 }
 
 impl SerializedLogicalPlan {
@@ -320,6 +405,7 @@ impl SerializedLogicalPlan {
                         worker_context.worker_partition_ids.clone(),
                         worker_context.chunk_id_to_record_batches.clone(),
                         worker_context.parquet_metadata_cache.clone(),
+                        worker_context.batch_cache.clone(),
                     )),
                     SerializedTableSource::InlineTable(v) => Arc::new(
                         v.to_worker_table(worker_context.inline_table_ids_to_execute.clone()),
@@ -467,6 +553,15 @@ impl SerializedLogicalPlan {
                     aggs: exprs(&aggs),
                 }),
             },
+            SerializedLogicalPlan::Window {
+                input,
+                window_expr,
+                schema,
+            } => LogicalPlan::Window {
+                input: Arc::new(input.logical_plan(worker_context)?),
+                window_expr: exprs(&window_expr),
+                schema: schema.clone(),
+            },
             SerializedLogicalPlan::Panic {} => LogicalPlan::Extension {
                 node: Arc::new(PanicWorkerNode {}),
             },
@@ -825,6 +920,27 @@ impl SerializedLogicalPlan {
                     aggs: aggs.clone(),
                 }
             }
+            SerializedLogicalPlan::Window {
+                input,
+                window_expr,
+                schema,
+            } => {
+                let input =
+                    input.remove_unused_tables(partition_ids_to_execute, inline_tables_to_execute);
+
+                if input.is_empty_relation().is_some() {
+                    SerializedLogicalPlan::EmptyRelation {
+                        produce_one_row: false,
+                        schema: schema.clone(),
+                    }
+                } else {
+                    SerializedLogicalPlan::Window {
+                        input: Arc::new(input),
+                        window_expr: window_expr.clone(),
+                        schema: schema.clone(),
+                    }
+                }
+            }
             SerializedLogicalPlan::Panic {} => SerializedLogicalPlan::Panic {},
         }
     }
@@ -895,6 +1011,13 @@ pub enum SerializedExpr {
         end: WindowFrameBound,
         offset_to_end: bool,
     },
+    WindowFunction {
+        fun: SerializedWindowFunction,
+        args: Vec<SerializedExpr>,
+        partition_by: Vec<SerializedExpr>,
+        order_by: Vec<SerializedExpr>,
+        window_frame: Option<SerializedWindowFrame>,
+    },
     InList {
         expr: Box<SerializedExpr>,
         list: Vec<SerializedExpr>,
@@ -903,6 +1026,80 @@ pub enum SerializedExpr {
     Wildcard,
 }
 
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum SerializedWindowFunction {
+    AggregateFunction(aggregates::AggregateFunction),
+    /// Built-in window functions are identified by their SQL name, e.g. `ROW_NUMBER`.
+    BuiltInWindowFunction(String),
+}
+
+impl SerializedWindowFunction {
+    fn new(f: &WindowFunction) -> Self {
+        match f {
+            WindowFunction::AggregateFunction(f) => {
+                SerializedWindowFunction::AggregateFunction(f.clone())
+            }
+            WindowFunction::BuiltInWindowFunction(f) => {
+                SerializedWindowFunction::BuiltInWindowFunction(f.to_string())
+            }
+        }
+    }
+
+    fn window_function(&self) -> WindowFunction {
+        match self {
+            SerializedWindowFunction::AggregateFunction(f) => {
+                WindowFunction::AggregateFunction(f.clone())
+            }
+            SerializedWindowFunction::BuiltInWindowFunction(name) => {
+                WindowFunction::BuiltInWindowFunction(
+                    BuiltInWindowFunction::from_str(name)
+                        .expect("window function was serialized by its name"),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum SerializedWindowFrameUnits {
+    Rows,
+    Range,
+    Groups,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SerializedWindowFrame {
+    units: SerializedWindowFrameUnits,
+    start_bound: WindowFrameBound,
+    end_bound: WindowFrameBound,
+}
+
+impl SerializedWindowFrame {
+    fn new(f: &WindowFrame) -> Self {
+        SerializedWindowFrame {
+            units: match f.units {
+                WindowFrameUnits::Rows => SerializedWindowFrameUnits::Rows,
+                WindowFrameUnits::Range => SerializedWindowFrameUnits::Range,
+                WindowFrameUnits::Groups => SerializedWindowFrameUnits::Groups,
+            },
+            start_bound: f.start_bound.clone(),
+            end_bound: f.end_bound.clone(),
+        }
+    }
+
+    fn window_frame(&self) -> WindowFrame {
+        WindowFrame {
+            units: match self.units {
+                SerializedWindowFrameUnits::Rows => WindowFrameUnits::Rows,
+                SerializedWindowFrameUnits::Range => WindowFrameUnits::Range,
+                SerializedWindowFrameUnits::Groups => WindowFrameUnits::Groups,
+            },
+            start_bound: self.start_bound.clone(),
+            end_bound: self.end_bound.clone(),
+        }
+    }
+}
+
 impl SerializedExpr {
     fn expr(&self) -> Expr {
         match self {
@@ -998,6 +1195,19 @@ impl SerializedExpr {
                     true => RollingOffset::End,
                 },
             },
+            SerializedExpr::WindowFunction {
+                fun,
+                args,
+                partition_by,
+                order_by,
+                window_frame,
+            } => Expr::WindowFunction {
+                fun: fun.window_function(),
+                args: exprs(&args),
+                partition_by: exprs(&partition_by),
+                order_by: exprs(&order_by),
+                window_frame: window_frame.as_ref().map(|f| f.window_frame()),
+            },
             SerializedExpr::InList {
                 expr,
                 list,
@@ -1052,6 +1262,7 @@ impl SerializedPlan {
         remote_to_local_names: HashMap<String, String>,
         chunk_id_to_record_batches: HashMap<u64, Vec<RecordBatch>>,
         parquet_metadata_cache: Arc<dyn ParquetMetadataCache>,
+        batch_cache: Option<Arc<PartitionBatchCache>>,
     ) -> Result<LogicalPlan, CubeError> {
         self.logical_plan.logical_plan(&WorkerContext {
             remote_to_local_names,
@@ -1059,6 +1270,7 @@ impl SerializedPlan {
             inline_table_ids_to_execute: self.inline_table_ids_to_execute.clone(),
             chunk_id_to_record_batches,
             parquet_metadata_cache,
+            batch_cache,
         })
     }
 
@@ -1374,7 +1586,16 @@ impl SerializedPlan {
                     ),
                 },
             },
-            LogicalPlan::Window { .. } | LogicalPlan::CrossJoin { .. } => {
+            LogicalPlan::Window {
+                input,
+                window_expr,
+                schema,
+            } => SerializedLogicalPlan::Window {
+                input: Arc::new(Self::serialized_logical_plan(&input)),
+                window_expr: Self::exprs(window_expr),
+                schema: schema.clone(),
+            },
+            LogicalPlan::CrossJoin { .. } => {
                 panic!("unsupported plan node")
             }
         }
@@ -1495,7 +1716,19 @@ impl SerializedPlan {
                     RollingOffset::End => true,
                 },
             },
-            Expr::WindowFunction { .. } => panic!("window functions are not supported"),
+            Expr::WindowFunction {
+                fun,
+                args,
+                partition_by,
+                order_by,
+                window_frame,
+            } => SerializedExpr::WindowFunction {
+                fun: SerializedWindowFunction::new(fun),
+                args: Self::exprs(args),
+                partition_by: Self::exprs(partition_by),
+                order_by: Self::exprs(order_by),
+                window_frame: window_frame.as_ref().map(SerializedWindowFrame::new),
+            },
         }
     }
 
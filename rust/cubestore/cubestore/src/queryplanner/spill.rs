@@ -0,0 +1,1198 @@
+use crate::queryplanner::memory::{batch_memory_size, MemoryReservation, QueryMemory};
+use arrow::array::UInt32Array;
+use arrow::compute::{lexsort_to_indices, SortOptions};
+use arrow::datatypes::SchemaRef;
+use arrow::error::ArrowError;
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::cube_ext;
+use datafusion::cube_ext::stream::StreamWithSchema;
+use datafusion::error::DataFusionError;
+use datafusion::physical_plan::common::collect;
+use datafusion::physical_plan::expressions::{Column, PhysicalSortExpr};
+use datafusion::physical_plan::group_scalar::GroupByScalar;
+use datafusion::physical_plan::hash_aggregate::{
+    create_accumulators, create_group_by_values, write_group_result_row, AccumulatorSet,
+    AggregateMode, AggregateStrategy, HashAggregateExec,
+};
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::planner::compute_aggregation_strategy;
+use datafusion::physical_plan::{
+    AggregateExpr, Distribution, ExecutionPlan, OptimizerHints, Partitioning, PhysicalExpr,
+    SendableRecordBatchStream,
+};
+use futures::StreamExt;
+use smallvec::smallvec;
+use smallvec::SmallVec;
+use std::any::Any;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Replacement of [datafusion::physical_plan::sort::SortExec] that sorts parts of the input that
+/// fit into the [QueryMemory] limit and writes them to disk. The sorted parts are merged when the
+/// input is exhausted. All input partitions are read, so the node does not need a merge below it.
+#[derive(Debug)]
+pub struct SpillingSortExec {
+    pub expr: Vec<PhysicalSortExpr>,
+    pub input: Arc<dyn ExecutionPlan>,
+    pub memory: Arc<QueryMemory>,
+}
+
+#[async_trait]
+impl ExecutionPlan for SpillingSortExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn required_child_distribution(&self) -> Distribution {
+        Distribution::UnspecifiedDistribution
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        &self,
+        mut children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        assert_eq!(children.len(), 1);
+        Ok(Arc::new(SpillingSortExec {
+            expr: self.expr.clone(),
+            input: children.remove(0),
+            memory: self.memory.clone(),
+        }))
+    }
+
+    fn output_hints(&self) -> OptimizerHints {
+        OptimizerHints::default()
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        assert_eq!(partition, 0);
+        let schema = self.schema();
+        let num_inputs = self.input.output_partitioning().partition_count();
+        let mut inputs = Vec::with_capacity(num_inputs);
+        for p in 0..num_inputs {
+            inputs.push(self.input.execute(p).await?);
+        }
+        let mut input = futures::stream::select_all(inputs);
+        let mut reservation = self.memory.reservation();
+        let mut buffer = Vec::new();
+        let mut runs = Vec::new();
+        while let Some(b) = input.next().await {
+            let b = b?;
+            let size = batch_memory_size(&b);
+            if let Err(e) = reservation.try_grow(size) {
+                if buffer.is_empty() {
+                    return Err(e);
+                }
+                let sorted = sort_batch(&schema, &std::mem::take(&mut buffer), &self.expr)?;
+                runs.push(SpillFile::write(&self.memory, &schema, vec![sorted]).await?);
+                reservation.free();
+                reservation.try_grow(size)?;
+            }
+            buffer.push(b);
+        }
+
+        let sorted = sort_batch(&schema, &buffer, &self.expr)?;
+        drop(buffer);
+        if runs.is_empty() {
+            // Keep the memory reserved until the results are consumed.
+            return Ok(Box::pin(StreamWithSchema::wrap(
+                schema,
+                futures::stream::iter(vec![Ok(sorted)]).map(move |b| {
+                    let _reservation = &reservation;
+                    b
+                }),
+            )));
+        }
+        runs.push(SpillFile::write(&self.memory, &schema, vec![sorted]).await?);
+        drop(reservation);
+        log::debug!(
+            "Sort spilled {} runs to disk, query memory limit reached",
+            runs.len()
+        );
+
+        let runs = runs
+            .into_iter()
+            .map(|r| Box::new(r.into_reader()) as Box<dyn RunReader>)
+            .collect();
+        Ok(blocking_stream(
+            schema.clone(),
+            SortedRunsMerger::new(schema, self.expr.clone(), runs),
+        ))
+    }
+}
+
+/// Number of input rows pre-aggregated at once. Only the resulting group state is accounted in
+/// [QueryMemory], the raw rows are dropped right after the pre-aggregation.
+const PRE_AGGREGATE_ROWS: usize = 64 * 1024;
+
+/// Replacement of [HashAggregateExec] with the [AggregateStrategy::Hash] strategy. Raw input of
+/// the [AggregateMode::Partial] and [AggregateMode::Full] modes is pre-aggregated in chunks of
+/// [PRE_AGGREGATE_ROWS], input of the final modes already holds the group state. Group state is
+/// kept in memory while it fits into the [QueryMemory] limit and written to disk otherwise.
+/// The written parts are sorted by group keys and merged. In the partial mode, the state of equal
+/// groups is merged, so each group is still returned once. In other modes, the merged state is
+/// aggregated once again with a sorted aggregation.
+#[derive(Debug)]
+pub struct SpillingAggregateExec {
+    mode: AggregateMode,
+    group_expr: Vec<(Arc<dyn PhysicalExpr>, String)>,
+    aggr_expr: Vec<Arc<dyn AggregateExpr>>,
+    input: Arc<dyn ExecutionPlan>,
+    input_schema: SchemaRef,
+    /// Group keys followed by accumulator states, i.e. the output of the partial aggregation.
+    state_schema: SchemaRef,
+    /// Original aggregation, only used for planning.
+    aggregate: Arc<HashAggregateExec>,
+    memory: Arc<QueryMemory>,
+}
+
+impl SpillingAggregateExec {
+    /// Returns [None] if the aggregation is not supported.
+    pub fn try_wrap(
+        aggregate: &HashAggregateExec,
+        memory: Arc<QueryMemory>,
+    ) -> Result<Option<SpillingAggregateExec>, DataFusionError> {
+        if aggregate.strategy() != AggregateStrategy::Hash || aggregate.group_expr().is_empty() {
+            return Ok(None);
+        }
+        SpillingAggregateExec::try_new(
+            *aggregate.mode(),
+            aggregate.group_expr().into(),
+            aggregate.aggr_expr().into(),
+            aggregate.input().clone(),
+            aggregate.input_schema().clone(),
+            memory,
+        )
+        .map(Some)
+    }
+
+    fn try_new(
+        mode: AggregateMode,
+        group_expr: Vec<(Arc<dyn PhysicalExpr>, String)>,
+        aggr_expr: Vec<Arc<dyn AggregateExpr>>,
+        input: Arc<dyn ExecutionPlan>,
+        input_schema: SchemaRef,
+        memory: Arc<QueryMemory>,
+    ) -> Result<SpillingAggregateExec, DataFusionError> {
+        let aggregate = Arc::new(HashAggregateExec::try_new(
+            AggregateStrategy::Hash,
+            None,
+            mode,
+            group_expr.clone(),
+            aggr_expr.clone(),
+            input.clone(),
+            input_schema.clone(),
+        )?);
+        let state_schema = match mode {
+            AggregateMode::Partial | AggregateMode::Full => HashAggregateExec::try_new(
+                AggregateStrategy::Hash,
+                None,
+                AggregateMode::Partial,
+                group_expr.clone(),
+                aggr_expr.clone(),
+                input.clone(),
+                input_schema.clone(),
+            )?
+            .schema(),
+            AggregateMode::Final | AggregateMode::FinalPartitioned => input.schema(),
+        };
+        Ok(SpillingAggregateExec {
+            mode,
+            group_expr,
+            aggr_expr,
+            input,
+            input_schema,
+            state_schema,
+            aggregate,
+            memory,
+        })
+    }
+
+    pub fn mode(&self) -> &AggregateMode {
+        &self.mode
+    }
+
+    /// The aggregation this node replaces.
+    pub fn aggregate(&self) -> &Arc<HashAggregateExec> {
+        &self.aggregate
+    }
+
+    fn reads_raw_input(&self) -> bool {
+        matches!(self.mode, AggregateMode::Partial | AggregateMode::Full)
+    }
+
+    /// Turns buffered raw input into the group state.
+    async fn pre_aggregate(
+        &self,
+        buffer: Vec<RecordBatch>,
+    ) -> Result<Vec<RecordBatch>, DataFusionError> {
+        let input = Arc::new(MemoryExec::try_new(
+            &vec![buffer],
+            self.input.schema(),
+            None,
+        )?);
+        let partial = HashAggregateExec::try_new(
+            AggregateStrategy::Hash,
+            None,
+            AggregateMode::Partial,
+            self.group_expr.clone(),
+            self.aggr_expr.clone(),
+            input,
+            self.input_schema.clone(),
+        )?;
+        collect(partial.execute(0).await?).await
+    }
+
+    /// Keeps the group state in memory, writes all of it to disk if it does not fit.
+    async fn add_state(
+        &self,
+        batches: Vec<RecordBatch>,
+        state: &mut Vec<RecordBatch>,
+        reservation: &mut MemoryReservation,
+        runs: &mut Vec<SpillFile>,
+    ) -> Result<(), DataFusionError> {
+        let size = batches.iter().map(batch_memory_size).sum();
+        let over_limit = reservation.try_grow(size).is_err();
+        state.extend(batches);
+        if over_limit {
+            runs.push(self.spill_state(std::mem::take(state)).await?);
+            reservation.free();
+        }
+        Ok(())
+    }
+
+    /// The state is sorted by group keys.
+    async fn spill_state(&self, state: Vec<RecordBatch>) -> Result<SpillFile, DataFusionError> {
+        let state = sort_batch(&self.state_schema, &state, &self.state_group_sort())?;
+        SpillFile::write(&self.memory, &self.state_schema, vec![state]).await
+    }
+
+    /// Merges the state of equal groups in the [sorted] group state.
+    fn merge_state<I>(&self, sorted: I) -> Result<SortedStateMerger<I>, DataFusionError>
+    where
+        I: Iterator<Item = Result<RecordBatch, DataFusionError>>,
+    {
+        SortedStateMerger::new(
+            sorted,
+            self.state_schema.clone(),
+            self.group_expr.len(),
+            self.aggr_expr.clone(),
+        )
+    }
+
+    /// Sort expressions for group keys in the group state.
+    fn state_group_sort(&self) -> Vec<PhysicalSortExpr> {
+        (0..self.group_expr.len())
+            .map(|i| PhysicalSortExpr {
+                expr: Arc::new(Column::new(self.state_schema.field(i).name(), i)),
+                options: SortOptions {
+                    descending: false,
+                    nulls_first: true,
+                },
+            })
+            .collect()
+    }
+
+    /// Computes the final values from the group state.
+    fn final_aggregate(
+        &self,
+        state: Arc<dyn ExecutionPlan>,
+    ) -> Result<HashAggregateExec, DataFusionError> {
+        let group: Vec<(Arc<dyn PhysicalExpr>, String)> = self
+            .group_expr
+            .iter()
+            .enumerate()
+            .map(|(i, (_, name))| {
+                (
+                    Arc::new(Column::new(self.state_schema.field(i).name(), i))
+                        as Arc<dyn PhysicalExpr>,
+                    name.clone(),
+                )
+            })
+            .collect();
+        let (strategy, order) = compute_aggregation_strategy(state.as_ref(), &group);
+        HashAggregateExec::try_new(
+            strategy,
+            order,
+            AggregateMode::Final,
+            group,
+            self.aggr_expr.clone(),
+            state,
+            self.input_schema.clone(),
+        )
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for SpillingAggregateExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.aggregate.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.aggregate.output_partitioning()
+    }
+
+    fn required_child_distribution(&self) -> Distribution {
+        self.aggregate.required_child_distribution()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        &self,
+        mut children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        assert_eq!(children.len(), 1);
+        Ok(Arc::new(SpillingAggregateExec::try_new(
+            self.mode,
+            self.group_expr.clone(),
+            self.aggr_expr.clone(),
+            children.remove(0),
+            self.input_schema.clone(),
+            self.memory.clone(),
+        )?))
+    }
+
+    fn output_hints(&self) -> OptimizerHints {
+        self.aggregate.output_hints()
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        let mut input = self.input.execute(partition).await?;
+        let mut reservation = self.memory.reservation();
+        let mut raw = Vec::new();
+        let mut raw_rows = 0;
+        let mut state = Vec::new();
+        // Groups are only unique within a single pre-aggregated part.
+        let mut pre_aggregated_parts = 0;
+        let mut runs = Vec::new();
+        while let Some(b) = input.next().await {
+            let b = b?;
+            if !self.reads_raw_input() {
+                self.add_state(vec![b], &mut state, &mut reservation, &mut runs)
+                    .await?;
+                continue;
+            }
+            raw_rows += b.num_rows();
+            raw.push(b);
+            if PRE_AGGREGATE_ROWS <= raw_rows {
+                let s = self.pre_aggregate(std::mem::take(&mut raw)).await?;
+                raw_rows = 0;
+                pre_aggregated_parts += 1;
+                self.add_state(s, &mut state, &mut reservation, &mut runs)
+                    .await?;
+            }
+        }
+        if !raw.is_empty() {
+            let s = self.pre_aggregate(raw).await?;
+            pre_aggregated_parts += 1;
+            self.add_state(s, &mut state, &mut reservation, &mut runs)
+                .await?;
+        }
+
+        if runs.is_empty() {
+            let s: SendableRecordBatchStream = match self.mode {
+                AggregateMode::Partial if pre_aggregated_parts <= 1 => {
+                    Box::pin(StreamWithSchema::wrap(
+                        self.state_schema.clone(),
+                        futures::stream::iter(state.into_iter().map(Ok)),
+                    ))
+                }
+                AggregateMode::Partial => {
+                    let state = sort_batch(&self.state_schema, &state, &self.state_group_sort())?;
+                    blocking_stream(
+                        self.state_schema.clone(),
+                        self.merge_state(std::iter::once(Ok(state)))?,
+                    )
+                }
+                _ => {
+                    let state = Arc::new(MemoryExec::try_new(
+                        &vec![state],
+                        self.state_schema.clone(),
+                        None,
+                    )?);
+                    self.final_aggregate(state)?.execute(0).await?
+                }
+            };
+            // Keep the memory reserved until the state is consumed.
+            return Ok(Box::pin(StreamWithSchema::wrap(
+                s.schema(),
+                s.map(move |b| {
+                    let _reservation = &reservation;
+                    b
+                }),
+            )));
+        }
+
+        runs.push(self.spill_state(state).await?);
+        drop(reservation);
+        log::debug!(
+            "Aggregation spilled {} runs to disk, query memory limit reached",
+            runs.len()
+        );
+
+        let runs = runs
+            .into_iter()
+            .map(|r| Box::new(r.into_reader()) as Box<dyn RunReader>)
+            .collect::<Vec<_>>();
+        let schema = self.state_schema.clone();
+        let sort = self.state_group_sort();
+        let sort_order = (0..sort.len()).collect();
+        let merged = SortedRunsMerger::new(schema.clone(), sort, runs);
+        if matches!(self.mode, AggregateMode::Partial) {
+            return Ok(blocking_stream(schema, self.merge_state(merged)?));
+        }
+
+        let merged = blocking_stream(schema.clone(), merged);
+        let aggregate =
+            self.final_aggregate(Arc::new(StreamExec::new(merged, Some(sort_order))))?;
+        debug_assert!(aggregate.strategy() == AggregateStrategy::InplaceSorted);
+        aggregate.execute(0).await
+    }
+}
+
+/// Returns a stream that was already started as an input for other operators. Can only be
+/// executed once.
+pub struct StreamExec {
+    schema: SchemaRef,
+    sort_order: Option<Vec<usize>>,
+    stream: Mutex<Option<SendableRecordBatchStream>>,
+}
+
+impl StreamExec {
+    /// Pass [sort_order] to let the consumers know the stream is sorted by those columns.
+    pub fn new(stream: SendableRecordBatchStream, sort_order: Option<Vec<usize>>) -> StreamExec {
+        StreamExec {
+            schema: stream.schema(),
+            sort_order,
+            stream: Mutex::new(Some(stream)),
+        }
+    }
+}
+
+impl std::fmt::Debug for StreamExec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamExec")
+            .field("sort_order", &self.sort_order)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for StreamExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        assert!(children.is_empty());
+        Err(DataFusionError::Internal(
+            "StreamExec can not be rewritten".to_string(),
+        ))
+    }
+
+    fn output_hints(&self) -> OptimizerHints {
+        OptimizerHints {
+            sort_order: self.sort_order.clone(),
+            single_value_columns: Vec::new(),
+        }
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        assert_eq!(partition, 0);
+        self.stream
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| DataFusionError::Internal("StreamExec executed twice".to_string()))
+    }
+}
+
+/// Batches written to a temporary file inside [QueryMemory::spill_dir]. The file is removed
+/// when dropped.
+pub struct SpillFile {
+    file: File,
+}
+
+impl SpillFile {
+    pub async fn write(
+        memory: &QueryMemory,
+        schema: &SchemaRef,
+        batches: Vec<RecordBatch>,
+    ) -> Result<SpillFile, DataFusionError> {
+        let dir = memory.spill_dir().to_path_buf();
+        let schema = schema.clone();
+        cube_ext::spawn_blocking(move || SpillFile::write_sync(&dir, &schema, &batches))
+            .await
+            .map_err(|e| DataFusionError::Execution(format!("Failed to spill to disk: {}", e)))?
+    }
+
+    fn write_sync(
+        dir: &Path,
+        schema: &SchemaRef,
+        batches: &[RecordBatch],
+    ) -> Result<SpillFile, DataFusionError> {
+        std::fs::create_dir_all(dir)?;
+        let mut file = tempfile::tempfile_in(dir)?;
+        {
+            let mut writer = StreamWriter::try_new(&mut file, schema)?;
+            for b in batches {
+                writer.write(b)?;
+            }
+            writer.finish()?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(SpillFile { file })
+    }
+
+    pub fn into_reader(self) -> SpillReader {
+        SpillReader {
+            file: Some(self.file),
+            reader: None,
+        }
+    }
+}
+
+/// Reads batches from a [SpillFile]. The file is opened on first access, i.e. from the blocking
+/// thread that consumes it.
+pub struct SpillReader {
+    file: Option<File>,
+    reader: Option<StreamReader<File>>,
+}
+
+impl Iterator for SpillReader {
+    type Item = Result<RecordBatch, DataFusionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(file) = self.file.take() {
+            match StreamReader::try_new(file) {
+                Ok(r) => self.reader = Some(r),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        self.reader
+            .as_mut()?
+            .next()
+            .map(|r| r.map_err(|e| e.into()))
+    }
+}
+
+pub trait RunReader: Iterator<Item = Result<RecordBatch, DataFusionError>> + Send {}
+impl<T: Iterator<Item = Result<RecordBatch, DataFusionError>> + Send> RunReader for T {}
+
+/// Merges runs sorted by the same expressions into a single sorted stream. Keeps at most one batch
+/// from each run in memory, plus the rows that can't be returned yet.
+pub struct SortedRunsMerger {
+    schema: SchemaRef,
+    sort_expr: Vec<PhysicalSortExpr>,
+    /// [None] after the run is exhausted.
+    runs: Vec<Option<Box<dyn RunReader>>>,
+    /// Rows that were read and can be larger than rows not read yet.
+    pending: Option<RecordBatch>,
+}
+
+impl std::fmt::Debug for SortedRunsMerger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SortedRunsMerger")
+            .field("sort_expr", &self.sort_expr)
+            .field("runs", &self.runs.len())
+            .finish()
+    }
+}
+
+impl SortedRunsMerger {
+    pub fn new(
+        schema: SchemaRef,
+        sort_expr: Vec<PhysicalSortExpr>,
+        runs: Vec<Box<dyn RunReader>>,
+    ) -> SortedRunsMerger {
+        SortedRunsMerger {
+            schema,
+            sort_expr,
+            runs: runs.into_iter().map(Some).collect(),
+            pending: None,
+        }
+    }
+
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>, DataFusionError> {
+        let mut batches = Vec::with_capacity(self.runs.len() + 1);
+        let mut num_rows = 0;
+        if let Some(p) = self.pending.take() {
+            num_rows += p.num_rows();
+            batches.push(p);
+        }
+        // Positions of the last rows of batches from runs that can have more data. Rows are only
+        // returned up to the smallest of those.
+        let mut bounds = Vec::with_capacity(self.runs.len());
+        for run in &mut self.runs {
+            let batch = loop {
+                match run.as_mut().and_then(|r| r.next()) {
+                    Some(b) => {
+                        let b = b?;
+                        if b.num_rows() != 0 {
+                            break Some(b);
+                        }
+                    }
+                    None => break None,
+                }
+            };
+            match batch {
+                Some(b) => {
+                    num_rows += b.num_rows();
+                    bounds.push(num_rows - 1);
+                    batches.push(b);
+                }
+                None => *run = None,
+            }
+        }
+        if num_rows == 0 {
+            return Ok(None);
+        }
+
+        let batch = RecordBatch::concat(&self.schema, &batches)?;
+        drop(batches);
+        let indices = sort_indices(&batch, &self.sort_expr)?;
+        let indices = indices.values();
+        let emit = if bounds.is_empty() {
+            indices.len()
+        } else {
+            let mut is_bound = vec![false; num_rows];
+            for b in bounds {
+                is_bound[b] = true;
+            }
+            indices.iter().position(|i| is_bound[*i as usize]).unwrap() + 1
+        };
+        if emit != indices.len() {
+            self.pending = Some(take_rows(&batch, &indices[emit..])?);
+        }
+        Ok(Some(take_rows(&batch, &indices[..emit])?))
+    }
+}
+
+impl Iterator for SortedRunsMerger {
+    type Item = Result<RecordBatch, DataFusionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch().transpose()
+    }
+}
+
+/// Merges the state of adjacent rows with equal group keys, so that each group is returned once.
+/// Expects the input to be sorted by the group keys.
+struct SortedStateMerger<I> {
+    input: I,
+    /// Group keys followed by accumulator states.
+    schema: SchemaRef,
+    key_len: usize,
+    aggr_expr: Vec<Arc<dyn AggregateExpr>>,
+    /// Positions of the state columns of each aggregate.
+    state_columns: Vec<Range<usize>>,
+    /// Last group seen so far, it can continue in the next batch.
+    current: Option<(SmallVec<[GroupByScalar; 2]>, AccumulatorSet)>,
+}
+
+impl<I> SortedStateMerger<I>
+where
+    I: Iterator<Item = Result<RecordBatch, DataFusionError>>,
+{
+    fn new(
+        input: I,
+        schema: SchemaRef,
+        key_len: usize,
+        aggr_expr: Vec<Arc<dyn AggregateExpr>>,
+    ) -> Result<Self, DataFusionError> {
+        let mut state_columns = Vec::with_capacity(aggr_expr.len());
+        let mut start = key_len;
+        for e in &aggr_expr {
+            let len = e.state_fields()?.len();
+            state_columns.push(start..start + len);
+            start += len;
+        }
+        Ok(SortedStateMerger {
+            input,
+            schema,
+            key_len,
+            aggr_expr,
+            state_columns,
+            current: None,
+        })
+    }
+
+    /// Adds [rows] of the [batch] to the current group.
+    fn merge_rows(
+        &mut self,
+        batch: &RecordBatch,
+        rows: Range<usize>,
+    ) -> Result<(), DataFusionError> {
+        let (_, accumulators) = self.current.as_mut().unwrap();
+        for (acc, columns) in accumulators.iter_mut().zip(&self.state_columns) {
+            let state = batch.columns()[columns.clone()]
+                .iter()
+                .map(|c| c.slice(rows.start, rows.len()))
+                .collect::<Vec<_>>();
+            acc.merge_batch(&state)?;
+        }
+        Ok(())
+    }
+
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>, DataFusionError> {
+        let mut key_columns = Vec::with_capacity(self.key_len);
+        let mut value_columns = Vec::with_capacity(self.aggr_expr.len());
+        let mut num_groups = 0;
+        let mut key = smallvec![GroupByScalar::Int8(0); self.key_len];
+        while num_groups == 0 {
+            let batch = match self.input.next() {
+                Some(b) => b?,
+                None => {
+                    if let Some((k, accumulators)) = self.current.take() {
+                        write_group_result_row(
+                            AggregateMode::Partial,
+                            &k,
+                            &accumulators,
+                            &self.schema.fields()[..self.key_len],
+                            &mut key_columns,
+                            &mut value_columns,
+                        )?;
+                        num_groups += 1;
+                    }
+                    break;
+                }
+            };
+            // Rows from [start] up to the current one belong to the current group.
+            let mut start = 0;
+            for row in 0..batch.num_rows() {
+                create_group_by_values(&batch.columns()[..self.key_len], row, &mut key)?;
+                if matches!(&self.current, Some((k, _)) if *k == key) {
+                    continue;
+                }
+                if start < row {
+                    self.merge_rows(&batch, start..row)?;
+                }
+                if let Some((k, accumulators)) = self.current.take() {
+                    write_group_result_row(
+                        AggregateMode::Partial,
+                        &k,
+                        &accumulators,
+                        &self.schema.fields()[..self.key_len],
+                        &mut key_columns,
+                        &mut value_columns,
+                    )?;
+                    num_groups += 1;
+                }
+                self.current = Some((key.clone(), create_accumulators(&self.aggr_expr)?));
+                start = row;
+            }
+            if start < batch.num_rows() {
+                self.merge_rows(&batch, start..batch.num_rows())?;
+            }
+        }
+        if num_groups == 0 {
+            return Ok(None);
+        }
+        let columns = key_columns
+            .into_iter()
+            .chain(value_columns)
+            .map(|mut c| c.finish())
+            .collect();
+        Ok(Some(RecordBatch::try_new(self.schema.clone(), columns)?))
+    }
+}
+
+impl<I> Iterator for SortedStateMerger<I>
+where
+    I: Iterator<Item = Result<RecordBatch, DataFusionError>>,
+{
+    type Item = Result<RecordBatch, DataFusionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch().transpose()
+    }
+}
+
+/// Runs the blocking iterator on a separate thread for each batch.
+fn blocking_stream<I>(schema: SchemaRef, batches: I) -> SendableRecordBatchStream
+where
+    I: Iterator<Item = Result<RecordBatch, DataFusionError>> + Send + 'static,
+{
+    let stream = futures::stream::unfold(Some(batches), |batches| async move {
+        let mut batches = batches?;
+        let r = cube_ext::spawn_blocking(move || {
+            let next = batches.next();
+            (batches, next)
+        })
+        .await;
+        match r {
+            Ok((batches, Some(Ok(b)))) => Some((Ok(b), Some(batches))),
+            Ok((_, Some(Err(e)))) => Some((Err(ArrowError::ExternalError(Box::new(e))), None)),
+            Ok((_, None)) => None,
+            Err(e) => Some((Err(ArrowError::ExternalError(Box::new(e))), None)),
+        }
+    });
+    Box::pin(StreamWithSchema::wrap(schema, stream))
+}
+
+fn sort_indices(
+    batch: &RecordBatch,
+    expr: &[PhysicalSortExpr],
+) -> Result<UInt32Array, DataFusionError> {
+    let columns = expr
+        .iter()
+        .map(|e| e.evaluate_to_sort_column(batch))
+        .collect::<Result<Vec<_>, DataFusionError>>()?;
+    Ok(lexsort_to_indices(&columns, None)?)
+}
+
+fn take_rows(batch: &RecordBatch, indices: &[u32]) -> Result<RecordBatch, DataFusionError> {
+    let indices = UInt32Array::from(indices.to_vec());
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|c| arrow::compute::take(c.as_ref(), &indices, None))
+        .collect::<Result<Vec<_>, ArrowError>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+fn sort_batch(
+    schema: &SchemaRef,
+    batches: &[RecordBatch],
+    expr: &[PhysicalSortExpr],
+) -> Result<RecordBatch, DataFusionError> {
+    let batch = RecordBatch::concat(schema, batches)?;
+    if batch.num_rows() == 0 {
+        return Ok(batch);
+    }
+    let indices = sort_indices(&batch, expr)?;
+    take_rows(&batch, indices.values())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queryplanner::memory::MemoryPool;
+    use arrow::array::{Array, Int64Array};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::expressions::Sum;
+    use datafusion::physical_plan::merge::MergeExec;
+    use itertools::Itertools;
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, true)]))
+    }
+
+    fn batch(values: Vec<Option<i64>>) -> RecordBatch {
+        RecordBatch::try_new(schema(), vec![Arc::new(Int64Array::from(values))]).unwrap()
+    }
+
+    fn run(batches: Vec<Vec<Option<i64>>>) -> Box<dyn RunReader> {
+        Box::new(batches.into_iter().map(|b| Ok(batch(b))))
+    }
+
+    fn values(batches: Vec<RecordBatch>) -> Vec<Option<i64>> {
+        batches
+            .iter()
+            .flat_map(|b| {
+                let a = b.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+                (0..a.len())
+                    .map(|i| if a.is_null(i) { None } else { Some(a.value(i)) })
+                    .collect_vec()
+            })
+            .collect()
+    }
+
+    fn merge(descending: bool, runs: Vec<Box<dyn RunReader>>) -> Vec<Option<i64>> {
+        let sort = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: SortOptions {
+                descending,
+                nulls_first: true,
+            },
+        }];
+        values(
+            SortedRunsMerger::new(schema(), sort, runs)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn merge_runs() {
+        let merged = merge(
+            false,
+            vec![
+                run(vec![vec![None, Some(1), Some(4)], vec![Some(7), Some(10)]]),
+                run(vec![
+                    vec![Some(2), Some(3)],
+                    vec![],
+                    vec![Some(11), Some(12)],
+                ]),
+                run(vec![vec![None, Some(4), Some(4), Some(5)]]),
+                run(vec![]),
+            ],
+        );
+        assert_eq!(
+            merged,
+            vec![
+                None,
+                None,
+                Some(1),
+                Some(2),
+                Some(3),
+                Some(4),
+                Some(4),
+                Some(4),
+                Some(5),
+                Some(7),
+                Some(10),
+                Some(11),
+                Some(12)
+            ]
+        );
+
+        let merged = merge(
+            true,
+            vec![
+                run(vec![vec![None, Some(12)], vec![Some(10), Some(5)]]),
+                run(vec![vec![Some(11), Some(4)], vec![Some(4), Some(2)]]),
+                run(vec![vec![None, Some(7), Some(4), Some(3), Some(1)]]),
+            ],
+        );
+        assert_eq!(
+            merged,
+            vec![
+                None,
+                None,
+                Some(12),
+                Some(11),
+                Some(10),
+                Some(7),
+                Some(5),
+                Some(4),
+                Some(4),
+                Some(4),
+                Some(3),
+                Some(2),
+                Some(1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn spill_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill_dir = dir.path().join("spill");
+        let memory = QueryMemory::unlimited(spill_dir.clone());
+        let batches = vec![batch(vec![Some(1), None]), batch(vec![Some(3)])];
+        let f = SpillFile::write(&memory, &schema(), batches).await.unwrap();
+        // Unnamed temporary files are not visible in the directory.
+        assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 0);
+        let read = f.into_reader().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(values(read), vec![Some(1), None, Some(3)]);
+    }
+
+    fn kv_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int64, true),
+            Field::new("v", DataType::Int64, true),
+        ]))
+    }
+
+    /// Partitions of `num_rows` rows each, with keys from `0..num_keys` and values equal to 1.
+    fn kv_input(partitions: usize, num_rows: usize, num_keys: i64) -> Arc<dyn ExecutionPlan> {
+        let partitions = (0..partitions)
+            .map(|p| {
+                let keys = (0..num_rows as i64)
+                    .map(|i| Some((i + p as i64) % num_keys))
+                    .collect_vec();
+                vec![RecordBatch::try_new(
+                    kv_schema(),
+                    vec![
+                        Arc::new(Int64Array::from(keys)),
+                        Arc::new(Int64Array::from(vec![Some(1); num_rows])),
+                    ],
+                )
+                .unwrap()]
+            })
+            .collect_vec();
+        Arc::new(MemoryExec::try_new(&partitions, kv_schema(), None).unwrap())
+    }
+
+    fn sum_by_key(mode: AggregateMode, input: Arc<dyn ExecutionPlan>) -> HashAggregateExec {
+        let group: Vec<(Arc<dyn PhysicalExpr>, String)> =
+            vec![(Arc::new(Column::new("k", 0)), "k".to_string())];
+        let sum: Vec<Arc<dyn AggregateExpr>> = vec![Arc::new(Sum::new(
+            Arc::new(Column::new("v", 1)),
+            "s",
+            DataType::Int64,
+        ))];
+        HashAggregateExec::try_new(
+            AggregateStrategy::Hash,
+            None,
+            mode,
+            group,
+            sum,
+            input,
+            kv_schema(),
+        )
+        .unwrap()
+    }
+
+    /// Memory limit of a single byte makes every operator spill.
+    fn tiny_memory(dir: &Path) -> Arc<QueryMemory> {
+        QueryMemory::new(MemoryPool::new(0), 1, dir.to_path_buf())
+    }
+
+    async fn sorted_rows(p: Arc<dyn ExecutionPlan>) -> Vec<(i64, i64)> {
+        let batches = collect(p.execute(0).await.unwrap()).await.unwrap();
+        let mut rows = Vec::new();
+        for b in batches {
+            let k = b.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+            let v = b.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+            for i in 0..b.num_rows() {
+                rows.push((k.value(i), v.value(i)));
+            }
+        }
+        rows.sort();
+        rows
+    }
+
+    #[tokio::test]
+    async fn spilling_full_aggregate() {
+        let dir = tempfile::tempdir().unwrap();
+        // Spans several pre-aggregation chunks.
+        let num_rows = 3 * PRE_AGGREGATE_ROWS / 2;
+        let input = kv_input(1, num_rows, 1000);
+        let aggregate = sum_by_key(AggregateMode::Full, input);
+        let spilling =
+            SpillingAggregateExec::try_wrap(&aggregate, tiny_memory(dir.path())).unwrap();
+        let rows = sorted_rows(Arc::new(spilling.unwrap())).await;
+        assert_eq!(rows, sorted_rows(Arc::new(aggregate)).await);
+        assert_eq!(rows.len(), 1000);
+        assert_eq!(rows.iter().map(|(_, v)| v).sum::<i64>(), num_rows as i64);
+    }
+
+    #[tokio::test]
+    async fn spilling_partial_aggregate_reports_groups_once() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every pre-aggregated part has all the groups.
+        let part = collect(
+            kv_input(1, PRE_AGGREGATE_ROWS, 1000)
+                .execute(0)
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .remove(0);
+        let num_rows = 2 * PRE_AGGREGATE_ROWS;
+        for memory in [
+            tiny_memory(dir.path()),
+            QueryMemory::unlimited(dir.path().to_path_buf()),
+        ] {
+            let input = Arc::new(
+                MemoryExec::try_new(&vec![vec![part.clone(), part.clone()]], kv_schema(), None)
+                    .unwrap(),
+            );
+            let aggregate = sum_by_key(AggregateMode::Partial, input);
+            let spilling = SpillingAggregateExec::try_wrap(&aggregate, memory.clone())
+                .unwrap()
+                .unwrap();
+            // Top-k plans sort the partial aggregates on workers.
+            let sort = SpillingSortExec {
+                expr: vec![PhysicalSortExpr {
+                    expr: Arc::new(Column::new("s", 1)),
+                    options: SortOptions {
+                        descending: true,
+                        nulls_first: false,
+                    },
+                }],
+                input: Arc::new(spilling),
+                memory,
+            };
+            let rows = sorted_rows(Arc::new(sort)).await;
+            assert_eq!(rows.len(), 1000);
+            assert!(rows.windows(2).all(|w| w[0].0 < w[1].0));
+            assert_eq!(rows.iter().map(|(_, v)| v).sum::<i64>(), num_rows as i64);
+        }
+    }
+
+    #[tokio::test]
+    async fn spilling_final_aggregate_and_sort() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory = tiny_memory(dir.path());
+        let partial = Arc::new(sum_by_key(AggregateMode::Partial, kv_input(3, 100, 7)));
+
+        let final_aggregate = sum_by_key(
+            AggregateMode::Final,
+            Arc::new(MergeExec::new(partial.clone())),
+        );
+        let spilling = SpillingAggregateExec::try_wrap(&final_aggregate, memory.clone())
+            .unwrap()
+            .unwrap();
+        let rows = sorted_rows(Arc::new(spilling)).await;
+        assert_eq!(rows, sorted_rows(Arc::new(final_aggregate)).await);
+        assert_eq!(rows.len(), 7);
+        assert_eq!(rows.iter().map(|(_, v)| v).sum::<i64>(), 300);
+
+        // Reads all partitions of the input.
+        let sort = SpillingSortExec {
+            expr: vec![PhysicalSortExpr {
+                expr: Arc::new(Column::new("k", 0)),
+                options: SortOptions::default(),
+            }],
+            input: partial,
+            memory,
+        };
+        let batches = collect(sort.execute(0).await.unwrap()).await.unwrap();
+        let keys = values(batches);
+        assert_eq!(keys.len(), 21);
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::queryplanner::memory::{batch_memory_size, QueryMemory};
+use crate::queryplanner::spill::{SpillingAggregateExec, SpillingSortExec, StreamExec};
 use crate::queryplanner::topk::SortColumn;
 use crate::queryplanner::udfs::read_sketch;
 use arrow::array::ArrayRef;
@@ -7,23 +9,26 @@ use arrow::error::ArrowError;
 use arrow::record_batch::RecordBatch;
 use async_trait::async_trait;
 use datafusion::cube_ext;
+use datafusion::cube_ext::stream::StreamWithSchema;
 use datafusion::error::DataFusionError;
 
 use datafusion::physical_plan::common::collect;
+use datafusion::physical_plan::expressions::{Column, Max, Min, PhysicalSortExpr};
 use datafusion::physical_plan::filter::FilterExec;
 use datafusion::physical_plan::group_scalar::GroupByScalar;
 use datafusion::physical_plan::hash_aggregate::{
     create_accumulators, create_group_by_values, write_group_result_row, AccumulatorSet,
-    AggregateMode,
+    AggregateMode, AggregateStrategy, HashAggregateExec,
 };
 use datafusion::physical_plan::limit::GlobalLimitExec;
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::{
-    AggregateExpr, ExecutionPlan, OptimizerHints, Partitioning, PhysicalExpr,
+    Accumulator, AggregateExpr, ExecutionPlan, OptimizerHints, Partitioning, PhysicalExpr,
     SendableRecordBatchStream,
 };
 use datafusion::scalar::ScalarValue;
 use flatbuffers::bitflags::_core::cmp::Ordering;
+use futures::stream::Fuse;
 use futures::{Stream, StreamExt};
 use itertools::Itertools;
 use smallvec::smallvec;
@@ -32,6 +37,7 @@ use std::any::Any;
 use std::collections::BTreeSet;
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
 use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -40,8 +46,26 @@ pub enum TopKAggregateFunction {
     Min,
     Max,
     Merge,
+    Count,
+    /// Workers send the accumulator state, i.e. count and sum, instead of the final value.
+    Avg,
 }
 
+impl TopKAggregateFunction {
+    /// Whether a group missing on some node always has a strictly worse score than the estimate
+    /// that uses the last row from that node. In that case estimates of the secondary sort columns
+    /// can be used as is, otherwise they have to be replaced with the best possible values.
+    pub fn is_strictly_monotone(&self) -> bool {
+        match self {
+            TopKAggregateFunction::Sum | TopKAggregateFunction::Count => true,
+            _ => false,
+        }
+    }
+}
+
+/// Merges partial results of the aggregation from [cluster] and returns [limit] top groups.
+/// Input from each node must be sorted by [order_by] and contain the group keys followed by
+/// accumulator states of [agg_expr]. The state is a single column for all functions except `AVG`.
 #[derive(Debug)]
 pub struct AggregateTopKExec {
     pub limit: usize,
@@ -52,7 +76,11 @@ pub struct AggregateTopKExec {
     pub having: Option<Arc<dyn PhysicalExpr>>,
     /// Always an instance of ClusterSendExec or WorkerExec.
     pub cluster: Arc<dyn ExecutionPlan>,
+    /// Output schema, differs from the schema of [cluster] when it sends `AVG` states.
     pub schema: SchemaRef,
+    /// Groups are kept in memory while they fit into the limit. Once they do not, the whole input
+    /// is aggregated and sorted by operators that spill to disk.
+    pub memory: Option<Arc<QueryMemory>>,
 }
 
 /// Third item is the neutral value for the corresponding aggregate function.
@@ -71,6 +99,7 @@ impl AggregateTopKExec {
     ) -> AggregateTopKExec {
         assert_eq!(schema.fields().len(), agg_expr.len() + key_len);
         assert_eq!(agg_fun.len(), agg_expr.len());
+        assert!(!order_by.is_empty());
         let agg_descr = Self::compute_descr(&agg_expr, agg_fun, &order_by);
 
         AggregateTopKExec {
@@ -82,6 +111,7 @@ impl AggregateTopKExec {
             having,
             cluster,
             schema,
+            memory: None,
         }
     }
 
@@ -152,6 +182,7 @@ impl ExecutionPlan for AggregateTopKExec {
             having: self.having.clone(),
             cluster,
             schema: self.schema.clone(),
+            memory: self.memory.clone(),
         }))
     }
 
@@ -198,6 +229,10 @@ impl ExecutionPlan for AggregateTopKExec {
         )?;
         let mut wanted_nodes = vec![true; nodes];
         let mut batches = Vec::with_capacity(nodes);
+        // Input rows are copied into groups, so their size is a good estimate of the state size.
+        let mut reservation = self.memory.as_ref().map(|m| m.reservation());
+        // Input seen so far, replayed into the spilling aggregation when groups exceed the limit.
+        let mut consumed = Vec::new();
         'processing: loop {
             assert!(batches.is_empty());
             for i in 0..nodes {
@@ -210,6 +245,16 @@ impl ExecutionPlan for AggregateTopKExec {
                 }
                 batches.push(batch);
             }
+            if let (Some(memory), Some(r)) = (&self.memory, &mut reservation) {
+                let size = batches.iter().flatten().map(batch_memory_size).sum();
+                consumed.extend(batches.iter().flatten().cloned());
+                if r.try_grow(size).is_err() {
+                    log::debug!(
+                        "Top-k aggregation exceeded the query memory limit, switching to the spilling aggregation"
+                    );
+                    return self.execute_spilling(memory, consumed, streams).await;
+                }
+            }
 
             if state.update(&mut batches).await? {
                 batches.clear();
@@ -228,6 +273,74 @@ impl ExecutionPlan for AggregateTopKExec {
     }
 }
 
+impl AggregateTopKExec {
+    /// Aggregates all groups with operators that spill to disk, then sorts them and applies the
+    /// limit. [consumed] is the input already read from [streams].
+    async fn execute_spilling(
+        &self,
+        memory: &Arc<QueryMemory>,
+        consumed: Vec<RecordBatch>,
+        streams: Vec<(SchemaRef, Fuse<SendableRecordBatchStream>)>,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        let input_schema = self.cluster.schema();
+        let input = futures::stream::iter(consumed.into_iter().map(Ok)).chain(
+            futures::stream::select_all(streams.into_iter().map(|(_, s)| s)),
+        );
+        let input = Arc::new(StreamExec::new(
+            Box::pin(StreamWithSchema::wrap(input_schema.clone(), input)),
+            None,
+        ));
+        let group_expr = (0..self.key_len)
+            .map(|i| {
+                (
+                    Arc::new(Column::new(input_schema.field(i).name(), i)) as Arc<dyn PhysicalExpr>,
+                    self.schema.field(i).name().clone(),
+                )
+            })
+            .collect_vec();
+        let aggregate = HashAggregateExec::try_new(
+            AggregateStrategy::Hash,
+            None,
+            AggregateMode::Final,
+            group_expr,
+            self.agg_expr.clone(),
+            input,
+            input_schema,
+        )?;
+        let mut plan: Arc<dyn ExecutionPlan> =
+            match SpillingAggregateExec::try_wrap(&aggregate, memory.clone())? {
+                Some(a) => Arc::new(a),
+                None => Arc::new(aggregate),
+            };
+        if let Some(having) = &self.having {
+            plan = Arc::new(FilterExec::try_new(having.clone(), plan)?);
+        }
+        let sort_expr = self
+            .order_by
+            .iter()
+            .map(|c| {
+                let i = self.key_len + c.agg_index;
+                PhysicalSortExpr {
+                    expr: Arc::new(Column::new(self.schema.field(i).name(), i)),
+                    options: c.sort_options(),
+                }
+            })
+            .collect_vec();
+        let sort = Arc::new(SpillingSortExec {
+            expr: sort_expr,
+            input: plan,
+            memory: memory.clone(),
+        });
+        let results = GlobalLimitExec::new(sort, self.limit).execute(0).await?;
+        // Field names of the aggregation can differ from the output schema.
+        let schema = self.schema.clone();
+        Ok(Box::pin(StreamWithSchema::wrap(
+            schema.clone(),
+            results.map(move |b| RecordBatch::try_new(schema.clone(), b?.columns().to_vec())),
+        )))
+    }
+}
+
 // Mutex is to provide interior mutability inside async function, no actual waiting ever happens.
 // TODO: remove mutex with careful use of unsafe.
 type TopKBuffer = std::sync::Mutex<Vec<Group>>;
@@ -240,6 +353,10 @@ struct TopKState<'a> {
     having: &'a Option<Arc<dyn PhysicalExpr>>,
     agg_expr: &'a Vec<Arc<dyn AggregateExpr>>,
     agg_descr: &'a [AggDescr],
+    /// Columns of the input batches that hold the accumulator state of each aggregate.
+    state_columns: Vec<Range<usize>>,
+    /// Secondary sort columns that can't be estimated from the node estimates.
+    unbounded: Vec<bool>,
     /// Holds the maximum value seen in each node, used to estimate unseen scores.
     node_estimates: Vec<AccumulatorSet>,
     finished_nodes: Vec<bool>,
@@ -263,8 +380,25 @@ struct Group {
 }
 
 impl Group {
-    fn estimate(&self) -> Result<SmallVec<[ScalarValue; 1]>, DataFusionError> {
-        self.estimates.iter().map(|e| e.evaluate()).collect()
+    /// Aggregates marked as `unbounded` get the best possible score until the group was seen on all
+    /// nodes, see [TopKAggregateFunction::is_strictly_monotone].
+    fn estimate(
+        &self,
+        unbounded: &[bool],
+        agg_descr: &[AggDescr],
+    ) -> Result<SmallVec<[ScalarValue; 1]>, DataFusionError> {
+        let correct = self.estimate_correct();
+        self.estimates
+            .iter()
+            .enumerate()
+            .map(|(i, e)| {
+                let mut v = e.evaluate()?;
+                if unbounded[i] && !correct {
+                    to_best_value(&mut v, &agg_descr[i].1);
+                }
+                Ok(v)
+            })
+            .collect()
     }
 
     fn estimate_correct(&self) -> bool {
@@ -344,6 +478,19 @@ impl TopKState<'_> {
         buffer: &'a mut TopKBuffer,
         schema: SchemaRef,
     ) -> Result<TopKState<'a>, DataFusionError> {
+        let mut state_columns = Vec::with_capacity(agg_expr.len());
+        let mut offset = key_len;
+        for e in agg_expr {
+            let len = e.state_fields()?.len();
+            state_columns.push(offset..offset + len);
+            offset += len;
+        }
+        let mut unbounded = vec![false; agg_expr.len()];
+        if !agg_descr[order_by[0].agg_index].0.is_strictly_monotone() {
+            for o in &order_by[1..] {
+                unbounded[o.agg_index] = true;
+            }
+        }
         Ok(TopKState {
             limit,
             buffer,
@@ -352,6 +499,8 @@ impl TopKState<'_> {
             having,
             agg_expr,
             agg_descr,
+            state_columns,
+            unbounded,
             finished_nodes: vec![false; num_nodes],
             // initialized with the first record batches, see [update].
             node_estimates: Vec::with_capacity(num_nodes),
@@ -396,7 +545,7 @@ impl TopKState<'_> {
                 if let Some(batch) = &batches[node] {
                     assert_ne!(batch.num_rows(), 0, "empty batch passed to `update`");
                     Self::update_node_estimates(
-                        self.key_len,
+                        &self.state_columns,
                         self.agg_descr,
                         &mut estimates,
                         batch.columns(),
@@ -455,7 +604,7 @@ impl TopKState<'_> {
                         data.pop();
 
                         // Prepare to update the estimates, will re-add when done.
-                        let estimate = data[existing].estimate()?;
+                        let estimate = data[existing].estimate(&self.unbounded, self.agg_descr)?;
                         self.sorted.remove(&SortKey {
                             order_by: self.order_by,
                             estimate,
@@ -467,7 +616,8 @@ impl TopKState<'_> {
                         let mut data = self.buffer.lock().unwrap();
                         let g = &mut data[temp_index];
                         g.accumulators = create_accumulators(self.agg_expr).unwrap();
-                        g.estimates = create_accumulators(self.agg_expr).unwrap();
+                        g.estimates =
+                            create_estimate_accumulators(self.agg_expr, self.agg_descr).unwrap();
                         g.nodes = self.finished_nodes.clone();
                     }
 
@@ -478,14 +628,16 @@ impl TopKState<'_> {
                         let group = &mut data[existing];
                         group.nodes[node] = true;
                         for i in 0..group.accumulators.len() {
-                            group.accumulators[i].update_batch(&vec![batch
-                                .column(self.key_len + i)
-                                .slice(row_i, 1)])?;
+                            group.accumulators[i].merge_batch(&state_slice(
+                                batch.columns(),
+                                &self.state_columns[i],
+                                row_i,
+                            ))?;
                         }
                         self.update_group_estimates(group)?;
                         key = SortKey {
                             order_by: self.order_by,
-                            estimate: group.estimate()?,
+                            estimate: group.estimate(&self.unbounded, self.agg_descr)?,
                             estimate_correct: group.estimate_correct(),
                             index: existing,
                         }
@@ -494,7 +646,7 @@ impl TopKState<'_> {
                     assert!(inserted);
 
                     Self::update_node_estimates(
-                        self.key_len,
+                        &self.state_columns,
                         self.agg_descr,
                         &mut self.node_estimates[node],
                         batch.columns(),
@@ -538,7 +690,8 @@ impl TopKState<'_> {
                     self.update_group_estimates(&mut data[candidate.index])?;
                     updated = SortKey {
                         order_by: self.order_by,
-                        estimate: data[candidate.index].estimate()?,
+                        estimate: data[candidate.index]
+                            .estimate(&self.unbounded, self.agg_descr)?,
                         estimate_correct: data[candidate.index].estimate_correct(),
                         index: candidate.index,
                     };
@@ -636,8 +789,9 @@ impl TopKState<'_> {
     /// Returns true iff the estimate matches the correct score.
     fn update_group_estimates(&self, group: &mut Group) -> Result<(), DataFusionError> {
         for i in 0..group.estimates.len() {
+            let f = &self.agg_descr[i].0;
             group.estimates[i].reset();
-            group.estimates[i].merge(&group.accumulators[i].state()?)?;
+            group.estimates[i].merge(&estimate_state(f, group.accumulators[i].as_ref())?)?;
             // Node estimate might contain a neutral value (e.g. '0' for sum), but we must avoid
             // giving invalid estimates for NULL values.
             let use_node_estimates =
@@ -649,7 +803,8 @@ impl TopKState<'_> {
                         continue;
                     }
                     if use_node_estimates {
-                        group.estimates[i].merge(&self.node_estimates[node][i].state()?)?;
+                        group.estimates[i]
+                            .merge(&estimate_state(f, self.node_estimates[node][i].as_ref())?)?;
                     }
                 }
             }
@@ -658,7 +813,7 @@ impl TopKState<'_> {
     }
 
     fn update_node_estimates(
-        key_len: usize,
+        state_columns: &[Range<usize>],
         agg_descr: &[AggDescr],
         estimates: &mut AccumulatorSet,
         columns: &[ArrayRef],
@@ -666,12 +821,18 @@ impl TopKState<'_> {
     ) -> Result<(), DataFusionError> {
         for (i, acc) in estimates.iter_mut().enumerate() {
             acc.reset();
+            let state = state_slice(columns, &state_columns[i], row_i);
+            if agg_descr[i].0 == TopKAggregateFunction::Avg {
+                // Missing values do not affect the average, so there is no neutral value.
+                acc.merge_batch(&state)?;
+                continue;
+            }
 
             // evaluate() gives us a scalar value of the required type.
             let mut neutral = acc.evaluate()?;
             to_neutral_value(&mut neutral, &agg_descr[i].0);
 
-            acc.update_batch(&vec![columns[key_len + i].slice(row_i, 1)])?;
+            acc.merge_batch(&state)?;
 
             // Neutral value (i.e. missing on the node) might be the right estimate.
             // E.g. `0` is better than `-10` on `SUM(x) ORDER BY SUM(x) DESC`.
@@ -779,12 +940,94 @@ fn cmp_same_types(l: &ScalarValue, r: &ScalarValue, nulls_first: bool, asc: bool
     }
 }
 
+fn state_slice(columns: &[ArrayRef], state: &Range<usize>, row_i: usize) -> Vec<ArrayRef> {
+    columns[state.clone()]
+        .iter()
+        .map(|c| c.slice(row_i, 1))
+        .collect()
+}
+
+/// Estimates are computed by merging the states of the group and node accumulators. This works
+/// for all functions except `AVG`: average of the estimates is not an upper bound. Instead, we
+/// take the best of the averages seen so far, the group average can't be better than that.
+fn create_estimate_accumulators(
+    agg_expr: &[Arc<dyn AggregateExpr>],
+    agg_descr: &[AggDescr],
+) -> Result<AccumulatorSet, DataFusionError> {
+    let mut accs = create_accumulators(agg_expr)?;
+    for i in 0..accs.len() {
+        if agg_descr[i].0 != TopKAggregateFunction::Avg {
+            continue;
+        }
+        let field = agg_expr[i].field()?;
+        let col = Arc::new(Column::new(field.name(), 0));
+        let best: Arc<dyn AggregateExpr> = if agg_descr[i].1.descending {
+            Arc::new(Max::new(
+                col,
+                field.name().clone(),
+                field.data_type().clone(),
+            ))
+        } else {
+            Arc::new(Min::new(
+                col,
+                field.name().clone(),
+                field.data_type().clone(),
+            ))
+        };
+        accs[i] = best.create_accumulator()?;
+    }
+    Ok(accs)
+}
+
+/// State to merge into the estimate accumulators, see [create_estimate_accumulators].
+fn estimate_state(
+    f: &TopKAggregateFunction,
+    acc: &dyn Accumulator,
+) -> Result<SmallVec<[ScalarValue; 2]>, DataFusionError> {
+    match f {
+        TopKAggregateFunction::Avg => Ok(smallvec![acc.evaluate()?]),
+        _ => acc.state(),
+    }
+}
+
+/// Planner only allows types supported here, see `aggr_schema_allows_topk`.
 fn to_neutral_value(s: &mut ScalarValue, f: &TopKAggregateFunction) {
     match f {
-        TopKAggregateFunction::Sum => to_zero(s),
+        TopKAggregateFunction::Sum | TopKAggregateFunction::Count => to_zero(s),
         TopKAggregateFunction::Min => to_max_value(s),
         TopKAggregateFunction::Max => to_min_value(s),
         TopKAggregateFunction::Merge => to_empty_sketch(s),
+        TopKAggregateFunction::Avg => panic!("AVG does not have a neutral value"),
+    }
+}
+
+/// Replaces `s` with the value that goes first in the specified sort order.
+fn to_best_value(s: &mut ScalarValue, o: &SortOptions) {
+    if o.nulls_first {
+        to_null(s)
+    } else if o.descending {
+        to_max_value(s)
+    } else {
+        to_min_value(s)
+    }
+}
+
+fn to_null(s: &mut ScalarValue) {
+    match s {
+        ScalarValue::Boolean(v) => *v = None,
+        ScalarValue::Float32(v) => *v = None,
+        ScalarValue::Float64(v) => *v = None,
+        ScalarValue::Int8(v) => *v = None,
+        ScalarValue::Int16(v) => *v = None,
+        ScalarValue::Int32(v) => *v = None,
+        ScalarValue::Int64(v) => *v = None,
+        ScalarValue::Int64Decimal(v, _) => *v = None,
+        ScalarValue::UInt8(v) => *v = None,
+        ScalarValue::UInt16(v) => *v = None,
+        ScalarValue::UInt32(v) => *v = None,
+        ScalarValue::UInt64(v) => *v = None,
+        ScalarValue::Binary(v) => *v = None,
+        _ => panic!("unsupported data type"),
     }
 }
 
@@ -1211,6 +1454,7 @@ mod tests {
             TopKAggregateFunction::Sum => Some(AggregateFunction::Sum),
             TopKAggregateFunction::Max => Some(AggregateFunction::Max),
             TopKAggregateFunction::Min => Some(AggregateFunction::Min),
+            TopKAggregateFunction::Count => Some(AggregateFunction::Count),
             _ => None,
         }
     }
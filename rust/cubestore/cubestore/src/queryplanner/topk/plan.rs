@@ -5,17 +5,21 @@ use crate::queryplanner::udfs::{
     aggregate_kind_by_name, scalar_kind_by_name, scalar_udf_by_kind, CubeAggregateUDFKind,
     CubeScalarUDFKind,
 };
+use arrow::array::{Array, Float64Array};
+use arrow::compute::kernels::cast::cast;
 use arrow::datatypes::{DataType, Schema};
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::ExecutionContextState;
-use datafusion::logical_plan::{DFSchema, DFSchemaRef, Expr, LogicalPlan};
+use datafusion::logical_plan::{self, DFSchema, DFSchemaRef, Expr, LogicalPlan, Operator};
 use datafusion::physical_plan::aggregates::AggregateFunction;
 use datafusion::physical_plan::expressions::{Column, PhysicalSortExpr};
+use datafusion::physical_plan::functions::Signature;
 use datafusion::physical_plan::hash_aggregate::{AggregateMode, HashAggregateExec};
 use datafusion::physical_plan::planner::{compute_aggregation_strategy, physical_name};
 use datafusion::physical_plan::sort::{SortExec, SortOptions};
-use datafusion::physical_plan::udf::create_physical_expr;
-use datafusion::physical_plan::{ExecutionPlan, PhysicalExpr, PhysicalPlanner};
+use datafusion::physical_plan::udf::{create_physical_expr, ScalarUDF};
+use datafusion::physical_plan::{ColumnarValue, ExecutionPlan, PhysicalExpr, PhysicalPlanner};
+use datafusion::scalar::ScalarValue;
 
 use itertools::Itertools;
 use std::cmp::max;
@@ -49,7 +53,11 @@ pub fn materialize_topk(p: LogicalPlan) -> Result<LogicalPlan, DataFusionError>
                         if group_expr.len() == 0
                             || aggr_expr.len() == 0
                             || !aggr_exprs_allow_topk(aggr_expr)
-                            || !aggr_schema_allows_topk(aggregate_schema.as_ref(), group_expr.len())
+                            || !aggr_schema_allows_topk(
+                                aggregate_schema.as_ref(),
+                                aggr_expr,
+                                group_expr.len(),
+                            )
                         {
                             return Ok(p);
                         }
@@ -58,12 +66,15 @@ pub fn materialize_topk(p: LogicalPlan) -> Result<LogicalPlan, DataFusionError>
                             group_expr.len(),
                             &sort_expr,
                             sort_input.schema(),
-                            projection.as_ref().map(|c| c.input_columns.as_slice()),
+                            projection.as_ref(),
                         ) {
                             sort_columns = sc;
                         } else {
                             return Ok(p);
                         }
+                        if !sort_columns_allow_topk(&sort_columns, aggr_expr) {
+                            return Ok(p);
+                        }
                         match cluster_send.as_ref() {
                             LogicalPlan::Extension { node } => {
                                 let cs;
@@ -94,9 +105,7 @@ pub fn materialize_topk(p: LogicalPlan) -> Result<LogicalPlan, DataFusionError>
                                         let in_field = in_schema.field(p.input_columns[out_i]);
                                         let out_name = out_schema.field(out_i).name();
 
-                                        //let mut e = Expr::Column(f.qualified_column());
-                                        let mut e =
-                                            p.post_projection[p.input_columns[out_i]].clone();
+                                        let mut e = p.post_projection[out_i].clone();
                                         if out_name != in_field.name() {
                                             e = Expr::Alias(Box::new(e), out_name.clone())
                                         }
@@ -143,24 +152,31 @@ fn aggr_exprs_allow_topk(agg_exprs: &[Expr]) -> bool {
     return true;
 }
 
-fn aggr_schema_allows_topk(schema: &DFSchema, group_expr_len: usize) -> bool {
-    for agg_field in &schema.fields()[group_expr_len..] {
-        match agg_field.data_type() {
-            DataType::Boolean
-            | DataType::Int8
-            | DataType::Int16
-            | DataType::Int32
-            | DataType::Int64
-            | DataType::UInt8
-            | DataType::UInt16
-            | DataType::UInt32
-            | DataType::UInt64
-            | DataType::Float16
-            | DataType::Float32
-            | DataType::Float64
-            | DataType::Binary
-            | DataType::Int64Decimal(_) => {} // ok, continue.
-            _ => return false,
+/// Estimates need neutral and extreme values of the aggregate type, see `AggregateTopKExec`.
+/// Those are defined for numeric types and, in case of `MERGE`, for HLL sketches.
+fn aggr_schema_allows_topk(schema: &DFSchema, aggr_expr: &[Expr], group_expr_len: usize) -> bool {
+    for (agg_field, e) in schema.fields()[group_expr_len..].iter().zip(aggr_expr) {
+        let allowed = match extract_aggregate_fun(e) {
+            Some(TopKAggregateFunction::Merge) => agg_field.data_type() == &DataType::Binary,
+            Some(_) => match agg_field.data_type() {
+                DataType::Boolean
+                | DataType::Int8
+                | DataType::Int16
+                | DataType::Int32
+                | DataType::Int64
+                | DataType::UInt8
+                | DataType::UInt16
+                | DataType::UInt32
+                | DataType::UInt64
+                | DataType::Float32
+                | DataType::Float64
+                | DataType::Int64Decimal(_) => true,
+                _ => false,
+            },
+            None => false,
+        };
+        if !allowed {
+            return false;
         }
     }
     return true;
@@ -168,21 +184,37 @@ fn aggr_schema_allows_topk(schema: &DFSchema, group_expr_len: usize) -> bool {
 
 fn fun_allows_topk(f: AggregateFunction) -> bool {
     // Only monotone functions are allowed in principle.
-    // Implementation also requires accumulator state and final value to be the same.
-    // TODO: lift the restriction and add support for Avg.
+    // Avg is not monotone, workers send its state and the router estimates it separately.
     match f {
-        AggregateFunction::Sum | AggregateFunction::Min | AggregateFunction::Max => true,
-        AggregateFunction::Count | AggregateFunction::Avg => false,
+        AggregateFunction::Sum
+        | AggregateFunction::Min
+        | AggregateFunction::Max
+        | AggregateFunction::Count
+        | AggregateFunction::Avg => true,
     }
 }
 
+/// Secondary sort columns get the best possible estimates when the primary one is not strictly
+/// monotone. We can't produce those for HLL sketches.
+fn sort_columns_allow_topk(sort_columns: &[SortColumn], aggr_expr: &[Expr]) -> bool {
+    let primary = extract_aggregate_fun(&aggr_expr[sort_columns[0].agg_index]);
+    if primary.map(|f| f.is_strictly_monotone()).unwrap_or(false) {
+        return true;
+    }
+    sort_columns[1..].iter().all(|c| {
+        extract_aggregate_fun(&aggr_expr[c.agg_index]) != Some(TopKAggregateFunction::Merge)
+            || c.nulls_first
+    })
+}
+
 fn extract_aggregate_fun(e: &Expr) -> Option<TopKAggregateFunction> {
     match e {
         Expr::AggregateFunction { fun, .. } => match fun {
             AggregateFunction::Sum => Some(TopKAggregateFunction::Sum),
             AggregateFunction::Min => Some(TopKAggregateFunction::Min),
             AggregateFunction::Max => Some(TopKAggregateFunction::Max),
-            _ => None,
+            AggregateFunction::Count => Some(TopKAggregateFunction::Count),
+            AggregateFunction::Avg => Some(TopKAggregateFunction::Avg),
         },
         Expr::AggregateUDF { fun, .. } => match aggregate_kind_by_name(&fun.name) {
             Some(CubeAggregateUDFKind::MergeHll) => Some(TopKAggregateFunction::Merge),
@@ -195,6 +227,8 @@ fn extract_aggregate_fun(e: &Expr) -> Option<TopKAggregateFunction> {
 #[derive(Debug)]
 struct ColumnProjection<'a> {
     input_columns: Vec<usize>,
+    /// For each output column, true iff it is a non-increasing function of the input column.
+    reverses_order: Vec<bool>,
     input: &'a Arc<LogicalPlan>,
     schema: &'a DFSchemaRef,
     post_projection: Vec<Expr>,
@@ -217,12 +251,14 @@ fn extract_projection_and_having(p: &LogicalPlan) -> Option<ColumnProjection> {
         } => {
             let in_schema = input.schema();
             let mut input_columns = Vec::with_capacity(expr.len());
+            let mut reverses_order = Vec::with_capacity(expr.len());
             let mut post_projection = Vec::with_capacity(expr.len());
             for e in expr {
                 match e {
                     Expr::Alias(box Expr::Column(c), _) | Expr::Column(c) => {
                         let fi = field_index(in_schema, c.relation.as_deref(), &c.name)?;
                         input_columns.push(fi);
+                        reverses_order.push(false);
                         let in_field = in_schema.field(fi);
                         post_projection.push(Expr::Column(in_field.qualified_column()));
                     }
@@ -232,6 +268,7 @@ fn extract_projection_and_having(p: &LogicalPlan) -> Option<ColumnProjection> {
                             Expr::Column(c) => {
                                 let fi = field_index(in_schema, c.relation.as_deref(), &c.name)?;
                                 input_columns.push(fi);
+                                reverses_order.push(false);
                                 let in_field = in_schema.field(fi);
                                 post_projection.push(Expr::ScalarUDF {
                                     fun: Arc::new(
@@ -245,13 +282,19 @@ fn extract_projection_and_having(p: &LogicalPlan) -> Option<ColumnProjection> {
                         },
                         _ => return None,
                     },
-
-                    _ => return None,
+                    Expr::Alias(box e, _) | e => {
+                        let (c, increasing) = monotone_column_expr(e, in_schema)?;
+                        let fi = field_index(in_schema, c.relation.as_deref(), &c.name)?;
+                        input_columns.push(fi);
+                        reverses_order.push(!increasing);
+                        post_projection.push(e.clone());
+                    }
                 }
             }
             let (having_expr, input) = extract_having(input);
             Some(ColumnProjection {
                 input_columns,
+                reverses_order,
                 input,
                 schema,
                 post_projection,
@@ -266,26 +309,33 @@ fn extract_sort_columns(
     group_key_len: usize,
     sort_expr: &[Expr],
     schema: &DFSchema,
-    projection: Option<&[usize]>,
+    projection: Option<&ColumnProjection>,
 ) -> Option<Vec<SortColumn>> {
     let mut sort_columns = Vec::with_capacity(sort_expr.len());
     for e in sort_expr {
         match e {
             Expr::Sort {
-                expr: box Expr::Column(c),
+                expr,
                 asc,
                 nulls_first,
             } => {
+                // Sorting by a monotone function of the aggregate is the same as sorting by the
+                // aggregate itself, possibly in reverse order. NULLs stay in place.
+                let (c, increasing) = monotone_column_expr(expr, schema)?;
+                let mut asc = if increasing { *asc } else { !*asc };
                 let mut index = field_index(schema, c.relation.as_deref(), &c.name)?;
                 if let Some(p) = projection {
-                    index = p[index];
+                    if p.reverses_order[index] {
+                        asc = !asc;
+                    }
+                    index = p.input_columns[index];
                 }
                 if index < group_key_len {
                     return None;
                 }
                 sort_columns.push(SortColumn {
                     agg_index: index - group_key_len,
-                    asc: *asc,
+                    asc,
                     nulls_first: *nulls_first,
                 })
             }
@@ -295,6 +345,104 @@ fn extract_sort_columns(
     Some(sort_columns)
 }
 
+/// Checks `e` is a monotone function of a single column, e.g. `SUM(x) * 100` or `-MAX(x)`.
+/// Returns the column and whether the function is non-decreasing.
+fn monotone_column_expr<'a>(
+    e: &'a Expr,
+    schema: &DFSchema,
+) -> Option<(&'a logical_plan::Column, bool)> {
+    match e {
+        Expr::Column(c) => Some((c, true)),
+        Expr::Cast { expr, data_type } => {
+            // Narrowing casts wrap around or lose precision and change the order.
+            if !is_widening_cast(&expr.get_type(schema).ok()?, data_type) {
+                return None;
+            }
+            monotone_column_expr(expr, schema)
+        }
+        Expr::Negative(expr) => monotone_column_expr(expr, schema).map(|(c, inc)| (c, !inc)),
+        Expr::BinaryExpr { left, op, right } => match (left.as_ref(), op, right.as_ref()) {
+            (e, Operator::Plus | Operator::Minus, Expr::Literal(l))
+            | (Expr::Literal(l), Operator::Plus, e)
+                if !l.is_null() =>
+            {
+                monotone_column_expr(e, schema)
+            }
+            (Expr::Literal(l), Operator::Minus, e) if !l.is_null() => {
+                monotone_column_expr(e, schema).map(|(c, inc)| (c, !inc))
+            }
+            (e, Operator::Multiply | Operator::Divide, Expr::Literal(l))
+            | (Expr::Literal(l), Operator::Multiply, e) => {
+                let positive = literal_is_positive(l)?;
+                monotone_column_expr(e, schema).map(|(c, inc)| (c, inc == positive))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// True iff every value of `from` is represented exactly in `to`.
+fn is_widening_cast(from: &DataType, to: &DataType) -> bool {
+    if from == to {
+        return true;
+    }
+    match (int_type_width(from), int_type_width(to)) {
+        (Some((from_signed, from_bits)), Some((to_signed, to_bits))) => {
+            return if from_signed == to_signed {
+                from_bits <= to_bits
+            } else {
+                !from_signed && from_bits < to_bits
+            };
+        }
+        _ => {}
+    }
+    // Floats keep integers with up to 24 (f32) or 53 (f64) significant bits.
+    match (from, to) {
+        (DataType::Float32, DataType::Float64) => true,
+        (_, DataType::Float32) => int_type_width(from).map_or(false, |(_, bits)| bits <= 16),
+        (_, DataType::Float64) => int_type_width(from).map_or(false, |(_, bits)| bits <= 32),
+        _ => false,
+    }
+}
+
+/// Signedness and the number of bits of integer types.
+fn int_type_width(t: &DataType) -> Option<(bool, u8)> {
+    match t {
+        DataType::Int8 => Some((true, 8)),
+        DataType::Int16 => Some((true, 16)),
+        DataType::Int32 => Some((true, 32)),
+        DataType::Int64 => Some((true, 64)),
+        DataType::UInt8 => Some((false, 8)),
+        DataType::UInt16 => Some((false, 16)),
+        DataType::UInt32 => Some((false, 32)),
+        DataType::UInt64 => Some((false, 64)),
+        _ => None,
+    }
+}
+
+/// Returns `None` for zero, NULL and non-numeric values.
+fn literal_is_positive(v: &ScalarValue) -> Option<bool> {
+    let sign = match v {
+        ScalarValue::Int8(Some(v)) => v.signum() as i64,
+        ScalarValue::Int16(Some(v)) => v.signum() as i64,
+        ScalarValue::Int32(Some(v)) => v.signum() as i64,
+        ScalarValue::Int64(Some(v)) => v.signum(),
+        ScalarValue::Int64Decimal(Some(v), _) => v.signum(),
+        ScalarValue::UInt8(Some(v)) => (*v != 0) as i64,
+        ScalarValue::UInt16(Some(v)) => (*v != 0) as i64,
+        ScalarValue::UInt32(Some(v)) => (*v != 0) as i64,
+        ScalarValue::UInt64(Some(v)) => (*v != 0) as i64,
+        ScalarValue::Float32(Some(v)) if *v != 0. => v.signum() as i64,
+        ScalarValue::Float64(Some(v)) if *v != 0. => v.signum() as i64,
+        _ => return None,
+    };
+    match sign {
+        0 => None,
+        s => Some(s > 0),
+    }
+}
+
 fn field_index(schema: &DFSchema, qualifier: Option<&str>, name: &str) -> Option<usize> {
     schema
         .fields()
@@ -335,11 +483,23 @@ pub fn plan_topk(
             planner.create_aggregate_expr(e, &logical_input_schema, &physical_input_schema, ctx)
         })
         .collect::<Result<Vec<_>, DataFusionError>>()?;
+    let agg_fun = node
+        .aggregate_expr
+        .iter()
+        .map(|e| extract_aggregate_fun(e).unwrap())
+        .collect_vec();
+    // Final value of all functions except Avg matches its state, so workers can compute it.
+    let mode = if agg_fun.contains(&TopKAggregateFunction::Avg) {
+        AggregateMode::Partial
+    } else {
+        AggregateMode::Full
+    };
+
     let (strategy, order) = compute_aggregation_strategy(input.as_ref(), &group_expr);
     let aggregate = Arc::new(HashAggregateExec::try_new(
         strategy,
         order,
-        AggregateMode::Full,
+        mode,
         group_expr,
         initial_aggregate_expr.clone(),
         input,
@@ -348,44 +508,64 @@ pub fn plan_topk(
 
     let aggregate_schema = aggregate.as_ref().schema();
 
-    let agg_fun = node
-        .aggregate_expr
-        .iter()
-        .map(|e| extract_aggregate_fun(e).unwrap())
-        .collect_vec();
+    let mut state_columns = Vec::with_capacity(initial_aggregate_expr.len());
+    let mut offset = group_expr_len;
+    for e in &initial_aggregate_expr {
+        let len = e.state_fields()?.len();
+        state_columns.push(
+            (offset..offset + len)
+                .map(|i| {
+                    Arc::new(Column::new(aggregate_schema.field(i).name(), i))
+                        as Arc<dyn PhysicalExpr>
+                })
+                .collect_vec(),
+        );
+        offset += len;
+    }
     //
     // Sort on workers.
     let sort_expr = node
         .order_by
         .iter()
         .map(|c| {
-            let i = group_expr_len + c.agg_index;
-            PhysicalSortExpr {
+            Ok(PhysicalSortExpr {
                 expr: make_sort_expr(
                     &aggregate_schema,
                     &agg_fun[c.agg_index],
-                    Arc::new(Column::new(aggregate_schema.field(i).name(), i)),
-                ),
+                    state_columns[c.agg_index].clone(),
+                )?,
                 options: SortOptions {
                     descending: !c.asc,
                     nulls_first: c.nulls_first,
                 },
-            }
+            })
         })
-        .collect_vec();
+        .collect::<Result<Vec<_>, DataFusionError>>()?;
     let sort = Arc::new(SortExec::try_new(sort_expr, aggregate)?);
     let sort_schema = sort.schema();
 
     // Send results to router.
-    let schema = sort_schema.clone();
     let cluster = ext_planner.plan_cluster_send(
         sort,
         &node.snapshots,
-        schema.clone(),
+        sort_schema.clone(),
         /*use_streaming*/ true,
         /*max_batch_rows*/ max(2 * node.limit, MIN_TOPK_STREAM_ROWS),
     )?;
 
+    let schema = Arc::new(Schema::new(
+        sort_schema.fields()[0..group_expr_len]
+            .iter()
+            .cloned()
+            .chain(
+                initial_aggregate_expr
+                    .iter()
+                    .map(|e| e.field())
+                    .collect::<Result<Vec<_>, DataFusionError>>()?,
+            )
+            .collect(),
+    ));
+
     let having = if let Some(predicate) = &node.having_expr {
         Some(planner.create_physical_expr(predicate, &node.schema, &schema, ctx)?)
     } else {
@@ -407,15 +587,49 @@ pub fn plan_topk(
 fn make_sort_expr(
     schema: &Arc<Schema>,
     fun: &TopKAggregateFunction,
-    col: Arc<dyn PhysicalExpr>,
-) -> Arc<dyn PhysicalExpr> {
+    state: Vec<Arc<dyn PhysicalExpr>>,
+) -> Result<Arc<dyn PhysicalExpr>, DataFusionError> {
     match fun {
         TopKAggregateFunction::Merge => create_physical_expr(
             &scalar_udf_by_kind(CubeScalarUDFKind::HllCardinality).descriptor(),
-            &[col],
+            &state,
             schema,
-        )
-        .unwrap(),
-        _ => col,
+        ),
+        TopKAggregateFunction::Avg => create_physical_expr(&avg_from_state(), &state, schema),
+        _ => {
+            assert_eq!(state.len(), 1);
+            Ok(state.into_iter().next().unwrap())
+        }
+    }
+}
+
+/// Computes the average from the count and sum, i.e. the state of the `AVG` accumulator.
+/// Only used on workers to sort the partial results, never appears in the logical plan.
+fn avg_from_state() -> ScalarUDF {
+    ScalarUDF {
+        name: "AVG_FROM_STATE".to_string(),
+        signature: Signature::Any(2),
+        return_type: Arc::new(|_| Ok(Arc::new(DataType::Float64))),
+        fun: Arc::new(|inputs| {
+            assert_eq!(inputs.len(), 2);
+            let num_rows = match (&inputs[0], &inputs[1]) {
+                (ColumnarValue::Array(a), _) | (_, ColumnarValue::Array(a)) => a.len(),
+                _ => 1,
+            };
+            let count = cast(&inputs[0].clone().into_array(num_rows), &DataType::Float64)?;
+            let count = count.as_any().downcast_ref::<Float64Array>().unwrap();
+            let sum = cast(&inputs[1].clone().into_array(num_rows), &DataType::Float64)?;
+            let sum = sum.as_any().downcast_ref::<Float64Array>().unwrap();
+            let avg = (0..num_rows)
+                .map(|i| {
+                    if count.is_null(i) || sum.is_null(i) || count.value(i) == 0. {
+                        None
+                    } else {
+                        Some(sum.value(i) / count.value(i))
+                    }
+                })
+                .collect::<Float64Array>();
+            Ok(ColumnarValue::Array(Arc::new(avg)))
+        }),
     }
 }
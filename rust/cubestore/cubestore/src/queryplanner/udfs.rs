@@ -1,9 +1,13 @@
+use crate::metastore::HllFlavour;
 use crate::queryplanner::coalesce::{coalesce, SUPPORTED_COALESCE_TYPES};
 use crate::queryplanner::hll::Hll;
 use crate::CubeError;
-use arrow::array::{Array, BinaryArray, TimestampNanosecondArray, UInt64Builder};
+use arrow::array::{
+    Array, BinaryArray, BinaryBuilder, StringBuilder, TimestampNanosecondArray, UInt64Builder,
+};
 use arrow::datatypes::{DataType, IntervalUnit, TimeUnit};
 use chrono::{TimeZone, Utc};
+use cubedatasketches::ThetaSketch;
 use datafusion::cube_ext::datetime::{date_addsub_array, date_addsub_scalar};
 use datafusion::error::DataFusionError;
 use datafusion::physical_plan::functions::Signature;
@@ -24,6 +28,12 @@ pub enum CubeScalarUDFKind {
     UnixTimestamp,
     DateAdd,
     DateSub,
+    HllInit,            // hll_init(), building a HyperLogLog sketch from a single value.
+    HllExportPostgres,  // hll_export_postgres(), converting sketches to the Postgres `hll` type.
+    HllExportSnowflake, // hll_export_snowflake(), converting sketches to the Snowflake JSON.
+    HllConvert,         // hll_convert(), converting sketches between Airlift and ZetaSketch.
+    ThetaEstimate,      // theta_estimate(), accepting the theta sketches.
+    ThetaANotB,         // theta_a_not_b(), the difference of two theta sketches.
 }
 
 pub trait CubeScalarUDF {
@@ -40,6 +50,16 @@ pub fn scalar_udf_by_kind(k: CubeScalarUDFKind) -> Box<dyn CubeScalarUDF> {
         CubeScalarUDFKind::UnixTimestamp => Box::new(UnixTimestamp {}),
         CubeScalarUDFKind::DateAdd => Box::new(DateAddSub { is_add: true }),
         CubeScalarUDFKind::DateSub => Box::new(DateAddSub { is_add: false }),
+        CubeScalarUDFKind::HllInit => Box::new(HllInit {}),
+        CubeScalarUDFKind::HllExportPostgres => Box::new(HllExport {
+            flavour: HllFlavour::Postgres,
+        }),
+        CubeScalarUDFKind::HllExportSnowflake => Box::new(HllExport {
+            flavour: HllFlavour::Snowflake,
+        }),
+        CubeScalarUDFKind::HllConvert => Box::new(HllConvert {}),
+        CubeScalarUDFKind::ThetaEstimate => Box::new(ThetaEstimate {}),
+        CubeScalarUDFKind::ThetaANotB => Box::new(ThetaANotB {}),
     }
 }
 
@@ -63,12 +83,37 @@ pub fn scalar_kind_by_name(n: &str) -> Option<CubeScalarUDFKind> {
     if n == "DATE_SUB" {
         return Some(CubeScalarUDFKind::DateSub);
     }
+    if n == "HLL_INIT" {
+        return Some(CubeScalarUDFKind::HllInit);
+    }
+    if n == "HLL_EXPORT_POSTGRES" {
+        return Some(CubeScalarUDFKind::HllExportPostgres);
+    }
+    if n == "HLL_EXPORT_SNOWFLAKE" {
+        return Some(CubeScalarUDFKind::HllExportSnowflake);
+    }
+    if n == "HLL_CONVERT" {
+        return Some(CubeScalarUDFKind::HllConvert);
+    }
+    if n == "THETA_ESTIMATE" {
+        return Some(CubeScalarUDFKind::ThetaEstimate);
+    }
+    if n == "THETA_A_NOT_B" {
+        return Some(CubeScalarUDFKind::ThetaANotB);
+    }
     return None;
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum CubeAggregateUDFKind {
     MergeHll, // merge(), accepting the HyperLogLog sketches.
+    AnyValue,
+    LastValue,
+    BitOr,
+    HllInitAgg,     // hll_init_agg(), building a HyperLogLog sketch from raw values.
+    ThetaInitAgg,   // theta_init_agg(), building a theta sketch from raw values.
+    ThetaUnion,     // theta_union(), accepting the theta sketches.
+    ThetaIntersect, // theta_intersect(), accepting the theta sketches.
 }
 
 pub trait CubeAggregateUDF {
@@ -81,6 +126,17 @@ pub trait CubeAggregateUDF {
 pub fn aggregate_udf_by_kind(k: CubeAggregateUDFKind) -> Box<dyn CubeAggregateUDF> {
     match k {
         CubeAggregateUDFKind::MergeHll => Box::new(HllMergeUDF {}),
+        CubeAggregateUDFKind::AnyValue => Box::new(AnyValueUDF {}),
+        CubeAggregateUDFKind::LastValue => Box::new(LastValueUDF {}),
+        CubeAggregateUDFKind::BitOr => Box::new(BitOrUDF {}),
+        CubeAggregateUDFKind::HllInitAgg => Box::new(HllInitAggUDF {}),
+        CubeAggregateUDFKind::ThetaInitAgg => Box::new(ThetaInitAggUDF {}),
+        CubeAggregateUDFKind::ThetaUnion => Box::new(ThetaSetOpUDF {
+            op: ThetaSetOp::Union,
+        }),
+        CubeAggregateUDFKind::ThetaIntersect => Box::new(ThetaSetOpUDF {
+            op: ThetaSetOp::Intersect,
+        }),
     }
 }
 
@@ -89,6 +145,27 @@ pub fn aggregate_kind_by_name(n: &str) -> Option<CubeAggregateUDFKind> {
     if n == "MERGE" {
         return Some(CubeAggregateUDFKind::MergeHll);
     }
+    if n == "ANY_VALUE" {
+        return Some(CubeAggregateUDFKind::AnyValue);
+    }
+    if n == "LAST_VALUE" {
+        return Some(CubeAggregateUDFKind::LastValue);
+    }
+    if n == "BIT_OR" {
+        return Some(CubeAggregateUDFKind::BitOr);
+    }
+    if n == "HLL_INIT_AGG" {
+        return Some(CubeAggregateUDFKind::HllInitAgg);
+    }
+    if n == "THETA_INIT_AGG" {
+        return Some(CubeAggregateUDFKind::ThetaInitAgg);
+    }
+    if n == "THETA_UNION" {
+        return Some(CubeAggregateUDFKind::ThetaUnion);
+    }
+    if n == "THETA_INTERSECT" {
+        return Some(CubeAggregateUDFKind::ThetaIntersect);
+    }
     return None;
 }
 
@@ -424,11 +501,10 @@ impl HllMergeAccumulator {
             self.acc = Some(s);
             return Ok(());
         } else if let Some(acc_s) = &mut self.acc {
-            if !acc_s.is_compatible(&s) {
-                return Err(CubeError::internal(
-                    "cannot merge two incompatible HLL sketches".to_string(),
-                )
-                .into());
+            if acc_s.flavour() != s.flavour() || !acc_s.is_compatible(&s) {
+                // Lossy, see `Hll::merge_converted` for details.
+                acc_s.merge_converted(&s)?;
+                return Ok(());
             }
             acc_s.merge_with(&s)?;
         } else {
@@ -438,6 +514,791 @@ impl HllMergeAccumulator {
     }
 }
 
+/// Implements `HLL_CONVERT(hll, flavour)`, converting sketches between Airlift and ZetaSketch
+/// formats. The conversion is lossy, see `Hll::convert` for details.
+struct HllConvert {}
+impl CubeScalarUDF for HllConvert {
+    fn kind(&self) -> CubeScalarUDFKind {
+        return CubeScalarUDFKind::HllConvert;
+    }
+
+    fn name(&self) -> &str {
+        return "HLL_CONVERT";
+    }
+
+    fn descriptor(&self) -> ScalarUDF {
+        return ScalarUDF {
+            name: self.name().to_string(),
+            signature: Signature::Exact(vec![DataType::Binary, DataType::Utf8]),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::Binary))),
+            fun: Arc::new(|a| {
+                assert_eq!(a.len(), 2);
+                let flavour = match &a[1] {
+                    ColumnarValue::Scalar(f) => parse_hll_flavour(f)?,
+                    ColumnarValue::Array(_) => {
+                        return Err(DataFusionError::Execution(
+                            "HLL_CONVERT flavour must be a string literal".to_string(),
+                        ))
+                    }
+                };
+                let sketches = a[0].clone().into_array(1);
+                let sketches = sketches
+                    .as_any()
+                    .downcast_ref::<BinaryArray>()
+                    .expect("expected binary data");
+
+                let mut r = BinaryBuilder::new(sketches.len());
+                for s in sketches {
+                    match s {
+                        None => r.append_null()?,
+                        // Empty sketches stay empty, MERGE accepts them with any flavour.
+                        Some(d) if d.len() == 0 => r.append_value(d)?,
+                        Some(d) => r.append_value(&read_sketch(d)?.convert(flavour)?.write())?,
+                    }
+                }
+                return Ok(ColumnarValue::Array(Arc::new(r.finish())));
+            }),
+        };
+    }
+}
+
+/// Implements `HLL_INIT(value[, flavour])`, producing a sketch for a set of a single value.
+/// Results can be merged with `MERGE` to produce rollups over raw data.
+struct HllInit {}
+impl HllInit {
+    fn signature() -> Signature {
+        hll_init_signature()
+    }
+}
+impl CubeScalarUDF for HllInit {
+    fn kind(&self) -> CubeScalarUDFKind {
+        return CubeScalarUDFKind::HllInit;
+    }
+
+    fn name(&self) -> &str {
+        return "HLL_INIT";
+    }
+
+    fn descriptor(&self) -> ScalarUDF {
+        return ScalarUDF {
+            name: self.name().to_string(),
+            signature: Self::signature(),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::Binary))),
+            fun: Arc::new(|a| {
+                assert!(a.len() == 1 || a.len() == 2);
+                let flavour = match a.get(1) {
+                    None => HllFlavour::Airlift,
+                    Some(ColumnarValue::Scalar(f)) => parse_hll_flavour(f)?,
+                    Some(ColumnarValue::Array(_)) => {
+                        return Err(DataFusionError::Execution(
+                            "HLL_INIT flavour must be a string literal".to_string(),
+                        ))
+                    }
+                };
+                let values = a[0].clone().into_array(1);
+
+                let mut r = BinaryBuilder::new(values.len());
+                for i in 0..values.len() {
+                    let v = ScalarValue::try_from_array(&values, i)?;
+                    if v.is_null() {
+                        r.append_null()?;
+                        continue;
+                    }
+                    let mut hll = Hll::new(flavour)?;
+                    insert_hll_value(&mut hll, &v)?;
+                    r.append_value(&hll.write())?;
+                }
+                return Ok(ColumnarValue::Array(Arc::new(r.finish())));
+            }),
+        };
+    }
+}
+
+/// Implements `HLL_EXPORT_POSTGRES(hll)` and `HLL_EXPORT_SNOWFLAKE(hll)`, converting our sketches
+/// into the formats accepted by the `hll` extension of Postgres and by `HLL_IMPORT` of Snowflake.
+/// Only sketches in the Airlift format can be exported.
+struct HllExport {
+    flavour: HllFlavour,
+}
+impl CubeScalarUDF for HllExport {
+    fn kind(&self) -> CubeScalarUDFKind {
+        match self.flavour {
+            HllFlavour::Postgres => CubeScalarUDFKind::HllExportPostgres,
+            HllFlavour::Snowflake => CubeScalarUDFKind::HllExportSnowflake,
+            _ => panic!("unsupported HLL export flavour: {:?}", self.flavour),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self.flavour {
+            HllFlavour::Postgres => "HLL_EXPORT_POSTGRES",
+            HllFlavour::Snowflake => "HLL_EXPORT_SNOWFLAKE",
+            _ => panic!("unsupported HLL export flavour: {:?}", self.flavour),
+        }
+    }
+
+    fn descriptor(&self) -> ScalarUDF {
+        let flavour = self.flavour;
+        let return_type = match flavour {
+            HllFlavour::Postgres => DataType::Binary,
+            _ => DataType::Utf8,
+        };
+        return ScalarUDF {
+            name: self.name().to_string(),
+            signature: Signature::Exact(vec![DataType::Binary]),
+            return_type: Arc::new(move |_| Ok(Arc::new(return_type.clone()))),
+            fun: Arc::new(move |a| {
+                assert_eq!(a.len(), 1);
+                let sketches = a[0].clone().into_array(1);
+                let sketches = sketches
+                    .as_any()
+                    .downcast_ref::<BinaryArray>()
+                    .expect("expected binary data");
+
+                if flavour == HllFlavour::Postgres {
+                    let mut r = BinaryBuilder::new(sketches.len());
+                    for s in sketches {
+                        match s {
+                            Some(d) if d.len() != 0 => {
+                                r.append_value(&read_sketch(d)?.write_postgres()?)?
+                            }
+                            _ => r.append_null()?,
+                        }
+                    }
+                    return Ok(ColumnarValue::Array(Arc::new(r.finish())));
+                } else {
+                    let mut r = StringBuilder::new(sketches.len());
+                    for s in sketches {
+                        match s {
+                            Some(d) if d.len() != 0 => {
+                                r.append_value(&read_sketch(d)?.write_snowflake()?)?
+                            }
+                            _ => r.append_null()?,
+                        }
+                    }
+                    return Ok(ColumnarValue::Array(Arc::new(r.finish())));
+                }
+            }),
+        };
+    }
+}
+
+/// Implements `HLL_INIT_AGG(value[, flavour])`, producing a sketch for a set of all non-null
+/// values. The state is a serialized sketch, so partial results are merged as in `MERGE`.
+struct HllInitAggUDF {}
+impl CubeAggregateUDF for HllInitAggUDF {
+    fn kind(&self) -> CubeAggregateUDFKind {
+        return CubeAggregateUDFKind::HllInitAgg;
+    }
+    fn name(&self) -> &str {
+        return "HLL_INIT_AGG";
+    }
+    fn descriptor(&self) -> AggregateUDF {
+        return AggregateUDF {
+            name: self.name().to_string(),
+            signature: hll_init_signature(),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::Binary))),
+            accumulator: Arc::new(|| Ok(Box::new(HllInitAccumulator::new()))),
+            state_type: Arc::new(|_| Ok(Arc::new(vec![DataType::Binary]))),
+        };
+    }
+    fn accumulator(&self) -> Box<dyn Accumulator> {
+        return Box::new(HllInitAccumulator::new());
+    }
+}
+
+#[derive(Debug)]
+struct HllInitAccumulator {
+    merge: HllMergeAccumulator,
+}
+
+impl HllInitAccumulator {
+    fn new() -> HllInitAccumulator {
+        HllInitAccumulator {
+            merge: HllMergeAccumulator { acc: None },
+        }
+    }
+}
+
+impl Accumulator for HllInitAccumulator {
+    fn reset(&mut self) {
+        self.merge.reset();
+    }
+
+    fn state(&self) -> Result<SmallVec<[ScalarValue; 2]>, DataFusionError> {
+        return self.merge.state();
+    }
+
+    fn update(&mut self, row: &[ScalarValue]) -> Result<(), DataFusionError> {
+        assert!(row.len() == 1 || row.len() == 2);
+        if row[0].is_null() {
+            return Ok(()); // ignore NULL.
+        }
+        if self.merge.acc.is_none() {
+            let flavour = match row.get(1) {
+                None => HllFlavour::Airlift,
+                Some(f) => parse_hll_flavour(f)?,
+            };
+            self.merge.acc = Some(Hll::new(flavour)?);
+        }
+        return insert_hll_value(self.merge.acc.as_mut().unwrap(), &row[0]);
+    }
+
+    fn merge(&mut self, states: &[ScalarValue]) -> Result<(), DataFusionError> {
+        return self.merge.merge(states);
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        return self.merge.evaluate();
+    }
+}
+
+fn hll_init_signature() -> Signature {
+    let value_types = [DataType::Int64, DataType::Utf8, DataType::Binary];
+    let mut signatures = Vec::new();
+    for t in &value_types {
+        signatures.push(Signature::Exact(vec![t.clone()]));
+    }
+    for t in &value_types {
+        signatures.push(Signature::Exact(vec![t.clone(), DataType::Utf8]));
+    }
+    Signature::OneOf(signatures)
+}
+
+/// Flavours are named as the corresponding column types.
+fn parse_hll_flavour(f: &ScalarValue) -> Result<HllFlavour, DataFusionError> {
+    let name = match f {
+        ScalarValue::Utf8(Some(n)) => n.to_lowercase(),
+        _ => {
+            return Err(DataFusionError::Execution(format!(
+                "HLL flavour must be a non-null string, got {:?}",
+                f
+            )))
+        }
+    };
+    match name.as_str() {
+        "hyperloglog" | "airlift" => Ok(HllFlavour::Airlift),
+        "hyperloglogpp" | "zetasketch" => Ok(HllFlavour::ZetaSketch),
+        "hll_snowflake" => Ok(HllFlavour::Snowflake),
+        "hll_postgres" => Ok(HllFlavour::Postgres),
+        _ => Err(DataFusionError::Execution(format!(
+            "Unknown HLL flavour '{}', expected one of: hyperloglog (airlift), hyperloglogpp (zetasketch), hll_snowflake, hll_postgres",
+            name
+        ))),
+    }
+}
+
+/// Integers are hashed as 64-bit numbers, strings and binaries as their bytes.
+fn insert_hll_value(hll: &mut Hll, v: &ScalarValue) -> Result<(), DataFusionError> {
+    match v {
+        ScalarValue::Int64(Some(i)) => hll.insert_i64(*i)?,
+        ScalarValue::Utf8(Some(s)) => hll.insert_bytes(s.as_bytes())?,
+        ScalarValue::Binary(Some(b)) => hll.insert_bytes(b)?,
+        _ => {
+            return Err(CubeError::internal(format!(
+                "invalid scalar value passed to HLL_INIT: {:?}",
+                v
+            ))
+            .into())
+        }
+    }
+    return Ok(());
+}
+
+/// Implements `THETA_ESTIMATE(sketch)`, an estimate of the number of unique values in a theta
+/// sketch. Same as `CARDINALITY` for HyperLogLog.
+struct ThetaEstimate {}
+impl CubeScalarUDF for ThetaEstimate {
+    fn kind(&self) -> CubeScalarUDFKind {
+        return CubeScalarUDFKind::ThetaEstimate;
+    }
+
+    fn name(&self) -> &str {
+        return "THETA_ESTIMATE";
+    }
+
+    fn descriptor(&self) -> ScalarUDF {
+        return ScalarUDF {
+            name: self.name().to_string(),
+            signature: Signature::Exact(vec![DataType::Binary]),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::UInt64))),
+            fun: Arc::new(|a| {
+                assert_eq!(a.len(), 1);
+                let sketches = a[0].clone().into_array(1);
+                let sketches = sketches
+                    .as_any()
+                    .downcast_ref::<BinaryArray>()
+                    .expect("expected binary data");
+
+                let mut r = UInt64Builder::new(sketches.len());
+                for s in sketches {
+                    match s {
+                        None => r.append_null()?,
+                        Some(d) => r.append_value(read_theta_sketch(d)?.cardinality())?,
+                    }
+                }
+                return Ok(ColumnarValue::Array(Arc::new(r.finish())));
+            }),
+        };
+    }
+}
+
+/// Implements `THETA_A_NOT_B(a, b)`, producing a sketch of the values present in `a`, but not in
+/// `b`. E.g. `THETA_ESTIMATE(THETA_A_NOT_B(THETA_UNION(a), THETA_UNION(b)))` counts users that
+/// were seen in the first period, but did not come back in the second one.
+struct ThetaANotB {}
+impl CubeScalarUDF for ThetaANotB {
+    fn kind(&self) -> CubeScalarUDFKind {
+        return CubeScalarUDFKind::ThetaANotB;
+    }
+
+    fn name(&self) -> &str {
+        return "THETA_A_NOT_B";
+    }
+
+    fn descriptor(&self) -> ScalarUDF {
+        return ScalarUDF {
+            name: self.name().to_string(),
+            signature: Signature::Exact(vec![DataType::Binary, DataType::Binary]),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::Binary))),
+            fun: Arc::new(|a| {
+                assert_eq!(a.len(), 2);
+                let num_rows = a
+                    .iter()
+                    .map(|v| match v {
+                        ColumnarValue::Array(a) => a.len(),
+                        ColumnarValue::Scalar(_) => 1,
+                    })
+                    .max()
+                    .unwrap();
+                let a_sketches = a[0].clone().into_array(num_rows);
+                let a_sketches = a_sketches
+                    .as_any()
+                    .downcast_ref::<BinaryArray>()
+                    .expect("expected binary data");
+                let b_sketches = a[1].clone().into_array(num_rows);
+                let b_sketches = b_sketches
+                    .as_any()
+                    .downcast_ref::<BinaryArray>()
+                    .expect("expected binary data");
+
+                let mut r = BinaryBuilder::new(num_rows);
+                for (a, b) in a_sketches.into_iter().zip(b_sketches) {
+                    match (a, b) {
+                        (Some(a), Some(b)) => {
+                            let mut s = read_theta_sketch(a)?;
+                            s.subtract(&read_theta_sketch(b)?);
+                            r.append_value(&s.write())?
+                        }
+                        _ => r.append_null()?,
+                    }
+                }
+                return Ok(ColumnarValue::Array(Arc::new(r.finish())));
+            }),
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ThetaSetOp {
+    Union,
+    Intersect,
+}
+
+/// Implements `THETA_UNION(sketch)` and `THETA_INTERSECT(sketch)`. `MERGE` on theta sketch
+/// columns is the same as `THETA_UNION`.
+struct ThetaSetOpUDF {
+    op: ThetaSetOp,
+}
+impl CubeAggregateUDF for ThetaSetOpUDF {
+    fn kind(&self) -> CubeAggregateUDFKind {
+        match self.op {
+            ThetaSetOp::Union => CubeAggregateUDFKind::ThetaUnion,
+            ThetaSetOp::Intersect => CubeAggregateUDFKind::ThetaIntersect,
+        }
+    }
+    fn name(&self) -> &str {
+        match self.op {
+            ThetaSetOp::Union => "THETA_UNION",
+            ThetaSetOp::Intersect => "THETA_INTERSECT",
+        }
+    }
+    fn descriptor(&self) -> AggregateUDF {
+        let op = self.op;
+        return AggregateUDF {
+            name: self.name().to_string(),
+            signature: Signature::Exact(vec![DataType::Binary]),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::Binary))),
+            accumulator: Arc::new(move || Ok(Box::new(ThetaSetOpAccumulator { op, acc: None }))),
+            state_type: Arc::new(|_| Ok(Arc::new(vec![DataType::Binary]))),
+        };
+    }
+    fn accumulator(&self) -> Box<dyn Accumulator> {
+        return Box::new(ThetaSetOpAccumulator {
+            op: self.op,
+            acc: None,
+        });
+    }
+}
+
+#[derive(Debug)]
+struct ThetaSetOpAccumulator {
+    op: ThetaSetOp,
+    acc: Option<ThetaSketch>,
+}
+
+impl Accumulator for ThetaSetOpAccumulator {
+    fn reset(&mut self) {
+        self.acc = None;
+    }
+
+    fn state(&self) -> Result<SmallVec<[ScalarValue; 2]>, DataFusionError> {
+        return Ok(smallvec![self.evaluate()?]);
+    }
+
+    fn update(&mut self, row: &[ScalarValue]) -> Result<(), DataFusionError> {
+        // Inputs and states are both serialized sketches.
+        return self.merge(row);
+    }
+
+    fn merge(&mut self, states: &[ScalarValue]) -> Result<(), DataFusionError> {
+        assert_eq!(states.len(), 1);
+        let data = match &states[0] {
+            ScalarValue::Binary(Some(d)) => d,
+            ScalarValue::Binary(None) => return Ok(()), // ignore NULL.
+            v => {
+                return Err(CubeError::internal(format!(
+                    "invalid scalar value passed to theta sketch aggregate: {:?}",
+                    v
+                ))
+                .into())
+            }
+        };
+        let s = read_theta_sketch(data)?;
+        match &mut self.acc {
+            None => self.acc = Some(s),
+            Some(acc) => match self.op {
+                ThetaSetOp::Union => acc.merge_with(&s),
+                ThetaSetOp::Intersect => acc.intersect_with(&s),
+            },
+        }
+        return Ok(());
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        return Ok(ScalarValue::Binary(match (&self.acc, self.op) {
+            (Some(s), _) => Some(s.write()),
+            (None, ThetaSetOp::Union) => Some(ThetaSketch::new().write()),
+            // Intersection of no sets is not defined, NULL also makes sure partial results
+            // without inputs do not affect the final one.
+            (None, ThetaSetOp::Intersect) => None,
+        }));
+    }
+}
+
+/// Implements `THETA_INIT_AGG(value)`, producing a theta sketch for a set of all non-null values.
+/// Integers are hashed as 64-bit numbers, strings and binaries as their bytes, the same way
+/// DataSketches does it.
+struct ThetaInitAggUDF {}
+impl CubeAggregateUDF for ThetaInitAggUDF {
+    fn kind(&self) -> CubeAggregateUDFKind {
+        return CubeAggregateUDFKind::ThetaInitAgg;
+    }
+    fn name(&self) -> &str {
+        return "THETA_INIT_AGG";
+    }
+    fn descriptor(&self) -> AggregateUDF {
+        return AggregateUDF {
+            name: self.name().to_string(),
+            signature: Signature::OneOf(vec![
+                Signature::Exact(vec![DataType::Int64]),
+                Signature::Exact(vec![DataType::Utf8]),
+                Signature::Exact(vec![DataType::Binary]),
+            ]),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::Binary))),
+            accumulator: Arc::new(|| Ok(Box::new(ThetaInitAccumulator::new()))),
+            state_type: Arc::new(|_| Ok(Arc::new(vec![DataType::Binary]))),
+        };
+    }
+    fn accumulator(&self) -> Box<dyn Accumulator> {
+        return Box::new(ThetaInitAccumulator::new());
+    }
+}
+
+#[derive(Debug)]
+struct ThetaInitAccumulator {
+    union: ThetaSetOpAccumulator,
+}
+
+impl ThetaInitAccumulator {
+    fn new() -> ThetaInitAccumulator {
+        ThetaInitAccumulator {
+            union: ThetaSetOpAccumulator {
+                op: ThetaSetOp::Union,
+                acc: None,
+            },
+        }
+    }
+}
+
+impl Accumulator for ThetaInitAccumulator {
+    fn reset(&mut self) {
+        self.union.reset();
+    }
+
+    fn state(&self) -> Result<SmallVec<[ScalarValue; 2]>, DataFusionError> {
+        return self.union.state();
+    }
+
+    fn update(&mut self, row: &[ScalarValue]) -> Result<(), DataFusionError> {
+        assert_eq!(row.len(), 1);
+        let s = self.union.acc.get_or_insert_with(ThetaSketch::new);
+        match &row[0] {
+            ScalarValue::Int64(Some(i)) => s.update_i64(*i),
+            ScalarValue::Utf8(Some(v)) => s.update(v.as_bytes()),
+            ScalarValue::Binary(Some(v)) => s.update(v),
+            v if v.is_null() => {} // ignore NULL.
+            v => {
+                return Err(CubeError::internal(format!(
+                    "invalid scalar value passed to THETA_INIT_AGG: {:?}",
+                    v
+                ))
+                .into())
+            }
+        }
+        return Ok(());
+    }
+
+    fn merge(&mut self, states: &[ScalarValue]) -> Result<(), DataFusionError> {
+        return self.union.merge(states);
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        return self.union.evaluate();
+    }
+}
+
+/// Implements `ANY_VALUE`, keeps a single non-null value.
+struct AnyValueUDF {}
+impl CubeAggregateUDF for AnyValueUDF {
+    fn kind(&self) -> CubeAggregateUDFKind {
+        return CubeAggregateUDFKind::AnyValue;
+    }
+    fn name(&self) -> &str {
+        return "ANY_VALUE";
+    }
+    fn descriptor(&self) -> AggregateUDF {
+        return AggregateUDF {
+            name: self.name().to_string(),
+            signature: Signature::Any(1),
+            return_type: Arc::new(|inputs| Ok(Arc::new(inputs[0].clone()))),
+            accumulator: Arc::new(|| Ok(Box::new(AnyValueAccumulator { acc: None }))),
+            state_type: Arc::new(|inputs| Ok(Arc::new(vec![inputs[0].clone()]))),
+        };
+    }
+    fn accumulator(&self) -> Box<dyn Accumulator> {
+        return Box::new(AnyValueAccumulator { acc: None });
+    }
+}
+
+#[derive(Debug)]
+struct AnyValueAccumulator {
+    // Holds the first value seen, even if it is NULL, so we can produce typed NULLs.
+    acc: Option<ScalarValue>,
+}
+
+impl Accumulator for AnyValueAccumulator {
+    fn reset(&mut self) {
+        self.acc = None;
+    }
+
+    fn state(&self) -> Result<SmallVec<[ScalarValue; 2]>, DataFusionError> {
+        return Ok(smallvec![self.evaluate()?]);
+    }
+
+    fn update(&mut self, row: &[ScalarValue]) -> Result<(), DataFusionError> {
+        assert_eq!(row.len(), 1);
+        let v = &row[0];
+        let replace = match &self.acc {
+            None => true,
+            Some(acc) => !v.is_null() && acc.is_null(),
+        };
+        if replace {
+            self.acc = Some(v.clone());
+        }
+        return Ok(());
+    }
+
+    fn merge(&mut self, states: &[ScalarValue]) -> Result<(), DataFusionError> {
+        return self.update(states);
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        match &self.acc {
+            Some(v) => Ok(v.clone()),
+            None => Err(DataFusionError::Execution(
+                "ANY_VALUE has no input values".to_string(),
+            )),
+        }
+    }
+}
+
+/// Implements `LAST_VALUE(value, seq)`, the value of the row with the largest `seq`. The seq is
+/// kept in the state, so the result does not depend on the order chunks, partitions and workers
+/// are merged in. The planner passes the `__seq` column of the table as `seq`.
+/// Without `seq` it takes the last non-null value in the processing order.
+struct LastValueUDF {}
+impl CubeAggregateUDF for LastValueUDF {
+    fn kind(&self) -> CubeAggregateUDFKind {
+        return CubeAggregateUDFKind::LastValue;
+    }
+    fn name(&self) -> &str {
+        return "LAST_VALUE";
+    }
+    fn descriptor(&self) -> AggregateUDF {
+        return AggregateUDF {
+            name: self.name().to_string(),
+            signature: Signature::OneOf(vec![Signature::Any(1), Signature::Any(2)]),
+            return_type: Arc::new(|inputs| Ok(Arc::new(inputs[0].clone()))),
+            accumulator: Arc::new(|| Ok(Box::new(LastValueAccumulator { acc: None }))),
+            state_type: Arc::new(|inputs| Ok(Arc::new(vec![inputs[0].clone(), DataType::Int64]))),
+        };
+    }
+    fn accumulator(&self) -> Box<dyn Accumulator> {
+        return Box::new(LastValueAccumulator { acc: None });
+    }
+}
+
+#[derive(Debug)]
+struct LastValueAccumulator {
+    // Holds the first value seen, even if it is NULL, so we can produce typed NULLs.
+    acc: Option<(ScalarValue, Option<i64>)>,
+}
+
+impl Accumulator for LastValueAccumulator {
+    fn reset(&mut self) {
+        self.acc = None;
+    }
+
+    fn state(&self) -> Result<SmallVec<[ScalarValue; 2]>, DataFusionError> {
+        match &self.acc {
+            Some((v, seq)) => Ok(smallvec![v.clone(), ScalarValue::Int64(*seq)]),
+            None => Err(DataFusionError::Execution(
+                "LAST_VALUE has no input values".to_string(),
+            )),
+        }
+    }
+
+    fn update(&mut self, row: &[ScalarValue]) -> Result<(), DataFusionError> {
+        let seq = match row {
+            [_] => None,
+            [_, ScalarValue::Int64(seq)] => *seq,
+            _ => {
+                return Err(CubeError::internal(format!(
+                "invalid scalar values passed to LAST_VALUE, expecting value and Int64 seq: {:?}",
+                row
+            ))
+                .into())
+            }
+        };
+        let v = &row[0];
+        let replace = match &self.acc {
+            None => true,
+            Some((acc, acc_seq)) => match (seq, *acc_seq) {
+                (Some(seq), Some(acc_seq)) => seq > acc_seq || seq == acc_seq && acc.is_null(),
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => !v.is_null(),
+            },
+        };
+        if replace {
+            self.acc = Some((v.clone(), seq));
+        }
+        return Ok(());
+    }
+
+    fn merge(&mut self, states: &[ScalarValue]) -> Result<(), DataFusionError> {
+        return self.update(states);
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        match &self.acc {
+            Some((v, _)) => Ok(v.clone()),
+            None => Err(DataFusionError::Execution(
+                "LAST_VALUE has no input values".to_string(),
+            )),
+        }
+    }
+}
+
+struct BitOrUDF {}
+impl CubeAggregateUDF for BitOrUDF {
+    fn kind(&self) -> CubeAggregateUDFKind {
+        return CubeAggregateUDFKind::BitOr;
+    }
+    fn name(&self) -> &str {
+        return "BIT_OR";
+    }
+    fn descriptor(&self) -> AggregateUDF {
+        return AggregateUDF {
+            name: self.name().to_string(),
+            signature: Signature::Exact(vec![DataType::Int64]),
+            return_type: Arc::new(|_| Ok(Arc::new(DataType::Int64))),
+            accumulator: Arc::new(|| Ok(Box::new(BitOrAccumulator { acc: None }))),
+            state_type: Arc::new(|_| Ok(Arc::new(vec![DataType::Int64]))),
+        };
+    }
+    fn accumulator(&self) -> Box<dyn Accumulator> {
+        return Box::new(BitOrAccumulator { acc: None });
+    }
+}
+
+#[derive(Debug)]
+struct BitOrAccumulator {
+    acc: Option<i64>,
+}
+
+impl Accumulator for BitOrAccumulator {
+    fn reset(&mut self) {
+        self.acc = None;
+    }
+
+    fn state(&self) -> Result<SmallVec<[ScalarValue; 2]>, DataFusionError> {
+        return Ok(smallvec![self.evaluate()?]);
+    }
+
+    fn update(&mut self, row: &[ScalarValue]) -> Result<(), DataFusionError> {
+        assert_eq!(row.len(), 1);
+        match &row[0] {
+            ScalarValue::Int64(None) => {} // ignore NULL.
+            ScalarValue::Int64(Some(v)) => self.acc = Some(self.acc.unwrap_or(0) | *v),
+            _ => {
+                return Err(CubeError::internal(
+                    "invalid scalar value passed to BIT_OR, expecting Int64".to_string(),
+                )
+                .into())
+            }
+        }
+        return Ok(());
+    }
+
+    fn merge(&mut self, states: &[ScalarValue]) -> Result<(), DataFusionError> {
+        return self.update(states);
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        return Ok(ScalarValue::Int64(self.acc));
+    }
+}
+
 pub fn read_sketch(data: &[u8]) -> Result<Hll, DataFusionError> {
     return Hll::read(&data).map_err(|e| DataFusionError::Execution(e.message));
 }
+
+/// Empty data is read as a sketch of an empty set, same as for HLL.
+fn read_theta_sketch(data: &[u8]) -> Result<ThetaSketch, DataFusionError> {
+    if data.is_empty() {
+        return Ok(ThetaSketch::new());
+    }
+    return ThetaSketch::read(data).map_err(|e| DataFusionError::Execution(e.message));
+}
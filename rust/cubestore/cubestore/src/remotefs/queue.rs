@@ -1,3 +1,4 @@
+use crate::app_metrics;
 use crate::config::ConfigObj;
 use crate::di_service;
 use crate::remotefs::{RemoteFile, RemoteFs};
@@ -16,7 +17,7 @@ use std::fs::Metadata;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::{broadcast, watch, RwLock};
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 
 pub struct QueueRemoteFs {
     config: Arc<dyn ConfigObj>,
@@ -154,10 +155,21 @@ impl QueueRemoteFs {
                     .await?
                     .contains(remote_path.as_str())
                 {
+                    let start = Instant::now();
                     let res = self
                         .remote_fs
                         .upload_file(&temp_upload_path, &remote_path)
                         .await;
+                    match &res {
+                        Ok(size) => {
+                            app_metrics::REMOTE_FS_UPLOADS.increment();
+                            app_metrics::REMOTE_FS_UPLOAD_BYTES.add(*size as i64);
+                            app_metrics::REMOTE_FS_UPLOAD_TIME_MS
+                                .report(start.elapsed().as_millis() as i64);
+                        }
+                        Err(_) => app_metrics::REMOTE_FS_ERRORS
+                            .add_with_tags(1, &[("operation", "upload")]),
+                    }
                     self.result_sender
                         .send(RemoteFsOpResult::Upload(remote_path, res))?;
                 }
@@ -176,10 +188,24 @@ impl QueueRemoteFs {
     async fn download_loop(&self, to_process: RemoteFsOp) -> Result<(), CubeError> {
         match to_process {
             RemoteFsOp::Download(file, expected_file_size) => {
+                let start = Instant::now();
                 let result = self
                     .remote_fs
                     .download_file(file.as_str(), expected_file_size)
                     .await;
+                match &result {
+                    Ok(local_path) => {
+                        app_metrics::REMOTE_FS_DOWNLOADS.increment();
+                        app_metrics::REMOTE_FS_DOWNLOAD_TIME_MS
+                            .report(start.elapsed().as_millis() as i64);
+                        if let Ok(m) = tokio::fs::metadata(local_path).await {
+                            app_metrics::REMOTE_FS_DOWNLOAD_BYTES.add(m.len() as i64);
+                        }
+                    }
+                    Err(_) => {
+                        app_metrics::REMOTE_FS_ERRORS.add_with_tags(1, &[("operation", "download")])
+                    }
+                }
                 let mut downloading =
                     acquire_lock("download loop downloading", self.downloading.write()).await?;
                 self.result_sender
@@ -256,6 +256,16 @@ impl SchedulerImpl {
             error!("Error deleting middle man partitions: {}", e);
         }
 
+        if let Err(e) = warn_long_fut(
+            "Drop expired time buckets",
+            Duration::from_millis(5000),
+            self.drop_expired_time_buckets(),
+        )
+        .await
+        {
+            error!("Error dropping expired time buckets: {}", e);
+        }
+
         if let Err(e) = warn_long_fut(
             "Merge replay handles",
             Duration::from_millis(5000),
@@ -296,6 +306,45 @@ impl SchedulerImpl {
         Ok(())
     }
 
+    /// Applies retention of time partitioned tables. Deactivated chunks are removed along with
+    /// other inactive chunks, partitions once they have no chunks left.
+    async fn drop_expired_time_buckets(&self) -> Result<(), CubeError> {
+        let now = Utc::now();
+        for table in self.meta_store.get_tables().await? {
+            let retained_since = match table
+                .get_row()
+                .time_partition()
+                .as_ref()
+                .and_then(|p| p.retained_since(now))
+            {
+                Some(b) => b,
+                None => continue,
+            };
+            let expired = self
+                .meta_store
+                .deactivate_time_buckets_before(table.get_id(), retained_since)
+                .await?;
+            if !expired.is_empty() {
+                log::debug!(
+                    "Removing {} partitions of expired time buckets in table {}",
+                    expired.len(),
+                    table.get_row().get_table_name()
+                );
+            }
+            for partition in expired {
+                let deadline =
+                    Instant::now() + Duration::from_secs(self.config.import_job_timeout());
+                self.gc_loop
+                    .send(GCTimedTask {
+                        deadline,
+                        task: GCTask::DeletePartition(partition.get_id()),
+                    })
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn delete_middle_man_partitions(&self) -> Result<(), CubeError> {
         let all_inactive_partitions = self.meta_store.all_inactive_middle_man_partitions().await?;
 
@@ -874,7 +923,8 @@ impl SchedulerImpl {
         &self,
         multi_partition_id: u64,
     ) -> Result<(), CubeError> {
-        let node = pick_worker_by_ids(self.config.as_ref(), [multi_partition_id]).to_string();
+        let node = pick_worker_by_ids(self.cluster.worker_set().as_ref(), [multi_partition_id])
+            .to_string();
         let job = self
             .meta_store
             .add_job(Job::new(
@@ -902,7 +952,8 @@ impl SchedulerImpl {
         {
             return Ok(());
         }
-        let node = pick_worker_by_ids(self.config.as_ref(), [multi_partition_id]).to_string();
+        let node = pick_worker_by_ids(self.cluster.worker_set().as_ref(), [multi_partition_id])
+            .to_string();
         let job = self
             .meta_store
             .add_job(Job::new(
@@ -0,0 +1,336 @@
+use crate::app_metrics;
+use crate::CubeError;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+pub const DEFAULT_WORKLOAD_CLASS: &str = "default";
+
+/// Limits for queries of one workload class, parsed from `name:max_concurrency:max_queue:priority`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkloadClassConfig {
+    pub name: String,
+    /// Zero means no limit.
+    pub max_concurrency: usize,
+    /// Queries are rejected when this many queries of the class are already waiting.
+    pub max_queue: usize,
+    /// Waiting queries of classes with higher priority are started first when the router runs
+    /// [ConfigObj::max_concurrent_queries](crate::config::ConfigObj::max_concurrent_queries).
+    pub priority: i64,
+}
+
+impl FromStr for WorkloadClassConfig {
+    type Err = CubeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.trim().split(':').collect::<Vec<_>>();
+        if parts.len() != 4 || parts[0].is_empty() {
+            return Err(CubeError::user(format!(
+                "Expected 'name:max_concurrency:max_queue:priority' for workload class, got '{}'",
+                s
+            )));
+        }
+        let number = |i: usize| -> Result<i64, CubeError> {
+            parts[i]
+                .parse::<i64>()
+                .map_err(|e| CubeError::user(format!("Invalid workload class '{}': {}", s, e)))
+        };
+        let unsigned = |i: usize| -> Result<usize, CubeError> {
+            let v = number(i)?;
+            if v < 0 {
+                return Err(CubeError::user(format!(
+                    "Invalid workload class '{}': limits must not be negative",
+                    s
+                )));
+            }
+            Ok(v as usize)
+        };
+        Ok(WorkloadClassConfig {
+            name: parts[0].to_lowercase(),
+            max_concurrency: unsigned(1)?,
+            max_queue: unsigned(2)?,
+            priority: number(3)?,
+        })
+    }
+}
+
+/// Selects the workload class of a query. `SET workload_class` of the MySQL session takes
+/// precedence over the `workloadClass` field of the trace object sent over HTTP.
+pub fn query_workload_class(
+    workload_class: &Option<String>,
+    trace_obj: &Option<String>,
+) -> Option<String> {
+    if let Some(c) = workload_class {
+        return Some(c.to_lowercase());
+    }
+    let trace_obj: serde_json::Value = serde_json::from_str(trace_obj.as_ref()?).ok()?;
+    Some(trace_obj.get("workloadClass")?.as_str()?.to_lowercase())
+}
+
+/// Queues data queries on the router before they are sent to workers.
+pub struct AdmissionControl {
+    limits: Arc<ClassLimits>,
+    state: Arc<Mutex<AdmissionState>>,
+}
+
+struct AdmissionState {
+    running: usize,
+    running_by_class: HashMap<String, usize>,
+    queues: HashMap<String, VecDeque<oneshot::Sender<AdmissionPermit>>>,
+}
+
+/// Releases the slot of the query when dropped.
+pub struct AdmissionPermit {
+    class: String,
+    /// [None] if no slot was taken.
+    control: Option<(Arc<Mutex<AdmissionState>>, Arc<ClassLimits>)>,
+}
+
+struct ClassLimits {
+    classes: HashMap<String, WorkloadClassConfig>,
+    /// Zero means no limit.
+    max_concurrency: usize,
+}
+
+impl AdmissionControl {
+    pub fn new(classes: &[WorkloadClassConfig], max_concurrency: usize) -> AdmissionControl {
+        let mut classes = classes
+            .iter()
+            .map(|c| (c.name.clone(), c.clone()))
+            .collect::<HashMap<_, _>>();
+        classes
+            .entry(DEFAULT_WORKLOAD_CLASS.to_string())
+            .or_insert_with(|| WorkloadClassConfig {
+                name: DEFAULT_WORKLOAD_CLASS.to_string(),
+                max_concurrency: 0,
+                max_queue: usize::MAX,
+                priority: 0,
+            });
+        AdmissionControl {
+            limits: Arc::new(ClassLimits {
+                classes,
+                max_concurrency,
+            }),
+            state: Arc::new(Mutex::new(AdmissionState {
+                running: 0,
+                running_by_class: HashMap::new(),
+                queues: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn check_class(&self, class: &str) -> Result<(), CubeError> {
+        if self.limits.classes.contains_key(&class.to_lowercase()) {
+            Ok(())
+        } else {
+            Err(CubeError::user(format!(
+                "Unknown workload class '{}', available: {}",
+                class,
+                self.class_names().join(", ")
+            )))
+        }
+    }
+
+    fn class_names(&self) -> Vec<String> {
+        let mut names = self.limits.classes.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Waits until the query of [class] can run. Fails right away when the queue of the class is
+    /// full.
+    pub async fn acquire(&self, class: Option<&str>) -> Result<AdmissionPermit, CubeError> {
+        let class = class.unwrap_or(DEFAULT_WORKLOAD_CLASS).to_lowercase();
+        self.check_class(&class)?;
+        let start = Instant::now();
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            let queue = state.queues.entry(class.clone()).or_default();
+            queue.retain(|s| !s.is_closed());
+            let queue_empty = queue.is_empty();
+            if queue_empty && state.can_run(&self.limits, &class) {
+                state.start(&class);
+                return Ok(AdmissionPermit {
+                    class,
+                    control: Some((self.state.clone(), self.limits.clone())),
+                });
+            }
+            let config = &self.limits.classes[&class];
+            let queue = state.queues.get_mut(&class).unwrap();
+            if config.max_queue <= queue.len() {
+                app_metrics::QUERIES_REJECTED.add_with_tags(1, &[("class", &class)]);
+                return Err(CubeError::user(format!(
+                    "Too many queries in the '{}' workload class queue, try again later",
+                    class
+                )));
+            }
+            let (sender, receiver) = oneshot::channel();
+            queue.push_back(sender);
+            receiver
+        };
+        let permit = receiver
+            .await
+            .map_err(|_| CubeError::internal("Admission queue was dropped".to_string()))?;
+        app_metrics::QUERY_QUEUE_TIME_MS
+            .report_with_tags(start.elapsed().as_millis() as i64, &[("class", &class)]);
+        Ok(permit)
+    }
+}
+
+impl AdmissionState {
+    fn can_run(&self, limits: &ClassLimits, class: &str) -> bool {
+        if limits.max_concurrency != 0 && limits.max_concurrency <= self.running {
+            return false;
+        }
+        let max = limits.classes[class].max_concurrency;
+        max == 0 || self.running_by_class.get(class).cloned().unwrap_or(0) < max
+    }
+
+    fn start(&mut self, class: &str) {
+        self.running += 1;
+        *self.running_by_class.entry(class.to_string()).or_default() += 1;
+    }
+
+    fn finish(&mut self, class: &str) {
+        self.running -= 1;
+        *self.running_by_class.get_mut(class).unwrap() -= 1;
+    }
+
+    /// Starts waiting queries while there are free slots, higher priority classes first.
+    fn dispatch(&mut self, state: &Arc<Mutex<AdmissionState>>, limits: &Arc<ClassLimits>) {
+        loop {
+            let next = self
+                .queues
+                .iter()
+                .filter(|(class, queue)| !queue.is_empty() && self.can_run(limits, class))
+                .max_by_key(|(class, _)| limits.classes[*class].priority)
+                .map(|(class, _)| class.clone());
+            let class = match next {
+                Some(c) => c,
+                None => return,
+            };
+            let sender = self.queues.get_mut(&class).unwrap().pop_front().unwrap();
+            self.start(&class);
+            let permit = AdmissionPermit {
+                class: class.clone(),
+                control: Some((state.clone(), limits.clone())),
+            };
+            if let Err(mut permit) = sender.send(permit) {
+                // The query was cancelled while waiting, e.g. on timeout.
+                permit.control = None;
+                self.finish(&class);
+            }
+        }
+    }
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        if let Some((state, limits)) = self.control.take() {
+            let mut s = state.lock().unwrap();
+            s.finish(&self.class);
+            s.dispatch(&state, &limits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn class(
+        name: &str,
+        max_concurrency: usize,
+        max_queue: usize,
+        priority: i64,
+    ) -> WorkloadClassConfig {
+        WorkloadClassConfig {
+            name: name.to_string(),
+            max_concurrency,
+            max_queue,
+            priority,
+        }
+    }
+
+    #[test]
+    fn parse_class() {
+        assert_eq!(
+            "Refresh:2:10:-1".parse::<WorkloadClassConfig>().unwrap(),
+            class("refresh", 2, 10, -1)
+        );
+        assert!("refresh:2:10".parse::<WorkloadClassConfig>().is_err());
+        assert!("refresh:-2:10:1".parse::<WorkloadClassConfig>().is_err());
+        assert!(":2:10:1".parse::<WorkloadClassConfig>().is_err());
+    }
+
+    #[test]
+    fn select_class() {
+        let trace = Some(r#"{"workloadClass": "Refresh"}"#.to_string());
+        assert_eq!(
+            query_workload_class(&None, &trace),
+            Some("refresh".to_string())
+        );
+        assert_eq!(
+            query_workload_class(&Some("interactive".to_string()), &trace),
+            Some("interactive".to_string())
+        );
+        assert_eq!(query_workload_class(&None, &Some("{}".to_string())), None);
+        assert_eq!(
+            query_workload_class(&None, &Some("trace".to_string())),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn class_limits() {
+        let control = AdmissionControl::new(&[class("refresh", 1, 1, 0)], 0);
+        assert!(control.acquire(Some("unknown")).await.is_err());
+
+        let p1 = control.acquire(Some("refresh")).await.unwrap();
+        let mut waiting = Box::pin(control.acquire(Some("REFRESH")));
+        assert!((&mut waiting).now_or_never().is_none());
+        let err = control.acquire(Some("refresh")).await.err().unwrap();
+        assert!(err.message.contains("Too many queries"), "{}", err);
+        // Other classes are not affected.
+        let _d = control.acquire(None).await.unwrap();
+
+        drop(p1);
+        let p2 = waiting.await.unwrap();
+        drop(p2);
+        let _p3 = control.acquire(Some("refresh")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn priorities() {
+        let control = AdmissionControl::new(
+            &[class("refresh", 0, 10, 0), class("interactive", 0, 10, 10)],
+            1,
+        );
+        let p = control.acquire(Some("refresh")).await.unwrap();
+        let mut refresh = Box::pin(control.acquire(Some("refresh")));
+        assert!((&mut refresh).now_or_never().is_none());
+        let mut interactive = Box::pin(control.acquire(Some("interactive")));
+        assert!((&mut interactive).now_or_never().is_none());
+
+        drop(p);
+        assert!((&mut refresh).now_or_never().is_none());
+        let p = (&mut interactive).now_or_never().unwrap().unwrap();
+        drop(p);
+        let _p = (&mut refresh).now_or_never().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters() {
+        let control = AdmissionControl::new(&[], 1);
+        let p = control.acquire(None).await.unwrap();
+        let mut cancelled = Box::pin(control.acquire(None));
+        assert!((&mut cancelled).now_or_never().is_none());
+        drop(cancelled);
+        drop(p);
+        // The slot is not leaked to the cancelled query.
+        let _p = control.acquire(None).now_or_never().unwrap().unwrap();
+    }
+}
@@ -1,15 +1,20 @@
+use crate::cachestore::{CacheStore, ResultCacheItem};
 use crate::queryplanner::serialized_plan::SerializedPlan;
 use crate::sql::InlineTables;
 use crate::sql::SqlQueryContext;
 use crate::store::DataFrame;
 use crate::CubeError;
+use datafusion::cube_ext;
 use futures::Future;
-use log::trace;
+use log::{error, trace};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use tokio::sync::{watch, Mutex};
 
-#[derive(Clone, Hash, Eq, PartialEq, Debug)]
+#[derive(Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct SqlResultCacheKey {
     query: String,
     inline_tables: InlineTables,
@@ -57,11 +62,78 @@ impl SqlQueueCacheKey {
     }
 }
 
+/// Second level result cache stored in the cache store and shared by all router nodes.
+/// Entries are addressed by [SqlResultCacheKey], so any change of partitions or chunks referenced
+/// by a query produces a different key. Stale entries are never read and expire after `ttl_secs`.
+/// Results are kept apart from the user cache entries, see [ResultCacheItem].
+pub struct PersistentResultCache {
+    cache_store: Arc<dyn CacheStore>,
+    ttl_secs: u32,
+    max_entry_bytes: usize,
+}
+
+impl PersistentResultCache {
+    pub fn new(cache_store: Arc<dyn CacheStore>, ttl_secs: u32, max_entry_bytes: usize) -> Self {
+        Self {
+            cache_store,
+            ttl_secs,
+            max_entry_bytes,
+        }
+    }
+
+    fn path(key: &SqlResultCacheKey) -> String {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn is_cacheable(key: &SqlResultCacheKey) -> bool {
+        // Inline tables are sent with every request and are usually unique to it.
+        key.inline_tables.is_empty()
+    }
+
+    async fn get(&self, key: &SqlResultCacheKey) -> Result<Option<DataFrame>, CubeError> {
+        if !Self::is_cacheable(key) {
+            return Ok(None);
+        }
+        let item = match self.cache_store.result_cache_get(Self::path(key)).await? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let bytes = base64::decode(item.get_row().get_value())?;
+        let (stored_key, result) = bincode::deserialize::<(SqlResultCacheKey, DataFrame)>(&bytes)?;
+        // Hash collisions are possible, the full key is stored to detect them.
+        if &stored_key != key {
+            return Ok(None);
+        }
+        Ok(Some(result))
+    }
+
+    async fn put(&self, key: &SqlResultCacheKey, result: &DataFrame) -> Result<(), CubeError> {
+        if !Self::is_cacheable(key) {
+            return Ok(());
+        }
+        let bytes = bincode::serialize(&(key, result))?;
+        if bytes.len() > self.max_entry_bytes {
+            trace!(
+                "Result of '{}' is too large for persistent cache: {} bytes",
+                key.query,
+                bytes.len()
+            );
+            return Ok(());
+        }
+        let item = ResultCacheItem::new(Self::path(key), self.ttl_secs, base64::encode(bytes));
+        self.cache_store.result_cache_set(item).await?;
+        Ok(())
+    }
+}
+
 pub struct SqlResultCache {
     queue_cache: Mutex<
         lru::LruCache<SqlQueueCacheKey, watch::Receiver<Option<Result<Arc<DataFrame>, CubeError>>>>,
     >,
     result_cache: Mutex<lru::LruCache<SqlResultCacheKey, Arc<DataFrame>>>,
+    persistent_cache: Option<Arc<PersistentResultCache>>,
 }
 
 impl SqlResultCache {
@@ -69,6 +141,44 @@ impl SqlResultCache {
         Self {
             queue_cache: Mutex::new(lru::LruCache::new(capacity)),
             result_cache: Mutex::new(lru::LruCache::new(capacity)),
+            persistent_cache: None,
+        }
+    }
+
+    pub fn with_persistent_cache(self, persistent_cache: PersistentResultCache) -> Self {
+        Self {
+            persistent_cache: Some(Arc::new(persistent_cache)),
+            ..self
+        }
+    }
+
+    async fn get_persisted(&self, key: &SqlResultCacheKey) -> Option<Arc<DataFrame>> {
+        let persistent_cache = self.persistent_cache.as_ref()?;
+        match persistent_cache.get(key).await {
+            Ok(Some(result)) => {
+                let result = Arc::new(result);
+                let mut result_cache = self.result_cache.lock().await;
+                result_cache.put(key.clone(), result.clone());
+                Some(result)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("Error reading persistent result cache: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Writes the result in the background, the query does not wait for it.
+    fn persist(&self, key: &SqlResultCacheKey, result: &Arc<DataFrame>) {
+        if let Some(persistent_cache) = self.persistent_cache.clone() {
+            let key = key.clone();
+            let result = result.clone();
+            cube_ext::spawn(async move {
+                if let Err(e) = persistent_cache.put(&key, &result).await {
+                    error!("Error writing persistent result cache: {}", e);
+                }
+            });
         }
     }
 
@@ -109,7 +219,19 @@ impl SqlResultCache {
 
         if let Some(sender) = sender {
             trace!("Missing cache for '{}'", query);
-            let result = exec(plan).await.map(|d| Arc::new(d));
+            let result = match self.get_persisted(&result_key).await {
+                Some(result) => {
+                    trace!("Using persistent result cache for '{}'", query);
+                    Ok(result)
+                }
+                None => {
+                    let result = exec(plan).await.map(|d| Arc::new(d));
+                    if let Ok(r) = &result {
+                        self.persist(&result_key, r);
+                    }
+                    result
+                }
+            };
             if let Err(e) = sender.send(Some(result.clone())) {
                 trace!(
                     "Failed to set cached query result, possibly flushed from LRU cache: {}",
@@ -162,9 +284,10 @@ impl SqlResultCache {
 
 #[cfg(test)]
 mod tests {
+    use crate::cachestore::{CacheStore, RocksCacheStore};
     use crate::queryplanner::serialized_plan::SerializedPlan;
     use crate::queryplanner::PlanningMeta;
-    use crate::sql::cache::SqlResultCache;
+    use crate::sql::cache::{PersistentResultCache, SqlResultCache, SqlResultCacheKey};
     use crate::sql::SqlQueryContext;
     use crate::store::DataFrame;
     use crate::table::{Row, TableValue};
@@ -259,4 +382,92 @@ mod tests {
         );
         Ok(())
     }
+    #[tokio::test]
+    async fn persistent() -> Result<(), CubeError> {
+        let (_, cachestore) = RocksCacheStore::prepare_test_cachestore("persistent_result_cache");
+        let schema = Arc::new(DFSchema::new(Vec::new())?);
+        let plan = SerializedPlan::try_new(
+            LogicalPlan::EmptyRelation {
+                produce_one_row: false,
+                schema,
+            },
+            PlanningMeta {
+                indices: Vec::new(),
+                multi_part_subtree: HashMap::new(),
+            },
+        )
+        .await?;
+        let result = || {
+            DataFrame::new(
+                Vec::new(),
+                vec![Row::new(vec![TableValue::String("a".to_string())])],
+            )
+        };
+
+        // Routers share the cache store, but not the in-memory cache.
+        let first = SqlResultCache::new(100).with_persistent_cache(PersistentResultCache::new(
+            cachestore.clone(),
+            60,
+            1024,
+        ));
+        let second = SqlResultCache::new(100).with_persistent_cache(PersistentResultCache::new(
+            cachestore.clone(),
+            60,
+            1024,
+        ));
+
+        let r = first
+            .get(
+                "SELECT 1",
+                SqlQueryContext::default(),
+                plan.clone(),
+                async move |_p| Ok(result()),
+            )
+            .await?;
+        assert_eq!(r.as_ref(), &result());
+
+        // Results are written in the background.
+        let key = SqlResultCacheKey::from_plan("SELECT 1", &Vec::new(), &plan);
+        let mut written = false;
+        for _ in 0..50 {
+            if cachestore
+                .result_cache_get(PersistentResultCache::path(&key))
+                .await?
+                .is_some()
+            {
+                written = true;
+                break;
+            }
+            Delay::new(Duration::from_millis(20)).await;
+        }
+        assert!(written, "result must be written to the persistent cache");
+        // Results are not visible to users of the cache.
+        assert_eq!(cachestore.cache_all().await?.len(), 0);
+
+        let r = second
+            .get(
+                "SELECT 1",
+                SqlQueryContext::default(),
+                plan.clone(),
+                async move |_p| -> Result<DataFrame, CubeError> {
+                    panic!("result must be taken from the persistent cache")
+                },
+            )
+            .await?;
+        assert_eq!(r.as_ref(), &result());
+
+        // Different queries do not share entries.
+        let r = second
+            .get(
+                "SELECT 2",
+                SqlQueryContext::default(),
+                plan,
+                async move |_p| Ok(DataFrame::new(Vec::new(), Vec::new())),
+            )
+            .await?;
+        assert_eq!(r.get_rows().len(), 0);
+
+        RocksCacheStore::cleanup_test_cachestore("persistent_result_cache");
+        Ok(())
+    }
 }
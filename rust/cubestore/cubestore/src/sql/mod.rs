@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use arrow::array::*;
 use arrow::compute::kernels::cast_utils::string_to_timestamp_nanos;
@@ -17,6 +17,7 @@ use datafusion::cube_ext;
 use datafusion::physical_plan::ExecutionPlan;
 use datafusion::sql::parser::Statement as DFStatement;
 use futures::future::join_all;
+use futures::{StreamExt, TryStreamExt};
 use hex::FromHex;
 use itertools::Itertools;
 use log::trace;
@@ -32,6 +33,7 @@ use tokio::time::timeout;
 use tracing::instrument;
 use tracing_futures::WithSubscriber;
 
+use cubedatasketches::ThetaSketch;
 use cubehll::HllSketch;
 use parser::Statement as CubeStoreStatement;
 
@@ -41,23 +43,33 @@ use crate::config::injection::DIService;
 use crate::config::ConfigObj;
 use crate::import::limits::ConcurrencyLimits;
 use crate::import::{parse_space_separated_binstring, ImportService, Ingestion};
+use crate::metastore::backup::create_backup;
+use crate::metastore::check::{check_consistency, check_issues_data_frame};
 use crate::metastore::job::JobType;
 use crate::metastore::multi_index::MultiIndex;
 use crate::metastore::source::SourceCredentials;
-use crate::metastore::table::StreamOffset;
+use crate::metastore::table::{MaterializedView, StreamOffset, TimePartition};
 use crate::metastore::{
-    is_valid_plain_binary_hll, table::Table, HllFlavour, IdRow, ImportFormat, Index, IndexDef,
-    IndexType, MetaStoreTable, RowKey, Schema, TableId,
+    is_valid_plain_binary_hll, table::Table, AggregateFunction, HllFlavour, IdRow, ImportFormat,
+    Index, IndexDef, IndexType, MetaStoreTable, RowKey, Schema, TableId, TimePartitionDef,
 };
+use crate::queryplanner::explain::ExplainWorker;
 use crate::queryplanner::panic::PanicWorkerNode;
 use crate::queryplanner::pretty_printers::{pp_phys_plan, pp_plan};
 use crate::queryplanner::query_executor::{batch_to_dataframe, ClusterSendExec, QueryExecutor};
 use crate::queryplanner::serialized_plan::{RowFilter, SerializedPlan};
 use crate::queryplanner::{PlanningMeta, QueryPlan, QueryPlanner};
 use crate::remotefs::RemoteFs;
-use crate::sql::cache::SqlResultCache;
+use crate::sql::admission::{query_workload_class, AdmissionControl};
+use crate::sql::cache::{PersistentResultCache, SqlResultCache};
 use crate::sql::parser::{
     CubeStoreParser, MetastoreCommand, PartitionedIndexRef, RocksStoreName, SystemCommand,
+    TimePartitionRef,
+};
+use crate::sql::permissions::{check_permission, check_role_permission, Permission, Role};
+use crate::sql::query_log::{QueryLog, QueryLogEntry};
+use crate::sql::replay::{
+    config_env, write_bundle, ReplayBundle, BUNDLE_VERSION, DUMP_DOWNLOAD_CONCURRENCY,
 };
 use crate::store::ChunkDataStore;
 use crate::table::{data, Row, TableValue, TimestampValue};
@@ -75,8 +87,12 @@ use datafusion::cube_ext::catch_unwind::async_try_with_catch_unwind;
 use datafusion::physical_plan::parquet::NoopParquetMetadataCache;
 use std::mem::take;
 
+pub mod admission;
 pub mod cache;
 pub mod parser;
+pub mod permissions;
+pub mod query_log;
+pub mod replay;
 use mockall::automock;
 
 #[automock]
@@ -132,8 +148,15 @@ impl InlineTable {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SqlQueryContext {
     pub user: Option<String>,
+    /// Set only when authentication is configured, every statement is allowed otherwise.
+    pub role: Option<Role>,
     pub inline_tables: InlineTables,
     pub trace_obj: Option<String>,
+    /// Set with `SET workload_class` in MySQL sessions.
+    pub workload_class: Option<String>,
+    /// Only MySQL connections keep state between statements.
+    #[serde(skip)]
+    pub session: Option<Arc<SqlSession>>,
 }
 
 impl SqlQueryContext {
@@ -143,6 +166,12 @@ impl SqlQueryContext {
         res
     }
 
+    pub fn with_role(&self, role: Option<Role>) -> Self {
+        let mut res = self.clone();
+        res.role = role;
+        res
+    }
+
     pub fn with_inline_tables(&self, inline_tables: &InlineTables) -> Self {
         let mut res = self.clone();
         res.inline_tables = inline_tables.clone();
@@ -154,6 +183,34 @@ impl SqlQueryContext {
         res.trace_obj = trace_obj;
         res
     }
+
+    pub fn with_workload_class(&self, workload_class: Option<String>) -> Self {
+        let mut res = self.clone();
+        res.workload_class = workload_class;
+        res
+    }
+
+    pub fn with_session(&self, session: Option<Arc<SqlSession>>) -> Self {
+        let mut res = self.clone();
+        res.session = session;
+        res
+    }
+}
+
+/// State of a MySQL connection changed by its statements.
+#[derive(Debug, Default)]
+pub struct SqlSession {
+    workload_class: std::sync::Mutex<Option<String>>,
+}
+
+impl SqlSession {
+    pub fn workload_class(&self) -> Option<String> {
+        self.workload_class.lock().unwrap().clone()
+    }
+
+    fn set_workload_class(&self, workload_class: String) {
+        *self.workload_class.lock().unwrap() = Some(workload_class);
+    }
 }
 
 pub struct SqlServiceImpl {
@@ -171,6 +228,8 @@ pub struct SqlServiceImpl {
     query_timeout: Duration,
     create_table_timeout: Duration,
     cache: SqlResultCache,
+    admission: Arc<AdmissionControl>,
+    query_log: Arc<QueryLog>,
 }
 
 crate::di_service!(SqlServiceImpl, [SqlService]);
@@ -192,7 +251,20 @@ impl SqlServiceImpl {
         query_timeout: Duration,
         create_table_timeout: Duration,
         max_cached_queries: usize,
+        query_log: Arc<QueryLog>,
     ) -> Arc<SqlServiceImpl> {
+        let mut cache = SqlResultCache::new(max_cached_queries);
+        if config_obj.persistent_result_cache_ttl_secs() > 0 {
+            cache = cache.with_persistent_cache(PersistentResultCache::new(
+                cachestore.clone(),
+                config_obj.persistent_result_cache_ttl_secs(),
+                config_obj.persistent_result_cache_max_entry_bytes(),
+            ));
+        }
+        let admission = Arc::new(AdmissionControl::new(
+            config_obj.workload_classes(),
+            config_obj.max_concurrent_queries(),
+        ));
         Arc::new(SqlServiceImpl {
             db,
             cachestore,
@@ -207,7 +279,9 @@ impl SqlServiceImpl {
             query_timeout,
             create_table_timeout,
             remote_fs,
-            cache: SqlResultCache::new(max_cached_queries),
+            cache,
+            admission,
+            query_log,
         })
     }
 
@@ -235,9 +309,27 @@ impl SqlServiceImpl {
         unique_key: Option<Vec<Ident>>,
         aggregates: Option<Vec<(Ident, Ident)>>,
         partitioned_index: Option<PartitionedIndexRef>,
+        time_partition: Option<TimePartitionRef>,
+        retention: Option<String>,
         trace_obj: &Option<String>,
     ) -> Result<IdRow<Table>, CubeError> {
         let columns_to_set = convert_columns_type(columns)?;
+        let time_partition = match time_partition {
+            Some(p) => Some(TimePartitionDef {
+                column: p.column.value,
+                interval_secs: TimePartition::parse_interval(&p.interval)?,
+                retention_secs: retention
+                    .as_deref()
+                    .map(TimePartition::parse_interval)
+                    .transpose()?,
+            }),
+            None if retention.is_some() => {
+                return Err(CubeError::user(
+                    "retention requires PARTITION BY time_bucket".to_string(),
+                ))
+            }
+            None => None,
+        };
         let mut indexes_to_create = Vec::new();
         if let Some(mut p) = partitioned_index {
             let part_index_name = match p.name.0.as_mut_slice() {
@@ -338,6 +430,7 @@ impl SqlServiceImpl {
                             .collect()
                     }),
                     None,
+                    time_partition,
                 )
                 .await;
         }
@@ -363,7 +456,7 @@ impl SqlServiceImpl {
             .into_iter()
             .sum::<u64>();
 
-            let mut sel_workers_count = self.config_obj.select_workers().len() as u64;
+            let mut sel_workers_count = self.cluster.worker_set().workers().len() as u64;
             if sel_workers_count == 0 {
                 sel_workers_count = 1;
             }
@@ -397,6 +490,7 @@ impl SqlServiceImpl {
                         .collect()
                 }),
                 partition_split_threshold,
+                time_partition,
             )
             .await?;
 
@@ -524,6 +618,206 @@ impl SqlServiceImpl {
             .await?)
     }
 
+    async fn create_materialized_view(
+        &self,
+        name: ObjectName,
+        query: Box<Query>,
+    ) -> Result<IdRow<Table>, CubeError> {
+        let (schema_name, view_name) = match name.0.as_slice() {
+            [schema, view] => (schema.value.clone(), view.value.clone()),
+            _ => {
+                return Err(CubeError::user(format!(
+                    "Schema's name should be present in materialized view name but found: {}",
+                    name
+                )))
+            }
+        };
+        let definition = query.to_string();
+        let select = match &query.body {
+            SetExpr::Select(s)
+                if query.order_by.is_empty()
+                    && query.limit.is_none()
+                    && query.offset.is_none()
+                    && query.with.is_none() =>
+            {
+                s
+            }
+            _ => {
+                return Err(CubeError::user(format!(
+                    "Materialized view must be a single SELECT ... GROUP BY query, got: {}",
+                    definition
+                )))
+            }
+        };
+        if select.distinct || select.selection.is_some() || select.having.is_some() {
+            return Err(CubeError::user(format!(
+                "DISTINCT, WHERE and HAVING are not supported in materialized views, got: {}",
+                definition
+            )));
+        }
+        let source_name = match select.from.as_slice() {
+            [TableWithJoins {
+                relation: TableFactor::Table { name, .. },
+                joins,
+            }] if joins.is_empty() => name,
+            _ => {
+                return Err(CubeError::user(format!(
+                    "Materialized view must select from a single table, got: {}",
+                    definition
+                )))
+            }
+        };
+        let source = match source_name.0.as_slice() {
+            [schema, table] => {
+                self.db
+                    .get_table(schema.value.clone(), table.value.clone())
+                    .await?
+            }
+            _ => {
+                return Err(CubeError::user(format!(
+                    "Schema's name should be present in table name but found: {}",
+                    source_name
+                )))
+            }
+        };
+        let source_columns = source.get_row().get_columns();
+        let find_source_column = |name: &str| {
+            source_columns
+                .iter()
+                .find(|c| c.get_name() == name)
+                .ok_or_else(|| {
+                    CubeError::user(format!(
+                        "Column {} is not present in table {}",
+                        name, source_name
+                    ))
+                })
+        };
+
+        let mut columns = Vec::with_capacity(select.projection.len());
+        let mut view_sources = Vec::with_capacity(select.projection.len());
+        let mut dimensions = Vec::new();
+        let mut dimension_sources = Vec::new();
+        let mut aggregates = Vec::new();
+        for item in select.projection.iter() {
+            let (expr, alias) = match item {
+                SelectItem::UnnamedExpr(e) => (e, None),
+                SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias.value.clone())),
+                _ => {
+                    return Err(CubeError::user(format!(
+                        "Wildcards are not supported in materialized views, got: {}",
+                        definition
+                    )))
+                }
+            };
+            match expr {
+                Expr::Identifier(ident) => {
+                    let source_column = find_source_column(&ident.value)?;
+                    let name = alias.unwrap_or_else(|| ident.value.clone());
+                    columns.push(Column::new(
+                        name.clone(),
+                        source_column.get_column_type().clone(),
+                        columns.len(),
+                    ));
+                    view_sources.push(ident.value.clone());
+                    dimensions.push(name);
+                    dimension_sources.push(ident.value.clone());
+                }
+                Expr::Function(Function {
+                    name: fun,
+                    args,
+                    distinct: false,
+                    over: None,
+                    ..
+                }) => {
+                    let function = fun.to_string().parse::<AggregateFunction>()?;
+                    let is_count = function == AggregateFunction::COUNT;
+                    let (source, column_type) = match args.as_slice() {
+                        [FunctionArg::Unnamed(Expr::Identifier(ident))] => {
+                            let source_column = find_source_column(&ident.value)?;
+                            let column_type = if is_count {
+                                ColumnType::Int
+                            } else {
+                                source_column.get_column_type().clone()
+                            };
+                            (ident.value.clone(), column_type)
+                        }
+                        [FunctionArg::Unnamed(Expr::Wildcard)] if is_count => {
+                            ("*".to_string(), ColumnType::Int)
+                        }
+                        _ => {
+                            return Err(CubeError::user(format!(
+                                "Aggregate function in materialized view must have a single column argument, got: {}",
+                                expr
+                            )))
+                        }
+                    };
+                    let name = alias.ok_or_else(|| {
+                        CubeError::user(format!(
+                            "Aggregate function in materialized view must have an alias, got: {}",
+                            expr
+                        ))
+                    })?;
+                    columns.push(Column::new(name.clone(), column_type, columns.len()));
+                    view_sources.push(source);
+                    aggregates.push((function.to_string(), name));
+                }
+                _ => {
+                    return Err(CubeError::user(format!(
+                        "Only columns and aggregate functions are supported in materialized views, got: {}",
+                        expr
+                    )))
+                }
+            }
+        }
+
+        let mut group_by = Vec::with_capacity(select.group_by.len());
+        for e in select.group_by.iter() {
+            let source = match e {
+                Expr::Identifier(ident) => Some(ident.value.clone()),
+                Expr::Value(Value::Number(n, _)) => n
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|i| match select.projection.get(i) {
+                        Some(SelectItem::UnnamedExpr(Expr::Identifier(ident)))
+                        | Some(SelectItem::ExprWithAlias {
+                            expr: Expr::Identifier(ident),
+                            ..
+                        }) => Some(ident.value.clone()),
+                        _ => None,
+                    }),
+                _ => None,
+            };
+            group_by.push(source.ok_or_else(|| {
+                CubeError::user(format!(
+                    "GROUP BY in materialized view must reference selected columns, got: {}",
+                    e
+                ))
+            })?);
+        }
+        if group_by
+            .iter()
+            .sorted()
+            .ne(dimension_sources.iter().sorted())
+        {
+            return Err(CubeError::user(format!(
+                "GROUP BY in materialized view must list exactly the selected columns, got: {}",
+                definition
+            )));
+        }
+
+        self.db
+            .create_materialized_view(
+                schema_name,
+                view_name,
+                columns,
+                dimensions,
+                aggregates,
+                MaterializedView::new(source.get_id(), view_sources, definition),
+            )
+            .await
+    }
+
     async fn insert_data<'a>(
         &'a self,
         schema_name: String,
@@ -535,6 +829,12 @@ impl SqlServiceImpl {
             .db
             .get_table(schema_name.clone(), table_name.clone())
             .await?;
+        if table.get_row().is_materialized_view() {
+            return Err(CubeError::user(format!(
+                "Can't insert into materialized view {}.{}. Insert into its source table instead.",
+                schema_name, table_name
+            )));
+        }
         let table_columns = table.get_row().clone();
         let table_columns = table_columns.get_columns();
         let mut real_col: Vec<&Column> = Vec::new();
@@ -571,14 +871,13 @@ impl SqlServiceImpl {
         &self,
         query: &str,
         q: Box<Query>,
+        inline_tables: &InlineTables,
     ) -> Result<Arc<DataFrame>, CubeError> {
         // TODO: metastore snapshot must be consistent wrt the dumped data.
+        let select = q.to_string();
         let logical_plan = self
             .query_planner
-            .logical_plan(
-                DFStatement::Statement(Statement::Query(q)),
-                &InlineTables::new(),
-            )
+            .logical_plan(DFStatement::Statement(Statement::Query(q)), inline_tables)
             .await?;
 
         let mut dump_dir = PathBuf::from(&self.remote_fs.local_path().await);
@@ -591,28 +890,42 @@ impl SqlServiceImpl {
         log::debug!("Dumping metastore to {}", meta_dir);
         self.db.debug_dump(meta_dir).await?;
 
-        match logical_plan {
+        let data_dir = dump_dir.join("data");
+        tokio::fs::create_dir(&data_dir).await?;
+        let files = match logical_plan {
             QueryPlan::Select(p, _) => {
-                let data_dir = dump_dir.join("data");
-                tokio::fs::create_dir(&data_dir).await?;
                 log::debug!("Dumping data files to {:?}", data_dir);
-                // TODO: download in parallel.
-                for (_, f, size) in p.all_required_files() {
-                    let f = self.remote_fs.download_file(&f, size).await?;
-                    let name = Path::new(&f).file_name().ok_or_else(|| {
-                        CubeError::internal(format!("Could not get filename of '{}'", f))
-                    })?;
-                    tokio::fs::copy(&f, data_dir.join(&name)).await?;
-                }
+                let data_dir = &data_dir;
+                futures::stream::iter(p.all_required_files().into_iter().map(
+                    |(_, f, size)| async move {
+                        let local = self.remote_fs.download_file(&f, size).await?;
+                        tokio::fs::copy(&local, data_dir.join(&f)).await?;
+                        Ok::<_, CubeError>(f)
+                    },
+                ))
+                .buffer_unordered(DUMP_DOWNLOAD_CONCURRENCY)
+                .try_collect::<Vec<_>>()
+                .await?
             }
-            QueryPlan::Meta(_) => {}
-        }
+            QueryPlan::Meta(_) => Vec::new(),
+        };
 
         let query_file = dump_dir.join("query.sql");
         File::create(query_file)
             .await?
             .write_all(query.as_bytes())
             .await?;
+        write_bundle(
+            &dump_dir,
+            &ReplayBundle {
+                version: BUNDLE_VERSION,
+                query: select,
+                inline_tables: inline_tables.clone(),
+                env: config_env(),
+                files,
+            },
+        )
+        .await?;
 
         log::debug!("Wrote debug dump to {:?}", dump_dir);
 
@@ -656,15 +969,27 @@ impl SqlServiceImpl {
                         HashMap::new(),
                         HashMap::new(),
                         NoopParquetMetadataCache::new(),
+                        None,
                     )?;
 
+                    let mut plan = pp_plan(&logical_plan);
+                    for i in serialized.index_snapshots() {
+                        if !i.index_choice().is_empty() {
+                            plan += &format!(
+                                "\nIndex choice for {}: {}",
+                                i.table_name(),
+                                i.index_choice().iter().join(", ")
+                            );
+                        }
+                    }
+
                     DataFrame::new(
                         vec![Column::new(
                             "logical plan".to_string(),
                             ColumnType::String,
                             0,
                         )],
-                        vec![Row::new(vec![TableValue::String(pp_plan(&logical_plan))])],
+                        vec![Row::new(vec![TableValue::String(plan)])],
                     )
                 } else {
                     let cluster = self.cluster.clone();
@@ -717,6 +1042,39 @@ impl SqlServiceImpl {
         }?;
         Ok(Arc::new(res))
     }
+
+    async fn explain_json(
+        &self,
+        statement: Statement,
+        analyze: bool,
+    ) -> Result<Arc<DataFrame>, CubeError> {
+        let query_plan = self
+            .query_planner
+            .logical_plan(DFStatement::Statement(statement), &InlineTables::new())
+            .await?;
+        let serialized = match query_plan {
+            QueryPlan::Select(serialized, _) => serialized,
+            _ => {
+                return Err(CubeError::user(
+                    "Explain not supported for selects from system tables".to_string(),
+                ))
+            }
+        };
+        let plan = self
+            .query_executor
+            .explain_router_plan(serialized, self.cluster.clone(), analyze)
+            .await?;
+        let router = ExplainWorker {
+            node: self.config_obj.server_name().to_string(),
+            plan,
+        };
+        Ok(Arc::new(DataFrame::new(
+            vec![Column::new("plan".to_string(), ColumnType::String, 0)],
+            vec![Row::new(vec![TableValue::String(serde_json::to_string(
+                &router,
+            )?)])],
+        )))
+    }
 }
 
 pub fn string_prop(credentials: &Vec<SqlOption>, prop_name: &str) -> Option<String> {
@@ -793,6 +1151,7 @@ impl SqlService for SqlServiceImpl {
             let mut parser = CubeStoreParser::new(query)?;
             parser.parse_statement()?
         };
+        check_permission(&context.user, context.role, &ast)?;
         // trace!("AST is: {:?}", ast);
         match ast {
             CubeStoreStatement::Statement(Statement::ShowVariable { variable }) => {
@@ -847,7 +1206,7 @@ impl SqlService for SqlServiceImpl {
                 }
                 SystemCommand::PanicWorker => {
                     let cluster = self.cluster.clone();
-                    let workers = self.config_obj.select_workers();
+                    let workers = cluster.worker_set();
                     let plan = SerializedPlan::try_new(
                         PanicWorkerNode {}.into_plan(),
                         PlanningMeta {
@@ -856,7 +1215,7 @@ impl SqlService for SqlServiceImpl {
                         },
                     )
                     .await?;
-                    if workers.len() == 0 {
+                    if workers.is_empty() {
                         let executor = self.query_executor.clone();
                         match async_try_with_catch_unwind(
                             executor.execute_router_plan(plan, cluster),
@@ -867,7 +1226,7 @@ impl SqlService for SqlServiceImpl {
                             Err(panic) => Err(CubeError::from(panic)),
                         }?;
                     } else {
-                        let worker = &workers[0];
+                        let worker = &workers.workers()[0];
                         cluster.run_select(worker, plan).await?;
                     }
                     panic!("worker did not panic")
@@ -878,10 +1237,46 @@ impl SqlService for SqlServiceImpl {
                         Ok(Arc::new(DataFrame::new(vec![], vec![])))
                     }
                 },
+                SystemCommand::Check { repair } => {
+                    let issues = check_consistency(
+                        self.db.as_ref(),
+                        self.remote_fs.as_ref(),
+                        chrono::Duration::seconds(self.config_obj.import_job_timeout() as i64),
+                        repair,
+                    )
+                    .await?;
+                    Ok(Arc::new(check_issues_data_frame(issues)))
+                }
+                SystemCommand::Backup { prefix } => {
+                    let manifest =
+                        create_backup(self.db.as_ref(), self.remote_fs.clone(), &prefix).await?;
+                    let columns = vec![
+                        Column::new("snapshot".to_string(), ColumnType::String, 0),
+                        Column::new("files".to_string(), ColumnType::Int, 1),
+                        Column::new("size".to_string(), ColumnType::Int, 2),
+                    ];
+                    let size = manifest.files.iter().map(|f| f.size).sum::<u64>();
+                    Ok(Arc::new(DataFrame::new(
+                        columns,
+                        vec![Row::new(vec![
+                            TableValue::String(manifest.snapshot),
+                            TableValue::Int(manifest.files.len() as i64),
+                            TableValue::Int(size as i64),
+                        ])],
+                    )))
+                }
             },
             CubeStoreStatement::Statement(Statement::SetVariable { .. }) => {
                 Ok(Arc::new(DataFrame::new(vec![], vec![])))
             }
+            CubeStoreStatement::SetWorkloadClass { name } => {
+                self.admission.check_class(&name)?;
+                // Following queries of the session pass the class in their context.
+                if let Some(session) = &context.session {
+                    session.set_workload_class(name);
+                }
+                Ok(Arc::new(DataFrame::new(vec![], vec![])))
+            }
             CubeStoreStatement::CreateSchema {
                 schema_name,
                 if_not_exists,
@@ -890,6 +1285,10 @@ impl SqlService for SqlServiceImpl {
                 let res = self.create_schema(name, if_not_exists).await?;
                 Ok(Arc::new(DataFrame::from(vec![res])))
             }
+            CubeStoreStatement::CreateMaterializedView { name, query } => {
+                let res = self.create_materialized_view(name, query).await?;
+                Ok(Arc::new(DataFrame::from(vec![res])))
+            }
             CubeStoreStatement::CreateTable {
                 create_table:
                     Statement::CreateTable {
@@ -904,6 +1303,7 @@ impl SqlService for SqlServiceImpl {
                 locations,
                 unique_key,
                 partitioned_index,
+                time_partition,
             } => {
                 let nv = &name.0;
                 if nv.len() != 2 {
@@ -985,6 +1385,16 @@ impl SqlService for SqlServiceImpl {
                             option.value
                         ))),
                     })?;
+                let retention = with_options
+                    .iter()
+                    .find(|&opt| opt.name.value == "retention")
+                    .map_or(Result::Ok(None), |option| match &option.value {
+                        Value::SingleQuotedString(retention) => Result::Ok(Some(retention.clone())),
+                        _ => Result::Err(CubeError::user(format!(
+                            "Bad retention {}. Expected string.",
+                            option.value
+                        ))),
+                    })?;
 
                 let res = self
                     .create_table(
@@ -1002,6 +1412,8 @@ impl SqlService for SqlServiceImpl {
                         unique_key,
                         aggregates,
                         partitioned_index,
+                        time_partition,
+                        retention,
                         &context.trace_obj,
                     )
                     .await?;
@@ -1346,54 +1758,92 @@ impl SqlService for SqlServiceImpl {
                 }
             }
             CubeStoreStatement::Statement(Statement::Query(q)) => {
-                let logical_plan = self
-                    .query_planner
-                    .logical_plan(
-                        DFStatement::Statement(Statement::Query(q)),
-                        &context.inline_tables,
-                    )
-                    .await?;
-                // TODO distribute and combine
-                let res = match logical_plan {
-                    QueryPlan::Meta(logical_plan) => {
-                        app_metrics::META_QUERIES.increment();
-                        Arc::new(self.query_planner.execute_meta_plan(logical_plan).await?)
-                    }
-                    QueryPlan::Select(serialized, workers) => {
-                        app_metrics::DATA_QUERIES.increment();
-                        let cluster = self.cluster.clone();
-                        let executor = self.query_executor.clone();
-                        timeout(
-                            self.query_timeout,
-                            self.cache
-                                .get(query, context, serialized, async move |plan| {
-                                    let records;
-                                    if workers.len() == 0 {
-                                        records =
-                                            executor.execute_router_plan(plan, cluster).await?.1;
-                                    } else {
-                                        // Pick one of the workers to run as main for the request.
-                                        let i = thread_rng().sample(Uniform::new(0, workers.len()));
-                                        let rs = cluster.route_select(&workers[i], plan).await?.1;
-                                        records = rs
-                                            .into_iter()
-                                            .map(|r| r.read())
-                                            .collect::<Result<Vec<_>, _>>()?;
-                                    }
-                                    Ok(cube_ext::spawn_blocking(
-                                        move || -> Result<DataFrame, CubeError> {
-                                            let df = batch_to_dataframe(&records)?;
-                                            Ok(df)
-                                        },
-                                    )
-                                    .await??)
-                                })
-                                .with_current_subscriber(),
+                let started_at = Utc::now();
+                let start = Instant::now();
+                let trace_obj = context.trace_obj.clone();
+                let user = context.user.clone();
+                let mut scanned = None;
+                let worker_timings = Arc::new(std::sync::Mutex::new(None));
+                let res = async {
+                    let logical_plan = self
+                        .query_planner
+                        .logical_plan(
+                            DFStatement::Statement(Statement::Query(q)),
+                            &context.inline_tables,
                         )
-                        .await??
-                    }
-                };
-                Ok(res)
+                        .await?;
+                    // TODO distribute and combine
+                    let res = match logical_plan {
+                        QueryPlan::Meta(logical_plan) => {
+                            app_metrics::META_QUERIES.increment();
+                            Arc::new(self.query_planner.execute_meta_plan(logical_plan).await?)
+                        }
+                        QueryPlan::Select(serialized, workers) => {
+                            app_metrics::DATA_QUERIES.increment();
+                            scanned = Some(scanned_data(&serialized));
+                            let cluster = self.cluster.clone();
+                            let executor = self.query_executor.clone();
+                            let admission = self.admission.clone();
+                            let timings = worker_timings.clone();
+                            let workload_class =
+                                query_workload_class(&context.workload_class, &context.trace_obj);
+                            timeout(
+                                self.query_timeout,
+                                self.cache
+                                    .get(query, context, serialized, async move |plan| {
+                                        let _permit =
+                                            admission.acquire(workload_class.as_deref()).await?;
+                                        let records;
+                                        if workers.len() == 0 {
+                                            let (_, r, t) =
+                                                executor.execute_router_plan(plan, cluster).await?;
+                                            records = r;
+                                            *timings.lock().unwrap() = Some(t);
+                                        } else {
+                                            // Pick one of the workers to run as main for the request.
+                                            let i =
+                                                thread_rng().sample(Uniform::new(0, workers.len()));
+                                            let (_, rs, t) =
+                                                cluster.route_select(&workers[i], plan).await?;
+                                            *timings.lock().unwrap() = Some(t);
+                                            records = rs
+                                                .into_iter()
+                                                .map(|r| r.read())
+                                                .collect::<Result<Vec<_>, _>>()?;
+                                        }
+                                        Ok(cube_ext::spawn_blocking(
+                                            move || -> Result<DataFrame, CubeError> {
+                                                let df = batch_to_dataframe(&records)?;
+                                                Ok(df)
+                                            },
+                                        )
+                                        .await??)
+                                    })
+                                    .with_current_subscriber(),
+                            )
+                            .await??
+                        }
+                    };
+                    Ok::<_, CubeError>(res)
+                }
+                .await;
+                // Stays empty when the result is served from the cache.
+                let worker_timings = worker_timings.lock().unwrap().take();
+                self.query_log.add(QueryLogEntry {
+                    id: 0,
+                    started_at,
+                    query: query.to_string(),
+                    trace_obj,
+                    user,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                    partitions_scanned: scanned
+                        .filter(|_| worker_timings.is_some())
+                        .map(|(p, _)| p),
+                    rows_scanned: scanned.filter(|_| worker_timings.is_some()).map(|(_, r)| r),
+                    worker_timings: worker_timings.unwrap_or_default(),
+                    error: res.as_ref().err().map(|e| e.message.clone()),
+                });
+                res
             }
             CubeStoreStatement::Statement(Statement::Explain {
                 analyze,
@@ -1407,7 +1857,14 @@ impl SqlService for SqlServiceImpl {
                 ))),
             },
 
-            CubeStoreStatement::Dump(q) => self.dump_select_inputs(query, q).await,
+            CubeStoreStatement::ExplainJson { analyze, query: q } => {
+                self.explain_json(Statement::Query(q), analyze).await
+            }
+
+            CubeStoreStatement::Dump(q) => {
+                self.dump_select_inputs(query, q, &context.inline_tables)
+                    .await
+            }
 
             CubeStoreStatement::CacheSet {
                 key,
@@ -1553,10 +2010,12 @@ impl SqlService for SqlServiceImpl {
 
     async fn upload_temp_file(
         &self,
-        _context: SqlQueryContext,
+        context: SqlQueryContext,
         name: String,
         file_path: &Path,
     ) -> Result<(), CubeError> {
+        // Uploaded files are only read by CREATE TABLE ... LOCATION, so uploading needs the same role.
+        check_role_permission(&context.user, context.role, Permission::Write)?;
         // TODO persist file size
         self.remote_fs
             .upload_file(
@@ -1572,6 +2031,24 @@ impl SqlService for SqlServiceImpl {
     }
 }
 
+/// Partitions and rows (including chunks) the plan reads.
+fn scanned_data(plan: &SerializedPlan) -> (u64, u64) {
+    let mut partitions = 0;
+    let mut rows = 0;
+    for index in plan.index_snapshots() {
+        for p in index.partitions() {
+            partitions += 1;
+            rows += p.partition().get_row().main_table_row_count();
+            rows += p
+                .chunks()
+                .iter()
+                .map(|c| c.get_row().get_row_count())
+                .sum::<u64>();
+        }
+    }
+    (partitions, rows)
+}
+
 fn convert_columns_type(columns: &Vec<ColumnDef>) -> Result<Vec<Column>, CubeError> {
     let mut rolupdb_columns = Vec::new();
 
@@ -1625,6 +2102,7 @@ fn convert_columns_type(columns: &Vec<ColumnDef>) -> Result<Vec<Column>, CubeErr
                         "hyperloglogpp" => ColumnType::HyperLogLog(HllFlavour::ZetaSketch),
                         "hll_snowflake" => ColumnType::HyperLogLog(HllFlavour::Snowflake),
                         "hll_postgres" => ColumnType::HyperLogLog(HllFlavour::Postgres),
+                        "theta_sketch" => ColumnType::ThetaSketch,
                         _ => {
                             return Err(CubeError::user(format!(
                                 "Custom type '{}' is not supported",
@@ -1873,6 +2351,24 @@ fn extract_data<'a>(
                 .unwrap()
                 .append_value(val)?;
         }
+        ColumnType::ThetaSketch => {
+            let builder = builder
+                .as_any_mut()
+                .downcast_mut::<BinaryBuilder>()
+                .unwrap();
+            if is_null {
+                builder.append_null()?;
+                return Ok(());
+            }
+            let val;
+            if let Expr::Value(v) = cell {
+                val = parse_binary_string(buffer, v)?
+            } else {
+                return Err(CubeError::user("Corrupted data in query.".to_string()));
+            };
+            ThetaSketch::read(val)?;
+            builder.append_value(val)?;
+        }
         ColumnType::Timestamp => {
             let builder = builder
                 .as_any_mut()
@@ -2009,9 +2505,11 @@ mod tests {
     use crate::config::{Config, FileStoreProvider};
     use crate::import::MockImportService;
     use crate::metastore::{BaseRocksStoreFs, RocksMetaStore};
+    use crate::queryplanner::batch_cache::PartitionBatchCache;
     use crate::queryplanner::query_executor::MockQueryExecutor;
     use crate::queryplanner::MockQueryPlanner;
     use crate::remotefs::{LocalDirRemoteFs, RemoteFile, RemoteFs};
+    use crate::store::compaction::CompactionService;
     use crate::store::ChunkStore;
 
     use super::*;
@@ -2076,6 +2574,7 @@ mod tests {
                 query_timeout,
                 query_timeout,
                 10_000, // max_cached_queries
+                QueryLog::new(config.config_obj()),
             );
             let i = service.exec_query("CREATE SCHEMA foo").await.unwrap();
             assert_eq!(
@@ -2148,6 +2647,7 @@ mod tests {
                 query_timeout,
                 query_timeout,
                 10_000, // max_cached_queries
+                QueryLog::new(config.config_obj()),
             );
             let i = service.exec_query("CREATE SCHEMA Foo").await.unwrap();
             assert_eq!(
@@ -2249,6 +2749,7 @@ mod tests {
                 query_timeout,
                 query_timeout,
                 10_000, // max_cached_queries
+                QueryLog::new(config.config_obj()),
             );
             let i = service.exec_query("CREATE SCHEMA Foo").await.unwrap();
             assert_eq!(
@@ -2693,65 +3194,492 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn file_size_consistency() {
-        Config::test("file_size_consistency")
+    async fn query_memory_limit() {
+        Config::test("query_memory_limit")
+            .update_config(|mut c| {
+                c.query_memory_limit = 160 * 1024;
+                c
+            })
             .start_test(async move |services| {
                 let service = services.sql_service;
 
-                let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-                let _ = service
-                    .exec_query("CREATE TABLE foo.ints (value int)")
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (id int, g int)")
                     .await
                     .unwrap();
+                for batch in 0..20 {
+                    let values = (0..1000)
+                        .map(|i| {
+                            let id = batch * 1000 + i;
+                            format!("({}, {})", id, id % 1500)
+                        })
+                        .join(", ");
+                    service
+                        .exec_query(&format!(
+                            "INSERT INTO foo.numbers (id, g) VALUES {}",
+                            values
+                        ))
+                        .await
+                        .unwrap();
+                }
 
-                service
-                    .exec_query("INSERT INTO foo.ints (value) VALUES (42)")
+                let r = service
+                    .exec_query(
+                        "SELECT g, count(*) FROM foo.numbers GROUP BY 1 ORDER BY 1 DESC LIMIT 3",
+                    )
                     .await
                     .unwrap();
+                assert_eq!(
+                    r.get_rows(),
+                    &[1499, 1498, 1497]
+                        .iter()
+                        .map(|g| Row::new(vec![TableValue::Int(*g), TableValue::Int(13)]))
+                        .collect_vec()
+                );
 
-                let chunk = services.meta_store.get_chunk(1).await.unwrap();
-
-                let path = {
-                    let dir = env::temp_dir();
-
-                    let path = dir.clone().join("1.chunk.parquet");
-                    let mut file = File::create(path.clone()).unwrap();
-                    file.write_all("Malformed parquet".as_bytes()).unwrap();
-                    path
-                };
-
-                let remote_fs = services.injector.get_service_typed::<dyn RemoteFs>().await;
-                remote_fs
-                    .upload_file(
-                        path.to_str().unwrap(),
-                        &chunk.get_row().get_full_name(chunk.get_id()),
+                // Top-k needs each group once in the partial aggregates of every worker.
+                let r = service
+                    .exec_query(
+                        "SELECT g, sum(id) FROM foo.numbers GROUP BY 1 ORDER BY 2 DESC LIMIT 3",
                     )
                     .await
                     .unwrap();
+                assert_eq!(
+                    r.get_rows(),
+                    &[(499, 143486), (498, 143472), (497, 143458)]
+                        .iter()
+                        .map(|(g, s)| Row::new(vec![TableValue::Int(*g), TableValue::Int(*s)]))
+                        .collect_vec()
+                );
 
-                let result = service.exec_query("SELECT count(*) from foo.ints").await;
-                println!("Result: {:?}", result);
-                assert!(result.is_err(), "Expected error but {:?} found", result);
-
-                let result = service.exec_query("SELECT count(*) from foo.ints").await;
-                println!("Result: {:?}", result);
-                assert!(
-                    result
-                        .clone()
-                        .err()
-                        .unwrap()
-                        .to_string()
-                        .contains("not found"),
-                    "Expected table not found error but got {:?}",
-                    result
+                let r = service
+                    .exec_query("SELECT id FROM foo.numbers ORDER BY g, id DESC LIMIT 2")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    r.get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Int(19500)]),
+                        Row::new(vec![TableValue::Int(18000)])
+                    ]
                 );
             })
             .await;
     }
 
     #[tokio::test]
-    async fn high_frequency_inserts() {
+    async fn workload_classes() {
+        Config::test("workload_classes")
+            .update_config(|mut c| {
+                c.workload_classes = vec!["refresh:1:0:-1".parse().unwrap()];
+                c.max_concurrent_queries = 2;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (id int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (id) VALUES (1), (2)")
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query("SET workload_class = 'refresh'")
+                    .await
+                    .unwrap();
+                let err = service
+                    .exec_query("SET workload_class = 'unknown'")
+                    .await
+                    .unwrap_err();
+                assert!(
+                    err.message.contains("Unknown workload class 'unknown'"),
+                    "{}",
+                    err
+                );
+
+                // MySQL connections keep the class in their session.
+                let session = Arc::new(SqlSession::default());
+                service
+                    .exec_query_with_context(
+                        SqlQueryContext::default().with_session(Some(session.clone())),
+                        "SET workload_class = 'refresh'",
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(session.workload_class(), Some("refresh".to_string()));
+
+                let context = SqlQueryContext::default()
+                    .with_trace_obj(Some(r#"{"workloadClass": "refresh"}"#.to_string()));
+                let r = service
+                    .exec_query_with_context(context.clone(), "SELECT sum(id) FROM foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(r.get_rows(), &vec![Row::new(vec![TableValue::Int(3)])]);
+
+                let err = service
+                    .exec_query_with_context(
+                        context.with_workload_class(Some("unknown".to_string())),
+                        "SELECT count(*) FROM foo.numbers",
+                    )
+                    .await
+                    .unwrap_err();
+                assert!(
+                    err.message.contains("Unknown workload class 'unknown'"),
+                    "{}",
+                    err
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn read_only_user_cant_upload() {
+        Config::test("read_only_user_cant_upload")
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                let path = env::temp_dir().join("read-only-upload.csv");
+                tokio::fs::write(&path, "id\n1\n2\n").await.unwrap();
+
+                let reader = SqlQueryContext::default()
+                    .with_user(Some("reader".to_string()))
+                    .with_role(Some(Role::ReadOnly));
+                let err = service
+                    .upload_temp_file(reader.clone(), "numbers.csv".to_string(), &path)
+                    .await
+                    .unwrap_err();
+                assert!(err.message.contains("Permission denied"), "{}", err);
+                let err = service
+                    .exec_query_with_context(reader, "CREATE SCHEMA foo")
+                    .await
+                    .unwrap_err();
+                assert!(err.message.contains("Permission denied"), "{}", err);
+
+                let writer = SqlQueryContext::default()
+                    .with_user(Some("writer".to_string()))
+                    .with_role(Some(Role::DataWriter));
+                service
+                    .upload_temp_file(writer.clone(), "numbers.csv".to_string(), &path)
+                    .await
+                    .unwrap();
+                service
+                    .exec_query_with_context(writer.clone(), "CREATE SCHEMA foo")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query_with_context(
+                        writer.clone(),
+                        "CREATE TABLE foo.numbers (id int) LOCATION 'temp://numbers.csv'",
+                    )
+                    .await
+                    .unwrap();
+                let r = service
+                    .exec_query_with_context(writer, "SELECT sum(id) FROM foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(r.get_rows(), &vec![Row::new(vec![TableValue::Int(3)])]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn dump_replay() {
+        Config::test("dump_replay")
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (id int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (id) VALUES (1), (2), (3)")
+                    .await
+                    .unwrap();
+
+                let query = "SELECT sum(id) FROM foo.numbers";
+                let expected = service.exec_query(query).await.unwrap();
+                let r = service
+                    .exec_query(&format!("DUMP {}", query))
+                    .await
+                    .unwrap();
+                let dump_path = match &r.get_rows()[0].values()[0] {
+                    TableValue::String(p) => PathBuf::from(p),
+                    v => panic!("unexpected dump path: {:?}", v),
+                };
+
+                let bundle = replay::read_bundle(&dump_path).await.unwrap();
+                assert_eq!(bundle.query, query);
+                assert!(!bundle.files.is_empty());
+                for f in bundle.files.iter() {
+                    assert!(dump_path.join("data").join(f).exists(), "{}", f);
+                }
+
+                let replayed = replay::replay_bundle(&dump_path).await.unwrap();
+                assert_eq!(replayed.get_rows(), expected.get_rows());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn backup_restore() {
+        Config::test("backup_restore")
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (id int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (id) VALUES (1), (2)")
+                    .await
+                    .unwrap();
+
+                let r = service
+                    .exec_query("SYS BACKUP TO 'backups/b1'")
+                    .await
+                    .unwrap();
+                let files = match &r.get_rows()[0].values()[1] {
+                    TableValue::Int(files) => *files,
+                    v => panic!("unexpected files count: {:?}", v),
+                };
+                // Metastore checkpoint files and the chunk with inserted rows.
+                assert!(files > 1, "{:?}", r.get_rows());
+                service
+                    .exec_query("SYS BACKUP TO 'backups/b1'")
+                    .await
+                    .unwrap_err();
+
+                // Restore into the storage of another cluster.
+                let source = Config::test("backup_restore");
+                let target = Config::test("backup_restore_target");
+                let _ = std::fs::remove_dir_all(target.remote_dir());
+                let _ = std::fs::remove_dir_all(target.local_dir());
+                for f in services
+                    .injector
+                    .get_service_typed::<dyn RemoteFs>()
+                    .await
+                    .list_with_metadata("backups/b1/")
+                    .await
+                    .unwrap()
+                {
+                    let to = target.remote_dir().join(&f.remote_path);
+                    std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+                    std::fs::copy(source.remote_dir().join(&f.remote_path), to).unwrap();
+                }
+
+                let manifest = target.restore_backup("backups/b1").await.unwrap();
+                assert_eq!(manifest.files.len() as i64, files);
+                target.restore_backup("backups/b1").await.unwrap_err();
+
+                // Doesn't clean the remote storage before start.
+                target
+                    .start_test_worker(async move |services| {
+                        let r = services
+                            .sql_service
+                            .exec_query("SELECT id FROM foo.numbers ORDER BY id")
+                            .await
+                            .unwrap();
+                        assert_eq!(
+                            r.get_rows(),
+                            &vec![
+                                Row::new(vec![TableValue::Int(1)]),
+                                Row::new(vec![TableValue::Int(2)]),
+                            ]
+                        );
+                    })
+                    .await;
+                let _ = std::fs::remove_dir_all(target.remote_dir());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn check_consistency() {
+        Config::test("check_consistency")
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (id int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (id) VALUES (1), (2)")
+                    .await
+                    .unwrap();
+                let r = service.exec_query("SYS CHECK").await.unwrap();
+                assert_eq!(r.get_rows(), &vec![]);
+
+                let remote_fs = services.injector.get_service_typed::<dyn RemoteFs>().await;
+                let chunk = services
+                    .meta_store
+                    .chunks_table()
+                    .all_rows()
+                    .await
+                    .unwrap()
+                    .remove(0);
+                remote_fs
+                    .delete_file(&chunk.get_row().get_full_name(chunk.get_id()))
+                    .await
+                    .unwrap();
+                // Too recent to be reported as unreferenced.
+                let temp_path = remote_fs.temp_upload_path("1000.parquet").await.unwrap();
+                std::fs::write(&temp_path, "data").unwrap();
+                remote_fs
+                    .upload_file(&temp_path, "1000.parquet")
+                    .await
+                    .unwrap();
+
+                let r = service.exec_query("SYS CHECK REPAIR").await.unwrap();
+                assert_eq!(r.get_rows().len(), 1, "{:?}", r.get_rows());
+                assert_eq!(
+                    r.get_rows()[0].values()[0..2],
+                    [
+                        TableValue::String("missing_file".to_string()),
+                        TableValue::String(format!("chunk {}", chunk.get_id())),
+                    ]
+                );
+                assert_eq!(r.get_rows()[0].values()[3], TableValue::Boolean(true));
+
+                let r = service.exec_query("SYS CHECK").await.unwrap();
+                assert_eq!(r.get_rows(), &vec![]);
+                let r = service
+                    .exec_query("SELECT count(*) FROM foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(r.get_rows(), &vec![Row::new(vec![TableValue::Int(0)])]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn query_log() {
+        Config::test("query_log")
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (id int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (id) VALUES (1), (2)")
+                    .await
+                    .unwrap();
+
+                let context = SqlQueryContext::default()
+                    .with_trace_obj(Some(r#"{"traceId": "1"}"#.to_string()));
+                service
+                    .exec_query_with_context(context, "SELECT sum(id) FROM foo.numbers")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("SELECT * FROM foo.missing")
+                    .await
+                    .unwrap_err();
+
+                let r = service
+                    .exec_query(
+                        "SELECT query, trace_obj, partitions_scanned, rows_scanned, error IS NULL \
+                         FROM system.queries ORDER BY id",
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    r.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::String("SELECT sum(id) FROM foo.numbers".to_string()),
+                            TableValue::String(r#"{"traceId": "1"}"#.to_string()),
+                            TableValue::Int(1),
+                            TableValue::Int(2),
+                            TableValue::Boolean(true),
+                        ]),
+                        Row::new(vec![
+                            TableValue::String("SELECT * FROM foo.missing".to_string()),
+                            TableValue::Null,
+                            TableValue::Null,
+                            TableValue::Null,
+                            TableValue::Boolean(false),
+                        ]),
+                    ]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn file_size_consistency() {
+        Config::test("file_size_consistency")
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                let _ = service
+                    .exec_query("CREATE TABLE foo.ints (value int)")
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query("INSERT INTO foo.ints (value) VALUES (42)")
+                    .await
+                    .unwrap();
+
+                let chunk = services.meta_store.get_chunk(1).await.unwrap();
+
+                let path = {
+                    let dir = env::temp_dir();
+
+                    let path = dir.clone().join("1.chunk.parquet");
+                    let mut file = File::create(path.clone()).unwrap();
+                    file.write_all("Malformed parquet".as_bytes()).unwrap();
+                    path
+                };
+
+                let remote_fs = services.injector.get_service_typed::<dyn RemoteFs>().await;
+                remote_fs
+                    .upload_file(
+                        path.to_str().unwrap(),
+                        &chunk.get_row().get_full_name(chunk.get_id()),
+                    )
+                    .await
+                    .unwrap();
+
+                let result = service.exec_query("SELECT count(*) from foo.ints").await;
+                println!("Result: {:?}", result);
+                assert!(result.is_err(), "Expected error but {:?} found", result);
+
+                let result = service.exec_query("SELECT count(*) from foo.ints").await;
+                println!("Result: {:?}", result);
+                assert!(
+                    result
+                        .clone()
+                        .err()
+                        .unwrap()
+                        .to_string()
+                        .contains("not found"),
+                    "Expected table not found error but got {:?}",
+                    result
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn high_frequency_inserts() {
         Config::test("high_frequency_inserts")
             .update_config(|mut c| {
                 c.partition_split_threshold = 100;
@@ -3360,29 +4288,443 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn table_partition_split_threshold() {
-        let test_name = "table_partition_split_threshold";
-        let port_base = 24406;
-        Config::test(test_name).update_config(|mut config| {
-            config.select_workers = vec![format!("127.0.0.1:{}", port_base + 1), format!("127.0.0.1:{}", port_base + 2)];
-            config.metastore_bind_address = Some(format!("127.0.0.1:{}", port_base));
-            config.compaction_chunks_count_threshold = 0;
-            config.max_partition_split_threshold = 200;
-            config
-        }).start_test(async move |services| {
+    async fn join_without_index() {
+        Config::test("join_without_index").start_test(async move |services| {
             let service = services.sql_service;
 
-            Config::test(&format!("{}_cluster_worker_1", test_name)).update_config(|mut config| {
-                config.worker_bind_address = Some(format!("127.0.0.1:{}", port_base + 1));
-                config.server_name = format!("127.0.0.1:{}", port_base + 1);
-                config.metastore_remote_address = Some(format!("127.0.0.1:{}", port_base));
-                config.store_provider = FileStoreProvider::Filesystem {
-                    remote_dir: Some(env::current_dir()
-                        .unwrap()
-                        .join(format!("{}-upstream", test_name))),
-                };
-                config.compaction_chunks_count_threshold = 0;
-                config.max_partition_split_threshold = 200;
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service.exec_query("CREATE TABLE foo.orders (id int, product int, amount int)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.products (name text, product_id int)").await.unwrap();
+            service.exec_query("INSERT INTO foo.orders (id, product, amount) VALUES (1, 1, 10), (2, 2, 20), (3, 3, 30)").await.unwrap();
+            service.exec_query("INSERT INTO foo.orders (id, product, amount) VALUES (4, 1, 40)").await.unwrap();
+            service.exec_query("INSERT INTO foo.products (name, product_id) VALUES ('Potato', 1), ('Tomato', 2)").await.unwrap();
+
+            // Neither side has an index sorted by the join key.
+            let result = service.exec_query(
+                "SELECT id, name FROM foo.orders LEFT JOIN foo.products ON product = product_id ORDER BY 1"
+            ).await.unwrap();
+            assert_eq!(
+                result.get_rows(),
+                &vec![
+                    Row::new(vec![TableValue::Int(1), TableValue::String("Potato".to_string())]),
+                    Row::new(vec![TableValue::Int(2), TableValue::String("Tomato".to_string())]),
+                    Row::new(vec![TableValue::Int(3), TableValue::Null]),
+                    Row::new(vec![TableValue::Int(4), TableValue::String("Potato".to_string())]),
+                ]
+            );
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn batch_cache() {
+        Config::test("batch_cache")
+            .update_config(|mut c| {
+                c.batch_cache_max_capacity_bytes = 100 << 20;
+                c.compaction_chunks_count_threshold = 100;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+                let cache = services
+                    .injector
+                    .get_service_typed::<PartitionBatchCache>()
+                    .await;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, amount int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (1, 10), (2, 20)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (3, 30)")
+                    .await
+                    .unwrap();
+
+                let result = service
+                    .exec_query("SELECT sum(amount) FROM foo.orders")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(60)])]
+                );
+                // Both chunks are decoded in the background after the first query, later
+                // queries read them from memory.
+                cache.wait_for_loads().await;
+                assert_eq!(cache.usage().0, 2);
+                let result = service
+                    .exec_query("SELECT id FROM foo.orders WHERE amount > 10 ORDER BY 1")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Int(2)]),
+                        Row::new(vec![TableValue::Int(3)])
+                    ]
+                );
+                assert_eq!(cache.usage().0, 2);
+
+                // Chunks of the compacted partition are dropped by the next query.
+                let partition = services
+                    .meta_store
+                    .get_active_partitions_by_index_id(1)
+                    .await
+                    .unwrap();
+                services
+                    .injector
+                    .get_service_typed::<dyn CompactionService>()
+                    .await
+                    .compact(partition[0].get_id())
+                    .await
+                    .unwrap();
+                let result = service
+                    .exec_query("SELECT sum(amount) FROM foo.orders")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(60)])]
+                );
+                cache.wait_for_loads().await;
+                assert_eq!(cache.usage().0, 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn time_partition() {
+        Config::test("time_partition")
+            .update_config(|mut c| {
+                c.compaction_chunks_count_threshold = 100;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                for (query, error) in [
+                    (
+                        "CREATE TABLE foo.bad (ts timestamp, name text) PARTITION BY time_bucket(name, '1 day')",
+                        "must be a timestamp",
+                    ),
+                    (
+                        "CREATE TABLE foo.bad (ts timestamp) PARTITION BY time_bucket(ts, '1 fortnight')",
+                        "Bad interval",
+                    ),
+                    (
+                        "CREATE TABLE foo.bad (ts timestamp) WITH (retention = '1 day')",
+                        "requires PARTITION BY",
+                    ),
+                    (
+                        "CREATE TABLE foo.bad (id int, ts timestamp, n int) UNIQUE KEY (id) PARTITION BY time_bucket(ts, '1 day')",
+                        "must be in the unique key",
+                    ),
+                    (
+                        "CREATE TABLE foo.bad (ts timestamp, name text, n int) AGGREGATE INDEX by_name (name) PARTITION BY time_bucket(ts, '1 day')",
+                        "must contain time partition column",
+                    ),
+                ] {
+                    let e = service.exec_query(query).await.unwrap_err();
+                    assert!(e.to_string().contains(error), "{}: {}", query, e);
+                }
+
+                service
+                    .exec_query(
+                        "CREATE TABLE foo.events (ts timestamp, name text) PARTITION BY time_bucket(ts, '1 day')",
+                    )
+                    .await
+                    .unwrap();
+                let meta_store = services.meta_store.clone();
+                let table = meta_store
+                    .get_table("foo".to_string(), "events".to_string())
+                    .await
+                    .unwrap();
+                let index_id = meta_store
+                    .get_default_index(table.get_id())
+                    .await
+                    .unwrap()
+                    .get_id();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.events (ts, name) VALUES \
+                         ('2022-01-01T10:00:00.000Z', 'a'), \
+                         ('2022-01-02T05:00:00.000Z', 'b'), \
+                         (NULL, 'c')",
+                    )
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.events (ts, name) VALUES ('2022-01-01T20:00:00.000Z', 'd')",
+                    )
+                    .await
+                    .unwrap();
+
+                async fn buckets(meta_store: &Arc<dyn MetaStore>, index_id: u64) -> Vec<Option<i64>> {
+                    meta_store
+                        .get_active_partitions_by_index_id(index_id)
+                        .await
+                        .unwrap()
+                        .iter()
+                        .map(|p| p.get_row().time_bucket())
+                        .sorted()
+                        .collect_vec()
+                }
+                let (day1, day2) = (1640995200, 1641081600);
+                assert_eq!(
+                    buckets(&meta_store, index_id).await,
+                    vec![None, Some(day1), Some(day2)]
+                );
+
+                // Compaction keeps partitions within their buckets.
+                let partition = meta_store
+                    .get_active_partitions_by_index_id(index_id)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .find(|p| p.get_row().time_bucket() == Some(day1))
+                    .unwrap();
+                services
+                    .injector
+                    .get_service_typed::<dyn CompactionService>()
+                    .await
+                    .compact(partition.get_id())
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    buckets(&meta_store, index_id).await,
+                    vec![None, Some(day1), Some(day2)]
+                );
+
+                let result = service
+                    .exec_query("SELECT name FROM foo.events ORDER BY name")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::String("a".to_string())]),
+                        Row::new(vec![TableValue::String("b".to_string())]),
+                        Row::new(vec![TableValue::String("c".to_string())]),
+                        Row::new(vec![TableValue::String("d".to_string())]),
+                    ]
+                );
+
+                // The first day is pruned, the partition without a bucket is always read.
+                let query = "SELECT name FROM foo.events WHERE ts >= to_timestamp('2022-01-02T00:00:00.000Z')";
+                let result = service.exec_query(query).await.unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::String("b".to_string())])]
+                );
+                let plans = service.plan_query(query).await.unwrap();
+                let worker_plan = pp_phys_plan(plans.worker.as_ref());
+                assert_eq!(worker_plan.matches(".parquet").count(), 2, "{}", worker_plan);
+
+                // Buckets that ended more than the retention ago are dropped.
+                service
+                    .exec_query(
+                        "CREATE TABLE foo.recent (ts timestamp, name text) WITH (retention = '7 days') PARTITION BY time_bucket(ts, '1 day')",
+                    )
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.recent (ts, name) VALUES ('2022-01-01T10:00:00.000Z', 'old'), ('{}', 'new')",
+                        Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ")
+                    ))
+                    .await
+                    .unwrap();
+                services.scheduler.reconcile().await.unwrap();
+                let result = service
+                    .exec_query("SELECT name FROM foo.recent")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::String("new".to_string())])]
+                );
+                let result = service
+                    .exec_query("SELECT count(*) FROM foo.events")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(4)])]
+                );
+
+                // Late rows of expired buckets don't bring their partitions back.
+                service
+                    .exec_query("INSERT INTO foo.recent (ts, name) VALUES ('2022-01-01T11:00:00.000Z', 'late')")
+                    .await
+                    .unwrap();
+                let recent = meta_store
+                    .get_table("foo".to_string(), "recent".to_string())
+                    .await
+                    .unwrap();
+                let recent_index_id = meta_store
+                    .get_default_index(recent.get_id())
+                    .await
+                    .unwrap()
+                    .get_id();
+                assert!(!buckets(&meta_store, recent_index_id)
+                    .await
+                    .contains(&Some(day1)));
+                let result = service
+                    .exec_query("SELECT name FROM foo.recent")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::String("new".to_string())])]
+                );
+
+                // Expired partitions are deactivated and left to garbage collection.
+                let partition = meta_store
+                    .get_active_partitions_by_index_id(index_id)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .find(|p| p.get_row().time_bucket() == Some(day1))
+                    .unwrap();
+                let expired = meta_store
+                    .deactivate_time_buckets_before(table.get_id(), day2)
+                    .await
+                    .unwrap();
+                assert!(expired.iter().any(|p| p.get_id() == partition.get_id()));
+                assert!(expired.iter().all(|p| !p.get_row().is_active()));
+                assert!(meta_store.get_partition(partition.get_id()).await.is_ok());
+                assert_eq!(
+                    buckets(&meta_store, index_id).await,
+                    vec![None, Some(day2)]
+                );
+                let result = service
+                    .exec_query("SELECT count(*) FROM foo.events")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(2)])]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn cluster_hash_join() {
+        let test_name = "cluster_hash_join";
+        let port_base = 26306;
+        Config::test(test_name).update_config(|mut config| {
+            config.select_workers = vec![format!("127.0.0.1:{}", port_base + 1), format!("127.0.0.1:{}", port_base + 2)];
+            config.metastore_bind_address = Some(format!("127.0.0.1:{}", port_base));
+            config.compaction_chunks_count_threshold = 0;
+            // Split both sides by the join key.
+            config.join_broadcast_rows = 0;
+            config
+        }).start_test(async move |services| {
+            let service = services.sql_service;
+
+            Config::test(&format!("{}_worker_1", test_name)).update_config(|mut config| {
+                config.worker_bind_address = Some(format!("127.0.0.1:{}", port_base + 1));
+                config.server_name = format!("127.0.0.1:{}", port_base + 1);
+                config.metastore_remote_address = Some(format!("127.0.0.1:{}", port_base));
+                config.store_provider = FileStoreProvider::Filesystem {
+                    remote_dir: Some(env::current_dir()
+                        .unwrap()
+                        .join(format!("{}-upstream", test_name))),
+                };
+                config.compaction_chunks_count_threshold = 0;
+                config
+            }).start_test_worker(async move |_| {
+                Config::test(&format!("{}_worker_2", test_name)).update_config(|mut config| {
+                    config.worker_bind_address = Some(format!("127.0.0.1:{}", port_base + 2));
+                    config.server_name = format!("127.0.0.1:{}", port_base + 2);
+                    config.metastore_remote_address = Some(format!("127.0.0.1:{}", port_base));
+                    config.store_provider = FileStoreProvider::Filesystem {
+                        remote_dir: Some(env::current_dir()
+                            .unwrap()
+                            .join(format!("{}-upstream", test_name))),
+                    };
+                    config.compaction_chunks_count_threshold = 0;
+                    config
+                }).start_test_worker(async move |_| {
+                    service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                    service.exec_query("CREATE TABLE foo.orders (id int, product int, amount int)").await.unwrap();
+                    service.exec_query("CREATE TABLE foo.products (name text, product_id int)").await.unwrap();
+                    for batch in 0..3 {
+                        let values = (0..10)
+                            .map(|i| format!("({}, {}, {})", batch * 10 + i, i % 5, i))
+                            .join(", ");
+                        service.exec_query(
+                            &format!("INSERT INTO foo.orders (id, product, amount) VALUES {}", values)
+                        ).await.unwrap();
+                    }
+                    service.exec_query("INSERT INTO foo.products (name, product_id) VALUES ('p0', 0), ('p1', 1), ('p2', 2)").await.unwrap();
+                    service.exec_query("INSERT INTO foo.products (name, product_id) VALUES ('p3', 3), ('p5', 5)").await.unwrap();
+
+                    let result = service.exec_query(
+                        "SELECT name, count(*), sum(amount) FROM foo.orders JOIN foo.products ON product = product_id \
+                         GROUP BY 1 ORDER BY 1"
+                    ).await.unwrap();
+                    assert_eq!(
+                        result.get_rows(),
+                        &vec![
+                            Row::new(vec![TableValue::String("p0".to_string()), TableValue::Int(6), TableValue::Int(15)]),
+                            Row::new(vec![TableValue::String("p1".to_string()), TableValue::Int(6), TableValue::Int(21)]),
+                            Row::new(vec![TableValue::String("p2".to_string()), TableValue::Int(6), TableValue::Int(27)]),
+                            Row::new(vec![TableValue::String("p3".to_string()), TableValue::Int(6), TableValue::Int(33)]),
+                        ]
+                    );
+
+                    // Unmatched rows of outer joins are returned once.
+                    let result = service.exec_query(
+                        "SELECT name, count(id) FROM foo.products LEFT JOIN foo.orders ON product = product_id \
+                         GROUP BY 1 ORDER BY 1"
+                    ).await.unwrap();
+                    assert_eq!(
+                        result.get_rows(),
+                        &vec![
+                            Row::new(vec![TableValue::String("p0".to_string()), TableValue::Int(6)]),
+                            Row::new(vec![TableValue::String("p1".to_string()), TableValue::Int(6)]),
+                            Row::new(vec![TableValue::String("p2".to_string()), TableValue::Int(6)]),
+                            Row::new(vec![TableValue::String("p3".to_string()), TableValue::Int(6)]),
+                            Row::new(vec![TableValue::String("p5".to_string()), TableValue::Int(0)]),
+                        ]
+                    );
+                }).await;
+            }).await;
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn table_partition_split_threshold() {
+        let test_name = "table_partition_split_threshold";
+        let port_base = 24406;
+        Config::test(test_name).update_config(|mut config| {
+            config.select_workers = vec![format!("127.0.0.1:{}", port_base + 1), format!("127.0.0.1:{}", port_base + 2)];
+            config.metastore_bind_address = Some(format!("127.0.0.1:{}", port_base));
+            config.compaction_chunks_count_threshold = 0;
+            config.max_partition_split_threshold = 200;
+            config
+        }).start_test(async move |services| {
+            let service = services.sql_service;
+
+            Config::test(&format!("{}_cluster_worker_1", test_name)).update_config(|mut config| {
+                config.worker_bind_address = Some(format!("127.0.0.1:{}", port_base + 1));
+                config.server_name = format!("127.0.0.1:{}", port_base + 1);
+                config.metastore_remote_address = Some(format!("127.0.0.1:{}", port_base));
+                config.store_provider = FileStoreProvider::Filesystem {
+                    remote_dir: Some(env::current_dir()
+                        .unwrap()
+                        .join(format!("{}-upstream", test_name))),
+                };
+                config.compaction_chunks_count_threshold = 0;
+                config.max_partition_split_threshold = 200;
                 config
             }).start_test_worker(async move |_| {
                 Config::test(&format!("{}_cluster_worker_2", test_name)).update_config(|mut config| {
@@ -3638,6 +4980,51 @@ mod tests {
             );
         }).await;
     }
+    #[tokio::test]
+    async fn explain_index_choice() {
+        Config::run_test("explain_index_choice", async move |services| {
+            let service = services.sql_service;
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service
+                .exec_query("CREATE TABLE foo.orders (id int, platform text, age int, amount int)")
+                .await
+                .unwrap();
+            service
+                .exec_query("CREATE INDEX by_platform ON foo.orders (platform)")
+                .await
+                .unwrap();
+            service
+                .exec_query(
+                    "INSERT INTO foo.orders (id, platform, age, amount) VALUES \
+                     (1, 'android', 18, 4), (2, 'andorid', 17, 4), (3, 'ios', 20, 5)",
+                )
+                .await
+                .unwrap();
+
+            let result = service
+                .exec_query(
+                    "EXPLAIN SELECT platform, sum(amount) FROM foo.orders GROUP BY platform",
+                )
+                .await
+                .unwrap();
+            let plan = match &result.get_rows()[0].values()[0] {
+                TableValue::String(plan) => plan.clone(),
+                v => panic!("unexpected value: {:?}", v),
+            };
+            let choice = plan
+                .lines()
+                .find(|l| l.starts_with("Index choice for foo.orders: "))
+                .expect(&plan);
+            assert!(
+                choice.contains("by_platform (rows: 3, bytes: "),
+                "{}",
+                choice
+            );
+            assert!(choice.contains("default (rows: 3, bytes: "), "{}", choice);
+        })
+        .await;
+    }
+
     #[tokio::test]
     async fn explain_physical_plan() {
         Config::test("explain_analyze_router").update_config(|mut config| {
@@ -3726,6 +5113,79 @@ mod tests {
             }).await;
         }).await;
     }
+    #[tokio::test]
+    async fn explain_json() {
+        Config::run_test("explain_json", async move |services| {
+            let service = services.sql_service;
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service
+                .exec_query("CREATE TABLE foo.orders (id int, platform text, age int, amount int)")
+                .await
+                .unwrap();
+            service
+                .exec_query(
+                    "INSERT INTO foo.orders (id, platform, age, amount) VALUES \
+                     (1, 'android', 18, 4), (2, 'andorid', 17, 4), (3, 'ios', 20, 5)",
+                )
+                .await
+                .unwrap();
+
+            let query =
+                "SELECT platform, sum(amount) FROM foo.orders WHERE age > 15 GROUP BY platform";
+            for analyze in [false, true] {
+                let options = if analyze {
+                    "ANALYZE, FORMAT JSON"
+                } else {
+                    "FORMAT JSON"
+                };
+                let result = service
+                    .exec_query(&format!("EXPLAIN ({}) {}", options, query))
+                    .await
+                    .unwrap();
+                let plan = match &result.get_rows()[0].values()[0] {
+                    TableValue::String(plan) => {
+                        serde_json::from_str::<serde_json::Value>(plan).unwrap()
+                    }
+                    v => panic!("unexpected value: {:?}", v),
+                };
+                assert_eq!(plan["node"], "localhost");
+
+                let root = &plan["plan"];
+                assert_eq!(root["operator"], "Projection");
+                let send = &root["children"][0]["children"][0];
+                assert_eq!(send["operator"], "ClusterSend");
+                assert_eq!(send["indexes"][0]["table"], "foo.orders");
+                assert_eq!(send["indexes"][0]["index"], "default");
+                assert_eq!(send["indexes"][0]["partitions_scanned"], 1);
+                assert_eq!(send["indexes"][0]["partitions_pruned"], 0);
+                assert_eq!(send["indexes"][0]["rows"], 3);
+
+                let workers = send["workers"].as_array().unwrap();
+                assert_eq!(workers.len(), 1);
+                assert_eq!(workers[0]["node"], "localhost");
+                let worker = &workers[0]["plan"];
+                assert!(worker["operator"].as_str().unwrap().starts_with("Partial"));
+
+                if analyze {
+                    assert_eq!(root["output_rows"], 3);
+                    assert_eq!(send["output_rows"], 3);
+                    assert_eq!(worker["output_rows"], 3);
+                    assert!(root["elapsed_ms"].is_u64());
+                } else {
+                    assert!(root["output_rows"].is_null());
+                    assert!(worker["output_rows"].is_null());
+                }
+            }
+
+            let err = service
+                .exec_query("EXPLAIN (FORMAT JSON) SELECT * FROM system.tables")
+                .await
+                .unwrap_err();
+            assert!(err.message.contains("Explain not supported"), "{}", err);
+        })
+        .await;
+    }
+
     #[tokio::test]
     async fn create_aggr_index() {
         assert!(true);
@@ -3836,6 +5296,130 @@ mod tests {
             .await;
     }
 
+    #[tokio::test]
+    async fn aggr_index_count_bit_or_last_value() {
+        Config::test("aggr_index_count_bit_or_last_value")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 10;
+                c.compaction_chunks_count_threshold = 0;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                // LAST_VALUE needs a column to order the values by.
+                let e = service
+                    .exec_query(
+                        "CREATE TABLE foo.Bad (platform varchar(255), label varchar(255))
+                    AGGREGATIONS (last_value(label))",
+                    )
+                    .await
+                    .unwrap_err();
+                assert!(e.to_string().contains("LAST_VALUE requires"), "{}", e);
+                service
+                    .exec_query(
+                        "CREATE TABLE foo.Events (
+                                    platform varchar(255),
+                                    age int,
+                                    cnt int,
+                                    flags int,
+                                    label varchar(255),
+                                    __seq int
+                                  )
+                    AGGREGATIONS (count(cnt), bit_or(flags), last_value(label))
+                    AGGREGATE INDEX aggr_index (platform)",
+                    )
+                    .await
+                    .unwrap();
+                // LAST_VALUE picks the value with the largest `__seq`, not the one inserted last.
+                service
+                    .exec_query(
+                        "INSERT INTO foo.Events (platform, age, cnt, flags, label, __seq) VALUES \
+                         ('ios', 20, 1, 1, 'a', 1), ('ios', 21, NULL, 2, 'b', 4), ('web', 20, 5, 4, 'c', 3)",
+                    )
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.Events (platform, age, cnt, flags, label, __seq) VALUES \
+                         ('ios', 22, 7, 8, 'd', 2), ('web', 23, 3, 4, 'e', 2)",
+                    )
+                    .await
+                    .unwrap();
+
+                let expected = vec![
+                    Row::new(vec![
+                        TableValue::String("ios".to_string()),
+                        TableValue::Int(2),
+                        TableValue::Int(11),
+                        TableValue::String("b".to_string()),
+                    ]),
+                    Row::new(vec![
+                        TableValue::String("web".to_string()),
+                        TableValue::Int(2),
+                        TableValue::Int(4),
+                        TableValue::String("c".to_string()),
+                    ]),
+                ];
+
+                let query = "SELECT platform, count(cnt), bit_or(flags), last_value(label) \
+                             FROM foo.Events GROUP BY 1 ORDER BY 1";
+                let p = service.plan_query(query).await.unwrap();
+                let worker_plan = pp_phys_plan(p.worker.as_ref());
+                assert!(worker_plan.find("aggr_index").is_some());
+
+                let r = service.exec_query(query).await.unwrap();
+                assert_eq!(r.get_rows(), &expected);
+
+                // Compacted chunks keep the value with the largest seq.
+                let table = services
+                    .meta_store
+                    .get_table("foo".to_string(), "Events".to_string())
+                    .await
+                    .unwrap();
+                let compaction = services
+                    .injector
+                    .get_service_typed::<dyn CompactionService>()
+                    .await;
+                for index in services
+                    .meta_store
+                    .get_table_indexes(table.get_id())
+                    .await
+                    .unwrap()
+                {
+                    for p in services
+                        .meta_store
+                        .get_active_partitions_by_index_id(index.get_id())
+                        .await
+                        .unwrap()
+                    {
+                        compaction.compact(p.get_id()).await.unwrap();
+                    }
+                }
+                let r = service.exec_query(query).await.unwrap();
+                assert_eq!(r.get_rows(), &expected);
+
+                // The default index has the raw rows, the seq is used there as well.
+                let r = service
+                    .exec_query(
+                        "SELECT platform, count(cnt), bit_or(flags), last_value(label) \
+                         FROM foo.Events WHERE age > 0 GROUP BY 1 ORDER BY 1",
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(r.get_rows(), &expected);
+
+                let e = service
+                    .exec_query(
+                        "CREATE TABLE foo.Wrong (platform varchar(255), cnt varchar(255)) \
+                         AGGREGATIONS (count(cnt)) AGGREGATE INDEX aggr_index (platform)",
+                    )
+                    .await;
+                assert!(e.is_err());
+            })
+            .await;
+    }
+
     #[tokio::test]
     async fn validate_ksql_location() {
         Config::test("validate_ksql_location").update_config(|mut c| {
@@ -1,4 +1,5 @@
 use crate::cachestore::QueueItemStatus;
+use crate::queryplanner::grouping_sets::rewrite_grouping_sets_tokens;
 use sqlparser::ast::{
     HiveDistributionStyle, Ident, ObjectName, Query, SqlOption, Statement as SQLStatement, Value,
 };
@@ -34,12 +35,20 @@ pub struct PartitionedIndexRef {
     pub columns: Vec<Ident>,
 }
 
+/// `PARTITION BY time_bucket(<column>, '<interval>')`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimePartitionRef {
+    pub column: Ident,
+    pub interval: String,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
     Statement(SQLStatement),
     CreateTable {
         create_table: SQLStatement,
         partitioned_index: Option<PartitionedIndexRef>,
+        time_partition: Option<TimePartitionRef>,
         indexes: Vec<SQLStatement>,
         locations: Option<Vec<String>>,
         unique_key: Option<Vec<Ident>>,
@@ -49,6 +58,10 @@ pub enum Statement {
         schema_name: ObjectName,
         if_not_exists: bool,
     },
+    CreateMaterializedView {
+        name: ObjectName,
+        query: Box<Query>,
+    },
     CreateSource {
         name: Ident,
         source_type: String,
@@ -122,6 +135,14 @@ pub enum Statement {
     QueueTruncate {},
     System(SystemCommand),
     Dump(Box<Query>),
+    SetWorkloadClass {
+        name: String,
+    },
+    /// `EXPLAIN (FORMAT JSON)`.
+    ExplainJson {
+        analyze: bool,
+        query: Box<Query>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -137,6 +158,8 @@ pub enum SystemCommand {
     Repartition { partition_id: u64 },
     PanicWorker,
     Metastore(MetastoreCommand),
+    Backup { prefix: String },
+    Check { repair: bool },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -152,7 +175,7 @@ impl<'a> CubeStoreParser<'a> {
     pub fn new(sql: &str) -> Result<Self, ParserError> {
         let dialect = &MySqlDialectWithBackTicks {};
         let mut tokenizer = Tokenizer::new(dialect, sql);
-        let tokens = tokenizer.tokenize()?;
+        let tokens = rewrite_grouping_sets_tokens(tokenizer.tokenize()?);
         Ok(CubeStoreParser {
             parser: Parser::new(tokens, dialect),
         })
@@ -177,6 +200,30 @@ impl<'a> CubeStoreParser<'a> {
                     self.parser.next_token();
                     self.parse_create()
                 }
+                Keyword::SET => {
+                    self.parser.next_token();
+                    if self.parse_custom_token("workload_class") {
+                        self.parse_set_workload_class()
+                    } else {
+                        self.parser.prev_token();
+                        Ok(Statement::Statement(self.parser.parse_statement()?))
+                    }
+                }
+                Keyword::EXPLAIN => {
+                    self.parser.next_token();
+                    if self.parser.consume_token(&Token::LParen) {
+                        if let Token::Word(w) = self.parser.peek_token() {
+                            if w.keyword == Keyword::ANALYZE
+                                || w.value.eq_ignore_ascii_case("format")
+                            {
+                                return self.parse_explain_options();
+                            }
+                        }
+                        self.parser.prev_token();
+                    }
+                    self.parser.prev_token();
+                    Ok(Statement::Statement(self.parser.parse_statement()?))
+                }
                 _ if w.value.eq_ignore_ascii_case("dump") => {
                     self.parser.next_token();
                     let s = self.parser.parse_statement()?;
@@ -196,11 +243,80 @@ impl<'a> CubeStoreParser<'a> {
         }
     }
 
+    fn parse_set_workload_class(&mut self) -> Result<Statement, ParserError> {
+        if !self.parser.consume_token(&Token::Eq) && !self.parser.parse_keyword(Keyword::TO) {
+            return Err(ParserError::ParserError(
+                "Expected '=' or TO after SET workload_class".to_string(),
+            ));
+        }
+        let name = match self.parser.next_token() {
+            Token::Word(w) => w.value,
+            Token::SingleQuotedString(s) => s,
+            t => {
+                return Err(ParserError::ParserError(format!(
+                    "Expected workload class name, found: {}",
+                    t
+                )))
+            }
+        };
+        Ok(Statement::SetWorkloadClass { name })
+    }
+
+    /// `EXPLAIN (FORMAT TEXT)` is the same as `EXPLAIN`.
+    fn parse_explain_options(&mut self) -> Result<Statement, ParserError> {
+        let mut analyze = false;
+        let mut json = false;
+        loop {
+            if self.parser.parse_keyword(Keyword::ANALYZE) {
+                analyze = true;
+            } else if self.parse_custom_token("format") {
+                if self.parse_custom_token("json") {
+                    json = true;
+                } else if self.parse_custom_token("text") {
+                    json = false;
+                } else {
+                    return Err(ParserError::ParserError(format!(
+                        "Expected JSON or TEXT after FORMAT, found: {}",
+                        self.parser.peek_token()
+                    )));
+                }
+            } else {
+                return Err(ParserError::ParserError(format!(
+                    "Expected ANALYZE or FORMAT in EXPLAIN options, found: {}",
+                    self.parser.peek_token()
+                )));
+            }
+            if self.parser.consume_token(&Token::RParen) {
+                break;
+            }
+            self.parser.expect_token(&Token::Comma)?;
+        }
+        let statement = self.parser.parse_statement()?;
+        if !json {
+            return Ok(Statement::Statement(SQLStatement::Explain {
+                analyze,
+                verbose: false,
+                statement: Box::new(statement),
+            }));
+        }
+        match statement {
+            SQLStatement::Query(query) => Ok(Statement::ExplainJson { analyze, query }),
+            _ => Err(ParserError::ParserError(
+                "Expected select query after EXPLAIN (FORMAT JSON)".to_string(),
+            )),
+        }
+    }
+
     pub fn parse_create(&mut self) -> Result<Statement, ParserError> {
         if self.parser.parse_keyword(Keyword::SCHEMA) {
             self.parse_create_schema()
         } else if self.parser.parse_keyword(Keyword::TABLE) {
             self.parse_create_table()
+        } else if self
+            .parser
+            .parse_keywords(&[Keyword::MATERIALIZED, Keyword::VIEW])
+        {
+            self.parse_create_materialized_view()
         } else if self.parser.consume_token(&Token::make_keyword("SOURCE"))
             || self.parser.consume_token(&Token::make_keyword("source"))
         {
@@ -483,6 +599,15 @@ impl<'a> CubeStoreParser<'a> {
             }
         } else if self.parse_custom_token("metastore") {
             self.parse_metastore()
+        } else if self.parse_custom_token("check") {
+            Ok(Statement::System(SystemCommand::Check {
+                repair: self.parse_custom_token("repair"),
+            }))
+        } else if self.parse_custom_token("backup") {
+            self.parser.expect_keyword(Keyword::TO)?;
+            Ok(Statement::System(SystemCommand::Backup {
+                prefix: self.parser.parse_literal_string()?,
+            }))
         } else if self.parse_custom_token("panic") && self.parse_custom_token("worker") {
             Ok(Statement::System(SystemCommand::PanicWorker))
         } else if self.parse_custom_token("compaction") {
@@ -599,6 +724,26 @@ impl<'a> CubeStoreParser<'a> {
                 None
             };
 
+            let time_partition = if self
+                .parser
+                .parse_keywords(&[Keyword::PARTITION, Keyword::BY])
+            {
+                if !self.parse_custom_token("time_bucket") {
+                    return Err(ParserError::ParserError(format!(
+                        "Expected time_bucket after PARTITION BY, found: {}",
+                        self.parser.peek_token()
+                    )));
+                }
+                self.parser.expect_token(&Token::LParen)?;
+                let column = self.parser.parse_identifier()?;
+                self.parser.expect_token(&Token::Comma)?;
+                let interval = self.parser.parse_literal_string()?;
+                self.parser.expect_token(&Token::RParen)?;
+                Some(TimePartitionRef { column, interval })
+            } else {
+                None
+            };
+
             let locations = if self.parser.parse_keyword(Keyword::LOCATION) {
                 Some(
                     self.parser
@@ -630,6 +775,7 @@ impl<'a> CubeStoreParser<'a> {
                 indexes,
                 aggregates,
                 partitioned_index,
+                time_partition,
                 locations,
                 unique_key,
             })
@@ -670,6 +816,13 @@ impl<'a> CubeStoreParser<'a> {
         })
     }
 
+    fn parse_create_materialized_view(&mut self) -> Result<Statement, ParserError> {
+        let name = self.parser.parse_object_name()?;
+        self.parser.expect_keyword(Keyword::AS)?;
+        let query = Box::new(self.parser.parse_query()?);
+        Ok(Statement::CreateMaterializedView { name, query })
+    }
+
     fn parse_create_source(&mut self) -> Result<Statement, ParserError> {
         let or_update = self.parser.parse_keywords(&[Keyword::OR, Keyword::UPDATE]);
         let name = self.parser.parse_identifier()?;
@@ -751,6 +904,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_time_partition() {
+        let query = "CREATE TABLE foo.Events (ts timestamp, name varchar(255))
+            INDEX by_name (name)
+            PARTITION BY time_bucket(ts, '1 day')
+            LOCATION 'stream://kafka/events/0'";
+        let mut parser = CubeStoreParser::new(&query).unwrap();
+        match parser.parse_statement().unwrap() {
+            Statement::CreateTable {
+                time_partition,
+                indexes,
+                locations,
+                ..
+            } => {
+                assert_eq!(
+                    time_partition,
+                    Some(TimePartitionRef {
+                        column: Ident::new("ts"),
+                        interval: "1 day".to_string(),
+                    })
+                );
+                assert_eq!(indexes.len(), 1);
+                assert_eq!(locations.unwrap().len(), 1);
+            }
+            s => panic!("unexpected statement: {:?}", s),
+        }
+
+        let mut parser =
+            CubeStoreParser::new("CREATE TABLE foo.Events (ts timestamp) PARTITION BY ts").unwrap();
+        assert!(parser.parse_statement().is_err());
+    }
+
+    #[test]
+    fn parse_create_materialized_view() {
+        let query = "CREATE MATERIALIZED VIEW foo.ByPlatform AS
+            SELECT platform, SUM(amount) total FROM foo.Orders GROUP BY platform";
+        let mut parser = CubeStoreParser::new(&query).unwrap();
+        let res = parser.parse_statement().unwrap();
+        match res {
+            Statement::CreateMaterializedView { name, query } => {
+                assert_eq!(name.to_string(), "foo.ByPlatform");
+                assert_eq!(
+                    query.to_string(),
+                    "SELECT platform, SUM(amount) AS total FROM foo.Orders GROUP BY platform"
+                );
+            }
+            _ => {
+                assert!(false)
+            }
+        }
+    }
+
     #[test]
     fn parse_metastore_set_current() {
         let query = "sys MeTasTore SEt_Current 1671235558783";
@@ -765,4 +970,84 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn parse_backup() {
+        let mut parser = CubeStoreParser::new("SYS BACKUP TO 'backups/2022-12-01'").unwrap();
+        assert_eq!(
+            parser.parse_statement().unwrap(),
+            Statement::System(SystemCommand::Backup {
+                prefix: "backups/2022-12-01".to_string()
+            })
+        );
+        assert!(CubeStoreParser::new("SYS BACKUP 'backups'")
+            .unwrap()
+            .parse_statement()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_check() {
+        for (query, repair) in [("SYS CHECK", false), ("sys check repair", true)] {
+            let mut parser = CubeStoreParser::new(query).unwrap();
+            assert_eq!(
+                parser.parse_statement().unwrap(),
+                Statement::System(SystemCommand::Check { repair })
+            );
+        }
+    }
+
+    #[test]
+    fn parse_set_workload_class() {
+        for query in [
+            "SET workload_class = 'refresh'",
+            "set WORKLOAD_CLASS TO refresh",
+        ] {
+            let mut parser = CubeStoreParser::new(&query).unwrap();
+            assert_eq!(
+                parser.parse_statement().unwrap(),
+                Statement::SetWorkloadClass {
+                    name: "refresh".to_string()
+                }
+            );
+        }
+
+        let mut parser = CubeStoreParser::new("SET autocommit = 1").unwrap();
+        match parser.parse_statement().unwrap() {
+            Statement::Statement(SQLStatement::SetVariable { .. }) => {}
+            s => panic!("unexpected statement: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn parse_explain_options() {
+        for (query, expected_analyze) in [
+            ("EXPLAIN (FORMAT JSON) SELECT 1", false),
+            ("explain (analyze, format json) SELECT 1", true),
+            ("EXPLAIN (FORMAT TEXT, ANALYZE, FORMAT JSON) SELECT 1", true),
+        ] {
+            let mut parser = CubeStoreParser::new(&query).unwrap();
+            match parser.parse_statement().unwrap() {
+                Statement::ExplainJson { analyze, .. } => assert_eq!(analyze, expected_analyze),
+                s => panic!("unexpected statement: {:?}", s),
+            }
+        }
+
+        for (query, expected_analyze) in [
+            ("EXPLAIN (FORMAT TEXT) SELECT 1", false),
+            ("EXPLAIN ANALYZE SELECT 1", true),
+            ("EXPLAIN (SELECT 1)", false),
+        ] {
+            let mut parser = CubeStoreParser::new(&query).unwrap();
+            match parser.parse_statement().unwrap() {
+                Statement::Statement(SQLStatement::Explain { analyze, .. }) => {
+                    assert_eq!(analyze, expected_analyze)
+                }
+                s => panic!("unexpected statement: {:?}", s),
+            }
+        }
+
+        let mut parser = CubeStoreParser::new("EXPLAIN (FORMAT YAML) SELECT 1").unwrap();
+        assert!(parser.parse_statement().is_err());
+    }
 }
@@ -0,0 +1,161 @@
+use crate::sql::parser::Statement as CubeStoreStatement;
+use crate::CubeError;
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::Statement;
+
+/// Role of an authenticated user, assigned in the users file or by a JWT claim.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Queries, `SHOW` and `EXPLAIN`.
+    ReadOnly,
+    /// Everything [Role::ReadOnly] can do plus creating, dropping and loading tables.
+    DataWriter,
+    /// Cache and queue commands only.
+    CacheQueue,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+    CacheQueue,
+    Admin,
+}
+
+impl Role {
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Role::ReadOnly => permission == Permission::Read,
+            Role::DataWriter => matches!(permission, Permission::Read | Permission::Write),
+            Role::CacheQueue => permission == Permission::CacheQueue,
+            Role::Admin => true,
+        }
+    }
+}
+
+pub fn statement_permission(statement: &CubeStoreStatement) -> Permission {
+    match statement {
+        CubeStoreStatement::Statement(Statement::Query(_))
+        | CubeStoreStatement::Statement(Statement::Explain { .. })
+        | CubeStoreStatement::Statement(Statement::ShowVariable { .. })
+        | CubeStoreStatement::Statement(Statement::SetVariable { .. })
+        | CubeStoreStatement::ExplainJson { .. }
+        | CubeStoreStatement::SetWorkloadClass { .. } => Permission::Read,
+        CubeStoreStatement::Statement(Statement::Insert { .. })
+        | CubeStoreStatement::Statement(Statement::Drop { .. })
+        | CubeStoreStatement::Statement(Statement::CreateIndex { .. })
+        | CubeStoreStatement::Statement(Statement::CreatePartitionedIndex { .. })
+        | CubeStoreStatement::CreateTable { .. }
+        | CubeStoreStatement::CreateSchema { .. }
+        | CubeStoreStatement::CreateMaterializedView { .. }
+        | CubeStoreStatement::CreateSource { .. } => Permission::Write,
+        CubeStoreStatement::CacheSet { .. }
+        | CubeStoreStatement::CacheGet { .. }
+        | CubeStoreStatement::CacheKeys { .. }
+        | CubeStoreStatement::CacheRemove { .. }
+        | CubeStoreStatement::CacheTruncate {}
+        | CubeStoreStatement::CacheIncr { .. }
+        | CubeStoreStatement::QueueAdd { .. }
+        | CubeStoreStatement::QueueGet { .. }
+        | CubeStoreStatement::QueueToCancel { .. }
+        | CubeStoreStatement::QueueList { .. }
+        | CubeStoreStatement::QueueCancel { .. }
+        | CubeStoreStatement::QueueHeartbeat { .. }
+        | CubeStoreStatement::QueueAck { .. }
+        | CubeStoreStatement::QueueMergeExtra { .. }
+        | CubeStoreStatement::QueueRetrieve { .. }
+        | CubeStoreStatement::QueueResult { .. }
+        | CubeStoreStatement::QueueResultBlocking { .. }
+        | CubeStoreStatement::QueueTruncate {} => Permission::CacheQueue,
+        // `SYS` commands, `DUMP` writes files on the router and statements we don't expect.
+        CubeStoreStatement::System(_)
+        | CubeStoreStatement::Dump(_)
+        | CubeStoreStatement::Statement(_) => Permission::Admin,
+    }
+}
+
+/// No role means authentication is not configured and every statement is allowed.
+pub fn check_permission(
+    user: &Option<String>,
+    role: Option<Role>,
+    statement: &CubeStoreStatement,
+) -> Result<(), CubeError> {
+    check_role_permission(user, role, statement_permission(statement))
+}
+
+/// Same as [check_permission] for operations that aren't SQL statements, e.g. file uploads.
+pub fn check_role_permission(
+    user: &Option<String>,
+    role: Option<Role>,
+    permission: Permission,
+) -> Result<(), CubeError> {
+    let role = match role {
+        None => return Ok(()),
+        Some(r) => r,
+    };
+    if role.allows(permission) {
+        Ok(())
+    } else {
+        Err(CubeError::user(format!(
+            "Permission denied: user '{}' with role {:?} can't execute {:?} statements",
+            user.as_deref().unwrap_or(""),
+            role,
+            permission
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::parser::CubeStoreParser;
+
+    fn check(role: Role, query: &str) -> Result<(), CubeError> {
+        let statement = CubeStoreParser::new(query)
+            .unwrap()
+            .parse_statement()
+            .unwrap();
+        check_permission(&Some("foo".to_string()), Some(role), &statement)
+    }
+
+    #[test]
+    fn roles() {
+        let select = "SELECT * FROM s.t";
+        let insert = "INSERT INTO s.t (a) VALUES (1)";
+        let drop = "DROP TABLE s.t";
+        let cache = "CACHE GET 'key'";
+        let queue = r#"QUEUE ADD PRIORITY 1 "queue:1" "payload""#;
+        let sys = "SYS KILL ALL";
+
+        assert!(check(Role::ReadOnly, select).is_ok());
+        for q in [insert, drop, cache, queue, sys] {
+            assert!(check(Role::ReadOnly, q).is_err(), "{}", q);
+        }
+
+        for q in [select, insert, drop] {
+            assert!(check(Role::DataWriter, q).is_ok(), "{}", q);
+        }
+        for q in [cache, queue, sys] {
+            assert!(check(Role::DataWriter, q).is_err(), "{}", q);
+        }
+
+        for q in [cache, queue] {
+            assert!(check(Role::CacheQueue, q).is_ok(), "{}", q);
+        }
+        for q in [select, insert, drop, sys] {
+            assert!(check(Role::CacheQueue, q).is_err(), "{}", q);
+        }
+
+        for q in [select, insert, drop, cache, queue, sys] {
+            assert!(check(Role::Admin, q).is_ok(), "{}", q);
+        }
+
+        let statement = CubeStoreParser::new(sys)
+            .unwrap()
+            .parse_statement()
+            .unwrap();
+        assert!(check_permission(&None, None, &statement).is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::config::ConfigObj;
+use crate::queryplanner::query_executor::WorkerTiming;
+use chrono::{DateTime, Utc};
+use datafusion::cube_ext;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The query log file is moved to `<name>.1` once it grows this large.
+const QUERY_LOG_FILE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueryLogEntry {
+    /// Assigned by [QueryLog::add].
+    pub id: u64,
+    pub started_at: DateTime<Utc>,
+    pub query: String,
+    pub trace_obj: Option<String>,
+    pub user: Option<String>,
+    pub duration_ms: u64,
+    /// Only known for data queries that were not served from the result cache.
+    pub partitions_scanned: Option<u64>,
+    /// Rows in the scanned partitions and chunks, filters can skip some of them.
+    pub rows_scanned: Option<u64>,
+    pub worker_timings: Vec<WorkerTiming>,
+    pub error: Option<String>,
+}
+
+/// Recent queries received by the router, shown in `system.queries`.
+pub struct QueryLog {
+    max_entries: usize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<QueryLogEntry>>,
+    file: Option<Arc<Mutex<QueryLogFile>>>,
+}
+
+crate::di_service!(QueryLog, []);
+
+struct QueryLogFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl QueryLog {
+    pub fn new(config: Arc<dyn ConfigObj>) -> Arc<QueryLog> {
+        Arc::new(QueryLog {
+            max_entries: config.query_log_max_entries(),
+            next_id: AtomicU64::new(1),
+            entries: Mutex::new(VecDeque::new()),
+            file: config.query_log_file().as_ref().map(|path| {
+                Arc::new(Mutex::new(QueryLogFile {
+                    path: path.clone(),
+                    file: None,
+                }))
+            }),
+        })
+    }
+
+    pub fn add(&self, mut entry: QueryLogEntry) {
+        entry.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Some(file) = &self.file {
+            let file = file.clone();
+            let entry = entry.clone();
+            cube_ext::spawn_blocking(move || {
+                let mut file = file.lock().unwrap();
+                if let Err(e) = file.append(&entry) {
+                    log::error!(
+                        "Failed to write query log to {}: {}",
+                        file.path.display(),
+                        e
+                    );
+                    file.file = None;
+                }
+            });
+        }
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Oldest first.
+    pub fn entries(&self) -> Vec<QueryLogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl QueryLogFile {
+    fn append(&mut self, entry: &QueryLogEntry) -> Result<(), std::io::Error> {
+        if self.file.is_none() {
+            self.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let file = self.file.as_mut().unwrap();
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        if QUERY_LOG_FILE_MAX_BYTES <= file.metadata()?.len() {
+            self.file = None;
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn entry(query: &str) -> QueryLogEntry {
+        QueryLogEntry {
+            id: 0,
+            started_at: Utc::now(),
+            query: query.to_string(),
+            trace_obj: None,
+            user: None,
+            duration_ms: 1,
+            partitions_scanned: None,
+            rows_scanned: None,
+            worker_timings: Vec::new(),
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queries.log");
+        let config = Config::test("query_log_bounded").update_config(|mut c| {
+            c.query_log_max_entries = 2;
+            c.query_log_file = Some(path.clone());
+            c
+        });
+        let log = QueryLog::new(config.config_obj());
+        for q in ["SELECT 1", "SELECT 2", "SELECT 3"] {
+            log.add(entry(q));
+        }
+        let entries = log.entries();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.id, e.query.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "SELECT 2"), (3, "SELECT 3")]
+        );
+
+        // Wait for the background writes.
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines = std::fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(|l| serde_json::from_str::<QueryLogEntry>(l).unwrap())
+                .collect::<Vec<_>>();
+            if lines.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let mut ids = lines.iter().map(|e| e.id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+}
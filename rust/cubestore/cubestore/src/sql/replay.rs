@@ -0,0 +1,108 @@
+//! Bundles written by `DUMP SELECT ...` and replayed by `cubestored replay <bundle>`.
+//!
+//! A bundle is a directory with:
+//! - `bundle.json`, see [ReplayBundle],
+//! - `metastore-backup/`, a RocksDB backup of the metastore,
+//! - `data/`, partition and chunk files the query reads, named as in the remote storage.
+use crate::config::Config;
+use crate::sql::{InlineTables, SqlQueryContext};
+use crate::store::DataFrame;
+use crate::CubeError;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs;
+
+pub const BUNDLE_FILE: &str = "bundle.json";
+pub const BUNDLE_VERSION: u32 = 1;
+/// Maximum number of data files downloaded at once while dumping a query.
+pub const DUMP_DOWNLOAD_CONCURRENCY: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplayBundle {
+    pub version: u32,
+    /// The query without `DUMP`.
+    pub query: String,
+    pub inline_tables: InlineTables,
+    /// `CUBESTORE_*` environment variables of the node that made the dump, except credentials.
+    pub env: Vec<(String, String)>,
+    /// Names of the files in `data/`.
+    pub files: Vec<String>,
+}
+
+/// Environment variables that configure the node, without ones that might hold credentials.
+pub fn config_env() -> Vec<(String, String)> {
+    let mut env = std::env::vars()
+        .filter(|(k, _)| {
+            k.starts_with("CUBESTORE_")
+                && !["KEY", "SECRET", "PASS", "TOKEN", "CREDENTIALS"]
+                    .iter()
+                    .any(|s| k.contains(s))
+        })
+        .collect::<Vec<_>>();
+    env.sort();
+    env
+}
+
+pub async fn write_bundle(dir: &Path, bundle: &ReplayBundle) -> Result<(), CubeError> {
+    fs::write(dir.join(BUNDLE_FILE), serde_json::to_vec_pretty(bundle)?).await?;
+    Ok(())
+}
+
+pub async fn read_bundle(dir: &Path) -> Result<ReplayBundle, CubeError> {
+    let bundle: ReplayBundle = serde_json::from_slice(&fs::read(dir.join(BUNDLE_FILE)).await?)?;
+    if bundle.version != BUNDLE_VERSION {
+        return Err(CubeError::user(format!(
+            "Unsupported bundle version {}, expected {}",
+            bundle.version, BUNDLE_VERSION
+        )));
+    }
+    Ok(bundle)
+}
+
+/// Loads the bundle into a single-node instance in `<bundle>/replay` and runs its query.
+/// Sets environment variables of the bundle for this process.
+pub async fn replay_bundle(dir: &Path) -> Result<Arc<DataFrame>, CubeError> {
+    let bundle = read_bundle(dir).await?;
+    for (k, v) in bundle.env.iter() {
+        std::env::set_var(k, v);
+    }
+
+    // Data files are put where the local storage keeps its files, so nothing is downloaded.
+    let data_dir = dir.join("replay");
+    if fs::metadata(&data_dir).await.is_ok() {
+        fs::remove_dir_all(&data_dir).await?;
+    }
+    fs::create_dir_all(&data_dir).await?;
+    for f in bundle.files.iter() {
+        fs::copy(dir.join("data").join(f), data_dir.join(f)).await?;
+    }
+
+    let config = Config::default().update_config(|mut c| {
+        c.data_dir = data_dir;
+        c.dump_dir = Some(dir.join("metastore-backup"));
+        c.store_provider = crate::config::FileStoreProvider::Filesystem { remote_dir: None };
+        c.select_workers = Vec::new();
+        c.worker_bind_address = None;
+        c.metastore_bind_address = None;
+        c.metastore_remote_address = None;
+        c.bind_address = None;
+        c.http_bind_address = None;
+        c.status_bind_address = None;
+        c
+    });
+    config.configure_injector().await;
+    let services = config.cube_services().await;
+    services.start_processing_loops().await?;
+
+    info!("Replaying '{}'", bundle.query);
+    let context = SqlQueryContext::default().with_inline_tables(&bundle.inline_tables);
+    let result = services
+        .sql_service
+        .exec_query_with_context(context, &bundle.query)
+        .await;
+
+    services.stop_processing_loops().await?;
+    result
+}
@@ -10,7 +10,7 @@ use crate::metastore::{
 };
 use crate::remotefs::{ensure_temp_file_is_dropped, RemoteFs};
 use crate::store::{ChunkDataStore, ChunkStore, ROW_GROUP_SIZE};
-use crate::table::data::{cmp_min_rows, cmp_partition_key};
+use crate::table::data::{cmp_min_rows, cmp_partition_column_same_type, cmp_partition_key};
 use crate::table::parquet::{arrow_schema, ParquetTableStore};
 use crate::table::redistribute::redistribute;
 use crate::table::{Row, TableValue};
@@ -52,6 +52,9 @@ use tokio::task::JoinHandle;
 pub trait CompactionService: DIService + Send + Sync {
     async fn compact(&self, partition_id: u64) -> Result<(), CubeError>;
     async fn compact_in_memory_chunks(&self, partition_id: u64) -> Result<(), CubeError>;
+    /// Persists all in memory chunks of the partition held by this node. Runs on the previous
+    /// owner before and after routing moves the partition to another node.
+    async fn flush_in_memory_chunks(&self, partition_id: u64) -> Result<(), CubeError>;
     /// Split multi-partition that has too many rows. Figures out the keys based on stored data.
     async fn split_multi_partition(&self, multi_partition_id: u64) -> Result<(), CubeError>;
     /// Process partitions that were added concurrently with multi-split.
@@ -560,7 +563,7 @@ impl CompactionService for CompactionServiceImpl {
         };
         let records =
             merge_chunks(key_size, main_table, new, unique_key, aggregate_columns).await?;
-        let count_and_min =
+        let (count_and_min, file_stats) =
             write_to_files(records, total_rows as usize, store, new_local_files2).await?;
 
         if let Some(c) = &new_chunk {
@@ -599,6 +602,17 @@ impl CompactionService for CompactionServiceImpl {
                         .remote_fs
                         .upload_file(&new_local_files[i], new_remote_path.as_str())
                         .await?;
+                    // Stats are copied to the stored partition by `swap_active_partitions`.
+                    let stats = file_stats.get(i);
+                    let p = IdRow::new(
+                        p.get_id(),
+                        p.get_row()
+                            .update_key_distinct(stats.map(|s| s.key_distinct.clone()))
+                            .update_column_min_max(
+                                stats.map(|s| Row::new(s.column_min.clone())),
+                                stats.map(|s| Row::new(s.column_max.clone())),
+                            ),
+                    );
                     filtered_partitions.push((p, file_size));
                 }
                 EitherOrBoth::Left(p) => {
@@ -757,6 +771,39 @@ impl CompactionService for CompactionServiceImpl {
         Ok(())
     }
 
+    async fn flush_in_memory_chunks(&self, partition_id: u64) -> Result<(), CubeError> {
+        let (partition, index, table, multi_part) = self
+            .meta_store
+            .get_partition_for_compaction(partition_id)
+            .await?;
+        if !partition.get_row().is_active() && !multi_part.is_some() {
+            log::trace!("Cannot flush inactive partition: {:?}", partition.get_row());
+            return Ok(());
+        }
+
+        let mut held = Vec::new();
+        for c in self
+            .meta_store
+            .get_chunks_by_partition(partition_id, false)
+            .await?
+        {
+            if c.get_row().in_memory()
+                && c.get_row().active()
+                && self.chunk_store.holds_memory_chunk(c.get_id()).await?
+            {
+                held.push(c);
+            }
+        }
+        let held_ids = held.iter().map(|c| c.get_id()).collect::<Vec<_>>();
+        self.compact_chunks_to_persistent(held, &partition, &index, &table)
+            .await?;
+        // Chunks are freed on the partition owner once deleted, which is not this node anymore.
+        for id in held_ids {
+            self.chunk_store.free_memory_chunk(id).await?;
+        }
+        Ok(())
+    }
+
     async fn split_multi_partition(&self, multi_partition_id: u64) -> Result<(), CubeError> {
         let (multi_index, multi_partition, partitions) = self
             .meta_store
@@ -1054,12 +1101,13 @@ async fn download_files(
 /// Writes [records] into [files], trying to split into equally-sized rows, with an additional
 /// restriction that files must have non-intersecting key ranges.
 /// [records] must be sorted and have exactly [num_rows] rows.
+/// Also returns statistics of each written file.
 pub(crate) async fn write_to_files(
     records: SendableRecordBatchStream,
     num_rows: usize,
     store: ParquetTableStore,
     files: Vec<String>,
-) -> Result<Vec<(usize, Vec<TableValue>)>, CubeError> {
+) -> Result<(Vec<(usize, Vec<TableValue>)>, Vec<FileStats>), CubeError> {
     let rows_per_file = div_ceil(num_rows as usize, files.len());
     let key_size = store.key_size() as usize;
     let partition_split_key_size = store.partition_split_key_size() as usize;
@@ -1110,13 +1158,96 @@ pub(crate) async fn write_to_files(
         };
     };
 
-    write_to_files_impl(records, store, files, pick_writer).await?;
+    let file_stats = write_to_files_impl(records, store, files, pick_writer).await?;
 
     let mut stats = take(stats.lock().unwrap().deref_mut());
     if stats.last().unwrap().0 == 0 {
         stats.pop();
     }
-    Ok(stats)
+    Ok((stats, file_stats))
+}
+
+/// Statistics of a written file, stored in [crate::metastore::Partition].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FileStats {
+    /// See [crate::metastore::Partition::key_distinct].
+    pub key_distinct: Vec<u64>,
+    /// See [crate::metastore::Partition::column_min].
+    pub column_min: Vec<TableValue>,
+    pub column_max: Vec<TableValue>,
+}
+
+/// Gathers [FileStats] from sorted rows written to each file.
+struct FileStatsCollector {
+    key_size: usize,
+    /// Key of the last counted row in the current file.
+    last_key: Vec<TableValue>,
+    files: Vec<FileStats>,
+}
+
+impl FileStatsCollector {
+    fn new(key_size: usize) -> Self {
+        FileStatsCollector {
+            key_size,
+            last_key: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, file: usize, b: &RecordBatch) {
+        while self.files.len() <= file {
+            self.files.push(FileStats {
+                key_distinct: vec![0; self.key_size],
+                column_min: vec![TableValue::Null; b.num_columns()],
+                column_max: vec![TableValue::Null; b.num_columns()],
+            });
+            self.last_key.clear();
+        }
+        let stats = self.files.last_mut().unwrap();
+        for (c, a) in b.columns().iter().enumerate() {
+            // Sketches and other binary values are not useful for filtering.
+            if let DataType::Binary = a.data_type() {
+                continue;
+            }
+            let (min, max) = (&mut stats.column_min[c], &mut stats.column_max[c]);
+            for i in 0..b.num_rows() {
+                if a.is_null(i) {
+                    continue;
+                }
+                // NULL compares below any value.
+                if *min == TableValue::Null
+                    || cmp_partition_column_same_type(min, a.as_ref(), i) == Ordering::Greater
+                {
+                    *min = TableValue::from_array(a.as_ref(), i);
+                }
+                if cmp_partition_column_same_type(max, a.as_ref(), i) == Ordering::Less {
+                    *max = TableValue::from_array(a.as_ref(), i);
+                }
+            }
+        }
+
+        let key = &b.columns()[0..self.key_size];
+        let counts = &mut stats.key_distinct;
+        for i in 0..b.num_rows() {
+            let first_diff = if self.last_key.is_empty() {
+                0
+            } else {
+                (0..self.key_size)
+                    .find(|&k| {
+                        cmp_partition_column_same_type(&self.last_key[k], key[k].as_ref(), i)
+                            != Ordering::Equal
+                    })
+                    .unwrap_or(self.key_size)
+            };
+            if first_diff == self.key_size {
+                continue;
+            }
+            for c in counts[first_diff..].iter_mut() {
+                *c += 1;
+            }
+            self.last_key = TableValue::from_columns(key, i);
+        }
+    }
 }
 
 enum WriteBatchTo {
@@ -1131,7 +1262,11 @@ async fn write_to_files_impl(
     store: ParquetTableStore,
     files: Vec<String>,
     mut pick_writer: impl FnMut(&RecordBatch) -> WriteBatchTo,
-) -> Result<(), CubeError> {
+) -> Result<Vec<FileStats>, CubeError> {
+    let file_stats = Arc::new(Mutex::new(FileStatsCollector::new(
+        store.key_size() as usize
+    )));
+    let file_stats_ref = file_stats.clone();
     let schema = Arc::new(store.arrow_schema());
     let mut writers = files.into_iter().map(move |f| -> Result<_, CubeError> {
         Ok(ArrowWriter::try_new(
@@ -1163,19 +1298,22 @@ async fn write_to_files_impl(
 
     let mut writer_i = 0;
     let mut process_row_group = move |b: RecordBatch| -> Result<_, CubeError> {
-        match pick_writer(&b) {
-            WriteBatchTo::Current => Ok(((writer_i, b), None)),
+        let r = match pick_writer(&b) {
+            WriteBatchTo::Current => ((writer_i, b), None),
             WriteBatchTo::Next {
                 rows_for_current: n,
             } => {
                 let current_writer = writer_i;
                 writer_i += 1; // Next iteration will write into the next file.
-                Ok((
+                (
                     (current_writer, b.slice(0, n)),
                     Some(b.slice(n, b.num_rows() - n)),
-                ))
+                )
             }
-        }
+        };
+        let (file, to_write) = &r.0;
+        file_stats_ref.lock().unwrap().update(*file, to_write);
+        Ok(r)
     };
     let err = redistribute(records, ROW_GROUP_SIZE, move |b| {
         let r = process_row_group(b);
@@ -1192,7 +1330,8 @@ async fn write_to_files_impl(
     io_job.await??;
     err?;
 
-    Ok(())
+    let file_stats = take(&mut file_stats.lock().unwrap().files);
+    Ok(file_stats)
 }
 
 async fn write_to_files_by_keys(
@@ -1292,10 +1431,12 @@ pub async fn merge_chunks(
 
         let output_sort_order = (0..key_size).map(|x| x as usize).collect();
 
+        // Some aggregates read several columns, e.g. `LAST_VALUE` reads the seq column, so the
+        // stored rows can't be treated as states and are aggregated with `Full`.
         res = Arc::new(HashAggregateExec::try_new(
             AggregateStrategy::InplaceSorted,
             Some(output_sort_order),
-            AggregateMode::Final,
+            AggregateMode::Full,
             groups,
             aggregates,
             res.clone(),
@@ -1366,6 +1507,7 @@ mod tests {
                 None,
                 None,
                 None,
+                None,
             )
             .await
             .unwrap();
@@ -1596,6 +1738,7 @@ mod tests {
                 None,
                 None,
                 None,
+                None,
             )
             .await
             .unwrap();
@@ -1695,6 +1838,114 @@ mod tests {
         RocksMetaStore::cleanup_test_metastore("compact_in_memory_chunks");
     }
 
+    #[tokio::test]
+    async fn flush_in_memory_chunks() {
+        let (remote_fs, metastore) =
+            RocksMetaStore::prepare_test_metastore("flush_in_memory_chunks");
+        let config = Config::test("flush_in_memory_chunks");
+        // The partition has already moved to another worker.
+        let mut cluster = MockCluster::new();
+        cluster
+            .expect_server_name()
+            .return_const("test".to_string());
+        cluster
+            .expect_node_name_by_partition()
+            .returning(move |_i| "worker-2".to_string());
+        let chunk_store = ChunkStore::new(
+            metastore.clone(),
+            remote_fs.clone(),
+            Arc::new(cluster),
+            config.config_obj(),
+            10,
+        );
+        metastore
+            .create_schema("foo".to_string(), false)
+            .await
+            .unwrap();
+        let cols = vec![Column::new("name".to_string(), ColumnType::String, 0)];
+        metastore
+            .create_table(
+                "foo".to_string(),
+                "bar".to_string(),
+                cols.clone(),
+                None,
+                None,
+                vec![],
+                true,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        metastore.get_default_index(1).await.unwrap();
+        let partition = metastore.get_partition(1).await.unwrap();
+        let index = metastore
+            .get_index(partition.get_row().get_index_id())
+            .await
+            .unwrap();
+
+        let rows = (0..5)
+            .map(|i| Row::new(vec![TableValue::String(format!("Foo {}", i))]))
+            .collect::<Vec<_>>();
+        let batch = RecordBatch::try_new(
+            Arc::new(arrow_schema(index.get_row())),
+            rows_to_columns(&cols, &rows),
+        )
+        .unwrap();
+        let held = metastore
+            .create_chunk(partition.get_id(), 5, true)
+            .await
+            .unwrap();
+        // Held by the new owner.
+        let other = metastore
+            .create_chunk(partition.get_id(), 5, true)
+            .await
+            .unwrap();
+        for c in [&held, &other] {
+            metastore.chunk_uploaded(c.get_id()).await.unwrap();
+        }
+        chunk_store
+            .add_memory_chunk(held.get_id(), batch)
+            .await
+            .unwrap();
+
+        let err = chunk_store
+            .get_chunk_columns(other.clone())
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("is not found on 'test'"), "{}", err);
+
+        let compaction_service = CompactionServiceImpl::new(
+            metastore.clone(),
+            chunk_store.clone(),
+            remote_fs,
+            config.config_obj(),
+        );
+        compaction_service
+            .flush_in_memory_chunks(partition.get_id())
+            .await
+            .unwrap();
+
+        let chunks = metastore
+            .get_chunks_by_partition(partition.get_id(), false)
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        let persisted = chunks.iter().find(|c| !c.get_row().in_memory()).unwrap();
+        assert_eq!(persisted.get_row().get_row_count(), 5);
+        // Chunks held elsewhere are left to their owner.
+        assert!(chunks.iter().any(|c| c.get_id() == other.get_id()));
+        assert!(!chunk_store.holds_memory_chunk(held.get_id()).await.unwrap());
+
+        RocksMetaStore::cleanup_test_metastore("flush_in_memory_chunks");
+    }
+
     #[tokio::test]
     async fn aggr_index_compaction() {
         let config = Config::test("create_aggr_chunk_test").update_config(|mut c| {
@@ -1759,6 +2010,7 @@ mod tests {
                 None,
                 Some(vec![("sum".to_string(), "sum_int".to_string())]),
                 None,
+                None,
             )
             .await
             .unwrap();
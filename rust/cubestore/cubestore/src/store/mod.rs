@@ -14,8 +14,8 @@ extern crate bincode;
 use bincode::{deserialize_from, serialize_into};
 
 use crate::metastore::{
-    deactivate_table_on_corrupt_data, table::Table, Chunk, Column, ColumnType, IdRow, Index,
-    IndexType, MetaStore, Partition, WAL,
+    deactivate_table_on_corrupt_data, table::Table, AggregateFunction, Chunk, Column, ColumnType,
+    IdRow, Index, IndexType, MetaStore, Partition, WAL,
 };
 use crate::remotefs::{ensure_temp_file_is_dropped, RemoteFs};
 use crate::table::{Row, TableValue};
@@ -33,8 +33,12 @@ use crate::config::ConfigObj;
 use crate::metastore::chunks::chunk_file_name;
 use crate::table::data::cmp_partition_key;
 use crate::table::parquet::{arrow_schema, ParquetTableStore};
-use arrow::array::{Array, ArrayRef, Int64Builder, StringBuilder, UInt64Array};
+use arrow::array::{
+    Array, ArrayRef, Int64Array, Int64Builder, StringBuilder, TimestampMicrosecondArray,
+    UInt64Array,
+};
 use arrow::record_batch::RecordBatch;
+use chrono::Utc;
 use datafusion::cube_ext;
 use datafusion::cube_ext::util::lexcmp_array_rows;
 use futures::future::join_all;
@@ -42,7 +46,7 @@ use itertools::Itertools;
 use log::trace;
 use mockall::automock;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 
@@ -229,6 +233,8 @@ pub trait ChunkDataStore: DIService + Send + Sync {
         partition: IdRow<Partition>,
         index: IdRow<Index>,
     ) -> Result<Vec<RecordBatch>, CubeError>;
+    /// Whether data of the in memory chunk is held by this node, regardless of the partition owner.
+    async fn holds_memory_chunk(&self, chunk_id: u64) -> Result<bool, CubeError>;
     async fn add_memory_chunk(&self, chunk_id: u64, batch: RecordBatch) -> Result<(), CubeError>;
     async fn free_memory_chunk(&self, chunk_id: u64) -> Result<(), CubeError>;
     async fn add_persistent_chunk(
@@ -351,8 +357,29 @@ impl ChunkDataStore for ChunkStore {
         in_memory: bool,
     ) -> Result<Vec<ChunkUploadJob>, CubeError> {
         let indexes = self.meta_store.get_table_indexes(table_id).await?;
-        self.build_index_chunks(&indexes, rows.into(), columns, in_memory)
-            .await
+        let mut new_chunks = Vec::new();
+        // Materialized views are updated in the same batch of chunks, so they are activated
+        // together with the source table.
+        for view in self.meta_store.get_materialized_views(table_id).await? {
+            let view_rows = materialized_view_rows(view.get_row(), &rows, columns)?;
+            let view_indexes = self.meta_store.get_table_indexes(view.get_id()).await?;
+            new_chunks.append(
+                &mut self
+                    .build_index_chunks(
+                        &view_indexes,
+                        view_rows.into(),
+                        view.get_row().get_columns(),
+                        in_memory,
+                    )
+                    .await?,
+            );
+        }
+        new_chunks.append(
+            &mut self
+                .build_index_chunks(&indexes, rows.into(), columns, in_memory)
+                .await?,
+        );
+        Ok(new_chunks)
     }
 
     async fn partition(&self, _wal_id: u64) -> Result<(), CubeError> {
@@ -522,18 +549,20 @@ impl ChunkDataStore for ChunkStore {
         index: IdRow<Index>,
     ) -> Result<Vec<RecordBatch>, CubeError> {
         if chunk.get_row().in_memory() {
-            let node_name = self.cluster.node_name_by_partition(&partition);
-            let server_name = self.cluster.server_name();
-            if node_name != server_name {
-                return Err(CubeError::internal(format!("In memory chunk {:?} with owner node '{}' is trying to be repartitioned or compacted on non owner node '{}'", chunk, node_name, server_name)));
-            }
+            // Data held in memory is read even if routing moved the partition elsewhere, that's
+            // how in memory chunks are flushed during a handoff. Missing data is never replaced
+            // with an empty batch: queries would silently miss rows.
             let memory_chunks = self.memory_chunks.read().await;
-            Ok(vec![memory_chunks
-                .get(&chunk.get_id())
-                .map(|b| b.clone())
-                .unwrap_or(RecordBatch::new_empty(Arc::new(
-                    arrow_schema(&index.get_row()),
-                )))])
+            match memory_chunks.get(&chunk.get_id()) {
+                Some(batch) => Ok(vec![batch.clone()]),
+                None => Err(CubeError::internal(format!(
+                    "In memory chunk {} of partition {} is not found on '{}', partition owner is '{}'",
+                    chunk.get_id(),
+                    partition.get_id(),
+                    self.cluster.server_name(),
+                    self.cluster.node_name_by_partition(&partition)
+                ))),
+            }
         } else {
             let (local_file, index) = self.download_chunk(chunk, partition, index).await?;
             Ok(cube_ext::spawn_blocking(move || -> Result<_, CubeError> {
@@ -565,6 +594,10 @@ impl ChunkDataStore for ChunkStore {
         }
     }
 
+    async fn holds_memory_chunk(&self, chunk_id: u64) -> Result<bool, CubeError> {
+        Ok(self.memory_chunks.read().await.contains_key(&chunk_id))
+    }
+
     async fn add_memory_chunk(&self, chunk_id: u64, batch: RecordBatch) -> Result<(), CubeError> {
         let mut memory_chunks = self.memory_chunks.write().await;
         memory_chunks.insert(chunk_id, batch);
@@ -699,6 +732,7 @@ mod tests {
                     None,
                     None,
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -792,6 +826,7 @@ mod tests {
                     None,
                     None,
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -899,6 +934,7 @@ mod tests {
                     None,
                     Some(vec![("sum".to_string(), "sum_int".to_string())]),
                     None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -959,12 +995,40 @@ impl ChunkStore {
         in_memory: bool,
     ) -> Result<Vec<JoinHandle<Result<(IdRow<Chunk>, Option<u64>), CubeError>>>, CubeError> {
         let index = self.meta_store.get_index(index_id).await?;
-        let partitions = self
+        let mut partitions = self
             .meta_store
             .get_active_partitions_by_index_id(index_id)
             .await?;
         let sort_key_size = index.get_row().sort_key_size() as usize;
 
+        let (row_buckets, retained_since) = match self.time_buckets(&index, &columns).await? {
+            Some((row_buckets, retained_since)) => (Some(row_buckets), retained_since),
+            None => (None, None),
+        };
+        // Partitions of expired buckets are being removed, rows must not bring them back.
+        let is_expired = |b: &i64| retained_since.map_or(false, |since| *b < since);
+        if let Some(row_buckets) = &row_buckets {
+            let existing = partitions
+                .iter()
+                .filter_map(|p| p.get_row().time_bucket())
+                .collect::<HashSet<_>>();
+            let missing = row_buckets
+                .iter()
+                .filter_map(|b| *b)
+                .filter(|b| !existing.contains(b) && !is_expired(b))
+                .unique()
+                .collect_vec();
+            if !missing.is_empty() {
+                self.meta_store
+                    .create_time_bucket_partitions(index_id, missing)
+                    .await?;
+                partitions = self
+                    .meta_store
+                    .get_active_partitions_by_index_id(index_id)
+                    .await?;
+            }
+        }
+
         let mut remaining_rows: Vec<u64> = (0..columns[0].len() as u64).collect_vec();
         {
             let (columns_again, remaining_rows_again) = cube_ext::spawn_blocking(move || {
@@ -980,12 +1044,38 @@ impl ChunkStore {
             remaining_rows = remaining_rows_again;
         }
 
+        // Each time bucket has its own partitions, rows keep the sort order within a bucket.
+        let mut remaining_rows = match &row_buckets {
+            Some(row_buckets) => remaining_rows
+                .into_iter()
+                .map(|r| (row_buckets[r as usize], r))
+                .into_group_map(),
+            None => HashMap::from([(None, remaining_rows)]),
+        };
+        remaining_rows.retain(|bucket, rows| match bucket {
+            Some(b) if is_expired(b) => {
+                log::warn!(
+                    "Dropping {} rows of time bucket {} in index {} that is past retention",
+                    rows.len(),
+                    b,
+                    index_id
+                );
+                false
+            }
+            _ => true,
+        });
+
         let mut new_chunks = Vec::new();
 
         for partition in partitions.into_iter() {
+            let time_bucket = partition.get_row().time_bucket();
+            let bucket_rows = match remaining_rows.remove(&time_bucket) {
+                Some(rows) => rows,
+                None => continue,
+            };
             let min = partition.get_row().get_min_val().as_ref();
             let max = partition.get_row().get_max_val().as_ref();
-            let (to_write, next) = remaining_rows.into_iter().partition::<Vec<_>, _>(|&r| {
+            let (to_write, next) = bucket_rows.into_iter().partition::<Vec<_>, _>(|&r| {
                 let r = r as usize;
                 (min.is_none()
                     || cmp_partition_key(
@@ -1014,7 +1104,9 @@ impl ChunkStore {
                         .await?,
                 );
             }
-            remaining_rows = next;
+            if !next.is_empty() {
+                remaining_rows.insert(time_bucket, next);
+            }
         }
 
         assert_eq!(remaining_rows.len(), 0);
@@ -1022,6 +1114,55 @@ impl ChunkStore {
         Ok(new_chunks)
     }
 
+    /// Time bucket of each row for indexes of time partitioned tables, `None` for rows without a
+    /// timestamp. Also returns the first bucket within retention, see
+    /// [TimePartition::retained_since](crate::metastore::table::TimePartition::retained_since).
+    async fn time_buckets(
+        &self,
+        index: &IdRow<Index>,
+        columns: &[ArrayRef],
+    ) -> Result<Option<(Vec<Option<i64>>, Option<i64>)>, CubeError> {
+        let table = self
+            .meta_store
+            .get_table_by_id(index.get_row().table_id())
+            .await?;
+        let (time_partition, time_column) = match (
+            table.get_row().time_partition(),
+            table.get_row().time_partition_column(),
+        ) {
+            (Some(p), Some(c)) => (p, c),
+            _ => return Ok(None),
+        };
+        let position = index
+            .get_row()
+            .columns()
+            .iter()
+            .position(|c| c.get_name() == time_column.get_name())
+            .ok_or_else(|| {
+                CubeError::internal(format!(
+                    "Time partition column {} is not found in index {:?}",
+                    time_column.get_name(),
+                    index
+                ))
+            })?;
+        let timestamps = columns[position]
+            .as_any()
+            .downcast_ref::<TimestampMicrosecondArray>()
+            .ok_or_else(|| {
+                CubeError::internal(format!(
+                    "Unexpected type of time partition column: {:?}",
+                    columns[position].data_type()
+                ))
+            })?;
+        Ok(Some((
+            timestamps
+                .iter()
+                .map(|t| t.map(|t| time_partition.bucket_of(t)))
+                .collect(),
+            time_partition.retained_since(Utc::now()),
+        )))
+    }
+
     ///Post-processing of index columns chunk data before saving to parqet files.
     ///Suitable for pre-aggregaions and similar things
     ///`data` must be sorted in order of index columns
@@ -1056,17 +1197,19 @@ impl ChunkStore {
                     .get_row()
                     .aggregate_columns()
                     .iter()
-                    .map(|aggr_col| aggr_col.aggregate_expr(&schema))
+                    .map(|aggr_col| aggr_col.initial_aggregate_expr(&schema))
                     .collect::<Result<Vec<_>, _>>()?;
 
                 let output_sort_order = (0..index.get_row().sort_key_size())
                     .map(|x| x as usize)
                     .collect();
 
+                // Raw rows are not aggregate states: `Full` evaluates the aggregate expressions
+                // on them, `Final` would read the columns as states by position.
                 let aggregate = Arc::new(HashAggregateExec::try_new(
                     AggregateStrategy::InplaceSorted,
                     Some(output_sort_order),
-                    AggregateMode::Final,
+                    AggregateMode::Full,
                     groups,
                     aggregates,
                     input,
@@ -1105,12 +1248,11 @@ impl ChunkStore {
                 chunk
             );
             let batch = RecordBatch::try_new(Arc::new(arrow_schema(&index.get_row())), data)?;
-            let node_name = self.cluster.node_name_by_partition(&partition);
             let cluster = self.cluster.clone();
 
             Ok(cube_ext::spawn(async move {
                 cluster
-                    .add_memory_chunk(&node_name, chunk.get_id(), batch)
+                    .add_memory_chunk(&partition, chunk.get_id(), batch)
                     .await?;
 
                 Ok((chunk, None))
@@ -1168,6 +1310,58 @@ impl ChunkStore {
     }
 }
 
+/// Maps rows of the source table to columns of the materialized view. Rows are aggregated later,
+/// in [ChunkStore::post_process_columns].
+fn materialized_view_rows(
+    view: &Table,
+    rows: &[ArrayRef],
+    columns: &[Column],
+) -> Result<Vec<ArrayRef>, CubeError> {
+    let definition = view.materialized_view().as_ref().ok_or_else(|| {
+        CubeError::internal(format!(
+            "Table '{}' is not a materialized view",
+            view.get_table_name()
+        ))
+    })?;
+    let aggregates = view.aggregate_columns();
+    view.get_columns()
+        .iter()
+        .zip(definition.source_columns().iter())
+        .map(|(view_column, source_name)| {
+            if source_name == "*" {
+                // `COUNT(*)` counts every source row.
+                let num_rows = rows.first().map(|r| r.len()).unwrap_or(0);
+                return Ok(Arc::new(Int64Array::from(vec![1i64; num_rows])) as ArrayRef);
+            }
+            let source_column = columns
+                .iter()
+                .find(|c| c.get_name() == source_name)
+                .ok_or_else(|| {
+                    CubeError::internal(format!(
+                        "Column '{}' of materialized view '{}' not found in {:?}",
+                        source_name,
+                        view.get_table_name(),
+                        columns
+                    ))
+                })?;
+            let array = rows[source_column.get_index()].clone();
+            let is_count = aggregates.iter().any(|a| {
+                a.function() == &AggregateFunction::COUNT
+                    && a.column().get_name() == view_column.get_name()
+            });
+            if is_count {
+                // Each non-null source value is counted as 1.
+                let ones = (0..array.len())
+                    .map(|i| if array.is_null(i) { None } else { Some(1i64) })
+                    .collect::<Int64Array>();
+                Ok(Arc::new(ones) as ArrayRef)
+            } else {
+                Ok(array)
+            }
+        })
+        .collect()
+}
+
 fn remap_columns(
     old: &[ArrayRef],
     old_columns: &[Column],
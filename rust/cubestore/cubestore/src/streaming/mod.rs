@@ -482,6 +482,11 @@ pub fn parse_json_value(column: &Column, value: &JsonValue) -> Result<TableValue
                 "ksql source HLL import isn't supported"
             ))),
         },
+        ColumnType::ThetaSketch => match value {
+            _ => Err(CubeError::internal(format!(
+                "ksql source theta sketch import isn't supported"
+            ))),
+        },
         ColumnType::Timestamp => match value {
             JsonValue::Short(v) => Ok(TableValue::Timestamp(timestamp_from_string(v.as_str())?)),
             JsonValue::String(v) => Ok(TableValue::Timestamp(timestamp_from_string(v.as_str())?)),
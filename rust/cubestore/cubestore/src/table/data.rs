@@ -136,6 +136,7 @@ macro_rules! match_column_type {
             ColumnType::Int => $matcher!(Int, Int64Builder, Int),
             ColumnType::Bytes => $matcher!(Bytes, BinaryBuilder, Bytes),
             ColumnType::HyperLogLog(_) => $matcher!(HyperLogLog, BinaryBuilder, Bytes),
+            ColumnType::ThetaSketch => $matcher!(ThetaSketch, BinaryBuilder, Bytes),
             ColumnType::Timestamp => $matcher!(Timestamp, TimestampMicrosecondBuilder, Timestamp),
             ColumnType::Boolean => $matcher!(Boolean, BooleanBuilder, Boolean),
             ColumnType::Decimal { .. } => match t.target_scale() {
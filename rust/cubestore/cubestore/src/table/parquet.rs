@@ -299,7 +299,7 @@ mod tests {
         let to_split_cols = rows_to_columns(&store.table.columns(), &to_split);
         let schema = Arc::new(arrow_schema(&store.table));
         let to_split_batch = RecordBatch::try_new(schema.clone(), to_split_cols.clone()).unwrap();
-        let count_min = compaction::write_to_files(
+        let (count_min, file_stats) = compaction::write_to_files(
             to_stream(to_split_batch).await,
             to_split.len(),
             ParquetTableStore::new(store.table.clone(), store.row_group_size),
@@ -336,6 +336,35 @@ mod tests {
                 )
             ]
         );
+        // The first file has NULL, 16 values repeated twice and 40..75 in the first column.
+        assert_eq!(
+            file_stats
+                .iter()
+                .map(|s| s.key_distinct.clone())
+                .collect_vec(),
+            vec![vec![52, 75, 75], vec![75, 75, 75]]
+        );
+        // The second file has only rows with values 75..150.
+        assert_eq!(
+            file_stats[1].column_min,
+            vec![
+                TableValue::Int(75),
+                TableValue::String("Foo 100".to_string()),
+                TableValue::String("Boo 100".to_string()),
+                TableValue::Boolean(false),
+                TableValue::Decimal(Decimal::new(750000)),
+            ]
+        );
+        assert_eq!(
+            file_stats[1].column_max,
+            vec![
+                TableValue::Int(149),
+                TableValue::String("Foo 99".to_string()),
+                TableValue::String("Boo 99".to_string()),
+                TableValue::Boolean(false),
+                TableValue::Decimal(Decimal::new(1490000)),
+            ]
+        );
     }
 
     #[test]
@@ -7,6 +7,9 @@
 //! methods on the created objects. See DataDog documentation for more information on different
 //! metric types.
 //!
+//! Tags are sent in the DogStatsD format. StatsD has no tags, so their values are appended to the
+//! metric name instead, e.g. `cs.sql.query.rejected.refresh`.
+//!
 //! Code does not do any sampling or buffering at the time. Too frequent metric updates can cause
 //! load on consuming servers or loose UDP packets entirely. We prefer to report only not very
 //! frequently updated metrics for now to avoid more complex implementation. We can consider
@@ -14,10 +17,20 @@
 //!
 //! Note that misconfiguration (invalid port, address, etc) can cause metric updates to be silently
 //! ignored. This is by design to avoid interrupting normal operation.
+//!
+//! All updates are also aggregated in-process, see [prometheus_text]. Metrics appear there after
+//! they are reported for the first time. Select workers run in subprocesses, they send their
+//! updates to the main process with every response, see [take_updates]. Those series carry an
+//! additional `worker` tag. Forwarded updates only appear in [prometheus_text], they are not sent
+//! over UDP by the main process.
 use crate::CubeError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
 use std::net::ToSocketAddrs;
 use std::net::UdpSocket;
-use std::sync::Once;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Once, RwLock};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Compatibility {
@@ -66,8 +79,13 @@ pub struct Counter {
 
 impl Counter {
     pub fn add(&self, v: i64) {
+        self.add_with_tags(v, &[])
+    }
+
+    pub fn add_with_tags(&self, v: i64, tags: &[(&str, &str)]) {
+        record(&self.metric, v, tags);
         if let Some(s) = sink() {
-            s.send(&self.metric, v)
+            s.send(&self.metric, v, tags)
         }
     }
 
@@ -82,8 +100,13 @@ pub struct IntMetric {
 
 impl IntMetric {
     pub fn report(&self, v: i64) {
+        self.report_with_tags(v, &[])
+    }
+
+    pub fn report_with_tags(&self, v: i64, tags: &[(&str, &str)]) {
+        record(&self.metric, v, tags);
         if let Some(s) = sink() {
-            s.send(&self.metric, v)
+            s.send(&self.metric, v, tags)
         }
     }
 }
@@ -92,6 +115,7 @@ pub type Gauge = IntMetric;
 pub type Histogram = IntMetric;
 pub type Distribution = IntMetric;
 
+#[derive(Clone, Copy)]
 enum MetricType {
     Counter,
     Gauge,
@@ -127,7 +151,13 @@ impl Sink {
         Ok(Sink { socket, mode })
     }
 
-    fn send(&self, m: &Metric, value: i64) {
+    fn send(&self, m: &Metric, value: i64, tags: &[(&str, &str)]) {
+        // We deliberately choose to loose metric submissions on failures.
+        // TODO: handle EWOULDBLOCK with background sends or at least internal failure counters.
+        let _ = self.socket.send(self.format(m, value, tags).as_bytes());
+    }
+
+    fn format(&self, m: &Metric, value: i64, tags: &[(&str, &str)]) -> String {
         let kind = match m.kind {
             MetricType::Counter => "c",
             MetricType::Gauge => "g",
@@ -136,11 +166,27 @@ impl Sink {
             MetricType::Distribution if self.mode == Compatibility::StatsD => "ms",
             MetricType::Distribution => "d",
         };
-        // We deliberately choose to loose metric submissions on failures.
-        // TODO: handle EWOULDBLOCK with background sends or at least internal failure counters.
-        let _ = self
-            .socket
-            .send(format!("{}:{}|{}", m.name, value, kind).as_bytes());
+        if tags.is_empty() {
+            return format!("{}:{}|{}", m.name, value, kind);
+        }
+        match self.mode {
+            Compatibility::DogStatsD => {
+                let tags = tags
+                    .iter()
+                    .map(|(k, v)| format!("{}:{}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!("{}:{}|{}|#{}", m.name, value, kind, tags)
+            }
+            Compatibility::StatsD => {
+                let mut name = m.name.to_string();
+                for (_, v) in tags {
+                    name.push('.');
+                    name.push_str(v);
+                }
+                format!("{}:{}|{}", name, value, kind)
+            }
+        }
     }
 }
 
@@ -177,3 +223,453 @@ mod global_sink {
 }
 
 use global_sink::sink;
+
+/// Content type of [prometheus_text].
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of buckets for histograms and distributions. Most of them report milliseconds.
+const HISTOGRAM_BUCKETS: &[i64] = &[
+    1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000, 60000, 300000,
+];
+
+/// Values of all metrics reported by this process in the Prometheus text format, including the
+/// ones forwarded by other processes with [apply_updates].
+pub fn prometheus_text() -> String {
+    REGISTRY.render()
+}
+
+fn record(m: &Metric, value: i64, tags: &[(&str, &str)]) {
+    REGISTRY.with_series(m.name, m.kind, tags, |s| s.record(value))
+}
+
+/// Changes of a single series since the previous [take_updates] call.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricUpdate {
+    name: String,
+    tags: Vec<(String, String)>,
+    value: UpdateValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum UpdateValue {
+    Counter(i64),
+    Gauge(i64),
+    Histogram {
+        buckets: Vec<u64>,
+        sum: i64,
+        count: u64,
+    },
+}
+
+/// Collects the changes of all metrics since the previous call and resets counters and
+/// histograms. Used by subprocesses, e.g. select workers, which are not scraped on their own.
+pub fn take_updates() -> Vec<MetricUpdate> {
+    REGISTRY.take_updates()
+}
+
+/// Adds updates from [take_updates] of another process, `tags` tell the processes apart.
+pub fn apply_updates(updates: Vec<MetricUpdate>, tags: &[(&str, &str)]) {
+    REGISTRY.apply_updates(updates, tags)
+}
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::default();
+}
+
+/// Series are created under the write lock once, updates only take the read lock and change
+/// atomic values.
+#[derive(Default)]
+struct Registry {
+    metrics: RwLock<BTreeMap<String, RegisteredMetric>>,
+}
+
+struct RegisteredMetric {
+    kind: MetricType,
+    /// Keyed by tags.
+    series: BTreeMap<Vec<(String, String)>, Series>,
+}
+
+impl RegisteredMetric {
+    fn series(&self, tags: &[(&str, &str)]) -> Option<&Series> {
+        // Metrics have a handful of series at most.
+        self.series
+            .iter()
+            .find(|(t, _)| {
+                t.len() == tags.len()
+                    && t.iter()
+                        .zip(tags)
+                        .all(|((k, v), (tk, tv))| k == tk && v == tv)
+            })
+            .map(|(_, s)| s)
+    }
+}
+
+enum Series {
+    Counter(AtomicI64),
+    Gauge(AtomicI64),
+    /// Bucket counts are cumulative, i.e. include all smaller values.
+    Histogram {
+        buckets: Vec<AtomicU64>,
+        sum: AtomicI64,
+        count: AtomicU64,
+    },
+}
+
+impl Series {
+    fn new(kind: MetricType) -> Series {
+        match kind {
+            MetricType::Counter => Series::Counter(AtomicI64::new(0)),
+            MetricType::Gauge => Series::Gauge(AtomicI64::new(0)),
+            MetricType::Histogram | MetricType::Distribution => Series::Histogram {
+                buckets: HISTOGRAM_BUCKETS
+                    .iter()
+                    .map(|_| AtomicU64::new(0))
+                    .collect(),
+                sum: AtomicI64::new(0),
+                count: AtomicU64::new(0),
+            },
+        }
+    }
+
+    fn record(&self, value: i64) {
+        match self {
+            Series::Counter(c) => {
+                c.fetch_add(value, Ordering::Relaxed);
+            }
+            Series::Gauge(g) => g.store(value, Ordering::Relaxed),
+            Series::Histogram {
+                buckets,
+                sum,
+                count,
+            } => {
+                for (b, upper) in buckets.iter().zip(HISTOGRAM_BUCKETS) {
+                    if value <= *upper {
+                        b.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                sum.fetch_add(value, Ordering::Relaxed);
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns [None] if nothing changed since the last call.
+    fn take_update(&self) -> Option<UpdateValue> {
+        match self {
+            Series::Counter(c) => match c.swap(0, Ordering::Relaxed) {
+                0 => None,
+                v => Some(UpdateValue::Counter(v)),
+            },
+            Series::Gauge(g) => Some(UpdateValue::Gauge(g.load(Ordering::Relaxed))),
+            Series::Histogram {
+                buckets,
+                sum,
+                count,
+            } => match count.swap(0, Ordering::Relaxed) {
+                0 => None,
+                count => Some(UpdateValue::Histogram {
+                    buckets: buckets
+                        .iter()
+                        .map(|b| b.swap(0, Ordering::Relaxed))
+                        .collect(),
+                    sum: sum.swap(0, Ordering::Relaxed),
+                    count,
+                }),
+            },
+        }
+    }
+
+    fn apply_update(&self, update: &UpdateValue) {
+        match (self, update) {
+            (Series::Counter(c), UpdateValue::Counter(v)) => {
+                c.fetch_add(*v, Ordering::Relaxed);
+            }
+            (Series::Gauge(g), UpdateValue::Gauge(v)) => g.store(*v, Ordering::Relaxed),
+            (
+                Series::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                },
+                UpdateValue::Histogram {
+                    buckets: update_buckets,
+                    sum: update_sum,
+                    count: update_count,
+                },
+            ) => {
+                for (b, v) in buckets.iter().zip(update_buckets) {
+                    b.fetch_add(*v, Ordering::Relaxed);
+                }
+                sum.fetch_add(*update_sum, Ordering::Relaxed);
+                count.fetch_add(*update_count, Ordering::Relaxed);
+            }
+            // Processes run the same binary, so the kinds of metrics always match.
+            _ => {}
+        }
+    }
+}
+
+impl Registry {
+    fn with_series(
+        &self,
+        name: &str,
+        kind: MetricType,
+        tags: &[(&str, &str)],
+        f: impl Fn(&Series),
+    ) {
+        {
+            let metrics = self.metrics.read().unwrap();
+            if let Some(s) = metrics.get(name).and_then(|m| m.series(tags)) {
+                return f(s);
+            }
+        }
+        let mut metrics = self.metrics.write().unwrap();
+        let metric = metrics
+            .entry(name.to_string())
+            .or_insert_with(|| RegisteredMetric {
+                kind,
+                series: BTreeMap::new(),
+            });
+        let tags = tags
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let kind = metric.kind;
+        f(&*metric
+            .series
+            .entry(tags)
+            .or_insert_with(|| Series::new(kind)))
+    }
+
+    fn take_updates(&self) -> Vec<MetricUpdate> {
+        let metrics = self.metrics.read().unwrap();
+        let mut updates = Vec::new();
+        for (name, metric) in metrics.iter() {
+            for (tags, series) in &metric.series {
+                if let Some(value) = series.take_update() {
+                    updates.push(MetricUpdate {
+                        name: name.clone(),
+                        tags: tags.clone(),
+                        value,
+                    });
+                }
+            }
+        }
+        updates
+    }
+
+    fn apply_updates(&self, updates: Vec<MetricUpdate>, tags: &[(&str, &str)]) {
+        for u in updates {
+            let kind = match u.value {
+                UpdateValue::Counter(_) => MetricType::Counter,
+                UpdateValue::Gauge(_) => MetricType::Gauge,
+                UpdateValue::Histogram { .. } => MetricType::Histogram,
+            };
+            let all_tags = u
+                .tags
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .chain(tags.iter().cloned())
+                .collect::<Vec<_>>();
+            self.with_series(&u.name, kind, &all_tags, |s| s.apply_update(&u.value));
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, metric) in self.metrics.read().unwrap().iter() {
+            let name = prometheus_name(name);
+            let (name, kind) = match metric.kind {
+                MetricType::Counter => (format!("{}_total", name), "counter"),
+                MetricType::Gauge => (name, "gauge"),
+                MetricType::Histogram | MetricType::Distribution => (name, "histogram"),
+            };
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for (tags, series) in &metric.series {
+                match series {
+                    Series::Counter(v) | Series::Gauge(v) => writeln!(
+                        out,
+                        "{}{} {}",
+                        name,
+                        prometheus_labels(tags, None),
+                        v.load(Ordering::Relaxed)
+                    )
+                    .unwrap(),
+                    Series::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    } => {
+                        // Values are read one by one, concurrent updates can make them disagree
+                        // slightly until the next scrape.
+                        for (b, upper) in buckets.iter().zip(HISTOGRAM_BUCKETS) {
+                            let labels = prometheus_labels(tags, Some(&upper.to_string()));
+                            writeln!(
+                                out,
+                                "{}_bucket{} {}",
+                                name,
+                                labels,
+                                b.load(Ordering::Relaxed)
+                            )
+                            .unwrap();
+                        }
+                        let count = count.load(Ordering::Relaxed);
+                        let labels = prometheus_labels(tags, Some("+Inf"));
+                        writeln!(out, "{}_bucket{} {}", name, labels, count).unwrap();
+                        let labels = prometheus_labels(tags, None);
+                        writeln!(
+                            out,
+                            "{}_sum{} {}",
+                            name,
+                            labels,
+                            sum.load(Ordering::Relaxed)
+                        )
+                        .unwrap();
+                        writeln!(out, "{}_count{} {}", name, labels, count).unwrap();
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Prometheus does not allow dots in names, e.g. `cs.sql.query.data` becomes `cs_sql_query_data`.
+fn prometheus_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn prometheus_labels(tags: &[(String, String)], le: Option<&str>) -> String {
+    let mut labels = tags
+        .iter()
+        .map(|(k, v)| (prometheus_name(k), v.as_str()))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        labels.push(("le".to_string(), le));
+    }
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{}}}", labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let m = Metric::new("cs.test", MetricType::Histogram);
+        let dogstatsd =
+            Sink::connect("127.0.0.1:0", "127.0.0.1:9", Compatibility::DogStatsD).unwrap();
+        assert_eq!(dogstatsd.format(&m, 5, &[]), "cs.test:5|h");
+        assert_eq!(
+            dogstatsd.format(&m, 5, &[("class", "refresh"), ("node", "r1")]),
+            "cs.test:5|h|#class:refresh,node:r1"
+        );
+        let statsd = Sink::connect("127.0.0.1:0", "127.0.0.1:9", Compatibility::StatsD).unwrap();
+        assert_eq!(
+            statsd.format(&m, 5, &[("class", "refresh")]),
+            "cs.test.refresh:5|ms"
+        );
+    }
+
+    #[test]
+    fn prometheus() {
+        let r = Registry::default();
+        let counter = Metric::new("cs.test.count", MetricType::Counter);
+        let gauge = Metric::new("cs.test.gauge", MetricType::Gauge);
+        let histogram = Metric::new("cs.test.ms", MetricType::Histogram);
+        let record = |m: &Metric, value: i64, tags: &[(&str, &str)]| {
+            r.with_series(m.name, m.kind, tags, |s| s.record(value))
+        };
+        record(&counter, 1, &[]);
+        record(&counter, 2, &[]);
+        record(&counter, 5, &[("class", "a\"b")]);
+        record(&gauge, 3, &[]);
+        record(&gauge, 2, &[]);
+        record(&histogram, 7, &[]);
+        record(&histogram, 400000, &[]);
+
+        let text = r.render();
+        let expected_histogram = HISTOGRAM_BUCKETS
+            .iter()
+            .map(|b| {
+                let count = if *b < 7 { 0 } else { 1 };
+                format!("cs_test_ms_bucket{{le=\"{}\"}} {}\n", b, count)
+            })
+            .collect::<String>();
+        assert_eq!(
+            text,
+            format!(
+                "# TYPE cs_test_count_total counter\n\
+                 cs_test_count_total 3\n\
+                 cs_test_count_total{{class=\"a\\\"b\"}} 5\n\
+                 # TYPE cs_test_gauge gauge\n\
+                 cs_test_gauge 2\n\
+                 # TYPE cs_test_ms histogram\n\
+                 {}\
+                 cs_test_ms_bucket{{le=\"+Inf\"}} 2\n\
+                 cs_test_ms_sum 400007\n\
+                 cs_test_ms_count 2\n",
+                expected_histogram
+            )
+        );
+    }
+
+    #[test]
+    fn forwarded_updates() {
+        let worker = Registry::default();
+        let counter = Metric::new("cs.test.count", MetricType::Counter);
+        let gauge = Metric::new("cs.test.gauge", MetricType::Gauge);
+        let histogram = Metric::new("cs.test.ms", MetricType::Histogram);
+        let record = |m: &Metric, value: i64, tags: &[(&str, &str)]| {
+            worker.with_series(m.name, m.kind, tags, |s| s.record(value))
+        };
+        record(&counter, 2, &[("class", "a")]);
+        record(&gauge, 3, &[]);
+        record(&histogram, 7, &[]);
+
+        let main = Registry::default();
+        main.apply_updates(worker.take_updates(), &[("worker", "sel1")]);
+        record(&counter, 1, &[("class", "a")]);
+        main.apply_updates(worker.take_updates(), &[("worker", "sel1")]);
+        // Nothing but the gauge changed since the last call.
+        assert_eq!(
+            worker.take_updates(),
+            vec![MetricUpdate {
+                name: "cs.test.gauge".to_string(),
+                tags: vec![],
+                value: UpdateValue::Gauge(3),
+            }]
+        );
+
+        let text = main.render();
+        assert!(text.contains("cs_test_count_total{class=\"a\",worker=\"sel1\"} 3\n"));
+        assert!(text.contains("cs_test_gauge{worker=\"sel1\"} 3\n"));
+        assert!(text.contains("cs_test_ms_bucket{worker=\"sel1\",le=\"10\"} 1\n"));
+        assert!(text.contains("cs_test_ms_count{worker=\"sel1\"} 1\n"));
+        assert!(worker
+            .render()
+            .contains("cs_test_count_total{class=\"a\"} 0\n"));
+    }
+}
@@ -0,0 +1,206 @@
+/*
+ * Copyright 2021 Cube Dev, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Fingerprint2011, the hash used by ZetaSketch (and BigQuery) to add values into HyperLogLog++
+//! sketches. Port of Guava's `Fingerprint2011`, which ZetaSketch uses through its
+//! `com.google.zetasketch.internal.hash.Hash`.
+use std::convert::TryInto;
+
+// Some primes between 2^63 and 2^64 for various uses.
+const K0: u64 = 0xa5b85c5e198ed849;
+const K1: u64 = 0x8d58ac26afe12e47;
+const K2: u64 = 0xc47b6e9e3a970ed3;
+const K3: u64 = 0xc6a4a7935bd1e995;
+
+pub fn fingerprint2011(s: &[u8]) -> u64 {
+    let len = s.len();
+    let result = if len <= 32 {
+        murmur_hash64_with_seed(s, K0 ^ K1 ^ K2)
+    } else if len <= 64 {
+        hash_len_33_to_64(s)
+    } else {
+        full_fingerprint(s)
+    };
+
+    let u = if len >= 8 { fetch64(s, 0) } else { K0 };
+    let v = if len >= 9 { fetch64(s, len - 8) } else { K0 };
+    let result = hash128_to_64(result.wrapping_add(v), u);
+    return if result == 0 || result == 1 {
+        result.wrapping_add(!1)
+    } else {
+        result
+    };
+}
+
+fn fetch64(s: &[u8], pos: usize) -> u64 {
+    return u64::from_le_bytes(s[pos..pos + 8].try_into().unwrap());
+}
+
+/// Same as [fetch64], but reads at most 8 remaining bytes.
+fn fetch64_safely(s: &[u8], pos: usize) -> u64 {
+    let mut result = 0;
+    for (i, b) in s[pos..].iter().take(8).enumerate() {
+        result |= (*b as u64) << (i * 8);
+    }
+    return result;
+}
+
+fn shift_mix(v: u64) -> u64 {
+    return v ^ (v >> 47);
+}
+
+/// Implementation of Hash128to64 from util/hash/hash128to64.h.
+fn hash128_to_64(high: u64, low: u64) -> u64 {
+    let mut a = (low ^ high).wrapping_mul(K3);
+    a ^= a >> 47;
+    let mut b = (high ^ a).wrapping_mul(K3);
+    b ^= b >> 47;
+    return b.wrapping_mul(K3);
+}
+
+/// Returns a 16-byte hash for s[pos..pos+32], `a` and `b`.
+fn weak_hash_len_32_with_seeds(s: &[u8], pos: usize, mut a: u64, mut b: u64) -> (u64, u64) {
+    let part1 = fetch64(s, pos);
+    let part2 = fetch64(s, pos + 8);
+    let part3 = fetch64(s, pos + 16);
+    let part4 = fetch64(s, pos + 24);
+
+    a = a.wrapping_add(part1);
+    b = b.wrapping_add(a).wrapping_add(part4).rotate_right(51);
+    let c = a;
+    a = a.wrapping_add(part2);
+    a = a.wrapping_add(part3);
+    b = b.wrapping_add(a.rotate_right(23));
+    return (a.wrapping_add(part4), b.wrapping_add(c));
+}
+
+/// Computes an 8-byte hash of a slice longer than 64 bytes.
+fn full_fingerprint(s: &[u8]) -> u64 {
+    let len = s.len();
+    // For lengths over 64 bytes we hash the end first, and then as we loop we keep 56 bytes of
+    // state: v, w, x, y, and z.
+    let mut x = fetch64(s, 0);
+    let mut y = fetch64(s, len - 16) ^ K1;
+    let mut z = fetch64(s, len - 56) ^ K0;
+    let mut v = weak_hash_len_32_with_seeds(s, len - 64, len as u64, y);
+    let mut w = weak_hash_len_32_with_seeds(s, len - 32, (len as u64).wrapping_mul(K1), K0);
+    z = z.wrapping_add(shift_mix(v.1).wrapping_mul(K1));
+    x = z.wrapping_add(x).rotate_right(39).wrapping_mul(K1);
+    y = y.rotate_right(33).wrapping_mul(K1);
+
+    // Decrease length to the nearest multiple of 64, and operate on 64-byte chunks.
+    let end = (len - 1) & !63;
+    let mut pos = 0;
+    while pos != end {
+        x = x
+            .wrapping_add(y)
+            .wrapping_add(v.0)
+            .wrapping_add(fetch64(s, pos + 16))
+            .rotate_right(37)
+            .wrapping_mul(K1);
+        y = y
+            .wrapping_add(v.1)
+            .wrapping_add(fetch64(s, pos + 48))
+            .rotate_right(42)
+            .wrapping_mul(K1);
+        x ^= w.1;
+        y ^= v.0;
+        z = (z ^ w.0).rotate_right(33);
+        v = weak_hash_len_32_with_seeds(s, pos, v.1.wrapping_mul(K1), x.wrapping_add(w.0));
+        w = weak_hash_len_32_with_seeds(s, pos + 32, z.wrapping_add(w.1), y);
+        std::mem::swap(&mut z, &mut x);
+        pos += 64;
+    }
+    return hash128_to_64(
+        hash128_to_64(v.0, w.0)
+            .wrapping_add(shift_mix(y).wrapping_mul(K1))
+            .wrapping_add(z),
+        hash128_to_64(v.1, w.1).wrapping_add(x),
+    );
+}
+
+fn hash_len_33_to_64(s: &[u8]) -> u64 {
+    let len = s.len();
+    let mut z = fetch64(s, 24);
+    let mut a = fetch64(s, 0).wrapping_add(
+        (len as u64)
+            .wrapping_add(fetch64(s, len - 16))
+            .wrapping_mul(K0),
+    );
+    let mut b = a.wrapping_add(z).rotate_right(52);
+    let mut c = a.rotate_right(37);
+    a = a.wrapping_add(fetch64(s, 8));
+    c = c.wrapping_add(a.rotate_right(7));
+    a = a.wrapping_add(fetch64(s, 16));
+    let vf = a.wrapping_add(z);
+    let vs = b.wrapping_add(a.rotate_right(31)).wrapping_add(c);
+    a = fetch64(s, 16).wrapping_add(fetch64(s, len - 32));
+    z = fetch64(s, len - 8);
+    b = a.wrapping_add(z).rotate_right(52);
+    c = a.rotate_right(37);
+    a = a.wrapping_add(fetch64(s, len - 24));
+    c = c.wrapping_add(a.rotate_right(7));
+    a = a.wrapping_add(fetch64(s, len - 16));
+    let wf = a.wrapping_add(z);
+    let ws = b.wrapping_add(a.rotate_right(31)).wrapping_add(c);
+    let r = shift_mix(
+        vf.wrapping_add(ws)
+            .wrapping_mul(K2)
+            .wrapping_add(wf.wrapping_add(vs).wrapping_mul(K0)),
+    );
+    return shift_mix(r.wrapping_mul(K0).wrapping_add(vs)).wrapping_mul(K2);
+}
+
+fn murmur_hash64_with_seed(s: &[u8], seed: u64) -> u64 {
+    let mul = K3;
+    let len = s.len();
+    let len_aligned = len & !7;
+    let mut hash = seed ^ (len as u64).wrapping_mul(mul);
+
+    for pos in (0..len_aligned).step_by(8) {
+        let data = shift_mix(fetch64(s, pos).wrapping_mul(mul)).wrapping_mul(mul);
+        hash ^= data;
+        hash = hash.wrapping_mul(mul);
+    }
+    if len_aligned != len {
+        hash ^= fetch64_safely(s, len_aligned);
+        hash = hash.wrapping_mul(mul);
+    }
+
+    hash = shift_mix(hash).wrapping_mul(mul);
+    return shift_mix(hash);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint2011() {
+        // Expected values are from Guava's `Fingerprint2011Test`.
+        assert_eq!(fingerprint2011(b"test") as i64, 8473225671271759044);
+        // 32 bytes long.
+        assert_eq!(
+            fingerprint2011("test".repeat(8).as_bytes()) as i64,
+            7345148637025587076
+        );
+        // 256 bytes long.
+        assert_eq!(
+            fingerprint2011("test".repeat(64).as_bytes()) as i64,
+            4904844928629814570
+        );
+    }
+}
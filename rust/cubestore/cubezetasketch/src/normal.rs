@@ -184,6 +184,18 @@ impl NormalRepresentation {
         return Ok(());
     }
 
+    /// Updates the register at the index of `hash` with its *ρ(w)*.
+    pub fn add_hash(&mut self, state: &mut State, hash: u64) {
+        Self::ensure_data(state);
+        let data = state.data.as_mut().unwrap();
+
+        let idx = self.encoding.index(hash);
+        let rho_w = self.encoding.rho_w(hash);
+        if data[idx] < rho_w {
+            data[idx] = rho_w;
+        }
+    }
+
     fn ensure_data(state: &mut State) {
         if state.has_data() {
             return;
@@ -29,6 +29,7 @@
 ///
 /// Note that this aggregator is *not* designed to be thread safe.
 use crate::error::Result;
+use crate::fingerprint::fingerprint2011;
 use crate::normal::NormalRepresentation;
 use crate::sparse::SparseRepresentation;
 use crate::state::aggregator_state_proto::AGGREGATOR_TYPE_HYPERLOGLOG_PLUS_UNIQUE;
@@ -76,9 +77,6 @@ impl HyperLogLogPlusPlus {
     // /** The largest normal precision supported by this aggregator. */
     // pub const MAXIMUM_PRECISION : i32= NormalRepresentation::MAXIMUM_PRECISION;
     //
-    // /** The default normal precision that is used if the user does not specify a normal precision. */
-    // pub const DEFAULT_NORMAL_PRECISION :i32 = 15;
-    //
     // /** The largest sparse precision supported by this aggregator. */
     // pub const MAXIMUM_SPARSE_PRECISION :i32 = SparseRepresentation::MAXIMUM_SPARSE_PRECISION;
     //
@@ -92,9 +90,37 @@ impl HyperLogLogPlusPlus {
      */
     pub const DEFAULT_SPARSE_PRECISION_DELTA: i32 = 5;
 
+    /** The default normal precision that is used if the user does not specify a normal precision. */
+    pub const DEFAULT_NORMAL_PRECISION: i32 = 15;
+
     /** The encoding version of the `AggregatorStateProto`. We only support v2. */
     const ENCODING_VERSION: i32 = 2;
 
+    /// Creates an aggregator for an empty set. Only sketches with the same precisions can be merged,
+    /// BigQuery uses `DEFAULT_NORMAL_PRECISION` and `DEFAULT_SPARSE_PRECISION_DELTA` by default.
+    pub fn new(precision: i32, sparse_precision: i32) -> Result<HyperLogLogPlusPlus> {
+        let mut state = State::default();
+        state.encoding_version = Self::ENCODING_VERSION;
+        state.precision = precision;
+        state.sparse_precision = sparse_precision;
+        return Self::from_state(state);
+    }
+
+    /// Creates an aggregator in the normal representation from its registers, i.e. `data` has
+    /// `2^precision` values of *ρ(w)*. The number of added values is unknown and is set to zero.
+    pub fn from_normal_data(
+        precision: i32,
+        sparse_precision: i32,
+        data: Vec<u8>,
+    ) -> Result<HyperLogLogPlusPlus> {
+        let mut state = State::default();
+        state.encoding_version = Self::ENCODING_VERSION;
+        state.precision = precision;
+        state.sparse_precision = sparse_precision;
+        state.data = Some(data);
+        return Self::from_state(state);
+    }
+
     /// Creates a new HyperLogLog++ aggregator from the serialized `proto`.
     ///
     /// `proto` is a valid aggregator state of type `AggregatorType::HYPERLOGLOG_PLUS_UNIQUE`.
@@ -102,6 +128,34 @@ impl HyperLogLogPlusPlus {
         return Self::for_coded_input(CodedInputStream::from_bytes(proto));
     }
 
+    /// Adds an integer value. Same as `HyperLogLogPlusPlus.add(long)` in ZetaSketch.
+    pub fn add_i64(&mut self, v: i64) -> Result<()> {
+        return self.add_hash(fingerprint2011(&v.to_le_bytes()));
+    }
+
+    /// Adds a string or bytes value. Same as `HyperLogLogPlusPlus.add(byte[])` in ZetaSketch, strings
+    /// are hashed as their UTF-8 bytes.
+    pub fn add_bytes(&mut self, v: &[u8]) -> Result<()> {
+        return self.add_hash(fingerprint2011(v));
+    }
+
+    /// Adds a value by its precomputed hash. Only sketches built with the same hash function can be
+    /// merged.
+    pub fn add_hash(&mut self, hash: u64) -> Result<()> {
+        let new_repr = match &mut self.representation {
+            Representation::Sparse(r) => r.add_hash(&mut self.state, hash)?,
+            Representation::Normal(r) => {
+                r.add_hash(&mut self.state, hash);
+                None
+            }
+        };
+        if let Some(n) = new_repr {
+            self.representation = Representation::Normal(n)
+        }
+        self.state.num_values += 1;
+        return Ok(());
+    }
+
     pub fn write(&self) -> Vec<u8> {
         if let Representation::Sparse(r) = &self.representation {
             if r.requires_compaction() {
@@ -121,6 +175,26 @@ impl HyperLogLogPlusPlus {
         }
     }
 
+    pub fn precision(&self) -> i32 {
+        return self.state.precision;
+    }
+
+    pub fn sparse_precision(&self) -> i32 {
+        return self.state.sparse_precision;
+    }
+
+    /// Registers of the normal representation, i.e. `2^precision` values of *ρ(w)*. Sparse
+    /// representation is converted to normal, losing the extra precision.
+    pub fn normal_data(&self) -> Result<Vec<u8>> {
+        let mut state = self.state.clone();
+        if let Representation::Sparse(r) = &self.representation {
+            let _ = r.clone().normalize(&mut state)?;
+        }
+        return Ok(state
+            .data
+            .unwrap_or_else(|| vec![0; 1 << self.state.precision]));
+    }
+
     pub fn is_compatible(&self, other: &HyperLogLogPlusPlus) -> bool {
         return self.state.precision == other.state.precision
             && self.state.sparse_precision == other.state.sparse_precision;
@@ -188,3 +262,75 @@ impl HyperLogLogPlusPlus {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add() {
+        let mut s = HyperLogLogPlusPlus::new(
+            HyperLogLogPlusPlus::DEFAULT_NORMAL_PRECISION,
+            HyperLogLogPlusPlus::DEFAULT_NORMAL_PRECISION
+                + HyperLogLogPlusPlus::DEFAULT_SPARSE_PRECISION_DELTA,
+        )
+        .unwrap();
+        for i in 0..100 {
+            s.add_i64(i).unwrap();
+            s.add_bytes(format!("value_{}", i).as_bytes()).unwrap();
+        }
+        // Sparse representation is exact on small sets.
+        assert_eq!(s.cardinality(), 200);
+        let mut read = HyperLogLogPlusPlus::read(&s.write()).unwrap();
+        assert_eq!(read.cardinality(), 200);
+
+        // Switch to the normal representation.
+        for i in 0..100_000 {
+            s.add_i64(i).unwrap();
+        }
+        if let Representation::Sparse(_) = &s.representation {
+            panic!("expected normal representation");
+        }
+        let c = s.cardinality();
+        assert!(99_000 <= c && c <= 101_000, "estimate {} is too far off", c);
+
+        read.merge_with(&s).unwrap();
+        assert_eq!(read.cardinality(), c);
+        assert!(HyperLogLogPlusPlus::new(9, 20).is_err());
+        assert!(HyperLogLogPlusPlus::new(15, 14).is_err());
+    }
+
+    #[test]
+    fn test_add_hashes_with_fingerprint2011() {
+        let new = || HyperLogLogPlusPlus::new(15, 20).unwrap();
+        let mut added = new();
+        added.add_bytes(b"test").unwrap();
+        // Fingerprint2011 of "test", see `Fingerprint2011Test` in Guava.
+        let mut expected = new();
+        expected.add_hash(8473225671271759044).unwrap();
+        assert_eq!(added.write(), expected.write());
+    }
+
+    #[test]
+    fn test_normal_data() {
+        let mut s = HyperLogLogPlusPlus::new(15, 20).unwrap();
+        for i in 0..1000 {
+            s.add_i64(i).unwrap();
+        }
+        assert!(matches!(s.representation, Representation::Sparse(_)));
+        let data = s.normal_data().unwrap();
+        assert_eq!(data.len(), 1 << 15);
+        let mut normal = HyperLogLogPlusPlus::from_normal_data(15, 20, data).unwrap();
+        let c = normal.cardinality();
+        assert!(990 <= c && c <= 1010, "estimate {} is too far off", c);
+
+        for i in 0..100_000 {
+            s.add_i64(i).unwrap();
+        }
+        let mut normal =
+            HyperLogLogPlusPlus::from_normal_data(15, 20, s.normal_data().unwrap()).unwrap();
+        assert_eq!(normal.cardinality(), s.cardinality());
+
+        assert!(HyperLogLogPlusPlus::from_normal_data(15, 20, vec![0; 10]).is_err());
+    }
+}
@@ -166,6 +166,18 @@ impl SparseRepresentation {
         return estimate.round() as u64;
     }
 
+    /// Returns a new normal representation if this sparse representation has outgrown itself or
+    /// `None` if the sparse representation can continue to be be used.
+    #[must_use]
+    pub fn add_hash(
+        &mut self,
+        state: &mut State,
+        hash: u64,
+    ) -> Result<Option<NormalRepresentation>> {
+        self.buffer.insert(self.encoding.encode(hash));
+        return self.update_representation(state);
+    }
+
     /// `self` may end up be in the invalid state on error and must not be used further.
     pub fn merge_with_sparse(
         &mut self,
@@ -369,7 +381,7 @@ impl SparseRepresentation {
 
     /// Convert to `NormalRepresentation`.
     #[must_use]
-    fn normalize(&mut self, state: &mut State) -> Result<NormalRepresentation> {
+    pub(crate) fn normalize(&mut self, state: &mut State) -> Result<NormalRepresentation> {
         let mut representation = NormalRepresentation::new(state).expect("programming error");
         let sparse_data = state.sparse_data.take();
         state.sparse_size = 0;